@@ -0,0 +1,370 @@
+use crate::fs_error::{report, FsCode};
+use crate::fs_guc;
+use crate::{FsReference, FsValue};
+use pgrx::prelude::*;
+use pgrx::shmem::*;
+use pgrx::{pg_shmem_init, PgLwLock};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Counters of the documents read and written through the fs_* functions, per
+// root collection, kept in shared memory like the pg_stat_* counters. SQL
+// against fs_documents itself is not counted. Like pg_stat_*, the counters
+// are not transactional, so work that is rolled back still counts, and they
+// start from zero when the server restarts.
+
+// The slots shared memory is sized for. pgfirestore.activity_max_collections
+// limits how many are used.
+pub(crate) const MAX_TRACKED_COLLECTIONS: usize = 1024;
+
+// Longer collection IDs are counted in the overflow row
+const MAX_NAME_BYTES: usize = 64;
+
+// Firestore reserves IDs matching __.*__, so no collection is called that
+const OVERFLOW_NAME: &str = "__other__";
+
+#[derive(Clone, Copy)]
+struct Counters {
+    name: [u8; MAX_NAME_BYTES],
+    name_len: usize,
+    reads: i64,
+    writes: i64,
+    // In Postgres microseconds
+    last_write: Option<i64>,
+}
+
+impl Counters {
+    const EMPTY: Counters = Counters {
+        name: [0; MAX_NAME_BYTES],
+        name_len: 0,
+        reads: 0,
+        writes: 0,
+        last_write: None,
+    };
+
+    fn named(collection_id: &str) -> Counters {
+        let mut counters = Counters::EMPTY;
+        counters.name[..collection_id.len()].copy_from_slice(collection_id.as_bytes());
+        counters.name_len = collection_id.len();
+        counters
+    }
+
+    fn name(&self) -> &str {
+        std::str::from_utf8(&self.name[..self.name_len]).expect("names are copied from a str")
+    }
+
+    fn add(&mut self, reads: i64, writes: i64, now: i64) {
+        self.reads += reads;
+        self.writes += writes;
+        if writes > 0 {
+            self.last_write = Some(now);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Activity {
+    collections: [Counters; MAX_TRACKED_COLLECTIONS],
+    len: usize,
+    overflow: Counters,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            collections: [Counters::EMPTY; MAX_TRACKED_COLLECTIONS],
+            len: 0,
+            overflow: Counters::EMPTY,
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for Activity {}
+
+impl Activity {
+    // The counters of `collection_id`, which gets a slot of its own while
+    // fewer than `max_collections` are in use
+    fn counters(&mut self, collection_id: &str, max_collections: usize) -> &mut Counters {
+        let len = self.len;
+        if let Some(index) = self.collections[..len]
+            .iter()
+            .position(|counters| counters.name() == collection_id)
+        {
+            return &mut self.collections[index];
+        }
+        if len >= max_collections.min(MAX_TRACKED_COLLECTIONS)
+            || collection_id.len() > MAX_NAME_BYTES
+        {
+            return &mut self.overflow;
+        }
+        self.collections[len] = Counters::named(collection_id);
+        self.len += 1;
+        &mut self.collections[len]
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+        self.overflow = Counters::EMPTY;
+    }
+
+    // Ordered by collection ID, with the overflow row last once it counted
+    // anything
+    fn rows(&self) -> Vec<(String, i64, i64, Option<i64>)> {
+        let mut rows = self.collections[..self.len]
+            .iter()
+            .map(|counters| {
+                (
+                    counters.name().to_owned(),
+                    counters.reads,
+                    counters.writes,
+                    counters.last_write,
+                )
+            })
+            .collect::<Vec<_>>();
+        rows.sort();
+        if self.overflow.reads > 0 || self.overflow.writes > 0 {
+            rows.push((
+                OVERFLOW_NAME.to_owned(),
+                self.overflow.reads,
+                self.overflow.writes,
+                self.overflow.last_write,
+            ));
+        }
+        rows
+    }
+}
+
+static ACTIVITY: PgLwLock<Activity> = PgLwLock::new();
+
+// Backends inherit this from the postmaster, which sets it when it loads the
+// extension through shared_preload_libraries
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn init() {
+    // Shared memory can only be requested while the postmaster starts
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        pg_shmem_init!(ACTIVITY);
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn expect_enabled() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        report(
+            FsCode::FailedPrecondition,
+            "Activity counters require pgfirestore in shared_preload_libraries".to_owned(),
+        )
+    }
+}
+
+// Adds to the counters of the root collection `collection_id`. A no-op when
+// the extension is not preloaded.
+pub(crate) fn count(collection_id: &str, reads: i64, writes: i64) {
+    if !ENABLED.load(Ordering::Relaxed) || (reads == 0 && writes == 0) {
+        return;
+    }
+    let max_collections = fs_guc::ACTIVITY_MAX_COLLECTIONS.get().max(0) as usize;
+    let now = unsafe { pg_sys::GetCurrentTimestamp() };
+    ACTIVITY
+        .exclusive()
+        .counters(collection_id, max_collections)
+        .add(reads, writes, now);
+}
+
+pub(crate) fn count_document(reference: &FsReference, reads: i64, writes: i64) {
+    if let Some(collection_id) = reference.root_collection_id() {
+        count(collection_id, reads, writes)
+    }
+}
+
+// Counts every document of `references` once, per root collection
+pub(crate) fn count_documents<'a>(references: impl Iterator<Item = &'a FsReference>, reads: bool) {
+    let mut documents = BTreeMap::<&str, i64>::new();
+    for collection_id in references.filter_map(FsReference::root_collection_id) {
+        *documents.entry(collection_id).or_default() += 1;
+    }
+    for (collection_id, documents) in documents {
+        if reads {
+            count(collection_id, documents, 0)
+        } else {
+            count(collection_id, 0, documents)
+        }
+    }
+}
+
+// Called by fs_collection_v2 once it returned its documents
+#[pg_extern]
+fn fs_activity_count_reads(parent: FsValue, collection_id: &str, documents: i64) {
+    match parent
+        .as_reference()
+        .and_then(FsReference::root_collection_id)
+    {
+        Some(root_collection_id) => count(root_collection_id, documents, 0),
+        None => count(collection_id, documents, 0),
+    }
+}
+
+#[pg_extern]
+fn fs_activity_stats() -> TableIterator<
+    'static,
+    (
+        name!(root_collection, String),
+        name!(reads, i64),
+        name!(writes, i64),
+        name!(last_write, Option<TimestampWithTimeZone>),
+    ),
+> {
+    expect_enabled();
+    let rows = ACTIVITY.share().rows();
+    TableIterator::new(
+        rows.into_iter()
+            .map(|(root_collection, reads, writes, last_write)| {
+                (
+                    root_collection,
+                    reads,
+                    writes,
+                    last_write.and_then(|micros| TimestampWithTimeZone::try_from(micros).ok()),
+                )
+            }),
+    )
+}
+
+#[pg_extern]
+fn fs_activity_reset() {
+    expect_enabled();
+    ACTIVITY.exclusive().reset();
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_activity::*;
+
+    fn rows(activity: &Activity) -> Vec<(String, i64, i64)> {
+        activity
+            .rows()
+            .into_iter()
+            .map(|(name, reads, writes, _)| (name, reads, writes))
+            .collect()
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut activity = Activity::default();
+        activity.counters("users", 10).add(2, 0, 1);
+        activity.counters("posts", 10).add(0, 1, 2);
+        activity.counters("users", 10).add(1, 3, 3);
+        assert_eq!(
+            rows(&activity),
+            vec![("posts".to_owned(), 0, 1), ("users".to_owned(), 3, 3),]
+        );
+        assert_eq!(activity.counters("users", 10).last_write, Some(3));
+        activity.reset();
+        assert!(rows(&activity).is_empty());
+        activity.counters("posts", 10).add(1, 0, 4);
+        assert_eq!(rows(&activity), vec![("posts".to_owned(), 1, 0)]);
+        assert_eq!(activity.counters("posts", 10).last_write, None);
+    }
+
+    #[test]
+    fn test_counters_overflow() {
+        let mut activity = Activity::default();
+        for collection_id in ["a", "b", "c", "d"] {
+            activity.counters(collection_id, 2).add(1, 1, 1);
+        }
+        activity
+            .counters(&"x".repeat(MAX_NAME_BYTES + 1), 10)
+            .add(1, 0, 2);
+        // Collections that got a slot keep it
+        activity.counters("a", 2).add(1, 0, 3);
+        assert_eq!(
+            rows(&activity),
+            vec![
+                ("a".to_owned(), 2, 1),
+                ("b".to_owned(), 1, 1),
+                (OVERFLOW_NAME.to_owned(), 3, 2),
+            ]
+        );
+        // Limits beyond the size of shared memory are capped
+        let mut activity = Activity::default();
+        for index in 0..=MAX_TRACKED_COLLECTIONS {
+            activity
+                .counters(&index.to_string(), usize::MAX)
+                .add(1, 0, 1);
+        }
+        assert_eq!(activity.len, MAX_TRACKED_COLLECTIONS);
+        assert_eq!(activity.overflow.reads, 1);
+    }
+
+    // (reads, writes) of a root collection
+    fn stats(root_collection: &str) -> Option<(i64, i64)> {
+        Spi::connect(|client| {
+            let mut table = client.select(
+                "SELECT reads, writes FROM fs_activity_stats() WHERE root_collection = $1",
+                None,
+                Some(vec![crate::fs_documents::text_arg(root_collection)]),
+            )?;
+            match table.next() {
+                Some(row) => Ok(Some((
+                    row.get::<i64>(1)?.unwrap_or_default(),
+                    row.get::<i64>(2)?.unwrap_or_default(),
+                ))),
+                None => Ok::<_, pgrx::spi::Error>(None),
+            }
+        })
+        .expect("SPI failed")
+    }
+
+    // Counters are shared with tests running at the same time, which only
+    // use collections of their own. One test resets them.
+    #[pg_test]
+    fn test_fs_activity_stats() {
+        fs_activity_reset();
+        assert_eq!(stats("activity"), None);
+        Spi::run(
+            "SELECT fs_set(fs_reference('/activity/1'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)])); \
+             SELECT fs_set(fs_reference('/activity/2/items/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])); \
+             SELECT fs_get(fs_reference('/activity/1')); \
+             SELECT count(*) FROM fs_collection(fs_reference('/'), 'activity'); \
+             SELECT count(*) FROM fs_collection(fs_reference('/activity/2'), 'items'); \
+             SELECT count(*) FROM fs_run_query(fs_reference('/'), '{\"from\": [{\"collectionId\": \"activity\"}]}')",
+        )
+        .expect("SPI failed");
+        // One read by fs_get, and one per document returned otherwise
+        assert_eq!(stats("activity"), Some((4, 2)));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT last_write <= clock_timestamp() FROM fs_activity_stats() \
+                 WHERE root_collection = 'activity'"
+            ),
+            Ok(Some(true))
+        );
+        // Writes that change nothing are not counted
+        Spi::run(
+            "SELECT fs_set(fs_reference('/activity/1'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)])); \
+             SELECT fs_delete(fs_reference('/activity/404'))",
+        )
+        .expect("SPI failed");
+        assert_eq!(stats("activity"), Some((4, 2)));
+        fs_activity_reset();
+        assert_eq!(stats("activity"), None);
+
+        // Collections beyond the slots in use share the overflow row
+        let max_collections = fs_guc::ACTIVITY_MAX_COLLECTIONS.get() as usize;
+        for index in 0..=max_collections {
+            Spi::run(&format!(
+                "SELECT fs_set(fs_reference('/activity{}/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+                index
+            ))
+            .expect("SPI failed");
+        }
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_activity_stats() WHERE root_collection <> '__other__'"
+            ),
+            Ok(Some(max_collections as i64))
+        );
+        assert!(stats(OVERFLOW_NAME).is_some_and(|(_, writes)| writes >= 1));
+        fs_activity_reset();
+    }
+}
@@ -0,0 +1,307 @@
+use crate::fs_number::{number_from_double, FsNumber};
+use crate::FsValue;
+use pgrx::prelude::*;
+
+// Aggregates follow Firestore's sum() and avg(): values other than numbers
+// are skipped, and a NaN makes the result NaN. A sum of integers stays an
+// integer until it leaves the range of a 64-bit integer. From then on, or
+// once a double is added, it is a double.
+// Unlike `+`, which saturates like the increment transform, integer sums
+// overflow to a double.
+fn add_to_sum(sum: FsNumber, number: FsNumber) -> FsNumber {
+    match sum.integers(&number) {
+        Some((l, r)) => match i64::try_from(l + r) {
+            Ok(total) => FsNumber::Number(total.into()),
+            Err(_) => number_from_double((l + r) as f64),
+        },
+        None => sum + number,
+    }
+}
+
+fn expect_sum(state: FsValue) -> FsNumber {
+    match state {
+        FsValue::Number(sum) => sum,
+        other => unreachable!("the state of fs_sum is a number but found {:?}", other),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_sum_transition(state: Option<FsValue>, value: Option<FsValue>) -> Option<FsValue> {
+    match value {
+        Some(FsValue::Number(number)) => Some(FsValue::Number(match state {
+            None => add_to_sum(FsNumber::Number(0.into()), number),
+            Some(sum) => add_to_sum(expect_sum(sum), number),
+        })),
+        _ => state,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_sum_combine(lhs: Option<FsValue>, rhs: Option<FsValue>) -> Option<FsValue> {
+    fs_sum_transition(lhs, rhs)
+}
+
+// The sum of no numbers is 0, like Firestore's sum()
+#[pg_extern(immutable, parallel_safe)]
+fn fs_sum_final(state: Option<FsValue>) -> FsValue {
+    state.unwrap_or(FsValue::Number(FsNumber::Number(0.into())))
+}
+
+// The state of fs_avg is the array [sum, count]
+fn avg_state(state: Option<FsValue>) -> (FsNumber, i64) {
+    match state {
+        None => (FsNumber::Number(0.into()), 0),
+        Some(FsValue::Array(state)) => match state.as_slice() {
+            [FsValue::Number(sum), FsValue::Number(FsNumber::Number(count))] => (
+                sum.to_owned(),
+                count.as_i64().expect("the count of fs_avg is an integer"),
+            ),
+            other => unreachable!("the state of fs_avg is [sum, count] but found {:?}", other),
+        },
+        Some(other) => unreachable!("the state of fs_avg is an array but found {:?}", other),
+    }
+}
+
+fn avg_value(sum: FsNumber, count: i64) -> Option<FsValue> {
+    Some(FsValue::Array(vec![
+        FsValue::Number(sum),
+        FsValue::Number(FsNumber::Number(count.into())),
+    ]))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_avg_transition(state: Option<FsValue>, value: Option<FsValue>) -> Option<FsValue> {
+    match value {
+        Some(FsValue::Number(number)) => {
+            let (sum, count) = avg_state(state);
+            avg_value(add_to_sum(sum, number), count + 1)
+        }
+        _ => state,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_avg_combine(lhs: Option<FsValue>, rhs: Option<FsValue>) -> Option<FsValue> {
+    match (lhs, rhs) {
+        (None, state) | (state, None) => state,
+        (lhs, rhs) => {
+            let ((lhs_sum, lhs_count), (rhs_sum, rhs_count)) = (avg_state(lhs), avg_state(rhs));
+            avg_value(add_to_sum(lhs_sum, rhs_sum), lhs_count + rhs_count)
+        }
+    }
+}
+
+// The mean as a double, like Firestore's avg(), or NULL without numbers
+#[pg_extern(immutable, parallel_safe)]
+fn fs_avg_final(state: Option<FsValue>) -> Option<FsValue> {
+    let (sum, count) = avg_state(state);
+    if count == 0 {
+        return None;
+    }
+    Some(FsValue::Number(number_from_double(
+        sum.as_double() / count as f64,
+    )))
+}
+
+// The lesser and greater of two values in the canonical ordering, keeping
+// the first of two equal values such as 1 and 1.0
+#[pg_extern(immutable, parallel_safe)]
+fn fs_least(lhs: FsValue, rhs: FsValue) -> FsValue {
+    if rhs < lhs {
+        rhs
+    } else {
+        lhs
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_greatest(lhs: FsValue, rhs: FsValue) -> FsValue {
+    if rhs > lhs {
+        rhs
+    } else {
+        lhs
+    }
+}
+
+// The transition functions of fs_min and fs_max are strict, so the first
+// value becomes the state and SQL NULLs are skipped like in min and max.
+extension_sql!(
+    "\n\
+        CREATE AGGREGATE fs_sum(fsvalue) ( \n\
+            SFUNC = fs_sum_transition, STYPE = fsvalue, \n\
+            COMBINEFUNC = fs_sum_combine, FINALFUNC = fs_sum_final, \n\
+            FINALFUNC_MODIFY = READ_ONLY, PARALLEL = SAFE \n\
+        ); \n\
+        CREATE AGGREGATE fs_avg(fsvalue) ( \n\
+            SFUNC = fs_avg_transition, STYPE = fsvalue, \n\
+            COMBINEFUNC = fs_avg_combine, FINALFUNC = fs_avg_final, \n\
+            FINALFUNC_MODIFY = READ_ONLY, PARALLEL = SAFE \n\
+        ); \n\
+        CREATE AGGREGATE fs_min(fsvalue) ( \n\
+            SFUNC = fs_least, STYPE = fsvalue, COMBINEFUNC = fs_least, \n\
+            SORTOP = <, PARALLEL = SAFE \n\
+        ); \n\
+        CREATE AGGREGATE fs_max(fsvalue) ( \n\
+            SFUNC = fs_greatest, STYPE = fsvalue, COMBINEFUNC = fs_greatest, \n\
+            SORTOP = >, PARALLEL = SAFE \n\
+        ); \n\
+    ",
+    name = "aggregates",
+    requires = [
+        FsValue,
+        fs_sum_transition,
+        fs_sum_combine,
+        fs_sum_final,
+        fs_avg_transition,
+        fs_avg_combine,
+        fs_avg_final,
+        fs_least,
+        fs_greatest,
+        fsvalue_lt,
+        fsvalue_gt
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_aggregate::*;
+    use crate::{fs_number_from_bigint, fs_number_from_double, fs_number_from_integer, fs_string};
+
+    #[test]
+    fn test_sum_overflows_to_double() {
+        let sum = [i64::MAX, 1, -1].into_iter().fold(None, |state, value| {
+            fs_sum_transition(state, Some(fs_number_from_bigint(value)))
+        });
+        assert_eq!(
+            fs_sum_final(sum),
+            fs_number_from_double(9223372036854775808.0)
+        );
+        let sum = [i64::MAX, -1, 1].into_iter().fold(None, |state, value| {
+            fs_sum_transition(state, Some(fs_number_from_bigint(value)))
+        });
+        assert_eq!(fs_sum_final(sum), fs_number_from_bigint(i64::MAX));
+    }
+
+    #[test]
+    fn test_sum_of_doubles_stays_double() {
+        let sum = [1.5, 1.5].into_iter().fold(None, |state, value| {
+            fs_sum_transition(state, Some(fs_number_from_double(value)))
+        });
+        assert_eq!(fs_sum_final(sum), fs_number_from_double(3.0));
+        let sum = fs_sum_transition(
+            fs_sum_transition(None, Some(fs_number_from_double(0.5))),
+            Some(fs_number_from_integer(1)),
+        );
+        assert_eq!(fs_sum_final(sum), fs_number_from_double(1.5));
+    }
+
+    #[test]
+    fn test_avg_combine() {
+        let state = |values: &[i32]| {
+            values.iter().fold(None, |state, value| {
+                fs_avg_transition(state, Some(fs_number_from_integer(*value)))
+            })
+        };
+        assert_eq!(
+            fs_avg_final(fs_avg_combine(state(&[1, 2]), state(&[6]))),
+            Some(fs_number_from_double(3.0))
+        );
+        assert_eq!(
+            fs_avg_final(fs_avg_combine(None, state(&[5]))),
+            Some(fs_number_from_double(5.0))
+        );
+        assert_eq!(fs_avg_final(fs_avg_combine(None, None)), None);
+    }
+
+    fn aggregate(aggregate: &str, source: &str) -> Option<FsValue> {
+        Spi::get_one::<FsValue>(&format!("SELECT {}(value) FROM {}", aggregate, source))
+            .expect("SPI failed")
+    }
+
+    fn create_values(values: &str) {
+        Spi::run(&format!(
+            "CREATE TEMPORARY TABLE aggregate_values AS SELECT value FROM (VALUES {}) AS v(value)",
+            values
+        ))
+        .expect("SPI failed");
+    }
+
+    const SEEDED_FOO: &str =
+        "(SELECT properties->'foo' AS value FROM fs_collection(fs_database_root(), 'users')) AS u";
+
+    #[pg_test]
+    fn test_aggregates_of_seeded_documents() {
+        assert_eq!(
+            aggregate("fs_sum", SEEDED_FOO),
+            Some(fs_number_from_integer(14))
+        );
+        assert_eq!(
+            aggregate("fs_avg", SEEDED_FOO),
+            Some(fs_number_from_double(2.8))
+        );
+        assert_eq!(
+            aggregate("fs_min", SEEDED_FOO),
+            Some(fs_number_from_integer(0))
+        );
+        assert_eq!(
+            aggregate("fs_max", SEEDED_FOO),
+            Some(fs_number_from_integer(5))
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT fs_reference_text(fs_max(reference)) FROM fs_documents"),
+            Ok(Some("/users/5".to_owned()))
+        );
+    }
+
+    #[pg_test]
+    fn test_aggregates_of_mixed_types() {
+        create_values(
+            "(fs_number_from_integer(1)), (fs_number_from_double(2.5)), (fs_string('10')), \
+             (fs_boolean(true)), (NULL::fsvalue), (fs_null())",
+        );
+        let source = "aggregate_values";
+        // Only the numbers count
+        assert_eq!(
+            aggregate("fs_sum", source),
+            Some(fs_number_from_double(3.5))
+        );
+        assert_eq!(
+            aggregate("fs_avg", source),
+            Some(fs_number_from_double(1.75))
+        );
+        // Firestore NULL sorts first, strings after numbers
+        assert_eq!(aggregate("fs_min", source), Some(crate::FsValue::NULL));
+        assert_eq!(aggregate("fs_max", source), Some(fs_string("10")));
+    }
+
+    #[pg_test]
+    fn test_aggregates_with_nan() {
+        create_values("(fs_number_from_integer(1)), (fs_nan()), (fs_number_from_integer(2))");
+        let is_nan = |value: Option<FsValue>| matches!(value, Some(FsValue::Number(FsNumber::NAN)));
+        assert!(is_nan(aggregate("fs_sum", "aggregate_values")));
+        assert!(is_nan(aggregate("fs_avg", "aggregate_values")));
+        // NaN sorts before every other number
+        assert!(is_nan(aggregate("fs_min", "aggregate_values")));
+        assert_eq!(
+            aggregate("fs_max", "aggregate_values"),
+            Some(fs_number_from_integer(2))
+        );
+    }
+
+    #[pg_test]
+    fn test_aggregates_without_numbers() {
+        let empty = "(SELECT NULL::fsvalue AS value WHERE false) AS e";
+        assert_eq!(aggregate("fs_sum", empty), Some(fs_number_from_integer(0)));
+        assert_eq!(aggregate("fs_avg", empty), None);
+        assert_eq!(aggregate("fs_min", empty), None);
+        assert_eq!(aggregate("fs_max", empty), None);
+        let strings = "(VALUES (fs_string('a'))) AS s(value)";
+        assert_eq!(
+            aggregate("fs_sum", strings),
+            Some(fs_number_from_integer(0))
+        );
+        assert_eq!(aggregate("fs_avg", strings), None);
+        assert_eq!(aggregate("fs_max", strings), Some(fs_string("a")));
+    }
+}
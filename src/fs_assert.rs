@@ -0,0 +1,141 @@
+use crate::fs_diff::{field_path_text, first_difference};
+use crate::fs_display::display_value;
+use crate::fs_documents::{
+    expect_document_reference, expect_parent_reference, fsvalue_arg, get_document, text_arg,
+};
+use crate::FsValue;
+use pgrx::prelude::*;
+
+// Assertions for tests written in SQL. They return true so that they can be
+// selected, and fail like a PL/pgSQL ASSERT, with SQLSTATE P0004 and a
+// message pointing at the first difference.
+
+fn fail(message: String) -> ! {
+    ereport!(
+        PgLogLevel::ERROR,
+        PgSqlErrorCode::ERRCODE_ASSERT_FAILURE,
+        message
+    );
+    unreachable!("an ERROR report does not return")
+}
+
+fn describe(value: Option<&FsValue>) -> String {
+    value.map_or("nothing".to_owned(), display_value)
+}
+
+// Describes the first difference between two values, None when they are equal
+fn difference(actual: &FsValue, expected: &FsValue, label: &str) -> Option<String> {
+    let path = first_difference(actual, expected)?;
+    let location = if path.is_empty() {
+        "the top level".to_owned()
+    } else {
+        format!("'{}'", field_path_text(path.to_owned()))
+    };
+    Some(format!(
+        "{}: values differ at {}: expected {} but found {}",
+        label,
+        location,
+        describe(expected.get_field(&path)),
+        describe(actual.get_field(&path))
+    ))
+}
+
+fn assert_eq(actual: &FsValue, expected: &FsValue, label: &str) {
+    if let Some(message) = difference(actual, expected, label) {
+        fail(message)
+    }
+}
+
+#[pg_extern]
+fn fs_assert_eq(actual: FsValue, expected: FsValue, label: default!(Option<&str>, "NULL")) -> bool {
+    assert_eq(&actual, &expected, label.unwrap_or("fs_assert_eq"));
+    true
+}
+
+// Compares the stored properties of a document
+#[pg_extern]
+fn fs_assert_document(reference: FsValue, expected: FsValue) -> bool {
+    let fs_ref = expect_document_reference(&reference);
+    match get_document(fs_ref) {
+        Some(properties) => assert_eq(&properties, &expected, &format!("Document {}", fs_ref)),
+        None => fail(format!("Document {} does not exist", fs_ref)),
+    }
+    true
+}
+
+#[pg_extern]
+fn fs_assert_count(parent: FsValue, collection_id: &str, expected: i64) -> bool {
+    let fs_ref = expect_parent_reference(&parent);
+    let count = Spi::get_one_with_args::<i64>(
+        "SELECT count(*) FROM fs_collection($1, $2)",
+        vec![fsvalue_arg(parent.to_owned()), text_arg(collection_id)],
+    )
+    .expect("Failed to count documents")
+    .expect("count must not be null");
+    if count != expected {
+        let collection = if fs_ref.is_root() {
+            format!("/{}", collection_id)
+        } else {
+            format!("{}/{}", fs_ref, collection_id)
+        };
+        fail(format!(
+            "Collection {} has {} documents but {} were expected",
+            collection, count, expected
+        ))
+    }
+    true
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_assert::*;
+    use crate::{fs_database_root, fs_reference};
+    use serde_json::json;
+
+    #[test]
+    fn test_assert_eq_message() {
+        let actual = FsValue::from_plain_json(&json!({"a": 1, "b": {"x": "old", "y": 2}}));
+        let expected = FsValue::from_plain_json(&json!({"a": 1, "b": {"x": "new", "y": 2}}));
+        assert_eq!(difference(&actual, &actual, "same"), None);
+        assert_eq!(
+            difference(&actual, &expected, "label").as_deref(),
+            Some("label: values differ at 'b.x': expected \"new\" but found \"old\"")
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_assert_passing() {
+        let properties = FsValue::from_plain_json(&json!({"foo": 2}));
+        assert!(fs_assert_eq(
+            properties.to_owned(),
+            properties.to_owned(),
+            None
+        ));
+        assert!(fs_assert_document(fs_reference("/users/2"), properties));
+        assert!(fs_assert_count(fs_database_root(), "users", 5));
+        assert!(fs_assert_count(fs_reference("/users/1"), "posts", 2));
+    }
+
+    #[pg_test(error = "Users: values differ at 'b': expected nothing but found true")]
+    fn test_fs_assert_eq_extra_field() {
+        fs_assert_eq(
+            FsValue::from_plain_json(&json!({"a": 1, "b": true})),
+            FsValue::from_plain_json(&json!({"a": 1})),
+            Some("Users"),
+        );
+    }
+
+    #[pg_test(error = "Document /users/1: values differ at 'foo': expected 1 but found 0")]
+    fn test_fs_assert_document_different() {
+        fs_assert_document(
+            fs_reference("/users/1"),
+            FsValue::from_plain_json(&json!({"foo": 1, "bar": 0})),
+        );
+    }
+
+    #[pg_test(error = "Collection /users/1/posts has 2 documents but 3 were expected")]
+    fn test_fs_assert_count_different() {
+        fs_assert_count(fs_reference("/users/1"), "posts", 3);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::fs_bytes::bytes_value;
+use crate::fs_timestamp::timestamp_value;
+use crate::{check_json_depth, FsError, FsNumber, FsValue};
+use pgrx::prelude::*;
+use pgrx::AnyElement;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// (datum, is null, type) of a variadic argument
+type Argument = (pg_sys::Datum, bool, pg_sys::Oid);
+
+// The SQL name of a type, e.g. `integer[]`
+fn type_name(type_oid: pg_sys::Oid) -> String {
+    unsafe { CStr::from_ptr(pg_sys::format_type_be(type_oid)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn double_value(double: f64) -> FsValue {
+    FsValue::Number(match serde_json::Number::from_f64(double) {
+        Some(number) => FsNumber::Number(number),
+        None if double.is_nan() => FsNumber::NAN,
+        None if double > 0.0 => FsNumber::PositiveInfinity,
+        None => FsNumber::NegativeInfinity,
+    })
+}
+
+// numeric goes through its text, which is rejected if neither an integer
+// nor a double holds it exactly
+unsafe fn numeric_value(datum: pg_sys::Datum, type_oid: pg_sys::Oid) -> Result<FsValue> {
+    let mut output = pg_sys::InvalidOid;
+    let mut is_varlena = false;
+    pg_sys::getTypeOutputInfo(type_oid, &mut output, &mut is_varlena);
+    let text = CStr::from_ptr(pg_sys::OidOutputFunctionCall(output, datum)).to_string_lossy();
+    FsNumber::from_str(&text).map(FsValue::Number)
+}
+
+unsafe fn array_value(datum: pg_sys::Datum) -> Result<FsValue> {
+    let array = pg_sys::pg_detoast_datum(datum.cast_mut_ptr()) as *mut pg_sys::ArrayType;
+    if (*array).ndim > 1 {
+        return Err(FsError::InvalidValue(
+            "Multidimensional arrays are not supported".to_owned(),
+        ));
+    }
+    let element_type = (*array).elemtype;
+    let mut typlen = 0;
+    let mut typbyval = false;
+    let mut typalign = 0;
+    pg_sys::get_typlenbyvalalign(element_type, &mut typlen, &mut typbyval, &mut typalign);
+    let mut elements = std::ptr::null_mut();
+    let mut nulls = std::ptr::null_mut();
+    let mut count = 0;
+    pg_sys::deconstruct_array(
+        array,
+        element_type,
+        typlen as i32,
+        typbyval,
+        typalign,
+        &mut elements,
+        &mut nulls,
+        &mut count,
+    );
+    let elements = (0..count as usize)
+        .map(|index| datum_value(*elements.add(index), *nulls.add(index), element_type))
+        .collect::<Result<Vec<FsValue>>>()?;
+    FsValue::check_array_nesting(&elements)?;
+    Ok(FsValue::Array(elements))
+}
+
+// Converts a datum of a supported native type or of fsvalue. SQL NULL
+// becomes a Firestore NULL, and arrays of any supported type become arrays.
+pub(crate) unsafe fn datum_value(
+    datum: pg_sys::Datum,
+    is_null: bool,
+    type_oid: pg_sys::Oid,
+) -> Result<FsValue> {
+    if is_null {
+        return Ok(FsValue::NULL);
+    }
+    if type_oid == FsValue::type_oid() {
+        return Ok(FsValue::from_datum(datum, false).expect("fsvalue must not be null"));
+    }
+    let value = match PgOid::from(type_oid) {
+        PgOid::BuiltIn(PgBuiltInOids::BOOLOID) => {
+            bool::from_datum(datum, false).map(FsValue::Boolean)
+        }
+        PgOid::BuiltIn(PgBuiltInOids::INT2OID) => i16::from_datum(datum, false)
+            .map(|integer| FsValue::Number(FsNumber::Number(integer.into()))),
+        PgOid::BuiltIn(PgBuiltInOids::INT4OID) => i32::from_datum(datum, false)
+            .map(|integer| FsValue::Number(FsNumber::Number(integer.into()))),
+        PgOid::BuiltIn(PgBuiltInOids::INT8OID) => i64::from_datum(datum, false)
+            .map(|integer| FsValue::Number(FsNumber::Number(integer.into()))),
+        PgOid::BuiltIn(PgBuiltInOids::FLOAT4OID) => {
+            f32::from_datum(datum, false).map(|double| double_value(double as f64))
+        }
+        PgOid::BuiltIn(PgBuiltInOids::FLOAT8OID) => f64::from_datum(datum, false).map(double_value),
+        PgOid::BuiltIn(PgBuiltInOids::NUMERICOID) => return numeric_value(datum, type_oid),
+        PgOid::BuiltIn(
+            PgBuiltInOids::TEXTOID | PgBuiltInOids::VARCHAROID | PgBuiltInOids::BPCHAROID,
+        ) => String::from_datum(datum, false).map(FsValue::String),
+        PgOid::BuiltIn(PgBuiltInOids::BYTEAOID) => {
+            return bytes_value(
+                Vec::<u8>::from_datum(datum, false).expect("bytea must not be null"),
+            )
+        }
+        PgOid::BuiltIn(PgBuiltInOids::DATEOID) => {
+            pgrx::Date::from_datum(datum, false).map(FsValue::Date)
+        }
+        PgOid::BuiltIn(PgBuiltInOids::TIMESTAMPTZOID) => {
+            return timestamp_value(
+                TimestampWithTimeZone::from_datum(datum, false)
+                    .expect("timestamptz must not be null"),
+            )
+        }
+        PgOid::BuiltIn(PgBuiltInOids::JSONBOID) => {
+            let json = pgrx::JsonB::from_datum(datum, false).expect("jsonb must not be null");
+            check_json_depth(&json.0)?;
+            Some(FsValue::from_plain_json(&json.0))
+        }
+        _ if pg_sys::get_element_type(type_oid) != pg_sys::InvalidOid => return array_value(datum),
+        _ => {
+            return Err(FsError::InvalidType(format!(
+                "Cannot convert {} to an fsvalue",
+                type_name(type_oid)
+            )))
+        }
+    };
+    Ok(value.expect("datum must not be null"))
+}
+
+// Native values convert like fs_doc and fs_value do, fsvalue is kept as is
+#[pg_extern(stable, parallel_safe)]
+fn fs_value(value: AnyElement) -> FsValue {
+    match unsafe { datum_value(value.datum(), false, value.oid()) } {
+        Ok(value) => value,
+        Err(error) => error.report(),
+    }
+}
+
+// A map of alternating text keys and values, like jsonb_build_object
+fn build_doc(values: &[Argument]) -> Result<FsValue> {
+    if values.len() % 2 == 1 {
+        return Err(FsError::InvalidValue(format!(
+            "fs_doc expects alternating keys and values but found {} arguments",
+            values.len()
+        )));
+    }
+    let mut map = BTreeMap::new();
+    for (index, pair) in values.chunks(2).enumerate() {
+        // Argument positions are 1-based like in SQL
+        let key_position = 2 * index + 1;
+        let (key_datum, key_is_null, key_type) = pair[0];
+        if key_is_null {
+            return Err(FsError::InvalidValue(format!(
+                "Argument {} of fs_doc is a key and must not be NULL",
+                key_position
+            )));
+        }
+        let key = match PgOid::from(key_type) {
+            PgOid::BuiltIn(PgBuiltInOids::TEXTOID | PgBuiltInOids::VARCHAROID) => unsafe {
+                String::from_datum(key_datum, false).expect("key must not be null")
+            },
+            _ => {
+                return Err(FsError::InvalidType(format!(
+                    "Argument {} of fs_doc is a key and must be text but found {}",
+                    key_position,
+                    type_name(key_type)
+                )))
+            }
+        };
+        let (datum, is_null, type_oid) = pair[1];
+        let value = unsafe { datum_value(datum, is_null, type_oid) }.map_err(|error| {
+            error.with_context(&format!("Argument {} of fs_doc", key_position + 1))
+        })?;
+        if map.insert(key.to_owned(), value).is_some() {
+            return Err(FsError::InvalidValue(format!(
+                "Argument {} of fs_doc repeats the key '{}'",
+                key_position, key
+            )));
+        }
+    }
+    Ok(FsValue::Map(map))
+}
+
+// An array of the values, like fs_array
+fn build_arr(values: &[Argument]) -> Result<FsValue> {
+    let elements = values
+        .iter()
+        .enumerate()
+        .map(|(index, (datum, is_null, type_oid))| {
+            unsafe { datum_value(*datum, *is_null, *type_oid) }
+                .map_err(|error| error.with_context(&format!("Argument {} of fs_arr", index + 1)))
+        })
+        .collect::<Result<Vec<FsValue>>>()?;
+    FsValue::check_array_nesting(&elements)?;
+    Ok(FsValue::Array(elements))
+}
+
+// VARIADIC "any" is not expressible through #[pg_extern], so fs_doc and
+// fs_arr are bare V1 functions. Untyped literals such as 'name' arrive as
+// text. Like jsonb_build_object, a variadic function needs an argument, so
+// fs_doc() and fs_arr() are separate overloads of the same functions.
+unsafe fn build_variadic(
+    fcinfo: pg_sys::FunctionCallInfo,
+    build: fn(&[Argument]) -> Result<FsValue>,
+) -> pg_sys::Datum {
+    let mut datums = std::ptr::null_mut();
+    let mut types = std::ptr::null_mut();
+    let mut nulls = std::ptr::null_mut();
+    let count = pg_sys::extract_variadic_args(fcinfo, 0, true, &mut datums, &mut types, &mut nulls);
+    // VARIADIC NULL
+    if count < 0 {
+        return pgrx::fcinfo::pg_return_null(fcinfo);
+    }
+    let values: Vec<Argument> = (0..count as usize)
+        .map(|index| (*datums.add(index), *nulls.add(index), *types.add(index)))
+        .collect();
+    match build(&values) {
+        Ok(value) => value.into_datum().expect("fsvalue must not be null"),
+        Err(error) => error.report(),
+    }
+}
+
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_doc_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    build_variadic(fcinfo, build_doc)
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_doc_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_arr_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    build_variadic(fcinfo, build_arr)
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_arr_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_doc(VARIADIC \"any\") RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_doc_wrapper' \n\
+        LANGUAGE C STABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_doc() RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_doc_wrapper' \n\
+        LANGUAGE C STABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_arr(VARIADIC \"any\") RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_arr_wrapper' \n\
+        LANGUAGE C STABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_arr() RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_arr_wrapper' \n\
+        LANGUAGE C STABLE PARALLEL SAFE; \n\
+    ",
+    name = "variadic_builders",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_build::*;
+    use crate::fs_test_util::plain;
+    use serde_json::json;
+
+    fn doc(arguments: &str) -> Option<FsValue> {
+        Spi::get_one::<FsValue>(&format!("SELECT fs_doc({})", arguments)).expect("SPI failed")
+    }
+
+    #[pg_test]
+    fn test_fs_doc_native_values() {
+        assert_eq!(
+            doc(
+                "'name', 'bob', 'age', 3, 'score', 1.5::float8, 'ok', true, \
+                 'tags', ARRAY['a', 'b'], 'ratio', 0.25"
+            ),
+            Some(plain(json!({
+                "name": "bob",
+                "age": 3,
+                "score": 1.5,
+                "ok": true,
+                "tags": ["a", "b"],
+                "ratio": 0.25
+            })))
+        );
+        assert_eq!(
+            doc("'at', '2024-01-01T00:00:00Z'::timestamptz, 'blob', '\\x6869'::bytea"),
+            Some(FsValue::Map(
+                [
+                    ("at".to_owned(), FsValue::Timestamp(1_704_067_200_000_000)),
+                    ("blob".to_owned(), FsValue::Bytes(b"hi".to_vec())),
+                ]
+                .into()
+            ))
+        );
+        assert_eq!(doc(""), Some(plain(json!({}))));
+    }
+
+    #[pg_test]
+    fn test_fs_doc_fsvalue_and_nested() {
+        assert_eq!(
+            doc("'link', fs_reference('/users/1'), 'meta', fs_doc('n', 1, 'inner', fs_doc())"),
+            Some(FsValue::Map(
+                [
+                    ("link".to_owned(), crate::fs_reference("/users/1")),
+                    ("meta".to_owned(), plain(json!({"n": 1, "inner": {}}))),
+                ]
+                .into()
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_doc_null_values() {
+        assert_eq!(
+            doc("'a', NULL, 'b', NULL::integer, 'c', ARRAY[1, NULL]"),
+            Some(plain(json!({"a": null, "b": null, "c": [1, null]})))
+        );
+        assert_eq!(doc("VARIADIC NULL::text[]"), None);
+    }
+
+    #[pg_test(error = "InvalidValue: Argument 5 of fs_doc repeats the key 'a'")]
+    fn test_fs_doc_duplicate_key() {
+        doc("'a', 1, 'b', 2, 'a', 3");
+    }
+
+    #[pg_test(
+        error = "InvalidValue: fs_doc expects alternating keys and values but found 3 arguments"
+    )]
+    fn test_fs_doc_odd_arguments() {
+        doc("'a', 1, 'b'");
+    }
+
+    #[pg_test(
+        error = "InvalidType: Argument 3 of fs_doc is a key and must be text but found integer"
+    )]
+    fn test_fs_doc_non_text_key() {
+        doc("'a', 1, 2, 3");
+    }
+
+    #[pg_test]
+    fn test_fs_value() {
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT fs_value(ARRAY[1.5, 2]::float8[])")
+                .expect("SPI failed"),
+            Some(plain(json!([1.5, 2.0])))
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT fs_value('{\"a\": [1]}'::jsonb)").expect("SPI failed"),
+            Some(plain(json!({"a": [1]})))
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Argument 2 of fs_doc: Cannot convert point to an fsvalue")]
+    fn test_fs_doc_unsupported_value() {
+        doc("'at', point(1, 2)");
+    }
+
+    #[pg_test(error = "InvalidType: Cannot convert point to an fsvalue")]
+    fn test_fs_value_unsupported_type() {
+        Spi::get_one::<FsValue>("SELECT fs_value(point(1, 2))").expect("SPI failed");
+    }
+
+    fn arr(arguments: &str) -> Option<FsValue> {
+        Spi::get_one::<FsValue>(&format!("SELECT fs_arr({})", arguments)).expect("SPI failed")
+    }
+
+    #[pg_test]
+    fn test_fs_arr() {
+        assert_eq!(
+            arr("1, 2.5, 'x', fs_reference('/users/1')"),
+            Some(FsValue::Array(vec![
+                plain(json!(1)),
+                plain(json!(2.5)),
+                plain(json!("x")),
+                crate::fs_reference("/users/1"),
+            ]))
+        );
+        assert_eq!(arr(""), Some(FsValue::Array(vec![])));
+        assert_eq!(arr("NULL, 1"), Some(plain(json!([null, 1]))));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_arr(1, 2, 3) #= fs_array(ARRAY[fs_number_from_integer(1), \
+                     fs_number_from_integer(2), fs_number_from_integer(3)])"
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+        // Directly nested arrays are only rejected with strict limits
+        Spi::run("SET LOCAL pgfirestore.strict_limits = off").expect("SPI failed");
+        assert_eq!(arr("1, fs_arr(2)"), Some(plain(json!([1, [2]]))));
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Array element at index 1 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_fs_arr_nesting_strict() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        arr("1, fs_arr(2)");
+    }
+}
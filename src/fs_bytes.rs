@@ -0,0 +1,191 @@
+use crate::{fs_guc, FsError, FsValue};
+use pgrx::prelude::*;
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserializer, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// Firestore's limit on the size of a single field value, 1 MiB - 89 bytes
+pub(crate) const MAX_BYTES_VALUE: usize = 1_048_487;
+
+// A BYTES value, which must fit Firestore's limit when
+// pgfirestore.strict_limits is on
+pub(crate) fn bytes_value(bytes: Vec<u8>) -> Result<FsValue> {
+    if fs_guc::STRICT_LIMITS.get() && bytes.len() > MAX_BYTES_VALUE {
+        return Err(FsError::LimitExceeded(format!(
+            "Bytes value of {} bytes exceeds Firestore's limit of {} bytes",
+            bytes.len(),
+            MAX_BYTES_VALUE
+        )));
+    }
+    Ok(FsValue::Bytes(bytes))
+}
+
+// Stored datums hold a BYTES payload as a byte string, which is read back
+// with a single copy. Datums written before held it as a sequence of
+// integers, one per byte, and are still read.
+pub(crate) fn serialize_payload<S>(
+    bytes: &[u8],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_bytes(bytes)
+}
+
+pub(crate) fn deserialize_payload<'de, D>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_byte_buf(PayloadVisitor)
+}
+
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte string or a sequence of bytes")
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> std::result::Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> std::result::Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Vec<u8>, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+// The sha256 of a BYTES value, to compare large payloads by a stored or
+// indexed digest instead of by their contents
+#[pg_extern(immutable, parallel_safe)]
+fn fs_bytes_digest(value: FsValue) -> Vec<u8> {
+    match value {
+        FsValue::Bytes(bytes) => Sha256::digest(bytes).to_vec(),
+        other => FsError::InvalidType(format!(
+            "Expecting a BYTES value but found {}",
+            other.type_name()
+        ))
+        .report(),
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_bytes::*;
+
+    #[test]
+    fn test_deserialize_payload() {
+        use serde::de::value::{BytesDeserializer, Error, SeqDeserializer};
+        use serde::de::IntoDeserializer;
+        let payload = vec![0, 1, 127, 128, 255];
+        assert_eq!(
+            deserialize_payload(BytesDeserializer::<Error>::new(&payload)),
+            Ok(payload.to_owned())
+        );
+        assert_eq!(
+            deserialize_payload(SeqDeserializer::<_, Error>::new(payload.iter().copied())),
+            Ok(payload.to_owned())
+        );
+        assert_eq!(
+            deserialize_payload(payload.to_owned().into_deserializer()),
+            Ok::<_, Error>(payload.to_owned())
+        );
+        assert!(
+            deserialize_payload(SeqDeserializer::<_, Error>::new([0, 256].into_iter())).is_err()
+        );
+    }
+
+    #[test]
+    fn test_bytes_serde_round_trip() {
+        let value = FsValue::Map(
+            [(
+                "blob".to_owned(),
+                FsValue::Array(vec![
+                    FsValue::Bytes(vec![]),
+                    FsValue::Bytes((0..=255).collect()),
+                ]),
+            )]
+            .into(),
+        );
+        let text = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<FsValue>(&text).unwrap(), value);
+    }
+
+    #[test]
+    fn test_bytes_ordering() {
+        let bytes = |bytes: &[u8]| FsValue::Bytes(bytes.to_vec());
+        // Unsigned bytes from the first one on, and a prefix sorts first
+        let mut large = vec![1; 5 * 1024 * 1024];
+        assert!(bytes(&[0, 255]) < FsValue::Bytes(large.to_owned()));
+        assert!(bytes(&[1]) < FsValue::Bytes(large.to_owned()));
+        assert!(bytes(&[2]) > FsValue::Bytes(large.to_owned()));
+        assert!(bytes(&[]) < bytes(&[0]));
+        assert!(bytes(&[127]) < bytes(&[128]));
+        let other = large.to_owned();
+        large[0] = 0;
+        assert!(FsValue::Bytes(large.to_owned()) < FsValue::Bytes(other.to_owned()));
+        assert_ne!(FsValue::Bytes(large.to_owned()), FsValue::Bytes(other));
+        large.push(0);
+        assert_ne!(FsValue::Bytes(large), bytes(&[0]));
+    }
+
+    #[pg_test]
+    fn test_bytes_limit() {
+        let at_limit = vec![0; MAX_BYTES_VALUE];
+        let over_limit = vec![0; MAX_BYTES_VALUE + 1];
+        assert!(bytes_value(over_limit.to_owned()).is_ok());
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert!(bytes_value(at_limit).is_ok());
+        assert_eq!(
+            bytes_value(over_limit).unwrap_err().to_string(),
+            "LimitExceeded: Bytes value of 1048488 bytes exceeds Firestore's limit of 1048487 bytes"
+        );
+    }
+
+    #[pg_test(
+        error = "LimitExceeded: Bytes value of 1048488 bytes exceeds Firestore's limit of 1048487 bytes"
+    )]
+    fn test_fs_bytes_strict_limit() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        Spi::run("SELECT fs_bytes(convert_to(repeat('x', 1048488), 'UTF8'))").expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_bytes_limit_off() {
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT length(fs_bytes_digest(fs_bytes(convert_to(repeat('x', 1048488), 'UTF8'))))"
+            ),
+            Ok(Some(32))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_bytes_digest(fs_bytes('\\x0001')) = sha256('\\x0001'::bytea)"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a BYTES value but found STRING")]
+    fn test_fs_bytes_digest_not_bytes() {
+        fs_bytes_digest(FsValue::String("x".to_owned()));
+    }
+}
@@ -0,0 +1,518 @@
+use crate::fs_display::display_value;
+use crate::fs_error::FsError;
+use crate::fs_number::number_to_bigdecimal;
+use crate::fs_reference::FsReference;
+use crate::fs_timestamp::{check_range, format_timestamp, parse_timestamp};
+use crate::{FsNumber, FsValue};
+use base64::{engine::general_purpose, Engine as _};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use pgrx::prelude::*;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+const TYPE_NAMES: [&str; 11] = [
+    "NULL",
+    "BOOLEAN",
+    "NUMBER",
+    "DATE",
+    "TIMESTAMP",
+    "STRING",
+    "BYTES",
+    "REFERENCE",
+    "GEOPOINT",
+    "ARRAY",
+    "MAP",
+];
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+
+// Conversions besides the identity, by target type. Strings are read with
+// surrounding whitespace ignored.
+//
+//   NUMBER     STRING holding a number ('NaN', 'Infinity' and '-Infinity'
+//              included), BOOLEAN as 1 or 0
+//   STRING     BOOLEAN, NUMBER, TIMESTAMP (RFC 3339), REFERENCE (its path)
+//              and BYTES (base64), as in the text format
+//   BOOLEAN    STRING 'true' or 'false', NUMBER 1 or 0
+//   TIMESTAMP  STRING in RFC 3339, NUMBER of seconds since the Unix epoch in
+//              whole microseconds
+//   REFERENCE  STRING holding a path
+//
+// Nothing converts to NULL, DATE, BYTES, GEOPOINT, ARRAY or MAP, and NULL,
+// DATE, GEOPOINT, ARRAY and MAP convert to nothing.
+fn cast(value: FsValue, target: &str) -> Result<FsValue> {
+    if value.type_name() == target {
+        return Ok(value);
+    }
+    let context = || format!("Cannot cast {} to {}", display_value(&value), target);
+    let unconvertible = || FsError::InvalidValue(context());
+    match (target, &value) {
+        ("NUMBER", FsValue::String(text)) => FsNumber::from_str(text.trim())
+            .map(FsValue::Number)
+            .map_err(|error| error.with_context(&context())),
+        ("NUMBER", FsValue::Boolean(boolean)) => Ok(FsValue::Number(FsNumber::Number(
+            serde_json::Number::from(*boolean as i64),
+        ))),
+        ("STRING", FsValue::Boolean(boolean)) => Ok(FsValue::String(boolean.to_string())),
+        ("STRING", FsValue::Number(number)) => Ok(FsValue::String(match number {
+            FsNumber::NAN => "NaN".to_owned(),
+            FsNumber::PositiveInfinity => "Infinity".to_owned(),
+            FsNumber::NegativeInfinity => "-Infinity".to_owned(),
+            FsNumber::Number(number) => number.to_string(),
+        })),
+        ("STRING", FsValue::Timestamp(micros)) => Ok(FsValue::String(format_timestamp(*micros))),
+        ("STRING", FsValue::Reference(reference)) => Ok(FsValue::String(reference.to_string())),
+        ("STRING", FsValue::Bytes(bytes)) => {
+            Ok(FsValue::String(general_purpose::STANDARD.encode(bytes)))
+        }
+        ("BOOLEAN", FsValue::String(text)) => match text.trim() {
+            "true" => Ok(FsValue::Boolean(true)),
+            "false" => Ok(FsValue::Boolean(false)),
+            _ => Err(unconvertible()),
+        },
+        ("BOOLEAN", FsValue::Number(FsNumber::Number(number))) => match number.as_f64() {
+            Some(1.0) => Ok(FsValue::Boolean(true)),
+            Some(0.0) => Ok(FsValue::Boolean(false)),
+            _ => Err(unconvertible()),
+        },
+        ("TIMESTAMP", FsValue::String(text)) => parse_timestamp(text.trim())
+            .map(FsValue::Timestamp)
+            .map_err(|error| error.with_context(&context())),
+        ("TIMESTAMP", FsValue::Number(FsNumber::Number(seconds))) => {
+            let micros = number_to_bigdecimal(seconds) * BigDecimal::from(MICROS_PER_SECOND);
+            if !micros.is_integer() {
+                return Err(unconvertible());
+            }
+            micros
+                .to_i64()
+                .ok_or_else(unconvertible)
+                .and_then(|micros| {
+                    check_range(micros).map_err(|error| error.with_context(&context()))
+                })
+                .map(FsValue::Timestamp)
+        }
+        ("REFERENCE", FsValue::String(text)) => FsReference::from_str(text.trim())
+            .map(FsValue::Reference)
+            .map_err(|error| error.with_context(&context())),
+        _ => Err(FsError::InvalidType(format!(
+            "Cannot cast {} to {}",
+            value.type_name(),
+            target
+        ))),
+    }
+}
+
+fn expect_target(target_type: &str) -> &str {
+    if !TYPE_NAMES.contains(&target_type) {
+        FsError::InvalidValue(format!(
+            "Unknown target type '{}', expecting one of {}",
+            target_type,
+            TYPE_NAMES.join(", ")
+        ))
+        .report()
+    }
+    target_type
+}
+
+// NULL when the value does not convert. An unknown target type is still an
+// error.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_safe_cast(value: FsValue, target_type: &str) -> Option<FsValue> {
+    cast(value, expect_target(target_type)).ok()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_cast(value: FsValue, target_type: &str) -> FsValue {
+    match cast(value, expect_target(target_type)) {
+        Ok(value) => value,
+        Err(error) => error.report(),
+    }
+}
+
+// Subtypes of NUMBER that fs_is_type tells apart
+const NUMBER_TYPE_NAMES: [&str; 2] = ["NUMBER_INTEGER", "NUMBER_DOUBLE"];
+
+fn is_type(value: &FsValue, type_name: &str) -> bool {
+    match (type_name, value) {
+        ("NUMBER_INTEGER", FsValue::Number(number)) => number.is_integer(),
+        ("NUMBER_DOUBLE", FsValue::Number(number)) => !number.is_integer(),
+        _ => value.type_name() == type_name,
+    }
+}
+
+// Whether a value is of a type, for the predicates of partial indexes over
+// fields holding values of mixed types. NUMBER_INTEGER and NUMBER_DOUBLE
+// tell apart how a NUMBER is stored, with NaN and the infinities being
+// doubles. An unknown type name is an error.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_type(value: FsValue, type_name: &str) -> bool {
+    if !TYPE_NAMES.contains(&type_name) && !NUMBER_TYPE_NAMES.contains(&type_name) {
+        FsError::InvalidValue(format!(
+            "Unknown type '{}', expecting one of {}, {}",
+            type_name,
+            TYPE_NAMES.join(", "),
+            NUMBER_TYPE_NAMES.join(", ")
+        ))
+        .report()
+    }
+    is_type(&value, type_name)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_cast::*;
+    use crate::fs_reference;
+    use crate::fs_test_util::explain;
+    use std::collections::BTreeMap;
+
+    fn number(text: &str) -> FsValue {
+        FsValue::Number(FsNumber::from_str(text).unwrap())
+    }
+
+    fn string(text: &str) -> FsValue {
+        FsValue::String(text.to_owned())
+    }
+
+    fn timestamp(text: &str) -> FsValue {
+        FsValue::Timestamp(parse_timestamp(text).unwrap())
+    }
+
+    // One value of every type
+    fn samples() -> Vec<FsValue> {
+        vec![
+            FsValue::NULL,
+            FsValue::Boolean(true),
+            number("1"),
+            FsValue::Date(pgrx::Date::from(0)),
+            timestamp("2024-01-31T12:00:00.5Z"),
+            string("x"),
+            FsValue::Bytes(vec![0, 255]),
+            fs_reference("/users/1"),
+            FsValue::GeoPoint(
+                FsNumber::from_str("1").unwrap(),
+                FsNumber::from_str("2").unwrap(),
+            ),
+            FsValue::Array(vec![number("1")]),
+            FsValue::Map(BTreeMap::from([("a".to_owned(), number("1"))])),
+        ]
+    }
+
+    #[test]
+    fn test_cast_identity() {
+        for value in samples() {
+            assert_eq!(cast(value.to_owned(), value.type_name()).unwrap(), value);
+        }
+        // Values a lenient cast would otherwise touch stay as they are
+        for value in [string(" 1 "), number("NaN"), number("0.1")] {
+            assert_eq!(cast(value.to_owned(), value.type_name()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_cast_matrix() {
+        // The source types that convert to each target, besides the target
+        // itself. The samples of these types all convert except the STRING
+        // "x", which holds neither a number, a boolean, a timestamp nor a
+        // path.
+        let supported = |target: &str| -> &[&str] {
+            match target {
+                "NUMBER" => &["STRING", "BOOLEAN"],
+                "STRING" => &["BOOLEAN", "NUMBER", "TIMESTAMP", "REFERENCE", "BYTES"],
+                "BOOLEAN" => &["STRING", "NUMBER"],
+                "TIMESTAMP" => &["STRING", "NUMBER"],
+                "REFERENCE" => &["STRING"],
+                _ => &[],
+            }
+        };
+        for target in TYPE_NAMES {
+            for value in samples() {
+                let source = value.type_name();
+                if source == target {
+                    continue;
+                }
+                match cast(value.to_owned(), target) {
+                    Ok(cast_value) => {
+                        assert!(supported(target).contains(&source), "{source} to {target}");
+                        assert_eq!(cast_value.type_name(), target);
+                    }
+                    Err(FsError::InvalidType(message)) => {
+                        assert!(!supported(target).contains(&source), "{source} to {target}");
+                        assert_eq!(message, format!("Cannot cast {} to {}", source, target));
+                    }
+                    Err(error) => {
+                        assert_eq!(source, "STRING", "{source} to {target}: {error}");
+                        assert!(supported(target).contains(&source));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cast_to_number() {
+        assert_eq!(cast(string(" 42 "), "NUMBER").unwrap(), number("42"));
+        assert_eq!(cast(string("-1.5e3"), "NUMBER").unwrap(), number("-1500.0"));
+        assert_eq!(
+            cast(string("Infinity"), "NUMBER").unwrap(),
+            number("Infinity")
+        );
+        assert_eq!(cast(FsValue::Boolean(true), "NUMBER").unwrap(), number("1"));
+        assert_eq!(
+            cast(FsValue::Boolean(false), "NUMBER").unwrap(),
+            number("0")
+        );
+        for text in ["", "1,000", "0x10", "one", "0.12345678901234567890"] {
+            assert!(cast(string(text), "NUMBER").is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_cast_to_string() {
+        assert_eq!(
+            cast(FsValue::Boolean(false), "STRING").unwrap(),
+            string("false")
+        );
+        assert_eq!(cast(number("7"), "STRING").unwrap(), string("7"));
+        assert_eq!(cast(number("0.5"), "STRING").unwrap(), string("0.5"));
+        assert_eq!(
+            cast(number("-Infinity"), "STRING").unwrap(),
+            string("-Infinity")
+        );
+        assert_eq!(
+            cast(timestamp("2024-01-31T13:00:00+01:00"), "STRING").unwrap(),
+            string("2024-01-31T12:00:00Z")
+        );
+        assert_eq!(
+            cast(fs_reference("/users/a%2Fb"), "STRING").unwrap(),
+            string("/users/a%2Fb")
+        );
+        assert_eq!(
+            cast(FsValue::Bytes(b"hi".to_vec()), "STRING").unwrap(),
+            string("aGk=")
+        );
+    }
+
+    #[test]
+    fn test_cast_to_boolean() {
+        assert_eq!(
+            cast(string("true"), "BOOLEAN").unwrap(),
+            FsValue::Boolean(true)
+        );
+        assert_eq!(
+            cast(string(" false"), "BOOLEAN").unwrap(),
+            FsValue::Boolean(false)
+        );
+        assert_eq!(
+            cast(number("1"), "BOOLEAN").unwrap(),
+            FsValue::Boolean(true)
+        );
+        assert_eq!(
+            cast(number("0.0"), "BOOLEAN").unwrap(),
+            FsValue::Boolean(false)
+        );
+        for value in [
+            string("TRUE"),
+            string("yes"),
+            string("1"),
+            number("2"),
+            number("0.5"),
+            number("NaN"),
+        ] {
+            assert!(cast(value.to_owned(), "BOOLEAN").is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_cast_to_timestamp() {
+        assert_eq!(
+            cast(string("2024-01-31T12:00:00+01:00"), "TIMESTAMP").unwrap(),
+            timestamp("2024-01-31T11:00:00Z")
+        );
+        assert_eq!(
+            cast(number("1706702400"), "TIMESTAMP").unwrap(),
+            timestamp("2024-01-31T12:00:00Z")
+        );
+        assert_eq!(
+            cast(number("-0.000001"), "TIMESTAMP").unwrap(),
+            timestamp("1969-12-31T23:59:59.999999Z")
+        );
+        for value in [
+            string("2024-01-31"),
+            string("yesterday"),
+            number("0.0000001"),
+            number("1e12"),
+            number("Infinity"),
+        ] {
+            assert!(cast(value.to_owned(), "TIMESTAMP").is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn test_cast_to_reference() {
+        assert_eq!(
+            cast(string(" /users/1/posts/2 "), "REFERENCE").unwrap(),
+            fs_reference("/users/1/posts/2")
+        );
+        for text in ["users/1", "hello", ""] {
+            assert!(cast(string(text), "REFERENCE").is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_cast_round_trip() {
+        // Through STRING and back gives the value again
+        for value in [
+            FsValue::Boolean(true),
+            number("-12"),
+            number("0.1"),
+            number("NaN"),
+            timestamp("2024-01-31T12:00:00.123456Z"),
+            fs_reference("/users/1/posts/%31"),
+        ] {
+            let text = cast(value.to_owned(), "STRING").unwrap();
+            assert_eq!(cast(text, value.type_name()).unwrap(), value);
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_safe_cast() {
+        assert_eq!(fs_safe_cast(string("3"), "NUMBER"), Some(number("3")));
+        assert_eq!(fs_safe_cast(string("three"), "NUMBER"), None);
+        assert_eq!(fs_safe_cast(FsValue::NULL, "STRING"), None);
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT fs_safe_cast(fs_string('true'), 'BOOLEAN')"),
+            Ok(Some(FsValue::Boolean(true)))
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT fs_safe_cast(fs_array(ARRAY[]::fsvalue[]), 'STRING')"),
+            Ok(None)
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Unknown target type 'number', expecting one of NULL, BOOLEAN, NUMBER, DATE, TIMESTAMP, STRING, BYTES, REFERENCE, GEOPOINT, ARRAY, MAP"
+    )]
+    fn test_fs_safe_cast_unknown_type() {
+        fs_safe_cast(string("3"), "number");
+    }
+
+    #[pg_test]
+    fn test_fs_cast() {
+        assert_eq!(fs_cast(FsValue::Boolean(true), "NUMBER"), number("1"));
+        assert_eq!(fs_cast(number("2"), "NUMBER"), number("2"));
+    }
+
+    #[pg_test(error = "InvalidType: Cannot cast MAP to STRING")]
+    fn test_fs_cast_unsupported() {
+        fs_cast(FsValue::Map(BTreeMap::new()), "STRING");
+    }
+
+    #[pg_test(error = "InvalidValue: Cannot cast 2 to BOOLEAN")]
+    fn test_fs_cast_unconvertible() {
+        fs_cast(number("2"), "BOOLEAN");
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Cannot cast \"abc\" to NUMBER: Failed to parse cstring ('abc') as a FsNumber: invalid number at line 1 column 1"
+    )]
+    fn test_fs_cast_invalid_number() {
+        fs_cast(string("abc"), "NUMBER");
+    }
+
+    #[test]
+    fn test_is_type() {
+        for value in samples() {
+            for type_name in TYPE_NAMES {
+                assert_eq!(is_type(&value, type_name), value.type_name() == type_name);
+            }
+        }
+        for (text, integer) in [
+            ("1", true),
+            ("-1", true),
+            ("18446744073709551615", true),
+            ("1.0", false),
+            ("0.5", false),
+            ("NaN", false),
+            ("Infinity", false),
+            ("-Infinity", false),
+        ] {
+            assert_eq!(
+                is_type(&number(text), "NUMBER_INTEGER"),
+                integer,
+                "{}",
+                text
+            );
+            assert_eq!(
+                is_type(&number(text), "NUMBER_DOUBLE"),
+                !integer,
+                "{}",
+                text
+            );
+        }
+        assert!(!is_type(&string("1"), "NUMBER_INTEGER"));
+        assert!(!is_type(&string("1.5"), "NUMBER_DOUBLE"));
+    }
+
+    #[pg_test]
+    fn test_fs_is_type_partial_index() {
+        // Scores of every other document are strings
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             SELECT fs_reference('/scores/' || i), fs_map_from_entries(ARRAY['score'], \
+                 ARRAY[CASE WHEN i % 2 = 0 THEN fs_number_from_integer(i) ELSE fs_string(i::text) END]) \
+             FROM generate_series(1, 2000) AS i; \
+             CREATE INDEX fs_documents_numeric_score ON fs_documents ((properties->'score')) \
+                 WHERE fs_is_type(properties->'score', 'NUMBER'); \
+             ANALYZE fs_documents; \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<f32>(
+                "SELECT reltuples FROM pg_class WHERE relname = 'fs_documents_numeric_score'"
+            ),
+            Ok(Some(1000.0))
+        );
+        let query = "SELECT reference FROM fs_documents \
+             WHERE fs_is_type(properties->'score', 'NUMBER') AND properties->'score' > 1990";
+        let plan = explain(query, true);
+        assert!(plan.contains("fs_documents_numeric_score"), "{}", plan);
+        assert_eq!(
+            Spi::get_one::<i64>(&format!("SELECT count(*) FROM ({}) AS q", query)),
+            Ok(Some(5))
+        );
+        // Without the type check, the index does not cover the query
+        let plan = explain(
+            "SELECT reference FROM fs_documents WHERE properties->'score' > 1990",
+            true,
+        );
+        assert!(!plan.contains("fs_documents_numeric_score"), "{}", plan);
+    }
+
+    #[pg_test]
+    fn test_fs_is_type() {
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_is_type(fs_number_from_integer(1), 'NUMBER') \
+                 AND fs_is_type(fs_number_from_integer(1), 'NUMBER_INTEGER') \
+                 AND NOT fs_is_type(fs_number_from_integer(1), 'NUMBER_DOUBLE') \
+                 AND fs_is_type(fs_number_from_double(1.5), 'NUMBER_DOUBLE') \
+                 AND NOT fs_is_type(fs_string('1'), 'NUMBER')"
+            ),
+            Ok(Some(true))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT fs_is_type(NULL::fsvalue, 'NULL')"),
+            Ok(None)
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Unknown type 'INTEGER', expecting one of NULL, BOOLEAN, NUMBER, DATE, TIMESTAMP, STRING, BYTES, REFERENCE, GEOPOINT, ARRAY, MAP, NUMBER_INTEGER, NUMBER_DOUBLE"
+    )]
+    fn test_fs_is_type_unknown_type() {
+        fs_is_type(number("1"), "INTEGER");
+    }
+}
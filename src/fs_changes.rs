@@ -0,0 +1,474 @@
+use crate::fs_documents::fsvalue_arg;
+use crate::FsError;
+use crate::FsValue;
+use pgrx::heap_tuple::PgHeapTupleError;
+use pgrx::prelude::*;
+use pgrx::{PgOid, WhoAllocated};
+use std::collections::BTreeSet;
+
+type Change = (i64, FsValue, String, Option<FsValue>, TimestampWithTimeZone);
+
+fn record_change(reference: FsValue, change_type: &str, properties: Option<FsValue>) {
+    Spi::run_with_args(
+        "INSERT INTO fs_document_changes (reference, change_type, properties) VALUES ($1, $2, $3)",
+        Some(vec![
+            fsvalue_arg(reference),
+            (PgBuiltInOids::TEXTOID.oid(), change_type.into_datum()),
+            (PgOid::from(FsValue::type_oid()), properties.into_datum()),
+        ]),
+    )
+    .expect("Failed to write to fs_document_changes")
+}
+
+fn row_reference(row: &PgHeapTuple<'_, impl WhoAllocated>) -> FsValue {
+    row.get_by_name::<FsValue>("reference")
+        .expect("Failed to read reference from the row")
+        .expect("reference must not be null")
+}
+
+fn row_properties(row: &PgHeapTuple<'_, impl WhoAllocated>) -> Option<FsValue> {
+    row.get_by_name::<FsValue>("properties")
+        .expect("Failed to read properties from the row")
+}
+
+fn row_update_time(row: &PgHeapTuple<'_, impl WhoAllocated>) -> Option<TimestampWithTimeZone> {
+    row.get_by_name::<TimestampWithTimeZone>("update_time")
+        .expect("Failed to read update_time from the row")
+}
+
+// Tombstones left by soft deletes are not documents
+fn row_is_live(row: &PgHeapTuple<'_, impl WhoAllocated>) -> bool {
+    row.get_by_name::<TimestampWithTimeZone>("deleted_at")
+        .expect("Failed to read deleted_at from the row")
+        .is_none()
+}
+
+// Logs every write to fs_documents, including direct SQL. An UPDATE moving a
+// document to another reference is logged as a delete and an insert. Soft
+// deleting a document is logged as a delete and writing over its tombstone as
+// an insert, while purging tombstones is not logged again. An UPDATE that
+// leaves properties and update_time alone, such as one filling in
+// content_hash, changes nothing a consumer sees and is not logged.
+#[pg_trigger]
+fn fs_documents_change_log<'a>(
+    trigger: &'a pgrx::PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PgHeapTupleError> {
+    let old = trigger.old().filter(row_is_live);
+    let new = trigger.new().filter(row_is_live);
+    match (&old, &new) {
+        (Some(old), Some(new)) if row_reference(old) == row_reference(new) => {
+            if row_properties(old) != row_properties(new)
+                || row_update_time(old) != row_update_time(new)
+            {
+                record_change(row_reference(new), "update", row_properties(new))
+            }
+        }
+        _ => {
+            if let Some(old) = &old {
+                record_change(row_reference(old), "delete", None);
+            }
+            if let Some(new) = &new {
+                record_change(row_reference(new), "insert", row_properties(new));
+            }
+        }
+    }
+    // The return value of an AFTER trigger is ignored
+    Ok(new)
+}
+
+// Keeps only the last change of every document, in seq order
+fn coalesce_changes(changes: Vec<Change>) -> Vec<Change> {
+    let mut seen = BTreeSet::new();
+    let mut latest: Vec<Change> = changes
+        .into_iter()
+        .rev()
+        .filter(|change| seen.insert(change.1.to_owned()))
+        .collect();
+    latest.reverse();
+    latest
+}
+
+// The first `limit_n` changes matching `condition` on $1. Coalescing happens
+// within that page, so the largest seq returned is still where the next page
+// starts.
+fn delta_stream(
+    condition: &str,
+    arg: (PgOid, Option<pg_sys::Datum>),
+    limit_n: i64,
+    coalesce: bool,
+) -> Vec<Change> {
+    if limit_n < 0 {
+        FsError::InvalidValue(format!("Limit must not be negative but found {}", limit_n)).report()
+    }
+    let changes = Spi::connect(|client| {
+        let mut changes = Vec::new();
+        for row in client.select(
+            &format!(
+                "SELECT seq, reference, change_type, properties, changed_at \
+                 FROM fs_document_changes WHERE {} ORDER BY seq LIMIT $2",
+                condition
+            ),
+            None,
+            Some(vec![
+                arg,
+                (PgBuiltInOids::INT8OID.oid(), limit_n.into_datum()),
+            ]),
+        )? {
+            changes.push((
+                row.get::<i64>(1)?.expect("seq must not be null"),
+                row.get::<FsValue>(2)?.expect("reference must not be null"),
+                row.get::<String>(3)?.expect("change type must not be null"),
+                row.get::<FsValue>(4)?,
+                row.get::<TimestampWithTimeZone>(5)?
+                    .expect("change time must not be null"),
+            ));
+        }
+        Ok::<_, pgrx::spi::Error>(changes)
+    })
+    .expect("Failed to read from fs_document_changes");
+    if coalesce {
+        coalesce_changes(changes)
+    } else {
+        changes
+    }
+}
+
+// Changes made strictly after `since`. Clocks can step back, so consumers
+// paging through the feed should prefer fs_delta_stream_since_seq.
+#[pg_extern]
+fn fs_delta_stream(
+    since: TimestampWithTimeZone,
+    limit_n: default!(i64, 1000),
+    coalesce: default!(bool, false),
+) -> TableIterator<
+    'static,
+    (
+        name!(seq, i64),
+        name!(reference, FsValue),
+        name!(change_type, String),
+        name!(properties, Option<FsValue>),
+        name!(changed_at, TimestampWithTimeZone),
+    ),
+> {
+    TableIterator::new(
+        delta_stream(
+            "changed_at > $1",
+            (PgBuiltInOids::TIMESTAMPTZOID.oid(), since.into_datum()),
+            limit_n,
+            coalesce,
+        )
+        .into_iter(),
+    )
+}
+
+// Changes with a seq greater than `since_seq`, e.g. the last seq of the
+// previous page
+#[pg_extern]
+fn fs_delta_stream_since_seq(
+    since_seq: i64,
+    limit_n: default!(i64, 1000),
+    coalesce: default!(bool, false),
+) -> TableIterator<
+    'static,
+    (
+        name!(seq, i64),
+        name!(reference, FsValue),
+        name!(change_type, String),
+        name!(properties, Option<FsValue>),
+        name!(changed_at, TimestampWithTimeZone),
+    ),
+> {
+    TableIterator::new(
+        delta_stream(
+            "seq > $1",
+            (PgBuiltInOids::INT8OID.oid(), since_seq.into_datum()),
+            limit_n,
+            coalesce,
+        )
+        .into_iter(),
+    )
+}
+
+// Created after the seed data so that the log starts out empty
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_document_changes (\n\
+            seq bigserial PRIMARY KEY,\n\
+            reference fsvalue NOT NULL,\n\
+            change_type text NOT NULL,\n\
+            properties fsvalue,\n\
+            changed_at timestamptz NOT NULL DEFAULT clock_timestamp()\n\
+        );\n\
+        CREATE INDEX ON fs_document_changes (changed_at);\n\
+    ",
+    name = "document_changes_table",
+    requires = ["main_table", "seed_data"],
+);
+
+extension_sql!(
+    "\n\
+        CREATE TRIGGER fs_documents_change_log \n\
+        AFTER INSERT OR UPDATE OR DELETE ON fs_documents \n\
+        FOR EACH ROW EXECUTE PROCEDURE fs_documents_change_log(); \n\
+    ",
+    name = "document_changes_trigger",
+    requires = [
+        "main_table",
+        "document_changes_table",
+        fs_documents_change_log
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_changes::*;
+    use crate::{fs_map_from_entries, fs_number_from_integer, fs_reference};
+
+    fn properties(n: i32) -> FsValue {
+        fs_map_from_entries(vec!["n".to_owned()], vec![fs_number_from_integer(n)])
+    }
+
+    // The seq right before the next change. Sequences are not rolled back
+    // with the tests that used them, so this takes a value of its own rather
+    // than looking at the changes left in the table.
+    fn last_seq() -> i64 {
+        Spi::get_one::<i64>("SELECT nextval(pg_get_serial_sequence('fs_document_changes', 'seq'))")
+            .expect("SPI failed")
+            .expect("nextval must not be null")
+    }
+
+    // (seq offset, reference, change type, properties) of every change
+    fn summary(changes: Vec<Change>, base: i64) -> Vec<(i64, FsValue, String, Option<FsValue>)> {
+        changes
+            .into_iter()
+            .map(|(seq, reference, change_type, properties, _)| {
+                (seq - base, reference, change_type, properties)
+            })
+            .collect()
+    }
+
+    // Three changes to two documents
+    fn write_changes() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/deltas/a'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)])); \
+             SELECT fs_set(fs_reference('/deltas/b'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(2)])); \
+             SELECT fs_set(fs_reference('/deltas/a'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(3)]))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_delta_stream_since_seq() {
+        let base = last_seq();
+        write_changes();
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 1000, false).collect(), base),
+            vec![
+                (
+                    1,
+                    fs_reference("/deltas/a"),
+                    "insert".to_owned(),
+                    Some(properties(1))
+                ),
+                (
+                    2,
+                    fs_reference("/deltas/b"),
+                    "insert".to_owned(),
+                    Some(properties(2))
+                ),
+                (
+                    3,
+                    fs_reference("/deltas/a"),
+                    "update".to_owned(),
+                    Some(properties(3))
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 1000, true).collect(), base),
+            vec![
+                (
+                    2,
+                    fs_reference("/deltas/b"),
+                    "insert".to_owned(),
+                    Some(properties(2))
+                ),
+                (
+                    3,
+                    fs_reference("/deltas/a"),
+                    "update".to_owned(),
+                    Some(properties(3))
+                ),
+            ]
+        );
+
+        // Paging by the last seq of the previous page
+        assert_eq!(fs_delta_stream_since_seq(base, 2, false).count(), 2);
+        assert_eq!(
+            summary(
+                fs_delta_stream_since_seq(base + 2, 2, false).collect(),
+                base
+            ),
+            vec![(
+                3,
+                fs_reference("/deltas/a"),
+                "update".to_owned(),
+                Some(properties(3))
+            )]
+        );
+        assert_eq!(fs_delta_stream_since_seq(base + 3, 2, false).count(), 0);
+        // Coalescing within a page keeps the page boundary
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 2, true).collect(), base),
+            vec![
+                (
+                    1,
+                    fs_reference("/deltas/a"),
+                    "insert".to_owned(),
+                    Some(properties(1))
+                ),
+                (
+                    2,
+                    fs_reference("/deltas/b"),
+                    "insert".to_owned(),
+                    Some(properties(2))
+                ),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_delta_stream_deletes() {
+        let base = last_seq();
+        write_changes();
+        Spi::run(
+            "DELETE FROM fs_documents WHERE reference = fs_reference('/deltas/b'); \
+             UPDATE fs_documents SET reference = fs_reference('/deltas/c') WHERE reference = fs_reference('/deltas/a')",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            summary(
+                fs_delta_stream_since_seq(base + 3, 1000, false).collect(),
+                base
+            ),
+            vec![
+                (4, fs_reference("/deltas/b"), "delete".to_owned(), None),
+                (5, fs_reference("/deltas/a"), "delete".to_owned(), None),
+                (
+                    6,
+                    fs_reference("/deltas/c"),
+                    "insert".to_owned(),
+                    Some(properties(3))
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 1000, true).collect(), base),
+            vec![
+                (4, fs_reference("/deltas/b"), "delete".to_owned(), None),
+                (5, fs_reference("/deltas/a"), "delete".to_owned(), None),
+                (
+                    6,
+                    fs_reference("/deltas/c"),
+                    "insert".to_owned(),
+                    Some(properties(3))
+                ),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_delta_stream() {
+        let base = last_seq();
+        write_changes();
+        let since = Spi::get_one_with_args::<TimestampWithTimeZone>(
+            "SELECT changed_at FROM fs_document_changes WHERE seq = $1",
+            vec![(PgBuiltInOids::INT8OID.oid(), (base + 1).into_datum())],
+        )
+        .expect("SPI failed")
+        .expect("change must exist");
+        // clock_timestamp() moves on between the writes
+        assert_eq!(
+            summary(fs_delta_stream(since, 1000, true).collect(), base),
+            vec![
+                (
+                    2,
+                    fs_reference("/deltas/b"),
+                    "insert".to_owned(),
+                    Some(properties(2))
+                ),
+                (
+                    3,
+                    fs_reference("/deltas/a"),
+                    "update".to_owned(),
+                    Some(properties(3))
+                ),
+            ]
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Limit must not be negative but found -1")]
+    fn test_fs_delta_stream_negative_limit() {
+        fs_delta_stream_since_seq(0, -1, false);
+    }
+
+    #[pg_test]
+    fn test_soft_delete_changes() {
+        let base = last_seq();
+        Spi::run(
+            "SELECT fs_set(fs_reference('/deltas/a'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)])); \
+             SELECT fs_soft_delete(fs_reference('/deltas/a')); \
+             SELECT fs_set(fs_reference('/deltas/a'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(2)])); \
+             SELECT fs_soft_delete(fs_reference('/deltas/a')); \
+             UPDATE fs_documents SET deleted_at = '2000-01-01' WHERE reference = fs_reference('/deltas/a'); \
+             SELECT fs_purge_tombstones('1 day')",
+        )
+        .expect("SPI failed");
+        // Backdating and purging the tombstone are not changes of a document
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 1000, false).collect(), base),
+            vec![
+                (
+                    1,
+                    fs_reference("/deltas/a"),
+                    "insert".to_owned(),
+                    Some(properties(1))
+                ),
+                (2, fs_reference("/deltas/a"), "delete".to_owned(), None),
+                (
+                    3,
+                    fs_reference("/deltas/a"),
+                    "insert".to_owned(),
+                    Some(properties(2))
+                ),
+                (4, fs_reference("/deltas/a"), "delete".to_owned(), None),
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_unchanged_update_not_logged() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/deltas/a'), fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)]))",
+        )
+        .expect("SPI failed");
+        let base = last_seq();
+        Spi::run(
+            "UPDATE fs_documents SET content_hash = NULL WHERE reference = fs_reference('/deltas/a'); \
+             SELECT fs_rebuild_content_hashes(); \
+             SET LOCAL pgfirestore.fixed_request_time = '2000-01-01T00:00:00Z'; \
+             SELECT fs_touch(fs_reference('/deltas/a'))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            summary(fs_delta_stream_since_seq(base, 1000, false).collect(), base),
+            vec![(
+                1,
+                fs_reference("/deltas/a"),
+                "update".to_owned(),
+                Some(properties(1))
+            )]
+        );
+    }
+}
@@ -0,0 +1,809 @@
+use crate::fs_bytes::MAX_BYTES_VALUE;
+use crate::fs_documents::scan_documents;
+use crate::fs_error::panic_message;
+use crate::fs_etag::content_hash;
+use crate::fs_lint::{child_path, MAX_FIELD_NAME_BYTES};
+use crate::fs_reference::{FsReference, ResourceId};
+use crate::fs_reference_pattern::ReferencePattern;
+use crate::fs_schema::{allowed_patterns, is_allowed};
+use crate::{document_key_error, FsValue};
+use crate::{fs_guc, FsError};
+use pgrx::prelude::*;
+use pgrx::AnyElement;
+use std::panic;
+
+// Firestore limits, see https://firebase.google.com/docs/firestore/quotas
+const MAX_DOCUMENT_BYTES: usize = 1024 * 1024;
+const MAX_FIELD_DEPTH: usize = 20;
+// Added to the size of every document besides its name and fields
+const DOCUMENT_OVERHEAD_BYTES: usize = 32;
+
+const CHECKS: [&str; 5] = [
+    "valid_key",
+    "map_properties",
+    "size_limit",
+    "depth_limit",
+    "schema_allowed",
+];
+
+// Storage size of a document name, following
+// https://firebase.google.com/docs/firestore/storage-size
+fn reference_size(reference: &FsReference) -> usize {
+    let segments: usize = reference
+        .path
+        .0
+        .iter()
+        .map(|element| {
+            element.collection_id().len()
+                + 1
+                + match element.resource_id() {
+                    None => 0,
+                    Some(ResourceId::String(id)) => id.len() + 1,
+                    Some(ResourceId::Number(_)) => 8,
+                }
+        })
+        .sum();
+    segments + 16
+}
+
+fn value_size(value: &FsValue) -> usize {
+    match value {
+        FsValue::NULL | FsValue::Boolean(_) => 1,
+        FsValue::Number(_) | FsValue::Date(_) | FsValue::Timestamp(_) => 8,
+        FsValue::String(string) => string.len() + 1,
+        FsValue::Bytes(bytes) => bytes.len(),
+        FsValue::Reference(reference) => reference_size(reference),
+        FsValue::GeoPoint(_, _) => 16,
+        FsValue::Array(array) => array.iter().map(value_size).sum(),
+        FsValue::Map(map) => map
+            .iter()
+            .map(|(key, value)| key.len() + 1 + value_size(value))
+            .sum(),
+    }
+}
+
+fn document_size(reference: &FsReference, properties: &FsValue) -> usize {
+    reference_size(reference) + value_size(properties) + DOCUMENT_OVERHEAD_BYTES
+}
+
+// Levels of maps and arrays below `value`
+fn nesting_depth(value: &FsValue) -> usize {
+    match value {
+        FsValue::Array(array) => 1 + array.iter().map(nesting_depth).max().unwrap_or(0),
+        FsValue::Map(map) => 1 + map.values().map(nesting_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// The depth of the deepest field, fields of the properties map being at
+// depth 1, i.e. the levels of maps and arrays from the properties map down
+fn depth_violation(properties: &FsValue) -> Option<String> {
+    let depth = nesting_depth(properties);
+    (depth > MAX_FIELD_DEPTH).then(|| {
+        format!(
+            "Fields are nested {} levels deep, more than {}",
+            depth, MAX_FIELD_DEPTH
+        )
+    })
+}
+
+// `message` about the field at `path`, or about the properties map itself
+fn at_path(path: &str, message: String) -> String {
+    if path.is_empty() {
+        message
+    } else {
+        format!("{}: {}", path, message)
+    }
+}
+
+// Violations of the limits on field names and on single values at or below
+// `value`. Field names are reported at the path of their map, as the path
+// would repeat a name that is too long.
+fn collect_field_violations(value: &FsValue, path: &str, violations: &mut Vec<String>) {
+    match value {
+        FsValue::String(string) if string.len() > MAX_BYTES_VALUE => violations.push(at_path(
+            path,
+            format!(
+                "String of {} bytes exceeds the {} bytes limit",
+                string.len(),
+                MAX_BYTES_VALUE
+            ),
+        )),
+        FsValue::Bytes(bytes) if bytes.len() > MAX_BYTES_VALUE => violations.push(at_path(
+            path,
+            format!(
+                "Bytes value of {} bytes exceeds the {} bytes limit",
+                bytes.len(),
+                MAX_BYTES_VALUE
+            ),
+        )),
+        FsValue::Array(array) => {
+            for (index, element) in array.iter().enumerate() {
+                // Like fs_array, only strict limits refuse nested arrays
+                if matches!(element, FsValue::Array(_)) && fs_guc::STRICT_LIMITS.get() {
+                    violations.push(at_path(
+                        path,
+                        format!(
+                            "Array element at index {} is an array; Firestore does not support directly nested arrays",
+                            index
+                        ),
+                    ));
+                }
+                collect_field_violations(element, &format!("{}[{}]", path, index), violations);
+            }
+        }
+        FsValue::Map(map) => {
+            for (key, child) in map.iter() {
+                if key.is_empty() {
+                    violations.push(at_path(path, "Field name is empty".to_owned()));
+                } else if key.len() > MAX_FIELD_NAME_BYTES {
+                    violations.push(at_path(
+                        path,
+                        format!(
+                            "Field name of {} bytes exceeds the {} bytes limit",
+                            key.len(),
+                            MAX_FIELD_NAME_BYTES
+                        ),
+                    ));
+                }
+                collect_field_violations(child, &child_path(path, key), violations);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Violations of Firestore's limits by the properties of a document. Its name
+// is not known here, so the size leaves it out.
+fn document_violations(properties: &FsValue) -> Vec<String> {
+    if !matches!(properties, FsValue::Map(_)) {
+        return vec![format!(
+            "Properties must be a MAP but found {}",
+            properties.type_name()
+        )];
+    }
+    let mut violations = Vec::new();
+    let size = value_size(properties) + DOCUMENT_OVERHEAD_BYTES;
+    if size > MAX_DOCUMENT_BYTES {
+        violations.push(format!(
+            "Document size of at least {} bytes exceeds the {} bytes limit",
+            size, MAX_DOCUMENT_BYTES
+        ));
+    }
+    violations.extend(depth_violation(properties));
+    collect_field_violations(properties, "", &mut violations);
+    violations
+}
+
+// Whether the properties of a document are within Firestore's limits, for
+// the CHECK constraint on fs_documents. fs_document_violations says why not.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_validate_document(properties: FsValue) -> bool {
+    document_violations(&properties).is_empty()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_document_violations(properties: FsValue) -> SetOfIterator<'static, String> {
+    SetOfIterator::new(document_violations(&properties).into_iter())
+}
+
+// The detail of the violation of `check`, if any
+fn run_check(
+    check: &str,
+    reference: &FsValue,
+    properties: &FsValue,
+    patterns: &[ReferencePattern],
+) -> Option<String> {
+    match check {
+        "valid_key" => document_key_error(reference),
+        "map_properties" if !matches!(properties, FsValue::Map(_)) => Some(format!(
+            "Properties must be a MAP but found {}",
+            properties.type_name()
+        )),
+        "size_limit" => {
+            let size = document_size(reference.as_reference()?, properties);
+            (size > MAX_DOCUMENT_BYTES).then(|| {
+                format!(
+                    "Document size of {} bytes exceeds the {} bytes limit",
+                    size, MAX_DOCUMENT_BYTES
+                )
+            })
+        }
+        "depth_limit" => depth_violation(properties),
+        "schema_allowed" => {
+            reference.as_reference()?;
+            (!is_allowed(patterns, reference))
+                .then(|| "Reference does not match any allowed collection pattern".to_owned())
+        }
+        _ => None,
+    }
+}
+
+// Reports the rows of fs_documents that would violate the named checks, all
+// of them by default, e.g. before turning on enforcement of a new rule. The
+// table is scanned through a cursor so only violations are kept in memory.
+#[pg_extern]
+fn fs_check_constraint_report(
+    checks: default!(Option<Vec<String>>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(reference, FsValue),
+        name!(check_name, String),
+        name!(detail, String),
+    ),
+> {
+    let checks = checks.unwrap_or_else(|| CHECKS.iter().map(|check| check.to_string()).collect());
+    for check in checks.iter() {
+        if !CHECKS.contains(&check.as_str()) {
+            FsError::InvalidValue(format!(
+                "Unknown check '{}', expecting one of {}",
+                check,
+                CHECKS.join(", ")
+            ))
+            .report()
+        }
+    }
+    let patterns = if checks.iter().any(|check| check == "schema_allowed") {
+        allowed_patterns()
+    } else {
+        Vec::new()
+    };
+    let mut rows: Vec<(FsValue, String, String)> = Vec::new();
+    scan_documents(
+        "SELECT reference, properties FROM fs_documents ORDER BY reference",
+        vec![],
+        |reference, properties| {
+            for check in CHECKS
+                .iter()
+                .filter(|check| checks.iter().any(|c| c == *check))
+            {
+                if let Some(detail) = run_check(check, &reference, &properties, &patterns) {
+                    rows.push((reference.to_owned(), check.to_string(), detail));
+                }
+            }
+        },
+    );
+    TableIterator::new(rows.into_iter())
+}
+
+// Checks of fs_storage_problems, those a stored row must have passed
+const STORAGE_CHECKS: [&str; 4] = ["valid_key", "map_properties", "size_limit", "depth_limit"];
+
+// Decodes a stored fsvalue, reporting a datum that no longer decodes rather
+// than raising the error
+fn try_decode(value: &AnyElement) -> Result<FsValue, String> {
+    panic::catch_unwind(|| unsafe { FsValue::from_datum(value.datum(), false) })
+        .map_err(panic_message)?
+        .ok_or_else(|| "Datum is NULL".to_owned())
+}
+
+// The problems of one row of fs_documents, located by `row` (its ctid).
+// Columns are passed undecoded so that this is where they are decoded, and
+// the reference is returned when it decodes.
+#[pg_extern(stable, parallel_safe)]
+fn fs_storage_problems(
+    row: &str,
+    stored_reference: AnyElement,
+    stored_properties: Option<AnyElement>,
+    stored_content_hash: Option<Vec<u8>>,
+) -> TableIterator<'static, (name!(reference, Option<FsValue>), name!(problem, String))> {
+    let reference = match try_decode(&stored_reference) {
+        Ok(reference) => reference,
+        Err(error) => {
+            return TableIterator::new(
+                vec![(
+                    None,
+                    format!("Row {}: reference does not decode: {}", row, error),
+                )]
+                .into_iter(),
+            )
+        }
+    };
+    let properties = match stored_properties.as_ref().map(try_decode) {
+        Some(Ok(properties)) => properties,
+        None => FsValue::NULL,
+        Some(Err(error)) => {
+            return TableIterator::new(
+                vec![(
+                    Some(reference),
+                    format!("properties do not decode: {}", error),
+                )]
+                .into_iter(),
+            )
+        }
+    };
+    let mut problems: Vec<(Option<FsValue>, String)> = STORAGE_CHECKS
+        .iter()
+        .filter_map(|check| {
+            run_check(check, &reference, &properties, &[])
+                .map(|detail| (Some(reference.to_owned()), format!("{}: {}", check, detail)))
+        })
+        .collect();
+    // A NULL hash was left out by a write with pgfirestore.content_hashes off
+    if stored_content_hash.is_some_and(|hash| hash != content_hash(&properties)) {
+        problems.push((
+            Some(reference),
+            "content_hash: Stored hash does not match the properties".to_owned(),
+        ));
+    }
+    TableIterator::new(problems.into_iter())
+}
+
+// A SQL function so that it is inlined: rows stream out of the scan, which
+// stops on cancel like any other
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_verify_storage(sample_fraction double precision DEFAULT 1.0) \n\
+        RETURNS TABLE (reference fsvalue, problem text) AS $$ \n\
+            SELECT p.reference, p.problem \n\
+            FROM fs_documents AS d TABLESAMPLE BERNOULLI (100 * sample_fraction), \n\
+                fs_storage_problems(d.ctid::text, d.reference, d.properties, d.content_hash) AS p \n\
+        $$ LANGUAGE SQL STABLE; \n\
+    ",
+    name = "verify_storage",
+    requires = [fs_storage_problems, "main_table", "content_hash_column"],
+);
+
+// The first document above `reference`, from the root down, that does not
+// exist. All ancestors are looked up by a single query.
+#[pg_extern]
+fn fs_first_missing_ancestor(reference: FsValue) -> Option<FsValue> {
+    let fs_ref = reference.expect_reference();
+    let ancestors: Vec<FsValue> = fs_ref
+        .ancestor_documents()
+        .into_iter()
+        .map(FsValue::Reference)
+        .collect();
+    if ancestors.is_empty() {
+        return None;
+    }
+    Spi::get_one_with_args::<FsValue>(
+        "SELECT (SELECT a.ancestor FROM unnest($1) WITH ORDINALITY AS a(ancestor, depth) \
+         WHERE NOT EXISTS (SELECT 1 FROM fs_documents WHERE reference = a.ancestor) \
+         ORDER BY a.depth LIMIT 1)",
+        vec![(
+            PgOid::from(Vec::<FsValue>::type_oid()),
+            ancestors.into_datum(),
+        )],
+    )
+    .expect("Failed to read from fs_documents")
+}
+
+// Documents of a subcollection whose parent document does not exist, with
+// the first missing document above them. Documents below an orphan whose own
+// parent exists are not reported.
+#[pg_extern]
+fn fs_orphaned_documents(
+    limit_n: default!(Option<i64>, "NULL"),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(missing_ancestor, FsValue))> {
+    if let Some(limit_n) = limit_n.filter(|limit_n| *limit_n < 0) {
+        FsError::InvalidValue(format!("Limit must not be negative but found {}", limit_n)).report()
+    }
+    let mut rows: Vec<(FsValue, FsValue)> = Vec::new();
+    scan_documents(
+        "SELECT d.reference, fs_first_missing_ancestor(d.reference) FROM fs_documents d \
+         WHERE fs_parent(d.reference) <> fs_database_root() \
+         AND NOT EXISTS ( \
+             SELECT 1 FROM fs_documents p WHERE p.reference = fs_parent(d.reference) \
+         ) \
+         ORDER BY d.reference LIMIT $1",
+        vec![(PgBuiltInOids::INT8OID.oid(), limit_n.into_datum())],
+        |reference, missing_ancestor| rows.push((reference, missing_ancestor)),
+    );
+    TableIterator::new(rows.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_check::*;
+    use crate::fs_test_util::map;
+
+    #[test]
+    fn test_scan_checks_for_interrupts() {
+        let source = include_str!("fs_documents.rs");
+        let scan = &source[source
+            .find("pub(crate) fn scan_documents")
+            .expect("scan_documents must exist")..];
+        let fetch = scan
+            .find("cursor.fetch")
+            .expect("scan_documents must fetch");
+        assert!(scan[..fetch].contains("check_for_interrupts!()"));
+    }
+
+    #[test]
+    fn test_document_size() {
+        use std::str::FromStr;
+
+        // Example from the Firestore storage size documentation
+        let reference = FsReference::from_str("/users/jeff/tasks/my_task_id").unwrap();
+        assert_eq!(reference_size(&reference), 6 + 5 + 6 + 11 + 16);
+        let properties = FsValue::Map(
+            [
+                ("type".to_owned(), FsValue::String("Personal".to_owned())),
+                ("done".to_owned(), FsValue::Boolean(false)),
+                ("priority".to_owned(), crate::fs_number_from_integer(1)),
+                (
+                    "description".to_owned(),
+                    FsValue::String("Learn Cloud Firestore".to_owned()),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(document_size(&reference, &properties), 147);
+    }
+
+    // `levels` maps, the properties map included, around a string
+    fn nested(levels: usize) -> FsValue {
+        (1..levels).fold(
+            map(vec![("k", FsValue::String("x".to_owned()))]),
+            |value, _| map(vec![("k", value)]),
+        )
+    }
+
+    #[test]
+    fn test_document_violations() {
+        assert!(document_violations(&nested(20)).is_empty());
+        assert_eq!(
+            document_violations(&nested(21)),
+            vec!["Fields are nested 21 levels deep, more than 20"]
+        );
+        assert_eq!(
+            document_violations(&FsValue::String("x".to_owned())),
+            vec!["Properties must be a MAP but found STRING"]
+        );
+        let properties = map(vec![
+            ("", FsValue::NULL),
+            ("a", map(vec![(&"k".repeat(1501), FsValue::NULL)])),
+            (
+                "b",
+                FsValue::Array(vec![FsValue::Bytes(vec![0; 1_048_488])]),
+            ),
+            ("c", FsValue::String("x".repeat(1_048_487))),
+        ]);
+        assert_eq!(
+            document_violations(&properties),
+            vec![
+                "Document size of at least 2098519 bytes exceeds the 1048576 bytes limit",
+                "Field name is empty",
+                "a: Field name of 1501 bytes exceeds the 1500 bytes limit",
+                "b[0]: Bytes value of 1048488 bytes exceeds the 1048487 bytes limit",
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_document_violations_nested_arrays() {
+        let properties = map(vec![(
+            "a",
+            FsValue::Array(vec![
+                FsValue::NULL,
+                FsValue::Array(vec![FsValue::Array(vec![])]),
+            ]),
+        )]);
+        Spi::run("SET LOCAL pgfirestore.strict_limits = off").expect("SPI failed");
+        assert!(document_violations(&properties).is_empty());
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert_eq!(
+            document_violations(&properties),
+            vec![
+                "a: Array element at index 1 is an array; Firestore does not support directly nested arrays",
+                "a[1]: Array element at index 0 is an array; Firestore does not support directly nested arrays",
+            ]
+        );
+    }
+
+    // SQL for `levels` maps, the properties map included, around a string
+    fn nested_sql(levels: usize) -> String {
+        (0..levels).fold("fs_string('x')".to_owned(), |value, _| {
+            format!("fs_map_from_entries(ARRAY['k'], ARRAY[{}])", value)
+        })
+    }
+
+    #[pg_test(
+        error = "new row for relation \"fs_documents\" violates check constraint \"valid_document_limits\""
+    )]
+    fn test_fs_documents_rejects_deep_nesting() {
+        Spi::run(&format!(
+            "INSERT INTO fs_documents (reference, properties) VALUES (fs_reference('/users/6'), {})",
+            nested_sql(21)
+        ))
+        .expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "new row for relation \"fs_documents\" violates check constraint \"valid_document_limits\""
+    )]
+    fn test_fs_documents_rejects_oversized_bytes() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/6'), fs_map_from_entries(ARRAY['blob'], \
+                 ARRAY[fs_bytes(convert_to(repeat('x', 1048488), 'UTF8'))]))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_validate_document() {
+        // Just below the limits: a 1,048,487 byte string, and 20 levels of
+        // fields and a 1500 byte field name. The string alone leaves no room
+        // in the document for the rest.
+        let large = [
+            "fs_map_from_entries(ARRAY['text'], ARRAY[fs_string(repeat('x', 1048487))])".to_owned(),
+            format!(
+                "fs_map_from_entries(ARRAY['deep', repeat('k', 1500)], ARRAY[{}, fs_null()])",
+                nested_sql(19)
+            ),
+        ];
+        for (id, large) in large.iter().enumerate() {
+            Spi::run(&format!(
+                "INSERT INTO fs_documents (reference, properties) \
+                 VALUES (fs_reference('/users/{}'), {})",
+                id + 6,
+                large
+            ))
+            .expect("SPI failed");
+            assert_eq!(
+                Spi::get_one::<bool>(&format!("SELECT fs_validate_document({})", large)),
+                Ok(Some(true))
+            );
+        }
+        assert_eq!(
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(v, '; ') FROM fs_document_violations({}) AS v",
+                nested_sql(21)
+            )),
+            Ok(Some(
+                "Fields are nested 21 levels deep, more than 20".to_owned()
+            ))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(v, '; ') FROM fs_document_violations(fs_map_from_entries( \
+                     ARRAY['a', 'big'], ARRAY[fs_map_from_entries(ARRAY[''], ARRAY[fs_null()]), \
+                     fs_bytes(convert_to(repeat('x', 1048488), 'UTF8'))])) AS v"
+            ),
+            Ok(Some(
+                "a: Field name is empty; big: Bytes value of 1048488 bytes exceeds the 1048487 bytes limit"
+                    .to_owned()
+            ))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents WHERE NOT fs_validate_document(properties)"
+            ),
+            Ok(Some(0))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_check_constraint_report() {
+        let deep = (0..21).fold("fs_string('x')".to_owned(), |value, _| {
+            format!("fs_map_from_entries(ARRAY['k'], ARRAY[{}])", value)
+        });
+        Spi::run(&format!(
+            "ALTER TABLE fs_documents DROP CONSTRAINT valid_document_key; \
+             ALTER TABLE fs_documents DROP CONSTRAINT valid_document_properties; \
+             ALTER TABLE fs_documents DROP CONSTRAINT valid_document_limits; \
+             INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/rogue'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])), \
+                 (fs_reference('/users/6'), fs_string('not a map')), \
+                 (fs_reference('/users/7'), fs_map_from_entries(ARRAY['big'], ARRAY[fs_string(repeat('x', 1048576))])), \
+                 (fs_reference('/users/8'), {}); \
+             SELECT fs_allow_collection('/users/{{id}}'); \
+             SELECT fs_allow_collection('/users/{{id}}/posts/{{post}}')",
+            deep
+        ))
+        .expect("SPI failed");
+
+        let report = |checks: Option<Vec<String>>| -> Vec<(String, String)> {
+            fs_check_constraint_report(checks)
+                .map(|(reference, check, _)| (reference.expect_reference().to_string(), check))
+                .collect()
+        };
+        let expected = |rows: &[(&str, &str)]| -> Vec<(String, String)> {
+            rows.iter()
+                .map(|(reference, check)| (reference.to_string(), check.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            report(None),
+            expected(&[
+                ("/posts/1", "schema_allowed"),
+                ("/posts/2", "schema_allowed"),
+                ("/rogue", "valid_key"),
+                ("/rogue", "schema_allowed"),
+                ("/users/6", "map_properties"),
+                ("/users/7", "size_limit"),
+                ("/users/8", "depth_limit"),
+            ])
+        );
+        assert_eq!(
+            report(Some(vec!["map_properties".to_owned()])),
+            expected(&[("/users/6", "map_properties")])
+        );
+        assert_eq!(
+            fs_check_constraint_report(Some(vec!["depth_limit".to_owned()]))
+                .map(|(_, _, detail)| detail)
+                .collect::<Vec<String>>(),
+            vec!["Fields are nested 21 levels deep, more than 20"]
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Unknown check 'size', expecting one of valid_key, map_properties, size_limit, depth_limit, schema_allowed"
+    )]
+    fn test_fs_check_constraint_report_unknown_check() {
+        fs_check_constraint_report(Some(vec!["size".to_owned()]));
+    }
+
+    fn storage_problems(sample_fraction: f64) -> Vec<(Option<String>, String)> {
+        Spi::connect(|client| {
+            let mut problems = Vec::new();
+            for row in client.select(
+                "SELECT fs_reference_text(reference), problem FROM fs_verify_storage($1) \
+                 ORDER BY 1, 2",
+                None,
+                Some(vec![(
+                    PgBuiltInOids::FLOAT8OID.oid(),
+                    sample_fraction.into_datum(),
+                )]),
+            )? {
+                problems.push((
+                    row.get::<String>(1)?,
+                    row.get::<String>(2)?.expect("problem must not be null"),
+                ));
+            }
+            Ok::<_, pgrx::spi::Error>(problems)
+        })
+        .expect("SPI failed")
+    }
+
+    #[pg_test]
+    fn test_fs_verify_storage_healthy() {
+        assert_eq!(storage_problems(1.0), vec![]);
+        assert_eq!(storage_problems(0.5).len(), 0);
+    }
+
+    #[pg_test]
+    fn test_fs_verify_storage_content_hash() {
+        // Setting only content_hash does not fire the trigger that hashes
+        Spi::run(
+            "UPDATE fs_documents SET content_hash = '\\x00' WHERE reference = fs_reference('/users/2'); \
+             SET LOCAL pgfirestore.content_hashes = off; \
+             SELECT fs_set(fs_reference('/users/3'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            storage_problems(1.0),
+            vec![(
+                Some("/users/2".to_owned()),
+                "content_hash: Stored hash does not match the properties".to_owned()
+            )]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_verify_storage() {
+        // Raw bytes are stored through a binary cast, which skips the input
+        // function, once the constraints, index and triggers that would
+        // decode them are gone
+        Spi::run(
+            "ALTER TABLE fs_documents DROP CONSTRAINT fs_documents_pkey; \
+             ALTER TABLE fs_documents DROP CONSTRAINT valid_document_key; \
+             ALTER TABLE fs_documents DROP CONSTRAINT valid_document_properties; \
+             ALTER TABLE fs_documents DROP CONSTRAINT valid_document_limits; \
+             ALTER TABLE fs_documents DISABLE TRIGGER USER; \
+             CREATE CAST (bytea AS fsvalue) WITHOUT FUNCTION; \
+             INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/users/6'), '\\xff'::bytea::fsvalue), \
+                 ('\\xff'::bytea::fsvalue, fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])), \
+                 (fs_reference('/users/7'), fs_string('not a map')), \
+                 (fs_reference('/rogue'), NULL)",
+        )
+        .expect("SPI failed");
+        let problems = storage_problems(1.0);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert_eq!(
+            problems[0],
+            (
+                Some("/rogue".to_owned()),
+                "map_properties: Properties must be a MAP but found NULL".to_owned()
+            )
+        );
+        assert_eq!(problems[1].0.as_deref(), Some("/rogue"));
+        assert!(
+            problems[1].1.starts_with("valid_key: "),
+            "{}",
+            problems[1].1
+        );
+        assert_eq!(problems[2].0.as_deref(), Some("/users/6"));
+        assert!(
+            problems[2].1.starts_with("properties do not decode: "),
+            "{}",
+            problems[2].1
+        );
+        assert_eq!(
+            problems[3],
+            (
+                Some("/users/7".to_owned()),
+                "map_properties: Properties must be a MAP but found STRING".to_owned()
+            )
+        );
+        assert_eq!(problems[4].0, None);
+        assert!(
+            problems[4].1.starts_with("Row (")
+                && problems[4].1.contains("): reference does not decode: "),
+            "{}",
+            problems[4].1
+        );
+        assert_eq!(storage_problems(0.0), vec![]);
+    }
+
+    fn orphans(limit_n: Option<i64>) -> Vec<(String, String)> {
+        fs_orphaned_documents(limit_n)
+            .map(|(reference, missing_ancestor)| {
+                (
+                    crate::fs_reference_text(reference),
+                    crate::fs_reference_text(missing_ancestor),
+                )
+            })
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_fs_first_missing_ancestor() {
+        use crate::fs_reference;
+
+        // /users/1/posts/1 has an existing parent
+        assert_eq!(
+            fs_first_missing_ancestor(fs_reference("/users/1/posts/1")),
+            None
+        );
+        assert_eq!(fs_first_missing_ancestor(fs_reference("/users/1")), None);
+        assert_eq!(fs_first_missing_ancestor(fs_reference("/")), None);
+        assert_eq!(
+            fs_first_missing_ancestor(fs_reference("/users/1/posts/404/likes/1")),
+            Some(fs_reference("/users/1/posts/404"))
+        );
+        // The chain is walked from the root down
+        assert_eq!(
+            fs_first_missing_ancestor(fs_reference("/ghosts/1/items/1/parts/1")),
+            Some(fs_reference("/ghosts/1"))
+        );
+        assert_eq!(
+            fs_first_missing_ancestor(fs_reference("/ghosts/1/items")),
+            Some(fs_reference("/ghosts/1"))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_orphaned_documents() {
+        assert!(orphans(None).is_empty());
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/ghosts/1/items/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])), \
+                 (fs_reference('/ghosts/1/items/1/parts/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])), \
+                 (fs_reference('/users/1/posts/404/likes/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        // /ghosts/1/items/1/parts/1 has an existing parent
+        assert_eq!(
+            orphans(None),
+            vec![
+                ("/ghosts/1/items/1".to_owned(), "/ghosts/1".to_owned()),
+                (
+                    "/users/1/posts/404/likes/1".to_owned(),
+                    "/users/1/posts/404".to_owned()
+                ),
+            ]
+        );
+        assert_eq!(orphans(Some(1)).len(), 1);
+    }
+
+    #[pg_test(error = "InvalidValue: Limit must not be negative but found -1")]
+    fn test_fs_orphaned_documents_negative_limit() {
+        orphans(Some(-1));
+    }
+}
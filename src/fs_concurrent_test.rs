@@ -0,0 +1,442 @@
+use crate::fs_documents::text_arg;
+use pgrx::prelude::*;
+use std::time::{Duration, Instant};
+
+// Test helpers that drive other backends through dblink, so that a test can
+// interleave transactions deterministically: a statement is sent to one
+// session, the test waits until it blocks on a lock held by another session,
+// and then commits that one. Sessions work in a scratch database of their
+// own because what they commit outlives the test's transaction, which is
+// rolled back, and would otherwise show up in tests running in parallel.
+//
+// Statements run in sessions must return a single column, which is read as
+// text. Statements without a result, e.g. an UPDATE, return their command
+// tag instead.
+
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn install_dblink() {
+    Spi::run("CREATE EXTENSION IF NOT EXISTS dblink").expect("Failed to create extension dblink");
+}
+
+// Sessions are told apart from those of tests running in parallel by the
+// process ID of the backend that opened them
+fn application_name(name: &str) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT format('fs_test %s %s', pg_backend_pid(), $1)",
+        vec![text_arg(name)],
+    )
+    .expect("SPI failed")
+    .expect("format() must not return NULL")
+}
+
+fn current_database() -> String {
+    Spi::get_one::<String>("SELECT current_database()::text")
+        .expect("SPI failed")
+        .expect("current_database() must not be NULL")
+}
+
+fn quote_ident(name: &str) -> String {
+    Spi::get_one_with_args::<String>("SELECT quote_ident($1)", vec![text_arg(name)])
+        .expect("SPI failed")
+        .expect("quote_ident() must not return NULL")
+}
+
+// A libpq connection string for `database` of this server, as this user
+fn connection_string(database: &str, application_name: &str) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT format('host=''%s'' port=%s dbname=''%s'' user=''%s'' application_name=''%s''', \
+             COALESCE(host(inet_server_addr()), \
+                 split_part(current_setting('unix_socket_directories'), ',', 1)), \
+             current_setting('port'), $1, current_user, $2)",
+        vec![text_arg(database), text_arg(application_name)],
+    )
+    .expect("SPI failed")
+    .expect("a connection string must not be NULL")
+}
+
+// Runs `sql` in a short-lived connection to `database`, outside of any
+// transaction block
+fn exec_in(database: &str, sql: &str) {
+    Spi::run_with_args(
+        "SELECT dblink_exec($1, $2)",
+        Some(vec![
+            text_arg(&connection_string(database, "fs_test_admin")),
+            text_arg(sql),
+        ]),
+    )
+    .expect("Failed to run a statement through dblink")
+}
+
+// (Re)creates `database` with pgfirestore installed, for the sessions of one
+// test
+#[pg_extern]
+fn fs_test_create_database(database: &str) {
+    install_dblink();
+    let current = current_database();
+    exec_in(
+        &current,
+        &format!("DROP DATABASE IF EXISTS {}", quote_ident(database)),
+    );
+    exec_in(
+        &current,
+        &format!("CREATE DATABASE {}", quote_ident(database)),
+    );
+    exec_in(database, "CREATE EXTENSION pgfirestore");
+}
+
+// Drops `database` once its sessions are finished
+#[pg_extern]
+fn fs_test_drop_database(database: &str) {
+    exec_in(
+        &current_database(),
+        &format!("DROP DATABASE IF EXISTS {}", quote_ident(database)),
+    );
+}
+
+// Opens a second backend called `name` on `database`, outside of any
+// transaction block, e.g. to CALL a procedure that commits
+#[pg_extern]
+fn fs_test_connect(name: &str, database: &str) {
+    install_dblink();
+    Spi::run_with_args(
+        "SELECT dblink_connect($1, $2)",
+        Some(vec![
+            text_arg(name),
+            text_arg(&connection_string(database, &application_name(name))),
+        ]),
+    )
+    .expect("Failed to connect a session");
+}
+
+// Opens a second backend called `name` on `database` and starts a
+// transaction in it
+#[pg_extern]
+fn fs_test_begin_session(name: &str, database: &str) {
+    fs_test_connect(name, database);
+    fs_test_run(name, "BEGIN");
+}
+
+// Starts `sql` in session `name` without waiting for it, e.g. because it is
+// expected to block. Its outcome is read with fs_test_result or
+// fs_test_error.
+#[pg_extern]
+fn fs_test_send(name: &str, sql: &str) {
+    let sent = Spi::get_one_with_args::<i32>(
+        "SELECT dblink_send_query($1, $2)",
+        vec![text_arg(name), text_arg(sql)],
+    )
+    .expect("SPI failed");
+    if sent != Some(1) {
+        panic!("Failed to send a statement to session {}", name)
+    }
+}
+
+// Waits for the statement sent to session `name`, returning the first column
+// of its first row, or its error message in Err
+fn outcome(name: &str) -> Result<Option<String>, String> {
+    let rows = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT result FROM dblink_get_result($1, false) AS t(result text)",
+                None,
+                Some(vec![text_arg(name)]),
+            )?
+            .map(|row| row.get::<String>(1))
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .expect("Failed to read the result of a session");
+    let error =
+        Spi::get_one_with_args::<String>("SELECT dblink_error_message($1)", vec![text_arg(name)])
+            .expect("SPI failed")
+            .unwrap_or_default();
+    // Every sent statement ends with an empty result, which frees the
+    // connection for the next one
+    Spi::run_with_args(
+        "SELECT count(*) FROM dblink_get_result($1, false) AS t(result text)",
+        Some(vec![text_arg(name)]),
+    )
+    .expect("Failed to read the result of a session");
+    match error.trim() {
+        "" | "OK" => Ok(rows.into_iter().next().flatten()),
+        error => Err(error.to_owned()),
+    }
+}
+
+#[pg_extern]
+fn fs_test_result(name: &str) -> Option<String> {
+    outcome(name).unwrap_or_else(|error| panic!("Session {} failed: {}", name, error))
+}
+
+// The error message of the statement sent to session `name`
+#[pg_extern]
+fn fs_test_error(name: &str) -> String {
+    match outcome(name) {
+        Ok(result) => panic!(
+            "Expecting session {} to fail but it returned {:?}",
+            name, result
+        ),
+        Err(error) => error,
+    }
+}
+
+#[pg_extern]
+fn fs_test_run(name: &str, sql: &str) -> Option<String> {
+    fs_test_send(name, sql);
+    fs_test_result(name)
+}
+
+// Waits until the statement sent to session `name` blocks on a lock
+#[pg_extern]
+fn fs_test_wait_for_lock(name: &str) {
+    let started = Instant::now();
+    loop {
+        let blocked = Spi::get_one_with_args::<bool>(
+            "SELECT EXISTS (SELECT 1 FROM pg_stat_activity \
+             WHERE application_name = $1 AND wait_event_type = 'Lock')",
+            vec![text_arg(&application_name(name))],
+        )
+        .expect("SPI failed")
+        .unwrap_or(false);
+        if blocked {
+            return;
+        }
+        if started.elapsed() > LOCK_TIMEOUT {
+            panic!("Session {} did not block on a lock", name)
+        }
+        Spi::run("SELECT pg_stat_clear_snapshot(), pg_sleep(0.01)").expect("SPI failed");
+    }
+}
+
+#[pg_extern]
+fn fs_test_disconnect(name: &str) {
+    Spi::run_with_args("SELECT dblink_disconnect($1)", Some(vec![text_arg(name)]))
+        .expect("Failed to disconnect a session");
+}
+
+#[pg_extern]
+fn fs_test_commit(name: &str) {
+    fs_test_run(name, "COMMIT");
+    fs_test_disconnect(name);
+}
+
+#[pg_extern]
+fn fs_test_rollback(name: &str) {
+    fs_test_run(name, "ROLLBACK");
+    fs_test_disconnect(name);
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_concurrent_test::*;
+
+    fn set_writer(id: &str, writer: &str) -> String {
+        format!(
+            "SELECT fs_set(fs_reference('/races/{}'), \
+                 fs_map_from_entries(ARRAY['writer'], ARRAY[fs_string('{}')]))",
+            id, writer
+        )
+    }
+
+    fn writer_is(id: &str, writer: &str) -> String {
+        format!(
+            "SELECT fs_get(fs_reference('/races/{}'))->'writer' = fs_string('{}')",
+            id, writer
+        )
+    }
+
+    #[pg_test]
+    fn test_concurrent_set_of_new_reference() {
+        let database = "fs_concurrent_set";
+        fs_test_create_database(database);
+        fs_test_begin_session("a", database);
+        fs_test_begin_session("b", database);
+
+        assert_eq!(
+            fs_test_run("a", &set_writer("1", "a")),
+            Some("t".to_owned())
+        );
+        // b waits for a's uncommitted insert of the same reference and then
+        // takes the conflict path instead of failing with a unique violation
+        fs_test_send("b", &set_writer("1", "b"));
+        fs_test_wait_for_lock("b");
+        fs_test_commit("a");
+        assert_eq!(fs_test_result("b"), Some("t".to_owned()));
+        fs_test_commit("b");
+
+        fs_test_begin_session("c", database);
+        assert_eq!(fs_test_run("c", &writer_is("1", "b")), Some("t".to_owned()));
+        assert_eq!(
+            fs_test_run(
+                "c",
+                "SELECT count(*) FROM fs_document_changes \
+                 WHERE reference = fs_reference('/races/1')"
+            ),
+            Some("2".to_owned())
+        );
+        fs_test_commit("c");
+        fs_test_drop_database(database);
+    }
+
+    #[pg_test]
+    fn test_precondition_fails_after_other_commit() {
+        let database = "fs_concurrent_precondition";
+        fs_test_create_database(database);
+        fs_test_begin_session("a", database);
+        fs_test_begin_session("b", database);
+
+        // Creating a document requires that it does not exist yet
+        let create = "SELECT fs_rest_handle('POST', \
+                          '/v1/projects/p/databases/(default)/documents/races?documentId=1', \
+                          '{\"fields\": {}}'::jsonb)->'error'->>'status'";
+        assert_eq!(fs_test_run("a", create), None);
+        fs_test_send("b", create);
+        fs_test_wait_for_lock("b");
+        fs_test_commit("a");
+        assert_eq!(fs_test_result("b"), Some("ALREADY_EXISTS".to_owned()));
+        // The failed create keeps its lock on the document until b ends
+        fs_test_rollback("b");
+
+        // Updating one requires that it exists, which b sees as soon as a's
+        // delete commits even though b's transaction started before it
+        fs_test_begin_session("b", database);
+        fs_test_begin_session("a", database);
+        assert_eq!(
+            fs_test_run("a", "SELECT fs_delete(fs_reference('/races/1'))"),
+            Some("t".to_owned())
+        );
+        fs_test_commit("a");
+        fs_test_send(
+            "b",
+            "SELECT fs_update(fs_reference('/races/1'), \
+                 fs_map_from_entries(ARRAY['writer'], ARRAY[fs_string('b')]))",
+        );
+        assert!(fs_test_error("b").contains("Cannot update document /races/1 which does not exist"));
+        fs_test_rollback("b");
+        fs_test_drop_database(database);
+    }
+
+    #[pg_test]
+    fn test_freeze_blocks_concurrent_update() {
+        let database = "fs_concurrent_freeze";
+        fs_test_create_database(database);
+        fs_test_begin_session("setup", database);
+        fs_test_run("setup", &set_writer("1", "setup"));
+        fs_test_commit("setup");
+
+        fs_test_begin_session("a", database);
+        fs_test_begin_session("b", database);
+        fs_test_run("a", "SELECT fs_freeze(fs_reference('/races/1'))");
+        // b's write waits for the freeze instead of slipping past it while
+        // it is uncommitted, and then fails the guard
+        fs_test_send("b", &set_writer("1", "b"));
+        fs_test_wait_for_lock("b");
+        fs_test_commit("a");
+        assert!(fs_test_error("b").contains("Document /races/1 is frozen"));
+        fs_test_rollback("b");
+
+        fs_test_begin_session("c", database);
+        assert_eq!(
+            fs_test_run("c", &writer_is("1", "setup")),
+            Some("t".to_owned())
+        );
+        fs_test_commit("c");
+        fs_test_drop_database(database);
+    }
+
+    // A line of an NDJSON import of an empty document at `path`
+    fn import_line(path: &str) -> String {
+        format!(
+            "{{\"name\": \"projects/pgfirestore/databases/(default)/documents/{}\", \"fields\": {{}}}}\n",
+            path
+        )
+    }
+
+    fn import(job_id: &str, paths: &[&str]) -> String {
+        let payload: String = paths.iter().map(|path| import_line(path)).collect();
+        format!(
+            "CALL fs_import_ndjson_resume('{}', 'dump', '{}', on_conflict => 'error', batch_size => 2)",
+            job_id, payload
+        )
+    }
+
+    // lines_processed/documents_written/last_error of an import job
+    fn import_status(job_id: &str) -> Option<String> {
+        fs_test_begin_session("observer", "fs_concurrent_import");
+        let status = fs_test_run(
+            "observer",
+            &format!(
+                "SELECT lines_processed || '/' || documents_written || '/' || coalesce(last_error, '-') \
+                 FROM fs_import_status('{}')",
+                job_id
+            ),
+        );
+        fs_test_commit("observer");
+        status
+    }
+
+    #[pg_test]
+    fn test_import_progress_is_visible_during_the_import() {
+        let database = "fs_concurrent_import";
+        fs_test_create_database(database);
+        let paths = [
+            "imports/1",
+            "imports/2",
+            "imports/3",
+            "imports/4",
+            "imports/5",
+            "imports/6",
+        ];
+
+        // The import blocks in its second batch on a document another
+        // session is creating, after committing the first batch
+        fs_test_begin_session("blocker", database);
+        fs_test_run(
+            "blocker",
+            "SELECT fs_set(fs_reference('/imports/4'), \
+                 fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        );
+        fs_test_connect("importer", database);
+        fs_test_send("importer", &import("load", &paths));
+        fs_test_wait_for_lock("importer");
+        assert_eq!(import_status("load"), Some("2/2/-".to_owned()));
+        fs_test_rollback("blocker");
+        assert_eq!(fs_test_result("importer"), Some("CALL".to_owned()));
+        assert_eq!(import_status("load"), Some("6/6/-".to_owned()));
+
+        // A failure keeps the committed batches and the offset of the line
+        // that failed
+        fs_test_send(
+            "importer",
+            &import(
+                "resumed",
+                &["retries/1", "retries/2", "retries", "retries/4"],
+            ),
+        );
+        assert!(fs_test_error("importer").contains("Import job 'resumed' stopped at line 3"));
+        assert!(import_status("resumed")
+            .is_some_and(|status| status.starts_with("2/2/InvalidValue: Line 3: ")));
+        // Resuming imports the lines after it alone, as on_conflict => 'error'
+        // would reject the documents of the first batch
+        fs_test_run(
+            "importer",
+            &import(
+                "resumed",
+                &["retries/1", "retries/2", "retries/3", "retries/4"],
+            ),
+        );
+        fs_test_disconnect("importer");
+        assert_eq!(import_status("resumed"), Some("4/4/-".to_owned()));
+        fs_test_begin_session("observer", database);
+        assert_eq!(
+            fs_test_run(
+                "observer",
+                "SELECT count(*) FROM fs_collection(fs_reference('/'), 'retries')"
+            ),
+            Some("4".to_owned())
+        );
+        fs_test_commit("observer");
+        fs_test_drop_database(database);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::fs_documents::{expect_parent_reference, fsvalue_arg, text_arg};
+use crate::fs_field_path::{FieldPath, PathSegment};
+use crate::{collect_differences, FsValue};
+use pgrx::prelude::*;
+
+const DIFF_BATCH_SIZE: i64 = 1000;
+
+// Documents present on both sides with identical properties are filtered out
+// by the join so that only differing rows reach the cursor. 1 and 1.0 differ
+// here, as they do in the paths reported.
+const DIFF_QUERY: &str = "\
+    WITH a AS ( \
+        SELECT fs_document_id(reference) AS document_id, properties FROM fs_documents \
+        WHERE fs_parent(reference) = $1 AND fs_collection_id(reference) = $3 \
+            AND deleted_at IS NULL \
+    ), b AS ( \
+        SELECT fs_document_id(reference) AS document_id, properties FROM fs_documents \
+        WHERE fs_parent(reference) = $2 AND fs_collection_id(reference) = $3 \
+            AND deleted_at IS NULL \
+    ) \
+    SELECT document_id, a.properties, b.properties, \
+        a.document_id IS NOT NULL, b.document_id IS NOT NULL \
+    FROM a FULL OUTER JOIN b USING (document_id) \
+    WHERE a.document_id IS NULL OR b.document_id IS NULL \
+        OR NOT fs_identical(a.properties, b.properties) \
+    ORDER BY document_id";
+
+fn difference_paths(lhs: &FsValue, rhs: &FsValue) -> Vec<String> {
+    let mut differences = Vec::new();
+    collect_differences(lhs, rhs, Vec::new(), &mut differences);
+    differences.into_iter().map(field_path_text).collect()
+}
+
+// The first path, in map key order, at which `lhs` and `rhs` differ. The
+// empty path means the values differ as a whole.
+pub(crate) fn first_difference(lhs: &FsValue, rhs: &FsValue) -> Option<Vec<String>> {
+    let mut differences = Vec::new();
+    collect_differences(lhs, rhs, Vec::new(), &mut differences);
+    differences.into_iter().next()
+}
+
+pub(crate) fn field_path_text(path: Vec<String>) -> String {
+    FieldPath(path.into_iter().map(PathSegment::Field).collect()).to_string()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_first_difference(lhs: FsValue, rhs: FsValue) -> Option<String> {
+    first_difference(&lhs, &rhs).map(field_path_text)
+}
+
+#[pg_extern]
+fn fs_diff_collections(
+    a_parent: FsValue,
+    b_parent: FsValue,
+    collection_id: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(document_id, String),
+        name!(status, String),
+        name!(difference_paths, Option<Vec<String>>),
+    ),
+> {
+    expect_parent_reference(&a_parent);
+    expect_parent_reference(&b_parent);
+    let mut rows: Vec<(String, String, Option<Vec<String>>)> = Vec::new();
+    Spi::connect(|client| {
+        let mut cursor = client.open_cursor(
+            DIFF_QUERY,
+            Some(vec![
+                fsvalue_arg(a_parent),
+                fsvalue_arg(b_parent),
+                text_arg(collection_id),
+            ]),
+        );
+        loop {
+            let table = cursor.fetch(DIFF_BATCH_SIZE)?;
+            if table.is_empty() {
+                return Ok::<(), pgrx::spi::Error>(());
+            }
+            for row in table {
+                let document_id = row.get::<String>(1)?.expect("document id must not be null");
+                let in_a = row.get::<bool>(4)?.unwrap_or(false);
+                let in_b = row.get::<bool>(5)?.unwrap_or(false);
+                rows.push(match (in_a, in_b) {
+                    (true, false) => (document_id, "only_a".to_owned(), None),
+                    (false, true) => (document_id, "only_b".to_owned(), None),
+                    _ => {
+                        let a_properties = row.get::<FsValue>(2)?.unwrap_or(FsValue::NULL);
+                        let b_properties = row.get::<FsValue>(3)?.unwrap_or(FsValue::NULL);
+                        (
+                            document_id,
+                            "different".to_owned(),
+                            Some(difference_paths(&a_properties, &b_properties)),
+                        )
+                    }
+                });
+            }
+        }
+    })
+    .expect("Failed to diff collections");
+    TableIterator::new(rows.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_diff::*;
+
+    #[test]
+    fn test_difference_paths() {
+        use crate::{fs_boolean, fs_map_from_entries, fs_number_from_integer, fs_string};
+
+        let lhs = fs_map_from_entries(
+            vec!["a".to_owned(), "b".to_owned(), "c d".to_owned()],
+            vec![
+                fs_number_from_integer(1),
+                fs_map_from_entries(
+                    vec!["x".to_owned(), "y".to_owned()],
+                    vec![fs_boolean(true), fs_boolean(false)],
+                ),
+                fs_string("same"),
+            ],
+        );
+        let rhs = fs_map_from_entries(
+            vec!["b".to_owned(), "c d".to_owned(), "e".to_owned()],
+            vec![
+                fs_map_from_entries(
+                    vec!["x".to_owned(), "y".to_owned()],
+                    vec![fs_boolean(true), fs_boolean(true)],
+                ),
+                fs_string("same"),
+                fs_string("new"),
+            ],
+        );
+        assert_eq!(difference_paths(&lhs, &rhs), vec!["a", "b.y", "e"]);
+        assert_eq!(difference_paths(&lhs, &lhs), Vec::<String>::new());
+        assert_eq!(difference_paths(&lhs, &fs_string("not a map")), vec![""]);
+        assert_eq!(
+            fs_first_difference(lhs.to_owned(), rhs),
+            Some("a".to_owned())
+        );
+        assert_eq!(fs_first_difference(lhs.to_owned(), lhs), None);
+    }
+
+    #[pg_test]
+    fn test_fs_diff_collections() {
+        Spi::run(
+            r#"
+            SELECT fs_set(fs_reference('/ns/a/users/1'), fs_map_from_entries(ARRAY['v'], ARRAY[fs_number_from_integer(1)]));
+            SELECT fs_set(fs_reference('/ns/a/users/2'), fs_map_from_entries(ARRAY['v'], ARRAY[fs_number_from_integer(2)]));
+            SELECT fs_set(fs_reference('/ns/a/users/3'), fs_map_from_entries(ARRAY['v', 'nested'], ARRAY[fs_number_from_integer(3), fs_map_from_entries(ARRAY['x'], ARRAY[fs_boolean(true)])]));
+            SELECT fs_set(fs_reference('/ns/b/users/2'), fs_map_from_entries(ARRAY['v'], ARRAY[fs_number_from_integer(2)]));
+            SELECT fs_set(fs_reference('/ns/b/users/3'), fs_map_from_entries(ARRAY['v', 'nested', 'w'], ARRAY[fs_number_from_integer(3), fs_map_from_entries(ARRAY['x'], ARRAY[fs_boolean(false)]), fs_null()]));
+            SELECT fs_set(fs_reference('/ns/b/users/4'), fs_map_from_entries(ARRAY['v'], ARRAY[fs_number_from_integer(4)]));
+            SELECT fs_set(fs_reference('/ns/b/posts/1'), fs_map_from_entries(ARRAY['v'], ARRAY[fs_number_from_integer(1)]));
+            "#,
+        )
+        .expect("SPI failed");
+
+        let rows: Vec<(String, String, Option<Vec<String>>)> = fs_diff_collections(
+            crate::fs_reference("/ns/a"),
+            crate::fs_reference("/ns/b"),
+            "users",
+        )
+        .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("1".to_owned(), "only_a".to_owned(), None),
+                (
+                    "3".to_owned(),
+                    "different".to_owned(),
+                    Some(vec!["nested.x".to_owned(), "w".to_owned()])
+                ),
+                ("4".to_owned(), "only_b".to_owned(), None),
+            ]
+        );
+
+        assert_eq!(
+            fs_diff_collections(
+                crate::fs_reference("/ns/a"),
+                crate::fs_reference("/ns/a"),
+                "users"
+            )
+            .count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use crate::fs_timestamp::{format_date, format_timestamp};
+use crate::FsError;
+use crate::{FsNumber, FsValue};
+use pgrx::prelude::*;
+use serde_json::Value;
+
+// Budget of values interpolated into log lines and error messages
+pub(crate) const DISPLAY_TEXT_LIMIT: usize = 256;
+
+const ELLIPSIS: &str = "…";
+
+// Cuts `text` to at most `budget` bytes on a character boundary, marking the
+// cut with an ellipsis.
+fn truncate(text: String, budget: usize) -> String {
+    if text.len() <= budget {
+        return text;
+    }
+    if budget < ELLIPSIS.len() {
+        return String::new();
+    }
+    let mut end = budget - ELLIPSIS.len();
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &text[..end], ELLIPSIS)
+}
+
+// A quoted, escaped string of at most `budget` bytes. Characters are escaped
+// one at a time so that an escape sequence is never cut in half.
+fn quoted(string: &str, budget: usize) -> String {
+    if string.len() + 2 <= budget {
+        let text = Value::String(string.to_owned()).to_string();
+        if text.len() <= budget {
+            return text;
+        }
+    }
+    let mut text = String::from("\"");
+    for (index, c) in string.char_indices() {
+        let escaped = Value::String(c.to_string()).to_string();
+        let escaped = &escaped[1..escaped.len() - 1];
+        let is_last = index + c.len_utf8() == string.len();
+        // Room for the closing quote, and for an ellipsis unless this is the
+        // last character
+        let reserve = 1 + if is_last { 0 } else { ELLIPSIS.len() };
+        if text.len() + escaped.len() + reserve > budget {
+            if text.len() + ELLIPSIS.len() + 1 > budget {
+                return truncate(text, budget);
+            }
+            text.push_str(ELLIPSIS);
+            break;
+        }
+        text.push_str(escaped);
+    }
+    text.push('"');
+    truncate(text, budget)
+}
+
+fn number_text(number: &FsNumber) -> String {
+    match number {
+        FsNumber::NAN => "NaN".to_owned(),
+        FsNumber::PositiveInfinity => "Infinity".to_owned(),
+        FsNumber::NegativeInfinity => "-Infinity".to_owned(),
+        FsNumber::Number(number) => number.to_string(),
+    }
+}
+
+// `header` followed by as many `entries` as fit in `budget`, e.g.
+// `ARRAY[3: 1, 2, …]`
+fn container<'a, I>(header: String, close: &str, entries: I, budget: usize) -> String
+where
+    I: ExactSizeIterator<Item = (Option<&'a str>, &'a FsValue)>,
+{
+    let mut text = header;
+    let count = entries.len();
+    for (index, (key, value)) in entries.enumerate() {
+        let separator = if index == 0 { ": " } else { ", " };
+        let reserve = close.len()
+            + if index + 1 == count {
+                0
+            } else {
+                ", ".len() + ELLIPSIS.len()
+            };
+        let available = budget.saturating_sub(text.len() + separator.len() + reserve);
+        let mut entry = String::new();
+        if let Some(key) = key {
+            entry.push_str(&quoted(key, available / 2));
+            entry.push_str(": ");
+        }
+        let value = display_text(value, available.saturating_sub(entry.len()));
+        // Leaves out entries that would not show anything of their value
+        if value.is_empty() || value == ELLIPSIS {
+            text.push_str(separator);
+            text.push_str(ELLIPSIS);
+            break;
+        }
+        entry.push_str(&value);
+        text.push_str(separator);
+        text.push_str(&entry);
+    }
+    text.push_str(close);
+    truncate(text, budget)
+}
+
+// A single line summary of `value` of at most `budget` bytes. Scalars are
+// shown fully when they fit, containers by their size and leading entries.
+pub(crate) fn display_text(value: &FsValue, budget: usize) -> String {
+    let text = match value {
+        FsValue::NULL => "NULL".to_owned(),
+        FsValue::Boolean(boolean) => boolean.to_string(),
+        FsValue::Number(number) => number_text(number),
+        FsValue::Date(date) => format!("DATE({})", format_date(date)),
+        FsValue::Timestamp(micros) => format_timestamp(*micros),
+        FsValue::String(string) => return quoted(string, budget),
+        FsValue::Bytes(bytes) => format!("BYTES[{}]", bytes.len()),
+        FsValue::Reference(reference) => reference.to_string(),
+        FsValue::GeoPoint(latitude, longitude) => format!(
+            "GEOPOINT({}, {})",
+            number_text(latitude),
+            number_text(longitude)
+        ),
+        FsValue::Array(array) => {
+            return container(
+                format!("ARRAY[{}", array.len()),
+                "]",
+                array.iter().map(|element| (None, element)),
+                budget,
+            )
+        }
+        FsValue::Map(map) => {
+            return container(
+                format!(
+                    "MAP{{{} field{}",
+                    map.len(),
+                    if map.len() == 1 { "" } else { "s" }
+                ),
+                "}",
+                map.iter().map(|(key, value)| (Some(key.as_str()), value)),
+                budget,
+            )
+        }
+    };
+    truncate(text, budget)
+}
+
+// `value` cut to the error message budget
+pub(crate) fn display_value(value: &FsValue) -> String {
+    display_text(value, DISPLAY_TEXT_LIMIT)
+}
+
+// The compact JSON of `value`, cut to the error message budget
+pub(crate) fn display_json(value: &Value) -> String {
+    truncate(value.to_string(), DISPLAY_TEXT_LIMIT)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_to_display_text(fs_value: FsValue, max_len: default!(i32, 256)) -> String {
+    if max_len < 0 {
+        FsError::InvalidValue(format!(
+            "Display length must not be negative but found {}",
+            max_len
+        ))
+        .report()
+    }
+    display_text(&fs_value, max_len as usize)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_display::*;
+    use crate::fs_test_util::plain;
+    use serde_json::json;
+
+    #[test]
+    fn test_display_scalars() {
+        assert_eq!(display_text(&FsValue::NULL, 256), "NULL");
+        assert_eq!(display_text(&plain(json!(1.5)), 256), "1.5");
+        assert_eq!(
+            display_text(&FsValue::String("a\"b\nc".to_owned()), 256),
+            "\"a\\\"b\\nc\""
+        );
+        assert_eq!(
+            display_text(&FsValue::String("abcdef".to_owned()), 7),
+            "\"ab…\""
+        );
+        assert_eq!(
+            display_text(&FsValue::Bytes(vec![0; 1024]), 256),
+            "BYTES[1024]"
+        );
+        assert_eq!(
+            display_text(&crate::fs_reference("/users/1"), 256),
+            "/users/1"
+        );
+    }
+
+    #[test]
+    fn test_display_multibyte_boundary() {
+        // Every é takes two bytes, so no budget may split one
+        let string = FsValue::String("é".repeat(10));
+        for budget in 0..30 {
+            let text = display_text(&string, budget);
+            assert!(text.len() <= budget, "{} > {}", text.len(), budget);
+        }
+        assert_eq!(display_text(&string, 10), "\"éé…\"");
+        assert_eq!(display_text(&string, 22), "\"éééééééééé\"");
+        assert_eq!(truncate("aébcd".to_owned(), 5), "a…");
+    }
+
+    #[test]
+    fn test_display_containers() {
+        assert_eq!(display_text(&plain(json!([])), 256), "ARRAY[0]");
+        assert_eq!(display_text(&plain(json!({})), 256), "MAP{0 fields}");
+        assert_eq!(
+            display_text(&plain(json!({"a": 1, "b": [true, "x"]})), 256),
+            "MAP{2 fields: \"a\": 1, \"b\": ARRAY[2: true, \"x\"]}"
+        );
+        let array = plain(json!((0..34).collect::<Vec<i32>>()));
+        assert_eq!(display_text(&array, 24), "ARRAY[34: 0, 1, 2, …]");
+        let map = plain(json!({"a": {"b": {"c": "long text"}}}));
+        assert_eq!(display_text(&map, 30), "MAP{1 field: \"a\": MAP{1 fi…}");
+    }
+
+    #[test]
+    fn test_display_fits_budget() {
+        let nested = plain(json!({
+            "name": "ünïcödé ".repeat(100),
+            "tags": vec!["a"; 1000],
+            "deep": {"deeper": {"deepest": [1, 2, {"x": "y"}]}}
+        }));
+        for budget in 0..400 {
+            assert!(display_text(&nested, budget).len() <= budget);
+        }
+    }
+
+    #[test]
+    fn test_display_parse_error() {
+        let huge = json!({"value": {"nested": "x".repeat(1_000_000)}});
+        let error = FsValue::from(huge).unwrap_err().to_string();
+        assert!(error.len() < 2 * DISPLAY_TEXT_LIMIT, "{}", error.len());
+    }
+
+    #[pg_test]
+    fn test_fs_to_display_text() {
+        assert_eq!(
+            fs_to_display_text(plain(json!({"name": "Ada"})), 256),
+            "MAP{1 field: \"name\": \"Ada\"}"
+        );
+    }
+}
@@ -0,0 +1,1884 @@
+use crate::fs_activity;
+use crate::fs_display::display_value;
+use crate::fs_error::{report, FsCode};
+use crate::fs_freeze::{find_frozen_in_subtree, report_frozen};
+use crate::fs_guc;
+use crate::fs_rest::{generate_document_id, AUTO_ID_ATTEMPTS};
+use crate::fs_unique::report_unique_violations;
+use crate::{
+    fs_is_valid_document_key, fs_is_valid_document_properties, parse_field_names, FieldPath,
+    FsError, FsNumber, FsReference, FsValue,
+};
+use pgrx::prelude::*;
+use pgrx::{Interval, JsonB, PgBuiltInOids, PgOid};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+pub(crate) fn fsvalue_arg(value: FsValue) -> (PgOid, Option<pg_sys::Datum>) {
+    (PgOid::from(FsValue::type_oid()), value.into_datum())
+}
+
+pub(crate) fn text_arg(text: &str) -> (PgOid, Option<pg_sys::Datum>) {
+    (PgBuiltInOids::TEXTOID.oid(), text.into_datum())
+}
+
+pub(crate) fn jsonb_arg(value: Value) -> (PgOid, Option<pg_sys::Datum>) {
+    (PgBuiltInOids::JSONBOID.oid(), JsonB(value).into_datum())
+}
+
+const SCAN_BATCH_SIZE: i64 = 1000;
+
+// Visits the (reference, properties) rows returned by `query` through a cursor
+// so that only one batch of rows is held in memory at a time.
+pub(crate) fn scan_documents<F>(
+    query: &str,
+    args: Vec<(PgOid, Option<pg_sys::Datum>)>,
+    mut visit: F,
+) where
+    F: FnMut(FsValue, FsValue),
+{
+    Spi::connect(|client| {
+        let mut cursor = client.open_cursor(query, Some(args));
+        loop {
+            // Honors statement_timeout and cancel requests on long scans
+            check_for_interrupts!();
+            let table = cursor.fetch(SCAN_BATCH_SIZE)?;
+            if table.is_empty() {
+                return Ok::<(), pgrx::spi::Error>(());
+            }
+            for row in table {
+                visit(
+                    row.get::<FsValue>(1)?.expect("reference must not be null"),
+                    row.get::<FsValue>(2)?.unwrap_or(FsValue::NULL),
+                );
+            }
+        }
+    })
+    .expect("Failed to scan fs_documents")
+}
+
+pub(crate) fn expect_document_reference(reference: &FsValue) -> &FsReference {
+    if !fs_is_valid_document_key(reference.to_owned()) {
+        FsError::InvalidType(format!(
+            "Expecting a document reference but found {}",
+            display_value(reference)
+        ))
+        .report()
+    }
+    reference.expect_reference()
+}
+
+pub(crate) fn expect_parent_reference(parent: &FsValue) -> &FsReference {
+    let fs_ref = parent.expect_reference();
+    if !fs_ref.has_complete_path() {
+        FsError::InvalidValue(format!(
+            "Expecting the database root or a document reference but found {}",
+            fs_ref
+        ))
+        .report()
+    }
+    fs_ref
+}
+
+pub(crate) fn get_document(reference: &FsReference) -> Option<FsValue> {
+    read_document(reference, false)
+}
+
+// With `include_deleted`, the last properties of a soft-deleted document are
+// returned as well
+fn read_document(reference: &FsReference, include_deleted: bool) -> Option<FsValue> {
+    Spi::connect(|client| {
+        let mut table = client.select(
+            "SELECT properties FROM fs_documents \
+             WHERE reference = $1 AND ($2 OR deleted_at IS NULL)",
+            Some(1),
+            Some(vec![
+                fsvalue_arg(FsValue::Reference(reference.to_owned())),
+                (PgBuiltInOids::BOOLOID.oid(), include_deleted.into_datum()),
+            ]),
+        )?;
+        match table.next() {
+            Some(row) => row.get::<FsValue>(1),
+            None => Ok(None),
+        }
+    })
+    .expect("Failed to read from fs_documents")
+}
+
+// Writing over a tombstone re-creates the document: it gets a new
+// create_time and is no longer deleted.
+pub(crate) const REVIVE_TOMBSTONE: &str = "\
+    create_time = CASE WHEN fs_documents.deleted_at IS NULL \
+        THEN fs_documents.create_time ELSE fs_request_time() END, \
+    deleted_at = NULL";
+
+// The upsert of the (reference, properties) rows of `source`. ON CONFLICT
+// locks the existing row and resolves the conflict against its latest
+// version, so concurrent writers of a new reference do not fail with a unique
+// violation and a merge never works from a stale read of the document.
+fn upsert_statement(source: &str, skip_unchanged: bool, merge: bool) -> String {
+    let properties = if merge {
+        "CASE WHEN fs_documents.deleted_at IS NULL \
+         THEN fs_map_merge(fs_documents.properties, EXCLUDED.properties, true) \
+         ELSE EXCLUDED.properties END"
+    } else {
+        "EXCLUDED.properties"
+    };
+    let condition = if skip_unchanged {
+        format!(
+            " WHERE fs_documents.deleted_at IS NOT NULL \
+             OR NOT fs_identical(fs_documents.properties, {})",
+            properties
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "INSERT INTO fs_documents (reference, properties) {} \
+         ON CONFLICT (reference) DO UPDATE \
+         SET properties = {}, update_time = fs_request_time(), {}{} \
+         RETURNING reference",
+        source, properties, REVIVE_TOMBSTONE, condition
+    )
+}
+
+fn count_write(reference: &FsReference, written: bool) -> bool {
+    if written {
+        fs_activity::count_document(reference, 0, 1);
+    }
+    written
+}
+
+// Creates or overwrites a document, returning whether anything was written.
+// With `skip_unchanged`, overwriting a document with equal properties is a
+// no-op that leaves its update_time alone. With `merge`, the properties are
+// merged into those of an existing document.
+pub(crate) fn set_document(
+    reference: &FsReference,
+    properties: FsValue,
+    skip_unchanged: bool,
+    merge: bool,
+) -> bool {
+    report_unique_violations(&[(reference, &properties)], || {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    &upsert_statement("VALUES ($1, $2)", skip_unchanged, merge),
+                    None,
+                    Some(vec![
+                        fsvalue_arg(FsValue::Reference(reference.to_owned())),
+                        fsvalue_arg(properties.to_owned()),
+                    ]),
+                )
+                .map(|table| !table.is_empty())
+        })
+    })
+    .map(|written| count_write(reference, written))
+    .expect("Failed to write to fs_documents")
+}
+
+// Inserts a new document, returning false without writing anything when the
+// document already exists. A tombstone does not count as existing.
+pub(crate) fn create_document(reference: &FsReference, properties: FsValue) -> bool {
+    report_unique_violations(&[(reference, &properties)], || {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    &format!(
+                        "INSERT INTO fs_documents (reference, properties) VALUES ($1, $2) \
+                         ON CONFLICT (reference) DO UPDATE \
+                         SET properties = EXCLUDED.properties, update_time = fs_request_time(), {} \
+                         WHERE fs_documents.deleted_at IS NOT NULL \
+                         RETURNING reference",
+                        REVIVE_TOMBSTONE
+                    ),
+                    None,
+                    Some(vec![
+                        fsvalue_arg(FsValue::Reference(reference.to_owned())),
+                        fsvalue_arg(properties.to_owned()),
+                    ]),
+                )
+                .map(|table| !table.is_empty())
+        })
+    })
+    .map(|written| count_write(reference, written))
+    .expect("Failed to write to fs_documents")
+}
+
+// The statement removing the documents matching `condition`, or marking them
+// deleted when pgfirestore.soft_delete is on or `soft`
+fn delete_statement(condition: &str, soft: bool) -> String {
+    if soft || fs_guc::SOFT_DELETE.get() {
+        format!(
+            "UPDATE fs_documents SET deleted_at = fs_request_time(), update_time = fs_request_time() \
+             WHERE ({}) AND deleted_at IS NULL RETURNING reference",
+            condition
+        )
+    } else {
+        format!(
+            "DELETE FROM fs_documents WHERE ({}) AND deleted_at IS NULL RETURNING reference",
+            condition
+        )
+    }
+}
+
+// Returns whether a document was deleted. Deleting a tombstone is a no-op
+// like deleting a missing document.
+pub(crate) fn delete_document(reference: &FsReference, soft: bool) -> bool {
+    Spi::connect(|mut client| {
+        client
+            .update(
+                &delete_statement("reference = $1", soft),
+                None,
+                Some(vec![fsvalue_arg(FsValue::Reference(reference.to_owned()))]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .map(|deleted| count_write(reference, deleted))
+    .expect("Failed to delete from fs_documents")
+}
+
+pub(crate) fn parse_update_mask(field_paths: &[String]) -> Result<Vec<Vec<String>>> {
+    field_paths
+        .iter()
+        .map(|field_path| FieldPath::from_str(field_path)?.into_field_names())
+        .collect()
+}
+
+// Firestore update semantics: every masked field present in `patch` is copied
+// into `base` (creating intermediate maps as needed) and every masked field
+// missing from `patch` is removed from `base`. Fields outside of the mask are
+// left untouched.
+pub(crate) fn apply_update_mask(
+    base: FsValue,
+    patch: &FsValue,
+    update_mask: &[Vec<String>],
+) -> FsValue {
+    let mut updated = match base {
+        FsValue::Map(_) => base,
+        _ => FsValue::Map(BTreeMap::new()),
+    };
+    for field_names in update_mask.iter() {
+        match patch.get_field(field_names) {
+            Some(value) => updated.set_field(field_names, value.to_owned()),
+            None => {
+                updated.remove_field(field_names);
+            }
+        }
+    }
+    updated
+}
+
+// The stand-in for FieldValue.delete() in a merge patch. Firestore reserves
+// field names of the form `__.*__`, so no stored document holds this map.
+const DELETE_SENTINEL_KEY: &str = "__delete__";
+
+fn delete_sentinel() -> FsValue {
+    FsValue::Map(BTreeMap::from([(
+        DELETE_SENTINEL_KEY.to_owned(),
+        FsValue::Boolean(true),
+    )]))
+}
+
+fn is_delete_sentinel(value: &FsValue) -> bool {
+    value == &delete_sentinel()
+}
+
+// Firestore set() with merge: fields of `patch` overwrite those of `base`
+// while maps present on both sides are merged recursively. A delete sentinel
+// removes the field instead. A map patch over a missing or non-map base is
+// merged into an empty map so that none of its sentinels are kept.
+pub(crate) fn merge_properties(base: FsValue, patch: FsValue) -> FsValue {
+    match (base, patch) {
+        (FsValue::Map(mut base), FsValue::Map(patch)) => {
+            for (key, value) in patch.into_iter() {
+                let existing = base.remove(&key);
+                if is_delete_sentinel(&value) {
+                    continue;
+                }
+                let merged = merge_properties(existing.unwrap_or(FsValue::NULL), value);
+                base.insert(key, merged);
+            }
+            FsValue::Map(base)
+        }
+        (_, patch @ FsValue::Map(_)) => merge_properties(FsValue::Map(BTreeMap::new()), patch),
+        (_, patch) => patch,
+    }
+}
+
+// Merges `patch` into `base`, recursing into maps present on both sides
+// unless `recursive` is false, in which case top level fields are replaced.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_merge(base: FsValue, patch: FsValue, recursive: default!(bool, true)) -> FsValue {
+    match (base, patch) {
+        (base, patch) if recursive => merge_properties(base, patch),
+        (FsValue::Map(mut base), FsValue::Map(patch)) => {
+            for (key, value) in patch {
+                if is_delete_sentinel(&value) {
+                    base.remove(&key);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+            FsValue::Map(base)
+        }
+        (_, patch @ FsValue::Map(_)) => fs_map_merge(FsValue::Map(BTreeMap::new()), patch, false),
+        (_, patch) => patch,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_delete_sentinel() -> FsValue {
+    delete_sentinel()
+}
+
+pub(crate) fn fsvalue_array_arg(values: Vec<FsValue>) -> (PgOid, Option<pg_sys::Datum>) {
+    (PgOid::from(Vec::<FsValue>::type_oid()), values.into_datum())
+}
+
+// Checks the arrays pairwise, reporting the 1-based array position of the
+// first invalid element.
+fn validate_bulk_documents(
+    references: Vec<Option<FsValue>>,
+    properties: Vec<Option<FsValue>>,
+) -> Result<(Vec<FsValue>, Vec<FsValue>)> {
+    if references.len() != properties.len() {
+        return Err(FsError::InvalidValue(format!(
+            "References size ({}) does not match properties size ({})",
+            references.len(),
+            properties.len()
+        )));
+    }
+    let mut positions: BTreeMap<FsValue, usize> = BTreeMap::new();
+    let mut valid_references = Vec::with_capacity(references.len());
+    let mut valid_properties = Vec::with_capacity(properties.len());
+    for (index, (reference, properties)) in references.into_iter().zip(properties).enumerate() {
+        let position = index + 1;
+        let reference = match reference {
+            Some(reference) if fs_is_valid_document_key(reference.to_owned()) => reference,
+            Some(FsValue::Reference(reference)) => {
+                return Err(FsError::InvalidValue(format!(
+                    "Expecting a document reference at position {} but found {}",
+                    position, reference
+                )))
+            }
+            reference => {
+                return Err(FsError::InvalidValue(format!(
+                    "Expecting a document reference at position {} but found {}",
+                    position,
+                    reference.as_ref().map_or("NULL".to_owned(), display_value)
+                )))
+            }
+        };
+        let properties = match properties {
+            Some(properties @ FsValue::Map(_)) => properties,
+            properties => {
+                return Err(FsError::InvalidValue(format!(
+                    "Expecting map properties at position {} but found {}",
+                    position,
+                    properties.as_ref().map_or("NULL".to_owned(), display_value)
+                )))
+            }
+        };
+        if let Some(first) = positions.insert(reference.to_owned(), position) {
+            return Err(FsError::InvalidValue(format!(
+                "Duplicate reference {} at positions {} and {}",
+                reference.expect_reference(),
+                first,
+                position
+            )));
+        }
+        valid_references.push(reference);
+        valid_properties.push(properties);
+    }
+    Ok((valid_references, valid_properties))
+}
+
+#[pg_extern]
+fn fs_get(reference: FsValue, include_deleted: default!(bool, false)) -> Option<FsValue> {
+    let fs_ref = expect_document_reference(&reference);
+    fs_activity::count_document(fs_ref, 1, 0);
+    read_document(fs_ref, include_deleted)
+}
+
+// The properties of the document `value` points at, if it is a reference to
+// an existing document
+fn deref(value: &FsValue) -> Option<FsValue> {
+    match value.as_reference() {
+        Some(reference) if !reference.is_root() && reference.has_complete_path() => {
+            get_document(reference)
+        }
+        _ => None,
+    }
+}
+
+#[pg_extern]
+fn fs_deref(value: FsValue) -> Option<FsValue> {
+    deref(&value)
+}
+
+#[pg_extern]
+fn fs_deref_field(doc: FsValue, path: &str) -> Option<FsValue> {
+    doc.get_field(&parse_field_names(path)).and_then(deref)
+}
+
+// Replaces the reference at each of `paths` by a map of the reference and
+// the properties of its target, NULL for a dangling reference. Paths that do
+// not hold a reference are left alone.
+#[pg_extern]
+fn fs_resolve_references(doc: FsValue, paths: Vec<String>) -> FsValue {
+    let mut resolved = doc.to_owned();
+    for path in paths.iter() {
+        let field_names = parse_field_names(path);
+        if let Some(reference @ FsValue::Reference(_)) = doc.get_field(&field_names) {
+            let target = BTreeMap::from([
+                ("reference".to_owned(), reference.to_owned()),
+                (
+                    "properties".to_owned(),
+                    deref(reference).unwrap_or(FsValue::NULL),
+                ),
+            ]);
+            resolved.set_field(&field_names, FsValue::Map(target));
+        }
+    }
+    resolved
+}
+
+#[pg_extern]
+fn fs_set(
+    reference: FsValue,
+    properties: FsValue,
+    skip_unchanged: default!(bool, true),
+    merge: default!(bool, false),
+) -> bool {
+    let fs_ref = expect_document_reference(&reference);
+    set_document(fs_ref, properties, skip_unchanged, merge)
+}
+
+#[pg_extern]
+fn fs_generate_document_id() -> String {
+    generate_document_id()
+}
+
+// Creates a document with an auto ID in a collection of `parent`, like add()
+// in Firestore, and returns its reference. An ID that is taken, however
+// unlikely, is replaced by a fresh one.
+#[pg_extern]
+fn fs_create_document(parent: FsValue, collection_id: &str, properties: FsValue) -> FsValue {
+    let fs_ref = expect_parent_reference(&parent);
+    if !fs_is_valid_document_properties(properties.to_owned()) {
+        FsError::InvalidType(format!(
+            "Expecting a map of properties but found {}",
+            display_value(&properties)
+        ))
+        .report()
+    }
+    for _ in 0..AUTO_ID_ATTEMPTS {
+        let reference = fs_ref
+            .child(collection_id, &generate_document_id())
+            .unwrap_or_else(|error| error.report());
+        if create_document(&reference, properties.to_owned()) {
+            return FsValue::Reference(reference);
+        }
+    }
+    report(
+        FsCode::AlreadyExists,
+        format!(
+            "No free document ID in {} after {} attempts",
+            fs_ref
+                .push_element(collection_id, None)
+                .unwrap_or_else(|error| error.report()),
+            AUTO_ID_ATTEMPTS
+        ),
+    )
+}
+
+// Writes all documents with a single statement, returning the number of
+// documents written. Like fs_set, unchanged documents are not rewritten.
+#[pg_extern]
+fn fs_bulk_set(
+    references: Vec<Option<FsValue>>,
+    properties: Vec<Option<FsValue>>,
+    merge: default!(bool, false),
+) -> i64 {
+    let (references, properties) = match validate_bulk_documents(references, properties) {
+        Ok(documents) => documents,
+        Err(error) => error.report(),
+    };
+    let documents = references
+        .iter()
+        .filter_map(FsValue::as_reference)
+        .zip(properties.iter())
+        .collect::<Vec<_>>();
+    report_unique_violations(&documents, || {
+        Spi::connect(|mut client| {
+            client
+                .update(
+                    &upsert_statement("SELECT * FROM unnest($1, $2)", true, merge),
+                    None,
+                    Some(vec![
+                        fsvalue_array_arg(references.to_owned()),
+                        fsvalue_array_arg(properties.to_owned()),
+                    ]),
+                )?
+                .map(|row| row.get::<FsValue>(1))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        })
+    })
+    .map(|written| {
+        fs_activity::count_documents(
+            written.iter().flatten().filter_map(FsValue::as_reference),
+            false,
+        );
+        written.len() as i64
+    })
+    .expect("Failed to write to fs_documents")
+}
+
+// Bumps the update_time of a document without changing its properties,
+// returning whether the document exists.
+#[pg_extern]
+fn fs_touch(reference: FsValue) -> bool {
+    expect_document_reference(&reference);
+    Spi::connect(|mut client| {
+        client
+            .update(
+                "UPDATE fs_documents SET update_time = fs_request_time() \
+                 WHERE reference = $1 AND deleted_at IS NULL RETURNING reference",
+                None,
+                Some(vec![fsvalue_arg(reference)]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to write to fs_documents")
+}
+
+// Without `field_paths`, the top level keys of `properties` are used as the
+// update mask, like the client SDKs' update().
+#[pg_extern]
+fn fs_update(
+    reference: FsValue,
+    properties: FsValue,
+    field_paths: default!(Option<Vec<String>>, "NULL"),
+) -> FsValue {
+    let fs_ref = expect_document_reference(&reference);
+    let fields = properties.as_map().unwrap_or_else(|| {
+        FsError::InvalidType(format!(
+            "Expecting a map of properties but found {}",
+            display_value(&properties)
+        ))
+        .report()
+    });
+    let update_mask = match field_paths {
+        Some(field_paths) => match parse_update_mask(&field_paths) {
+            Ok(update_mask) => update_mask,
+            Err(error) => error.report(),
+        },
+        None => fields.keys().map(|key| vec![key.to_owned()]).collect(),
+    };
+    let existing = get_document(fs_ref).unwrap_or_else(|| {
+        report(
+            FsCode::NotFound,
+            format!("Cannot update document {} which does not exist", fs_ref),
+        )
+    });
+    let updated = apply_update_mask(existing, &properties, &update_mask);
+    set_document(fs_ref, updated.to_owned(), true, false);
+    updated
+}
+
+#[pg_extern]
+fn fs_delete(reference: FsValue) -> bool {
+    delete_document(expect_document_reference(&reference), false)
+}
+
+// Leaves a tombstone whatever pgfirestore.soft_delete says
+#[pg_extern]
+fn fs_soft_delete(reference: FsValue) -> bool {
+    delete_document(expect_document_reference(&reference), true)
+}
+
+// Removes tombstones of documents deleted more than `older_than` before the
+// request time, returning how many were removed
+#[pg_extern]
+fn fs_purge_tombstones(older_than: Interval) -> i64 {
+    Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM fs_documents \
+                 WHERE deleted_at < fs_request_time() - $1 RETURNING reference",
+                None,
+                Some(vec![(
+                    PgBuiltInOids::INTERVALOID.oid(),
+                    older_than.into_datum(),
+                )]),
+            )
+            .map(|table| table.len() as i64)
+    })
+    .expect("Failed to delete from fs_documents")
+}
+
+// Deletes a document together with all documents below it, returning the
+// number of deleted documents. Nothing is deleted if any of them is frozen.
+// Like fs_delete, it leaves tombstones when pgfirestore.soft_delete is on.
+// The documents below it are a range of the primary key, as in
+// fs_descendants.
+#[pg_extern]
+fn fs_delete_recursive(reference: FsValue) -> i64 {
+    let fs_ref = expect_document_reference(&reference);
+    if let Some(frozen) = find_frozen_in_subtree(&reference) {
+        let frozen_ref = frozen.expect_reference();
+        report_frozen(format!(
+            "Cannot delete {} recursively because {} is frozen",
+            fs_ref, frozen_ref
+        ));
+    }
+    Spi::connect(|mut client| {
+        client
+            .update(
+                &delete_statement(
+                    "reference = $1 \
+                     OR (reference > $1 AND reference < fs_prefix_successor($1))",
+                    false,
+                ),
+                None,
+                Some(vec![fsvalue_arg(reference.to_owned())]),
+            )
+            .map(|table| table.len() as i64)
+    })
+    .expect("Failed to delete from fs_documents")
+}
+
+// The document ID of an embedded document: its `id_field` when it holds a
+// string or an integer, and an auto ID otherwise
+fn embedded_document_id(element: &FsValue, index: usize, id_field: Option<&str>) -> Result<String> {
+    let id = match id_field.and_then(|id_field| element.as_map()?.get(id_field)) {
+        None => return Ok(generate_document_id()),
+        Some(FsValue::String(id)) => id.to_owned(),
+        Some(FsValue::Number(FsNumber::Number(id))) if id.is_i64() => id.to_string(),
+        Some(id) => {
+            return Err(FsError::InvalidValue(format!(
+                "Array element at index {} has an ID of {} rather than a string or integer",
+                index,
+                display_value(id)
+            )))
+        }
+    };
+    if id.is_empty() || id.contains('/') {
+        return Err(FsError::InvalidValue(format!(
+            "Array element at index {} has an invalid ID '{}'",
+            index, id
+        )));
+    }
+    Ok(id)
+}
+
+// Splits the array at `field_names` off `document` into child documents of
+// `reference`, returning the children and the trimmed document.
+fn promote_array(
+    reference: &FsReference,
+    mut document: FsValue,
+    field_names: &[String],
+    collection_id: &str,
+    id_field: Option<&str>,
+) -> Result<(Vec<(FsReference, FsValue)>, FsValue)> {
+    let elements = match document.remove_field(field_names) {
+        Some(FsValue::Array(elements)) => elements,
+        other => {
+            return Err(FsError::InvalidValue(format!(
+                "Expecting an array at '{}' of document {} but found {}",
+                field_names.join("."),
+                reference,
+                other.as_ref().map_or("nothing".to_owned(), display_value)
+            )))
+        }
+    };
+    let mut children = Vec::with_capacity(elements.len());
+    for (index, element) in elements.into_iter().enumerate() {
+        if !matches!(element, FsValue::Map(_)) {
+            return Err(FsError::InvalidValue(format!(
+                "Array element at index {} is not a map but {}",
+                index,
+                display_value(&element)
+            )));
+        }
+        let id = embedded_document_id(&element, index, id_field)?;
+        children.push((reference.child(collection_id, &id)?, element));
+    }
+    Ok((children, document))
+}
+
+// Moves an array of embedded documents into a subcollection of the document,
+// returning the number of documents created. Elements keep all their fields.
+// Nothing is written unless every element can be promoted.
+#[pg_extern]
+fn fs_promote_array_to_collection(
+    reference: FsValue,
+    array_path: &str,
+    collection_id: &str,
+    id_field: default!(Option<&str>, "NULL"),
+) -> i64 {
+    let fs_ref = expect_document_reference(&reference);
+    let document = get_document(fs_ref).unwrap_or_else(|| {
+        report(
+            FsCode::NotFound,
+            format!(
+                "Cannot promote an array of document {} which does not exist",
+                fs_ref
+            ),
+        )
+    });
+    let promoted = FieldPath::from_str(array_path)
+        .and_then(FieldPath::into_field_names)
+        .and_then(|field_names| {
+            promote_array(fs_ref, document, &field_names, collection_id, id_field)
+        });
+    let (children, trimmed) = match promoted {
+        Ok(promoted) => promoted,
+        Err(error) => error.report(),
+    };
+    for (child, properties) in children.iter() {
+        if !create_document(child, properties.to_owned()) {
+            report(
+                FsCode::AlreadyExists,
+                format!("Document {} already exists", child),
+            )
+        }
+    }
+    set_document(fs_ref, trimmed, true, false);
+    children.len() as i64
+}
+
+// The IDs of the collections holding documents directly below `parent`, the
+// database root or a document, like listCollectionIds. Rather than reading
+// every descendant, each step looks up the first descendant after the
+// collections found so far and skips past the rest of its collection.
+#[pg_extern]
+fn fs_collections(parent: FsValue) -> SetOfIterator<'static, String> {
+    let fs_ref = expect_parent_reference(&parent);
+    let depth = fs_ref.path_len();
+    let mut collection_ids = Vec::new();
+    let mut after = parent.to_owned();
+    let upper = fs_ref.prefix_successor().map(FsValue::Reference);
+    while let Some(descendant) = Spi::get_one_with_args::<FsValue>(
+        "SELECT (SELECT reference FROM fs_documents \
+         WHERE reference > $1 AND ($2::fsvalue IS NULL OR reference < $2) \
+             AND deleted_at IS NULL \
+         ORDER BY reference LIMIT 1)",
+        vec![
+            fsvalue_arg(after),
+            (
+                PgOid::from(FsValue::type_oid()),
+                upper.to_owned().into_datum(),
+            ),
+        ],
+    )
+    .expect("Failed to read from fs_documents")
+    {
+        check_for_interrupts!();
+        let collection = descendant
+            .expect_reference()
+            .element_at(depth)
+            .expect("a descendant is longer than its parent")
+            .collection_id()
+            .to_owned();
+        let collection_ref = fs_ref
+            .push_element(&collection, None)
+            .unwrap_or_else(|error| error.report());
+        collection_ids.push(collection);
+        match collection_ref.prefix_successor() {
+            Some(successor) => after = FsValue::Reference(successor),
+            None => break,
+        }
+    }
+    SetOfIterator::new(collection_ids.into_iter())
+}
+
+// The next `batch_size` documents of a collection after `after`, in
+// reference order
+fn collection_batch(
+    parent: &FsValue,
+    collection_id: &str,
+    after: Option<FsValue>,
+    batch_size: i32,
+) -> Vec<(FsValue, FsValue)> {
+    Spi::connect(|client| {
+        let mut batch = Vec::new();
+        for row in client.select(
+            "SELECT reference, properties FROM fs_collection($1, $2) \
+             WHERE $3::fsvalue IS NULL OR reference > $3 \
+             ORDER BY reference LIMIT $4",
+            None,
+            Some(vec![
+                fsvalue_arg(parent.to_owned()),
+                text_arg(collection_id),
+                (PgOid::from(FsValue::type_oid()), after.into_datum()),
+                (PgBuiltInOids::INT4OID.oid(), batch_size.into_datum()),
+            ]),
+        )? {
+            batch.push((
+                row.get::<FsValue>(1)?.expect("reference must not be null"),
+                row.get::<FsValue>(2)?.unwrap_or(FsValue::NULL),
+            ));
+        }
+        Ok::<_, pgrx::spi::Error>(batch)
+    })
+    .expect("Failed to read from fs_documents")
+}
+
+// Sets a field on every document of a collection, returning the number of
+// documents modified. Documents that already have the field are skipped when
+// `only_if_missing`. Everything runs in the calling transaction: documents
+// are read `batch_size` at a time to bound memory, not committed per batch.
+#[pg_extern]
+fn fs_set_field_all(
+    parent: FsValue,
+    collection_id: &str,
+    path: &str,
+    value: FsValue,
+    only_if_missing: default!(bool, true),
+    batch_size: default!(i32, 1000),
+) -> i64 {
+    expect_parent_reference(&parent);
+    if batch_size <= 0 {
+        FsError::InvalidValue(format!(
+            "Batch size must be positive but found {}",
+            batch_size
+        ))
+        .report()
+    }
+    let field_names = match FieldPath::from_str(path).and_then(FieldPath::into_field_names) {
+        Ok(field_names) => field_names,
+        Err(error) => error.report(),
+    };
+    let mut modified = 0;
+    let mut after = None;
+    loop {
+        // Honors statement_timeout and cancel requests on large collections
+        check_for_interrupts!();
+        let batch = collection_batch(&parent, collection_id, after, batch_size);
+        after = match batch.last() {
+            Some((reference, _)) => Some(reference.to_owned()),
+            None => return modified,
+        };
+        for (reference, mut properties) in batch.into_iter() {
+            if only_if_missing && properties.get_field(&field_names).is_some() {
+                continue;
+            }
+            properties.set_field(&field_names, value.to_owned());
+            let fs_ref = reference.expect_reference();
+            if set_document(fs_ref, properties, true, false) {
+                modified += 1;
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_documents::*;
+    use crate::fs_test_util::plain;
+    use crate::{
+        fs_boolean, fs_map_from_entries, fs_number_from_double, fs_number_from_integer,
+        fs_reference,
+    };
+    use serde_json::{json, Value};
+
+    #[pg_test]
+    fn test_fs_get() {
+        assert_eq!(
+            fs_get(fs_reference("/users/2"), false),
+            Some(fs_map_from_entries(
+                vec!["foo".to_owned()],
+                vec![fs_number_from_integer(2)]
+            ))
+        );
+        assert_eq!(fs_get(fs_reference("/users/404"), false), None);
+    }
+
+    #[pg_test]
+    fn test_fs_deref() {
+        // /posts/1 links to /users/1/posts/1
+        let post = fs_get(fs_reference("/posts/1"), false).expect("/posts/1 must exist");
+        let target = fs_get(fs_reference("/users/1/posts/1"), false);
+        assert!(target.is_some());
+        assert_eq!(fs_deref(fs_reference("/users/1/posts/1")), target);
+        assert_eq!(fs_deref_field(post.to_owned(), "link"), target);
+        assert_eq!(fs_deref_field(post, "missing"), None);
+        assert_eq!(fs_deref(fs_reference("/users/1/posts/404")), None);
+        assert_eq!(fs_deref(fs_reference("/users")), None);
+        assert_eq!(fs_deref(fs_number_from_integer(1)), None);
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "SELECT fs_deref_field(properties, 'link') FROM fs_documents \
+                 WHERE reference = fs_reference('/posts/2')"
+            )
+            .expect("SPI failed"),
+            fs_get(fs_reference("/users/1/posts/2"), false)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_resolve_references() {
+        let map = |entries: Vec<(&str, FsValue)>| {
+            FsValue::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value))
+                    .collect(),
+            )
+        };
+        let doc = map(vec![
+            ("title", FsValue::String("hi".to_owned())),
+            ("link", fs_reference("/users/1/posts/1")),
+            (
+                "meta",
+                map(vec![("dangling", fs_reference("/users/1/posts/404"))]),
+            ),
+        ]);
+        let paths = ["link", "meta.dangling", "title", "missing"];
+        assert_eq!(
+            fs_resolve_references(doc, paths.iter().map(|path| path.to_string()).collect()),
+            map(vec![
+                ("title", FsValue::String("hi".to_owned())),
+                (
+                    "link",
+                    map(vec![
+                        ("reference", fs_reference("/users/1/posts/1")),
+                        (
+                            "properties",
+                            fs_get(fs_reference("/users/1/posts/1"), false).unwrap()
+                        ),
+                    ])
+                ),
+                (
+                    "meta",
+                    map(vec![(
+                        "dangling",
+                        map(vec![
+                            ("reference", fs_reference("/users/1/posts/404")),
+                            ("properties", FsValue::NULL),
+                        ])
+                    )])
+                ),
+            ])
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_set_and_delete() {
+        let properties = fs_map_from_entries(vec!["ok".to_owned()], vec![fs_boolean(true)]);
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            properties.to_owned(),
+            true,
+            false
+        ));
+        assert_eq!(fs_get(fs_reference("/users/9"), false), Some(properties));
+
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            fs_map_from_entries(vec![], vec![]),
+            true,
+            false,
+        ));
+        assert_eq!(
+            fs_get(fs_reference("/users/9"), false),
+            Some(fs_map_from_entries(vec![], vec![]))
+        );
+
+        assert!(fs_delete(fs_reference("/users/9")));
+        assert!(!fs_delete(fs_reference("/users/9")));
+        assert_eq!(fs_get(fs_reference("/users/9"), false), None);
+    }
+
+    // Moves the update_time of a document to the past so that bumping it can
+    // be observed within a single transaction.
+    fn backdate(path: &str) {
+        Spi::run_with_args(
+            "UPDATE fs_documents SET update_time = '2000-01-01' WHERE reference = $1",
+            Some(vec![fsvalue_arg(fs_reference(path))]),
+        )
+        .expect("SPI failed");
+    }
+
+    fn is_backdated(path: &str) -> bool {
+        Spi::get_one_with_args::<bool>(
+            "SELECT update_time = '2000-01-01' FROM fs_documents WHERE reference = $1",
+            vec![fsvalue_arg(fs_reference(path))],
+        )
+        .expect("SPI failed")
+        .expect("expecting an existing document")
+    }
+
+    #[pg_test]
+    fn test_fs_set_skip_unchanged() {
+        // /users/2 is seeded with {"foo": 2}
+        let properties =
+            fs_map_from_entries(vec!["foo".to_owned()], vec![fs_number_from_integer(2)]);
+        backdate("/users/2");
+        assert!(!fs_set(
+            fs_reference("/users/2"),
+            properties.to_owned(),
+            true,
+            false
+        ));
+        assert!(is_backdated("/users/2"));
+
+        assert!(fs_set(
+            fs_reference("/users/2"),
+            properties.to_owned(),
+            false,
+            false
+        ));
+        assert!(!is_backdated("/users/2"));
+
+        backdate("/users/2");
+        let changed = fs_map_from_entries(vec!["foo".to_owned()], vec![fs_number_from_integer(3)]);
+        assert!(fs_set(
+            fs_reference("/users/2"),
+            changed.to_owned(),
+            true,
+            false
+        ));
+        assert!(!is_backdated("/users/2"));
+        assert_eq!(fs_get(fs_reference("/users/2"), false), Some(changed));
+
+        // 3.0 equals 3 but is stored apart, so it is still written
+        backdate("/users/2");
+        let double = fs_map_from_entries(vec!["foo".to_owned()], vec![fs_number_from_double(3.0)]);
+        assert!(fs_set(
+            fs_reference("/users/2"),
+            double.to_owned(),
+            true,
+            false
+        ));
+        assert!(!is_backdated("/users/2"));
+        assert_eq!(fs_get(fs_reference("/users/2"), false), Some(double));
+    }
+
+    #[test]
+    fn test_upsert_statement() {
+        assert_eq!(
+            upsert_statement("VALUES ($1, $2)", true, true),
+            "INSERT INTO fs_documents (reference, properties) VALUES ($1, $2) \
+             ON CONFLICT (reference) DO UPDATE \
+             SET properties = CASE WHEN fs_documents.deleted_at IS NULL \
+             THEN fs_map_merge(fs_documents.properties, EXCLUDED.properties, true) \
+             ELSE EXCLUDED.properties END, \
+             update_time = fs_request_time(), \
+             create_time = CASE WHEN fs_documents.deleted_at IS NULL \
+             THEN fs_documents.create_time ELSE fs_request_time() END, \
+             deleted_at = NULL \
+             WHERE fs_documents.deleted_at IS NOT NULL \
+             OR NOT fs_identical(fs_documents.properties, \
+             CASE WHEN fs_documents.deleted_at IS NULL \
+             THEN fs_map_merge(fs_documents.properties, EXCLUDED.properties, true) \
+             ELSE EXCLUDED.properties END) \
+             RETURNING reference"
+        );
+        assert_eq!(
+            upsert_statement("VALUES ($1, $2)", false, false),
+            "INSERT INTO fs_documents (reference, properties) VALUES ($1, $2) \
+             ON CONFLICT (reference) DO UPDATE \
+             SET properties = EXCLUDED.properties, update_time = fs_request_time(), \
+             create_time = CASE WHEN fs_documents.deleted_at IS NULL \
+             THEN fs_documents.create_time ELSE fs_request_time() END, \
+             deleted_at = NULL \
+             RETURNING reference"
+        );
+    }
+
+    #[test]
+    fn test_fs_map_merge() {
+        let base = FsValue::from_plain_json(&json!({"a": 1, "nested": {"x": 1, "y": 2}}));
+        let patch = FsValue::from_plain_json(&json!({"b": 2, "nested": {"y": 3}}));
+        assert_eq!(
+            fs_map_merge(base.to_owned(), patch.to_owned(), true),
+            FsValue::from_plain_json(&json!({"a": 1, "b": 2, "nested": {"x": 1, "y": 3}}))
+        );
+        assert_eq!(
+            fs_map_merge(base, patch.to_owned(), false),
+            FsValue::from_plain_json(&json!({"a": 1, "b": 2, "nested": {"y": 3}}))
+        );
+        assert_eq!(fs_map_merge(FsValue::NULL, patch.to_owned(), true), patch);
+    }
+
+    #[pg_test]
+    fn test_fs_map_merge_nested() {
+        let plain = |value: Value| FsValue::from_plain_json(&value);
+        let delete = fs_map_delete_sentinel;
+        let base = plain(json!({
+            "a": {"b": {"c": 1, "d": 2, "gone": 3}, "list": [1, 2]},
+            "kept": true,
+            "scalar": "s"
+        }));
+        let patch = FsValue::Map(BTreeMap::from([
+            (
+                "a".to_owned(),
+                FsValue::Map(BTreeMap::from([
+                    (
+                        "b".to_owned(),
+                        FsValue::Map(BTreeMap::from([
+                            ("c".to_owned(), plain(json!(10))),
+                            ("gone".to_owned(), delete()),
+                            ("missing".to_owned(), delete()),
+                        ])),
+                    ),
+                    ("list".to_owned(), plain(json!([3]))),
+                ])),
+            ),
+            (
+                "scalar".to_owned(),
+                FsValue::Map(BTreeMap::from([
+                    ("x".to_owned(), plain(json!(1))),
+                    (
+                        "y".to_owned(),
+                        FsValue::Map(BTreeMap::from([("z".to_owned(), delete())])),
+                    ),
+                ])),
+            ),
+            ("kept".to_owned(), delete()),
+            ("new".to_owned(), plain(json!({"n": {"m": 1}}))),
+        ]));
+        // Arrays are replaced, and maps over a scalar lose their sentinels
+        assert_eq!(
+            fs_map_merge(base.to_owned(), patch.to_owned(), true),
+            plain(json!({
+                "a": {"b": {"c": 10, "d": 2}, "list": [3]},
+                "new": {"n": {"m": 1}},
+                "scalar": {"x": 1, "y": {}}
+            }))
+        );
+        assert_eq!(
+            fs_map_merge(base.to_owned(), patch.to_owned(), false)
+                .as_map()
+                .map(|map| map.keys().cloned().collect::<Vec<_>>()),
+            Some(vec!["a".to_owned(), "new".to_owned(), "scalar".to_owned()])
+        );
+        assert_eq!(
+            fs_map_merge(plain(json!([1])), patch.to_owned(), true),
+            fs_map_merge(plain(json!({})), patch.to_owned(), true)
+        );
+        assert_eq!(
+            fs_map_merge(base.to_owned(), plain(json!([1])), true),
+            plain(json!([1]))
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "SELECT fs_map_merge(fs_doc('a', fs_doc('b', fs_doc('c', 1, 'd', 2))), \
+                     fs_doc('a', fs_doc('b', fs_doc('c', fs_map_delete_sentinel()))))"
+            ),
+            Ok(Some(plain(json!({"a": {"b": {"d": 2}}}))))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_set_merge() {
+        let document = |value: Value| FsValue::from_plain_json(&value);
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            document(json!({"a": 1, "nested": {"x": 1}})),
+            true,
+            true
+        ));
+        // Merging the same fields again writes nothing
+        assert!(!fs_set(
+            fs_reference("/users/9"),
+            document(json!({"nested": {"x": 1}})),
+            true,
+            true
+        ));
+
+        // A writer reads the document, then another writer adds a field
+        // before the first one merges its own changes in
+        let stale = fs_get(fs_reference("/users/9"), false);
+        Spi::run(
+            "UPDATE fs_documents \
+             SET properties = fs_map_merge(properties, fs_map_from_entries(ARRAY['b'], ARRAY[fs_number_from_integer(2)])) \
+             WHERE reference = fs_reference('/users/9')",
+        )
+        .expect("SPI failed");
+        assert_eq!(stale, Some(document(json!({"a": 1, "nested": {"x": 1}}))));
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            document(json!({"nested": {"y": 2}})),
+            true,
+            true
+        ));
+        assert_eq!(
+            fs_get(fs_reference("/users/9"), false),
+            Some(document(
+                json!({"a": 1, "b": 2, "nested": {"x": 1, "y": 2}})
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_bulk_set() {
+        let references: Vec<Option<FsValue>> = (1..=1000)
+            .map(|id| Some(fs_reference(&format!("/bulk/{}", id))))
+            .collect();
+        let properties: Vec<Option<FsValue>> = (1..=1000)
+            .map(|id| {
+                Some(fs_map_from_entries(
+                    vec!["id".to_owned()],
+                    vec![fs_number_from_integer(id)],
+                ))
+            })
+            .collect();
+        assert_eq!(
+            fs_bulk_set(references.to_owned(), properties.to_owned(), false),
+            1000
+        );
+        Spi::run(
+            "SELECT fs_assert_count(fs_database_root(), 'bulk', 1000); \
+             SELECT fs_assert_document(fs_reference('/bulk/500'), \
+                 fs_map_from_entries(ARRAY['id'], ARRAY[fs_number_from_integer(500)]))",
+        )
+        .expect("SPI failed");
+        // Rewriting the same documents is a no-op
+        assert_eq!(fs_bulk_set(references, properties, false), 0);
+    }
+
+    #[pg_test]
+    fn test_fs_bulk_set_merge() {
+        fs_set(
+            fs_reference("/bulk/1"),
+            fs_map_from_entries(
+                vec!["a".to_owned(), "nested".to_owned()],
+                vec![
+                    fs_number_from_integer(1),
+                    fs_map_from_entries(
+                        vec!["x".to_owned(), "y".to_owned()],
+                        vec![fs_number_from_integer(1), fs_number_from_integer(2)],
+                    ),
+                ],
+            ),
+            true,
+            false,
+        );
+        let patch = fs_map_from_entries(
+            vec!["nested".to_owned()],
+            vec![fs_map_from_entries(
+                vec!["y".to_owned()],
+                vec![fs_boolean(true)],
+            )],
+        );
+        assert_eq!(
+            fs_bulk_set(
+                vec![Some(fs_reference("/bulk/1")), Some(fs_reference("/bulk/2"))],
+                vec![Some(patch.to_owned()), Some(patch.to_owned())],
+                true,
+            ),
+            2
+        );
+        assert_eq!(
+            fs_get(fs_reference("/bulk/1"), false),
+            Some(fs_map_from_entries(
+                vec!["a".to_owned(), "nested".to_owned()],
+                vec![
+                    fs_number_from_integer(1),
+                    fs_map_from_entries(
+                        vec!["x".to_owned(), "y".to_owned()],
+                        vec![fs_number_from_integer(1), fs_boolean(true)],
+                    ),
+                ],
+            ))
+        );
+        assert_eq!(fs_get(fs_reference("/bulk/2"), false), Some(patch));
+    }
+
+    #[pg_test(error = "InvalidValue: References size (2) does not match properties size (1)")]
+    fn test_fs_bulk_set_mismatched_lengths() {
+        fs_bulk_set(
+            vec![Some(fs_reference("/bulk/1")), Some(fs_reference("/bulk/2"))],
+            vec![Some(fs_map_from_entries(vec![], vec![]))],
+            false,
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Expecting a document reference at position 2 but found /bulk")]
+    fn test_fs_bulk_set_invalid_reference() {
+        fs_bulk_set(
+            vec![Some(fs_reference("/bulk/1")), Some(fs_reference("/bulk"))],
+            vec![
+                Some(fs_map_from_entries(vec![], vec![])),
+                Some(fs_map_from_entries(vec![], vec![])),
+            ],
+            false,
+        );
+    }
+
+    fn is_tombstone(path: &str) -> bool {
+        Spi::get_one_with_args::<bool>(
+            "SELECT deleted_at IS NOT NULL FROM fs_documents WHERE reference = $1",
+            vec![fsvalue_arg(fs_reference(path))],
+        )
+        .expect("SPI failed")
+        .unwrap_or(false)
+    }
+
+    fn collection_size(parent: &str, collection_id: &str, include_deleted: bool) -> i64 {
+        Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fs_collection($1, $2, $3)",
+            vec![
+                fsvalue_arg(fs_reference(parent)),
+                text_arg(collection_id),
+                (PgBuiltInOids::BOOLOID.oid(), include_deleted.into_datum()),
+            ],
+        )
+        .expect("SPI failed")
+        .unwrap_or(0)
+    }
+
+    #[pg_test]
+    fn test_soft_delete() {
+        Spi::run("SET LOCAL pgfirestore.soft_delete = on").expect("SPI failed");
+        let properties = fs_map_from_entries(vec!["foo".to_owned()], vec![fs_boolean(true)]);
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            properties.to_owned(),
+            true,
+            false
+        ));
+        assert!(fs_delete(fs_reference("/users/9")));
+        assert!(is_tombstone("/users/9"));
+        assert!(!fs_delete(fs_reference("/users/9")));
+
+        // Invisible to reads unless asked for
+        assert_eq!(fs_get(fs_reference("/users/9"), false), None);
+        assert_eq!(
+            fs_get(fs_reference("/users/9"), true),
+            Some(properties.to_owned())
+        );
+        assert_eq!(collection_size("/", "users", false), 5);
+        assert_eq!(collection_size("/", "users", true), 6);
+        assert!(!fs_touch(fs_reference("/users/9")));
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_collection_group('users') WHERE reference = fs_reference('/users/9')"
+            ),
+            Ok(Some(0))
+        );
+
+        // Writing the same properties again re-creates the document
+        assert!(fs_set(
+            fs_reference("/users/9"),
+            properties.to_owned(),
+            true,
+            false
+        ));
+        assert!(!is_tombstone("/users/9"));
+        assert_eq!(fs_get(fs_reference("/users/9"), false), Some(properties));
+    }
+
+    #[pg_test]
+    fn test_soft_delete_recreate() {
+        assert!(fs_soft_delete(fs_reference("/users/1")));
+        // A merge does not pick up the fields of the deleted document
+        let patch = fs_map_from_entries(vec!["baz".to_owned()], vec![fs_boolean(true)]);
+        assert!(fs_set(
+            fs_reference("/users/1"),
+            patch.to_owned(),
+            true,
+            true
+        ));
+        assert_eq!(fs_get(fs_reference("/users/1"), false), Some(patch));
+
+        assert!(fs_soft_delete(fs_reference("/users/2")));
+        assert!(create_document(
+            fs_reference("/users/2").as_reference().unwrap(),
+            fs_map_from_entries(vec![], vec![])
+        ));
+        assert!(!is_tombstone("/users/2"));
+        assert!(!create_document(
+            fs_reference("/users/2").as_reference().unwrap(),
+            fs_map_from_entries(vec![], vec![])
+        ));
+    }
+
+    #[pg_test]
+    fn test_soft_delete_recursive() {
+        Spi::run("SET LOCAL pgfirestore.soft_delete = on").expect("SPI failed");
+        assert_eq!(fs_delete_recursive(fs_reference("/users/1")), 3);
+        assert!(is_tombstone("/users/1/posts/2"));
+        assert_eq!(fs_delete_recursive(fs_reference("/users/1")), 0);
+        // fs_delete and fs_soft_delete agree while the setting is on
+        assert!(!fs_soft_delete(fs_reference("/users/1")));
+    }
+
+    #[pg_test]
+    fn test_fs_purge_tombstones() {
+        assert!(fs_soft_delete(fs_reference("/users/2")));
+        assert!(fs_soft_delete(fs_reference("/users/3")));
+        Spi::run(
+            "UPDATE fs_documents SET deleted_at = fs_request_time() - interval '2 days' \
+             WHERE reference = fs_reference('/users/2')",
+        )
+        .expect("SPI failed");
+        let purge = |older_than: &str| {
+            Spi::get_one_with_args::<i64>(
+                "SELECT fs_purge_tombstones($1::interval)",
+                vec![text_arg(older_than)],
+            )
+            .expect("SPI failed")
+        };
+        assert_eq!(purge("3 days"), Some(0));
+        assert_eq!(purge("1 day"), Some(1));
+        assert_eq!(fs_get(fs_reference("/users/2"), true), None);
+        assert!(is_tombstone("/users/3"));
+        // Live documents are never purged
+        assert_eq!(purge("-1 day"), Some(1));
+        assert_eq!(collection_size("/", "users", true), 3);
+    }
+
+    #[pg_test]
+    fn test_fs_touch() {
+        let properties = fs_get(fs_reference("/users/3"), false);
+        backdate("/users/3");
+        assert!(fs_touch(fs_reference("/users/3")));
+        assert!(!is_backdated("/users/3"));
+        assert_eq!(fs_get(fs_reference("/users/3"), false), properties);
+        assert!(!fs_touch(fs_reference("/users/404")));
+    }
+
+    #[pg_test]
+    fn test_fs_update() {
+        // /users/1 is seeded with {"foo": 0, "bar": 0}
+        let updated = fs_update(
+            fs_reference("/users/1"),
+            fs_map_from_entries(vec!["foo".to_owned()], vec![fs_number_from_integer(7)]),
+            None,
+        );
+        let expected = fs_map_from_entries(
+            vec!["foo".to_owned(), "bar".to_owned()],
+            vec![fs_number_from_integer(7), fs_number_from_integer(0)],
+        );
+        assert_eq!(updated, expected);
+        assert_eq!(fs_get(fs_reference("/users/1"), false), Some(expected));
+
+        let updated = fs_update(
+            fs_reference("/users/1"),
+            fs_map_from_entries(
+                vec!["nested".to_owned()],
+                vec![fs_map_from_entries(
+                    vec!["a".to_owned()],
+                    vec![fs_boolean(true)],
+                )],
+            ),
+            Some(vec!["nested.a".to_owned(), "bar".to_owned()]),
+        );
+        assert_eq!(
+            updated,
+            fs_map_from_entries(
+                vec!["foo".to_owned(), "nested".to_owned()],
+                vec![
+                    fs_number_from_integer(7),
+                    fs_map_from_entries(vec!["a".to_owned()], vec![fs_boolean(true)]),
+                ],
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_delete_recursive() {
+        assert_eq!(fs_delete_recursive(fs_reference("/users/1")), 3);
+        Spi::run(
+            "SELECT fs_assert_count(fs_reference('/users/1'), 'posts', 0); \
+             SELECT fs_assert_count(fs_database_root(), 'users', 4)",
+        )
+        .expect("SPI failed");
+        assert_eq!(fs_delete_recursive(fs_reference("/users/1")), 0);
+    }
+
+    #[pg_test]
+    fn test_fs_delete_recursive_keeps_siblings() {
+        for path in ["/users/10", "/users/1a/posts/1", "/users/%31/posts/1"] {
+            fs_set(
+                fs_reference(path),
+                fs_map_from_entries(vec![], vec![]),
+                true,
+                false,
+            );
+        }
+        fs_delete_recursive(fs_reference("/users/1"));
+        for path in ["/users/10", "/users/1a/posts/1", "/users/%31/posts/1"] {
+            assert!(fs_get(fs_reference(path), false).is_some(), "{}", path);
+        }
+    }
+
+    #[pg_test(
+        error = "Cannot delete /users/1 recursively because /users/1/posts/2/comments/1 is frozen"
+    )]
+    fn test_fs_delete_recursive_frozen_descendant() {
+        fs_set(
+            fs_reference("/users/1/posts/2/comments/1"),
+            fs_map_from_entries(vec![], vec![]),
+            true,
+            false,
+        );
+        Spi::run("SELECT fs_freeze(fs_reference('/users/1/posts/2/comments/1'))")
+            .expect("SPI failed");
+        fs_delete_recursive(fs_reference("/users/1"));
+    }
+
+    #[test]
+    fn test_promote_array() {
+        let parent = FsReference::from_str("/orders/1").unwrap();
+        let document = plain(json!({
+            "note": "x",
+            "nested": {"items": [{"id": "a"}, {"id": 7, "qty": 2}]}
+        }));
+        let (children, trimmed) = promote_array(
+            &parent,
+            document.to_owned(),
+            &["nested".to_owned(), "items".to_owned()],
+            "items",
+            Some("id"),
+        )
+        .unwrap();
+        assert_eq!(
+            children
+                .iter()
+                .map(|(child, properties)| (child.to_string(), properties.to_owned()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/orders/1/items/a".to_owned(), plain(json!({"id": "a"}))),
+                (
+                    "/orders/1/items/7".to_owned(),
+                    plain(json!({"id": 7, "qty": 2}))
+                ),
+            ]
+        );
+        assert_eq!(trimmed, plain(json!({"note": "x", "nested": {}})));
+
+        let error = |document: Value, id_field: Option<&str>| {
+            promote_array(
+                &parent,
+                plain(document),
+                &["items".to_owned()],
+                "items",
+                id_field,
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(
+            error(json!({"items": [{"id": "a"}, 1]}), Some("id")),
+            "InvalidValue: Array element at index 1 is not a map but 1"
+        );
+        assert_eq!(
+            error(json!({"items": [{"id": true}]}), Some("id")),
+            "InvalidValue: Array element at index 0 has an ID of true rather than a string or integer"
+        );
+        assert_eq!(
+            error(json!({"items": [{"id": "a/b"}]}), Some("id")),
+            "InvalidValue: Array element at index 0 has an invalid ID 'a/b'"
+        );
+        assert_eq!(
+            error(json!({"items": "a"}), None),
+            "InvalidValue: Expecting an array at 'items' of document /orders/1 but found \"a\""
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_promote_array_to_collection() {
+        fs_set(
+            fs_reference("/orders/1"),
+            plain(json!({
+                "customer": "ada",
+                "items": [{"id": "a", "qty": 1}, {"id": "b", "qty": 2}, {"id": 3, "qty": 3}]
+            })),
+            true,
+            false,
+        );
+        assert_eq!(
+            fs_promote_array_to_collection(fs_reference("/orders/1"), "items", "items", Some("id")),
+            3
+        );
+        assert_eq!(
+            fs_get(fs_reference("/orders/1"), false),
+            Some(plain(json!({"customer": "ada"})))
+        );
+        assert_eq!(
+            fs_get(fs_reference("/orders/1/items/b"), false),
+            Some(plain(json!({"id": "b", "qty": 2})))
+        );
+        assert_eq!(
+            fs_get(fs_reference("/orders/1/items/3"), false),
+            Some(plain(json!({"id": 3, "qty": 3})))
+        );
+
+        // Without the ID field every child gets an auto ID
+        fs_set(
+            fs_reference("/orders/2"),
+            plain(json!({"items": [{"qty": 1}, {"qty": 2}]})),
+            true,
+            false,
+        );
+        assert_eq!(
+            fs_promote_array_to_collection(
+                fs_reference("/orders/2"),
+                "items",
+                "items",
+                Some("sku")
+            ),
+            2
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_collection(fs_reference('/orders/2'), 'items') \
+                 WHERE fs_is_auto_id(reference)"
+            ),
+            Ok(Some(2))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_promote_array_to_collection_non_map() {
+        let document = plain(json!({"items": [{"id": "a"}, "b"]}));
+        fs_set(fs_reference("/orders/1"), document.to_owned(), true, false);
+        Spi::run(
+            "DO $$ BEGIN \
+                PERFORM fs_promote_array_to_collection(fs_reference('/orders/1'), 'items', 'items', 'id'); \
+                RAISE EXCEPTION 'promotion succeeded'; \
+             EXCEPTION WHEN invalid_parameter_value THEN \
+                IF SQLERRM <> 'InvalidValue: Array element at index 1 is not a map but \"b\"' THEN \
+                    RAISE; \
+                END IF; \
+             END $$",
+        )
+        .expect("SPI failed");
+        assert_eq!(fs_get(fs_reference("/orders/1"), false), Some(document));
+        assert_eq!(fs_get(fs_reference("/orders/1/items/a"), false), None);
+    }
+
+    #[pg_test(error = "Cannot update document /users/404 which does not exist")]
+    fn test_fs_update_missing_document() {
+        fs_update(
+            fs_reference("/users/404"),
+            fs_map_from_entries(vec![], vec![]),
+            None,
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a map of properties but found \"x\"")]
+    fn test_fs_update_non_map() {
+        Spi::run("SELECT fs_update(fs_reference('/users/1'), fs_string('x'))").expect("SPI failed");
+    }
+
+    // Four documents, half of which already have a version
+    fn write_migration_collection() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/migrations/a'), fs_map_from_entries(ARRAY['version'], ARRAY[fs_number_from_integer(2)])); \
+             SELECT fs_set(fs_reference('/migrations/b'), fs_map_from_entries(ARRAY['name'], ARRAY[fs_string('b')])); \
+             SELECT fs_set(fs_reference('/migrations/c'), fs_map_from_entries(ARRAY['version'], ARRAY[fs_number_from_integer(1)])); \
+             SELECT fs_set(fs_reference('/migrations/d'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+    }
+
+    fn migrated(path: &str) -> FsValue {
+        fs_get(fs_reference(path), false).expect("document must exist")
+    }
+
+    #[pg_test]
+    fn test_fs_set_field_all() {
+        write_migration_collection();
+        // A batch size of 1 pages through the collection one document at a time
+        assert_eq!(
+            fs_set_field_all(
+                crate::fs_database_root(),
+                "migrations",
+                "version",
+                fs_number_from_integer(1),
+                true,
+                1
+            ),
+            2
+        );
+        assert_eq!(migrated("/migrations/a"), plain(json!({"version": 2})));
+        assert_eq!(
+            migrated("/migrations/b"),
+            plain(json!({"name": "b", "version": 1}))
+        );
+        assert_eq!(migrated("/migrations/c"), plain(json!({"version": 1})));
+        assert_eq!(migrated("/migrations/d"), plain(json!({"version": 1})));
+
+        // Overwriting skips the documents that already hold the value
+        assert_eq!(
+            fs_set_field_all(
+                crate::fs_database_root(),
+                "migrations",
+                "version",
+                fs_number_from_integer(1),
+                false,
+                1000
+            ),
+            1
+        );
+        assert_eq!(migrated("/migrations/a"), plain(json!({"version": 1})));
+    }
+
+    #[pg_test]
+    fn test_fs_set_field_all_nested_path() {
+        write_migration_collection();
+        assert_eq!(
+            fs_set_field_all(
+                crate::fs_database_root(),
+                "migrations",
+                "meta.migrated",
+                fs_boolean(true),
+                true,
+                3
+            ),
+            4
+        );
+        assert_eq!(
+            migrated("/migrations/d"),
+            plain(json!({"meta": {"migrated": true}}))
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Batch size must be positive but found 0")]
+    fn test_fs_set_field_all_batch_size() {
+        fs_set_field_all(
+            crate::fs_database_root(),
+            "migrations",
+            "version",
+            fs_number_from_integer(1),
+            true,
+            0,
+        );
+    }
+
+    #[test]
+    fn test_set_field_all_checks_for_interrupts() {
+        let source = include_str!("fs_documents.rs");
+        let function = &source[source
+            .find("fn fs_set_field_all")
+            .expect("fs_set_field_all must exist")..];
+        let interrupts = function
+            .find("check_for_interrupts!()")
+            .expect("fs_set_field_all must check for interrupts");
+        let batch = function
+            .find("collection_batch(")
+            .expect("fs_set_field_all must read in batches");
+        assert!(function[..interrupts].contains("loop {"));
+        assert!(interrupts < batch);
+    }
+
+    fn collections(parent: &str) -> Vec<String> {
+        fs_collections(crate::fs_reference(parent)).collect()
+    }
+
+    #[pg_test]
+    fn test_fs_collections() {
+        assert_eq!(collections("/"), vec!["posts", "users"]);
+        assert_eq!(collections("/users/1"), vec!["posts"]);
+        assert!(collections("/users/2").is_empty());
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/users/1/posts/1/likes/1'), fs_empty_map()), \
+                 (fs_reference('/users/1/albums/9/photos/1'), fs_empty_map()), \
+                 (fs_reference('/users/1/posts%00/1'), fs_empty_map()), \
+                 (fs_reference('/users/10/drafts/1'), fs_empty_map())",
+        )
+        .expect("SPI failed");
+        // Collections of missing documents count, as in Firestore
+        assert_eq!(collections("/users/1"), vec!["albums", "posts", "posts\0"]);
+        assert_eq!(collections("/users/1/posts/1"), vec!["likes"]);
+        assert_eq!(collections("/users/1/albums/9"), vec!["photos"]);
+        Spi::run(
+            "UPDATE fs_documents SET deleted_at = now() \
+             WHERE reference = fs_reference('/users/1/albums/9/photos/1')",
+        )
+        .expect("SPI failed");
+        assert_eq!(collections("/users/1"), vec!["posts", "posts\0"]);
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(id, ',') FROM fs_collections(fs_database_root()) AS id"
+            ),
+            Ok(Some("posts,users".to_owned()))
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Expecting the database root or a document reference but found /users"
+    )]
+    fn test_fs_collections_of_collection() {
+        fs_collections(crate::fs_reference("/users"));
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a reference but found \"users\"")]
+    fn test_fs_collections_of_string() {
+        fs_collections(crate::fs_string("users"));
+    }
+
+    fn is_auto_id(id: &str) -> bool {
+        id.len() == 20 && id.bytes().all(|byte| byte.is_ascii_alphanumeric())
+    }
+
+    #[pg_test]
+    fn test_fs_generate_document_id() {
+        let first = fs_generate_document_id();
+        assert!(is_auto_id(&first), "{}", first);
+        assert_ne!(fs_generate_document_id(), first);
+    }
+
+    #[pg_test]
+    fn test_fs_create_document() {
+        let properties =
+            fs_map_from_entries(vec!["title".to_owned()], vec![crate::fs_string("hello")]);
+        let reference = Spi::get_one_with_args::<FsValue>(
+            "SELECT fs_create_document(fs_reference('/users/1'), 'posts', $1)",
+            vec![fsvalue_arg(properties.to_owned())],
+        )
+        .expect("SPI failed")
+        .expect("reference must not be null");
+        let id = crate::fs_document_id(reference.to_owned());
+        assert!(is_auto_id(&id), "{}", id);
+        assert_eq!(
+            Spi::get_one_with_args::<FsValue>(
+                "SELECT properties FROM fs_collection(fs_reference('/users/1'), 'posts') \
+                 WHERE reference = $1",
+                vec![fsvalue_arg(reference)],
+            ),
+            Ok(Some(properties))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_collection(fs_reference('/users/1'), 'posts')"
+            ),
+            Ok(Some(3))
+        );
+        let top_level =
+            fs_create_document(crate::fs_database_root(), "logs", crate::fs_empty_map());
+        assert!(top_level.expect_reference().parent().is_root());
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Expecting the database root or a document reference but found /users"
+    )]
+    fn test_fs_create_document_in_collection() {
+        fs_create_document(
+            crate::fs_reference("/users"),
+            "posts",
+            crate::fs_empty_map(),
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a map of properties but found 1")]
+    fn test_fs_create_document_non_map() {
+        fs_create_document(
+            crate::fs_reference("/users/1"),
+            "posts",
+            fs_number_from_integer(1),
+        );
+    }
+}
@@ -1,10 +1,14 @@
+use pgrx::prelude::*;
+use std::any::Any;
 use std::fmt;
 use std::fmt::Display;
+use std::panic;
 
 #[derive(Debug)]
 pub enum FsError {
     InvalidValue(String),
     InvalidType(String),
+    LimitExceeded(String),
 }
 
 impl Display for FsError {
@@ -12,6 +16,307 @@ impl Display for FsError {
         match &self {
             FsError::InvalidValue(err_msg) => write!(f, "InvalidValue: {}", err_msg),
             FsError::InvalidType(err_msg) => write!(f, "InvalidType: {}", err_msg),
+            FsError::LimitExceeded(err_msg) => write!(f, "LimitExceeded: {}", err_msg),
         }
     }
 }
+
+impl FsError {
+    pub(crate) fn code(&self) -> FsCode {
+        match self {
+            FsError::InvalidValue(_) | FsError::InvalidType(_) => FsCode::InvalidArgument,
+            FsError::LimitExceeded(_) => FsCode::ResourceExhausted,
+        }
+    }
+
+    pub(crate) fn report(self) -> ! {
+        report(self.code(), self.to_string())
+    }
+
+    // The same error with `context` in front of its message
+    pub fn with_context(self, context: &str) -> FsError {
+        match self {
+            FsError::InvalidValue(err_msg) => {
+                FsError::InvalidValue(format!("{}: {}", context, err_msg))
+            }
+            FsError::InvalidType(err_msg) => {
+                FsError::InvalidType(format!("{}: {}", context, err_msg))
+            }
+            FsError::LimitExceeded(err_msg) => {
+                FsError::LimitExceeded(format!("{}: {}", context, err_msg))
+            }
+        }
+    }
+}
+
+// Kinds of failure applications can branch on, named after the gRPC status
+// codes of Firestore. Errors of each kind have their own SQLSTATE and a
+// `fs_code=<code>` token in their DETAIL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FsCode {
+    InvalidArgument,
+    NotFound,
+    AlreadyExists,
+    FailedPrecondition,
+    ResourceExhausted,
+}
+
+// (code, its name, SQLSTATE, SQLSTATE text, description)
+type FsCodeEntry = (
+    FsCode,
+    &'static str,
+    PgSqlErrorCode,
+    &'static str,
+    &'static str,
+);
+
+// The one place codes are mapped to SQLSTATEs, the standard ones closest to
+// them. Neither may change once released, since retry logic matches on them.
+const FS_CODES: [FsCodeEntry; 5] = [
+    (
+        FsCode::InvalidArgument,
+        "INVALID_ARGUMENT",
+        PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+        "22023",
+        "A value or its text is invalid, whatever the state of the database",
+    ),
+    (
+        FsCode::NotFound,
+        "NOT_FOUND",
+        PgSqlErrorCode::ERRCODE_NO_DATA_FOUND,
+        "P0002",
+        "A document the operation requires does not exist",
+    ),
+    (
+        FsCode::AlreadyExists,
+        "ALREADY_EXISTS",
+        PgSqlErrorCode::ERRCODE_UNIQUE_VIOLATION,
+        "23505",
+        "A document the operation creates already exists",
+    ),
+    (
+        FsCode::FailedPrecondition,
+        "FAILED_PRECONDITION",
+        PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+        "55000",
+        "A document is not in the state the operation requires, like being frozen",
+    ),
+    (
+        FsCode::ResourceExhausted,
+        "RESOURCE_EXHAUSTED",
+        PgSqlErrorCode::ERRCODE_PROGRAM_LIMIT_EXCEEDED,
+        "54000",
+        "A value exceeds one of Firestore's limits",
+    ),
+];
+
+fn fs_code_entry(code: FsCode) -> &'static FsCodeEntry {
+    FS_CODES
+        .iter()
+        .find(|entry| entry.0 == code)
+        .expect("every code is mapped")
+}
+
+// Raises an ERROR of kind `code`
+pub(crate) fn report(code: FsCode, message: String) -> ! {
+    let (_, name, sql_error_code, _, _) = fs_code_entry(code);
+    ereport!(
+        PgLogLevel::ERROR,
+        *sql_error_code,
+        message,
+        format!("fs_code={}", name)
+    );
+    unreachable!("an ERROR report does not return")
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_error_codes() -> TableIterator<
+    'static,
+    (
+        name!(code, String),
+        name!(sqlstate, String),
+        name!(description, String),
+    ),
+> {
+    TableIterator::new(FS_CODES.iter().map(|(_, name, _, sqlstate, description)| {
+        (
+            name.to_string(),
+            sqlstate.to_string(),
+            description.to_string(),
+        )
+    }))
+}
+
+// The message of a Rust panic caught with catch_unwind. Anything else, like
+// an error raised by Postgres, keeps unwinding.
+pub(crate) fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_error::*;
+
+    #[test]
+    fn test_fs_codes() {
+        for code in [
+            FsCode::InvalidArgument,
+            FsCode::NotFound,
+            FsCode::AlreadyExists,
+            FsCode::FailedPrecondition,
+            FsCode::ResourceExhausted,
+        ] {
+            assert_eq!(fs_code_entry(code).0, code);
+        }
+        for (index, (_, name, sql_error_code, sqlstate, _)) in FS_CODES.iter().enumerate() {
+            for (_, other_name, other_sql_error_code, other_sqlstate, _) in &FS_CODES[index + 1..] {
+                assert_ne!(name, other_name);
+                assert_ne!(sql_error_code, other_sql_error_code);
+                assert_ne!(sqlstate, other_sqlstate);
+            }
+        }
+        assert_eq!(
+            FsError::InvalidType("x".to_owned()).code(),
+            FsCode::InvalidArgument
+        );
+        assert_eq!(
+            FsError::LimitExceeded("x".to_owned()).code(),
+            FsCode::ResourceExhausted
+        );
+    }
+
+    // The SQLSTATE and DETAIL `statement` fails with. OTHERS does not catch
+    // ASSERT_FAILURE, which has to be named.
+    fn error_of(statement: &str) -> (String, String) {
+        Spi::run(
+            "CREATE OR REPLACE FUNCTION pg_temp.error_of( \
+                 statement text, OUT error_code text, OUT error_detail text) AS $$ \
+             BEGIN \
+                 EXECUTE statement; \
+                 RAISE EXCEPTION 'statement succeeded'; \
+             EXCEPTION WHEN OTHERS OR ASSERT_FAILURE THEN \
+                 GET STACKED DIAGNOSTICS \
+                     error_code = RETURNED_SQLSTATE, error_detail = PG_EXCEPTION_DETAIL; \
+             END $$ LANGUAGE plpgsql",
+        )
+        .expect("SPI failed");
+        match Spi::get_two_with_args::<String, String>(
+            "SELECT * FROM pg_temp.error_of($1)",
+            vec![crate::fs_documents::text_arg(statement)],
+        ) {
+            Ok((Some(error_code), Some(error_detail))) => (error_code, error_detail),
+            other => panic!("{} failed with {:?}", statement, other),
+        }
+    }
+
+    fn expected(sqlstate: &str, name: &str) -> (String, String) {
+        (sqlstate.to_owned(), format!("fs_code={}", name))
+    }
+
+    #[pg_test]
+    fn test_error_codes() {
+        assert_eq!(
+            error_of("SELECT '{\"type\": \"NUMBER\", \"value\": \"x\"}'::fsvalue"),
+            expected("22023", "INVALID_ARGUMENT")
+        );
+        assert_eq!(
+            error_of("SELECT fs_cast(fs_string('x'), 'NUMBER')"),
+            expected("22023", "INVALID_ARGUMENT")
+        );
+        assert_eq!(
+            error_of(
+                "SELECT fs_update(fs_reference('/users/404'), \
+                     fs_map_from_entries(ARRAY['foo'], ARRAY[fs_number_from_integer(1)]))"
+            ),
+            expected("P0002", "NOT_FOUND")
+        );
+        Spi::run(
+            "SELECT fs_set(fs_reference('/orders/1'), fs_map_from_entries(ARRAY['items'], \
+                 ARRAY[fs_array(ARRAY[fs_map_from_entries(ARRAY['id'], ARRAY[fs_string('a')])])])); \
+             SELECT fs_set(fs_reference('/orders/1/items/a'), \
+                 fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            error_of(
+                "SELECT fs_promote_array_to_collection(fs_reference('/orders/1'), 'items', 'items', 'id')"
+            ),
+            expected("23505", "ALREADY_EXISTS")
+        );
+        Spi::run(
+            "SELECT fs_set(fs_reference('/drafts/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])); \
+             SELECT fs_set(fs_reference('/notes/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            error_of("SELECT fs_rename_collection('/drafts', 'notes', dry_run => false)"),
+            expected("23505", "ALREADY_EXISTS")
+        );
+        assert_eq!(
+            error_of("SELECT fs_refresh_view('missing_view')"),
+            expected("P0002", "NOT_FOUND")
+        );
+        Spi::run("SELECT fs_freeze(fs_reference('/users/2'))").expect("SPI failed");
+        assert_eq!(
+            error_of("DELETE FROM fs_documents WHERE reference = fs_reference('/users/2')"),
+            expected("55000", "FAILED_PRECONDITION")
+        );
+        // Failed assertions are no Firestore error, but fail like ASSERT
+        assert_eq!(
+            error_of("SELECT fs_assert_eq(fs_null(), fs_string('x'))").0,
+            "P0004"
+        );
+        assert_eq!(
+            error_of("SELECT fs_assert_count(fs_reference('/'), 'users', -1)").0,
+            "P0004"
+        );
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert_eq!(
+            error_of("SELECT fs_bytes(convert_to(repeat('x', 1048488), 'UTF8'))"),
+            expected("54000", "RESOURCE_EXHAUSTED")
+        );
+    }
+
+    #[pg_test]
+    fn test_invalid_arguments_are_not_internal_errors() {
+        for statement in [
+            "SELECT fs_parent(fs_number_from_integer(1))",
+            "SELECT fs_parent(fs_reference('/'))",
+            "SELECT fs_collection_id(fs_reference('/'))",
+            "SELECT fs_map_from_entries(ARRAY['a', 'b'], ARRAY[fs_null()])",
+            "SELECT fs_number_from_integer(1) + fs_string('a')",
+            "SELECT '{\"type\": 1, \"value\": 1}'::fsvalue",
+            "SELECT fs_array_chunk(fs_array(ARRAY[fs_number_from_integer(1)]), 0)",
+            "SELECT fs_geo_distance(fs_string('a'), fs_string('b'))",
+            "SELECT fs_time_bucket(fs_timestamp(now()), interval '1 month')",
+        ] {
+            assert_eq!(
+                error_of(statement),
+                expected("22023", "INVALID_ARGUMENT"),
+                "{}",
+                statement
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_error_codes() {
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(code || '=' || sqlstate, ', ') FROM fs_error_codes()"
+            ),
+            Ok(Some(
+                "INVALID_ARGUMENT=22023, NOT_FOUND=P0002, ALREADY_EXISTS=23505, \
+                 FAILED_PRECONDITION=55000, RESOURCE_EXHAUSTED=54000"
+                    .to_owned()
+            ))
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use crate::fs_documents::{expect_document_reference, fsvalue_arg, set_document};
+use crate::fs_error::{report, FsCode};
+use crate::fs_guc;
+use crate::FsError;
+use crate::{encode_hex, FsReference, FsValue};
+use pgrx::heap_tuple::PgHeapTupleError;
+use pgrx::prelude::*;
+use pgrx::WhoAllocated;
+use sha2::{Digest, Sha256};
+
+// An If-Match ETag matching any existing document, as in HTTP
+const ANY_ETAG: &str = "*";
+
+// The sha256 of the canonical text of `properties`, which fs_documents keeps
+// in content_hash so that ETags do not need the whole document
+pub(crate) fn content_hash(properties: &FsValue) -> Vec<u8> {
+    Sha256::digest(properties.canonical_text().as_bytes()).to_vec()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_content_hash(properties: FsValue) -> Vec<u8> {
+    content_hash(&properties)
+}
+
+// Sets content_hash of every written row, or clears it while
+// pgfirestore.content_hashes is off so that no stale hash is left behind.
+// Only fires when properties is written, so fs_touch leaves it alone.
+#[pg_trigger]
+fn fs_documents_content_hash<'a>(
+    trigger: &'a pgrx::PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PgHeapTupleError> {
+    let mut new = trigger
+        .new()
+        .expect("expecting a NEW row in an INSERT or UPDATE trigger")
+        .into_owned();
+    let hash = match fs_guc::CONTENT_HASHES.get() {
+        true => new
+            .get_by_name::<FsValue>("properties")
+            .expect("Failed to read properties from the NEW row")
+            .map(|properties| content_hash(&properties)),
+        false => None,
+    };
+    new.set_by_name("content_hash", hash)
+        .expect("Failed to set content_hash of the NEW row");
+    Ok(Some(new))
+}
+
+// A strong ETag of the content and update_time, quoted as in HTTP headers
+fn etag(content_hash: &[u8], update_time: TimestampWithTimeZone) -> String {
+    let micros: i64 = update_time.into();
+    let digest = Sha256::new()
+        .chain_update(content_hash)
+        .chain_update(micros.to_be_bytes())
+        .finalize();
+    format!("\"{}\"", encode_hex(&digest[..16]))
+}
+
+// The ETag of a live document, locking its row with `lock`. Rows written
+// while content hashes were off are hashed on the spot.
+fn read_etag(reference: &FsReference, lock: bool) -> Option<String> {
+    let row = Spi::connect(|mut client| {
+        let table = client.update(
+            &format!(
+                "SELECT COALESCE(content_hash, fs_content_hash(properties)), update_time \
+                 FROM fs_documents WHERE reference = $1 AND deleted_at IS NULL{}",
+                if lock { " FOR UPDATE" } else { "" }
+            ),
+            Some(1),
+            Some(vec![fsvalue_arg(FsValue::Reference(reference.to_owned()))]),
+        )?;
+        if table.is_empty() {
+            return Ok(None);
+        }
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>(Some((
+            row.get::<Vec<u8>>(1)?
+                .expect("content hash must not be null"),
+            row.get::<TimestampWithTimeZone>(2)?
+                .expect("update_time must not be null"),
+        )))
+    })
+    .expect("Failed to read from fs_documents");
+    row.map(|(content_hash, update_time)| etag(&content_hash, update_time))
+}
+
+// NULL for a missing or deleted document
+#[pg_extern]
+fn fs_etag(reference: FsValue) -> Option<String> {
+    read_etag(expect_document_reference(&reference), false)
+}
+
+// Overwrites a document like fs_set, only if `etag` is its current ETag or
+// `*`. Returns whether anything was written.
+#[pg_extern]
+fn fs_set_if_match(reference: FsValue, properties: FsValue, etag: &str) -> bool {
+    let fs_ref = expect_document_reference(&reference);
+    // The lock keeps the document as it was checked until the write
+    match read_etag(fs_ref, true) {
+        Some(current) if etag == ANY_ETAG || etag == current => {}
+        Some(current) => report(
+            FsCode::FailedPrecondition,
+            format!(
+                "ETag {} does not match the current ETag {} of {}",
+                etag, current, fs_ref
+            ),
+        ),
+        None => report(
+            FsCode::FailedPrecondition,
+            format!("Document {} does not exist to match ETag {}", fs_ref, etag),
+        ),
+    }
+    set_document(fs_ref, properties, true, false)
+}
+
+// Fills in the content hashes left out while pgfirestore.content_hashes was
+// off, `batch_size` rows at a time, returning the number of rows hashed.
+// Frozen documents cannot be updated and keep being hashed on read.
+#[pg_extern]
+fn fs_rebuild_content_hashes(batch_size: default!(i64, 1000)) -> i64 {
+    if batch_size < 1 {
+        FsError::InvalidValue(format!(
+            "Batch size must be positive but found {}",
+            batch_size
+        ))
+        .report()
+    }
+    let mut rebuilt = 0;
+    loop {
+        // Honors statement_timeout and cancel requests on large tables
+        check_for_interrupts!();
+        let count = Spi::connect(|mut client| {
+            client
+                .update(
+                    "UPDATE fs_documents SET content_hash = fs_content_hash(properties) \
+                     WHERE reference IN ( \
+                         SELECT reference FROM fs_documents d \
+                         WHERE content_hash IS NULL AND properties IS NOT NULL \
+                         AND NOT EXISTS ( \
+                             SELECT 1 FROM fs_frozen_documents f WHERE f.reference = d.reference \
+                         ) \
+                         LIMIT $1 \
+                     ) \
+                     RETURNING reference",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::INT8OID.oid(),
+                        batch_size.into_datum(),
+                    )]),
+                )
+                .map(|table| table.len() as i64)
+        })
+        .expect("Failed to write to fs_documents");
+        rebuilt += count;
+        if count < batch_size {
+            return rebuilt;
+        }
+    }
+}
+
+// Seed rows are hashed once the trigger is in place
+extension_sql!(
+    "\n\
+        ALTER TABLE fs_documents ADD COLUMN content_hash bytea;\n\
+        CREATE TRIGGER fs_documents_content_hash \n\
+        BEFORE INSERT OR UPDATE OF properties ON fs_documents \n\
+        FOR EACH ROW EXECUTE PROCEDURE fs_documents_content_hash(); \n\
+        UPDATE fs_documents SET content_hash = fs_content_hash(properties);\n\
+    ",
+    name = "content_hash_column",
+    requires = [
+        "main_table",
+        "seed_data",
+        fs_content_hash,
+        fs_documents_content_hash
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_etag::*;
+    use crate::fs_reference;
+
+    fn stored_hash(path: &str) -> Option<Vec<u8>> {
+        Spi::get_one_with_args::<Vec<u8>>(
+            "SELECT content_hash FROM fs_documents WHERE reference = $1",
+            vec![fsvalue_arg(fs_reference(path))],
+        )
+        .expect("SPI failed")
+    }
+
+    fn set(path: &str, n: i32) {
+        Spi::run(&format!(
+            "SELECT fs_set(fs_reference('{}'), \
+                 fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer({})]), false)",
+            path, n
+        ))
+        .expect("SPI failed");
+    }
+
+    #[test]
+    fn test_etag() {
+        let hash = content_hash(&FsValue::NULL);
+        let update_time = TimestampWithTimeZone::try_from(0).unwrap();
+        let tag = etag(&hash, update_time);
+        assert_eq!(tag.len(), 34);
+        assert!(tag.starts_with('"') && tag.ends_with('"'));
+        assert_eq!(etag(&hash, update_time), tag);
+        assert_ne!(
+            etag(&hash, TimestampWithTimeZone::try_from(1).unwrap()),
+            tag
+        );
+        assert_ne!(
+            etag(&content_hash(&FsValue::Boolean(true)), update_time),
+            tag
+        );
+    }
+
+    #[pg_test]
+    fn test_content_hash() {
+        // Seed rows are hashed at install
+        assert_eq!(
+            stored_hash("/users/1"),
+            Spi::get_one::<Vec<u8>>(
+                "SELECT fs_content_hash(properties) FROM fs_documents \
+                 WHERE reference = fs_reference('/users/1')"
+            )
+            .expect("SPI failed")
+        );
+        set("/hashes/1", 1);
+        let hash = stored_hash("/hashes/1");
+        assert_eq!(hash.as_ref().map(Vec::len), Some(32));
+        // Writing the same content again keeps the hash
+        set("/hashes/1", 1);
+        assert_eq!(stored_hash("/hashes/1"), hash);
+        Spi::run("SELECT fs_touch(fs_reference('/hashes/1'))").expect("SPI failed");
+        assert_eq!(stored_hash("/hashes/1"), hash);
+        set("/hashes/1", 2);
+        assert_ne!(stored_hash("/hashes/1"), hash);
+        set("/hashes/2", 1);
+        assert_eq!(stored_hash("/hashes/2"), hash);
+    }
+
+    #[pg_test]
+    fn test_fs_etag() {
+        Spi::run("SET LOCAL pgfirestore.fixed_request_time = '2024-01-01T00:00:00Z'")
+            .expect("SPI failed");
+        set("/hashes/1", 1);
+        let tag = fs_etag(fs_reference("/hashes/1")).expect("document must exist");
+        set("/hashes/1", 1);
+        assert_eq!(fs_etag(fs_reference("/hashes/1")), Some(tag.to_owned()));
+        set("/hashes/1", 2);
+        assert_ne!(fs_etag(fs_reference("/hashes/1")), Some(tag.to_owned()));
+        // update_time is part of the ETag
+        set("/hashes/1", 1);
+        assert_eq!(fs_etag(fs_reference("/hashes/1")), Some(tag.to_owned()));
+        Spi::run(
+            "SET LOCAL pgfirestore.fixed_request_time = '2024-01-02T00:00:00Z'; \
+             SELECT fs_touch(fs_reference('/hashes/1'))",
+        )
+        .expect("SPI failed");
+        assert_ne!(fs_etag(fs_reference("/hashes/1")), Some(tag));
+        assert_eq!(fs_etag(fs_reference("/hashes/404")), None);
+    }
+
+    #[pg_test]
+    fn test_fs_set_if_match() {
+        set("/hashes/1", 1);
+        let tag = fs_etag(fs_reference("/hashes/1")).expect("document must exist");
+        assert!(fs_set_if_match(
+            fs_reference("/hashes/1"),
+            FsValue::Map([("n".to_owned(), FsValue::Boolean(true))].into()),
+            &tag
+        ));
+        assert_ne!(fs_etag(fs_reference("/hashes/1")), Some(tag));
+        assert!(fs_set_if_match(
+            fs_reference("/hashes/1"),
+            FsValue::Map([("n".to_owned(), FsValue::Boolean(false))].into()),
+            ANY_ETAG
+        ));
+        assert_eq!(
+            crate::fs_documents::get_document(fs_reference("/hashes/1").as_reference().unwrap()),
+            Some(FsValue::Map(
+                [("n".to_owned(), FsValue::Boolean(false))].into()
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_set_if_match_mismatch() {
+        set("/hashes/1", 1);
+        let tag = fs_etag(fs_reference("/hashes/1")).expect("document must exist");
+        set("/hashes/1", 2);
+        for stale in [tag.as_str(), "\"stale\"", ""] {
+            assert!(std::panic::catch_unwind(|| {
+                fs_set_if_match(
+                    fs_reference("/hashes/1"),
+                    FsValue::Map(Default::default()),
+                    stale,
+                )
+            })
+            .is_err());
+        }
+        assert_eq!(
+            crate::fs_documents::get_document(fs_reference("/hashes/1").as_reference().unwrap()),
+            Some(FsValue::Map(
+                [("n".to_owned(), crate::fs_number_from_integer(2))].into()
+            ))
+        );
+    }
+
+    #[pg_test(error = "Document /hashes/404 does not exist to match ETag *")]
+    fn test_fs_set_if_match_missing() {
+        fs_set_if_match(
+            fs_reference("/hashes/404"),
+            FsValue::Map(Default::default()),
+            ANY_ETAG,
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_rebuild_content_hashes() {
+        Spi::run("SET LOCAL pgfirestore.content_hashes = off").expect("SPI failed");
+        for n in 1..=5 {
+            set(&format!("/hashes/{}", n), n);
+        }
+        // Writes while off clear the hash of an existing document too
+        set("/users/1", 7);
+        assert_eq!(stored_hash("/hashes/1"), None);
+        assert_eq!(stored_hash("/users/1"), None);
+        let tag = fs_etag(fs_reference("/hashes/1"));
+        assert!(tag.is_some());
+        Spi::run("SET LOCAL pgfirestore.content_hashes = on").expect("SPI failed");
+        assert_eq!(fs_rebuild_content_hashes(2), 6);
+        assert_eq!(fs_rebuild_content_hashes(2), 0);
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT bool_and(content_hash = fs_content_hash(properties)) FROM fs_documents"
+            ),
+            Ok(Some(true))
+        );
+        assert_eq!(fs_etag(fs_reference("/hashes/1")), tag);
+    }
+
+    #[pg_test(error = "InvalidValue: Batch size must be positive but found 0")]
+    fn test_fs_rebuild_content_hashes_batch_size() {
+        fs_rebuild_content_hashes(0);
+    }
+}
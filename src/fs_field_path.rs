@@ -0,0 +1,271 @@
+use crate::FsError;
+use std::fmt;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// A dotted field path following the Firestore field path syntax, e.g.
+// `address.city` or `` `weird.key`.inner ``. Segments that are not simple
+// identifiers are quoted with backticks, with '`' and '\' escaped by '\'.
+// An unquoted `*` is a wildcard matching exactly one map key.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FieldPath(pub Vec<PathSegment>);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PathSegment {
+    Field(String),
+    Wildcard,
+}
+
+impl FieldPath {
+    pub fn has_wildcard(&self) -> bool {
+        self.0
+            .iter()
+            .any(|segment| segment == &PathSegment::Wildcard)
+    }
+
+    // The literal field names of a path that must not contain wildcards
+    pub fn into_field_names(self) -> Result<Vec<String>> {
+        let path = self.to_string();
+        self.0
+            .into_iter()
+            .map(|segment| match segment {
+                PathSegment::Field(name) => Ok(name),
+                PathSegment::Wildcard => Err(FsError::InvalidValue(format!(
+                    "Wildcards are not allowed in field path '{}'",
+                    path
+                ))),
+            })
+            .collect()
+    }
+}
+
+fn is_simple_field_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first == '_' || first.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+pub fn quote_field_name(name: &str) -> String {
+    if is_simple_field_name(name) {
+        return name.to_owned();
+    }
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('`');
+    for c in name.chars() {
+        if c == '`' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('`');
+    quoted
+}
+
+impl FromStr for FieldPath {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(FsError::InvalidValue(
+                "Field path must not be empty".to_string(),
+            ));
+        }
+        let mut segments = Vec::new();
+        let mut chars = s.chars().peekable();
+        loop {
+            let position = segments.len();
+            if chars.peek() == Some(&'`') {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => name.push(escaped),
+                            None => {
+                                return Err(FsError::InvalidValue(format!(
+                                    "Dangling escape in segment {} of field path '{}'",
+                                    position, s
+                                )))
+                            }
+                        },
+                        Some('`') => break,
+                        Some(c) => name.push(c),
+                        None => {
+                            return Err(FsError::InvalidValue(format!(
+                                "Unterminated backtick in segment {} of field path '{}'",
+                                position, s
+                            )))
+                        }
+                    }
+                }
+                if name.is_empty() {
+                    return Err(FsError::InvalidValue(format!(
+                        "Empty segment {} in field path '{}'",
+                        position, s
+                    )));
+                }
+                segments.push(PathSegment::Field(name));
+            } else {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '.' {
+                        break;
+                    }
+                    if c == '`' {
+                        return Err(FsError::InvalidValue(format!(
+                            "Unexpected backtick in segment {} of field path '{}'",
+                            position, s
+                        )));
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                if name.is_empty() {
+                    return Err(FsError::InvalidValue(format!(
+                        "Empty segment {} in field path '{}'",
+                        position, s
+                    )));
+                }
+                if name == "*" {
+                    segments.push(PathSegment::Wildcard);
+                } else {
+                    segments.push(PathSegment::Field(name));
+                }
+            }
+            match chars.next() {
+                None => break,
+                Some('.') => {
+                    if chars.peek().is_none() {
+                        return Err(FsError::InvalidValue(format!(
+                            "Trailing '.' in field path '{}'",
+                            s
+                        )));
+                    }
+                }
+                Some(c) => {
+                    return Err(FsError::InvalidValue(format!(
+                        "Unexpected character '{}' after segment {} of field path '{}'",
+                        c, position, s
+                    )))
+                }
+            }
+        }
+        Ok(FieldPath(segments))
+    }
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathSegment::Field(name) => write!(f, "{}", quote_field_name(name)),
+            PathSegment::Wildcard => write!(f, "*"),
+        }
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.0
+                .iter()
+                .map(|segment| segment.to_string())
+                .collect::<Vec<String>>()
+                .join(".")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str) -> PathSegment {
+        PathSegment::Field(name.to_string())
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        assert_eq!(
+            FieldPath::from_str("foo").unwrap(),
+            FieldPath(vec![field("foo")])
+        );
+        assert_eq!(
+            FieldPath::from_str("address.city.zip").unwrap(),
+            FieldPath(vec![field("address"), field("city"), field("zip")])
+        );
+        assert_eq!(
+            FieldPath::from_str("tags.0").unwrap(),
+            FieldPath(vec![field("tags"), field("0")])
+        );
+    }
+
+    #[test]
+    fn test_parse_wildcard() {
+        assert_eq!(
+            FieldPath::from_str("scores.*.total").unwrap(),
+            FieldPath(vec![field("scores"), PathSegment::Wildcard, field("total")])
+        );
+        assert!(FieldPath::from_str("scores.*").unwrap().has_wildcard());
+        assert_eq!(
+            FieldPath::from_str("`*`").unwrap(),
+            FieldPath(vec![field("*")])
+        );
+    }
+
+    #[test]
+    fn test_parse_escaping() {
+        assert_eq!(
+            FieldPath::from_str("`weird.key`.inner").unwrap(),
+            FieldPath(vec![field("weird.key"), field("inner")])
+        );
+        assert_eq!(
+            FieldPath::from_str(r"`back\`tick`.`back\\slash`").unwrap(),
+            FieldPath(vec![field("back`tick"), field(r"back\slash")])
+        );
+        assert!(FieldPath::from_str("`unterminated").is_err());
+        assert!(FieldPath::from_str("`quoted`trailing").is_err());
+        assert!(FieldPath::from_str("in`side").is_err());
+    }
+
+    #[test]
+    fn test_parse_empty_segments() {
+        assert!(FieldPath::from_str("").is_err());
+        assert!(FieldPath::from_str("a..b").is_err());
+        assert!(FieldPath::from_str(".a").is_err());
+        assert!(FieldPath::from_str("a.").is_err());
+        assert!(FieldPath::from_str("``").is_err());
+    }
+
+    #[test]
+    fn test_into_field_names() {
+        assert_eq!(
+            FieldPath::from_str("a.`b.c`")
+                .unwrap()
+                .into_field_names()
+                .unwrap(),
+            vec!["a".to_string(), "b.c".to_string()]
+        );
+        assert!(FieldPath::from_str("a.*")
+            .unwrap()
+            .into_field_names()
+            .is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let path = FieldPath(vec![field("a.b"), field("_c1"), field("1d"), field("*")]);
+        assert_eq!(path.to_string(), "`a.b`._c1.`1d`.`*`");
+        assert_eq!(FieldPath::from_str(&path.to_string()).unwrap(), path);
+        assert_eq!(
+            FieldPath(vec![field("scores"), PathSegment::Wildcard]).to_string(),
+            "scores.*"
+        );
+    }
+}
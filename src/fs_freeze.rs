@@ -0,0 +1,193 @@
+use crate::fs_documents::{expect_document_reference, fsvalue_arg};
+use crate::fs_error::{report, FsCode};
+use crate::FsValue;
+use pgrx::heap_tuple::PgHeapTupleError;
+use pgrx::prelude::*;
+use pgrx::WhoAllocated;
+
+fn is_frozen(reference: FsValue) -> bool {
+    Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM fs_frozen_documents WHERE reference = $1)",
+        vec![fsvalue_arg(reference)],
+    )
+    .expect("Failed to read from fs_frozen_documents")
+    .unwrap_or(false)
+}
+
+// Returns the first frozen document among `reference` and its descendants
+pub(crate) fn find_frozen_in_subtree(reference: &FsValue) -> Option<FsValue> {
+    Spi::get_one_with_args::<FsValue>(
+        "SELECT (SELECT reference FROM fs_frozen_documents \
+         WHERE reference = $1 OR (reference > $1 AND reference < fs_prefix_successor($1)) \
+         ORDER BY reference LIMIT 1)",
+        vec![fsvalue_arg(reference.to_owned())],
+    )
+    .expect("Failed to read from fs_frozen_documents")
+}
+
+// Writes to frozen documents fail with FAILED_PRECONDITION, SQLSTATE 55000,
+// so that callers can tell them apart from other errors
+pub(crate) fn report_frozen(message: String) -> ! {
+    report(FsCode::FailedPrecondition, message)
+}
+
+#[pg_extern]
+fn fs_freeze(reference: FsValue) {
+    let fs_ref = expect_document_reference(&reference);
+    // Locking the row makes a concurrent write wait for the freeze to commit
+    // and then fail the guard, rather than slip past an uncommitted freeze
+    let exists = Spi::connect(|mut client| {
+        client
+            .update(
+                "SELECT reference FROM fs_documents \
+                 WHERE reference = $1 AND deleted_at IS NULL FOR SHARE",
+                None,
+                Some(vec![fsvalue_arg(reference.to_owned())]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to read from fs_documents");
+    if !exists {
+        report(
+            FsCode::NotFound,
+            format!("Cannot freeze document {} which does not exist", fs_ref),
+        )
+    }
+    Spi::run_with_args(
+        "INSERT INTO fs_frozen_documents (reference) VALUES ($1) ON CONFLICT DO NOTHING",
+        Some(vec![fsvalue_arg(reference.to_owned())]),
+    )
+    .expect("Failed to write to fs_frozen_documents")
+}
+
+// Returns whether the document was frozen
+#[pg_extern]
+fn fs_unfreeze(reference: FsValue) -> bool {
+    expect_document_reference(&reference);
+    Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM fs_frozen_documents WHERE reference = $1 RETURNING reference",
+                None,
+                Some(vec![fsvalue_arg(reference)]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to delete from fs_frozen_documents")
+}
+
+#[pg_extern]
+fn fs_is_frozen(reference: FsValue) -> bool {
+    expect_document_reference(&reference);
+    is_frozen(reference)
+}
+
+// Rejects UPDATE and DELETE of frozen documents, including direct SQL on
+// fs_documents.
+#[pg_trigger]
+fn fs_documents_frozen_guard<'a>(
+    trigger: &'a pgrx::PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PgHeapTupleError> {
+    let old = trigger
+        .old()
+        .expect("expecting an OLD row in an UPDATE or DELETE trigger");
+    let reference = old
+        .get_by_name::<FsValue>("reference")
+        .expect("Failed to read reference from the OLD row")
+        .expect("reference must not be null");
+    if is_frozen(reference.to_owned()) {
+        let fs_ref = reference.expect_reference();
+        report_frozen(format!("Document {} is frozen", fs_ref));
+    }
+    // DELETE has no NEW row and must return OLD to proceed
+    Ok(Some(trigger.new().unwrap_or(old)))
+}
+
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_frozen_documents (\n\
+            reference fsvalue PRIMARY KEY\n\
+        );\n\
+    ",
+    name = "frozen_documents_table",
+    requires = ["main_table"],
+);
+
+extension_sql!(
+    "\n\
+        CREATE TRIGGER fs_documents_frozen_guard \n\
+        BEFORE UPDATE OR DELETE ON fs_documents \n\
+        FOR EACH ROW EXECUTE PROCEDURE fs_documents_frozen_guard(); \n\
+    ",
+    name = "frozen_documents_trigger",
+    requires = [
+        "main_table",
+        "frozen_documents_table",
+        fs_documents_frozen_guard
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_freeze::*;
+    use crate::{fs_map_from_entries, fs_number_from_integer, fs_reference};
+
+    // Fails unless `statement` raises SQLSTATE 55000
+    fn assert_frozen_error(statement: &str) {
+        Spi::run(&format!(
+            "DO $$ BEGIN \
+                {}; \
+                RAISE EXCEPTION 'statement succeeded'; \
+             EXCEPTION WHEN object_not_in_prerequisite_state THEN NULL; \
+             END $$",
+            statement
+        ))
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_freeze() {
+        assert!(!fs_is_frozen(fs_reference("/users/2")));
+        fs_freeze(fs_reference("/users/2"));
+        fs_freeze(fs_reference("/users/2"));
+        assert!(fs_is_frozen(fs_reference("/users/2")));
+        assert!(!fs_is_frozen(fs_reference("/users/3")));
+
+        assert_frozen_error(
+            "UPDATE fs_documents SET properties = fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]) \
+             WHERE reference = fs_reference('/users/2')",
+        );
+        assert_frozen_error("DELETE FROM fs_documents WHERE reference = fs_reference('/users/2')");
+        assert_frozen_error(
+            "PERFORM fs_set(fs_reference('/users/2'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        );
+
+        assert!(fs_unfreeze(fs_reference("/users/2")));
+        assert!(!fs_unfreeze(fs_reference("/users/2")));
+        Spi::run(
+            "UPDATE fs_documents SET properties = fs_map_from_entries(ARRAY['foo'], ARRAY[fs_number_from_integer(7)]) \
+             WHERE reference = fs_reference('/users/2')",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            crate::fs_documents::get_document(fs_reference("/users/2").expect_reference()),
+            Some(fs_map_from_entries(
+                vec!["foo".to_owned()],
+                vec![fs_number_from_integer(7)]
+            ))
+        );
+    }
+
+    #[pg_test(error = "Document /users/3 is frozen")]
+    fn test_fs_freeze_rejects_delete() {
+        fs_freeze(fs_reference("/users/3"));
+        Spi::run("DELETE FROM fs_documents WHERE reference = fs_reference('/users/3')")
+            .expect("SPI failed");
+    }
+
+    #[pg_test(error = "Cannot freeze document /users/404 which does not exist")]
+    fn test_fs_freeze_missing_document() {
+        fs_freeze(fs_reference("/users/404"));
+    }
+}
@@ -0,0 +1,452 @@
+use crate::fs_number::number_from_double;
+use crate::{FsError, FsValue};
+use pgrx::prelude::*;
+
+// Distances are measured on a sphere with the Earth's mean radius
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+// (latitude, longitude) in degrees
+type Coordinates = (f64, f64);
+
+fn coordinates(point: &FsValue) -> Option<Coordinates> {
+    match point {
+        FsValue::GeoPoint(latitude, longitude) => {
+            Some((latitude.as_double(), longitude.as_double()))
+        }
+        _ => None,
+    }
+}
+
+fn expect_coordinates(point: &FsValue) -> Coordinates {
+    coordinates(point).unwrap_or_else(|| {
+        FsError::InvalidType(format!(
+            "Expecting a GEOPOINT but found {}",
+            point.type_name()
+        ))
+        .report()
+    })
+}
+
+fn geo_point((latitude, longitude): Coordinates) -> FsValue {
+    FsValue::GeoPoint(number_from_double(latitude), number_from_double(longitude))
+}
+
+// A geo point within Firestore's bounds, which also leave out NaN and the
+// infinities
+pub(crate) fn geo_point_value(latitude: f64, longitude: f64) -> Result<FsValue, FsError> {
+    if !(-90.0..=90.0).contains(&latitude) {
+        return Err(FsError::InvalidValue(format!(
+            "Latitude must be between -90 and 90 but found {}",
+            latitude
+        )));
+    }
+    if !(-180.0..=180.0).contains(&longitude) {
+        return Err(FsError::InvalidValue(format!(
+            "Longitude must be between -180 and 180 but found {}",
+            longitude
+        )));
+    }
+    Ok(geo_point((latitude, longitude)))
+}
+
+// Haversine distance in meters
+fn distance((lat1, lng1): Coordinates, (lat2, lng2): Coordinates) -> f64 {
+    let half_chord = ((lat2 - lat1).to_radians() / 2.0).sin().powi(2)
+        + lat1.to_radians().cos()
+            * lat2.to_radians().cos()
+            * ((lng2 - lng1).to_radians() / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * half_chord.sqrt().min(1.0).asin()
+}
+
+// Whether `longitude` lies east of `west` and west of `east`, wrapping past
+// the antimeridian when west > east. -180 and 180 are the same meridian.
+fn longitude_in_range(longitude: f64, west: f64, east: f64) -> bool {
+    let within = |longitude: f64| match west <= east {
+        true => west <= longitude && longitude <= east,
+        false => longitude >= west || longitude <= east,
+    };
+    within(longitude) || (longitude.abs() == 180.0 && within(-longitude))
+}
+
+// Edges are inclusive, so a box whose corners share a latitude or a longitude
+// is a line and one whose corners are equal is a single point. A box whose
+// south-west corner lies north of its north-east one is empty.
+fn in_box((latitude, longitude): Coordinates, sw: Coordinates, ne: Coordinates) -> bool {
+    sw.0 <= latitude && latitude <= ne.0 && longitude_in_range(longitude, sw.1, ne.1)
+}
+
+fn wrap_longitude(longitude: f64) -> f64 {
+    match longitude {
+        longitude if longitude < -180.0 => longitude + 360.0,
+        longitude if longitude > 180.0 => longitude - 360.0,
+        longitude => longitude,
+    }
+}
+
+// The smallest box holding every point within `radius_m` of `center`, as its
+// south-west and north-east corners (J. Matuschek, "Finding Points Within a
+// Distance of a Latitude/Longitude Using Bounding Coordinates"). The box
+// crosses the antimeridian when the circle does. When the circle reaches a
+// pole, the box spans every longitude and its latitude stops at the pole.
+fn box_for_radius((latitude, longitude): Coordinates, radius_m: f64) -> (Coordinates, Coordinates) {
+    let angle = (radius_m / EARTH_RADIUS_M).to_degrees();
+    let (south, north) = (latitude - angle, latitude + angle);
+    if south <= -90.0 || north >= 90.0 {
+        return ((south.max(-90.0), -180.0), (north.min(90.0), 180.0));
+    }
+    let delta = (angle.to_radians().sin() / latitude.to_radians().cos())
+        .asin()
+        .to_degrees();
+    (
+        (south, wrap_longitude(longitude - delta)),
+        (north, wrap_longitude(longitude + delta)),
+    )
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_geopoint(latitude: f64, longitude: f64) -> FsValue {
+    geo_point_value(latitude, longitude).unwrap_or_else(|error| error.report())
+}
+
+// A point that is not a GEOPOINT, e.g. a field of another type, is never in
+// the box, like Firestore filters never match across types
+#[pg_extern(immutable, parallel_safe)]
+fn fs_geo_in_box(point: FsValue, sw: FsValue, ne: FsValue) -> bool {
+    let (sw, ne) = (expect_coordinates(&sw), expect_coordinates(&ne));
+    coordinates(&point).is_some_and(|point| in_box(point, sw, ne))
+}
+
+// Meant as a prefilter for fs_geo_distance: every point within the radius is
+// in the box, but the corners of the box are farther away
+#[pg_extern(immutable, parallel_safe)]
+fn fs_geo_box_for_radius(
+    center: FsValue,
+    radius_m: f64,
+) -> TableIterator<'static, (name!(sw, FsValue), name!(ne, FsValue))> {
+    if radius_m.is_nan() || radius_m < 0.0 {
+        FsError::InvalidValue(format!(
+            "Radius must be a non-negative number of meters but found {}",
+            radius_m
+        ))
+        .report()
+    }
+    let (sw, ne) = box_for_radius(expect_coordinates(&center), radius_m);
+    TableIterator::new(std::iter::once((geo_point(sw), geo_point(ne))))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_geo_distance(a: FsValue, b: FsValue) -> f64 {
+    distance(expect_coordinates(&a), expect_coordinates(&b))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_documents::fsvalue_arg;
+    use crate::fs_geo::*;
+    use crate::{fs_map_from_entries, fs_reference};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // A degree of latitude, and of longitude at the equator
+    const DEGREE_M: f64 = 111_195.080_233_532_9;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} is not close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_in_box_edges() {
+        let (sw, ne) = ((10.0, 20.0), (11.0, 21.0));
+        for inside in [
+            (10.000001, 20.5),
+            (10.999999, 20.5),
+            (10.5, 20.000001),
+            (10.5, 20.999999),
+            (10.0, 20.0),
+            (11.0, 21.0),
+        ] {
+            assert!(in_box(inside, sw, ne), "{:?}", inside);
+        }
+        for outside in [
+            (9.999999, 20.5),
+            (11.000001, 20.5),
+            (10.5, 19.999999),
+            (10.5, 21.000001),
+        ] {
+            assert!(!in_box(outside, sw, ne), "{:?}", outside);
+        }
+    }
+
+    #[test]
+    fn test_in_box_antimeridian() {
+        let (sw, ne) = ((-10.0, 179.0), (10.0, -179.0));
+        for inside in [(0.0, 179.9), (0.0, -179.9), (0.0, 180.0), (0.0, -180.0)] {
+            assert!(in_box(inside, sw, ne), "{:?}", inside);
+        }
+        for outside in [(0.0, 0.0), (0.0, 178.9), (0.0, -178.9), (10.1, 179.9)] {
+            assert!(!in_box(outside, sw, ne), "{:?}", outside);
+        }
+        // 180 and -180 are the same meridian on either side of a box
+        assert!(in_box((0.0, 180.0), (0.0, -180.0), (1.0, -179.0)));
+        assert!(in_box((0.0, -180.0), (0.0, 179.0), (1.0, 180.0)));
+    }
+
+    #[test]
+    fn test_in_box_degenerate() {
+        // A single point
+        assert!(in_box((5.0, 5.0), (5.0, 5.0), (5.0, 5.0)));
+        assert!(!in_box((5.0, 5.000001), (5.0, 5.0), (5.0, 5.0)));
+        // A segment of a parallel
+        assert!(in_box((5.0, 3.0), (5.0, 0.0), (5.0, 10.0)));
+        assert!(!in_box((5.000001, 3.0), (5.0, 0.0), (5.0, 10.0)));
+        // South of north is empty
+        assert!(!in_box((5.5, 3.0), (6.0, 0.0), (5.0, 10.0)));
+    }
+
+    #[test]
+    fn test_box_for_radius() {
+        let ((south, west), (north, east)) = box_for_radius((0.0, 0.0), DEGREE_M);
+        assert_close(south, -1.0);
+        assert_close(north, 1.0);
+        assert_close(west, -1.0);
+        assert_close(east, 1.0);
+
+        // Crossing the antimeridian
+        let (sw, ne) = box_for_radius((0.0, 179.9), 50_000.0);
+        assert!(sw.1 > ne.1);
+        assert!(in_box((0.0, -179.9), sw, ne));
+        assert!(in_box((0.0, 179.9), sw, ne));
+
+        assert_eq!(box_for_radius((0.0, 0.0), 0.0), ((0.0, 0.0), (0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_box_for_radius_near_poles() {
+        let ((south, west), (north, east)) = box_for_radius((89.9, 10.0), 50_000.0);
+        assert_close(north, 90.0);
+        assert_close(south, 89.9 - 50_000.0 / DEGREE_M);
+        assert_eq!((west, east), (-180.0, 180.0));
+
+        let ((south, west), (north, east)) = box_for_radius((-89.95, 45.0), 10_000.0);
+        assert_close(south, -90.0);
+        assert_close(north, -89.95 + 10_000.0 / DEGREE_M);
+        assert_eq!((west, east), (-180.0, 180.0));
+
+        // A radius past the pole still covers the whole globe
+        let (sw, ne) = box_for_radius((0.0, 0.0), 30_000_000.0);
+        assert_eq!((sw, ne), ((-90.0, -180.0), (90.0, 180.0)));
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_close(distance((0.0, 0.0), (0.0, 0.0)), 0.0);
+        assert!((distance((0.0, 0.0), (1.0, 0.0)) - DEGREE_M).abs() < 0.01);
+        assert!((distance((0.0, 179.5), (0.0, -179.5)) - DEGREE_M).abs() < 0.01);
+        assert!(
+            (distance((90.0, 0.0), (-90.0, 0.0)) - std::f64::consts::PI * EARTH_RADIUS_M).abs()
+                < 0.01
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a GEOPOINT but found STRING")]
+    fn test_fs_geo_in_box_not_a_box() {
+        fs_geo_in_box(
+            geo_point((0.0, 0.0)),
+            FsValue::String("sw".to_owned()),
+            geo_point((1.0, 1.0)),
+        );
+    }
+
+    #[test]
+    fn test_geo_point_value_bounds() {
+        for (latitude, longitude) in [(90.0, 180.0), (-90.0, -180.0), (0.0, 0.0)] {
+            assert_eq!(
+                geo_point_value(latitude, longitude).unwrap(),
+                geo_point((latitude, longitude))
+            );
+        }
+        for (latitude, longitude) in [
+            (90.000001, 0.0),
+            (-91.0, 0.0),
+            (0.0, 180.5),
+            (0.0, -181.0),
+            (f64::NAN, 0.0),
+            (0.0, f64::NAN),
+            (f64::INFINITY, 0.0),
+            (0.0, f64::NEG_INFINITY),
+        ] {
+            assert!(
+                geo_point_value(latitude, longitude).is_err(),
+                "{} {}",
+                latitude,
+                longitude
+            );
+        }
+    }
+
+    #[test]
+    fn test_geo_point_text() {
+        let point = geo_point((1.5, -2.25));
+        let text = point.to_json_value().to_string();
+        assert_eq!(text, r#"{"type":"GEOPOINT","value":[1.5,-2.25]}"#);
+        assert_eq!(FsValue::parse_text(&text), Ok(point));
+        // Integral coordinates are doubles all the same
+        assert_eq!(
+            FsValue::parse_text(r#"{"type": "GEOPOINT", "value": [1, 2]}"#),
+            Ok(geo_point((1.0, 2.0)))
+        );
+        for text in [
+            r#"{"type": "GEOPOINT", "value": [1]}"#,
+            r#"{"type": "GEOPOINT", "value": [1, 2, 3]}"#,
+            r#"{"type": "GEOPOINT", "value": ["NaN", 0]}"#,
+            r#"{"type": "GEOPOINT", "value": {"latitude": 1, "longitude": 2}}"#,
+            r#"{"type": "GEOPOINT", "value": [91, 0]}"#,
+        ] {
+            assert!(FsValue::parse_text(text).is_err(), "{}", text);
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_geopoint_nested() {
+        let text = Spi::get_one::<String>(
+            "SELECT fs_doc('at', fs_geopoint(1.5, -2.25), \
+                 'route', fs_arr(fs_geopoint(0, 0), fs_geopoint(-90, 180)))::text",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            text.as_deref(),
+            Some(
+                "{\"type\":\"MAP\",\"value\":{\
+                 \"at\":{\"type\":\"GEOPOINT\",\"value\":[1.5,-2.25]},\
+                 \"route\":{\"type\":\"ARRAY\",\"value\":[\
+                 {\"type\":\"GEOPOINT\",\"value\":[0.0,0.0]},\
+                 {\"type\":\"GEOPOINT\",\"value\":[-90.0,180.0]}]}}}"
+            )
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(&format!(
+                "SELECT '{}'::fsvalue = fs_doc('at', fs_geopoint(1.5, -2.25), \
+                     'route', fs_arr(fs_geopoint(0, 0), fs_geopoint(-90, 180)))",
+                text.unwrap()
+            )),
+            Ok(Some(true))
+        );
+    }
+
+    // Like Firestore, by latitude and then by longitude
+    #[pg_test]
+    fn test_fs_geopoint_ordering() {
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_geopoint(1, 100) #< fs_geopoint(2, -100) \
+                 AND fs_geopoint(1, -1) #< fs_geopoint(1, 1) \
+                 AND fs_geopoint(-90, 180) #< fs_geopoint(90, -180) \
+                 AND fs_geopoint(1, 2) #= fs_geopoint(1.0, 2.0)"
+            ),
+            Ok(Some(true))
+        );
+        assert_close(
+            fs_geo_distance(fs_geopoint(0.0, 0.0), fs_geopoint(0.0, 1.0)),
+            DEGREE_M,
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Latitude must be between -90 and 90 but found 91")]
+    fn test_fs_geopoint_out_of_range() {
+        fs_geopoint(91.0, 0.0);
+    }
+
+    #[pg_test]
+    fn test_fs_geo_in_box() {
+        let (sw, ne) = (geo_point((-1.0, 179.0)), geo_point((1.0, -179.0)));
+        assert!(fs_geo_in_box(
+            geo_point((0.0, 179.9)),
+            sw.to_owned(),
+            ne.to_owned()
+        ));
+        assert!(fs_geo_in_box(
+            geo_point((0.0, -179.9)),
+            sw.to_owned(),
+            ne.to_owned()
+        ));
+        assert!(!fs_geo_in_box(
+            geo_point((0.0, 0.0)),
+            sw.to_owned(),
+            ne.to_owned()
+        ));
+        assert!(!fs_geo_in_box(FsValue::String("here".to_owned()), sw, ne));
+    }
+
+    #[pg_test(error = "InvalidValue: Radius must be a non-negative number of meters but found -1")]
+    fn test_fs_geo_box_for_radius_negative() {
+        fs_geo_box_for_radius(geo_point((0.0, 0.0)), -1.0);
+    }
+
+    // The references of documents whose location is within `radius_m` of
+    // `center`, with or without the box prefilter
+    fn within_radius(center: &FsValue, radius_m: f64, prefilter: bool) -> Vec<String> {
+        let query = match prefilter {
+            true => {
+                "SELECT array_agg(fs_reference_text(reference) ORDER BY reference) \
+                 FROM fs_documents, fs_geo_box_for_radius($1, $2) AS box \
+                 WHERE fs_geo_in_box(properties->'location', box.sw, box.ne) \
+                     AND fs_geo_distance(properties->'location', $1) <= $2"
+            }
+            false => {
+                "SELECT array_agg(fs_reference_text(reference) ORDER BY reference) \
+                 FROM fs_documents WHERE fs_geo_distance(properties->'location', $1) <= $2"
+            }
+        };
+        Spi::get_one_with_args::<Vec<String>>(
+            query,
+            vec![
+                fsvalue_arg(center.to_owned()),
+                (PgBuiltInOids::FLOAT8OID.oid(), radius_m.into_datum()),
+            ],
+        )
+        .expect("SPI failed")
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_prefilter_and_refine() {
+        let mut rng = StdRng::seed_from_u64(1973);
+        let centers: [(f64, f64); 4] =
+            [(48.85, 2.35), (10.0, 179.5), (88.0, -60.0), (-60.0, -179.9)];
+        for (i, (latitude, longitude)) in centers.iter().enumerate() {
+            for j in 0..100 {
+                let point = (
+                    (latitude + rng.gen_range(-5.0..5.0)).clamp(-90.0, 90.0),
+                    wrap_longitude(longitude + rng.gen_range(-20.0..20.0)),
+                );
+                Spi::run_with_args(
+                    "INSERT INTO fs_documents (reference, properties) VALUES ($1, $2)",
+                    Some(vec![
+                        fsvalue_arg(fs_reference(&format!("/places/{}-{}", i, j))),
+                        fsvalue_arg(fs_map_from_entries(
+                            vec!["location".to_owned()],
+                            vec![geo_point(point)],
+                        )),
+                    ]),
+                )
+                .expect("SPI failed");
+            }
+        }
+        for center in centers {
+            for radius_m in [50_000.0, 300_000.0, 1_000_000.0] {
+                let center = geo_point(center);
+                let expected = within_radius(&center, radius_m, false);
+                assert_eq!(within_radius(&center, radius_m, true), expected);
+                if radius_m == 1_000_000.0 {
+                    assert!(!expected.is_empty());
+                }
+            }
+        }
+    }
+}
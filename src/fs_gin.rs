@@ -0,0 +1,389 @@
+use crate::fs_display::display_value;
+use crate::fs_ordering::{contains_nan, integer_form};
+use crate::FsError;
+use crate::FsValue;
+use pgrx::prelude::*;
+use pgrx::Internal;
+use sha2::{Digest, Sha256};
+
+// Strategy numbers of the fs_array_ops GIN operator class
+const CONTAINS_STRATEGY: i16 = 1;
+const CONTAINS_ANY_STRATEGY: i16 = 2;
+const CONTAINS_ALL_STRATEGY: i16 = 3;
+
+// Index keys are the first 8 bytes of the sha256 of an element's canonical
+// text, with every number stored as an integer where it can be so that equal
+// elements like 1 and 1.0 share a key. Hash collisions are resolved by
+// rechecking the operator.
+fn element_key(element: &FsValue) -> i64 {
+    let normalized = integer_form(element);
+    let digest = Sha256::digest(normalized.canonical_text().as_bytes());
+    let mut key = [0u8; 8];
+    key.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(key)
+}
+
+fn element_keys(elements: &[FsValue]) -> Vec<i64> {
+    let mut keys: Vec<i64> = elements.iter().map(element_key).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys
+}
+
+// Firestore never matches NaN in an array-contains query, not even in an
+// array holding NaN, nor an array or map holding one
+fn is_matchable(element: &FsValue) -> bool {
+    !contains_nan(element)
+}
+
+// Elements match by value like `#=`, so 1 matches 1.0
+fn contains_element(array: &[FsValue], element: &FsValue) -> bool {
+    is_matchable(element) && array.iter().any(|candidate| candidate.cmp(element).is_eq())
+}
+
+fn expect_candidates(candidates: &FsValue) -> &Vec<FsValue> {
+    candidates.as_array().unwrap_or_else(|| {
+        FsError::InvalidType(format!(
+            "Expecting an array of candidates but found {}",
+            display_value(candidates)
+        ))
+        .report()
+    })
+}
+
+// Hands `keys` to GIN as a palloc'd datum array, setting `nkeys`. The array
+// is never NULL since GIN does not accept NULL from its support functions.
+unsafe fn into_gin_keys(keys: Vec<i64>, nkeys: &Internal) -> Internal {
+    *nkeys.get_mut::<i32>().expect("nkeys must not be null") = keys.len() as i32;
+    let datums = pg_sys::palloc(keys.len().max(1) * std::mem::size_of::<pg_sys::Datum>())
+        as *mut pg_sys::Datum;
+    for (i, key) in keys.into_iter().enumerate() {
+        *datums.add(i) = key.into_datum().expect("int8 datum must not be null");
+    }
+    Internal::from(Some(pg_sys::Datum::from(datums as usize)))
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(#@>)]
+fn fs_array_contains(array: FsValue, element: FsValue) -> bool {
+    array
+        .as_array()
+        .is_some_and(|array| contains_element(array, &element))
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(#?|)]
+fn fs_array_contains_any(array: FsValue, candidates: FsValue) -> bool {
+    let candidates = expect_candidates(&candidates);
+    array.as_array().is_some_and(|array| {
+        candidates
+            .iter()
+            .any(|candidate| contains_element(array, candidate))
+    })
+}
+
+// fs_array_contains_any with the candidates as a SQL array
+#[pg_extern(immutable, parallel_safe, name = "fs_array_contains_any")]
+fn fs_array_contains_any_of(array: FsValue, candidates: Vec<FsValue>) -> bool {
+    array.as_array().is_some_and(|array| {
+        candidates
+            .iter()
+            .any(|candidate| contains_element(array, candidate))
+    })
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(#?&)]
+fn fs_array_contains_all(array: FsValue, candidates: FsValue) -> bool {
+    let candidates = expect_candidates(&candidates);
+    array.as_array().is_some_and(|array| {
+        candidates
+            .iter()
+            .all(|candidate| contains_element(array, candidate))
+    })
+}
+
+// GIN extractValue: the distinct element keys of an array, none otherwise
+#[pg_extern(immutable, parallel_safe)]
+fn fs_gin_extract_value(value: FsValue, nkeys: Internal) -> Internal {
+    let keys = match value.as_array() {
+        Some(array) => element_keys(array),
+        None => Vec::new(),
+    };
+    unsafe { into_gin_keys(keys, &nkeys) }
+}
+
+// GIN extractQuery. Without keys, contains and contains-any match nothing (the
+// default search mode) while contains-all matches every array, so the whole
+// index is scanned and the operator rechecked. NaN candidates are left out
+// where they cannot match anyway.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_gin_extract_query(
+    query: FsValue,
+    nkeys: Internal,
+    strategy: i16,
+    _partial_matches: Internal,
+    _extra_data: Internal,
+    _null_flags: Internal,
+    search_mode: Internal,
+) -> Internal {
+    let keys = match strategy {
+        CONTAINS_STRATEGY if is_matchable(&query) => vec![element_key(&query)],
+        CONTAINS_STRATEGY => Vec::new(),
+        CONTAINS_ANY_STRATEGY => {
+            let candidates: Vec<FsValue> = expect_candidates(&query)
+                .iter()
+                .filter(|candidate| is_matchable(candidate))
+                .cloned()
+                .collect();
+            element_keys(&candidates)
+        }
+        CONTAINS_ALL_STRATEGY => element_keys(expect_candidates(&query)),
+        _ => panic!("Unknown fs_array_ops strategy {}", strategy),
+    };
+    unsafe {
+        if strategy == CONTAINS_ALL_STRATEGY && keys.is_empty() {
+            *search_mode
+                .get_mut::<i32>()
+                .expect("searchMode must not be null") = pg_sys::GIN_SEARCH_MODE_ALL as i32;
+        }
+        into_gin_keys(keys, &nkeys)
+    }
+}
+
+// GIN consistent: any key for contains-any, all keys otherwise. Matches are
+// always rechecked because keys are hashes.
+#[pg_extern(immutable, parallel_safe)]
+#[allow(clippy::too_many_arguments)]
+fn fs_gin_consistent(
+    check: Internal,
+    strategy: i16,
+    _query: FsValue,
+    nkeys: i32,
+    _extra_data: Internal,
+    recheck: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> bool {
+    unsafe {
+        *recheck.get_mut::<bool>().expect("recheck must not be null") = true;
+        let check = match check.unwrap() {
+            Some(check) if nkeys > 0 => {
+                std::slice::from_raw_parts(check.cast_mut_ptr::<bool>(), nkeys as usize)
+            }
+            _ => &[],
+        };
+        match strategy {
+            CONTAINS_ANY_STRATEGY => check.iter().any(|matched| *matched),
+            _ => check.iter().all(|matched| *matched),
+        }
+    }
+}
+
+extension_sql!(
+    "\n\
+        CREATE OPERATOR CLASS fs_array_ops \n\
+        DEFAULT FOR TYPE fsvalue USING gin AS \n\
+            OPERATOR 1 #@> (fsvalue, fsvalue), \n\
+            OPERATOR 2 #?| (fsvalue, fsvalue), \n\
+            OPERATOR 3 #?& (fsvalue, fsvalue), \n\
+            FUNCTION 1 btint8cmp(int8, int8), \n\
+            FUNCTION 2 fs_gin_extract_value(fsvalue, internal), \n\
+            FUNCTION 3 fs_gin_extract_query(fsvalue, internal, int2, internal, internal, internal, internal), \n\
+            FUNCTION 4 fs_gin_consistent(internal, int2, fsvalue, int4, internal, internal, internal, internal), \n\
+            STORAGE int8; \n\
+    ",
+    name = "array_gin_opclass",
+    requires = [
+        fs_array_contains,
+        fs_array_contains_any,
+        fs_array_contains_all,
+        fs_gin_extract_value,
+        fs_gin_extract_query,
+        fs_gin_consistent
+    ],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_gin::*;
+    use crate::fs_test_util::explain;
+    use crate::{
+        fs_array, fs_boolean, fs_nan, fs_null, fs_number_from_double, fs_number_from_integer,
+        fs_string,
+    };
+
+    fn count(query: &str) -> i64 {
+        Spi::get_one::<i64>(query)
+            .expect("SPI failed")
+            .expect("count must not be null")
+    }
+
+    #[test]
+    fn test_element_keys() {
+        let keys = element_keys(&[
+            fs_number_from_integer(1),
+            fs_string("a"),
+            fs_number_from_integer(1),
+        ]);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(element_key(&fs_string("a")), element_key(&fs_string("a")));
+        assert_ne!(
+            element_key(&fs_string("1")),
+            element_key(&fs_number_from_integer(1))
+        );
+        assert_eq!(
+            element_key(&fs_number_from_double(1.0)),
+            element_key(&fs_number_from_integer(1))
+        );
+        assert_eq!(
+            element_key(&fs_array(vec![fs_number_from_double(2.0)])),
+            element_key(&fs_array(vec![fs_number_from_integer(2)]))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_array_operators() {
+        let array = fs_array(vec![
+            fs_number_from_integer(1),
+            fs_number_from_integer(2),
+            fs_number_from_integer(2),
+        ]);
+        assert!(fs_array_contains(
+            array.to_owned(),
+            fs_number_from_integer(2)
+        ));
+        assert!(!fs_array_contains(array.to_owned(), fs_string("2")));
+        assert!(fs_array_contains_any(
+            array.to_owned(),
+            fs_array(vec![fs_number_from_integer(3), fs_number_from_integer(1)])
+        ));
+        assert!(!fs_array_contains_any(array.to_owned(), fs_array(vec![])));
+        assert!(fs_array_contains_all(
+            array.to_owned(),
+            fs_array(vec![fs_number_from_integer(2), fs_number_from_integer(1)])
+        ));
+        assert!(!fs_array_contains_all(
+            array.to_owned(),
+            fs_array(vec![fs_number_from_integer(2), fs_number_from_integer(3)])
+        ));
+        assert!(fs_array_contains_all(array, fs_array(vec![])));
+        assert!(!fs_array_contains_all(fs_string("a"), fs_array(vec![])));
+        assert!(!fs_array_contains(fs_string("a"), fs_string("a")));
+        assert!(fs_array_contains(
+            fs_array(vec![fs_number_from_double(1.0)]),
+            fs_number_from_integer(1)
+        ));
+
+        let with_nan = fs_array(vec![fs_nan(), fs_number_from_integer(1)]);
+        assert!(!fs_array_contains(with_nan.to_owned(), fs_nan()));
+        assert!(!fs_array_contains_any(
+            with_nan.to_owned(),
+            fs_array(vec![fs_nan()])
+        ));
+        assert!(fs_array_contains_any(
+            with_nan.to_owned(),
+            fs_array(vec![fs_nan(), fs_number_from_integer(1)])
+        ));
+        assert!(!fs_array_contains_all(with_nan, fs_array(vec![fs_nan()])));
+
+        let array = fs_array(vec![fs_number_from_integer(1), fs_null(), fs_boolean(true)]);
+        assert!(fs_array_contains(array.to_owned(), fs_null()));
+        assert!(fs_array_contains(array.to_owned(), fs_boolean(true)));
+        assert!(!fs_array_contains(array.to_owned(), fs_boolean(false)));
+        assert!(fs_array_contains_any_of(
+            array.to_owned(),
+            vec![fs_number_from_integer(2), fs_number_from_integer(1)]
+        ));
+        assert!(!fs_array_contains_any_of(array, vec![fs_nan()]));
+        assert!(!fs_array_contains_any_of(
+            fs_string("a"),
+            vec![fs_string("a")]
+        ));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_array_contains_any(fs_string('a'), ARRAY[fs_string('a')])"
+            ),
+            Ok(Some(false))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_array_ops_index() {
+        // Every array holds i % 7 twice so that duplicates are covered
+        Spi::run(
+            "CREATE TEMPORARY TABLE gin_arrays (id int, tags fsvalue); \
+             INSERT INTO gin_arrays \
+             SELECT i, fs_array(ARRAY[fs_number_from_integer(i % 100), fs_number_from_integer(i % 7), fs_number_from_integer(i % 7)]) \
+             FROM generate_series(1, 10000) i; \
+             INSERT INTO gin_arrays VALUES (0, fs_string('not an array')); \
+             INSERT INTO gin_arrays VALUES (-1, fs_array(ARRAY[fs_nan(), fs_number_from_integer(3)])); \
+             INSERT INTO gin_arrays VALUES (-2, fs_array(ARRAY[fs_number_from_double(1000.0)])); \
+             CREATE INDEX gin_arrays_tags ON gin_arrays USING gin (tags); \
+             ANALYZE gin_arrays;",
+        )
+        .expect("SPI failed");
+
+        let expected = |keep: fn(i64) -> bool| (1..=10000).filter(|i| keep(*i)).count() as i64;
+        let cases = [
+            (
+                "tags #@> fs_number_from_integer(3)",
+                expected(|i| i % 100 == 3 || i % 7 == 3) + 1,
+            ),
+            ("tags #@> fs_nan()", 0),
+            // Numbers match by value, whichever way each side is stored
+            ("tags #@> fs_number_from_integer(1000)", 1),
+            (
+                "tags #@> fs_number_from_double(3.0)",
+                expected(|i| i % 100 == 3 || i % 7 == 3) + 1,
+            ),
+            ("tags #?| fs_array(ARRAY[fs_number_from_integer(1000)])", 1),
+            (
+                "tags #?& fs_array(ARRAY[fs_number_from_integer(1000), fs_number_from_double(1000.0)])",
+                1,
+            ),
+            (
+                "tags #?| fs_array(ARRAY[fs_nan(), fs_number_from_integer(53)])",
+                expected(|i| i % 100 == 53),
+            ),
+            (
+                "tags #?| fs_array(ARRAY[fs_number_from_integer(53), fs_number_from_integer(1)])",
+                expected(|i| i % 100 == 53 || i % 100 == 1 || i % 7 == 1),
+            ),
+            (
+                "tags #?& fs_array(ARRAY[fs_number_from_integer(53), fs_number_from_integer(4)])",
+                expected(|i| i % 100 == 53 && i % 7 == 4),
+            ),
+            ("tags #?| fs_array(ARRAY[]::fsvalue[])", 0),
+            ("tags #?& fs_array(ARRAY[]::fsvalue[])", 10002),
+        ];
+        for (condition, expected) in cases {
+            let query = format!("SELECT count(*) FROM gin_arrays WHERE {}", condition);
+
+            Spi::run("SET LOCAL enable_seqscan = off").expect("SPI failed");
+            let plan = explain(&query, true);
+            let indexed = count(&query);
+
+            Spi::run(
+                "SET LOCAL enable_seqscan = on; \
+                 SET LOCAL enable_bitmapscan = off; \
+                 SET LOCAL enable_indexscan = off",
+            )
+            .expect("SPI failed");
+            let sequential = count(&query);
+            Spi::run("RESET enable_bitmapscan; RESET enable_indexscan").expect("SPI failed");
+
+            // Without keys there is nothing to look up in the index
+            if !condition.contains("ARRAY[]") && condition != "tags #@> fs_nan()" {
+                assert!(
+                    plan.contains("Bitmap Index Scan on gin_arrays_tags"),
+                    "{}",
+                    plan
+                );
+            }
+            assert_eq!(indexed, expected, "{}", condition);
+            assert_eq!(sequential, expected, "{}", condition);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::fs_timestamp::parse_timestamp;
+use pgrx::{pg_sys, GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputMode {
+    // Only the typed JSON format is accepted by the fsvalue input function
+    Strict,
+    // A bare path starting with '/' is additionally accepted as a REFERENCE
+    Auto,
+}
+
+#[derive(PostgresGucEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputStyle {
+    // The typed JSON format, as accepted by the input function in any mode
+    Canonical,
+    // The typed JSON format with bytes rendered as hex for reading in psql
+    Readable,
+}
+
+pub static INPUT_MODE: GucSetting<InputMode> = GucSetting::new(InputMode::Strict);
+
+pub static OUTPUT_STYLE: GucSetting<OutputStyle> = GucSetting::new(OutputStyle::Canonical);
+
+pub static STRICT_LIMITS: GucSetting<bool> = GucSetting::new(false);
+
+// Firestore's limits on document names: 100 levels of collections and 6 KiB
+pub static MAX_REFERENCE_DEPTH: GucSetting<i32> = GucSetting::new(100);
+
+pub static MAX_REFERENCE_BYTES: GucSetting<i32> = GucSetting::new(6 * 1024);
+
+pub static SOFT_DELETE: GucSetting<bool> = GucSetting::new(false);
+
+pub static CONTENT_HASHES: GucSetting<bool> = GucSetting::new(true);
+
+pub static REFERENCE_CACHE_SIZE: GucSetting<i32> = GucSetting::new(1024);
+
+// Named slots of the activity counters. Shared memory is sized for
+// fs_activity::MAX_TRACKED_COLLECTIONS of them whatever this says.
+pub static ACTIVITY_MAX_COLLECTIONS: GucSetting<i32> = GucSetting::new(100);
+
+// Owned by Postgres, which points it at the current value of
+// pgfirestore.fixed_request_time. GucRegistry has no check hooks, so the
+// setting is defined through pg_sys directly.
+static mut FIXED_REQUEST_TIME: *mut c_char = std::ptr::null_mut();
+
+fn parse_request_time(text: &CStr) -> Result<Option<i64>, String> {
+    let text = text
+        .to_str()
+        .map_err(|_| "The value is not valid UTF-8".to_owned())?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    parse_timestamp(text)
+        .map(Some)
+        .map_err(|error| error.to_string())
+}
+
+// Postgres keeps pointers to the name, descriptions and boot value of a
+// setting for the life of the backend
+fn backend_lifetime_cstr(text: &str) -> *const c_char {
+    CString::new(text)
+        .expect("setting text must not contain NUL")
+        .into_raw()
+}
+
+// Rejects an unparsable time when it is SET rather than at first use
+unsafe extern "C" fn check_fixed_request_time(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource,
+) -> bool {
+    if (*newval).is_null() {
+        return true;
+    }
+    match parse_request_time(CStr::from_ptr(*newval)) {
+        Ok(_) => true,
+        Err(error) => {
+            let detail = CString::new(error).unwrap_or_default();
+            pg_sys::GUC_check_errdetail_string = pg_sys::pstrdup(detail.as_ptr());
+            false
+        }
+    }
+}
+
+// Microseconds since the Unix epoch that pgfirestore.fixed_request_time
+// pins the request time to, if set
+pub fn fixed_request_time() -> Option<i64> {
+    let value = unsafe { FIXED_REQUEST_TIME };
+    if value.is_null() {
+        return None;
+    }
+    parse_request_time(unsafe { CStr::from_ptr(value) })
+        .expect("the check hook validates pgfirestore.fixed_request_time")
+}
+
+pub fn init() {
+    GucRegistry::define_enum_guc(
+        "pgfirestore.input_mode",
+        "Textual forms accepted by the fsvalue input function.",
+        "'strict' only accepts the typed JSON format. 'auto' also accepts a bare path such as '/users/1' as a REFERENCE value.",
+        &INPUT_MODE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        "pgfirestore.output_style",
+        "Textual form produced by the fsvalue output function.",
+        "'canonical' produces the typed JSON format. 'readable' renders bytes as 0x-prefixed hex, truncated after 64 bytes.",
+        &OUTPUT_STYLE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pgfirestore.strict_limits",
+        "Enforce Firestore's structural limits on fsvalue construction.",
+        "When on, values that the Firestore service would reject (e.g. arrays directly containing arrays) are rejected instead of being stored.",
+        &STRICT_LIMITS,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pgfirestore.max_reference_depth",
+        "Maximum number of collection levels in a reference.",
+        "References nested deeper are rejected when parsed or built. Defaults to Firestore's limit of 100.",
+        &MAX_REFERENCE_DEPTH,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pgfirestore.max_reference_bytes",
+        "Maximum size in bytes of a reference path.",
+        "Longer paths are rejected when parsed or built. Defaults to Firestore's limit of 6 KiB.",
+        &MAX_REFERENCE_BYTES,
+        1,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pgfirestore.soft_delete",
+        "Whether fs_delete leaves a tombstone instead of removing the row.",
+        "When on, deleted documents keep their row with deleted_at set, so that sync consumers can learn about the delete. Reads skip them unless asked to include deleted documents, and fs_purge_tombstones removes old ones.",
+        &SOFT_DELETE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_bool_guc(
+        "pgfirestore.content_hashes",
+        "Whether writes maintain the content_hash column of fs_documents.",
+        "When off, e.g. for bulk loads, written rows get a NULL content_hash, which fs_rebuild_content_hashes fills in afterward. ETags of such rows are computed from their properties.",
+        &CONTENT_HASHES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pgfirestore.reference_cache_size",
+        "Number of parents and collection IDs each call site remembers.",
+        "fs_parent and fs_collection_id keep a per-statement cache of this many distinct parents and collection IDs, evicting the least recently used. 0 disables the cache.",
+        &REFERENCE_CACHE_SIZE,
+        0,
+        1 << 20,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        "pgfirestore.activity_max_collections",
+        "Maximum number of root collections with activity counters of their own.",
+        "Reads and writes of further root collections are counted in a single '__other__' row of fs_activity_stats(). Requires a restart, at most 1024.",
+        &ACTIVITY_MAX_COLLECTIONS,
+        0,
+        crate::fs_activity::MAX_TRACKED_COLLECTIONS as i32,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    unsafe {
+        pg_sys::DefineCustomStringVariable(
+            backend_lifetime_cstr("pgfirestore.fixed_request_time"),
+            backend_lifetime_cstr("Pins the request time, e.g. for deterministic tests."),
+            backend_lifetime_cstr("A timestamp such as '2024-01-01T00:00:00Z' used for fs_timestamp_now() and update_time instead of the transaction start time. Empty uses the transaction start time."),
+            std::ptr::addr_of_mut!(FIXED_REQUEST_TIME),
+            backend_lifetime_cstr(""),
+            pg_sys::GucContext_PGC_USERSET,
+            0,
+            Some(check_fixed_request_time),
+            None,
+            None,
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{FsNumber, FsValue};
+use pgrx::prelude::*;
+use sha2::{Digest, Sha256};
+
+// The hash operator class of fsvalue, which GROUP BY, DISTINCT, hash joins
+// and hash indexes use. Hashes follow `=`, which compares by value like the
+// btree order: a double holding an integer is hashed in its integer form, so
+// `1` and `1.0` group together, as do `-0.0`, `0.0` and `0`.
+//
+// Hash indexes keep hashes on disk, so they come from sha256 over a fixed
+// encoding of the value rather than from Rust's Hash, whose output may change
+// between releases. The encoding is a one-byte type tag and the payload, with
+// big-endian numbers and lengths in front of strings and collections.
+
+fn feed_length(length: usize, digest: &mut Sha256) {
+    digest.update((length as u64).to_be_bytes());
+}
+
+fn feed_double(double: f64, digest: &mut Sha256) {
+    // -0.0 == 0.0
+    let double = if double == 0.0 { 0.0 } else { double };
+    digest.update(double.to_bits().to_be_bytes());
+}
+
+fn feed_number(number: &FsNumber, digest: &mut Sha256) {
+    let twin = number.integer_twin();
+    match twin.as_ref().unwrap_or(number) {
+        FsNumber::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                digest.update([b'i']);
+                digest.update(integer.to_be_bytes());
+            } else if let Some(integer) = number.as_u64() {
+                digest.update([b'u']);
+                digest.update(integer.to_be_bytes());
+            } else {
+                digest.update([b'd']);
+                feed_double(number.as_f64().unwrap_or_default(), digest);
+            }
+        }
+        FsNumber::NAN => digest.update([b'n']),
+        FsNumber::NegativeInfinity => digest.update([b'-']),
+        FsNumber::PositiveInfinity => digest.update([b'+']),
+    }
+}
+
+fn feed_value(value: &FsValue, digest: &mut Sha256) {
+    match value {
+        FsValue::NULL => digest.update([0]),
+        FsValue::Boolean(boolean) => digest.update([1, *boolean as u8]),
+        FsValue::Number(number) => {
+            digest.update([2]);
+            feed_number(number, digest);
+        }
+        FsValue::Date(date) => {
+            digest.update([3]);
+            digest.update(date.to_pg_epoch_days().to_be_bytes());
+        }
+        FsValue::Timestamp(micros) => {
+            digest.update([4]);
+            digest.update(micros.to_be_bytes());
+        }
+        FsValue::String(string) => {
+            digest.update([5]);
+            feed_length(string.len(), digest);
+            digest.update(string.as_bytes());
+        }
+        FsValue::Bytes(bytes) => {
+            digest.update([6]);
+            feed_length(bytes.len(), digest);
+            digest.update(bytes);
+        }
+        FsValue::Reference(reference) => {
+            let text = reference.to_string();
+            digest.update([7]);
+            feed_length(text.len(), digest);
+            digest.update(text.as_bytes());
+        }
+        FsValue::GeoPoint(latitude, longitude) => {
+            digest.update([8]);
+            feed_number(latitude, digest);
+            feed_number(longitude, digest);
+        }
+        FsValue::Array(elements) => {
+            digest.update([9]);
+            feed_length(elements.len(), digest);
+            for element in elements {
+                feed_value(element, digest);
+            }
+        }
+        FsValue::Map(entries) => {
+            digest.update([10]);
+            feed_length(entries.len(), digest);
+            for (key, value) in entries {
+                feed_length(key.len(), digest);
+                digest.update(key.as_bytes());
+                feed_value(value, digest);
+            }
+        }
+    }
+}
+
+pub(crate) fn hash_value(value: &FsValue, seed: i64) -> i64 {
+    let mut digest = Sha256::new();
+    digest.update(seed.to_be_bytes());
+    feed_value(value, &mut digest);
+    let mut hash = [0u8; 8];
+    hash.copy_from_slice(&digest.finalize()[..8]);
+    i64::from_be_bytes(hash)
+}
+
+// Postgres expects the standard hash to be the low 32 bits of the extended
+// one with seed 0
+#[pg_extern(immutable, parallel_safe)]
+fn fs_hash(value: FsValue) -> i32 {
+    hash_value(&value, 0) as i32
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_hash_extended(value: FsValue, seed: i64) -> i64 {
+    hash_value(&value, seed)
+}
+
+extension_sql!(
+    "\n\
+        CREATE OPERATOR CLASS fs_value_hash_ops \n\
+        DEFAULT FOR TYPE fsvalue USING hash AS \n\
+            OPERATOR 1 = (fsvalue, fsvalue), \n\
+            FUNCTION 1 fs_hash(fsvalue), \n\
+            FUNCTION 2 fs_hash_extended(fsvalue, int8); \n\
+    ",
+    name = "value_hash_opclass",
+    requires = [FsValue, fsvalue_eq, fs_hash, fs_hash_extended],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_hash::*;
+    use crate::fs_number::number_from_double;
+    use crate::fs_test_util::{explain, map};
+    use std::str::FromStr;
+
+    fn double(double: f64) -> FsValue {
+        FsValue::Number(number_from_double(double))
+    }
+
+    #[test]
+    fn test_hash_follows_eq() {
+        let values = vec![
+            FsValue::NULL,
+            FsValue::Boolean(false),
+            FsValue::Boolean(true),
+            crate::fs_number_from_integer(0),
+            crate::fs_number_from_integer(1),
+            double(0.5),
+            FsValue::Number(FsNumber::NAN),
+            FsValue::Number(FsNumber::PositiveInfinity),
+            FsValue::Number(FsNumber::NegativeInfinity),
+            FsValue::Timestamp(0),
+            crate::fs_string(""),
+            crate::fs_string("a"),
+            crate::fs_bytes(vec![]),
+            crate::fs_bytes(vec![0]),
+            crate::fs_bytes(vec![0, 0]),
+            crate::fs_reference("/users/1"),
+            crate::fs_reference("/users/%31"),
+            FsValue::Array(vec![]),
+            FsValue::Array(vec![FsValue::NULL]),
+            map(vec![]),
+            map(vec![("a", crate::fs_string("b"))]),
+            map(vec![("ab", crate::fs_string(""))]),
+            map(vec![("a", map(vec![("b", FsValue::NULL)]))]),
+        ];
+        for (index, lhs) in values.iter().enumerate() {
+            for rhs in &values[index + 1..] {
+                assert!(lhs.cmp(rhs).is_ne());
+                assert_ne!(
+                    hash_value(lhs, 0),
+                    hash_value(rhs, 0),
+                    "{:?} {:?}",
+                    lhs,
+                    rhs
+                );
+            }
+        }
+        assert_eq!(double(-0.0), double(0.0));
+        assert_eq!(hash_value(&double(-0.0), 0), hash_value(&double(0.0), 0));
+        assert_eq!(
+            hash_value(&double(1.0), 0),
+            hash_value(&crate::fs_number_from_integer(1), 0)
+        );
+        assert_ne!(hash_value(&values[0], 0), hash_value(&values[0], 1));
+    }
+
+    #[test]
+    fn test_eq_cmp_and_hash_agree() {
+        let integer = crate::fs_number_from_integer;
+        let values = vec![
+            integer(0),
+            double(0.0),
+            double(-0.0),
+            integer(1),
+            double(1.0),
+            FsValue::Number(FsNumber::NAN),
+            FsValue::GeoPoint(number_from_double(1.0), FsNumber::from_str("2").unwrap()),
+            FsValue::GeoPoint(FsNumber::from_str("1").unwrap(), number_from_double(2.0)),
+            FsValue::Array(vec![integer(1), double(2.0)]),
+            FsValue::Array(vec![double(1.0), integer(2)]),
+            FsValue::Array(vec![double(1.0), double(2.0)]),
+            map(vec![("a", integer(1)), ("b", double(1.0))]),
+            map(vec![("a", double(1.0)), ("b", integer(1))]),
+        ];
+        for lhs in &values {
+            for rhs in &values {
+                assert_eq!(lhs.cmp(rhs), rhs.cmp(lhs).reverse(), "{:?} {:?}", lhs, rhs);
+                if lhs.cmp(rhs).is_eq() {
+                    assert_eq!(
+                        hash_value(lhs, 0),
+                        hash_value(rhs, 0),
+                        "{:?} {:?}",
+                        lhs,
+                        rhs
+                    );
+                }
+            }
+        }
+        // Equal by value, whichever of them are integers
+        assert!(values[0].cmp(&values[2]).is_eq());
+        assert!(values[3].cmp(&values[4]).is_eq());
+        assert!(values[6].cmp(&values[7]).is_eq());
+        assert!(values[8].cmp(&values[10]).is_eq());
+        assert!(values[11].cmp(&values[12]).is_eq());
+    }
+
+    #[pg_test]
+    fn test_group_by_joins_integers_and_doubles() {
+        Spi::run(
+            "CREATE TEMP TABLE numbers AS \
+             SELECT CASE WHEN i % 2 = 0 THEN fs_number_from_integer(1) \
+                 ELSE fs_number_from_double(1.0) END AS n \
+             FROM generate_series(1, 10) i",
+        )
+        .expect("SPI failed");
+        // Sorting and hashing group alike
+        for setting in ["enable_hashagg = off", "enable_sort = off"] {
+            Spi::run(&format!("SET LOCAL {}", setting)).expect("SPI failed");
+            assert_eq!(
+                Spi::get_one::<i64>("SELECT count(*) FROM (SELECT n FROM numbers GROUP BY n) g"),
+                Ok(Some(1))
+            );
+            Spi::run(&format!("RESET {}", setting.split(' ').next().unwrap())).expect("SPI failed");
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_hash() {
+        // Keys are ordered, however the map was built
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_hash(fs_doc('a', 1, 'b', fs_doc('c', fs_bytes('\\x00ff'::bytea)))) = \
+                     fs_hash(fs_doc('b', fs_doc('c', fs_bytes('\\x00ff'::bytea)), 'a', 1)) \
+                 AND fs_hash(fs_reference('/users/1')) = fs_hash(fs_reference('/users/1')) \
+                 AND fs_hash(fs_reference('/users/1')) <> fs_hash(fs_reference('/users/2')) \
+                 AND fs_hash(fs_bytes('\\x00'::bytea)) <> fs_hash(fs_bytes('\\x0000'::bytea)) \
+                 AND fs_hash(fs_nan()) = fs_hash(fs_nan()) \
+                 AND fs_hash_extended(fs_null(), 0) & 4294967295 = fs_hash(fs_null())::bigint & 4294967295 \
+                 AND fs_hash_extended(fs_null(), 1) <> fs_hash_extended(fs_null(), 0)"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_group_by_and_hash_join() {
+        Spi::run(
+            "SET LOCAL enable_sort = off; \
+             CREATE TEMP TABLE countries AS \
+             SELECT fs_doc('country', fs_string(country)) AS properties \
+             FROM unnest(ARRAY['fr', 'de', 'fr', 'jp', 'fr', 'de']) AS country",
+        )
+        .expect("SPI failed");
+        let group_by = "SELECT properties->'country', count(*) FROM countries GROUP BY 1";
+        let plan = explain(group_by, false);
+        assert!(plan.contains("HashAggregate"), "{}", plan);
+        assert_eq!(
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(count::text, ',' ORDER BY count) FROM ({}) AS g",
+                group_by
+            )),
+            Ok(Some("1,2,3".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(DISTINCT properties) FROM countries"),
+            Ok(Some(3))
+        );
+
+        Spi::run("SET LOCAL enable_mergejoin = off; SET LOCAL enable_nestloop = off")
+            .expect("SPI failed");
+        let join = "SELECT count(*) FROM countries c JOIN fs_documents d \
+                    ON d.properties->'country' = c.properties->'country'";
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             VALUES (fs_reference('/cities/paris'), fs_doc('country', 'fr'))",
+        )
+        .expect("SPI failed");
+        let plan = explain(join, false);
+        assert!(plan.contains("Hash Join"), "{}", plan);
+        assert_eq!(Spi::get_one::<i64>(join), Ok(Some(3)));
+    }
+}
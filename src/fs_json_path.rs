@@ -0,0 +1,268 @@
+use crate::{check_json_depth, FsValue};
+use pgrx::prelude::*;
+use pgrx::JsonB;
+
+// The plain JSON projection of a value, with the type tags dropped
+#[pg_extern(immutable, parallel_safe)]
+fn fs_to_plain_json(fs_value: FsValue) -> JsonB {
+    JsonB(fs_value.to_plain_json())
+}
+
+// Types JSON by its shape, e.g. strings always become STRING values. Stable
+// rather than immutable, as nested arrays depend on pgfirestore.strict_limits.
+#[pg_extern(stable, parallel_safe)]
+fn fs_from_plain_json(json: JsonB) -> FsValue {
+    if let Err(error) = check_json_depth(&json.0) {
+        error.report()
+    }
+    FsValue::from_plain_json(&json.0)
+}
+
+// Types a jsonb document by its shape, the function of the jsonb to fsvalue
+// cast. Stable like fs_from_plain_json.
+#[pg_extern(stable, parallel_safe)]
+fn fs_from_jsonb(doc: JsonB) -> FsValue {
+    fs_from_plain_json(doc)
+}
+
+// The plain JSON projection as jsonb, the function of the fsvalue to jsonb
+// cast
+#[pg_extern(immutable, parallel_safe)]
+fn fs_to_jsonb(value: FsValue) -> JsonB {
+    fs_to_plain_json(value)
+}
+
+// Explicit casts only, so that jsonb operators are never picked for fsvalue
+// operands or the other way around
+extension_sql!(
+    "\n\
+        CREATE CAST (jsonb AS fsvalue) WITH FUNCTION fs_from_jsonb(jsonb); \n\
+        CREATE CAST (fsvalue AS jsonb) WITH FUNCTION fs_to_jsonb(fsvalue); \n\
+    ",
+    name = "jsonb_casts",
+    requires = [fs_to_jsonb, fs_from_jsonb],
+);
+
+// jsonpath is evaluated by the server's own jsonb_path_query over the plain
+// JSON projection, and matches are typed back by their shape
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_json_path(fs_value fsvalue, path jsonpath) \n\
+        RETURNS SETOF fsvalue AS $$ \n\
+            SELECT fs_from_plain_json(matched) \n\
+            FROM jsonb_path_query(fs_to_plain_json(fs_value), path) AS matched \n\
+        $$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_json_path_exists(fs_value fsvalue, path jsonpath) \n\
+        RETURNS boolean AS $$ \n\
+            SELECT jsonb_path_exists(fs_to_plain_json(fs_value), path) \n\
+        $$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE; \n\
+    ",
+    name = "json_path",
+    requires = [fs_to_plain_json, fs_from_plain_json],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_json_path::*;
+    use crate::fs_test_util::plain;
+    use serde_json::json;
+
+    fn matches(document: serde_json::Value, path: &str) -> Vec<FsValue> {
+        Spi::connect(|client| {
+            let mut matches = Vec::new();
+            for row in client.select(
+                "SELECT fs_json_path(fs_from_plain_json($1), $2::jsonpath)",
+                None,
+                Some(vec![
+                    (PgBuiltInOids::JSONBOID.oid(), JsonB(document).into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), path.into_datum()),
+                ]),
+            )? {
+                matches.push(row.get::<FsValue>(1)?.expect("match must not be null"));
+            }
+            Ok::<_, pgrx::spi::Error>(matches)
+        })
+        .expect("SPI failed")
+    }
+
+    #[test]
+    fn test_to_plain_json() {
+        let document = FsValue::Map(
+            [
+                ("at".to_owned(), FsValue::Timestamp(0)),
+                ("blob".to_owned(), FsValue::Bytes(b"hi".to_vec())),
+                ("link".to_owned(), crate::fs_reference("/users/1")),
+                ("tags".to_owned(), plain(json!(["a", 1, null, true]))),
+            ]
+            .into(),
+        );
+        assert_eq!(
+            document.to_plain_json(),
+            json!({
+                "at": "1970-01-01T00:00:00Z",
+                "blob": "aGk=",
+                "link": "/users/1",
+                "tags": ["a", 1, null, true]
+            })
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Array element at index 1 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_jsonb_cast_nesting_strict() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = off").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT '{\"a\": [[1]]}'::jsonb::fsvalue"),
+            Ok(Some(plain(json!({"a": [[1]]}))))
+        );
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        Spi::get_one::<FsValue>("SELECT '{\"a\": [1, [2]]}'::jsonb::fsvalue").expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_json_path_wildcard() {
+        assert_eq!(
+            matches(json!({"a": {"x": 1, "y": {"z": [2, 3]}}, "b": 4}), "$.a.*"),
+            vec![plain(json!(1)), plain(json!({"z": [2, 3]}))]
+        );
+        assert_eq!(
+            matches(json!({"a": {"x": 1, "y": {"z": [2, 3]}}}), "$.**.z[*]"),
+            vec![plain(json!(2)), plain(json!(3))]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_json_path_filter() {
+        let document = json!({
+            "items": [
+                {"name": "pen", "price": 5},
+                {"name": "book", "price": 15},
+                {"name": "lamp", "price": 40}
+            ]
+        });
+        assert_eq!(
+            matches(document, "$.items[*] ? (@.price > 10).name"),
+            vec![plain(json!("book")), plain(json!("lamp"))]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_json_path_lossy_types() {
+        // A matched timestamp comes back as a STRING
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "SELECT fs_json_path(fs_map_from_entries(ARRAY['at'], ARRAY[fs_timestamp('2024-01-01T00:00:00Z')]), '$.at')"
+            )
+            .expect("SPI failed"),
+            Some(FsValue::String("2024-01-01T00:00:00Z".to_owned()))
+        );
+    }
+
+    #[pg_test]
+    fn test_jsonb_casts() {
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "SELECT '{\"a\": [1, 2.5, \"b\", null, true]}'::jsonb::fsvalue"
+            )
+            .expect("SPI failed"),
+            Some(plain(json!({"a": [1, 2.5, "b", null, true]})))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_bytes('\\x6869'::bytea)::jsonb = '\"aGk=\"' \
+                 AND fs_reference('/users/1')::jsonb = '\"/users/1\"' \
+                 AND fs_nan()::jsonb = '\"NaN\"' \
+                 AND fs_geopoint(1, 2)::jsonb = '[1, 2]'"
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_from_jsonb_and_fs_to_jsonb() {
+        assert_eq!(
+            fs_from_jsonb(JsonB(json!({"a": [1, 2.5, "b", null, true], "m": {}}))),
+            plain(json!({"a": [1, 2.5, "b", null, true], "m": {}}))
+        );
+        assert_eq!(fs_to_jsonb(crate::fs_nan()).0, json!("NaN"));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_to_jsonb(fs_from_jsonb('{\"a\": {\"b\": [1]}}')) \
+                     = '{\"a\": {\"b\": [1]}}'::jsonb \
+                 AND fs_to_jsonb(fs_geopoint(1.5, -2)) = '[1.5, -2]'"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_jsonb_casts_nesting_and_large_numbers() {
+        // The deepest an fsvalue datum decodes: serde_cbor stops at 128
+        // levels, and each array takes two, its variant and its elements
+        let nested = format!("{}1{}", "[".repeat(62), "]".repeat(62));
+        assert_eq!(
+            Spi::get_one::<bool>(&format!(
+                "SELECT '{}'::jsonb::fsvalue::jsonb = '{}'::jsonb",
+                nested, nested
+            ))
+            .expect("SPI failed"),
+            Some(true)
+        );
+        // Integers beyond 64 bits come back as the nearest double
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT '[-9223372036854775808, 18446744073709551615, \
+                     123456789012345678901234567890]'::jsonb::fsvalue::jsonb::text"
+            )
+            .expect("SPI failed"),
+            Some(
+                "[-9223372036854775808, 18446744073709551615, 123456789012345680000000000000]"
+                    .to_owned()
+            )
+        );
+    }
+
+    // pgrx decodes the jsonb with serde_json, which rejects it before the
+    // cast gets to check its depth
+    #[pg_test(
+        error = "failed to parse JsonB value: Error(\"recursion limit exceeded\", line: 1, column: 128)"
+    )]
+    fn test_jsonb_cast_too_deep() {
+        let nested = format!("{}{}", "[".repeat(128), "]".repeat(128));
+        Spi::get_one::<FsValue>(&format!("SELECT '{}'::jsonb::fsvalue", nested))
+            .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_json_path_exists() {
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents \
+                 WHERE fs_json_path_exists(properties, '$ ? (@.foo > 2)')"
+            )
+            .expect("SPI failed"),
+            Some(3)
+        );
+    }
+
+    #[pg_test(error = "JSON object does not contain key \"missing\"")]
+    fn test_fs_json_path_strict_error() {
+        matches(json!({"a": 1}), "strict $.missing");
+    }
+
+    #[pg_test]
+    fn test_fs_json_path_syntax_error() {
+        // The jsonpath parser rejects the path before anything is evaluated
+        Spi::run(
+            "DO $$ BEGIN \
+                 PERFORM fs_json_path(fs_null(), '$.a['); \
+                 RAISE EXCEPTION 'invalid jsonpath was accepted'; \
+             EXCEPTION WHEN syntax_error THEN NULL; \
+             END $$",
+        )
+        .expect("SPI failed");
+    }
+}
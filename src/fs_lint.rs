@@ -0,0 +1,308 @@
+use crate::fs_field_path::quote_field_name;
+use crate::FsValue;
+use pgrx::prelude::*;
+use std::collections::BTreeMap;
+
+// Firestore limits on field names and documents
+pub(crate) const MAX_FIELD_NAME_BYTES: usize = 1500;
+const MAX_STRING_BYTES: usize = 1024 * 1024;
+// Advisory thresholds
+const MAX_ARRAY_ELEMENTS: usize = 20000;
+const MIN_FLATTENED_ARRAY_KEYS: usize = 3;
+const MIN_SINGLE_FIELD_CHAIN: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Finding {
+    severity: &'static str,
+    path: String,
+    message: String,
+}
+
+impl Finding {
+    fn error(path: &str, message: String) -> Finding {
+        Finding {
+            severity: "error",
+            path: path.to_owned(),
+            message,
+        }
+    }
+
+    fn warning(path: &str, message: String) -> Finding {
+        Finding {
+            severity: "warning",
+            path: path.to_owned(),
+            message,
+        }
+    }
+}
+
+pub(crate) fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        quote_field_name(key)
+    } else {
+        format!("{}.{}", path, quote_field_name(key))
+    }
+}
+
+// Keys like `0, 1, 2` or `item1, item2, item3`: a common prefix followed by
+// consecutive integers starting at 0 or 1.
+fn looks_like_flattened_array(map: &BTreeMap<String, FsValue>) -> bool {
+    if map.len() < MIN_FLATTENED_ARRAY_KEYS {
+        return false;
+    }
+    let mut prefix: Option<&str> = None;
+    let mut indexes = Vec::with_capacity(map.len());
+    for key in map.keys() {
+        let digits_start = key.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let (key_prefix, digits) = key.split_at(digits_start);
+        let index = match digits.parse::<u64>() {
+            Ok(index) if !digits.starts_with('0') || digits == "0" => index,
+            _ => return false,
+        };
+        match prefix {
+            Some(prefix) if prefix != key_prefix => return false,
+            _ => prefix = Some(key_prefix),
+        }
+        indexes.push(index);
+    }
+    indexes.sort_unstable();
+    let first = indexes[0];
+    (first == 0 || first == 1)
+        && indexes
+            .iter()
+            .enumerate()
+            .all(|(offset, index)| *index == first + offset as u64)
+}
+
+// Number of nested maps starting at `value` that each hold a single field
+fn single_field_chain_length(value: &FsValue) -> usize {
+    match value {
+        FsValue::Map(map) if map.len() == 1 => {
+            1 + single_field_chain_length(map.values().next().expect("map has one field"))
+        }
+        _ => 0,
+    }
+}
+
+fn lint_value(value: &FsValue, path: &str, in_chain: bool, findings: &mut Vec<Finding>) {
+    match value {
+        FsValue::String(string) if string.len() > MAX_STRING_BYTES => {
+            findings.push(Finding::error(
+                path,
+                format!(
+                    "String of {} bytes exceeds the {} bytes document size limit on its own",
+                    string.len(),
+                    MAX_STRING_BYTES
+                ),
+            ));
+        }
+        FsValue::Array(array) => {
+            if array.len() > MAX_ARRAY_ELEMENTS {
+                findings.push(Finding::warning(
+                    path,
+                    format!(
+                        "Array has {} elements, more than {}",
+                        array.len(),
+                        MAX_ARRAY_ELEMENTS
+                    ),
+                ));
+            }
+            for (index, element) in array.iter().enumerate() {
+                lint_value(element, &format!("{}[{}]", path, index), false, findings);
+            }
+        }
+        FsValue::Map(map) => {
+            let chain_length = single_field_chain_length(value);
+            if !in_chain && chain_length >= MIN_SINGLE_FIELD_CHAIN {
+                findings.push(Finding::warning(
+                    path,
+                    format!(
+                        "Chain of {} nested maps holding a single field each",
+                        chain_length
+                    ),
+                ));
+            }
+            if looks_like_flattened_array(map) {
+                findings.push(Finding::warning(
+                    path,
+                    format!(
+                        "Keys {} look like an array flattened into a map",
+                        map.keys().cloned().collect::<Vec<String>>().join(", ")
+                    ),
+                ));
+            }
+            for (key, child) in map.iter() {
+                let child_path = child_path(path, key);
+                if key.trim() != key {
+                    findings.push(Finding::warning(
+                        &child_path,
+                        "Field name starts or ends with whitespace".to_owned(),
+                    ));
+                }
+                if key.len() > MAX_FIELD_NAME_BYTES {
+                    findings.push(Finding::error(
+                        &child_path,
+                        format!(
+                            "Field name of {} bytes exceeds the {} bytes limit",
+                            key.len(),
+                            MAX_FIELD_NAME_BYTES
+                        ),
+                    ));
+                }
+                // The root map is not reported, so a chain may start below it
+                let continues_chain = !path.is_empty() && chain_length > 1;
+                lint_value(child, &child_path, continues_chain, findings);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn lint_document(properties: &FsValue) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    match properties {
+        FsValue::Map(_) => lint_value(properties, "", true, &mut findings),
+        _ => findings.push(Finding::warning(
+            "",
+            format!(
+                "Document properties must be a MAP but found {}",
+                properties.type_name()
+            ),
+        )),
+    }
+    findings
+}
+
+// Advisory checks mirroring Firestore best practices. Findings are reported
+// as rows and never raised.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_lint_document(
+    properties: FsValue,
+) -> TableIterator<
+    'static,
+    (
+        name!(severity, String),
+        name!(path, String),
+        name!(message, String),
+    ),
+> {
+    let rows: Vec<(String, String, String)> = lint_document(&properties)
+        .into_iter()
+        .map(|finding| (finding.severity.to_owned(), finding.path, finding.message))
+        .collect();
+    TableIterator::new(rows.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_lint::*;
+    use crate::fs_test_util::map;
+    use crate::{fs_array, fs_boolean, fs_number_from_integer, fs_string};
+
+    fn findings(properties: FsValue) -> Vec<(&'static str, String)> {
+        lint_document(&properties)
+            .into_iter()
+            .map(|finding| (finding.severity, finding.path))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_document_rules() {
+        let long_key = "k".repeat(1501);
+        let document = map(vec![
+            (" padded", fs_boolean(true)),
+            (&long_key, fs_boolean(true)),
+            ("big", fs_string(&"x".repeat(1024 * 1024 + 1))),
+            ("tags", fs_array(vec![fs_number_from_integer(0); 20001])),
+            (
+                "items",
+                map(vec![
+                    ("item1", fs_boolean(true)),
+                    ("item2", fs_boolean(true)),
+                    ("item3", fs_boolean(true)),
+                ]),
+            ),
+            (
+                "a",
+                map(vec![(
+                    "b",
+                    map(vec![(
+                        "c",
+                        map(vec![("d", map(vec![("e", fs_boolean(true))]))]),
+                    )]),
+                )]),
+            ),
+        ]);
+        assert_eq!(
+            findings(document),
+            vec![
+                ("warning", "` padded`".to_owned()),
+                ("warning", "a".to_owned()),
+                ("error", "big".to_owned()),
+                ("warning", "items".to_owned()),
+                ("error", long_key.to_owned()),
+                ("warning", "tags".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_document_rules_nested() {
+        assert_eq!(
+            findings(map(vec![(
+                "list",
+                fs_array(vec![map(vec![
+                    ("0", fs_boolean(true)),
+                    ("1", fs_boolean(true)),
+                    ("2", fs_boolean(true))
+                ])])
+            )])),
+            vec![("warning", "list[0]".to_owned())]
+        );
+        // Not consecutive, a zero-padded key, or only three nested maps
+        assert_eq!(
+            findings(map(vec![
+                ("x1", fs_boolean(true)),
+                ("x2", fs_boolean(true)),
+                ("x4", fs_boolean(true)),
+                (
+                    "y",
+                    map(vec![
+                        ("01", fs_boolean(true)),
+                        ("2", fs_boolean(true)),
+                        ("3", fs_boolean(true))
+                    ])
+                ),
+                ("z", map(vec![("a", map(vec![("b", fs_boolean(true))]))])),
+            ])),
+            vec![]
+        );
+        assert_eq!(
+            findings(fs_string("not a map")),
+            vec![("warning", "".to_owned())]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_lint_document() {
+        let clean = map(vec![
+            ("active", fs_boolean(true)),
+            ("name", fs_string("ann")),
+            ("tags", fs_array(vec![fs_string("a"), fs_string("b")])),
+            (
+                "address",
+                map(vec![("city", fs_string("x")), ("zip", fs_string("9"))]),
+            ),
+        ]);
+        assert_eq!(fs_lint_document(clean).count(), 0);
+        assert_eq!(
+            fs_lint_document(fs_number_from_integer(1)).collect::<Vec<_>>(),
+            vec![(
+                "warning".to_owned(),
+                "".to_owned(),
+                "Document properties must be a MAP but found NUMBER".to_owned()
+            )]
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::FsError;
 use bigdecimal::BigDecimal;
 use serde::{Deserialize, Serialize};
-use std::ops::Add;
+use std::ops::{Add, Mul, Sub};
 use std::{cmp::Ordering, str::FromStr};
 
 type Result<T> = std::result::Result<T, FsError>;
@@ -20,38 +20,217 @@ impl From<serde_json::Number> for FsNumber {
     }
 }
 
-fn number_to_bigdecimal(val: &serde_json::Number) -> BigDecimal {
-    // TODO(louiskuang): parsing error should be thrown at FsNumber construction time.
-    BigDecimal::from_str(val.to_string().as_str()).unwrap()
+#[cfg(test)]
+thread_local! {
+    // Calls of number_to_bigdecimal, which tests count to check that the
+    // fast paths of comparison and addition stay clear of it
+    static BIGDECIMAL_CONVERSIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
-fn number_from_bigdecimal(val: &BigDecimal) -> FsNumber {
-    // TODO(louiskuang): parsing error should be thrown at FsNumber construction time.
-    FsNumber::Number(serde_json::Number::from_str(val.to_string().as_str()).unwrap())
+// Numbers are stored as a 64-bit integer or a double, whose text always
+// parses as a BigDecimal of the same value. Formatting and parsing it is
+// slow, so comparisons and sums of two integers or two doubles avoid it.
+pub(crate) fn number_to_bigdecimal(val: &serde_json::Number) -> BigDecimal {
+    #[cfg(test)]
+    BIGDECIMAL_CONVERSIONS.with(|count| count.set(count.get() + 1));
+    BigDecimal::from_str(val.to_string().as_str())
+        .expect("a serde_json number must parse as a BigDecimal")
+}
+
+// Rounds to the nearest double, like Firestore's floating point arithmetic,
+// and overflows to an infinity
+fn double_from_bigdecimal(val: &BigDecimal) -> FsNumber {
+    let double = val
+        .to_plain_string()
+        .parse::<f64>()
+        .expect("a plain decimal string must parse as a double");
+    number_from_double(double)
+}
+
+// Parses decimal text, rejecting values that neither a 64-bit integer nor a
+// double holds exactly instead of silently rounding them.
+fn number_from_str(s: &str) -> Result<serde_json::Number> {
+    let number = serde_json::Number::from_str(s).map_err(|error| {
+        FsError::InvalidValue(format!(
+            "Failed to parse cstring ('{}') as a FsNumber: {}",
+            s, error
+        ))
+    })?;
+    let exact = BigDecimal::from_str(s).expect("a JSON number must parse as a BigDecimal");
+    if number_to_bigdecimal(&number) != exact {
+        return Err(FsError::InvalidValue(format!(
+            "Number '{}' cannot be stored without changing its value, which would become {}",
+            s, number
+        )));
+    }
+    Ok(number)
+}
+
+impl FsNumber {
+    // Arithmetic like Firestore's increment transform. Two integers give an
+    // integer saturating at the bounds of an i64, and a double operand gives
+    // a double. Finite doubles combine as the decimals they are written as,
+    // e.g. 0.1 + 0.2 is 0.3, which IEEE addition would not give. NaN and the
+    // infinities follow IEEE, so NaN or opposite infinities give NaN.
+    fn combine(
+        self,
+        other: FsNumber,
+        integers: fn(i128, i128) -> i128,
+        decimals: fn(BigDecimal, BigDecimal) -> BigDecimal,
+        doubles: fn(f64, f64) -> f64,
+    ) -> FsNumber {
+        match (&self, &other) {
+            (FsNumber::Number(l), FsNumber::Number(r)) => {
+                if let (Some(left), Some(right)) = (as_integer(l), as_integer(r)) {
+                    return saturating_integer(integers(left, right));
+                }
+                double_from_bigdecimal(&decimals(number_to_bigdecimal(l), number_to_bigdecimal(r)))
+            }
+            _ => number_from_double(doubles(self.as_double(), other.as_double())),
+        }
+    }
 }
 
 impl Add for FsNumber {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        match (self, other) {
-            (FsNumber::NAN, _) => FsNumber::NAN,
-            (_, FsNumber::NAN) => FsNumber::NAN,
-            (FsNumber::NegativeInfinity, FsNumber::PositiveInfinity) => FsNumber::NAN,
-            (FsNumber::PositiveInfinity, FsNumber::NegativeInfinity) => FsNumber::NAN,
-            (FsNumber::NegativeInfinity, _) => FsNumber::NegativeInfinity,
-            (FsNumber::PositiveInfinity, _) => FsNumber::PositiveInfinity,
-            (_, FsNumber::PositiveInfinity) => FsNumber::PositiveInfinity,
-            (_, FsNumber::NegativeInfinity) => FsNumber::NegativeInfinity,
-            (FsNumber::Number(l), FsNumber::Number(r)) => {
-                let left = number_to_bigdecimal(&l);
-                let right = number_to_bigdecimal(&r);
-                number_from_bigdecimal(&(left + right))
+        self.combine(other, i128::saturating_add, |l, r| l + r, |l, r| l + r)
+    }
+}
+
+impl Sub for FsNumber {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.combine(other, i128::saturating_sub, |l, r| l - r, |l, r| l - r)
+    }
+}
+
+impl Mul for FsNumber {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        self.combine(other, i128::saturating_mul, |l, r| l * r, |l, r| l * r)
+    }
+}
+
+// Integers are exact in an i128, which holds every stored integer as well as
+// quotients like i64::MIN / -1 that overflow an i64.
+fn as_integer(number: &serde_json::Number) -> Option<i128> {
+    number
+        .as_i64()
+        .map(i128::from)
+        .or_else(|| number.as_u64().map(i128::from))
+}
+
+fn as_f64(number: &serde_json::Number) -> f64 {
+    number
+        .as_f64()
+        .expect("a serde_json number must convert to a double")
+}
+
+fn number_from_integer(integer: i128) -> FsNumber {
+    if let Ok(integer) = i64::try_from(integer) {
+        return FsNumber::Number(serde_json::Number::from(integer));
+    }
+    if let Ok(integer) = u64::try_from(integer) {
+        return FsNumber::Number(serde_json::Number::from(integer));
+    }
+    number_from_double(integer as f64)
+}
+
+fn saturating_integer(integer: i128) -> FsNumber {
+    let integer = integer.clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+    FsNumber::Number(serde_json::Number::from(integer))
+}
+
+pub(crate) fn number_from_double(double: f64) -> FsNumber {
+    match serde_json::Number::from_f64(double) {
+        Some(number) => FsNumber::Number(number),
+        None if double.is_nan() => FsNumber::NAN,
+        None if double > 0.0 => FsNumber::PositiveInfinity,
+        None => FsNumber::NegativeInfinity,
+    }
+}
+
+impl FsNumber {
+    // Whether the number is stored as an integer rather than a double, like
+    // integerValue and doubleValue in the REST API
+    pub(crate) fn is_integer(&self) -> bool {
+        matches!(self, FsNumber::Number(number) if as_integer(number).is_some())
+    }
+
+    // The same value stored as an integer, when it has one. Only an integral
+    // double within the range of integers has. Mixed comparisons go by the
+    // decimal a double prints as, so that decimal is the twin, e.g.
+    // 9223372036854776000 rather than 2^63 for 9.223372036854776e18.
+    pub(crate) fn integer_twin(&self) -> Option<FsNumber> {
+        match self {
+            FsNumber::Number(number) if as_integer(number).is_none() => {
+                let decimal = number_to_bigdecimal(number);
+                if !decimal.is_integer() {
+                    return None;
+                }
+                let integer = decimal
+                    .with_scale(0)
+                    .to_plain_string()
+                    .parse::<i128>()
+                    .ok()?;
+                let twin = number_from_integer(integer);
+                twin.is_integer().then_some(twin)
             }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_double(&self) -> f64 {
+        match self {
+            FsNumber::NAN => f64::NAN,
+            FsNumber::NegativeInfinity => f64::NEG_INFINITY,
+            FsNumber::PositiveInfinity => f64::INFINITY,
+            FsNumber::Number(number) => as_f64(number),
+        }
+    }
+
+    // Both operands as integers, None when either one is a double
+    pub(crate) fn integers(&self, other: &FsNumber) -> Option<(i128, i128)> {
+        match (self, other) {
+            (FsNumber::Number(l), FsNumber::Number(r)) => Some((as_integer(l)?, as_integer(r)?)),
+            _ => None,
+        }
+    }
+
+    // The remainder of a division truncating toward zero, which has the sign
+    // of the dividend like `%` in Postgres, C, Java and JavaScript (and unlike
+    // Python's floored modulo). Integers stay integers. Otherwise both
+    // operands are doubles and the result is IEEE fmod, e.g. NaN for a zero
+    // divisor. None for an integer divided by zero.
+    pub(crate) fn checked_rem(&self, divisor: &FsNumber) -> Option<FsNumber> {
+        match self.integers(divisor) {
+            Some((_, 0)) => None,
+            Some((l, r)) => Some(number_from_integer(l % r)),
+            None => Some(number_from_double(self.as_double() % divisor.as_double())),
+        }
+    }
+
+    // The quotient truncated toward zero, so that
+    // `self == divisor * quotient + remainder` for integers. Doubles divide
+    // in IEEE arithmetic before truncating, e.g. an infinity for a non-zero
+    // double divided by zero. None for an integer divided by zero.
+    pub(crate) fn checked_div_trunc(&self, divisor: &FsNumber) -> Option<FsNumber> {
+        match self.integers(divisor) {
+            Some((_, 0)) => None,
+            Some((l, r)) => Some(number_from_integer(l / r)),
+            None => Some(number_from_double(
+                (self.as_double() / divisor.as_double()).trunc(),
+            )),
         }
     }
 }
 
+// Firestore's order of numbers, by value: NaN first, then -Infinity, and an
+// integer and a double of the same value, e.g. 1 and 1.0, are equal
 impl Ord for FsNumber {
     fn cmp(&self, other: &Self) -> Ordering {
         if self.eq(other) {
@@ -60,12 +239,22 @@ impl Ord for FsNumber {
         match (&self, other) {
             (FsNumber::NAN, _) => Ordering::Less,
             (FsNumber::PositiveInfinity, _) => Ordering::Greater,
+            // NaN sorts before -Infinity
+            (FsNumber::NegativeInfinity, FsNumber::NAN) => Ordering::Greater,
             (FsNumber::NegativeInfinity, _) => Ordering::Less,
             (FsNumber::Number(_), FsNumber::NAN) => Ordering::Greater,
             (FsNumber::Number(_), FsNumber::PositiveInfinity) => Ordering::Less,
             (FsNumber::Number(_), FsNumber::NegativeInfinity) => Ordering::Greater,
             (FsNumber::Number(left), FsNumber::Number(right)) => {
-                number_to_bigdecimal(left).cmp(&number_to_bigdecimal(right))
+                match (as_integer(left), as_integer(right)) {
+                    (Some(left), Some(right)) => left.cmp(&right),
+                    (None, None) => as_f64(left)
+                        .partial_cmp(&as_f64(right))
+                        .expect("a serde_json number is never NaN"),
+                    // Either side may not be exact in the type of the other,
+                    // e.g. 9007199254740993 and 9007199254740992.0
+                    _ => number_to_bigdecimal(left).cmp(&number_to_bigdecimal(right)),
+                }
             }
         }
     }
@@ -85,13 +274,7 @@ impl FromStr for FsNumber {
             "NaN" => Ok(FsNumber::NAN),
             "-Infinity" => Ok(FsNumber::NegativeInfinity),
             "Infinity" => Ok(FsNumber::PositiveInfinity),
-            _ => match serde_json::Number::from_str(s) {
-                Ok(number) => Ok(FsNumber::Number(number)),
-                Err(error) => Err(FsError::InvalidValue(format!(
-                    "Failed to parse cstring ('{}') as a FsNumber: {}",
-                    s, error
-                ))),
-            },
+            _ => number_from_str(s).map(FsNumber::Number),
         }
     }
 }
@@ -114,6 +297,12 @@ mod tests {
                 .cmp(&FsNumber::from_str("0.0").unwrap()),
             Ordering::Equal
         );
+        assert_eq!(
+            FsNumber::from_str("-0.0")
+                .unwrap()
+                .cmp(&FsNumber::from_str("0.0").unwrap()),
+            Ordering::Equal
+        );
         assert_eq!(
             FsNumber::from_str("0")
                 .unwrap()
@@ -151,6 +340,106 @@ mod tests {
             FsNumber::from_str("0").unwrap(),
             FsNumber::from_str("0.5").unwrap(),
         );
+        assert_lt(FsNumber::NAN, FsNumber::NegativeInfinity);
+    }
+
+    fn number(s: &str) -> FsNumber {
+        FsNumber::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (input, output) in [
+            ("0.1", "0.1"),
+            ("-0.25", "-0.25"),
+            ("1E+2", "100.0"),
+            ("1e2", "100.0"),
+            ("1e-7", "1e-7"),
+            ("18446744073709551615", "18446744073709551615"),
+            ("1e20", "1e+20"),
+        ] {
+            match number(input) {
+                FsNumber::Number(stored) => assert_eq!(stored.to_string(), output),
+                other => panic!("{} parsed as {:?}", input, other),
+            }
+        }
+        for input in [
+            "123456789012345678901234",
+            "0.12345678901234567890",
+            "1.00000000000000001",
+        ] {
+            assert!(FsNumber::from_str(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_add_rounding() {
+        assert_eq!(number("0.1") + number("0.2"), number("0.3"));
+        assert_eq!(number("1.0") + number("1.0"), number("2.0"));
+        assert_eq!(number("1e2") + number("1"), number("101.0"));
+        // Rounded to the nearest double
+        assert_eq!(number("1e20") + number("0.1"), number("1e20"));
+        assert_eq!(
+            number("1.7976931348623157e308") + number("1.7976931348623157e308"),
+            FsNumber::PositiveInfinity
+        );
+    }
+
+    #[test]
+    fn test_random_decimals_round_trip() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1939);
+        let digits = |rng: &mut StdRng, count: usize| -> String {
+            (0..count)
+                .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+                .collect()
+        };
+        for _ in 0..10000 {
+            // At most 15 significant digits, which a double always holds
+            let integer_digits = rng.gen_range(1..8);
+            let fraction_digits = rng.gen_range(0..8);
+            let mut text = format!(
+                "{}{}{}",
+                if rng.gen_bool(0.5) { "-" } else { "" },
+                rng.gen_range(1..10),
+                digits(&mut rng, integer_digits - 1)
+            );
+            if fraction_digits > 0 {
+                text = format!("{}.{}", text, digits(&mut rng, fraction_digits));
+            }
+            if rng.gen_bool(0.3) {
+                text = format!("{}e{}", text, rng.gen_range(-20..20));
+            }
+
+            let parsed = number(&text);
+            let exact = BigDecimal::from_str(&text).unwrap();
+            match &parsed {
+                FsNumber::Number(stored) => assert_eq!(number_to_bigdecimal(stored), exact),
+                other => panic!("{} parsed as {:?}", text, other),
+            }
+            let zero = number(if rng.gen_bool(0.5) { "0" } else { "0.0" });
+            assert_eq!(
+                (parsed.to_owned() + zero).cmp(&parsed),
+                Ordering::Equal,
+                "{}",
+                text
+            );
+            let output = match &parsed {
+                FsNumber::Number(stored) => serde_json::to_string(stored).unwrap(),
+                _ => unreachable!(),
+            };
+            assert_eq!(number(&output), parsed, "{}", text);
+        }
+
+        // Too many significant digits for a double
+        for _ in 0..1000 {
+            let text = format!("1{}1", digits(&mut rng, 23));
+            assert!(FsNumber::from_str(&text).is_err(), "{}", text);
+            let text = format!("0.1{}1", digits(&mut rng, 23));
+            assert!(FsNumber::from_str(&text).is_err(), "{}", text);
+        }
     }
 
     #[test]
@@ -168,4 +457,218 @@ mod tests {
             FsNumber::from_str("1.5").unwrap(),
         );
     }
+
+    #[test]
+    fn test_arithmetic_saturates_integers() {
+        let max = number("9223372036854775807");
+        let min = number("-9223372036854775808");
+        assert_eq!(max.to_owned() + number("1"), max);
+        assert_eq!(min.to_owned() - number("1"), min);
+        assert_eq!(min.to_owned() + number("-1"), min);
+        assert_eq!(max.to_owned() - number("-1"), max);
+        assert_eq!(max.to_owned() * number("2"), max);
+        assert_eq!(max.to_owned() * number("-2"), min);
+        assert_eq!(min.to_owned() * number("-1"), max);
+        assert_eq!(
+            number("18446744073709551615") * number("18446744073709551615"),
+            max
+        );
+        assert_eq!(number("7") - number("10"), number("-3"));
+        assert_eq!(number("-7") * number("6"), number("-42"));
+        // A double operand gives a double, even for an integral result
+        for result in [
+            number("1.0") + number("1"),
+            number("1e18") + number("1"),
+            number("2") * number("0.5"),
+            number("1.5") - number("0.5"),
+        ] {
+            assert!(
+                matches!(&result, FsNumber::Number(n) if n.is_f64()),
+                "{:?}",
+                result
+            );
+        }
+        assert_eq!(number("0.3") - number("0.1"), number("0.2"));
+        assert_eq!(number("0.1") * number("3"), number("0.3"));
+        assert_eq!(
+            number("1e300") * number("1e300"),
+            FsNumber::PositiveInfinity
+        );
+        assert_eq!(
+            number("-1e300") * number("1e300"),
+            FsNumber::NegativeInfinity
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_nan_and_infinities() {
+        let (inf, neg_inf) = (FsNumber::PositiveInfinity, FsNumber::NegativeInfinity);
+        for result in [
+            FsNumber::NAN + number("1"),
+            number("1") - FsNumber::NAN,
+            FsNumber::NAN * inf.to_owned(),
+            neg_inf.to_owned() + inf.to_owned(),
+            inf.to_owned() + neg_inf.to_owned(),
+            inf.to_owned() - inf.to_owned(),
+            inf.to_owned() * number("0"),
+            number("0.0") * neg_inf.to_owned(),
+        ] {
+            assert_eq!(result, FsNumber::NAN);
+        }
+        assert_eq!(inf.to_owned() + inf.to_owned(), inf);
+        assert_eq!(inf.to_owned() - neg_inf.to_owned(), inf);
+        assert_eq!(number("1") - inf.to_owned(), neg_inf);
+        assert_eq!(neg_inf.to_owned() * number("-2"), inf);
+        assert_eq!(number("9223372036854775807") + inf.to_owned(), inf);
+    }
+
+    // The number of BigDecimal conversions `run` makes
+    #[cfg(test)]
+    fn bigdecimal_conversions(run: impl FnOnce()) -> usize {
+        let before = BIGDECIMAL_CONVERSIONS.with(|count| count.get());
+        run();
+        BIGDECIMAL_CONVERSIONS.with(|count| count.get()) - before
+    }
+
+    #[test]
+    fn test_number_from_double() {
+        assert_eq!(number_from_double(f64::NAN), FsNumber::NAN);
+        assert_eq!(
+            number_from_double(f64::INFINITY),
+            FsNumber::PositiveInfinity
+        );
+        assert_eq!(
+            number_from_double(f64::NEG_INFINITY),
+            FsNumber::NegativeInfinity
+        );
+        let negative_zero = number_from_double(-0.0);
+        match &negative_zero {
+            FsNumber::Number(number) => assert_eq!(number.to_string(), "-0.0"),
+            other => panic!("expecting a number but found {:?}", other),
+        }
+        let parsed = number("-0.0");
+        assert!(parsed.as_double().is_sign_negative());
+        assert_eq!(parsed, negative_zero);
+        assert_eq!(negative_zero, number_from_double(0.0));
+    }
+
+    #[test]
+    fn test_fast_paths() {
+        let integers = [i64::MIN, -1, 0, 1, 9_007_199_254_740_993, i64::MAX]
+            .map(|integer| FsNumber::Number(integer.into()));
+        let doubles = ["-1e300", "-0.5", "0.0", "0.1", "9007199254740992.0"].map(number);
+        let unsigned = number("18446744073709551615");
+        let (negative_zero, zero) = (number("-0.0"), number("0.0"));
+        let sums = [number("9007199254740992"), number("9223372036854775807")];
+        // Parsing converts, so the operands are parsed up front
+        let conversions = bigdecimal_conversions(|| {
+            for numbers in [&integers[..], &doubles[..]] {
+                for (index, l) in numbers.iter().enumerate() {
+                    for (other_index, r) in numbers.iter().enumerate() {
+                        assert_eq!(l.cmp(r), index.cmp(&other_index), "{:?} {:?}", l, r);
+                    }
+                }
+            }
+            assert_eq!(negative_zero.cmp(&zero), Ordering::Equal);
+            assert_lt(integers[5].to_owned(), unsigned.to_owned());
+            assert_eq!(integers[1].to_owned() + integers[4].to_owned(), sums[0]);
+            // Integer sums saturate
+            assert_eq!(integers[5].to_owned() + integers[5].to_owned(), sums[1]);
+        });
+        assert_eq!(conversions, 0);
+
+        // Integers and doubles compare exactly with each other
+        let conversions = bigdecimal_conversions(|| {
+            assert_lt(doubles[4].to_owned(), integers[4].to_owned());
+            assert_eq!(doubles[4].cmp(&sums[0]), Ordering::Equal);
+        });
+        assert!(conversions > 0);
+    }
+
+    fn rem(l: &str, r: &str) -> Option<FsNumber> {
+        number(l).checked_rem(&number(r))
+    }
+
+    fn div(l: &str, r: &str) -> Option<FsNumber> {
+        number(l).checked_div_trunc(&number(r))
+    }
+
+    #[test]
+    fn test_rem_signs() {
+        // The remainder has the sign of the dividend
+        assert_eq!(rem("7", "3"), Some(number("1")));
+        assert_eq!(rem("-7", "3"), Some(number("-1")));
+        assert_eq!(rem("7", "-3"), Some(number("1")));
+        assert_eq!(rem("-7", "-3"), Some(number("-1")));
+        assert_eq!(rem("7.5", "2"), Some(number("1.5")));
+        assert_eq!(rem("-7.5", "2"), Some(number("-1.5")));
+        assert_eq!(rem("7.5", "-2"), Some(number("1.5")));
+        assert_eq!(rem("-9223372036854775808", "-1"), Some(number("0")));
+    }
+
+    #[test]
+    fn test_div_trunc_signs() {
+        assert_eq!(div("7", "2"), Some(number("3")));
+        assert_eq!(div("-7", "2"), Some(number("-3")));
+        assert_eq!(div("7", "-2"), Some(number("-3")));
+        assert_eq!(div("-7", "-2"), Some(number("3")));
+        assert_eq!(div("-7.5", "2"), Some(number("-3.0")));
+        assert_eq!(div("7", "0.5"), Some(number("14.0")));
+        // Overflows an i64 but not the unsigned integers numbers can hold
+        assert_eq!(
+            div("-9223372036854775808", "-1"),
+            Some(number("9223372036854775808"))
+        );
+    }
+
+    #[test]
+    fn test_integer_and_double_results() {
+        // Integers stay integers, and a double operand makes a double
+        assert!(matches!(rem("7", "3"), Some(FsNumber::Number(n)) if n.is_i64()));
+        assert!(matches!(rem("7.0", "3"), Some(FsNumber::Number(n)) if n.is_f64()));
+        assert!(matches!(rem("7", "3.0"), Some(FsNumber::Number(n)) if n.is_f64()));
+        assert!(matches!(div("7", "3"), Some(FsNumber::Number(n)) if n.is_i64()));
+        assert!(matches!(div("7.0", "3"), Some(FsNumber::Number(n)) if n.is_f64()));
+        // Integers are exact beyond 2^53, where doubles round
+        assert_eq!(rem("9007199254740993", "2"), Some(number("1")));
+        assert_eq!(rem("9007199254740993", "2.0"), Some(number("0.0")));
+        assert_eq!(rem("18446744073709551615", "10"), Some(number("5")));
+        // fmod of infinities
+        assert_eq!(rem("5", "Infinity"), Some(number("5.0")));
+        assert_eq!(rem("Infinity", "5"), Some(FsNumber::NAN));
+        assert_eq!(rem("NaN", "5"), Some(FsNumber::NAN));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(rem("1", "0"), None);
+        assert_eq!(rem("0", "0"), None);
+        assert_eq!(div("1", "0"), None);
+        assert_eq!(rem("1.5", "0"), Some(FsNumber::NAN));
+        assert_eq!(rem("1", "0.0"), Some(FsNumber::NAN));
+        assert_eq!(div("1.5", "0"), Some(FsNumber::PositiveInfinity));
+        assert_eq!(div("-1", "0.0"), Some(FsNumber::NegativeInfinity));
+        assert_eq!(div("0.0", "0"), Some(FsNumber::NAN));
+    }
+
+    #[test]
+    fn test_shard_assignment() {
+        // hash % 8, computed by hand. A negative hash gets a negative shard
+        // like in Java and JavaScript, so callers normalize it with
+        // (hash % n + n) % n.
+        let table = [
+            ("0", "0"),
+            ("7", "7"),
+            ("8", "0"),
+            ("1234567", "7"),
+            ("-1", "-1"),
+            ("-9", "-1"),
+            ("9223372036854775807", "7"),
+            ("-9223372036854775808", "0"),
+            ("18446744073709551615", "7"),
+        ];
+        for (hash, shard) in table {
+            assert_eq!(rem(hash, "8"), Some(number(shard)), "{} % 8", hash);
+        }
+    }
 }
@@ -0,0 +1,752 @@
+use crate::fs_reference::ReferenceView;
+use crate::{FsNumber, FsValue};
+use pgrx::prelude::*;
+use pgrx::Internal;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::os::raw::c_int;
+
+// Version of the comparison semantics of fsvalue, which btree and GIN
+// indexes bake into their on-disk layout. Bump it with every change to how
+// FsValue, FsNumber or FsReference values compare or hash.
+pub(crate) const ORDERING_VERSION: i32 = 5;
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_ordering_version() -> i32 {
+    ORDERING_VERSION
+}
+
+// Firestore's type order, with dates next to timestamps
+// https://firebase.google.com/docs/firestore/manage-data/data-types#value_type_ordering
+fn type_rank(value: &FsValue) -> u8 {
+    match value {
+        FsValue::NULL => 0,
+        FsValue::Boolean(_) => 1,
+        FsValue::Number(_) => 2,
+        FsValue::Date(_) => 3,
+        FsValue::Timestamp(_) => 4,
+        FsValue::String(_) => 5,
+        FsValue::Bytes(_) => 6,
+        FsValue::Reference(_) => 7,
+        FsValue::GeoPoint(_, _) => 8,
+        FsValue::Array(_) => 9,
+        FsValue::Map(_) => 10,
+    }
+}
+
+// Values of different types compare by type, and values of the same type
+// like Firestore compares them:
+// - numbers by value, NaN first (see FsNumber), so 1 and 1.0 are equal as
+//   they are under `=` and in hashes (see fs_hash)
+// - geo points by latitude and then by longitude
+// - arrays element by element, a prefix first
+// - maps key by key in ascending order (keys by UTF-8 bytes), the key before
+//   its value, a prefix first
+// test_cross_type_ordering and test_map_ordering pin this down.
+impl Ord for FsValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (FsValue::NULL, FsValue::NULL) => Ordering::Equal,
+            (FsValue::Boolean(lhs), FsValue::Boolean(rhs)) => lhs.cmp(rhs),
+            (FsValue::Number(lhs), FsValue::Number(rhs)) => lhs.cmp(rhs),
+            (FsValue::Date(lhs), FsValue::Date(rhs)) => lhs.cmp(rhs),
+            (FsValue::Timestamp(lhs), FsValue::Timestamp(rhs)) => lhs.cmp(rhs),
+            (FsValue::String(lhs), FsValue::String(rhs)) => lhs.cmp(rhs),
+            (FsValue::Bytes(lhs), FsValue::Bytes(rhs)) => lhs.cmp(rhs),
+            (FsValue::Reference(lhs), FsValue::Reference(rhs)) => lhs.cmp(rhs),
+            (
+                FsValue::GeoPoint(lhs_latitude, lhs_longitude),
+                FsValue::GeoPoint(rhs_latitude, rhs_longitude),
+            ) => lhs_latitude
+                .cmp(rhs_latitude)
+                .then_with(|| lhs_longitude.cmp(rhs_longitude)),
+            (FsValue::Array(lhs), FsValue::Array(rhs)) => lhs.cmp(rhs),
+            (FsValue::Map(lhs), FsValue::Map(rhs)) => lhs.iter().cmp(rhs.iter()),
+            _ => type_rank(self).cmp(&type_rank(other)),
+        }
+    }
+}
+
+impl PartialOrd for FsValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// `value` with every number that has an integer twin stored as the integer,
+// e.g. 1 for 1.0: the one value that all values equal to `value` share
+pub(crate) fn integer_form(value: &FsValue) -> FsValue {
+    let number = |number: &FsNumber| number.integer_twin().unwrap_or_else(|| number.to_owned());
+    match value {
+        FsValue::Number(value) => FsValue::Number(number(value)),
+        FsValue::GeoPoint(latitude, longitude) => {
+            FsValue::GeoPoint(number(latitude), number(longitude))
+        }
+        FsValue::Array(elements) => FsValue::Array(elements.iter().map(integer_form).collect()),
+        FsValue::Map(entries) => FsValue::Map(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_owned(), integer_form(value)))
+                .collect(),
+        ),
+        value => value.to_owned(),
+    }
+}
+
+// Whether `value` is NaN or holds one at any depth
+pub(crate) fn contains_nan(value: &FsValue) -> bool {
+    match value {
+        FsValue::Number(FsNumber::NAN) => true,
+        FsValue::Array(elements) => elements.iter().any(contains_nan),
+        FsValue::Map(entries) => entries.values().any(contains_nan),
+        _ => false,
+    }
+}
+
+// The order of Firestore filters, which unlike Ord leaves NaN unordered: it
+// is neither equal to, less nor greater than anything, NaN included. Arrays
+// and maps compare like in Ord up to their first difference, so they are
+// unordered once a NaN decides it. Ord and Eq keep NaN a value equal to
+// itself, which ORDER BY, btree indexes and the primary key rely on.
+pub(crate) fn query_cmp(lhs: &FsValue, rhs: &FsValue) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (FsValue::Number(FsNumber::NAN), _) | (_, FsValue::Number(FsNumber::NAN)) => None,
+        (FsValue::Array(lhs), FsValue::Array(rhs)) => {
+            for (lhs, rhs) in lhs.iter().zip(rhs) {
+                match query_cmp(lhs, rhs)? {
+                    Ordering::Equal => continue,
+                    ordering => return Some(ordering),
+                }
+            }
+            Some(lhs.len().cmp(&rhs.len()))
+        }
+        (FsValue::Map(lhs), FsValue::Map(rhs)) => {
+            for ((lhs_key, lhs), (rhs_key, rhs)) in lhs.iter().zip(rhs) {
+                match lhs_key.cmp(rhs_key) {
+                    Ordering::Equal => {}
+                    ordering => return Some(ordering),
+                }
+                match query_cmp(lhs, rhs)? {
+                    Ordering::Equal => continue,
+                    ordering => return Some(ordering),
+                }
+            }
+            Some(lhs.len().cmp(&rhs.len()))
+        }
+        _ => Some(lhs.cmp(rhs)),
+    }
+}
+
+// The total order of ORDER BY and btree indexes: -1, 0 or 1 as `lhs` sorts
+// before, with or after `rhs`. The #< family of operators instead never
+// matches values of different types, like Firestore filters.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_cmp(lhs: FsValue, rhs: FsValue) -> i32 {
+    lhs.cmp(&rhs) as i32
+}
+
+// A serialized FsValue read only as far as telling a reference apart, which
+// mirrors the serde layout of FsValue. The reference is read onto the stack.
+#[allow(clippy::large_enum_variant)]
+#[derive(Deserialize)]
+enum ValueView<'a> {
+    #[serde(rename = "NULL")]
+    Null,
+    #[serde(borrow)]
+    Reference(ReferenceView<'a>),
+    #[serde(
+        alias = "Boolean",
+        alias = "Number",
+        alias = "Date",
+        alias = "Timestamp",
+        alias = "String",
+        alias = "Bytes",
+        alias = "GeoPoint",
+        alias = "Array",
+        alias = "Map"
+    )]
+    Other(#[allow(dead_code)] IgnoredAny),
+}
+
+// The reference held by an fsvalue datum, borrowed from it
+unsafe fn reference_view<'a>(datum: pg_sys::Datum) -> Option<ReferenceView<'a>> {
+    match pgrx::cbor_decode(datum.cast_mut_ptr()) {
+        ValueView::Reference(reference) => Some(reference),
+        _ => None,
+    }
+}
+
+// The reference held by the CBOR of an fsvalue datum, borrowed from it, or
+// None for another value or bytes that do not decode
+pub(crate) fn view_reference(bytes: &[u8]) -> Option<ReferenceView<'_>> {
+    match serde_cbor::from_slice(bytes).ok()? {
+        ValueView::Reference(reference) => Some(reference),
+        _ => None,
+    }
+}
+
+// Two references compare straight from their datums, without allocating
+// their IDs and segments. Other values, and references too long to view,
+// compare through Ord like fs_cmp.
+unsafe fn compare_datums(lhs: pg_sys::Datum, rhs: pg_sys::Datum) -> Ordering {
+    let value = |datum| FsValue::from_datum(datum, false).expect("fsvalue must not be null");
+    reference_view(lhs)
+        .and_then(|lhs| lhs.compare(&reference_view(rhs)?))
+        .unwrap_or_else(|| value(lhs).cmp(&value(rhs)))
+}
+
+// The comparator of sorts, like the index builds and REINDEX of the
+// fs_documents primary key
+#[pg_guard]
+unsafe extern "C" fn sort_comparator(
+    lhs: pg_sys::Datum,
+    rhs: pg_sys::Datum,
+    _ssup: pg_sys::SortSupport,
+) -> c_int {
+    compare_datums(lhs, rhs) as c_int
+}
+
+// The sort support function of the btree operator class
+#[pg_extern(immutable, parallel_safe)]
+fn fs_value_sortsupport(ssup: Internal) {
+    let ssup =
+        unsafe { ssup.get_mut::<pg_sys::SortSupportData>() }.expect("expecting sort support data");
+    ssup.comparator = Some(sort_comparator);
+}
+
+// The comparison function of the btree operator class, which index lookups
+// and inserts call, such as those of the fs_documents primary key. It is a
+// bare V1 function like fs_map_get, as #[pg_extern] would decode both
+// arguments whole before the references could be viewed.
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fsvalue_cmp_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    let lhs = pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 0);
+    let rhs = pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 1);
+    (compare_datums(lhs, rhs) as i32)
+        .into_datum()
+        .expect("int4 datum must not be null")
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fsvalue_cmp_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+// The default btree operator class of fsvalue, which ORDER BY and the
+// fs_documents primary key use, ordered by Ord (see lib.rs for its operators)
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fsvalue_cmp(fsvalue, fsvalue) RETURNS integer \n\
+        AS 'MODULE_PATHNAME', 'fsvalue_cmp_wrapper' \n\
+        LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE; \n\
+        CREATE OPERATOR FAMILY fsvalue_btree_ops USING btree; \n\
+        CREATE OPERATOR CLASS fsvalue_btree_ops \n\
+        DEFAULT FOR TYPE fsvalue USING btree FAMILY fsvalue_btree_ops AS \n\
+            OPERATOR 1 <, \n\
+            OPERATOR 2 <=, \n\
+            OPERATOR 3 =, \n\
+            OPERATOR 4 >=, \n\
+            OPERATOR 5 >, \n\
+            FUNCTION 1 fsvalue_cmp(fsvalue, fsvalue), \n\
+            FUNCTION 2 fs_value_sortsupport(internal); \n\
+    ",
+    name = "btree_opclass",
+    requires = [
+        FsValue,
+        fsvalue_lt,
+        fsvalue_le,
+        fsvalue_eq,
+        fsvalue_ge,
+        fsvalue_gt,
+        fs_value_sortsupport
+    ],
+);
+
+// (index name, needs reindex) of every index on fsvalue. An index needs a
+// reindex when it was built under an older ordering version and has not been
+// rebuilt since, which REINDEX, VACUUM FULL and CLUSTER do by giving it a new
+// relfilenode. Indexes the catalog knows nothing about are flagged too.
+fn index_ordering(current_version: i32) -> Vec<(String, bool)> {
+    Spi::connect(|client| {
+        let mut indexes = Vec::new();
+        for row in client.select(
+            "SELECT v.index_oid::regclass::text, \
+                 COALESCE(o.ordering_version < $1 AND o.relfilenode = v.relfilenode, true) \
+             FROM fs_value_indexes v \
+             LEFT JOIN fs_index_ordering o ON o.index_oid = v.index_oid \
+             ORDER BY 1",
+            None,
+            Some(vec![(
+                PgBuiltInOids::INT4OID.oid(),
+                current_version.into_datum(),
+            )]),
+        )? {
+            indexes.push((
+                row.get::<String>(1)?.expect("index name must not be null"),
+                row.get::<bool>(2)?.expect("needs_reindex must not be null"),
+            ));
+        }
+        Ok::<_, pgrx::spi::Error>(indexes)
+    })
+    .expect("Failed to read from fs_index_ordering")
+}
+
+fn reindex_statements(current_version: i32) -> Vec<String> {
+    index_ordering(current_version)
+        .into_iter()
+        .filter(|(_, needs_reindex)| *needs_reindex)
+        .map(|(index_name, _)| format!("REINDEX INDEX {};", index_name))
+        .collect()
+}
+
+#[pg_extern]
+fn fs_check_index_ordering(
+) -> TableIterator<'static, (name!(index_name, String), name!(needs_reindex, bool))> {
+    TableIterator::new(index_ordering(ORDERING_VERSION).into_iter())
+}
+
+// The REINDEX statements to run after an upgrade that changed the ordering
+#[pg_extern]
+fn fs_reindex_statements() -> SetOfIterator<'static, String> {
+    SetOfIterator::new(reindex_statements(ORDERING_VERSION).into_iter())
+}
+
+// Every index with a key column of an fsvalue operator class, including
+// expression indexes like `(properties->'tags')`, and every index whose
+// expressions or predicate call fs_sort_key or fs_order_by_key, whose bytes
+// follow the ordering too, like the indexes of unique field constraints
+extension_sql!(
+    "\n\
+        CREATE VIEW fs_value_indexes AS \n\
+        SELECT i.indexrelid AS index_oid, c.relfilenode \n\
+        FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid \n\
+        WHERE EXISTS ( \n\
+            SELECT 1 FROM pg_opclass opclass \n\
+            WHERE opclass.oid = ANY (i.indclass::oid[]) AND opclass.opcintype = 'fsvalue'::regtype \n\
+        ) OR EXISTS ( \n\
+            SELECT 1 FROM pg_depend d JOIN pg_proc p ON p.oid = d.refobjid \n\
+            WHERE d.classid = 'pg_class'::regclass AND d.objid = i.indexrelid \n\
+                AND d.refclassid = 'pg_proc'::regclass \n\
+                AND p.proname IN ('fs_sort_key', 'fs_order_by_key') \n\
+        );\n\
+        CREATE TABLE fs_index_ordering (\n\
+            index_oid oid PRIMARY KEY,\n\
+            relfilenode oid NOT NULL,\n\
+            ordering_version integer NOT NULL\n\
+        );\n\
+    ",
+    name = "index_ordering_table",
+    requires = [FsValue],
+);
+
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_record_index_ordering() RETURNS event_trigger AS $$ \n\
+        BEGIN \n\
+            INSERT INTO fs_index_ordering (index_oid, relfilenode, ordering_version) \n\
+            SELECT v.index_oid, v.relfilenode, fs_ordering_version() \n\
+            FROM pg_event_trigger_ddl_commands() command \n\
+            JOIN fs_value_indexes v ON v.index_oid = command.objid \n\
+            WHERE command.classid = 'pg_class'::regclass \n\
+            ON CONFLICT (index_oid) DO UPDATE \n\
+            SET relfilenode = EXCLUDED.relfilenode, ordering_version = EXCLUDED.ordering_version; \n\
+        END \n\
+        $$ LANGUAGE plpgsql; \n\
+        CREATE EVENT TRIGGER fs_record_index_ordering ON ddl_command_end \n\
+        WHEN TAG IN ('CREATE INDEX', 'CREATE TABLE', 'ALTER TABLE') \n\
+        EXECUTE PROCEDURE fs_record_index_ordering(); \n\
+        CREATE FUNCTION fs_forget_index_ordering() RETURNS event_trigger AS $$ \n\
+        BEGIN \n\
+            DELETE FROM fs_index_ordering \n\
+            WHERE index_oid IN ( \n\
+                SELECT objid FROM pg_event_trigger_dropped_objects() \n\
+                WHERE classid = 'pg_class'::regclass \n\
+            ); \n\
+        END \n\
+        $$ LANGUAGE plpgsql; \n\
+        CREATE EVENT TRIGGER fs_forget_index_ordering ON sql_drop \n\
+        EXECUTE PROCEDURE fs_forget_index_ordering(); \n\
+    ",
+    name = "index_ordering_triggers",
+    requires = ["index_ordering_table", fs_ordering_version],
+);
+
+// Indexes created by the extension itself, e.g. on fs_documents, only exist
+// once everything else does
+extension_sql!(
+    "\n\
+        INSERT INTO fs_index_ordering (index_oid, relfilenode, ordering_version) \n\
+        SELECT index_oid, relfilenode, fs_ordering_version() FROM fs_value_indexes \n\
+        ON CONFLICT (index_oid) DO NOTHING; \n\
+    ",
+    name = "ordering_finalize",
+    finalize,
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_number::number_from_double;
+    use crate::fs_ordering::*;
+    use crate::fs_reference::VIEW_ELEMENTS;
+    use crate::fs_test_util::map;
+    use crate::fs_timestamp::date_from_unix_days;
+    use crate::{fs_array, fs_map_from_entries, fs_reference, FsNumber};
+
+    fn number(double: f64) -> FsValue {
+        FsValue::Number(number_from_double(double))
+    }
+
+    fn integer(integer: i64) -> FsValue {
+        FsValue::Number(FsNumber::Number(integer.into()))
+    }
+
+    fn geo_point(latitude: f64, longitude: f64) -> FsValue {
+        FsValue::GeoPoint(number_from_double(latitude), number_from_double(longitude))
+    }
+
+    // One or more values of every type, in Firestore's order
+    fn sorted_values() -> Vec<FsValue> {
+        vec![
+            FsValue::NULL,
+            FsValue::Boolean(false),
+            FsValue::Boolean(true),
+            FsValue::Number(FsNumber::NAN),
+            FsValue::Number(FsNumber::NegativeInfinity),
+            integer(i64::MIN),
+            number(-1.5),
+            integer(0),
+            number(0.5),
+            integer(1),
+            number(1e300),
+            FsValue::Number(FsNumber::PositiveInfinity),
+            FsValue::Date(date_from_unix_days(-1).unwrap()),
+            FsValue::Date(date_from_unix_days(0).unwrap()),
+            FsValue::Timestamp(-1),
+            FsValue::Timestamp(0),
+            FsValue::String(String::new()),
+            FsValue::String("A".to_owned()),
+            FsValue::String("a".to_owned()),
+            FsValue::String("ab".to_owned()),
+            FsValue::Bytes(vec![]),
+            FsValue::Bytes(vec![0]),
+            FsValue::Bytes(vec![0, 0]),
+            FsValue::Bytes(vec![0xff]),
+            fs_reference("/a/1"),
+            // Too long for a ReferenceView
+            fs_reference(&"/a/1".repeat(VIEW_ELEMENTS + 1)),
+            fs_reference("/a/1/b/1"),
+            fs_reference("/a/2"),
+            geo_point(-1.0, 5.0),
+            geo_point(0.0, -5.0),
+            geo_point(0.0, 5.0),
+            fs_array(vec![]),
+            fs_array(vec![FsValue::NULL]),
+            fs_array(vec![FsValue::NULL, FsValue::NULL]),
+            fs_array(vec![integer(1)]),
+            fs_array(vec![FsValue::String(String::new())]),
+            map(vec![]),
+            map(vec![("a", FsValue::NULL)]),
+            map(vec![("a", integer(1))]),
+            map(vec![("a", integer(1)), ("b", FsValue::NULL)]),
+            map(vec![("b", FsValue::NULL)]),
+        ]
+    }
+
+    #[test]
+    fn test_cross_type_ordering() {
+        let expected = sorted_values();
+        // Interleave the values from both ends so that no run starts sorted
+        let mut values = Vec::new();
+        let (mut front, mut back) = (expected.iter(), expected.iter().rev());
+        for _ in 0..expected.len() / 2 {
+            values.push(back.next().unwrap().to_owned());
+            values.push(front.next().unwrap().to_owned());
+        }
+        if expected.len() % 2 == 1 {
+            values.push(expected[expected.len() / 2].to_owned());
+        }
+        values.sort();
+        assert_eq!(values, expected);
+
+        for (index, lhs) in expected.iter().enumerate() {
+            for (other_index, rhs) in expected.iter().enumerate() {
+                assert_eq!(
+                    lhs.cmp(rhs),
+                    index.cmp(&other_index),
+                    "{:?} vs {:?}",
+                    lhs,
+                    rhs
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_integer_form() {
+        let values = vec![
+            integer(1),
+            number(1.0),
+            number(-0.0),
+            integer(i64::MIN),
+            number(0.5),
+            FsValue::Number(FsNumber::Number(u64::MAX.into())),
+            FsValue::Number(FsNumber::Number(9007199254740993u64.into())),
+            number(1e300),
+            geo_point(1.0, 0.5),
+            fs_array(vec![integer(2), number(3.0)]),
+            map(vec![("a", number(1.0)), ("b", integer(2))]),
+        ];
+        for value in values.iter() {
+            let form = integer_form(value);
+            assert_eq!(form.cmp(value), Ordering::Equal, "{:?}", value);
+            assert_eq!(integer_form(&form), form, "{:?}", value);
+        }
+        assert_eq!(integer_form(&number(1.0)), integer(1));
+        assert_eq!(integer_form(&number(-0.0)), integer(0));
+        assert_eq!(integer_form(&number(1e300)), number(1e300));
+        assert_eq!(
+            integer_form(&map(vec![("a", number(1.0)), ("b", number(2.5))])),
+            map(vec![("a", integer(1)), ("b", number(2.5))])
+        );
+    }
+
+    #[test]
+    fn test_query_cmp_leaves_nan_unordered() {
+        let nan = FsValue::Number(FsNumber::NAN);
+        assert_eq!(nan.cmp(&nan), Ordering::Equal);
+        assert_eq!(query_cmp(&nan, &nan), None);
+        assert_eq!(query_cmp(&nan, &integer(1)), None);
+        assert_eq!(query_cmp(&integer(1), &nan), None);
+        // A NaN decides the order of arrays and maps unless an earlier
+        // element or entry does
+        let array = |elements: Vec<FsValue>| FsValue::Array(elements);
+        assert_eq!(
+            query_cmp(&array(vec![nan.clone()]), &array(vec![nan.clone()])),
+            None
+        );
+        assert_eq!(
+            query_cmp(
+                &array(vec![integer(1), nan.clone()]),
+                &array(vec![integer(2), nan.clone()])
+            ),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            query_cmp(
+                &map(vec![("a", nan.clone())]),
+                &map(vec![("a", integer(1))])
+            ),
+            None
+        );
+        assert_eq!(
+            query_cmp(
+                &map(vec![("a", nan.clone())]),
+                &map(vec![("b", nan.clone())])
+            ),
+            Some(Ordering::Less)
+        );
+        // Without NaN, it is Ord
+        for lhs in sorted_values().iter().filter(|value| !contains_nan(value)) {
+            for rhs in sorted_values().iter().filter(|value| !contains_nan(value)) {
+                assert_eq!(query_cmp(lhs, rhs), Some(lhs.cmp(rhs)));
+            }
+        }
+        assert!(contains_nan(&map(vec![("a", array(vec![nan]))])));
+        assert!(!contains_nan(&array(vec![integer(1)])));
+    }
+
+    #[test]
+    fn test_value_views() {
+        for value in sorted_values() {
+            let json = serde_json::to_string(&value).unwrap();
+            let view: ValueView = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                matches!(view, ValueView::Reference(_)),
+                matches!(value, FsValue::Reference(_)),
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_cmp() {
+        Spi::run("CREATE TEMP TABLE ordering_values (position integer, value fsvalue)")
+            .expect("SPI failed");
+        let values = sorted_values();
+        for (position, value) in values.iter().enumerate().rev() {
+            Spi::run_with_args(
+                "INSERT INTO ordering_values VALUES ($1, $2)",
+                Some(vec![
+                    (PgBuiltInOids::INT4OID.oid(), (position as i32).into_datum()),
+                    (
+                        PgOid::from(FsValue::type_oid()),
+                        value.to_owned().into_datum(),
+                    ),
+                ]),
+            )
+            .expect("SPI failed");
+        }
+        // ORDER BY and fs_cmp agree with Ord
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(position::text, ',' ORDER BY value) FROM ordering_values"
+            ),
+            Ok(Some(
+                (0..values.len())
+                    .map(|position| position.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM ordering_values l, ordering_values r \
+                 WHERE fs_cmp(l.value, r.value) <> sign(l.position - r.position)"
+            ),
+            Ok(Some(0))
+        );
+        // fsvalue_cmp, which compares references from their datums, agrees
+        // too
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM ordering_values l, ordering_values r \
+                 WHERE fsvalue_cmp(l.value, r.value) <> fs_cmp(l.value, r.value)"
+            ),
+            Ok(Some(0))
+        );
+        // Sorts compare with fs_value_sortsupport, index lookups with fsvalue_cmp
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(amproc::text, ',' ORDER BY amprocnum) FROM pg_amproc \
+                 WHERE amprocfamily = (SELECT oid FROM pg_opfamily WHERE opfname = 'fsvalue_btree_ops')"
+            ),
+            Ok(Some("fsvalue_cmp,fs_value_sortsupport".to_owned()))
+        );
+        Spi::run("CREATE INDEX ordering_values_value ON ordering_values (value)")
+            .expect("SPI failed");
+        Spi::run("SET LOCAL enable_seqscan = off").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM ordering_values l \
+                 WHERE (SELECT count(*) FROM ordering_values r WHERE r.value < l.value) <> l.position"
+            ),
+            Ok(Some(0))
+        );
+        assert_eq!(
+            fs_cmp(fs_map_from_entries(vec![], vec![]), FsValue::NULL),
+            1
+        );
+        // The query operators still only compare within a type
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT fs_null() #< fs_boolean(false)"),
+            Ok(Some(false))
+        );
+    }
+
+    fn recorded_version(index_name: &str) -> Option<i32> {
+        Spi::get_one::<i32>(&format!(
+            "SELECT (SELECT ordering_version FROM fs_index_ordering \
+             WHERE index_oid = '{}'::regclass)",
+            index_name
+        ))
+        .expect("SPI failed")
+    }
+
+    fn needs_reindex(current_version: i32, index_name: &str) -> Option<bool> {
+        index_ordering(current_version)
+            .into_iter()
+            .find(|(name, _)| name == index_name)
+            .map(|(_, needs_reindex)| needs_reindex)
+    }
+
+    #[pg_test]
+    fn test_fs_check_index_ordering() {
+        Spi::run(
+            "CREATE TABLE ordering_test (id integer, value fsvalue); \
+             CREATE INDEX ordering_test_value ON ordering_test (value); \
+             CREATE INDEX ordering_test_field ON ordering_test ((value->'foo')); \
+             CREATE INDEX ordering_test_id ON ordering_test (id); \
+             CREATE INDEX ordering_test_key ON ordering_test (fs_sort_key(value)); \
+             CREATE INDEX ordering_test_partial ON ordering_test (id) \
+                 WHERE fs_sort_key(value) > '\\x00'",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            recorded_version("ordering_test_value"),
+            Some(ORDERING_VERSION)
+        );
+        // Sort keys are bytea, but their bytes follow the ordering
+        assert_eq!(
+            recorded_version("ordering_test_key"),
+            Some(ORDERING_VERSION)
+        );
+        assert_eq!(
+            recorded_version("ordering_test_partial"),
+            Some(ORDERING_VERSION)
+        );
+        assert_eq!(
+            recorded_version("ordering_test_field"),
+            Some(ORDERING_VERSION)
+        );
+        assert_eq!(recorded_version("ordering_test_id"), None);
+        assert_eq!(
+            recorded_version("fs_documents_pkey"),
+            Some(ORDERING_VERSION)
+        );
+
+        assert!(fs_check_index_ordering().all(|(_, needs_reindex)| !needs_reindex));
+        assert_eq!(needs_reindex(ORDERING_VERSION, "ordering_test_id"), None);
+
+        // A build with a bumped ordering version flags every existing index
+        assert_eq!(
+            needs_reindex(ORDERING_VERSION + 1, "ordering_test_value"),
+            Some(true)
+        );
+        assert_eq!(
+            needs_reindex(ORDERING_VERSION + 1, "fs_documents_pkey"),
+            Some(true)
+        );
+        assert!(reindex_statements(ORDERING_VERSION + 1)
+            .contains(&"REINDEX INDEX ordering_test_field;".to_owned()));
+        assert!(reindex_statements(ORDERING_VERSION + 1)
+            .contains(&"REINDEX INDEX ordering_test_key;".to_owned()));
+
+        // ... until it is rebuilt
+        Spi::run("REINDEX INDEX ordering_test_value").expect("SPI failed");
+        assert_eq!(
+            needs_reindex(ORDERING_VERSION + 1, "ordering_test_value"),
+            Some(false)
+        );
+        assert!(!reindex_statements(ORDERING_VERSION + 1)
+            .contains(&"REINDEX INDEX ordering_test_value;".to_owned()));
+
+        Spi::run("DROP INDEX ordering_test_field").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM fs_index_ordering o LEFT JOIN pg_class c ON c.oid = o.index_oid WHERE c.oid IS NULL")
+                .expect("SPI failed"),
+            Some(0)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_reindex_statements() {
+        assert_eq!(fs_reindex_statements().count(), 0);
+        Spi::run(
+            "CREATE TABLE ordering_test (value fsvalue); \
+             CREATE INDEX ordering_test_value ON ordering_test (value); \
+             DELETE FROM fs_index_ordering WHERE index_oid = 'ordering_test_value'::regclass",
+        )
+        .expect("SPI failed");
+        // An index missing from the catalog was built under an unknown ordering
+        assert_eq!(
+            fs_reindex_statements().collect::<Vec<_>>(),
+            vec!["REINDEX INDEX ordering_test_value;".to_owned()]
+        );
+    }
+}
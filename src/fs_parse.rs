@@ -0,0 +1,414 @@
+use crate::fs_error::FsError;
+use crate::FsValue;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Reads the typed JSON text of an fsvalue straight off serde_json's token
+// stream, so that a large document is not first built as a serde_json::Value.
+// ARRAY and MAP values are built as they are read when their 'type' comes
+// before their 'value', as in fsvalue output. Anything else is small, or in an
+// unusual order, and goes through the serde_json::Value parsers in lib.rs,
+// which keeps validation and messages in one place.
+//
+// Syntax errors, including serde_json's nesting limit, win over invalid
+// values like they did when the whole text was parsed first: the first
+// invalid value is kept aside and the rest of the text is only checked for
+// syntax. Failures are returned as the message the input function raises.
+pub(crate) fn parse_typed_json(text: &str) -> Result<FsValue, String> {
+    let mut failure = None;
+    let mut deserializer = serde_json::Deserializer::from_str(text);
+    let value = Parser::new(Shape::Typed, &mut failure)
+        .deserialize(&mut deserializer)
+        .and_then(|value| deserializer.end().map(|_| value))
+        .map_err(|error| format!("Failed to parse cstring as a serde_json object: {}", error))?;
+    match failure {
+        Some(error) => Err(error.to_string()),
+        None => Ok(value),
+    }
+}
+
+// What the JSON at hand is the text of
+#[derive(Clone, Copy)]
+enum Shape {
+    // An object with 'type' and 'value'
+    Typed,
+    // The 'value' of an ARRAY
+    Array,
+    // The 'value' of a MAP
+    Map,
+}
+
+struct Parser<'a> {
+    shape: Shape,
+    failure: &'a mut Option<FsError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(shape: Shape, failure: &'a mut Option<FsError>) -> Self {
+        Parser { shape, failure }
+    }
+
+    // Keeps the first error. The value returned in its place is never used.
+    fn finish(self, result: Result<FsValue, FsError>) -> FsValue {
+        result.unwrap_or_else(|error| {
+            self.failure.get_or_insert(error);
+            FsValue::NULL
+        })
+    }
+
+    // JSON that cannot be streamed as the shape at hand
+    fn fallback(self, value: Value) -> FsValue {
+        if self.failure.is_some() {
+            return FsValue::NULL;
+        }
+        let result = match self.shape {
+            Shape::Typed => FsValue::from_typed_json(value),
+            Shape::Array => FsValue::from_array_value(value),
+            Shape::Map => FsValue::from_map_value(value),
+        };
+        self.finish(result)
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Parser<'a> {
+    type Value = FsValue;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<FsValue, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if self.failure.is_some() {
+            deserializer.deserialize_ignored_any(IgnoredAny)?;
+            return Ok(FsValue::NULL);
+        }
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Parser<'a> {
+    type Value = FsValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("JSON")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::Bool(value)))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::Number(value.into())))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::Number(value.into())))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<FsValue, E> {
+        Ok(self.fallback(Number::from_f64(value).map_or(Value::Null, Value::Number)))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::String(value.to_owned())))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::String(value)))
+    }
+
+    fn visit_unit<E>(self) -> Result<FsValue, E> {
+        Ok(self.fallback(Value::Null))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<FsValue, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        if !matches!(self.shape, Shape::Array) {
+            let mut elements = Vec::new();
+            while let Some(element) = seq.next_element::<Value>()? {
+                elements.push(element);
+            }
+            return Ok(self.fallback(Value::Array(elements)));
+        }
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(element) =
+            seq.next_element_seed(Parser::new(Shape::Typed, &mut *self.failure))?
+        {
+            elements.push(element);
+        }
+        let result = FsValue::check_array_nesting(&elements).map(|_| FsValue::Array(elements));
+        Ok(self.finish(result))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<FsValue, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        match self.shape {
+            Shape::Typed => self.visit_typed(map),
+            Shape::Map => {
+                let mut entries = BTreeMap::new();
+                while let Some(key) = map.next_key::<String>()? {
+                    let value =
+                        map.next_value_seed(Parser::new(Shape::Typed, &mut *self.failure))?;
+                    entries.insert(key, value);
+                }
+                Ok(FsValue::Map(entries))
+            }
+            Shape::Array => {
+                let mut entries = Map::new();
+                while let Some((key, value)) = map.next_entry::<String, Value>()? {
+                    entries.insert(key, value);
+                }
+                Ok(self.fallback(Value::Object(entries)))
+            }
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn visit_typed<'de, A>(self, mut map: A) -> Result<FsValue, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut fields = Map::new();
+        // The type and value of a streamed 'value'
+        let mut streamed: Option<(String, FsValue)> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            let shape = match (key.as_str(), fields.get("type")) {
+                ("value", Some(Value::String(fs_type))) if fs_type == "ARRAY" => Some(Shape::Array),
+                ("value", Some(Value::String(fs_type))) if fs_type == "MAP" => Some(Shape::Map),
+                _ => None,
+            };
+            if let Some(shape) = shape {
+                let fs_value = map.next_value_seed(Parser::new(shape, &mut *self.failure))?;
+                let fs_type = fields["type"].as_str().unwrap_or_default().to_owned();
+                // The last of repeated keys wins, as in a serde_json::Value
+                fields.remove("value");
+                streamed = Some((fs_type, fs_value));
+                continue;
+            }
+            let value = map.next_value::<Value>()?;
+            if key == "type" {
+                // A repeated 'type' that no longer matches the streamed value
+                // puts the value back as JSON
+                if let Some((fs_type, fs_value)) = streamed.take() {
+                    if value.as_str() == Some(fs_type.as_str()) {
+                        streamed = Some((fs_type, fs_value));
+                    } else {
+                        let mut json_value = fs_value.to_json_value();
+                        fields.insert("value".to_owned(), json_value["value"].take());
+                    }
+                }
+            }
+            fields.insert(key, value);
+        }
+        match streamed {
+            Some((_, fs_value)) => Ok(fs_value),
+            None => Ok(self.fallback(Value::Object(fields))),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use crate::fs_parse::*;
+    use crate::FsNumber;
+    use pgrx::prelude::*;
+    use std::panic;
+
+    // How the input function read text before parse_typed_json
+    fn parse_via_value(text: &str) -> FsValue {
+        let value = serde_json::from_str::<Value>(text).unwrap_or_else(|error| {
+            panic!("Failed to parse cstring as a serde_json object: {}", error)
+        });
+        match FsValue::from(value) {
+            Ok(value) => value,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    fn parse_or_panic(text: &str) -> FsValue {
+        parse_typed_json(text).unwrap_or_else(|message| panic!("{}", message))
+    }
+
+    // The value, or the message the input function fails with
+    fn outcome(parse: fn(&str) -> FsValue, text: &str) -> std::result::Result<FsValue, String> {
+        let text = text.to_owned();
+        panic::catch_unwind(move || parse(&text)).map_err(|payload| {
+            match payload.downcast::<String>() {
+                Ok(message) => *message,
+                Err(payload) => payload
+                    .downcast::<&str>()
+                    .map(|message| message.to_string())
+                    .unwrap_or_default(),
+            }
+        })
+    }
+
+    fn assert_same_outcome(text: &str) {
+        assert_eq!(
+            outcome(parse_or_panic, text),
+            outcome(parse_via_value, text),
+            "{}",
+            text
+        );
+    }
+
+    // Built as text: a deeply nested serde_json::Value overflows the stack
+    fn nested(depth: usize, open: &str, leaf: &str, close: &str) -> String {
+        format!("{}{}{}", open.repeat(depth), leaf, close.repeat(depth))
+    }
+
+    fn nested_arrays(depth: usize) -> String {
+        nested(
+            depth,
+            r#"{"type": "ARRAY", "value": ["#,
+            r#"{"type": "NULL", "value": null}"#,
+            "]}",
+        )
+    }
+
+    fn nested_maps(depth: usize) -> String {
+        nested(
+            depth,
+            r#"{"type": "MAP", "value": {"a": "#,
+            r#"{"type": "BOOLEAN", "value": true}"#,
+            "}}",
+        )
+    }
+
+    fn sample_values() -> Vec<FsValue> {
+        let scalars = vec![
+            FsValue::NULL,
+            FsValue::Boolean(false),
+            FsValue::Number(FsNumber::from(Number::from(-7))),
+            FsValue::Number(FsNumber::from(Number::from_f64(0.1).unwrap())),
+            FsValue::Date(pgrx::Date::from(8_766)),
+            FsValue::Timestamp(1_700_000_000_123_456),
+            FsValue::String("multi\nline \"quoted\" ☃".to_owned()),
+            FsValue::Bytes((0..=255).collect()),
+            crate::fs_reference("/users/1/posts/2"),
+        ];
+        let array = FsValue::Array(scalars.clone());
+        let map = FsValue::Map(
+            scalars
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (format!("field{}", index), value.to_owned()))
+                .collect(),
+        );
+        let mut values = scalars;
+        values.push(FsValue::Map(
+            [
+                ("array".to_owned(), array.to_owned()),
+                ("map".to_owned(), map.to_owned()),
+                ("empty".to_owned(), FsValue::Map(BTreeMap::new())),
+            ]
+            .into(),
+        ));
+        values.push(FsValue::Array(vec![map, FsValue::Array(vec![])]));
+        values.push(array);
+        values
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        for value in sample_values() {
+            for style in [
+                crate::fs_guc::OutputStyle::Canonical,
+                crate::fs_guc::OutputStyle::Readable,
+            ] {
+                let text = value.to_styled_json_value(style).to_string();
+                if text.contains("\"length\"") {
+                    continue;
+                }
+                assert_same_outcome(&text);
+            }
+            assert_eq!(
+                parse_typed_json(&value.to_json_value().to_string()),
+                Ok(value)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_matches_value_path() {
+        let corpus = [
+            // Key orders and extra fields
+            r#"{"value": [{"type": "NULL", "value": null}], "type": "ARRAY"}"#,
+            r#"{"value": {"a": {"value": 1, "type": "NUMBER"}}, "type": "MAP"}"#,
+            r#"{"type": "ARRAY", "extra": 1, "value": []}"#,
+            r#"{"type": "BYTES", "encoding": "hex", "value": "0x00ff"}"#,
+            r#"{"type": "BYTES", "value": "AP8=", "encoding": "hex"}"#,
+            r#"{"type": "BYTES", "encoding": "hex", "value": "0x00…", "length": 99}"#,
+            r#"{"type": "BYTES", "encoding": "base32", "value": "AA"}"#,
+            // Repeated keys
+            r#"{"type": "MAP", "value": {"a": {"type": "NULL", "value": null}}, "value": {}}"#,
+            r#"{"type": "ARRAY", "value": [], "type": "STRING", "value": "x"}"#,
+            r#"{"type": "ARRAY", "value": [], "type": "STRING"}"#,
+            r#"{"type": "ARRAY", "value": [], "type": "ARRAY"}"#,
+            r#"{"type": "MAP", "value": {"a": {"type": "NULL", "value": null}, "a": {"type": "BOOLEAN", "value": true}}}"#,
+            // Invalid values
+            r#"{"type": "UNKNOWN", "value": 1}"#,
+            r#"{"value": 1}"#,
+            r#"{"type": "NUMBER"}"#,
+            r#"{"type": "BOOLEAN", "value": "true"}"#,
+            r#"{"type": "NUMBER", "value": "1"}"#,
+            r#"{"type": "NULL", "value": 0}"#,
+            r#"{"type": "NUMBER", "value": "NaN"}"#,
+            r#"{"type": "TIMESTAMP", "value": "yesterday"}"#,
+            r#"{"type": "REFERENCE", "value": "/users"}"#,
+            r#"{"type": "BYTES", "value": "not base64!"}"#,
+            r#"{"type": "ARRAY", "value": {"a": 1}}"#,
+            r#"{"type": "ARRAY", "value": 3}"#,
+            r#"{"type": "MAP", "value": [1]}"#,
+            r#"{"type": "MAP", "value": "x"}"#,
+            r#"{"type": "ARRAY", "value": [{"type": "STRING", "value": 1}]}"#,
+            r#"{"type": "MAP", "value": {"a": {"type": "MAP", "value": {"b": {"type": "NUMBER"}}}}}"#,
+            r#"{"type": "ARRAY", "value": [{"type": "ARRAY", "value": []}]}"#,
+            // Values the input function rejects with a panic
+            r#"[1, 2]"#,
+            r#""text""#,
+            r#"null"#,
+            r#"{"type": 1, "value": 1}"#,
+            r#"{"type": "ARRAY", "value": [1]}"#,
+            r#"{"type": "MAP", "value": {"a": true}}"#,
+            // Syntax errors, also after an invalid value
+            r#"{"type": "ARRAY", "value": [}"#,
+            r#"{"type": "STRING", "value": "x"} trailing"#,
+            r#"{"type": "UNKNOWN", "value": 1"#,
+            r#"{"type": "ARRAY", "value": [{"type": "STRING", "value": 1}, oops]}"#,
+            r#"{"type": "MAP", "value": {"a": {"type": "NUMBER"}, "b": {"type": "NULL", "value": null}}"#,
+            "",
+        ];
+        for text in corpus {
+            assert_same_outcome(text);
+        }
+    }
+
+    #[test]
+    fn test_parse_pathological_nesting() {
+        // Every level takes two levels of JSON: its object and its value
+        for depth in [0, 1, 62, 63, 64, 1000] {
+            assert_same_outcome(&nested_arrays(depth));
+            assert_same_outcome(&nested_maps(depth));
+        }
+        assert!(outcome(parse_or_panic, &nested_arrays(63)).is_ok());
+        assert_same_outcome(&format!("{}{}", "[".repeat(10_000), "]".repeat(10_000)));
+        assert_same_outcome(&"{\"a\":".repeat(10_000));
+    }
+
+    #[pg_test]
+    fn test_parse_strict_array_nesting() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert_same_outcome(
+            r#"{"type": "ARRAY", "value": [{"type": "NULL", "value": null}, {"type": "ARRAY", "value": []}]}"#,
+        );
+        assert_same_outcome(r#"{"value": [{"type": "ARRAY", "value": []}], "type": "ARRAY"}"#);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{parse_field_names, FsValue};
+use pgrx::prelude::*;
+use serde::de::{DeserializeSeed, EnumAccess, IgnoredAny, MapAccess, VariantAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+// `->` and fs_get_field run once per row for every field a query filters,
+// orders or indexes on, and the field is usually a small part of the
+// document. Instead of decoding the whole datum into an FsValue, they walk
+// its CBOR, skipping the fields off the path without building them, and
+// decode the value at the end of the path alone. The datum is still detoasted
+// whole; fsvalue has no field directory to seek with. Adding one would change
+// the stored CBOR of every value, so the datum would need a storage format
+// version for old rows to keep decoding.
+
+const VARIANTS: &[&str] = &[
+    "NULL",
+    "Boolean",
+    "Number",
+    "Date",
+    "Timestamp",
+    "String",
+    "Bytes",
+    "Reference",
+    "GeoPoint",
+    "Array",
+    "Map",
+];
+
+#[derive(Deserialize)]
+#[serde(variant_identifier)]
+enum Variant {
+    #[serde(rename = "NULL")]
+    Null,
+    Map,
+    #[serde(
+        alias = "Boolean",
+        alias = "Number",
+        alias = "Date",
+        alias = "Timestamp",
+        alias = "String",
+        alias = "Bytes",
+        alias = "Reference",
+        alias = "GeoPoint",
+        alias = "Array"
+    )]
+    Other,
+}
+
+// The value at `field_names` below the value being deserialized, like
+// FsValue::get_field
+struct FieldSeed<'a> {
+    field_names: &'a [String],
+}
+
+impl<'de> DeserializeSeed<'de> for FieldSeed<'_> {
+    type Value = Option<FsValue>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        if self.field_names.is_empty() {
+            FsValue::deserialize(deserializer).map(Some)
+        } else {
+            deserializer.deserialize_enum("FsValue", VARIANTS, self)
+        }
+    }
+}
+
+impl<'de> Visitor<'de> for FieldSeed<'_> {
+    type Value = Option<FsValue>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an fsvalue")
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<Self::Value, A::Error> {
+        match data.variant()? {
+            (Variant::Map, entries) => entries.newtype_variant_seed(EntriesSeed {
+                field_names: self.field_names,
+            }),
+            (Variant::Null, value) => value.unit_variant().map(|_| None),
+            (Variant::Other, value) => value.newtype_variant::<IgnoredAny>().map(|_| None),
+        }
+    }
+}
+
+// The entries of a map, of which the one named by the first of `field_names`
+// continues the path
+struct EntriesSeed<'a> {
+    field_names: &'a [String],
+}
+
+impl<'de> DeserializeSeed<'de> for EntriesSeed<'_> {
+    type Value = Option<FsValue>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for EntriesSeed<'_> {
+    type Value = Option<FsValue>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the entries of a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<Self::Value, A::Error> {
+        let (name, rest) = self
+            .field_names
+            .split_first()
+            .expect("expecting a field name");
+        // Every entry is read, as the deserializer rejects a map left
+        // unfinished
+        let mut found = None;
+        while let Some(matches) = entries.next_key_seed(KeyIs(name))? {
+            if matches && found.is_none() {
+                found = Some(entries.next_value_seed(FieldSeed { field_names: rest })?);
+            } else {
+                entries.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found.flatten())
+    }
+}
+
+// Whether a key equals the name, compared without copying the key
+struct KeyIs<'a>(&'a str);
+
+impl<'de> DeserializeSeed<'de> for KeyIs<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeyIs<'_> {
+    type Value = bool;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a field name")
+    }
+
+    fn visit_str<E>(self, key: &str) -> Result<bool, E> {
+        Ok(key == self.0)
+    }
+}
+
+// The value at `field_names` in the CBOR of an fsvalue datum
+pub(crate) fn decode_field(
+    bytes: &[u8],
+    field_names: &[String],
+) -> Result<Option<FsValue>, serde_cbor::Error> {
+    let mut deserializer = serde_cbor::Deserializer::from_slice(bytes);
+    FieldSeed { field_names }.deserialize(&mut deserializer)
+}
+
+// The field named by the text argument of a call, or SQL NULL for a missing
+// one
+unsafe fn field_arg(
+    fcinfo: pg_sys::FunctionCallInfo,
+    field_names: fn(&str) -> Vec<String>,
+) -> pg_sys::Datum {
+    let bytes = pgrx::fcinfo::pg_getarg::<&[u8]>(fcinfo, 0).expect("fsvalue must not be null");
+    let name = pgrx::fcinfo::pg_getarg::<&str>(fcinfo, 1).expect("text must not be null");
+    match decode_field(bytes, &field_names(name)) {
+        Ok(Some(value)) => value.into_datum().expect("fsvalue must not be null"),
+        Ok(None) => pgrx::fcinfo::pg_return_null(fcinfo),
+        // Only a datum that is not an fsvalue fails to decode
+        Err(error) => {
+            ereport!(
+                PgLogLevel::ERROR,
+                PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+                format!("Failed to decode fsvalue: {}", error)
+            );
+            unreachable!("an ERROR report does not return")
+        }
+    }
+}
+
+// Like fs_parent, these are bare V1 functions: #[pg_extern] would decode the
+// whole argument before the field could be picked out of it.
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_map_get_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    field_arg(fcinfo, |field_name| vec![field_name.to_owned()])
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_map_get_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_get_field_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    field_arg(fcinfo, parse_field_names)
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_get_field_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_map_get(fs_map fsvalue, field_name text) RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_map_get_wrapper' \n\
+        LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE; \n\
+        CREATE OPERATOR -> ( \n\
+            LEFTARG = fsvalue, RIGHTARG = text, FUNCTION = fs_map_get \n\
+        ); \n\
+        CREATE FUNCTION fs_get_field(fs_value fsvalue, field_path text) RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_get_field_wrapper' \n\
+        LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE; \n\
+    ",
+    name = "field_functions",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_partial_decode::*;
+    use crate::fs_test_util::{map, plain};
+    use crate::FsNumber;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use serde_json::json;
+
+    const NAMES: &[&str] = &["a", "b", "ab", "", "0", "é"];
+
+    fn random_value(rng: &mut StdRng, depth: u32) -> FsValue {
+        let number = |rng: &mut StdRng| {
+            FsNumber::from(if rng.gen_bool(0.5) {
+                serde_json::Number::from(rng.gen_range(-3..30))
+            } else {
+                serde_json::Number::from_f64(rng.gen_range(-1.0..1.0)).unwrap()
+            })
+        };
+        match rng.gen_range(0..if depth == 0 { 7 } else { 9 }) {
+            0 => FsValue::NULL,
+            1 => FsValue::Boolean(rng.gen_bool(0.5)),
+            2 => FsValue::Number(number(rng)),
+            3 => FsValue::Timestamp(rng.gen()),
+            4 => FsValue::String(NAMES[rng.gen_range(0..NAMES.len())].repeat(3)),
+            5 => FsValue::Bytes((0..rng.gen_range(0..4)).map(|_| rng.gen()).collect()),
+            6 => FsValue::GeoPoint(number(rng), number(rng)),
+            7 => FsValue::Array(
+                (0..rng.gen_range(0..3))
+                    .map(|_| random_value(rng, depth - 1))
+                    .collect(),
+            ),
+            _ => map((0..rng.gen_range(0..4))
+                .map(|_| {
+                    (
+                        NAMES[rng.gen_range(0..NAMES.len())],
+                        random_value(rng, depth - 1),
+                    )
+                })
+                .collect()),
+        }
+    }
+
+    #[test]
+    fn test_decode_field_matches_get_field() {
+        use rand::SeedableRng;
+        let mut rng = StdRng::seed_from_u64(1954);
+        for _ in 0..5000 {
+            let value = random_value(&mut rng, 3);
+            let bytes = serde_cbor::to_vec(&value).unwrap();
+            let field_names: Vec<String> = (0..rng.gen_range(0..4))
+                .map(|_| NAMES[rng.gen_range(0..NAMES.len())].to_owned())
+                .collect();
+            assert_eq!(
+                decode_field(&bytes, &field_names).unwrap(),
+                value.get_field(&field_names).cloned(),
+                "{:?} of {:?}",
+                field_names,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_field_truncated() {
+        let bytes = serde_cbor::to_vec(&plain(json!({"a": {"b": 1}}))).unwrap();
+        assert!(decode_field(&bytes[..bytes.len() - 1], &["a".to_owned()]).is_err());
+    }
+
+    #[pg_test]
+    fn test_field_functions() {
+        let doc = "fs_doc('a', fs_doc('b', 1, 'c', 'x'), 'd', fs_arr(true))";
+        let get = |expression: &str| {
+            Spi::get_one::<FsValue>(&format!("SELECT {}", expression.replace("$doc", doc)))
+                .expect("SPI failed")
+        };
+        assert_eq!(get("fs_get_field($doc, 'a.b')"), Some(plain(json!(1))));
+        assert_eq!(get("$doc -> 'd'"), Some(plain(json!([true]))));
+        assert_eq!(get("$doc -> 'a' -> 'c'"), Some(plain(json!("x"))));
+        assert_eq!(get("$doc -> 'a.b'"), None);
+        assert_eq!(get("fs_get_field($doc, 'a.b.c')"), None);
+        assert_eq!(get("fs_get_field($doc, 'd.0')"), None);
+    }
+}
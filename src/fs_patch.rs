@@ -0,0 +1,354 @@
+use crate::fs_display::display_json;
+use crate::{check_json_depth, FsError, FsValue};
+use pgrx::prelude::*;
+use pgrx::JsonB;
+use serde_json::Value;
+
+// Errors of a single operation, reported with its index and path
+type StepResult<T> = std::result::Result<T, String>;
+
+// Splits a JSON Pointer (RFC 6901) into unescaped reference tokens
+fn parse_pointer(pointer: &str) -> StepResult<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let tokens = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| "a JSON Pointer must start with '/'".to_owned())?;
+    tokens
+        .split('/')
+        .map(|token| {
+            let mut unescaped = String::with_capacity(token.len());
+            let mut chars = token.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '~' => match chars.next() {
+                        Some('0') => unescaped.push('~'),
+                        Some('1') => unescaped.push('/'),
+                        _ => return Err(format!("invalid escape in token '{}'", token)),
+                    },
+                    c => unescaped.push(c),
+                }
+            }
+            Ok(unescaped)
+        })
+        .collect()
+}
+
+// The array position named by `token`. `-` and `len` itself name the end of
+// the array, which only adding to it accepts.
+fn array_index(token: &str, len: usize, allow_end: bool) -> StepResult<usize> {
+    let index = match token {
+        "-" => len,
+        _ if token.len() > 1 && token.starts_with('0') => {
+            return Err(format!("invalid array index '{}'", token))
+        }
+        _ => token
+            .parse::<usize>()
+            .map_err(|_| format!("invalid array index '{}'", token))?,
+    };
+    if index < len || (allow_end && index == len) {
+        Ok(index)
+    } else {
+        Err(format!("array index '{}' is out of bounds", token))
+    }
+}
+
+fn resolve<'a>(value: &'a FsValue, tokens: &[String]) -> StepResult<&'a FsValue> {
+    tokens.iter().try_fold(value, |value, token| match value {
+        FsValue::Map(map) => map
+            .get(token)
+            .ok_or_else(|| format!("field '{}' does not exist", token)),
+        FsValue::Array(array) => Ok(&array[array_index(token, array.len(), false)?]),
+        _ => Err(format!(
+            "cannot look up '{}' in a {} value",
+            token,
+            value.type_name()
+        )),
+    })
+}
+
+fn resolve_mut<'a>(value: &'a mut FsValue, tokens: &[String]) -> StepResult<&'a mut FsValue> {
+    tokens.iter().try_fold(value, |value, token| match value {
+        FsValue::Map(map) => map
+            .get_mut(token)
+            .ok_or_else(|| format!("field '{}' does not exist", token)),
+        FsValue::Array(array) => {
+            let index = array_index(token, array.len(), false)?;
+            Ok(&mut array[index])
+        }
+        _ => Err(format!(
+            "cannot look up '{}' in a {} value",
+            token,
+            value.type_name()
+        )),
+    })
+}
+
+fn add(document: &mut FsValue, tokens: &[String], value: FsValue) -> StepResult<()> {
+    let (last, parents) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+    match resolve_mut(document, parents)? {
+        FsValue::Map(map) => {
+            map.insert(last.to_owned(), value);
+        }
+        FsValue::Array(array) => {
+            let index = array_index(last, array.len(), true)?;
+            array.insert(index, value);
+        }
+        parent => {
+            return Err(format!(
+                "cannot add '{}' to a {} value",
+                last,
+                parent.type_name()
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn remove(document: &mut FsValue, tokens: &[String]) -> StepResult<FsValue> {
+    let (last, parents) = tokens
+        .split_last()
+        .ok_or_else(|| "cannot remove the whole document".to_owned())?;
+    match resolve_mut(document, parents)? {
+        FsValue::Map(map) => map
+            .remove(last)
+            .ok_or_else(|| format!("field '{}' does not exist", last)),
+        FsValue::Array(array) => {
+            let index = array_index(last, array.len(), false)?;
+            Ok(array.remove(index))
+        }
+        parent => Err(format!(
+            "cannot remove '{}' from a {} value",
+            last,
+            parent.type_name()
+        )),
+    }
+}
+
+fn operand<'a>(operation: &'a Value, name: &str) -> StepResult<&'a Value> {
+    operation
+        .get(name)
+        .ok_or_else(|| format!("missing '{}'", name))
+}
+
+fn pointer_operand(operation: &Value, name: &str) -> StepResult<Vec<String>> {
+    let pointer = operand(operation, name)?
+        .as_str()
+        .ok_or_else(|| format!("'{}' must be a string", name))?;
+    parse_pointer(pointer)
+}
+
+fn apply_operation(document: &mut FsValue, operation: &Value) -> StepResult<()> {
+    let op = operand(operation, "op")?
+        .as_str()
+        .ok_or_else(|| "'op' must be a string".to_owned())?;
+    let path = pointer_operand(operation, "path")?;
+    match op {
+        "add" => add(
+            document,
+            &path,
+            FsValue::from_plain_json(operand(operation, "value")?),
+        ),
+        "remove" => remove(document, &path).map(|_| ()),
+        "replace" => {
+            let value = FsValue::from_plain_json(operand(operation, "value")?);
+            *resolve_mut(document, &path)? = value;
+            Ok(())
+        }
+        "move" => {
+            let from = pointer_operand(operation, "from")?;
+            if path.len() > from.len() && path.starts_with(&from) {
+                return Err("cannot move a value into one of its children".to_owned());
+            }
+            let value = remove(document, &from)?;
+            add(document, &path, value)
+        }
+        "copy" => {
+            let from = pointer_operand(operation, "from")?;
+            let value = resolve(document, &from)?.to_owned();
+            add(document, &path, value)
+        }
+        "test" => {
+            let expected = FsValue::from_plain_json(operand(operation, "value")?);
+            if resolve(document, &path)? == &expected {
+                Ok(())
+            } else {
+                Err("value does not match".to_owned())
+            }
+        }
+        _ => Err(format!("unknown operation '{}'", op)),
+    }
+}
+
+// Applies the operations in order, failing on the first that fails with its
+// index and path in the error.
+fn apply_patch(document: FsValue, patch: &Value) -> Result<FsValue, FsError> {
+    check_json_depth(patch)?;
+    let operations = patch.as_array().ok_or_else(|| {
+        FsError::InvalidValue(format!(
+            "Expecting an array of patch operations but found {}",
+            display_json(patch)
+        ))
+    })?;
+    let mut document = document;
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(&mut document, operation).map_err(|reason| {
+            FsError::InvalidValue(format!(
+                "Patch operation {} failed at {}: {}",
+                index,
+                display_json(operation.get("path").unwrap_or(&Value::Null)),
+                reason
+            ))
+        })?;
+    }
+    Ok(document)
+}
+
+// Applies a JSON Patch (RFC 6902) whose values are typed as plain JSON
+#[pg_extern(immutable, parallel_safe)]
+fn fs_apply_patch(fs_value: FsValue, patch: JsonB) -> FsValue {
+    match apply_patch(fs_value, &patch.0) {
+        Ok(patched) => patched,
+        Err(error) => error.report(),
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_patch::*;
+    use crate::fs_test_util::plain;
+    use serde_json::json;
+
+    fn patched(document: Value, patch: Value) -> Result<FsValue, String> {
+        apply_patch(FsValue::from_plain_json(&document), &patch).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn test_parse_pointer() {
+        assert_eq!(parse_pointer(""), Ok(vec![]));
+        assert_eq!(parse_pointer("/"), Ok(vec!["".to_owned()]));
+        assert_eq!(
+            parse_pointer("/a~1b/m~0n/~01"),
+            Ok(vec!["a/b".to_owned(), "m~n".to_owned(), "~1".to_owned()])
+        );
+        assert!(parse_pointer("a").is_err());
+        assert!(parse_pointer("/a~2").is_err());
+    }
+
+    #[test]
+    fn test_apply_patch_operations() {
+        let document = json!({"a": {"b": 1, "c": [1, 2]}, "d": "x"});
+        assert_eq!(
+            patched(
+                document.to_owned(),
+                json!([
+                    {"op": "add", "path": "/a/e", "value": {"f": null}},
+                    {"op": "add", "path": "/a/c/1", "value": 9},
+                    {"op": "add", "path": "/a/c/-", "value": true},
+                    {"op": "remove", "path": "/d"},
+                    {"op": "replace", "path": "/a/b", "value": "two"},
+                    {"op": "copy", "from": "/a/c", "path": "/copied"},
+                    {"op": "test", "path": "/copied/3", "value": true},
+                ])
+            ),
+            Ok(plain(json!({
+                "a": {"b": "two", "c": [1, 9, 2, true], "e": {"f": null}},
+                "copied": [1, 9, 2, true]
+            })))
+        );
+        // Move between branches, and replacing the whole document
+        assert_eq!(
+            patched(
+                document.to_owned(),
+                json!([{"op": "move", "from": "/a/c/0", "path": "/z"}])
+            ),
+            Ok(plain(json!({"a": {"b": 1, "c": [2]}, "d": "x", "z": 1})))
+        );
+        assert_eq!(
+            patched(
+                document.to_owned(),
+                json!([{"op": "replace", "path": "", "value": {"new": 1}}])
+            ),
+            Ok(plain(json!({"new": 1})))
+        );
+        // Escaped tokens
+        assert_eq!(
+            patched(
+                json!({"a/b": 1, "m~n": 2}),
+                json!([
+                    {"op": "remove", "path": "/a~1b"},
+                    {"op": "replace", "path": "/m~0n", "value": 3},
+                ])
+            ),
+            Ok(plain(json!({"m~n": 3})))
+        );
+    }
+
+    #[test]
+    fn test_apply_patch_errors() {
+        let document = json!({"a": {"b": 1, "c": [1, 2]}});
+        for (patch, error) in [
+            (
+                json!([{"op": "test", "path": "/a/b", "value": 1}, {"op": "test", "path": "/a/b", "value": 2}]),
+                "Patch operation 1 failed at \"/a/b\": value does not match",
+            ),
+            (
+                json!([{"op": "remove", "path": "/a/x"}]),
+                "Patch operation 0 failed at \"/a/x\": field 'x' does not exist",
+            ),
+            (
+                json!([{"op": "add", "path": "/x/y", "value": 1}]),
+                "Patch operation 0 failed at \"/x/y\": field 'x' does not exist",
+            ),
+            (
+                json!([{"op": "add", "path": "/a/c/3", "value": 1}]),
+                "Patch operation 0 failed at \"/a/c/3\": array index '3' is out of bounds",
+            ),
+            (
+                json!([{"op": "replace", "path": "/a/c/-", "value": 1}]),
+                "Patch operation 0 failed at \"/a/c/-\": array index '-' is out of bounds",
+            ),
+            (
+                json!([{"op": "move", "from": "/a", "path": "/a/d"}]),
+                "Patch operation 0 failed at \"/a/d\": cannot move a value into one of its children",
+            ),
+            (
+                json!([{"op": "copy", "path": "/a/d"}]),
+                "Patch operation 0 failed at \"/a/d\": missing 'from'",
+            ),
+        ] {
+            assert_eq!(
+                patched(document.to_owned(), patch),
+                Err(format!("InvalidValue: {}", error))
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_apply_patch() {
+        let document = FsValue::from_plain_json(&json!({"tags": ["a"]}));
+        assert_eq!(
+            fs_apply_patch(
+                document,
+                JsonB(json!([{"op": "add", "path": "/tags/-", "value": "b"}]))
+            ),
+            FsValue::from_plain_json(&json!({"tags": ["a", "b"]}))
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Patch operation 0 failed at \"/n\": value does not match")]
+    fn test_fs_apply_patch_failing_test() {
+        fs_apply_patch(
+            FsValue::from_plain_json(&json!({"n": 1})),
+            JsonB(json!([{"op": "test", "path": "/n", "value": 2}])),
+        );
+    }
+}
@@ -0,0 +1,444 @@
+use crate::fs_documents::{fsvalue_arg, scan_documents, text_arg};
+use crate::fs_field_path::quote_field_name;
+use crate::FsError;
+use crate::{FieldPath, FsValue};
+use pgrx::prelude::*;
+use pgrx::JsonB;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// Scans at most `limit` documents of a collection, or of a collection group
+// when `parent` is None, in reference order.
+fn scan_collection<F>(parent: Option<FsValue>, collection_id: &str, limit: Option<i64>, visit: F)
+where
+    F: FnMut(FsValue, FsValue),
+{
+    let limit = limit.map_or(String::new(), |limit| format!(" LIMIT {}", limit));
+    match parent {
+        Some(parent) => scan_documents(
+            &format!(
+                "SELECT reference, properties FROM fs_documents \
+                 WHERE fs_parent(reference) = $1 AND fs_collection_id(reference) = $2 \
+                 ORDER BY reference{}",
+                limit
+            ),
+            vec![fsvalue_arg(parent), text_arg(collection_id)],
+            visit,
+        ),
+        None => scan_documents(
+            &format!(
+                "SELECT reference, properties FROM fs_documents \
+                 WHERE fs_collection_id(reference) = $1 \
+                 ORDER BY reference{}",
+                limit
+            ),
+            vec![text_arg(collection_id)],
+            visit,
+        ),
+    }
+}
+
+// Reservoir sampling (Algorithm R) in a single pass over the collection
+fn sample(
+    parent: Option<FsValue>,
+    collection_id: &str,
+    n: i32,
+    seed: Option<i64>,
+) -> Vec<(FsValue, FsValue)> {
+    if n < 0 {
+        FsError::InvalidValue(format!("Sample size must not be negative but found {}", n)).report()
+    }
+    let n = n as usize;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed as u64),
+        None => StdRng::from_entropy(),
+    };
+    // `n` may be far larger than the collection, so the reservoir grows as
+    // documents come in rather than being reserved up front
+    let mut reservoir = Vec::new();
+    let mut seen = 0usize;
+    scan_collection(parent, collection_id, None, |reference, properties| {
+        if reservoir.len() < n {
+            reservoir.push((reference, properties));
+        } else {
+            let index = rng.gen_range(0..=seen);
+            if index < n {
+                reservoir[index] = (reference, properties);
+            }
+        }
+        seen += 1;
+    });
+    reservoir.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    reservoir
+}
+
+#[pg_extern]
+fn fs_sample(
+    parent: FsValue,
+    collection_id: &str,
+    n: i32,
+    seed: default!(Option<i64>, "NULL"),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(properties, FsValue))> {
+    TableIterator::new(sample(Some(parent), collection_id, n, seed).into_iter())
+}
+
+#[pg_extern]
+fn fs_sample_group(
+    collection_id: &str,
+    n: i32,
+    seed: default!(Option<i64>, "NULL"),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(properties, FsValue))> {
+    TableIterator::new(sample(None, collection_id, n, seed).into_iter())
+}
+
+#[derive(Default)]
+struct FieldStats {
+    type_counts: BTreeMap<&'static str, i64>,
+    present_in: i64,
+}
+
+// Records the type of `value` at `path` and walks into maps (`path.key`) and
+// array elements (`path[]`). Every occurrence is counted by type while
+// `present_in` counts each document at most once per path.
+fn collect_field_types(
+    path: &str,
+    value: &FsValue,
+    stats: &mut BTreeMap<String, FieldStats>,
+    seen: &mut Vec<String>,
+) {
+    let field_stats = stats.entry(path.to_owned()).or_default();
+    *field_stats
+        .type_counts
+        .entry(value.type_name())
+        .or_default() += 1;
+    if !seen.iter().any(|seen_path| seen_path == path) {
+        field_stats.present_in += 1;
+        seen.push(path.to_owned());
+    }
+    match value {
+        FsValue::Map(map) => {
+            for (key, child) in map.iter() {
+                let child_path = format!("{}.{}", path, quote_field_name(key));
+                collect_field_types(&child_path, child, stats, seen);
+            }
+        }
+        FsValue::Array(array) => {
+            let element_path = format!("{}[]", path);
+            for element in array.iter() {
+                collect_field_types(&element_path, element, stats, seen);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[pg_extern]
+fn fs_schema_infer(
+    parent: FsValue,
+    collection_id: &str,
+    sample_limit: default!(i32, 10000),
+) -> TableIterator<
+    'static,
+    (
+        name!(field_path, String),
+        name!(type_counts, JsonB),
+        name!(present_in, i64),
+        name!(total, i64),
+    ),
+> {
+    if sample_limit < 0 {
+        FsError::InvalidValue(format!(
+            "Sample limit must not be negative but found {}",
+            sample_limit
+        ))
+        .report()
+    }
+    let mut stats: BTreeMap<String, FieldStats> = BTreeMap::new();
+    let mut total = 0i64;
+    scan_collection(
+        Some(parent),
+        collection_id,
+        Some(sample_limit as i64),
+        |_, properties| {
+            total += 1;
+            let mut seen = Vec::new();
+            if let FsValue::Map(map) = properties {
+                for (key, value) in map.iter() {
+                    collect_field_types(&quote_field_name(key), value, &mut stats, &mut seen);
+                }
+            }
+        },
+    );
+    let rows: Vec<(String, JsonB, i64, i64)> = stats
+        .into_iter()
+        .map(|(path, field_stats)| {
+            (
+                path,
+                JsonB(json!(field_stats.type_counts)),
+                field_stats.present_in,
+                total,
+            )
+        })
+        .collect();
+    TableIterator::new(rows.into_iter())
+}
+
+// Counts the documents of a collection per value at `path`, largest groups
+// first. Values are grouped by their comparison order, so that 1 and 1.0 fall
+// into one group shown with the value of its first document in reference
+// order. Documents missing the field are counted under a NULL value, unlike
+// those holding a Firestore NULL.
+#[pg_extern]
+fn fs_group_by_field(
+    parent: FsValue,
+    collection_id: &str,
+    path: &str,
+) -> TableIterator<'static, (name!(value, Option<FsValue>), name!(count, i64))> {
+    let field_names = match FieldPath::from_str(path).and_then(FieldPath::into_field_names) {
+        Ok(field_names) => field_names,
+        Err(error) => error.report(),
+    };
+    let mut groups: BTreeMap<FsValue, i64> = BTreeMap::new();
+    let mut missing = 0i64;
+    scan_collection(
+        Some(parent),
+        collection_id,
+        None,
+        |_, properties| match properties.get_field(&field_names) {
+            Some(value) => *groups.entry(value.to_owned()).or_default() += 1,
+            None => missing += 1,
+        },
+    );
+    // The sort is stable, so ties stay in value order with missing last
+    let mut rows: Vec<(Option<FsValue>, i64)> = groups
+        .into_iter()
+        .map(|(value, count)| (Some(value), count))
+        .collect();
+    if missing > 0 {
+        rows.push((None, missing));
+    }
+    rows.sort_by_key(|(_, count)| Reverse(*count));
+    TableIterator::new(rows.into_iter())
+}
+
+// The parent documents with documents in a collection group, the database
+// root for top-level collections, with the most children first
+#[pg_extern]
+fn fs_collection_group_parents(
+    collection_id: &str,
+) -> TableIterator<'static, (name!(parent, FsValue), name!(child_count, i64))> {
+    let rows = Spi::connect(|client| {
+        let mut rows = Vec::new();
+        for row in client.select(
+            "SELECT fs_parent(reference), count(*) FROM fs_documents \
+             WHERE fs_collection_id(reference) = $1 \
+             GROUP BY 1 ORDER BY 2 DESC, 1",
+            None,
+            Some(vec![text_arg(collection_id)]),
+        )? {
+            rows.push((
+                row.get::<FsValue>(1)?.expect("parent must not be null"),
+                row.get::<i64>(2)?.expect("count must not be null"),
+            ));
+        }
+        Ok::<_, pgrx::spi::Error>(rows)
+    })
+    .expect("Failed to read from fs_documents");
+    TableIterator::new(rows.into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_profiling::*;
+    use crate::{
+        fs_database_root, fs_null, fs_number_from_integer, fs_reference, fs_reference_text,
+        fs_string,
+    };
+
+    fn sampled(parent: FsValue, collection_id: &str, n: i32, seed: Option<i64>) -> Vec<String> {
+        fs_sample(parent, collection_id, n, seed)
+            .map(|(reference, _)| fs_reference_text(reference))
+            .collect()
+    }
+
+    #[pg_test]
+    fn test_fs_sample_smaller_collection() {
+        assert_eq!(
+            sampled(fs_database_root(), "users", 10, None),
+            vec!["/users/1", "/users/2", "/users/3", "/users/4", "/users/5"]
+        );
+        assert_eq!(
+            sampled(fs_reference("/users/1"), "posts", 10, None),
+            vec!["/users/1/posts/1", "/users/1/posts/2"]
+        );
+        assert_eq!(sampled(fs_database_root(), "users", 0, None).len(), 0);
+        assert_eq!(fs_sample_group("posts", 10, None).count(), 4);
+        assert_eq!(sampled(fs_database_root(), "users", 3, None).len(), 3);
+        assert_eq!(
+            sampled(fs_database_root(), "users", i32::MAX, Some(1)).len(),
+            5
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_sample(fs_database_root(), 'users', 2000000000)"
+            ),
+            Ok(Some(5))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_sample_uniform() {
+        // Each of the 5 seeded users is expected 200 times in 1000 draws of 1
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for _ in 0..1000 {
+            for reference in sampled(fs_database_root(), "users", 1, None) {
+                *counts.entry(reference).or_default() += 1;
+            }
+        }
+        assert_eq!(counts.len(), 5);
+        for (reference, count) in counts {
+            assert!(
+                (130..=270).contains(&count),
+                "{} sampled {} times out of 1000",
+                reference,
+                count
+            );
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_sample_seeded() {
+        let first = sampled(fs_database_root(), "users", 2, Some(42));
+        assert_eq!(first.len(), 2);
+        for _ in 0..5 {
+            assert_eq!(sampled(fs_database_root(), "users", 2, Some(42)), first);
+        }
+        assert_eq!(
+            fs_sample_group("posts", 2, Some(7)).collect::<Vec<_>>(),
+            fs_sample_group("posts", 2, Some(7)).collect::<Vec<_>>()
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_schema_infer() {
+        Spi::run(
+            r#"
+            SELECT fs_set(fs_reference('/things/1'), '{"type": "MAP", "value": {
+                "v": {"type": "NUMBER", "value": 1},
+                "tags": {"type": "ARRAY", "value": [{"type": "STRING", "value": "a"}, {"type": "NUMBER", "value": 2}]}
+            }}'::fsvalue);
+            SELECT fs_set(fs_reference('/things/2'), '{"type": "MAP", "value": {
+                "v": {"type": "STRING", "value": "one"},
+                "nested": {"type": "MAP", "value": {"a.b": {"type": "BOOLEAN", "value": true}}}
+            }}'::fsvalue);
+            SELECT fs_set(fs_reference('/things/3'), '{"type": "MAP", "value": {
+                "v": {"type": "NUMBER", "value": 3}
+            }}'::fsvalue);
+            "#,
+        )
+        .expect("SPI failed");
+
+        let rows: Vec<(String, serde_json::Value, i64, i64)> =
+            fs_schema_infer(fs_database_root(), "things", 10000)
+                .map(|(path, type_counts, present_in, total)| {
+                    (path, type_counts.0, present_in, total)
+                })
+                .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("nested".to_owned(), json!({"MAP": 1}), 1, 3),
+                ("nested.`a.b`".to_owned(), json!({"BOOLEAN": 1}), 1, 3),
+                ("tags".to_owned(), json!({"ARRAY": 1}), 1, 3),
+                ("tags[]".to_owned(), json!({"NUMBER": 1, "STRING": 1}), 1, 3),
+                ("v".to_owned(), json!({"NUMBER": 2, "STRING": 1}), 3, 3),
+            ]
+        );
+
+        let limited: Vec<(String, i64)> = fs_schema_infer(fs_database_root(), "things", 1)
+            .map(|(path, _, _, total)| (path, total))
+            .collect();
+        assert_eq!(
+            limited,
+            vec![
+                ("tags".to_owned(), 1),
+                ("tags[]".to_owned(), 1),
+                ("v".to_owned(), 1)
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_collection_group_parents() {
+        let parents = |collection_id: &str| {
+            fs_collection_group_parents(collection_id)
+                .map(|(parent, child_count)| (fs_reference_text(parent), child_count))
+                .collect::<Vec<_>>()
+        };
+        // Ties are in reference order, where the root comes first
+        assert_eq!(
+            parents("posts"),
+            vec![("/".to_owned(), 2), ("/users/1".to_owned(), 2)]
+        );
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/users/2/posts/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])), \
+                 (fs_reference('/users/1/posts/3'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            parents("posts"),
+            vec![
+                ("/users/1".to_owned(), 3),
+                ("/".to_owned(), 2),
+                ("/users/2".to_owned(), 1)
+            ]
+        );
+        assert!(parents("unknown").is_empty());
+    }
+
+    #[pg_test]
+    fn test_fs_group_by_field() {
+        // Odd documents hold the integer 1 and even ones the double 1.0
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             SELECT fs_reference('/stats/' || i), CASE \
+                 WHEN i <= 30 AND i % 2 = 1 THEN fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(1)]) \
+                 WHEN i <= 30 THEN fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_double(1.0)]) \
+                 WHEN i <= 40 THEN fs_map_from_entries(ARRAY['n', 'm'], ARRAY[fs_number_from_integer(2), fs_map_from_entries(ARRAY['k'], ARRAY[fs_string('x')])]) \
+                 WHEN i <= 45 THEN fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]) \
+                 ELSE fs_map_from_entries(ARRAY['n'], ARRAY[fs_null()]) \
+             END \
+             FROM generate_series(1, 50) i",
+        )
+        .expect("SPI failed");
+
+        assert_eq!(
+            fs_group_by_field(fs_database_root(), "stats", "n").collect::<Vec<_>>(),
+            vec![
+                (Some(fs_number_from_integer(1)), 30),
+                (Some(fs_number_from_integer(2)), 10),
+                (Some(fs_null()), 5),
+                (None, 5),
+            ]
+        );
+        assert_eq!(
+            fs_group_by_field(fs_database_root(), "stats", "m.k").collect::<Vec<_>>(),
+            vec![(None, 40), (Some(fs_string("x")), 10)]
+        );
+        assert_eq!(
+            fs_group_by_field(fs_reference("/users/1"), "stats", "n").count(),
+            0
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Sample size must not be negative but found -1")]
+    fn test_fs_sample_negative() {
+        fs_sample(fs_database_root(), "users", -1, None);
+    }
+}
@@ -0,0 +1,1970 @@
+use crate::fs_activity;
+use crate::fs_display::display_json;
+use crate::fs_documents::{fsvalue_arg, jsonb_arg, text_arg};
+use crate::fs_error::{report, FsCode};
+use crate::fs_rest::{from_rest_value, to_rest_value, DEFAULT_DATABASE};
+use crate::{
+    fs_eq, fs_ge, fs_gt, fs_is_nan, fs_is_not_nan, fs_is_not_null, fs_is_null, fs_le, fs_lt,
+    fs_neq, FieldPath, FsError, FsReference, FsValue,
+};
+use pgrx::prelude::*;
+use pgrx::{JsonB, PgOid};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::fmt;
+
+// (reference, properties, create_time, update_time) of a matching document
+type QueryRow = (
+    FsValue,
+    FsValue,
+    TimestampWithTimeZone,
+    TimestampWithTimeZone,
+);
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// Structured queries in the JSON format of the Firestore REST API
+// (google.firestore.v1.StructuredQuery). A query is planned into a SQL
+// statement over fs_documents carrying every filter that has an equivalent
+// fsvalue operator, while the remaining predicates are evaluated row by row in
+// Rust on the rows it returns.
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldReference {
+    // `__name__`, i.e. the document reference
+    DocumentName,
+    Field(Vec<String>),
+}
+
+impl FieldReference {
+    fn parse(value: Option<&Value>) -> Result<FieldReference> {
+        let field_path = value
+            .and_then(|field| field.get("fieldPath"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                FsError::InvalidValue(format!(
+                    "Expecting a field reference of the form {{\"fieldPath\": ...}} but found {}",
+                    display_json(value.unwrap_or(&Value::Null))
+                ))
+            })?;
+        if field_path == "__name__" {
+            return Ok(FieldReference::DocumentName);
+        }
+        Ok(FieldReference::Field(
+            FieldPath::from_str(field_path)?.into_field_names()?,
+        ))
+    }
+
+    fn sql_expression(&self) -> String {
+        match self {
+            FieldReference::DocumentName => "reference".to_string(),
+            FieldReference::Field(names) => {
+                names.iter().fold("properties".to_string(), |expr, name| {
+                    format!("{}->{}", expr, sql_literal(name))
+                })
+            }
+        }
+    }
+
+    // The expression as Postgres prints it back in an index definition
+    fn deparsed_expression(&self) -> String {
+        match self {
+            FieldReference::DocumentName => "(reference)".to_string(),
+            FieldReference::Field(names) => {
+                names.iter().fold("properties".to_string(), |expr, name| {
+                    format!("({} -> {}::text)", expr, sql_literal(name))
+                })
+            }
+        }
+    }
+
+    fn value_of<'a>(&self, reference: &'a FsValue, properties: &'a FsValue) -> Option<&'a FsValue> {
+        match self {
+            FieldReference::DocumentName => Some(reference),
+            FieldReference::Field(names) => properties.get_field(names),
+        }
+    }
+}
+
+impl fmt::Display for FieldReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldReference::DocumentName => write!(f, "__name__"),
+            FieldReference::Field(names) => write!(
+                f,
+                "{}",
+                FieldPath(
+                    names
+                        .iter()
+                        .cloned()
+                        .map(crate::PathSegment::Field)
+                        .collect()
+                )
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldOperator {
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    Equal,
+    NotEqual,
+    ArrayContains,
+    In,
+    ArrayContainsAny,
+    NotIn,
+}
+
+impl FieldOperator {
+    fn parse(op: &str) -> Result<FieldOperator> {
+        Ok(match op {
+            "LESS_THAN" => FieldOperator::LessThan,
+            "LESS_THAN_OR_EQUAL" => FieldOperator::LessThanOrEqual,
+            "GREATER_THAN" => FieldOperator::GreaterThan,
+            "GREATER_THAN_OR_EQUAL" => FieldOperator::GreaterThanOrEqual,
+            "EQUAL" => FieldOperator::Equal,
+            "NOT_EQUAL" => FieldOperator::NotEqual,
+            "ARRAY_CONTAINS" => FieldOperator::ArrayContains,
+            "IN" => FieldOperator::In,
+            "ARRAY_CONTAINS_ANY" => FieldOperator::ArrayContainsAny,
+            "NOT_IN" => FieldOperator::NotIn,
+            _ => {
+                return Err(FsError::InvalidValue(format!(
+                    "Unsupported field filter operator '{}'",
+                    op
+                )))
+            }
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldOperator::LessThan => "LESS_THAN",
+            FieldOperator::LessThanOrEqual => "LESS_THAN_OR_EQUAL",
+            FieldOperator::GreaterThan => "GREATER_THAN",
+            FieldOperator::GreaterThanOrEqual => "GREATER_THAN_OR_EQUAL",
+            FieldOperator::Equal => "EQUAL",
+            FieldOperator::NotEqual => "NOT_EQUAL",
+            FieldOperator::ArrayContains => "ARRAY_CONTAINS",
+            FieldOperator::In => "IN",
+            FieldOperator::ArrayContainsAny => "ARRAY_CONTAINS_ANY",
+            FieldOperator::NotIn => "NOT_IN",
+        }
+    }
+
+    // The fsvalue operator implementing the same semantics, if there is one
+    fn sql_operator(&self) -> Option<&'static str> {
+        match self {
+            FieldOperator::LessThan => Some("#<"),
+            FieldOperator::LessThanOrEqual => Some("#<="),
+            FieldOperator::GreaterThan => Some("#>"),
+            FieldOperator::GreaterThanOrEqual => Some("#>="),
+            FieldOperator::Equal => Some("#="),
+            FieldOperator::NotEqual => Some("#!="),
+            _ => None,
+        }
+    }
+
+    fn is_inequality(&self) -> bool {
+        matches!(
+            self,
+            FieldOperator::LessThan
+                | FieldOperator::LessThanOrEqual
+                | FieldOperator::GreaterThan
+                | FieldOperator::GreaterThanOrEqual
+                | FieldOperator::NotEqual
+                | FieldOperator::NotIn
+        )
+    }
+
+    fn matches(&self, field_value: &FsValue, value: &FsValue) -> bool {
+        let elements = || {
+            value
+                .as_array()
+                .map(|array| array.iter())
+                .into_iter()
+                .flatten()
+        };
+        match self {
+            FieldOperator::LessThan => fs_lt(field_value.to_owned(), value.to_owned()),
+            FieldOperator::LessThanOrEqual => fs_le(field_value.to_owned(), value.to_owned()),
+            FieldOperator::GreaterThan => fs_gt(field_value.to_owned(), value.to_owned()),
+            FieldOperator::GreaterThanOrEqual => fs_ge(field_value.to_owned(), value.to_owned()),
+            FieldOperator::Equal => fs_eq(field_value.to_owned(), value.to_owned()),
+            FieldOperator::NotEqual => fs_neq(field_value.to_owned(), value.to_owned()),
+            FieldOperator::ArrayContains => field_value
+                .as_array()
+                .is_some_and(|array| array.contains(value)),
+            FieldOperator::ArrayContainsAny => field_value
+                .as_array()
+                .is_some_and(|array| elements().any(|element| array.contains(element))),
+            FieldOperator::In => elements().any(|element| field_value == element),
+            FieldOperator::NotIn => {
+                elements().all(|element| fs_neq(field_value.to_owned(), element.to_owned()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnaryOperator {
+    Nan,
+    Null,
+    NotNan,
+    NotNull,
+}
+
+impl UnaryOperator {
+    fn parse(op: &str) -> Result<UnaryOperator> {
+        Ok(match op {
+            "IS_NAN" => UnaryOperator::Nan,
+            "IS_NULL" => UnaryOperator::Null,
+            "IS_NOT_NAN" => UnaryOperator::NotNan,
+            "IS_NOT_NULL" => UnaryOperator::NotNull,
+            _ => {
+                return Err(FsError::InvalidValue(format!(
+                    "Unsupported unary filter operator '{}'",
+                    op
+                )))
+            }
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            UnaryOperator::Nan => "IS_NAN",
+            UnaryOperator::Null => "IS_NULL",
+            UnaryOperator::NotNan => "IS_NOT_NAN",
+            UnaryOperator::NotNull => "IS_NOT_NULL",
+        }
+    }
+
+    fn sql_function(&self) -> &'static str {
+        match self {
+            UnaryOperator::Nan => "fs_is_nan",
+            UnaryOperator::Null => "fs_is_null",
+            UnaryOperator::NotNan => "fs_is_not_nan",
+            UnaryOperator::NotNull => "fs_is_not_null",
+        }
+    }
+
+    fn matches(&self, field_value: &FsValue) -> bool {
+        match self {
+            UnaryOperator::Nan => fs_is_nan(field_value.to_owned()),
+            UnaryOperator::Null => fs_is_null(field_value.to_owned()),
+            UnaryOperator::NotNan => fs_is_not_nan(field_value.to_owned()),
+            UnaryOperator::NotNull => fs_is_not_null(field_value.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Field {
+        field: FieldReference,
+        op: FieldOperator,
+        value: FsValue,
+    },
+    Unary {
+        field: FieldReference,
+        op: UnaryOperator,
+    },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    fn parse(value: &Value) -> Result<Filter> {
+        let invalid = || {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a filter",
+                display_json(value)
+            ))
+        };
+        if let Some(filter) = value.get("fieldFilter") {
+            let op = filter
+                .get("op")
+                .and_then(Value::as_str)
+                .ok_or_else(invalid)?;
+            Ok(Filter::Field {
+                field: FieldReference::parse(filter.get("field"))?,
+                op: FieldOperator::parse(op)?,
+                value: from_rest_value(filter.get("value").ok_or_else(invalid)?)?,
+            })
+        } else if let Some(filter) = value.get("unaryFilter") {
+            let op = filter
+                .get("op")
+                .and_then(Value::as_str)
+                .ok_or_else(invalid)?;
+            Ok(Filter::Unary {
+                field: FieldReference::parse(filter.get("field"))?,
+                op: UnaryOperator::parse(op)?,
+            })
+        } else if let Some(filter) = value.get("compositeFilter") {
+            let filters = filter
+                .get("filters")
+                .and_then(Value::as_array)
+                .ok_or_else(invalid)?
+                .iter()
+                .map(Filter::parse)
+                .collect::<Result<Vec<Filter>>>()?;
+            match filter.get("op").and_then(Value::as_str) {
+                Some("AND") => Ok(Filter::And(filters)),
+                Some("OR") => Ok(Filter::Or(filters)),
+                _ => Err(invalid()),
+            }
+        } else {
+            Err(invalid())
+        }
+    }
+
+    // The SQL condition for this filter, appending the values it binds to
+    // `params`, or None when part of it has to be evaluated in Rust.
+    fn to_sql(&self, params: &mut Vec<FsValue>) -> Option<String> {
+        match self {
+            Filter::Field { field, op, value } => {
+                let operator = op.sql_operator()?;
+                params.push(value.to_owned());
+                Some(format!(
+                    "{} {} ${}",
+                    field.sql_expression(),
+                    operator,
+                    params.len()
+                ))
+            }
+            Filter::Unary { field, op } => {
+                Some(format!("{}({})", op.sql_function(), field.sql_expression()))
+            }
+            Filter::And(filters) | Filter::Or(filters) => {
+                let separator = if matches!(self, Filter::And(_)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                let mut bound = params.to_owned();
+                let conditions = filters
+                    .iter()
+                    .map(|filter| filter.to_sql(&mut bound))
+                    .collect::<Option<Vec<String>>>()?;
+                *params = bound;
+                Some(format!("({})", conditions.join(separator)))
+            }
+        }
+    }
+
+    fn matches(&self, reference: &FsValue, properties: &FsValue) -> bool {
+        match self {
+            Filter::Field { field, op, value } => field
+                .value_of(reference, properties)
+                .is_some_and(|field_value| op.matches(field_value, value)),
+            Filter::Unary { field, op } => field
+                .value_of(reference, properties)
+                .is_some_and(|field_value| op.matches(field_value)),
+            Filter::And(filters) => filters
+                .iter()
+                .all(|filter| filter.matches(reference, properties)),
+            Filter::Or(filters) => filters
+                .iter()
+                .any(|filter| filter.matches(reference, properties)),
+        }
+    }
+
+    fn inequality_fields(&self, fields: &mut Vec<FieldReference>) {
+        match self {
+            Filter::Field { field, op, .. } if op.is_inequality() && !fields.contains(field) => {
+                fields.push(field.to_owned())
+            }
+            Filter::And(filters) | Filter::Or(filters) => filters
+                .iter()
+                .for_each(|filter| filter.inequality_fields(fields)),
+            _ => {}
+        }
+    }
+
+    fn conjuncts(self) -> Vec<Filter> {
+        match self {
+            Filter::And(filters) => filters.into_iter().flat_map(Filter::conjuncts).collect(),
+            filter => vec![filter],
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Field { field, op, value } => {
+                write!(f, "{} {} {}", field, op.name(), describe_value(value))
+            }
+            Filter::Unary { field, op } => write!(f, "{} {}", field, op.name()),
+            Filter::And(filters) | Filter::Or(filters) => {
+                let separator = if matches!(self, Filter::And(_)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                let filters: Vec<String> =
+                    filters.iter().map(|filter| filter.to_string()).collect();
+                write!(f, "({})", filters.join(separator))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Order {
+    field: FieldReference,
+    descending: bool,
+}
+
+impl Order {
+    fn direction(&self) -> &'static str {
+        if self.descending {
+            "DESC"
+        } else {
+            "ASC"
+        }
+    }
+
+    fn sql(&self) -> String {
+        format!("{} {}", self.field.sql_expression(), self.direction())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    values: Vec<FsValue>,
+    before: bool,
+}
+
+impl Cursor {
+    fn parse(value: &Value) -> Result<Cursor> {
+        let values = value
+            .get("values")
+            .and_then(Value::as_array)
+            .ok_or_else(|| {
+                FsError::InvalidValue(format!(
+                    "Failed to parse {} as a cursor",
+                    display_json(value)
+                ))
+            })?
+            .iter()
+            .map(from_rest_value)
+            .collect::<Result<Vec<FsValue>>>()?;
+        Ok(Cursor {
+            values,
+            before: value
+                .get("before")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+        })
+    }
+
+    // Compares a row's order by values with the cursor position, looking only
+    // at as many order by fields as the cursor has values.
+    fn compare(&self, order_by: &[Order], row: &[Option<&FsValue>]) -> Ordering {
+        for ((order, row_value), cursor_value) in order_by.iter().zip(row).zip(self.values.iter()) {
+            let ordering = match row_value {
+                Some(row_value) => (*row_value).cmp(cursor_value),
+                None => Ordering::Less,
+            };
+            let ordering = if order.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct StructuredQuery {
+    collection_id: String,
+    all_descendants: bool,
+    filter: Option<Filter>,
+    order_by: Vec<Order>,
+    start_at: Option<Cursor>,
+    end_at: Option<Cursor>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+fn parse_count(value: &Value, name: &str) -> Result<usize> {
+    // Int32Value wrappers may be spelled either as a number or as {"value": n}
+    value
+        .as_u64()
+        .or_else(|| value.get("value").and_then(Value::as_u64))
+        .map(|count| count as usize)
+        .ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a non-negative integer for '{}' but found {}",
+                name,
+                display_json(value)
+            ))
+        })
+}
+
+impl StructuredQuery {
+    fn parse(query: &Value) -> Result<StructuredQuery> {
+        let object = query.as_object().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a structured query object but found {}",
+                display_json(query)
+            ))
+        })?;
+        if let Some(key) = object.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "from" | "where" | "orderBy" | "startAt" | "endAt" | "offset" | "limit"
+            )
+        }) {
+            return Err(FsError::InvalidValue(format!(
+                "Unsupported structured query field '{}'",
+                key
+            )));
+        }
+        let from = match object.get("from").and_then(Value::as_array) {
+            Some(from) if from.len() == 1 => &from[0],
+            _ => {
+                return Err(FsError::InvalidValue(
+                    "Expecting exactly one collection selector in 'from'".to_string(),
+                ))
+            }
+        };
+        let collection_id = from
+            .get("collectionId")
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                FsError::InvalidValue(format!(
+                    "Expecting 'collectionId' in {}",
+                    display_json(from)
+                ))
+            })?;
+        let order_by = match object.get("orderBy") {
+            Some(Value::Array(orders)) => orders
+                .iter()
+                .map(|order| {
+                    Ok(Order {
+                        field: FieldReference::parse(order.get("field"))?,
+                        descending: order.get("direction").and_then(Value::as_str)
+                            == Some("DESCENDING"),
+                    })
+                })
+                .collect::<Result<Vec<Order>>>()?,
+            Some(other) => {
+                return Err(FsError::InvalidValue(format!(
+                    "Expecting an array for 'orderBy' but found {}",
+                    display_json(other)
+                )))
+            }
+            None => Vec::new(),
+        };
+        Ok(StructuredQuery {
+            collection_id: collection_id.to_owned(),
+            all_descendants: from
+                .get("allDescendants")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            filter: object.get("where").map(Filter::parse).transpose()?,
+            order_by,
+            start_at: object.get("startAt").map(Cursor::parse).transpose()?,
+            end_at: object.get("endAt").map(Cursor::parse).transpose()?,
+            offset: object
+                .get("offset")
+                .map(|offset| parse_count(offset, "offset"))
+                .transpose()?
+                .unwrap_or(0),
+            limit: object
+                .get("limit")
+                .map(|limit| parse_count(limit, "limit"))
+                .transpose()?,
+        })
+    }
+
+    // Explicit orderings, followed by inequality fields that are not ordered
+    // explicitly and finally by the document name, like Firestore.
+    fn effective_order_by(&self) -> Vec<Order> {
+        let mut order_by = self.order_by.to_owned();
+        let descending = order_by.last().is_some_and(|order| order.descending);
+        let mut inequality_fields = Vec::new();
+        if let Some(filter) = &self.filter {
+            filter.inequality_fields(&mut inequality_fields);
+        }
+        inequality_fields.sort_by_key(|field| field.to_string());
+        for field in inequality_fields {
+            if !order_by.iter().any(|order| order.field == field) {
+                order_by.push(Order { field, descending });
+            }
+        }
+        if !order_by
+            .iter()
+            .any(|order| order.field == FieldReference::DocumentName)
+        {
+            let descending = order_by.last().is_some_and(|order| order.descending);
+            order_by.push(Order {
+                field: FieldReference::DocumentName,
+                descending,
+            });
+        }
+        order_by
+    }
+}
+
+// A predicate that is evaluated in Rust on the rows returned by the SQL query
+enum PostFilter {
+    Descendant(FsReference),
+    Filter(Filter),
+}
+
+impl fmt::Display for PostFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostFilter::Descendant(parent) => write!(f, "__name__ is a descendant of {}", parent),
+            PostFilter::Filter(filter) => write!(f, "{}", filter),
+        }
+    }
+}
+
+// How the SQL statement returns rows in query order
+#[derive(Debug, PartialEq)]
+enum OrderStrategy {
+    // By reference alone, which the primary key serves
+    Reference,
+    // By the expression of this index, so that Postgres scans it instead of
+    // sorting every matching document
+    Index(String),
+    Sort,
+}
+
+// An ORDER BY that an expression index can serve, when Postgres prints the
+// expression of the index back as `index_expression`
+#[derive(Debug, PartialEq)]
+struct IndexOrdering {
+    index_expression: String,
+    order_by: String,
+}
+
+// Orderings by fields that an index may serve, from the best one on.
+// fs_order_by_key serves the whole ordering, while fs_sort_key of a single
+// field leaves documents with equal values to be sorted by reference.
+fn index_orderings(order_by: &[Order]) -> Vec<IndexOrdering> {
+    let mut keyed = order_by;
+    if let [rest @ .., previous, last] = order_by {
+        if last.field == FieldReference::DocumentName && last.descending == previous.descending {
+            keyed = &order_by[..rest.len() + 1];
+        }
+    }
+    if keyed
+        .iter()
+        .all(|order| order.field == FieldReference::DocumentName)
+    {
+        return vec![];
+    }
+    let paths: Vec<String> = keyed
+        .iter()
+        .map(|order| sql_literal(&order.field.to_string()))
+        .collect();
+    let descending: Vec<&str> = keyed
+        .iter()
+        .map(|order| if order.descending { "true" } else { "false" })
+        .collect();
+    let key = |paths: &[String]| {
+        format!(
+            "fs_order_by_key(reference, properties, ARRAY[{}], ARRAY[{}])",
+            paths.join(", "),
+            descending.join(", ")
+        )
+    };
+    let mut orderings = vec![IndexOrdering {
+        index_expression: key(&paths
+            .iter()
+            .map(|path| format!("{}::text", path))
+            .collect::<Vec<String>>()),
+        order_by: format!("{} ASC", key(&paths)),
+    }];
+    if let [order @ Order {
+        field: FieldReference::Field(_),
+        ..
+    }, rest @ ..] = order_by
+    {
+        if keyed.len() == 1 {
+            let path = sql_literal(&order.field.to_string());
+            orderings.push(IndexOrdering {
+                index_expression: format!("fs_sort_key(fs_get_field(properties, {}::text))", path),
+                order_by: std::iter::once(format!(
+                    "fs_sort_key(fs_get_field(properties, {})) {}",
+                    path,
+                    order.direction()
+                ))
+                .chain(rest.iter().map(Order::sql))
+                .collect::<Vec<String>>()
+                .join(", "),
+            });
+        }
+    }
+    orderings
+}
+
+// Expressions of the indexes on fs_documents that can return rows in their
+// order, i.e. valid btree indexes without a predicate on a single expression,
+// by index name
+fn expression_indexes() -> Vec<(String, String)> {
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT c.relname::text, pg_get_indexdef(i.indexrelid, 1, false) \
+                 FROM pg_index i \
+                 JOIN pg_class c ON c.oid = i.indexrelid \
+                 JOIN pg_am am ON am.oid = c.relam \
+                 WHERE i.indrelid = 'fs_documents'::regclass AND i.indnkeyatts = 1 \
+                     AND i.indkey[0] = 0 AND i.indisvalid AND i.indpred IS NULL \
+                     AND am.amname = 'btree' \
+                 ORDER BY c.relname",
+                None,
+                None,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.expect("index name must not be null"),
+                    row.get::<String>(2)?
+                        .expect("index expression must not be null"),
+                ))
+            })
+            .collect::<std::result::Result<Vec<(String, String)>, pgrx::spi::Error>>()
+    })
+    .expect("Failed to read index definitions")
+}
+
+// The first of `orderings` an index serves, with the name of that index. An
+// index expression is printed with the schema of its functions when that is
+// not on the search_path.
+fn find_index_ordering(
+    orderings: Vec<IndexOrdering>,
+    indexes: &[(String, String)],
+) -> Option<(String, IndexOrdering)> {
+    orderings.into_iter().find_map(|ordering| {
+        indexes
+            .iter()
+            .find(|(_, expression)| {
+                expression == &ordering.index_expression
+                    || expression.ends_with(&format!(".{}", ordering.index_expression))
+            })
+            .map(|(name, _)| (name.to_owned(), ordering))
+    })
+}
+
+struct QueryPlan {
+    sql: String,
+    params: Vec<FsValue>,
+    pushed_filters: Vec<Filter>,
+    post_filters: Vec<PostFilter>,
+    order_by: Vec<Order>,
+    order_strategy: OrderStrategy,
+    start_at: Option<Cursor>,
+    end_at: Option<Cursor>,
+    // OFFSET and LIMIT are applied in Rust when anything is evaluated there
+    offset: usize,
+    limit: Option<usize>,
+    limit_in_sql: bool,
+}
+
+// Quotes `text` as a SQL string literal the way quote_literal() does
+pub(crate) fn sql_literal(text: &str) -> String {
+    let quoted = text.replace('\'', "''");
+    if text.contains('\\') {
+        format!("E'{}'", quoted.replace('\\', "\\\\"))
+    } else {
+        format!("'{}'", quoted)
+    }
+}
+
+fn describe_value(value: &FsValue) -> String {
+    to_rest_value(value, DEFAULT_DATABASE)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| format!("{:?}", value))
+}
+
+fn plan_query(parent: &FsReference, query: StructuredQuery, include_deleted: bool) -> QueryPlan {
+    let order_by = query.effective_order_by();
+    let mut params = vec![FsValue::Reference(parent.to_owned())];
+    let mut conditions = Vec::new();
+    let mut post_filters = Vec::new();
+    let mut pushed_filters = Vec::new();
+
+    if query.all_descendants {
+        if !parent.is_root() {
+            post_filters.push(PostFilter::Descendant(parent.to_owned()));
+        }
+    } else {
+        conditions.push("fs_parent(reference) = $1".to_string());
+    }
+    conditions.push(format!(
+        "fs_collection_id(reference) = {}",
+        sql_literal(&query.collection_id)
+    ));
+    if !include_deleted {
+        conditions.push("deleted_at IS NULL".to_string());
+    }
+    for filter in query.filter.into_iter().flat_map(Filter::conjuncts) {
+        match filter.to_sql(&mut params) {
+            Some(condition) => {
+                conditions.push(condition);
+                pushed_filters.push(filter);
+            }
+            None => post_filters.push(PostFilter::Filter(filter)),
+        }
+    }
+    // Ordering by a field only matches documents where the field exists
+    for order in order_by.iter() {
+        if let FieldReference::Field(_) = order.field {
+            conditions.push(format!("{} IS NOT NULL", order.field.sql_expression()));
+        }
+    }
+
+    let orderings = index_orderings(&order_by);
+    let (order_strategy, order_sql) = if orderings.is_empty() {
+        (OrderStrategy::Reference, None)
+    } else {
+        match find_index_ordering(orderings, &expression_indexes()) {
+            Some((index, ordering)) => (OrderStrategy::Index(index), Some(ordering.order_by)),
+            None => (OrderStrategy::Sort, None),
+        }
+    };
+    let mut sql = format!(
+        "SELECT reference, properties, create_time, update_time FROM fs_documents WHERE {} ORDER BY {}",
+        conditions.join(" AND "),
+        order_sql.unwrap_or_else(|| order_by
+            .iter()
+            .map(Order::sql)
+            .collect::<Vec<String>>()
+            .join(", "))
+    );
+    let limit_in_sql =
+        post_filters.is_empty() && query.start_at.is_none() && query.end_at.is_none();
+    if limit_in_sql {
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {}", limit));
+        }
+        if query.offset > 0 {
+            sql.push_str(&format!(" OFFSET {}", query.offset));
+        }
+    }
+    QueryPlan {
+        sql,
+        params,
+        pushed_filters,
+        post_filters,
+        order_by,
+        order_strategy,
+        start_at: query.start_at,
+        end_at: query.end_at,
+        offset: query.offset,
+        limit: query.limit,
+        limit_in_sql,
+    }
+}
+
+impl QueryPlan {
+    fn sql_args(&self) -> Vec<(PgOid, Option<pg_sys::Datum>)> {
+        self.params
+            .iter()
+            .map(|param| fsvalue_arg(param.to_owned()))
+            .collect()
+    }
+
+    fn accepts(&self, reference: &FsValue, properties: &FsValue) -> bool {
+        let post_filtered = self
+            .post_filters
+            .iter()
+            .all(|post_filter| match post_filter {
+                PostFilter::Descendant(parent) => reference
+                    .as_reference()
+                    .is_some_and(|reference| parent.is_ancestor_of(reference)),
+                PostFilter::Filter(filter) => filter.matches(reference, properties),
+            });
+        if !post_filtered {
+            return false;
+        }
+        let row: Vec<Option<&FsValue>> = self
+            .order_by
+            .iter()
+            .map(|order| order.field.value_of(reference, properties))
+            .collect();
+        let after_start = self.start_at.as_ref().map_or(true, |cursor| {
+            match cursor.compare(&self.order_by, &row) {
+                Ordering::Greater => true,
+                Ordering::Equal => cursor.before,
+                Ordering::Less => false,
+            }
+        });
+        let before_end = self.end_at.as_ref().map_or(true, |cursor| {
+            match cursor.compare(&self.order_by, &row) {
+                Ordering::Less => true,
+                Ordering::Equal => !cursor.before,
+                Ordering::Greater => false,
+            }
+        });
+        after_start && before_end
+    }
+
+    fn execute(&self) -> Vec<QueryRow> {
+        let rows = Spi::connect(|client| {
+            client
+                .select(&self.sql, None, Some(self.sql_args()))?
+                .map(|row| {
+                    Ok((
+                        row.get::<FsValue>(1)?.expect("reference must not be null"),
+                        row.get::<FsValue>(2)?.unwrap_or(FsValue::NULL),
+                        row.get::<TimestampWithTimeZone>(3)?
+                            .expect("create_time must not be null"),
+                        row.get::<TimestampWithTimeZone>(4)?
+                            .expect("update_time must not be null"),
+                    ))
+                })
+                .collect::<std::result::Result<Vec<QueryRow>, pgrx::spi::Error>>()
+        })
+        .expect("Failed to run query");
+        let rows: Vec<QueryRow> = if self.limit_in_sql {
+            rows
+        } else {
+            rows.into_iter()
+                .filter(|(reference, properties, _, _)| self.accepts(reference, properties))
+                .skip(self.offset)
+                .take(self.limit.unwrap_or(usize::MAX))
+                .collect()
+        };
+        fs_activity::count_documents(
+            rows.iter()
+                .filter_map(|(reference, _, _, _)| reference.as_reference()),
+            true,
+        );
+        rows
+    }
+
+    // Index definitions on fs_documents that contain the expression of a
+    // pushed down filter
+    fn usable_indexes(&self) -> Vec<(String, String)> {
+        let indexes = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT indexname::text, indexdef FROM pg_indexes WHERE tablename = 'fs_documents'",
+                    None,
+                    None,
+                )?
+                .map(|row| Ok((row.get::<String>(1)?, row.get::<String>(2)?)))
+                .collect::<std::result::Result<Vec<(Option<String>, Option<String>)>, pgrx::spi::Error>>()
+        })
+        .expect("Failed to read index definitions");
+        let mut usable = Vec::new();
+        let mut fields = Vec::new();
+        for filter in self.pushed_filters.iter() {
+            collect_fields(filter, &mut fields);
+        }
+        for field in fields {
+            let expression = field.deparsed_expression();
+            for (name, definition) in indexes.iter() {
+                if let (Some(name), Some(definition)) = (name, definition) {
+                    if definition.contains(&expression) {
+                        usable.push((field.to_string(), name.to_owned()));
+                    }
+                }
+            }
+        }
+        usable
+    }
+
+    fn explain(&self, analyze: bool) -> Vec<String> {
+        let mut lines = vec![format!("SQL: {}", self.sql)];
+        for (index, param) in self.params.iter().enumerate() {
+            lines.push(format!(
+                "Parameter ${}: {}",
+                index + 1,
+                describe_value(param)
+            ));
+        }
+        for filter in self.pushed_filters.iter() {
+            lines.push(format!("Pushed down: {}", filter));
+        }
+        if self.post_filters.is_empty() && self.start_at.is_none() && self.end_at.is_none() {
+            lines.push("Post-filter: none".to_string());
+        }
+        for post_filter in self.post_filters.iter() {
+            lines.push(format!("Post-filter: {}", post_filter));
+        }
+        if let Some(cursor) = &self.start_at {
+            lines.push(format!(
+                "Post-filter: {} cursor at {}",
+                if cursor.before {
+                    "startAt"
+                } else {
+                    "startAfter"
+                },
+                describe_cursor(cursor)
+            ));
+        }
+        if let Some(cursor) = &self.end_at {
+            lines.push(format!(
+                "Post-filter: {} cursor at {}",
+                if cursor.before { "endBefore" } else { "endAt" },
+                describe_cursor(cursor)
+            ));
+        }
+        if !self.limit_in_sql && (self.limit.is_some() || self.offset > 0) {
+            lines.push("Post-filter: OFFSET and LIMIT applied after post-filtering".to_string());
+        }
+        lines.push(match &self.order_strategy {
+            OrderStrategy::Reference => "Order: by reference".to_string(),
+            OrderStrategy::Index(index) => format!("Order: index scan using {}", index),
+            OrderStrategy::Sort => "Order: sort, no index serves the ordering".to_string(),
+        });
+        let indexes = self.usable_indexes();
+        if indexes.is_empty() {
+            lines.push("Index: none usable by pushed down filters".to_string());
+        }
+        for (field, index) in indexes {
+            lines.push(format!("Index: {} may serve {}", index, field));
+        }
+        if analyze {
+            // Not read-only, which SPI takes any utility statement for
+            let plan = Spi::connect(|mut client| {
+                client
+                    .update(
+                        &format!("EXPLAIN (ANALYZE, BUFFERS) {}", self.sql),
+                        None,
+                        Some(self.sql_args()),
+                    )?
+                    .map(|row| row.get::<String>(1))
+                    .collect::<std::result::Result<Vec<Option<String>>, pgrx::spi::Error>>()
+            })
+            .expect("Failed to explain query");
+            lines.extend(
+                plan.into_iter()
+                    .flatten()
+                    .map(|line| format!("Plan: {}", line)),
+            );
+        }
+        lines
+    }
+}
+
+fn describe_cursor(cursor: &Cursor) -> String {
+    let values: Vec<String> = cursor.values.iter().map(describe_value).collect();
+    format!("[{}]", values.join(", "))
+}
+
+fn collect_fields(filter: &Filter, fields: &mut Vec<FieldReference>) {
+    match filter {
+        Filter::Field { field, .. } | Filter::Unary { field, .. } => {
+            if !fields.contains(field) {
+                fields.push(field.to_owned())
+            }
+        }
+        Filter::And(filters) | Filter::Or(filters) => filters
+            .iter()
+            .for_each(|filter| collect_fields(filter, fields)),
+    }
+}
+
+// A bound parameter value may be given in the REST format, recognized as an
+// object with a single "...Value" field, or as plain JSON.
+fn param_value(name: &str, value: &Value) -> Result<Value> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 && object.keys().all(|key| key.ends_with("Value")) => {
+            from_rest_value(value)
+                .map_err(|error| error.with_context(&format!("Parameter '{}'", name)))?;
+            Ok(value.to_owned())
+        }
+        _ => to_rest_value(&FsValue::from_plain_json(value), DEFAULT_DATABASE),
+    }
+}
+
+// The parameter name of a {"param": name} placeholder
+fn placeholder(value: &Value) -> Option<&str> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => object.get("param").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+// Replaces the placeholders in filter values and cursor values with the
+// parameters they name, recording which parameters were used.
+struct Binder<'a> {
+    params: &'a serde_json::Map<String, Value>,
+    used: Vec<String>,
+    missing: Vec<String>,
+}
+
+impl Binder<'_> {
+    fn bind_value(&mut self, value: &mut Value) -> Result<()> {
+        let name = match placeholder(value) {
+            Some(name) => name.to_owned(),
+            None => return Ok(()),
+        };
+        match self.params.get(&name) {
+            Some(param) => {
+                *value = param_value(&name, param)?;
+                if !self.used.contains(&name) {
+                    self.used.push(name);
+                }
+            }
+            None => {
+                if !self.missing.contains(&name) {
+                    self.missing.push(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_filter(&mut self, filter: &mut Value) -> Result<()> {
+        if let Some(value) = filter.pointer_mut("/fieldFilter/value") {
+            self.bind_value(value)?;
+        }
+        if let Some(Value::Array(filters)) = filter.pointer_mut("/compositeFilter/filters") {
+            for filter in filters.iter_mut() {
+                self.bind_filter(filter)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_cursor(&mut self, cursor: &mut Value) -> Result<()> {
+        if let Some(Value::Array(values)) = cursor.get_mut("values") {
+            for value in values.iter_mut() {
+                self.bind_value(value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Substitutes `params`, a JSON object mapping parameter names to values, for
+// the {"param": name} placeholders of a structured query. Every placeholder
+// must have a parameter and every parameter must be used.
+fn bind_params(query: &Value, params: Option<&Value>) -> Result<Value> {
+    let empty = serde_json::Map::new();
+    let params = match params {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(params)) => params,
+        Some(other) => {
+            return Err(FsError::InvalidValue(format!(
+                "Expecting a JSON object of query parameters but found {}",
+                display_json(other)
+            )))
+        }
+    };
+    let mut binder = Binder {
+        params,
+        used: Vec::new(),
+        missing: Vec::new(),
+    };
+    let mut query = query.to_owned();
+    if let Some(filter) = query.get_mut("where") {
+        binder.bind_filter(filter)?;
+    }
+    for cursor in ["startAt", "endAt"] {
+        if let Some(cursor) = query.get_mut(cursor) {
+            binder.bind_cursor(cursor)?;
+        }
+    }
+    if !binder.missing.is_empty() {
+        return Err(FsError::InvalidValue(format!(
+            "Missing query parameters: {}",
+            binder.missing.join(", ")
+        )));
+    }
+    let unused = params
+        .keys()
+        .filter(|name| !binder.used.contains(name))
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+    if !unused.is_empty() {
+        return Err(FsError::InvalidValue(format!(
+            "Unused query parameters: {}",
+            unused.join(", ")
+        )));
+    }
+    Ok(query)
+}
+
+fn expect_query_parent(parent: &FsValue) -> &FsReference {
+    let parent = parent.expect_reference();
+    if !parent.is_root() && !parent.has_complete_path() {
+        FsError::InvalidValue(format!(
+            "Expecting the database root or a document reference as parent but found {}",
+            parent
+        ))
+        .report()
+    }
+    parent
+}
+
+fn plan(
+    parent: &FsValue,
+    query: &Value,
+    params: Option<&Value>,
+    include_deleted: bool,
+) -> QueryPlan {
+    let parent = expect_query_parent(parent);
+    match bind_params(query, params).and_then(|query| StructuredQuery::parse(&query)) {
+        Ok(query) => plan_query(parent, query, include_deleted),
+        Err(error) => error.report(),
+    }
+}
+
+#[pg_extern]
+fn fs_run_query(
+    parent: FsValue,
+    query: JsonB,
+    include_deleted: default!(bool, false),
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(properties, FsValue))> {
+    let params = params.map(|params| params.0);
+    TableIterator::new(
+        plan(&parent, &query.0, params.as_ref(), include_deleted)
+            .execute()
+            .into_iter()
+            .map(|(reference, properties, _, _)| (reference, properties)),
+    )
+}
+
+// fs_run_query with the document metadata columns of fs_collection_v2
+#[pg_extern]
+fn fs_run_query_v2(
+    parent: FsValue,
+    query: JsonB,
+    include_deleted: default!(bool, false),
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(reference, FsValue),
+        name!(properties, FsValue),
+        name!(create_time, TimestampWithTimeZone),
+        name!(update_time, TimestampWithTimeZone),
+    ),
+> {
+    let params = params.map(|params| params.0);
+    TableIterator::new(
+        plan(&parent, &query.0, params.as_ref(), include_deleted)
+            .execute()
+            .into_iter(),
+    )
+}
+
+#[pg_extern]
+fn fs_explain_query(
+    parent: FsValue,
+    query: JsonB,
+    analyze: default!(bool, false),
+    include_deleted: default!(bool, false),
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<'static, (name!(line, String),)> {
+    let params = params.map(|params| params.0);
+    let lines = plan(&parent, &query.0, params.as_ref(), include_deleted).explain(analyze);
+    TableIterator::new(lines.into_iter().map(|line| (line,)))
+}
+
+// Stores a structured query under `name`, replacing any query stored under
+// that name before. Placeholders are left for fs_run_named_query to bind.
+#[pg_extern]
+fn fs_prepare_query(name: &str, parent: FsValue, query: JsonB) {
+    expect_query_parent(&parent);
+    // Parses the query with every placeholder bound to null to catch mistakes
+    // in its shape now rather than on first run
+    let mut names = Vec::new();
+    collect_placeholders(&query.0, &mut names);
+    let nulls = names
+        .into_iter()
+        .map(|name| (name, json!({ "nullValue": null })))
+        .collect::<serde_json::Map<String, Value>>();
+    if let Err(error) = bind_params(&query.0, Some(&Value::Object(nulls)))
+        .and_then(|query| StructuredQuery::parse(&query))
+    {
+        error.report()
+    }
+    Spi::run_with_args(
+        "INSERT INTO fs_named_queries (name, parent, query) VALUES ($1, $2, $3) \
+         ON CONFLICT (name) DO UPDATE SET parent = excluded.parent, query = excluded.query",
+        Some(vec![
+            text_arg(name),
+            fsvalue_arg(parent),
+            jsonb_arg(query.0),
+        ]),
+    )
+    .expect("Failed to write to fs_named_queries")
+}
+
+fn collect_placeholders(value: &Value, names: &mut Vec<String>) {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_placeholders(value, names)),
+        Value::Object(object) => match placeholder(value) {
+            Some(name) => {
+                if !names.iter().any(|known| known == name) {
+                    names.push(name.to_owned())
+                }
+            }
+            None => object
+                .values()
+                .for_each(|value| collect_placeholders(value, names)),
+        },
+        _ => {}
+    }
+}
+
+// Runs a query stored by fs_prepare_query with `params` bound to its
+// placeholders
+#[pg_extern]
+fn fs_run_named_query(
+    name: &str,
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(properties, FsValue))> {
+    let stored = Spi::connect(|client| {
+        let table = client.select(
+            "SELECT parent, query FROM fs_named_queries WHERE name = $1",
+            Some(1),
+            Some(vec![text_arg(name)]),
+        )?;
+        if table.is_empty() {
+            return Ok(None);
+        }
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>(Some((
+            row.get::<FsValue>(1)?.expect("parent must not be null"),
+            row.get::<JsonB>(2)?.expect("query must not be null").0,
+        )))
+    })
+    .expect("Failed to read from fs_named_queries");
+    let (parent, query) = match stored {
+        Some(stored) => stored,
+        None => report(
+            FsCode::NotFound,
+            format!("Query {} was not prepared by fs_prepare_query", name),
+        ),
+    };
+    let params = params.map(|params| params.0);
+    TableIterator::new(
+        plan(&parent, &query, params.as_ref(), false)
+            .execute()
+            .into_iter()
+            .map(|(reference, properties, _, _)| (reference, properties)),
+    )
+}
+
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_named_queries (\n\
+            name text PRIMARY KEY,\n\
+            parent fsvalue NOT NULL,\n\
+            query jsonb NOT NULL\n\
+        );\n\
+    ",
+    name = "named_queries_table",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_query::*;
+    use crate::fs_reference_text;
+    use crate::{fs_database_root, fs_reference};
+    use serde_json::json;
+
+    fn run(parent: FsValue, query: Value) -> Vec<String> {
+        fs_run_query(parent, JsonB(query), false, None)
+            .map(|(reference, _)| fs_reference_text(reference))
+            .collect()
+    }
+
+    fn run_with_params(query: Value, params: Value) -> Vec<String> {
+        fs_run_query(fs_database_root(), JsonB(query), false, Some(JsonB(params)))
+            .map(|(reference, _)| fs_reference_text(reference))
+            .collect()
+    }
+
+    fn explain(query: Value, analyze: bool) -> Vec<String> {
+        fs_explain_query(fs_database_root(), JsonB(query), analyze, false, None)
+            .map(|(line,)| line)
+            .collect()
+    }
+
+    fn users_where(filter: Value) -> Value {
+        json!({"from": [{"collectionId": "users"}], "where": filter})
+    }
+
+    fn foo_filter(op: &str, value: Value) -> Value {
+        json!({"fieldFilter": {"field": {"fieldPath": "foo"}, "op": op, "value": value}})
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = StructuredQuery::parse(&json!({
+            "from": [{"collectionId": "users", "allDescendants": true}],
+            "where": {"compositeFilter": {"op": "AND", "filters": [
+                {"fieldFilter": {"field": {"fieldPath": "a.`b.c`"}, "op": "GREATER_THAN", "value": {"integerValue": "1"}}},
+                {"unaryFilter": {"field": {"fieldPath": "d"}, "op": "IS_NULL"}},
+            ]}},
+            "orderBy": [{"field": {"fieldPath": "e"}, "direction": "DESCENDING"}],
+            "limit": {"value": 3},
+        }))
+        .unwrap();
+        assert!(query.all_descendants);
+        assert_eq!(query.limit, Some(3));
+        assert_eq!(
+            query
+                .effective_order_by()
+                .iter()
+                .map(|order| (order.field.to_string(), order.descending))
+                .collect::<Vec<_>>(),
+            vec![
+                ("e".to_string(), true),
+                ("a.`b.c`".to_string(), true),
+                ("__name__".to_string(), true)
+            ]
+        );
+        assert!(StructuredQuery::parse(&json!({"from": []})).is_err());
+        assert!(StructuredQuery::parse(&json!({
+            "from": [{"collectionId": "users"}],
+            "select": {"fields": []}
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let filter = Filter::parse(&json!({"compositeFilter": {"op": "OR", "filters": [
+            {"fieldFilter": {"field": {"fieldPath": "it's"}, "op": "EQUAL", "value": {"integerValue": "1"}}},
+            {"fieldFilter": {"field": {"fieldPath": "a.b"}, "op": "LESS_THAN", "value": {"integerValue": "2"}}},
+        ]}}))
+        .unwrap();
+        let mut params = vec![FsValue::NULL];
+        assert_eq!(
+            filter.to_sql(&mut params).unwrap(),
+            "(properties->'it''s' #= $2 OR properties->'a'->'b' #< $3)"
+        );
+        assert_eq!(params.len(), 3);
+        assert_eq!(sql_literal(r"a\b"), r"E'a\\b'");
+
+        let filter = Filter::parse(&json!({"compositeFilter": {"op": "OR", "filters": [
+            {"fieldFilter": {"field": {"fieldPath": "a"}, "op": "EQUAL", "value": {"integerValue": "1"}}},
+            {"fieldFilter": {"field": {"fieldPath": "b"}, "op": "ARRAY_CONTAINS", "value": {"integerValue": "2"}}},
+        ]}}))
+        .unwrap();
+        let mut params = vec![];
+        assert_eq!(filter.to_sql(&mut params), None);
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn test_index_orderings() {
+        let orderings = |order_by: Value| {
+            let query = StructuredQuery::parse(&json!({
+                "from": [{"collectionId": "users"}],
+                "orderBy": order_by,
+            }))
+            .unwrap();
+            index_orderings(&query.effective_order_by())
+                .into_iter()
+                .map(|ordering| (ordering.index_expression, ordering.order_by))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            orderings(json!([{"field": {"fieldPath": "foo"}, "direction": "DESCENDING"}])),
+            vec![
+                (
+                    "fs_order_by_key(reference, properties, ARRAY['foo'::text], ARRAY[true])"
+                        .to_string(),
+                    "fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true]) ASC"
+                        .to_string()
+                ),
+                (
+                    "fs_sort_key(fs_get_field(properties, 'foo'::text))".to_string(),
+                    "fs_sort_key(fs_get_field(properties, 'foo')) DESC, reference DESC".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            orderings(json!([
+                {"field": {"fieldPath": "a.`b.c`"}},
+                {"field": {"fieldPath": "__name__"}, "direction": "DESCENDING"},
+            ])),
+            vec![(
+                "fs_order_by_key(reference, properties, ARRAY['a.`b.c`'::text, '__name__'::text], \
+                 ARRAY[false, true])"
+                    .to_string(),
+                "fs_order_by_key(reference, properties, ARRAY['a.`b.c`', '__name__'], \
+                 ARRAY[false, true]) ASC"
+                    .to_string()
+            )]
+        );
+        // The primary key serves ordering by reference alone
+        assert_eq!(orderings(json!([])), vec![]);
+        assert_eq!(
+            orderings(json!([{"field": {"fieldPath": "__name__"}, "direction": "DESCENDING"}])),
+            vec![]
+        );
+
+        let index = |name: &str, expression: &str| (name.to_string(), expression.to_string());
+        let order_by = vec![
+            Order {
+                field: FieldReference::Field(vec!["foo".to_string()]),
+                descending: false,
+            },
+            Order {
+                field: FieldReference::DocumentName,
+                descending: false,
+            },
+        ];
+        let found = |indexes: &[(String, String)]| {
+            find_index_ordering(index_orderings(&order_by), indexes).map(|(name, _)| name)
+        };
+        assert_eq!(
+            found(&[
+                index(
+                    "by_bar",
+                    "fs_sort_key(fs_get_field(properties, 'bar'::text))"
+                ),
+                index(
+                    "by_foo",
+                    "fs_sort_key(fs_get_field(properties, 'foo'::text))"
+                ),
+                index(
+                    "by_foo_key",
+                    "app.fs_order_by_key(reference, properties, ARRAY['foo'::text], ARRAY[false])"
+                ),
+            ]),
+            Some("by_foo_key".to_string())
+        );
+        assert_eq!(
+            found(&[index(
+                "by_foo_bar",
+                "fs_order_by_key(reference, properties, ARRAY['foo'::text, 'bar'::text], \
+                 ARRAY[false, false])"
+            )]),
+            None
+        );
+        assert_eq!(
+            found(&[index(
+                "by_foo_descending",
+                "fs_order_by_key(reference, properties, ARRAY['foo'::text], ARRAY[true])"
+            )]),
+            None
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query() {
+        let query = json!({
+            "from": [{"collectionId": "users"}],
+            "where": foo_filter("GREATER_THAN_OR_EQUAL", json!({"integerValue": "2"})),
+            "orderBy": [{"field": {"fieldPath": "foo"}, "direction": "DESCENDING"}],
+            "limit": 2,
+        });
+        assert_eq!(run(fs_database_root(), query), vec!["/users/5", "/users/4"]);
+        assert_eq!(
+            run(
+                fs_reference("/users/1"),
+                json!({"from": [{"collectionId": "posts"}]})
+            ),
+            vec!["/users/1/posts/1", "/users/1/posts/2"]
+        );
+        assert_eq!(
+            run(
+                fs_database_root(),
+                json!({"from": [{"collectionId": "posts", "allDescendants": true}]})
+            ),
+            vec![
+                "/posts/1",
+                "/posts/2",
+                "/users/1/posts/1",
+                "/users/1/posts/2"
+            ]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_post_filters() {
+        let query = users_where(foo_filter(
+            "IN",
+            json!({"arrayValue": {"values": [{"integerValue": "3"}, {"integerValue": "5"}]}}),
+        ));
+        assert_eq!(run(fs_database_root(), query), vec!["/users/3", "/users/5"]);
+
+        let mut query = users_where(foo_filter(
+            "NOT_IN",
+            json!({"arrayValue": {"values": [{"integerValue": "3"}]}}),
+        ));
+        query["offset"] = json!(1);
+        query["limit"] = json!(2);
+        assert_eq!(run(fs_database_root(), query), vec!["/users/2", "/users/4"]);
+
+        assert_eq!(
+            run(
+                fs_reference("/users/1"),
+                json!({"from": [{"collectionId": "posts", "allDescendants": true}]})
+            ),
+            vec!["/users/1/posts/1", "/users/1/posts/2"]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_v2() {
+        Spi::run(
+            "UPDATE fs_documents SET create_time = '2000-01-01', update_time = '2000-01-02' \
+             WHERE reference = fs_reference('/users/4')",
+        )
+        .expect("SPI failed");
+        let query = users_where(foo_filter("GREATER_THAN", json!({"integerValue": "3"})));
+        let rows: Vec<_> =
+            fs_run_query_v2(fs_database_root(), JsonB(query.clone()), false, None).collect();
+        assert_eq!(
+            rows.iter()
+                .map(|(reference, ..)| fs_reference_text(reference.to_owned()))
+                .collect::<Vec<_>>(),
+            run(fs_database_root(), query)
+        );
+        let (_, _, create_time, update_time) = &rows[0];
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "SELECT $1 = '2000-01-01'::timestamptz AND $2 = '2000-01-02'::timestamptz",
+                vec![
+                    (
+                        PgBuiltInOids::TIMESTAMPTZOID.oid(),
+                        create_time.into_datum()
+                    ),
+                    (
+                        PgBuiltInOids::TIMESTAMPTZOID.oid(),
+                        update_time.into_datum()
+                    ),
+                ],
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_soft_deleted() {
+        Spi::run("SELECT fs_soft_delete(fs_reference('/users/5'))").expect("SPI failed");
+        let query = users_where(foo_filter("GREATER_THAN", json!({"integerValue": "3"})));
+        assert_eq!(run(fs_database_root(), query.to_owned()), vec!["/users/4"]);
+        assert_eq!(
+            fs_run_query(fs_database_root(), JsonB(query), true, None)
+                .map(|(reference, _)| fs_reference_text(reference))
+                .collect::<Vec<String>>(),
+            vec!["/users/4", "/users/5"]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_cursors() {
+        let query = json!({
+            "from": [{"collectionId": "users"}],
+            "orderBy": [{"field": {"fieldPath": "foo"}}],
+            "startAt": {"values": [{"integerValue": "2"}], "before": false},
+            "endAt": {"values": [{"integerValue": "4"}], "before": false},
+        });
+        assert_eq!(run(fs_database_root(), query), vec!["/users/3", "/users/4"]);
+    }
+
+    #[test]
+    fn test_bind_params() {
+        let query = json!({
+            "from": [{"collectionId": "users"}],
+            "where": {"compositeFilter": {"op": "AND", "filters": [
+                foo_filter("GREATER_THAN", json!({"param": "low"})),
+                foo_filter("LESS_THAN", json!({"param": "high"})),
+            ]}},
+            "startAt": {"values": [{"param": "low"}]},
+        });
+        let bound = bind_params(
+            &query,
+            Some(&json!({"low": {"integerValue": "1"}, "high": 4.5})),
+        )
+        .unwrap();
+        assert_eq!(
+            bound.pointer("/where/compositeFilter/filters/0/fieldFilter/value"),
+            Some(&json!({"integerValue": "1"}))
+        );
+        assert_eq!(
+            bound.pointer("/where/compositeFilter/filters/1/fieldFilter/value"),
+            Some(&json!({"doubleValue": 4.5}))
+        );
+        assert_eq!(
+            bound.pointer("/startAt/values/0"),
+            Some(&json!({"integerValue": "1"}))
+        );
+        // Plain objects are bound as maps
+        let bound = bind_params(
+            &users_where(foo_filter("EQUAL", json!({"param": "p"}))),
+            Some(&json!({"p": {"a": 1}})),
+        )
+        .unwrap();
+        assert_eq!(
+            from_rest_value(bound.pointer("/where/fieldFilter/value").unwrap()).unwrap(),
+            FsValue::from_plain_json(&json!({"a": 1}))
+        );
+        assert_eq!(
+            bind_params(&query, None).unwrap_err().to_string(),
+            "InvalidValue: Missing query parameters: low, high"
+        );
+        assert!(bind_params(&query, Some(&json!([1]))).is_err());
+        assert!(bind_params(
+            &query,
+            Some(&json!({"low": {"integerValue": "x"}, "high": 1}))
+        )
+        .is_err());
+    }
+
+    #[pg_test]
+    fn test_fs_run_named_query() {
+        fs_prepare_query(
+            "users_between",
+            fs_database_root(),
+            JsonB(json!({
+                "from": [{"collectionId": "users"}],
+                "where": {"compositeFilter": {"op": "AND", "filters": [
+                    foo_filter("GREATER_THAN_OR_EQUAL", json!({"param": "low"})),
+                    foo_filter("LESS_THAN", json!({"param": "high"})),
+                ]}},
+            })),
+        );
+        let run_named = |params: Value| {
+            fs_run_named_query("users_between", Some(JsonB(params)))
+                .map(|(reference, _)| fs_reference_text(reference))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            run_named(json!({"low": {"integerValue": "2"}, "high": 4})),
+            vec!["/users/2", "/users/3"]
+        );
+        assert_eq!(
+            run_named(json!({"low": 4, "high": {"integerValue": "10"}})),
+            vec!["/users/4", "/users/5"]
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Missing query parameters: high")]
+    fn test_fs_run_query_missing_param() {
+        run_with_params(
+            users_where(foo_filter("LESS_THAN", json!({"param": "high"}))),
+            json!({}),
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Unused query parameters: low")]
+    fn test_fs_run_query_unused_param() {
+        run_with_params(
+            users_where(foo_filter("LESS_THAN", json!({"param": "high"}))),
+            json!({"high": 3, "low": 1}),
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_cursor_param() {
+        let query = json!({
+            "from": [{"collectionId": "users"}],
+            "orderBy": [{"field": {"fieldPath": "foo"}}],
+            "startAt": {"values": [{"param": "after"}], "before": false},
+        });
+        assert_eq!(
+            run_with_params(query, json!({"after": 3})),
+            vec!["/users/4", "/users/5"]
+        );
+    }
+
+    #[pg_test(error = "Query missing was not prepared by fs_prepare_query")]
+    fn test_fs_run_named_query_not_prepared() {
+        fs_run_named_query("missing", None).for_each(drop);
+    }
+
+    #[pg_test]
+    fn test_fs_explain_query_pushdown() {
+        let lines = explain(
+            users_where(foo_filter("GREATER_THAN", json!({"integerValue": "2"}))),
+            false,
+        );
+        assert!(lines[0].starts_with(
+            "SQL: SELECT reference, properties, create_time, update_time FROM fs_documents"
+        ));
+        assert!(lines[0].contains("properties->'foo' #> $2"));
+        assert!(lines[0].contains("ORDER BY properties->'foo' ASC, reference ASC"));
+        assert!(lines.contains(&"Parameter $2: {\"integerValue\":\"2\"}".to_string()));
+        assert!(lines.contains(&"Post-filter: none".to_string()));
+        assert!(lines.contains(&"Index: none usable by pushed down filters".to_string()));
+    }
+
+    #[pg_test]
+    fn test_fs_explain_query_post_filter() {
+        let lines = explain(
+            users_where(foo_filter("ARRAY_CONTAINS", json!({"integerValue": "2"}))),
+            false,
+        );
+        assert!(!lines[0].contains("$2"));
+        assert!(
+            lines.contains(&"Post-filter: foo ARRAY_CONTAINS {\"integerValue\":\"2\"}".to_string())
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_explain_query_index() {
+        Spi::run("CREATE INDEX fs_documents_foo ON fs_documents ((properties->'foo'))")
+            .expect("SPI failed");
+        let lines = explain(
+            users_where(foo_filter("EQUAL", json!({"integerValue": "2"}))),
+            false,
+        );
+        assert!(lines.contains(&"Index: fs_documents_foo may serve foo".to_string()));
+    }
+
+    // Many more users than a page of them, so that sorting all of them reads
+    // far more than an index scan
+    fn write_many_users() {
+        Spi::run(
+            "SELECT fs_bulk_set(array_agg(fs_reference('/users/many' || i)), \
+                 array_agg(fs_map_from_entries(ARRAY['foo', 'bar'], \
+                     ARRAY[fs_number_from_integer(i % 100), fs_number_from_integer(i)]))) \
+             FROM generate_series(1, 5000) i; \
+             ANALYZE fs_documents",
+        )
+        .expect("SPI failed");
+    }
+
+    // Shared buffers read by the query, as EXPLAIN (ANALYZE, BUFFERS) reports
+    // them for the top plan node
+    fn buffers(query: Value) -> i64 {
+        let lines = explain(query, true);
+        let line = lines
+            .iter()
+            .find(|line| line.contains("Buffers: shared"))
+            .unwrap_or_else(|| panic!("No buffers in {:?}", lines));
+        line.split_whitespace()
+            .filter_map(|word| word.split_once('='))
+            .filter(|(name, _)| matches!(*name, "hit" | "read"))
+            .map(|(_, count)| count.parse::<i64>().expect("a buffer count"))
+            .sum()
+    }
+
+    fn foo_descending(limit: usize) -> Value {
+        json!({
+            "from": [{"collectionId": "users"}],
+            "orderBy": [{"field": {"fieldPath": "foo"}, "direction": "DESCENDING"}],
+            "limit": limit,
+        })
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_order_by_index() {
+        write_many_users();
+        let page = foo_descending(3);
+        let mut cursor = foo_descending(50);
+        cursor["startAt"] = json!({"values": [{"integerValue": "42"}], "before": true});
+        let sorted = (
+            run(fs_database_root(), page.to_owned()),
+            run(fs_database_root(), foo_descending(6000)),
+            run(fs_database_root(), cursor.to_owned()),
+        );
+        assert!(explain(page.to_owned(), false)
+            .contains(&"Order: sort, no index serves the ordering".to_string()));
+        let sorted_buffers = buffers(page.to_owned());
+
+        // The collection filters are estimated to match few documents, which
+        // makes a sort of all of them look cheap
+        Spi::run(
+            "CREATE INDEX fs_documents_foo_key ON fs_documents \
+             (fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true])); \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        let lines = explain(page.to_owned(), true);
+        assert!(lines[0].ends_with(
+            "ORDER BY fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true]) ASC LIMIT 3"
+        ));
+        assert!(lines.contains(&"Order: index scan using fs_documents_foo_key".to_string()));
+        assert!(
+            lines.iter().any(|line| line.starts_with("Plan: ")
+                && line.contains("Index Scan using fs_documents_foo_key")),
+            "{:?}",
+            lines
+        );
+        assert_eq!(
+            (
+                run(fs_database_root(), page.to_owned()),
+                run(fs_database_root(), foo_descending(6000)),
+                run(fs_database_root(), cursor),
+            ),
+            sorted
+        );
+        let indexed_buffers = buffers(page);
+        assert!(
+            indexed_buffers < 20 && indexed_buffers * 4 < sorted_buffers,
+            "{} buffers with the index and {} without",
+            indexed_buffers,
+            sorted_buffers
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_run_query_order_by_sort_key_index() {
+        let query = json!({
+            "from": [{"collectionId": "users"}],
+            "orderBy": [{"field": {"fieldPath": "foo"}}],
+        });
+        let sorted = run(fs_database_root(), query.to_owned());
+        Spi::run(
+            "CREATE INDEX fs_documents_foo_sort_key ON fs_documents \
+             (fs_sort_key(fs_get_field(properties, 'foo'))); \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        let lines = explain(query.to_owned(), true);
+        assert!(lines[0]
+            .ends_with("ORDER BY fs_sort_key(fs_get_field(properties, 'foo')) ASC, reference ASC"));
+        assert!(lines.contains(&"Order: index scan using fs_documents_foo_sort_key".to_string()));
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("Index Scan using fs_documents_foo_sort_key")));
+        assert_eq!(run(fs_database_root(), query), sorted);
+    }
+
+    #[pg_test]
+    fn test_fs_explain_query_order_by_other_index() {
+        Spi::run(
+            "CREATE INDEX fs_documents_bar_key ON fs_documents \
+             (fs_order_by_key(reference, properties, ARRAY['bar'], ARRAY[true])); \
+             CREATE INDEX fs_documents_foo_ascending_key ON fs_documents \
+             (fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[false])); \
+             CREATE INDEX fs_documents_foo_partial ON fs_documents \
+             (fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true])) \
+             WHERE deleted_at IS NULL",
+        )
+        .expect("SPI failed");
+        let lines = explain(foo_descending(3), false);
+        assert!(lines[0].contains("ORDER BY properties->'foo' DESC, reference DESC"));
+        assert!(lines.contains(&"Order: sort, no index serves the ordering".to_string()));
+        assert!(explain(json!({"from": [{"collectionId": "users"}]}), false)
+            .contains(&"Order: by reference".to_string()));
+    }
+
+    #[pg_test]
+    fn test_fs_explain_query_analyze() {
+        let lines = explain(
+            users_where(foo_filter("GREATER_THAN", json!({"integerValue": "2"}))),
+            true,
+        );
+        assert!(lines.iter().any(|line| line.starts_with("Plan: ")
+            && line.contains("actual")
+            && line.contains("rows=3")));
+    }
+}
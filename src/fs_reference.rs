@@ -1,9 +1,26 @@
-use crate::FsError;
-use regex::Regex;
+use crate::{fs_guc, FsError};
+use serde::de::{Deserializer, IgnoredAny, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::fmt;
+use std::marker::PhantomData;
 use std::str::FromStr;
 
+// References order like Firestore document keys: segment by segment, with a
+// shorter prefix first, and every ID compared as a string. A numeric ID is
+// only a storage optimization and compares as its decimal text, so /users/10
+// sorts before /users/9. A string ID sorts right before the numeric ID of the
+// same text, e.g. /users/%31 before /users/1, to stay consistent with Eq.
+// Comparisons do not allocate. FsValue compares two references with this
+// ordering directly, which is what the fs_documents primary key relies on,
+// and sorts compare them through ReferenceView without decoding them.
+//
+// Like Firestore IDs, comparisons are case-sensitive: /users/Bob and
+// /users/bob are different documents, and /users/Bob sorts first because
+// strings compare by their UTF-8 bytes. Equality and ordering must never fold
+// case or follow a collation, or distinct documents would collide in the
+// primary key. Case-insensitive matching is eq_ignore_ascii_case, which no
+// operator class uses.
 #[derive(Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord, Debug, Clone)]
 pub struct FsReference {
     pub path: FsPath,
@@ -22,22 +39,275 @@ pub struct PathElement {
     resource_id: Option<ResourceId>,
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, PartialOrd, Ord, Debug, Clone)]
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
 pub enum ResourceId {
     String(String),
     Number(i64),
 }
 
+// Shape of the IDs Firestore generates for documents added without an ID
+pub const AUTO_ID_ALPHABET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+pub const AUTO_ID_LENGTH: usize = 20;
+
+// Firestore's limit on the size of a collection ID or document ID
+pub const MAX_ID_BYTES: usize = 1500;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResourceIdKind {
+    Numeric,
+    Auto,
+    Custom,
+}
+
+impl fmt::Display for ResourceIdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceIdKind::Numeric => write!(f, "numeric"),
+            ResourceIdKind::Auto => write!(f, "auto"),
+            ResourceIdKind::Custom => write!(f, "custom"),
+        }
+    }
+}
+
+impl PathElement {
+    pub fn collection_id(&self) -> &str {
+        &self.collection_id
+    }
+
+    pub fn resource_id(&self) -> Option<&ResourceId> {
+        self.resource_id.as_ref()
+    }
+}
+
+impl ResourceId {
+    // Whether the ID has the shape of a Firestore auto ID. This is purely
+    // structural: an explicit ID of the same shape is indistinguishable.
+    pub fn is_auto_id(&self) -> bool {
+        match self {
+            ResourceId::String(id) => {
+                id.len() == AUTO_ID_LENGTH
+                    && id.bytes().all(|byte| AUTO_ID_ALPHABET.contains(&byte))
+            }
+            ResourceId::Number(_) => false,
+        }
+    }
+
+    pub fn kind(&self) -> ResourceIdKind {
+        match self {
+            ResourceId::Number(_) => ResourceIdKind::Numeric,
+            _ if self.is_auto_id() => ResourceIdKind::Auto,
+            _ => ResourceIdKind::Custom,
+        }
+    }
+
+    fn view(&self) -> IdView<'_> {
+        match self {
+            ResourceId::String(id) => IdView::String(id),
+            ResourceId::Number(id) => IdView::Number(*id),
+        }
+    }
+}
+
+// The decimal text of `number` at the end of `buffer`, which fits i64::MIN
+fn decimal_text(number: i64, buffer: &mut [u8; 20]) -> &[u8] {
+    let mut start = buffer.len();
+    let mut magnitude = number.unsigned_abs();
+    loop {
+        start -= 1;
+        buffer[start] = b'0' + (magnitude % 10) as u8;
+        magnitude /= 10;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    if number < 0 {
+        start -= 1;
+        buffer[start] = b'-';
+    }
+    &buffer[start..]
+}
+
+impl Ord for ResourceId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.view().cmp(&other.view())
+    }
+}
+
+impl PartialOrd for ResourceId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A serialized reference read without allocating, its IDs borrowed from the
+// bytes it is read from, which sorts compare straight from datums with (see
+// fs_ordering.rs). The views mirror the serde layout of FsReference, FsPath,
+// PathElement and ResourceId, and order like them.
+#[derive(Deserialize)]
+pub(crate) struct ReferenceView<'a> {
+    #[serde(borrow)]
+    path: PathView<'a>,
+}
+
+// Paths up to this long are read onto the stack, longer ones are skipped
+pub(crate) const VIEW_ELEMENTS: usize = 16;
+
+struct PathView<'a> {
+    elements: [ElementView<'a>; VIEW_ELEMENTS],
+    // None when the path is longer than VIEW_ELEMENTS
+    len: Option<usize>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ElementView<'a> {
+    collection_id: &'a str,
+    #[serde(borrow)]
+    resource_id: Option<IdView<'a>>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+enum IdView<'a> {
+    String(&'a str),
+    Number(i64),
+}
+
+impl ReferenceView<'_> {
+    // The order of the references, or None when either path is too long to
+    // have been read
+    pub(crate) fn compare(&self, other: &ReferenceView) -> Option<Ordering> {
+        Some(self.path.elements()?.cmp(other.path.elements()?))
+    }
+
+    // A key of the path elements before the last one, which all the children
+    // of a parent share, or None for the database root or a path too long to
+    // have been read
+    pub(crate) fn parent_key(&self) -> Option<Vec<u8>> {
+        let (_, parent) = self.path.elements()?.split_last()?;
+        let mut key = Vec::new();
+        for element in parent {
+            element.write_key(&mut key);
+        }
+        Some(key)
+    }
+
+    // The collection ID of the last path element, or None for the database
+    // root or a path too long to have been read
+    pub(crate) fn collection_id(&self) -> Option<&str> {
+        Some(self.path.elements()?.last()?.collection_id)
+    }
+}
+
+impl ElementView<'_> {
+    // Appends the element to a key, with its collection ID and string ID
+    // length-prefixed so that the elements of a key never run together
+    fn write_key(&self, key: &mut Vec<u8>) {
+        key.extend_from_slice(&self.collection_id.len().to_le_bytes());
+        key.extend_from_slice(self.collection_id.as_bytes());
+        match self.resource_id {
+            None => key.push(0),
+            Some(IdView::String(id)) => {
+                key.push(1);
+                key.extend_from_slice(&id.len().to_le_bytes());
+                key.extend_from_slice(id.as_bytes());
+            }
+            Some(IdView::Number(id)) => {
+                key.push(2);
+                key.extend_from_slice(&id.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl<'a> PathView<'a> {
+    fn elements(&self) -> Option<&[ElementView<'a>]> {
+        Some(&self.elements[..self.len?])
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for PathView<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_newtype_struct("FsPath", PathVisitor(PhantomData))
+    }
+}
+
+struct PathVisitor<'a>(PhantomData<&'a ()>);
+
+impl<'de: 'a, 'a> Visitor<'de> for PathVisitor<'a> {
+    type Value = PathView<'a>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a reference path")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut path = PathView {
+            elements: [ElementView::default(); VIEW_ELEMENTS],
+            len: Some(VIEW_ELEMENTS),
+        };
+        for len in 0..VIEW_ELEMENTS {
+            match seq.next_element()? {
+                Some(element) => path.elements[len] = element,
+                None => {
+                    path.len = Some(len);
+                    return Ok(path);
+                }
+            }
+        }
+        while seq.next_element::<IgnoredAny>()?.is_some() {
+            path.len = None;
+        }
+        Ok(path)
+    }
+}
+
+impl IdView<'_> {
+    // The bytes of the ID as text, a numeric ID written into `buffer`
+    fn text_bytes<'b>(&'b self, buffer: &'b mut [u8; 20]) -> &'b [u8] {
+        match self {
+            IdView::String(id) => id.as_bytes(),
+            IdView::Number(id) => decimal_text(*id, buffer),
+        }
+    }
+}
+
+impl Ord for IdView<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (mut lhs, mut rhs) = ([0; 20], [0; 20]);
+        self.text_bytes(&mut lhs)
+            .cmp(other.text_bytes(&mut rhs))
+            .then_with(|| {
+                matches!(self, IdView::Number(_)).cmp(&matches!(other, IdView::Number(_)))
+            })
+    }
+}
+
+impl PartialOrd for IdView<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl FromStr for FsReference {
     type Err = FsError;
 
+    // Parses the text form, e.g. `/users/1`, where segments may be escaped
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let re = Regex::new(r"\/([-\w\/\d]*)").unwrap();
-        let cap = re
-            .captures(s)
-            .expect(&format!("Failed to parse {} as a fs reference", s));
+        let path = s.strip_prefix('/').ok_or_else(|| {
+            FsError::InvalidValue("Expecting a reference path starting with '/'".to_owned())
+        })?;
         Ok(FsReference {
-            path: FsPath::from_str(&cap[1])?,
+            path: FsPath::from_str(path)?,
         })
     }
 }
@@ -57,7 +327,7 @@ impl FsReference {
         if self.path.0.is_empty() {
             true
         } else {
-            self.path.0.last().unwrap().resource_id.is_some()
+            self.resource_id().is_some()
         }
     }
 
@@ -69,20 +339,221 @@ impl FsReference {
         }
     }
 
+    pub fn child(&self, collection_id: &str, resource_id: &str) -> Result<FsReference, FsError> {
+        self.push_element(collection_id, Some(resource_id))
+    }
+
+    // The reference with a path element appended: a document when
+    // `resource_id` is given, a collection otherwise. A resource ID reading
+    // as an integer is numeric, as in the text form. The result is held to
+    // the limits of the text form, so that it always reads back.
+    pub fn push_element(
+        &self,
+        collection_id: &str,
+        resource_id: Option<&str>,
+    ) -> Result<FsReference, FsError> {
+        let mut path = self.path.clone();
+        path.push_element(
+            collection_id,
+            resource_id.map(ResourceId::from_str).transpose()?,
+        )
+        .and_then(|()| check_path_limits(&path.to_string()))
+        .map_err(|error| error.with_context(&format!("Cannot append to {}", self)))?;
+        Ok(FsReference { path })
+    }
+
+    pub fn last_element(&self) -> Option<&PathElement> {
+        self.path.0.last()
+    }
+
+    // The number of path elements, each a collection ID and possibly a
+    // resource ID, e.g. 2 for /users/1/posts
+    pub fn path_len(&self) -> usize {
+        self.path.0.len()
+    }
+
+    // The path element at `depth`, 0 being the root collection
+    pub fn element_at(&self, depth: usize) -> Option<&PathElement> {
+        self.path.0.get(depth)
+    }
+
+    // The resource ID of a document reference
+    pub fn resource_id(&self) -> Option<&ResourceId> {
+        self.last_element().and_then(PathElement::resource_id)
+    }
+
+    // The resource ID of a document reference, or why there is none
+    pub fn last_resource_id(&self) -> Result<&ResourceId, FsError> {
+        match self.last_element() {
+            None => Err(FsError::InvalidValue(
+                "The database root has no document ID".to_owned(),
+            )),
+            Some(element) => element.resource_id().ok_or_else(|| {
+                FsError::InvalidValue(format!(
+                    "{} is a collection reference and has no document ID",
+                    self
+                ))
+            }),
+        }
+    }
+
+    pub fn document_id(&self) -> Option<String> {
+        self.resource_id()
+            .map(|resource_id| resource_id.to_string())
+    }
+
+    // The unescaped segments, alternating collection IDs and resource IDs
+    pub fn segments(&self) -> Vec<String> {
+        let mut segments = Vec::new();
+        for element in self.path.0.iter() {
+            segments.push(element.collection_id.to_owned());
+            if let Some(resource_id) = &element.resource_id {
+                segments.push(resource_id.to_string());
+            }
+        }
+        segments
+    }
+
+    // The reference with the given unescaped segments, such as those of a
+    // REST resource name
+    pub fn from_segments(segments: &[&str]) -> Result<FsReference, FsError> {
+        let text = segments.join("/");
+        check_path_limits(&text)?;
+        Ok(FsReference {
+            path: FsPath::from_segments(&text, segments, |segment| Ok(segment.to_owned()))?,
+        })
+    }
+
+    // The collection ID of the first segment, None for the database root
+    pub fn root_collection_id(&self) -> Option<&str> {
+        self.path
+            .0
+            .first()
+            .map(|element| element.collection_id.as_str())
+    }
+
     // TODO(louiskuang): this method should return an option
     pub fn collection_id(&self) -> &str {
         assert_ne!(self, &FS_REFERENCE_ROOT);
         let last_segment = self.path.0.last().expect("expecting a non-empty path");
         &last_segment.collection_id
     }
+
+    // Equality with ASCII case folding of every segment. Other characters
+    // must be identical, and numeric IDs only equal numeric IDs.
+    pub fn eq_ignore_ascii_case(&self, other: &FsReference) -> bool {
+        self.path.0.len() == other.path.0.len()
+            && self
+                .path
+                .0
+                .iter()
+                .zip(other.path.0.iter())
+                .all(|(lhs, rhs)| lhs.eq_ignore_ascii_case(rhs))
+    }
+
+    // The documents above this reference from the root down, e.g. /a/1 and
+    // /a/1/b/2 for /a/1/b/2/c
+    pub fn ancestor_documents(&self) -> Vec<FsReference> {
+        (1..self.path.0.len())
+            .map(|len| FsReference {
+                path: FsPath(self.path.0[..len].to_vec()),
+            })
+            .collect()
+    }
+
+    // The reference with the collection ID of its `depth`th path element
+    // replaced, 0 being its root collection
+    pub fn with_collection_id(&self, depth: usize, collection_id: &str) -> FsReference {
+        let mut path = self.path.0.to_vec();
+        path[depth].collection_id = collection_id.to_owned();
+        FsReference { path: FsPath(path) }
+    }
+
+    // Whether `other` is below this reference by path structure alone: the
+    // root is above every other reference, a document above the paths
+    // extending it and a collection above its documents and theirs. No
+    // reference is its own ancestor.
+    pub fn is_ancestor_of(&self, other: &FsReference) -> bool {
+        let (ancestor, candidate) = (&self.path.0, &other.path.0);
+        match ancestor.split_last() {
+            None => !candidate.is_empty(),
+            Some((last, _)) if last.resource_id.is_some() => {
+                candidate.len() > ancestor.len() && candidate.starts_with(ancestor)
+            }
+            Some((last, parents)) => {
+                candidate.len() >= ancestor.len()
+                    && candidate.starts_with(parents)
+                    && candidate[parents.len()].collection_id == last.collection_id
+                    && candidate[parents.len()].resource_id.is_some()
+            }
+        }
+    }
+
+    // The smallest reference sorting after this one and all of its
+    // descendants, None for the root. The ordering keeps a reference and its
+    // descendants together, so they are exactly the references between it and
+    // its successor.
+    pub fn prefix_successor(&self) -> Option<FsReference> {
+        let mut path = self.path.0.to_vec();
+        let last = path.pop()?;
+        path.push(last.upper_bound());
+        Some(FsReference { path: FsPath(path) })
+    }
+
+    // Bounds such that R is a descendant of self iff lower < R < upper, where
+    // no upper bound (for the root) means every reference but the root. The
+    // descendants of a collection are its documents and their descendants.
+    pub fn descendant_range(&self) -> (FsReference, Option<FsReference>) {
+        (self.to_owned(), self.prefix_successor())
+    }
+}
+
+impl PathElement {
+    fn eq_ignore_ascii_case(&self, other: &PathElement) -> bool {
+        self.collection_id
+            .eq_ignore_ascii_case(&other.collection_id)
+            && match (&self.resource_id, &other.resource_id) {
+                (Some(ResourceId::String(lhs)), Some(ResourceId::String(rhs))) => {
+                    lhs.eq_ignore_ascii_case(rhs)
+                }
+                (lhs, rhs) => lhs == rhs,
+            }
+    }
+
+    // The smallest path element sorting after this one and after every path
+    // element that extends it: the string ID right after the text of its ID
+    // (the text followed by '\0'), or the collection right after this one for
+    // a collection.
+    fn upper_bound(&self) -> PathElement {
+        match &self.resource_id {
+            Some(id) => PathElement {
+                collection_id: self.collection_id.to_owned(),
+                resource_id: Some(ResourceId::String(format!("{}\0", id))),
+            },
+            None => PathElement {
+                collection_id: format!("{}\0", self.collection_id),
+                resource_id: None,
+            },
+        }
+    }
+}
+
+// The integer an ID stands for when it is written the way the integer is,
+// with no sign but a minus and no leading zero. Other IDs such as `007`, `+5`
+// or `-0` are strings, or they would collide with `7`, `5` and `0` and not
+// read back as written.
+fn canonical_integer(s: &str) -> Option<i64> {
+    s.parse::<i64>()
+        .ok()
+        .filter(|number| number.to_string() == s)
 }
 
 impl FromStr for ResourceId {
     type Err = FsError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Ok(s.parse::<i64>()
-            .map(|num| ResourceId::Number(num))
+        Ok(canonical_integer(s)
+            .map(ResourceId::Number)
             .unwrap_or(ResourceId::String(s.to_string())))
     }
 }
@@ -101,6 +572,170 @@ impl fmt::Display for FsPath {
     }
 }
 
+// The text form percent-encodes the UTF-8 bytes of the separator, the escape
+// character and control characters in a segment, so that any ID reads back
+// as itself. Whitespace at either end is encoded too so that it stays
+// visible. Common IDs of letters, digits, dashes and dots are left as is.
+fn escape_segment(segment: &str, escape_first: bool) -> String {
+    let mut escaped = String::with_capacity(segment.len());
+    for (index, c) in segment.char_indices() {
+        let at_edge = index == 0 || index + c.len_utf8() == segment.len();
+        if c == '/'
+            || c == '%'
+            || c.is_control()
+            || (at_edge && c.is_whitespace())
+            || (index == 0 && escape_first)
+        {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                escaped.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape_segment(segment: &str) -> Result<String, FsError> {
+    if !segment.contains('%') {
+        return Ok(segment.to_owned());
+    }
+    let invalid = || {
+        FsError::InvalidValue(format!(
+            "Invalid escape sequence in reference segment '{}'",
+            segment
+        ))
+    };
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+// Rejects paths over the configured limits before they are split, so that a
+// hostile input never turns into a huge FsPath.
+fn check_path_limits(s: &str) -> Result<(), FsError> {
+    let max_bytes = fs_guc::MAX_REFERENCE_BYTES.get() as usize;
+    if s.len() > max_bytes {
+        return Err(FsError::LimitExceeded(format!(
+            "Path of {} bytes exceeds the limit of {} bytes",
+            s.len(),
+            max_bytes
+        )));
+    }
+    // A collection and its document ID make up one level
+    let segments = s.bytes().filter(|byte| *byte == b'/').count() + 1;
+    let depth = (segments + 1) / 2;
+    let max_depth = fs_guc::MAX_REFERENCE_DEPTH.get() as usize;
+    if depth > max_depth {
+        return Err(FsError::LimitExceeded(format!(
+            "Path is {} levels deep, more than the limit of {}",
+            depth, max_depth
+        )));
+    }
+    Ok(())
+}
+
+// Whether Firestore reserves the ID: `.`, `..` and names like `__id__`
+pub fn is_reserved_id(id: &str) -> bool {
+    id == "." || id == ".." || (id.len() >= 4 && id.starts_with("__") && id.ends_with("__"))
+}
+
+// Checks an unescaped collection ID or document ID at the 1-based `position`
+// of its path. Any character is allowed otherwise, as in Firestore.
+fn check_segment(segment: &str, position: usize) -> Result<(), FsError> {
+    let problem = if segment.is_empty() {
+        "is empty".to_owned()
+    } else if is_reserved_id(segment) {
+        format!("'{}' is a reserved ID", segment)
+    } else if segment.len() > MAX_ID_BYTES {
+        format!(
+            "is {} bytes, more than the limit of {} bytes",
+            segment.len(),
+            MAX_ID_BYTES
+        )
+    } else {
+        return Ok(());
+    };
+    Err(FsError::InvalidValue(format!(
+        "Segment {} {}",
+        position, problem
+    )))
+}
+
+impl FsPath {
+    // The path of alternating collection IDs and resource IDs, where `parse`
+    // turns a segment into the ID it stands for. A resource ID written the
+    // way an integer is, without escapes, is numeric. `text` names the path in errors.
+    fn from_segments<F>(text: &str, segments: &[&str], parse: F) -> Result<FsPath, FsError>
+    where
+        F: Fn(&str) -> Result<String, FsError>,
+    {
+        let parse_at = |segment: &str, position: usize| {
+            let id = parse(segment)?;
+            check_segment(&id, position)
+                .map_err(|error| error.with_context(&format!("Invalid reference '/{}'", text)))?;
+            Ok::<String, FsError>(id)
+        };
+        segments
+            .chunks(2)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let resource_id = match chunk.get(1) {
+                    None => None,
+                    Some(segment) => Some(match canonical_integer(segment) {
+                        Some(number) => ResourceId::Number(number),
+                        None => ResourceId::String(parse_at(segment, 2 * index + 2)?),
+                    }),
+                };
+                Ok(PathElement {
+                    collection_id: parse_at(chunk[0], 2 * index + 1)?,
+                    resource_id,
+                })
+            })
+            .collect::<Result<Vec<PathElement>, FsError>>()
+            .map(FsPath)
+    }
+}
+
+impl FsPath {
+    // Appends an element to a path of documents, checking its IDs like
+    // parsing does
+    pub fn push_element(
+        &mut self,
+        collection_id: &str,
+        resource_id: Option<ResourceId>,
+    ) -> Result<(), FsError> {
+        if let Some(last) = self.0.last().filter(|last| last.resource_id.is_none()) {
+            return Err(FsError::InvalidValue(format!(
+                "Path ends in collection '{}' without a document ID",
+                last.collection_id
+            )));
+        }
+        let position = 2 * self.0.len() + 1;
+        check_segment(collection_id, position)?;
+        if let Some(ResourceId::String(id)) = &resource_id {
+            check_segment(id, position + 1)?;
+        }
+        self.0.push(PathElement {
+            collection_id: collection_id.to_owned(),
+            resource_id,
+        });
+        Ok(())
+    }
+}
+
 impl FromStr for FsPath {
     type Err = FsError;
 
@@ -108,32 +743,25 @@ impl FromStr for FsPath {
         if s.is_empty() {
             return Ok(FsPath(vec![]));
         }
-        let splits: Vec<&str> = s.split("/").collect();
-        let mut paths: Vec<PathElement> = Vec::new();
-        if splits.len() >= 2 {
-            for i in (0..splits.len()).step_by(2) {
-                paths.push(PathElement {
-                    collection_id: splits[i].to_owned(),
-                    resource_id: Some(ResourceId::from_str(splits[i + 1])?),
-                })
-            }
-        }
-        if splits.len() % 2 == 1 {
-            paths.push(PathElement {
-                collection_id: splits.last().unwrap().to_string(),
-                resource_id: None,
-            })
-        }
-        Ok(FsPath(paths))
+        check_path_limits(s)?;
+        let segments: Vec<&str> = s.split('/').collect();
+        FsPath::from_segments(s, &segments, unescape_segment)
     }
 }
 
 impl fmt::Display for PathElement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // write!(f, "{}", self.0.iter().map(|path_element| path_element.to_string()).join("/"))
+        write!(f, "{}", escape_segment(&self.collection_id, false))?;
         match &self.resource_id {
-            Some(resource_id) => write!(f, "{}/{}", self.collection_id, resource_id.to_string()),
-            None => write!(f, "{}", self.collection_id),
+            Some(ResourceId::Number(number)) => write!(f, "/{}", number),
+            // A string ID written like an integer escapes its first character
+            // so that it does not read back as a numeric ID
+            Some(ResourceId::String(id)) => write!(
+                f,
+                "/{}",
+                escape_segment(id, canonical_integer(id).is_some())
+            ),
+            None => Ok(()),
         }
     }
 }
@@ -190,6 +818,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_fs_path_limits() {
+        let path = |depth: usize| vec!["c/d"; depth].join("/");
+        assert_eq!(FsPath::from_str(&path(100)).unwrap().0.len(), 100);
+        assert_eq!(
+            FsPath::from_str(&format!("{}/c", path(100)))
+                .unwrap_err()
+                .to_string(),
+            "LimitExceeded: Path is 101 levels deep, more than the limit of 100"
+        );
+        assert_eq!(
+            FsPath::from_str(&path(101)).unwrap_err().to_string(),
+            "LimitExceeded: Path is 101 levels deep, more than the limit of 100"
+        );
+        assert_eq!(
+            FsReference::from_str(&format!("/users/{}", "a".repeat(6144)))
+                .unwrap_err()
+                .to_string(),
+            "LimitExceeded: Path of 6150 bytes exceeds the limit of 6144 bytes"
+        );
+    }
+
     #[test]
     fn test_fs_reference() {
         assert_eq!(
@@ -238,6 +888,493 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_document_id() {
+        assert_eq!(
+            FsReference::from_str("/users/1/posts/abc")
+                .unwrap()
+                .document_id(),
+            Some("abc".to_owned())
+        );
+        assert_eq!(FsReference::from_str("/users").unwrap().document_id(), None);
+        assert_eq!(FS_REFERENCE_ROOT.document_id(), None);
+    }
+
+    #[test]
+    fn test_only_canonical_integers_are_numeric() {
+        for (id, numeric) in [
+            ("7", Some(7)),
+            ("0", Some(0)),
+            ("-7", Some(-7)),
+            ("9223372036854775807", Some(i64::MAX)),
+            ("007", None),
+            ("+5", None),
+            ("-0", None),
+            ("00", None),
+            ("9223372036854775808", None),
+        ] {
+            let reference = parse(&format!("/users/{}", id));
+            let expected = match numeric {
+                Some(number) => ResourceId::Number(number),
+                None => ResourceId::String(id.to_owned()),
+            };
+            assert_eq!(reference.resource_id(), Some(&expected), "{}", id);
+            assert_eq!(reference.to_string(), format!("/users/{}", id));
+            assert_eq!(
+                FS_REFERENCE_ROOT.child("users", id).unwrap(),
+                reference,
+                "{}",
+                id
+            );
+            assert_eq!(
+                FsReference::from_segments(&["users", id]).unwrap(),
+                reference,
+                "{}",
+                id
+            );
+        }
+        // Distinct IDs stay distinct documents
+        assert_ne!(parse("/users/007"), parse("/users/7"));
+        assert_ne!(parse("/users/+5"), parse("/users/5"));
+        assert_ne!(parse("/users/-0"), parse("/users/0"));
+    }
+
+    #[test]
+    fn test_resource_id_kind() {
+        let kind = |path: &str| {
+            FsReference::from_str(path)
+                .unwrap()
+                .resource_id()
+                .map(ResourceId::kind)
+        };
+        assert_eq!(kind("/users/1"), Some(ResourceIdKind::Numeric));
+        assert_eq!(kind("/users/-7"), Some(ResourceIdKind::Numeric));
+        assert_eq!(
+            kind("/users/aZ3kQ9xLm2Np8RtVb4Wc"),
+            Some(ResourceIdKind::Auto)
+        );
+        // One character short, or a character outside the alphabet
+        assert_eq!(
+            kind("/users/aZ3kQ9xLm2Np8RtVb4W"),
+            Some(ResourceIdKind::Custom)
+        );
+        assert_eq!(
+            kind("/users/aZ3kQ9xLm2Np8RtVb4W-"),
+            Some(ResourceIdKind::Custom)
+        );
+        assert_eq!(kind("/users/jane.doe"), Some(ResourceIdKind::Custom));
+        assert_eq!(kind("/users"), None);
+
+        let reference = FsReference::from_str("/users/1/posts/abc").unwrap();
+        let element = reference.last_element().unwrap();
+        assert_eq!(element.collection_id(), "posts");
+        assert_eq!(
+            element.resource_id(),
+            Some(&ResourceId::String("abc".to_owned()))
+        );
+    }
+
+    // Segment by segment ordering spelled out: collection ids as strings, then
+    // resource ids as strings with a string id before the numeric id of the
+    // same text, then shorter paths first.
+    fn compare_segments(lhs: &FsReference, rhs: &FsReference) -> std::cmp::Ordering {
+        for (lhs, rhs) in lhs.path.0.iter().zip(rhs.path.0.iter()) {
+            let ordering = lhs
+                .collection_id
+                .as_str()
+                .cmp(rhs.collection_id.as_str())
+                .then_with(|| match (&lhs.resource_id, &rhs.resource_id) {
+                    (None, None) => std::cmp::Ordering::Equal,
+                    (None, Some(_)) => std::cmp::Ordering::Less,
+                    (Some(_), None) => std::cmp::Ordering::Greater,
+                    (Some(l), Some(r)) => l.to_string().cmp(&r.to_string()).then_with(|| {
+                        matches!(l, ResourceId::Number(_)).cmp(&matches!(r, ResourceId::Number(_)))
+                    }),
+                });
+            if ordering != std::cmp::Ordering::Equal {
+                return ordering;
+            }
+        }
+        lhs.path.0.len().cmp(&rhs.path.0.len())
+    }
+
+    fn random_reference(rng: &mut impl rand::Rng) -> FsReference {
+        let depth = rng.gen_range(1..=3);
+        let path = (0..depth)
+            .map(|_| PathElement {
+                collection_id: ["users", "posts", "u"][rng.gen_range(0..3)].to_owned(),
+                resource_id: if rng.gen_bool(0.5) {
+                    Some(ResourceId::Number(rng.gen_range(-3..30)))
+                } else {
+                    Some(ResourceId::String(
+                        ["a", "ab", "b", "1a", "10", "-"][rng.gen_range(0..6)].to_owned(),
+                    ))
+                },
+            })
+            .collect();
+        FsReference { path: FsPath(path) }
+    }
+
+    fn element(collection_id: &str, resource_id: Option<ResourceId>) -> PathElement {
+        PathElement {
+            collection_id: collection_id.to_owned(),
+            resource_id,
+        }
+    }
+
+    fn reference(path: Vec<PathElement>) -> FsReference {
+        FsReference { path: FsPath(path) }
+    }
+
+    fn string_id(id: &str) -> Option<ResourceId> {
+        Some(ResourceId::String(id.to_owned()))
+    }
+
+    fn in_range(ancestor: &FsReference, candidate: &FsReference) -> bool {
+        let (lower, upper) = ancestor.descendant_range();
+        &lower < candidate && upper.map_or(true, |upper| candidate < &upper)
+    }
+
+    #[test]
+    fn test_ancestor_documents() {
+        let ancestors = |reference: &str| {
+            FsReference::from_str(reference)
+                .unwrap()
+                .ancestor_documents()
+                .iter()
+                .map(FsReference::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ancestors("/a/1/b/2/c"), vec!["/a/1", "/a/1/b/2"]);
+        assert_eq!(ancestors("/a/1/b/2"), vec!["/a/1"]);
+        assert!(ancestors("/a/1").is_empty());
+        assert!(ancestors("/a").is_empty());
+        assert!(ancestors("/").is_empty());
+    }
+
+    #[test]
+    fn test_descendant_range_boundaries() {
+        assert_eq!(
+            FS_REFERENCE_ROOT.descendant_range(),
+            (FS_REFERENCE_ROOT, None)
+        );
+        assert!(in_range(
+            &FS_REFERENCE_ROOT,
+            &FsReference::from_str("/users").unwrap()
+        ));
+
+        let max = reference(vec![element("users", Some(ResourceId::Number(i64::MAX)))]);
+        assert_eq!(
+            max.descendant_range().1,
+            Some(reference(vec![element(
+                "users",
+                string_id("9223372036854775807\0")
+            )]))
+        );
+        assert!(in_range(
+            &max,
+            &reference(vec![
+                element("users", Some(ResourceId::Number(i64::MAX))),
+                element("posts", Some(ResourceId::Number(1))),
+            ])
+        ));
+        assert!(!in_range(&max, &max));
+        assert!(!in_range(
+            &max,
+            &reference(vec![element("users", string_id("a"))])
+        ));
+
+        let high = reference(vec![element("users", string_id("a\u{10FFFF}"))]);
+        assert_eq!(
+            high.descendant_range().1,
+            Some(reference(vec![element(
+                "users",
+                string_id("a\u{10FFFF}\0")
+            )]))
+        );
+        assert!(in_range(
+            &high,
+            &reference(vec![
+                element("users", string_id("a\u{10FFFF}")),
+                element("\u{10FFFF}", string_id("\u{10FFFF}")),
+            ])
+        ));
+        assert!(!in_range(
+            &high,
+            &reference(vec![element("users", string_id("a\u{10FFFF}\u{10FFFF}"))])
+        ));
+        assert!(!in_range(
+            &high,
+            &reference(vec![element("users", Some(ResourceId::Number(i64::MIN)))])
+        ));
+
+        let collection = FsReference::from_str("/users").unwrap();
+        assert!(in_range(
+            &collection,
+            &FsReference::from_str("/users/1/posts/a").unwrap()
+        ));
+        assert!(!in_range(
+            &collection,
+            &FsReference::from_str("/usersa/1").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_path_elements() {
+        let reference = parse("/users/1/posts");
+        assert_eq!(reference.path_len(), 2);
+        assert_eq!(FS_REFERENCE_ROOT.path_len(), 0);
+        assert_eq!(
+            reference.element_at(0),
+            Some(&element("users", Some(ResourceId::Number(1))))
+        );
+        assert_eq!(reference.element_at(1), Some(&element("posts", None)));
+        assert_eq!(reference.element_at(2), None);
+    }
+
+    #[test]
+    fn test_is_ancestor_of() {
+        let is_ancestor =
+            |ancestor: &str, candidate: &str| parse(ancestor).is_ancestor_of(&parse(candidate));
+        assert!(is_ancestor("/", "/users"));
+        assert!(is_ancestor("/", "/users/1/posts/2"));
+        assert!(!is_ancestor("/", "/"));
+        assert!(is_ancestor("/users/1", "/users/1/posts"));
+        assert!(is_ancestor("/users/1", "/users/1/posts/2/likes/3"));
+        assert!(!is_ancestor("/users/1", "/users/1"));
+        assert!(!is_ancestor("/users/1", "/users/10/posts/2"));
+        assert!(!is_ancestor("/users/1/posts/2", "/users/1"));
+        assert!(is_ancestor("/users", "/users/1"));
+        assert!(is_ancestor("/users", "/users/1/posts"));
+        assert!(!is_ancestor("/users", "/users"));
+        assert!(!is_ancestor("/users", "/usersx/1"));
+    }
+
+    #[test]
+    fn test_prefix_successor() {
+        let successor =
+            |reference: &str| parse(reference).prefix_successor().map(|r| r.to_string());
+        assert_eq!(successor("/"), None);
+        assert_eq!(successor("/users/1"), Some("/users/1%00".to_owned()));
+        assert_eq!(successor("/users/a"), Some("/users/a%00".to_owned()));
+        assert_eq!(successor("/users"), Some("/users%00".to_owned()));
+        // Nothing sorts between a reference and its first descendant
+        let users_1 = parse("/users/1");
+        for reference in ["/users/1/a/1", "/users/1/posts", "/users/1/\u{10FFFF}/x"] {
+            let reference = parse(reference);
+            assert!(users_1 < reference);
+            assert!(reference < users_1.prefix_successor().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_descendant_range_matches_brute_force() {
+        use rand::{Rng, SeedableRng};
+
+        fn random_path(rng: &mut impl Rng) -> FsReference {
+            let depth = rng.gen_range(0..=3);
+            let path = (0..depth)
+                .map(|i| {
+                    let collection_id = ["u", "u\0", "v", ""][rng.gen_range(0..4)];
+                    let resource_id = match rng.gen_range(0..3) {
+                        0 if i == depth - 1 => None,
+                        0 | 1 => string_id(
+                            ["", "\0", "a", "a\0", "\u{10FFFF}", "\u{10FFFF}\0"]
+                                [rng.gen_range(0..6)],
+                        ),
+                        _ => Some(ResourceId::Number(
+                            [i64::MIN, -1, 0, i64::MAX - 1, i64::MAX][rng.gen_range(0..5)],
+                        )),
+                    };
+                    element(collection_id, resource_id)
+                })
+                .collect();
+            reference(path)
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1940);
+        let candidates: Vec<FsReference> = (0..2000).map(|_| random_path(&mut rng)).collect();
+        for _ in 0..200 {
+            let ancestor = random_path(&mut rng);
+            // Random references rarely descend from the ancestor, so extend it
+            let mut extensions = Vec::new();
+            for _ in 0..50 {
+                let mut path = ancestor.path.0.to_vec();
+                let mut rest = random_path(&mut rng).path.0;
+                match path.last_mut() {
+                    Some(last) if last.resource_id.is_none() => {
+                        last.resource_id = string_id("a");
+                    }
+                    _ if rest.is_empty() => rest.push(element("v", string_id("a"))),
+                    _ => {}
+                }
+                path.extend(rest);
+                extensions.push(reference(path));
+            }
+            for candidate in candidates.iter().chain(extensions.iter()) {
+                assert_eq!(
+                    in_range(&ancestor, candidate),
+                    ancestor.is_ancestor_of(candidate),
+                    "{:?} under {:?}",
+                    candidate,
+                    ancestor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_reference_ordering_matches_fsvalue_ordering() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1927);
+        for _ in 0..10000 {
+            let lhs = random_reference(&mut rng);
+            let rhs = random_reference(&mut rng);
+            let expected = compare_segments(&lhs, &rhs);
+            assert_eq!(lhs.cmp(&rhs), expected, "{} vs {}", lhs, rhs);
+            assert_eq!(
+                crate::FsValue::Reference(lhs.to_owned())
+                    .cmp(&crate::FsValue::Reference(rhs.to_owned())),
+                expected,
+                "{} vs {}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    fn view(json: &str) -> ReferenceView<'_> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_reference_views_order_like_references() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1927);
+        let random = |rng: &mut rand::rngs::StdRng| {
+            let mut reference = random_reference(rng);
+            if rng.gen_bool(0.1) {
+                reference
+                    .path
+                    .0
+                    .extend(random_reference(rng).path.0.into_iter().cycle().take(14));
+            }
+            if rng.gen_bool(0.2) {
+                reference.path.0.last_mut().unwrap().resource_id = None;
+            }
+            reference
+        };
+        for _ in 0..10000 {
+            let (lhs, rhs) = (random(&mut rng), random(&mut rng));
+            let (lhs_json, rhs_json) = (
+                serde_json::to_string(&lhs).unwrap(),
+                serde_json::to_string(&rhs).unwrap(),
+            );
+            let expected = (lhs.path.0.len() <= VIEW_ELEMENTS && rhs.path.0.len() <= VIEW_ELEMENTS)
+                .then(|| lhs.cmp(&rhs));
+            assert_eq!(
+                view(&lhs_json).compare(&view(&rhs_json)),
+                expected,
+                "{} vs {}",
+                lhs,
+                rhs
+            );
+        }
+
+        let path = |len: usize| {
+            serde_json::to_string(&reference(vec![element("a", string_id("b")); len])).unwrap()
+        };
+        let (full, longer) = (path(VIEW_ELEMENTS), path(VIEW_ELEMENTS + 1));
+        assert_eq!(view(&full).compare(&view(&full)), Some(Ordering::Equal));
+        assert_eq!(view(&full).compare(&view(&longer)), None);
+        assert_eq!(view(&longer).compare(&view(&full)), None);
+        assert_eq!(view(&path(0)).compare(&view(&full)), Some(Ordering::Less));
+    }
+
+    #[test]
+    fn test_child() {
+        assert_eq!(
+            FS_REFERENCE_ROOT.child("users", "1").unwrap(),
+            FsReference::from_str("/users/1").unwrap()
+        );
+        assert_eq!(
+            FsReference::from_str("/users/1")
+                .unwrap()
+                .child("posts", "abc")
+                .unwrap(),
+            FsReference::from_str("/users/1/posts/abc").unwrap()
+        );
+        assert!(FsReference::from_str("/users")
+            .unwrap()
+            .child("posts", "1")
+            .is_err());
+    }
+
+    #[test]
+    fn test_push_element() {
+        let users = FS_REFERENCE_ROOT.push_element("users", None).unwrap();
+        assert_eq!(users, FsReference::from_str("/users").unwrap());
+        assert!(!users.has_complete_path());
+        assert_eq!(
+            FsReference::from_str("/users/1")
+                .unwrap()
+                .push_element("posts", Some("a/b"))
+                .unwrap(),
+            reference(vec![
+                element("users", Some(ResourceId::Number(1))),
+                element("posts", string_id("a/b")),
+            ])
+        );
+        assert_eq!(
+            users
+                .push_element("posts", None)
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: Cannot append to /users: Path ends in collection 'users' without a document ID"
+        );
+        assert_eq!(
+            FsReference::from_str("/users/1")
+                .unwrap()
+                .push_element("posts", Some(""))
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: Cannot append to /users/1: Segment 4 is empty"
+        );
+        let mut path = FsPath(vec![]);
+        path.push_element("users", Some(ResourceId::Number(1)))
+            .unwrap();
+        path.push_element("posts", None).unwrap();
+        assert_eq!(path.to_string(), "users/1/posts");
+        assert!(path.push_element("comments", None).is_err());
+        assert_eq!(path.0.len(), 2);
+    }
+
+    #[test]
+    fn test_last_resource_id() {
+        assert_eq!(
+            FsReference::from_str("/users/1/posts/a")
+                .unwrap()
+                .last_resource_id()
+                .unwrap(),
+            &ResourceId::String("a".to_owned())
+        );
+        assert_eq!(
+            FS_REFERENCE_ROOT
+                .last_resource_id()
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: The database root has no document ID"
+        );
+        assert_eq!(
+            FsReference::from_str("/users/1/posts")
+                .unwrap()
+                .last_resource_id()
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: /users/1/posts is a collection reference and has no document ID"
+        );
+    }
+
     #[test]
     fn test_parent() {
         assert_eq!(
@@ -249,4 +1386,266 @@ mod tests {
             FsReference::from_str("/users/1").unwrap()
         );
     }
+
+    fn parse(path: &str) -> FsReference {
+        FsReference::from_str(path).unwrap()
+    }
+
+    #[test]
+    fn test_reference_ordering_compares_ids_as_strings() {
+        assert!(parse("/users/10") < parse("/users/9"));
+        assert!(parse("/users/-1") < parse("/users/0"));
+        assert!(parse("/users/1") < parse("/users/1/posts/1"));
+        assert!(parse("/users/1/posts/1") < parse("/users/10"));
+        assert!(parse("/users/123") < parse("/users/abc"));
+        assert!(parse("/users/9a") < parse("/users/A"));
+        // A string ID sorts right before the numeric ID of the same text
+        assert!(parse("/users/%31") < parse("/users/1"));
+        assert!(parse("/users/1") < parse("/users/1%00"));
+        let mut numbers: Vec<FsReference> = [i64::MIN, -10, -9, 0, 9, 10, 100, i64::MAX]
+            .iter()
+            .map(|id| reference(vec![element("users", Some(ResourceId::Number(*id)))]))
+            .collect();
+        numbers.sort();
+        assert_eq!(
+            numbers.iter().map(|r| r.to_string()).collect::<Vec<_>>(),
+            vec![
+                "/users/-10",
+                "/users/-9",
+                "/users/-9223372036854775808",
+                "/users/0",
+                "/users/10",
+                "/users/100",
+                "/users/9",
+                "/users/9223372036854775807",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reference_case_sensitivity() {
+        assert_ne!(parse("/users/Bob"), parse("/users/bob"));
+        assert_ne!(parse("/Users/1"), parse("/users/1"));
+        assert!(parse("/users/Bob") < parse("/users/bob"));
+        assert!(parse("/users/Bobby") < parse("/users/bob"));
+        assert!(parse("/users/Zed") < parse("/users/alice"));
+    }
+
+    #[test]
+    fn test_reference_eq_ignore_ascii_case() {
+        for (lhs, rhs, equal) in [
+            ("/users/Bob", "/users/bob", true),
+            ("/Users/BOB/Posts/1", "/users/bob/posts/1", true),
+            ("/users/bob", "/users/bobby", false),
+            ("/users/bob", "/users", false),
+            ("/users/1", "/users/1a", false),
+            // Only ASCII letters fold, other characters must be identical
+            ("/users/ÉMILE", "/users/Émile", true),
+            ("/users/Émile", "/users/émile", false),
+            ("/users/straße", "/users/STRASSE", false),
+            ("/users/ÄÖÜ", "/users/ÄÖÜ", true),
+            ("/", "/", true),
+        ] {
+            assert_eq!(
+                parse(lhs).eq_ignore_ascii_case(&parse(rhs)),
+                equal,
+                "{} vs {}",
+                lhs,
+                rhs
+            );
+            assert_eq!(parse(rhs).eq_ignore_ascii_case(&parse(lhs)), equal);
+        }
+    }
+
+    #[test]
+    fn test_escaped_segments() {
+        let text = |id: &str| reference(vec![element("users", string_id(id))]).to_string();
+        assert_eq!(text("jane.doe-1"), "/users/jane.doe-1");
+        assert_eq!(text("a b"), "/users/a b");
+        assert_eq!(text("é"), "/users/é");
+        assert_eq!(text("a/b"), "/users/a%2Fb");
+        assert_eq!(text("50%"), "/users/50%25");
+        assert_eq!(text(" x\t"), "/users/%20x%09");
+        assert_eq!(text("line\nbreak"), "/users/line%0Abreak");
+        // String IDs reading as integers stay strings
+        assert_eq!(text("1"), "/users/%31");
+        assert_eq!(text("-7"), "/users/%2D7");
+        // Other digits are strings already and need no escape
+        assert_eq!(text("-01"), "/users/-01");
+        assert_eq!(
+            FsReference::from_str("/users/%31").unwrap(),
+            reference(vec![element("users", string_id("1"))])
+        );
+        assert_eq!(
+            FsReference::from_str("/users/1").unwrap(),
+            reference(vec![element("users", Some(ResourceId::Number(1)))])
+        );
+        assert_eq!(
+            FsReference::from_str("/a%2Fb/c%C3%A9").unwrap(),
+            reference(vec![element("a/b", string_id("cé"))])
+        );
+        assert_eq!(
+            FsReference::from_str("/users/%zz").unwrap_err().to_string(),
+            "InvalidValue: Invalid escape sequence in reference segment '%zz'"
+        );
+        assert!(FsReference::from_str("/users/%C3").is_err());
+        assert!(FsReference::from_str("/users/%2").is_err());
+        assert!(FsReference::from_str("users/1").is_err());
+    }
+
+    #[test]
+    fn test_invalid_segments() {
+        let error = |path: &str| FsReference::from_str(path).unwrap_err().to_string();
+        // Characters outside of \w are part of the ID, never cut off
+        assert_eq!(
+            FsReference::from_str("/users/1?foo").unwrap(),
+            reference(vec![element("users", string_id("1?foo"))])
+        );
+        assert_eq!(
+            FsReference::from_str("/users/a b#c").unwrap(),
+            reference(vec![element("users", string_id("a b#c"))])
+        );
+        assert_eq!(
+            error("/users//1"),
+            "InvalidValue: Invalid reference '/users//1': Segment 2 is empty"
+        );
+        assert_eq!(
+            error("/users/1/"),
+            "InvalidValue: Invalid reference '/users/1/': Segment 3 is empty"
+        );
+        assert_eq!(
+            error("//users"),
+            "InvalidValue: Invalid reference '//users': Segment 1 is empty"
+        );
+        assert_eq!(
+            error("/users/.."),
+            "InvalidValue: Invalid reference '/users/..': Segment 2 '..' is a reserved ID"
+        );
+        assert_eq!(
+            error("/users/1/./2"),
+            "InvalidValue: Invalid reference '/users/1/./2': Segment 3 '.' is a reserved ID"
+        );
+        assert_eq!(
+            error("/__name__/1"),
+            "InvalidValue: Invalid reference '/__name__/1': Segment 1 '__name__' is a reserved ID"
+        );
+        // Escaping does not get around the checks
+        assert_eq!(
+            error("/users/%2E"),
+            "InvalidValue: Invalid reference '/users/%2E': Segment 2 '.' is a reserved ID"
+        );
+        assert!(FsReference::from_str("/users/__").is_ok());
+        assert!(FsReference::from_str("/users/_a_").is_ok());
+        assert!(FsReference::from_str("/users/...").is_ok());
+        let long = "x".repeat(MAX_ID_BYTES + 1);
+        assert_eq!(
+            FsReference::from_str(&format!("/users/{}", long))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "InvalidValue: Invalid reference '/users/{}': Segment 2 is 1501 bytes, \
+                 more than the limit of 1500 bytes",
+                long
+            )
+        );
+        assert!(FsReference::from_str(&format!("/users/{}", &long[1..])).is_ok());
+        assert_eq!(
+            FsReference::from_segments(&["users", ""])
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: Invalid reference '/users/': Segment 2 is empty"
+        );
+        assert_eq!(
+            FS_REFERENCE_ROOT
+                .child("users", "__id__")
+                .unwrap_err()
+                .to_string(),
+            "InvalidValue: Cannot append to /: Segment 2 '__id__' is a reserved ID"
+        );
+    }
+
+    #[test]
+    fn test_unescaped_segments() {
+        let nested = reference(vec![
+            element("users", string_id("a/b%")),
+            element("posts", Some(ResourceId::Number(7))),
+            element("tags", None),
+        ]);
+        assert_eq!(
+            nested.segments(),
+            vec!["users", "a/b%", "posts", "7", "tags"]
+        );
+        // Segments are taken as they are, without unescaping
+        assert_eq!(
+            FsReference::from_segments(&["users", "a%2F", "posts", "7", "tags"]).unwrap(),
+            reference(vec![
+                element("users", string_id("a%2F")),
+                element("posts", Some(ResourceId::Number(7))),
+                element("tags", None),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_text_form_round_trip() {
+        use rand::{Rng, SeedableRng};
+
+        const CHARACTERS: &[char] = &[
+            'a',
+            'Z',
+            '0',
+            '7',
+            '-',
+            '+',
+            '.',
+            '_',
+            ' ',
+            '\t',
+            '\n',
+            '\0',
+            '\u{7f}',
+            '\u{85}',
+            '/',
+            '%',
+            '`',
+            '~',
+            'é',
+            '\u{3000}',
+            '\u{10FFFF}',
+            '😀',
+        ];
+        // Firestore IDs are never empty or reserved
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1960);
+        let segment = |rng: &mut rand::rngs::StdRng| -> String {
+            loop {
+                let len = rng.gen_range(1..6);
+                let id: String = (0..len)
+                    .map(|_| CHARACTERS[rng.gen_range(0..CHARACTERS.len())])
+                    .collect();
+                if !is_reserved_id(&id) {
+                    return id;
+                }
+            }
+        };
+        for _ in 0..2000 {
+            let depth = rng.gen_range(1..=3);
+            let path = (0..depth)
+                .map(|level| {
+                    let resource_id = match rng.gen_range(0..4) {
+                        0 if level + 1 == depth => None,
+                        1 => Some(ResourceId::Number(rng.gen_range(-20..20))),
+                        _ => Some(ResourceId::String(segment(&mut rng))),
+                    };
+                    element(&segment(&mut rng), resource_id)
+                })
+                .collect();
+            let reference = reference(path);
+            assert_eq!(
+                FsReference::from_str(&reference.to_string()).unwrap(),
+                reference,
+                "{}",
+                reference
+            );
+        }
+    }
 }
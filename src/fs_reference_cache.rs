@@ -0,0 +1,386 @@
+use crate::fs_ordering::view_reference;
+use crate::fs_reference::FsReference;
+use crate::{fs_guc, FsError, FsValue};
+use pgrx::prelude::*;
+use std::collections::HashMap;
+
+// fs_parent and fs_collection_id run once per row in collection group scans
+// and structured queries, where a million documents often share a thousand
+// parents. Decoding each reference only to take its parent apart again
+// dominates those scans, so each call site remembers what it derived from the
+// last few distinct keys. Every row has a reference of its own, so the key is
+// not the whole argument datum but the part of it the result depends on: the
+// path elements before the last one for fs_parent, and the collection ID of
+// the last one for fs_collection_id. The cache lives in the fn_extra of the
+// calling expression, whose memory context is released with the statement.
+
+struct Entry<V> {
+    key: Vec<u8>,
+    value: V,
+    newer: Option<usize>,
+    older: Option<usize>,
+}
+
+// A map of at most `capacity` entries evicting the least recently used one.
+// Entries form a list from the newest to the oldest through their slot
+// indices.
+pub(crate) struct ReferenceCache<V> {
+    capacity: usize,
+    slots: HashMap<Vec<u8>, usize>,
+    entries: Vec<Entry<V>>,
+    newest: Option<usize>,
+    oldest: Option<usize>,
+}
+
+impl<V> ReferenceCache<V> {
+    pub(crate) fn new(capacity: usize) -> ReferenceCache<V> {
+        ReferenceCache {
+            capacity,
+            slots: HashMap::new(),
+            entries: Vec::new(),
+            newest: None,
+            oldest: None,
+        }
+    }
+
+    // Starts over when the capacity changed since the cache was created,
+    // e.g. when a cached plan outlives a SET
+    pub(crate) fn resize(&mut self, capacity: usize) {
+        if capacity != self.capacity {
+            *self = ReferenceCache::new(capacity);
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let (newer, older) = (self.entries[slot].newer, self.entries[slot].older);
+        match newer {
+            Some(newer) => self.entries[newer].older = older,
+            None => self.newest = older,
+        }
+        match older {
+            Some(older) => self.entries[older].newer = newer,
+            None => self.oldest = newer,
+        }
+    }
+
+    fn push_newest(&mut self, slot: usize) {
+        self.entries[slot].newer = None;
+        self.entries[slot].older = self.newest;
+        if let Some(newest) = self.newest {
+            self.entries[newest].newer = Some(slot);
+        }
+        self.newest = Some(slot);
+        if self.oldest.is_none() {
+            self.oldest = Some(slot);
+        }
+    }
+
+    // The value cached for `key`, deriving and caching it first on a miss
+    pub(crate) fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: &[u8], derive: F) -> &V {
+        assert!(
+            self.capacity > 0,
+            "expecting a cache with room for an entry"
+        );
+        if let Some(&slot) = self.slots.get(key) {
+            if self.newest != Some(slot) {
+                self.unlink(slot);
+                self.push_newest(slot);
+            }
+            return &self.entries[slot].value;
+        }
+        let value = derive();
+        let slot = if self.entries.len() < self.capacity {
+            self.entries.push(Entry {
+                key: key.to_vec(),
+                value,
+                newer: None,
+                older: None,
+            });
+            self.entries.len() - 1
+        } else {
+            let slot = self
+                .oldest
+                .expect("expecting a full cache to have an oldest entry");
+            self.unlink(slot);
+            let evicted = std::mem::replace(&mut self.entries[slot].key, key.to_vec());
+            self.slots.remove(&evicted);
+            self.entries[slot].value = value;
+            slot
+        };
+        self.slots.insert(key.to_vec(), slot);
+        self.push_newest(slot);
+        &self.entries[slot].value
+    }
+}
+
+fn cache_capacity() -> usize {
+    fs_guc::REFERENCE_CACHE_SIZE.get().max(0) as usize
+}
+
+// The key of fs_parent: the path elements before the last one, which all the
+// children of a parent share. Keys are read through ReferenceView, like the
+// comparisons of sorts, so a path too long to view is not cached.
+fn parent_key(bytes: &[u8]) -> Option<Vec<u8>> {
+    view_reference(bytes)?.parent_key()
+}
+
+// The key of fs_collection_id: the collection ID of the last path element
+fn collection_id_key(bytes: &[u8]) -> Option<Vec<u8>> {
+    Some(view_reference(bytes)?.collection_id()?.as_bytes().to_vec())
+}
+
+unsafe fn reference_arg(fcinfo: pg_sys::FunctionCallInfo) -> FsValue {
+    pgrx::fcinfo::pg_getarg::<FsValue>(fcinfo, 0).expect("reference must not be null")
+}
+
+// Derives a value from the reference argument of a strict V1 function through
+// the cache of the call site, or directly when the cache is disabled or the
+// argument has no key, like the database root
+unsafe fn cached_derive<V: 'static>(
+    fcinfo: pg_sys::FunctionCallInfo,
+    key: fn(&[u8]) -> Option<Vec<u8>>,
+    derive: fn(&FsValue) -> V,
+    into_datum: fn(&V) -> pg_sys::Datum,
+) -> pg_sys::Datum {
+    let capacity = cache_capacity();
+    let bytes = pgrx::fcinfo::pg_getarg::<&[u8]>(fcinfo, 0).expect("reference must not be null");
+    let key = match key(bytes) {
+        Some(key) if capacity > 0 => key,
+        _ => return into_datum(&derive(&reference_arg(fcinfo))),
+    };
+    let mut cache = pgrx::fcinfo::pg_func_extra(fcinfo, || ReferenceCache::<V>::new(capacity));
+    cache.resize(capacity);
+    let value = cache.get_or_insert_with(&key, || derive(&reference_arg(fcinfo)));
+    into_datum(value)
+}
+
+// The reference argument of `function`, which the database root has no
+// answer for
+fn expect_below_root<'a>(reference: &'a FsValue, function: &str) -> &'a FsReference {
+    let fs_ref = reference.expect_reference();
+    if fs_ref.is_root() {
+        FsError::InvalidValue(format!(
+            "{} expects a reference below the database root but found {}",
+            function, fs_ref
+        ))
+        .report()
+    }
+    fs_ref
+}
+
+fn parent(reference: &FsValue) -> FsValue {
+    let fs_ref = expect_below_root(reference, "fs_parent");
+    FsValue::Reference(fs_ref.parent())
+}
+
+fn collection_id(reference: &FsValue) -> String {
+    let fs_ref = expect_below_root(reference, "fs_collection_id");
+    fs_ref.collection_id().to_string()
+}
+
+// The parent is cached as the bytes of its datum, which go back out as a copy
+// without encoding the reference again
+fn parent_bytes(reference: &FsValue) -> Vec<u8> {
+    let datum = parent(reference)
+        .into_datum()
+        .expect("fsvalue must not be null");
+    unsafe { <&[u8]>::from_datum(datum, false) }
+        .expect("fsvalue must not be null")
+        .to_vec()
+}
+
+// Like fs_doc and fs_arr, these are bare V1 functions: #[pg_extern] would
+// decode the argument before the cache could be consulted.
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_parent_wrapper(fcinfo: pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+    cached_derive(fcinfo, parent_key, parent_bytes, |bytes| {
+        bytes
+            .as_slice()
+            .into_datum()
+            .expect("fsvalue must not be null")
+    })
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_parent_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+#[pg_guard]
+#[no_mangle]
+pub unsafe extern "C" fn fs_collection_id_wrapper(
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> pg_sys::Datum {
+    cached_derive(fcinfo, collection_id_key, collection_id, |collection_id| {
+        collection_id
+            .as_str()
+            .into_datum()
+            .expect("text must not be null")
+    })
+}
+
+#[no_mangle]
+#[doc(hidden)]
+pub extern "C" fn pg_finfo_fs_collection_id_wrapper() -> &'static pg_sys::Pg_finfo_record {
+    const V1_API: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+    &V1_API
+}
+
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_parent(reference fsvalue) RETURNS fsvalue \n\
+        AS 'MODULE_PATHNAME', 'fs_parent_wrapper' \n\
+        LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_collection_id(reference fsvalue) RETURNS text \n\
+        AS 'MODULE_PATHNAME', 'fs_collection_id_wrapper' \n\
+        LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE; \n\
+    ",
+    name = "reference_path_functions",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_reference::VIEW_ELEMENTS;
+    use crate::fs_reference_cache::*;
+    use serde_json::json;
+
+    // The keys among `keys` that missed the cache, looking them up in order
+    fn misses<'a>(cache: &mut ReferenceCache<String>, keys: &[&'a str]) -> Vec<&'a str> {
+        let mut misses = Vec::new();
+        for key in keys {
+            let value = cache.get_or_insert_with(key.as_bytes(), || {
+                misses.push(*key);
+                key.to_uppercase()
+            });
+            assert_eq!(value, &key.to_uppercase());
+        }
+        misses
+    }
+
+    #[test]
+    fn test_reference_cache_evicts_least_recently_used() {
+        let mut cache = ReferenceCache::new(2);
+        // Touching a makes b the least recently used when c comes in
+        assert_eq!(
+            misses(&mut cache, &["a", "b", "a", "c"]),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(misses(&mut cache, &["a", "c"]), Vec::<&str>::new());
+        assert_eq!(misses(&mut cache, &["b", "c"]), vec!["b"]);
+        assert_eq!(misses(&mut cache, &["a"]), vec!["a"]);
+
+        cache.resize(2);
+        assert_eq!(misses(&mut cache, &["a", "c"]), Vec::<&str>::new());
+        cache.resize(3);
+        assert_eq!(misses(&mut cache, &["a", "c"]), vec!["a", "c"]);
+    }
+
+    // The datum of a reference, built without the limit checks of parsing one
+    fn datum(path: &str) -> Vec<u8> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let elements: Vec<_> = segments
+            .chunks(2)
+            .map(|pair| {
+                json!({
+                    "collection_id": pair[0],
+                    "resource_id": pair.get(1).map(|id| match id.parse::<i64>() {
+                        Ok(number) => json!({ "Number": number }),
+                        Err(_) => json!({ "String": id }),
+                    }),
+                })
+            })
+            .collect();
+        let reference: FsReference = serde_json::from_value(json!({ "path": elements })).unwrap();
+        serde_cbor::to_vec(&FsValue::Reference(reference)).unwrap()
+    }
+
+    #[test]
+    fn test_cache_keys() {
+        let long = format!("/groups/{}", "x".repeat(300));
+        // Siblings share the key of fs_parent, cousins do not
+        assert_eq!(
+            parent_key(&datum("/groups/1/posts/a")),
+            parent_key(&datum("/groups/1/posts/b"))
+        );
+        assert_ne!(
+            parent_key(&datum("/groups/1/posts/a")),
+            parent_key(&datum("/groups/2/posts/a"))
+        );
+        assert_eq!(
+            parent_key(&datum(&format!("{}/posts/1", long))),
+            parent_key(&datum(&format!("{}/posts", long)))
+        );
+        assert_eq!(
+            parent_key(&datum("/groups/1")),
+            parent_key(&datum("/posts"))
+        );
+        // fs_collection_id is keyed by the collection ID alone
+        assert_eq!(
+            collection_id_key(&datum("/groups/1/posts/a")),
+            collection_id_key(&datum(&format!("{}/posts", long)))
+        );
+        assert_ne!(
+            collection_id_key(&datum("/groups/1/posts/a")),
+            collection_id_key(&datum("/groups/1"))
+        );
+        // The database root has no parent, and other values no path
+        assert_eq!(parent_key(&datum("/")), None);
+        let string = serde_cbor::to_vec(&FsValue::String("Reference".to_owned())).unwrap();
+        assert_eq!(parent_key(&string), None);
+        assert_eq!(collection_id_key(&string), None);
+        // Paths too long to view have no key, so their calls are not cached
+        let deep = "/c/1".repeat(VIEW_ELEMENTS + 1);
+        assert_eq!(parent_key(&datum(&deep)), None);
+        assert_eq!(collection_id_key(&datum(&deep)), None);
+    }
+
+    #[test]
+    fn test_reference_cache_single_entry() {
+        let mut cache = ReferenceCache::new(1);
+        assert_eq!(
+            misses(&mut cache, &["a", "a", "b", "a", "a"]),
+            vec!["a", "b", "a"]
+        );
+    }
+
+    // Results must not depend on whether or how much the calls are cached
+    #[pg_test]
+    fn test_cached_reference_functions_match_uncached() {
+        Spi::run(
+            "CREATE TEMP TABLE refs AS \
+             SELECT fs_reference(format('/groups/%s/posts/%s', n % 7, n)) AS reference, n \
+             FROM generate_series(1, 200) AS n \
+             UNION ALL SELECT fs_reference(format('/posts/%s', n)), 1000 + n \
+             FROM generate_series(1, 5) AS n",
+        )
+        .expect("SPI failed");
+        let results = |cache_size: i32| {
+            Spi::run(&format!(
+                "SET pgfirestore.reference_cache_size = {}",
+                cache_size
+            ))
+            .expect("SPI failed");
+            Spi::get_one::<String>(
+                "SELECT string_agg(fs_reference_text(fs_parent(reference)) || ' ' || \
+                 fs_collection_id(reference), ',' ORDER BY n) FROM refs",
+            )
+            .expect("SPI failed")
+            .expect("refs must not be empty")
+        };
+        let uncached = results(0);
+        assert!(uncached.starts_with("/groups/1 posts,/groups/2 posts,"));
+        // Fewer entries than distinct parents keeps evicting
+        assert_eq!(results(3), uncached);
+        assert_eq!(results(1024), uncached);
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a reference but found 1")]
+    fn test_cached_parent_of_non_reference() {
+        Spi::get_one::<FsValue>("SELECT fs_parent(fs_number_from_integer(1))").expect("SPI failed");
+    }
+}
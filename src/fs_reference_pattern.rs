@@ -0,0 +1,283 @@
+use crate::fs_reference::FsReference;
+use crate::FsError;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// A security rules style reference pattern, e.g. `/users/{uid}/posts/{postId}`.
+// `{name}` matches exactly one path segment and a trailing `{name=**}` matches
+// all remaining segments, possibly none.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReferencePattern {
+    segments: Vec<PatternSegment>,
+    rest: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum PatternSegment {
+    Literal(String),
+    Wildcard(String),
+}
+
+fn is_valid_wildcard_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first == '_' || first.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+impl FromStr for ReferencePattern {
+    type Err = FsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            FsError::InvalidValue(format!("Invalid reference pattern '{}': {}", s, reason))
+        };
+        let path = s
+            .strip_prefix('/')
+            .ok_or_else(|| invalid("expecting a leading '/'"))?;
+        let mut segments = Vec::new();
+        let mut rest = None;
+        let mut names: Vec<&str> = Vec::new();
+        if !path.is_empty() {
+            for segment in path.split('/') {
+                if rest.is_some() {
+                    return Err(invalid("'=**' is only allowed in the last segment"));
+                }
+                if segment.is_empty() {
+                    return Err(invalid("empty segment"));
+                }
+                let wildcard = segment
+                    .strip_prefix('{')
+                    .and_then(|segment| segment.strip_suffix('}'));
+                match wildcard {
+                    Some(wildcard) => {
+                        let (name, is_rest) = match wildcard.strip_suffix("=**") {
+                            Some(name) => (name, true),
+                            None => (wildcard, false),
+                        };
+                        if !is_valid_wildcard_name(name) {
+                            return Err(invalid(&format!("invalid wildcard name '{}'", name)));
+                        }
+                        if names.contains(&name) {
+                            return Err(invalid(&format!("duplicate wildcard '{}'", name)));
+                        }
+                        names.push(name);
+                        if is_rest {
+                            rest = Some(name.to_owned());
+                        } else {
+                            segments.push(PatternSegment::Wildcard(name.to_owned()));
+                        }
+                    }
+                    None => {
+                        if segment.contains('{') || segment.contains('}') {
+                            return Err(invalid(&format!(
+                                "wildcards must span a whole segment but found '{}'",
+                                segment
+                            )));
+                        }
+                        segments.push(PatternSegment::Literal(segment.to_owned()));
+                    }
+                }
+            }
+        }
+        Ok(ReferencePattern { segments, rest })
+    }
+}
+
+impl fmt::Display for ReferencePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in self.segments.iter() {
+            match segment {
+                PatternSegment::Literal(literal) => write!(f, "/{}", literal)?,
+                PatternSegment::Wildcard(name) => write!(f, "/{{{}}}", name)?,
+            }
+        }
+        match &self.rest {
+            Some(name) => write!(f, "/{{{}=**}}", name),
+            None if self.segments.is_empty() => write!(f, "/"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ReferencePattern {
+    // Number of leading segments of `reference` that the pattern matches,
+    // used to rank patterns by how close they come to matching.
+    pub fn matched_prefix_len(&self, reference: &FsReference) -> usize {
+        let segments = reference.segments();
+        let matched = self
+            .segments
+            .iter()
+            .zip(segments.iter())
+            .take_while(|(pattern, segment)| match pattern {
+                PatternSegment::Literal(literal) => literal == *segment,
+                PatternSegment::Wildcard(_) => true,
+            })
+            .count();
+        match self.rest {
+            Some(_) if matched == self.segments.len() => segments.len(),
+            _ => matched,
+        }
+    }
+
+    // Number of segments the pattern spells out, excluding a trailing `=**`
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    // The collection ID a pattern naming a collection ends with, e.g. `posts`
+    // for `/users/{uid}/posts`
+    pub fn collection_id(&self) -> Option<&str> {
+        match self.segments.last() {
+            Some(PatternSegment::Literal(literal))
+                if self.rest.is_none() && self.segments.len() % 2 == 1 =>
+            {
+                Some(literal)
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the leading segments of `reference` match the pattern, e.g.
+    // /users/1/posts/2/likes/3 for `/users/{uid}/posts`
+    pub fn matches_prefix(&self, reference: &FsReference) -> bool {
+        self.rest.is_none()
+            && reference.segments().len() >= self.segments.len()
+            && self.matched_prefix_len(reference) == self.segments.len()
+    }
+
+    // Returns the wildcard bindings if `reference` matches the pattern
+    pub fn extract(&self, reference: &FsReference) -> Option<BTreeMap<String, String>> {
+        let segments = reference.segments();
+        let matches_depth = match self.rest {
+            Some(_) => segments.len() >= self.segments.len(),
+            None => segments.len() == self.segments.len(),
+        };
+        if !matches_depth {
+            return None;
+        }
+        let mut bindings = BTreeMap::new();
+        for (pattern, segment) in self.segments.iter().zip(segments.iter()) {
+            match pattern {
+                PatternSegment::Literal(literal) => {
+                    if literal != segment {
+                        return None;
+                    }
+                }
+                PatternSegment::Wildcard(name) => {
+                    bindings.insert(name.to_owned(), segment.to_string());
+                }
+            }
+        }
+        if let Some(name) = &self.rest {
+            bindings.insert(name.to_owned(), segments[self.segments.len()..].join("/"));
+        }
+        Some(bindings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(path: &str) -> FsReference {
+        FsReference::from_str(path).unwrap()
+    }
+
+    fn bindings(entries: &[(&str, &str)]) -> Option<BTreeMap<String, String>> {
+        Some(
+            entries
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        for pattern in [
+            "/",
+            "/users/1",
+            "/users/{uid}/posts/{postId}",
+            "/users/{document=**}",
+            "/{rest=**}",
+        ] {
+            assert_eq!(
+                ReferencePattern::from_str(pattern).unwrap().to_string(),
+                pattern
+            );
+        }
+        for pattern in [
+            "users/{uid}",
+            "/users//1",
+            "/users/",
+            "/users/{uid}x",
+            "/users/x{uid}",
+            "/users/{}",
+            "/users/{1a}",
+            "/users/{uid}/posts/{uid}",
+            "/users/{rest=**}/posts",
+        ] {
+            assert!(
+                ReferencePattern::from_str(pattern).is_err(),
+                "{} should be rejected",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        let pattern = ReferencePattern::from_str("/users/1").unwrap();
+        assert_eq!(pattern.extract(&reference("/users/1")), bindings(&[]));
+        assert_eq!(pattern.extract(&reference("/users/2")), None);
+
+        let pattern = ReferencePattern::from_str("/users/{uid}/posts/{postId}").unwrap();
+        assert_eq!(
+            pattern.extract(&reference("/users/1/posts/abc")),
+            bindings(&[("uid", "1"), ("postId", "abc")])
+        );
+        assert_eq!(pattern.extract(&reference("/users/1")), None);
+        assert_eq!(
+            pattern.extract(&reference("/users/1/posts/abc/comments/1")),
+            None
+        );
+        assert_eq!(pattern.extract(&reference("/users/1/likes/abc")), None);
+
+        let pattern = ReferencePattern::from_str("/users/{uid}/{path=**}").unwrap();
+        assert_eq!(
+            pattern.extract(&reference("/users/1/posts/2/comments/3")),
+            bindings(&[("uid", "1"), ("path", "posts/2/comments/3")])
+        );
+        assert_eq!(
+            pattern.extract(&reference("/users/1")),
+            bindings(&[("uid", "1"), ("path", "")])
+        );
+        assert_eq!(pattern.extract(&reference("/posts/1")), None);
+    }
+
+    #[test]
+    fn test_matched_prefix_len() {
+        let pattern = ReferencePattern::from_str("/users/{uid}/posts/{postId}").unwrap();
+        assert_eq!(
+            pattern.matched_prefix_len(&reference("/users/1/posts/2")),
+            4
+        );
+        assert_eq!(
+            pattern.matched_prefix_len(&reference("/users/1/likes/2")),
+            2
+        );
+        assert_eq!(pattern.matched_prefix_len(&reference("/payments/1")), 0);
+        let pattern = ReferencePattern::from_str("/users/{rest=**}").unwrap();
+        assert_eq!(
+            pattern.matched_prefix_len(&reference("/users/1/posts/2")),
+            4
+        );
+    }
+}
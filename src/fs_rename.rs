@@ -0,0 +1,465 @@
+use crate::fs_documents::{fsvalue_array_arg, scan_documents};
+use crate::fs_error::{report, FsCode};
+use crate::fs_lint::child_path;
+use crate::fs_reference_pattern::ReferencePattern;
+use crate::{FsError, FsReference, FsValue};
+use pgrx::prelude::*;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// The field path of a renamed reference, and its old and new value
+type Rewrite = (String, FsReference, FsReference);
+
+// Renames the collection a pattern such as `/users/{uid}/posts` ends with
+struct Rename {
+    pattern: ReferencePattern,
+    // Index of the renamed collection among the path elements of a reference
+    depth: usize,
+    collection_id: String,
+}
+
+impl Rename {
+    fn new(old_pattern: &str, new_collection_id: &str) -> Result<Rename> {
+        let pattern = ReferencePattern::from_str(old_pattern)?;
+        let old_collection_id = pattern.collection_id().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a pattern ending with a collection ID but found '{}'",
+                old_pattern
+            ))
+        })?;
+        if new_collection_id.is_empty() || new_collection_id.contains('/') {
+            return Err(FsError::InvalidValue(format!(
+                "Invalid collection ID '{}'",
+                new_collection_id
+            )));
+        }
+        if new_collection_id == old_collection_id {
+            return Err(FsError::InvalidValue(format!(
+                "Collection '{}' already has that ID",
+                old_collection_id
+            )));
+        }
+        Ok(Rename {
+            depth: pattern.segment_count() / 2,
+            pattern,
+            collection_id: new_collection_id.to_owned(),
+        })
+    }
+
+    // The new reference of a collection matching the pattern or of anything
+    // below it
+    fn apply(&self, reference: &FsReference) -> Option<FsReference> {
+        self.pattern
+            .matches_prefix(reference)
+            .then(|| reference.with_collection_id(self.depth, &self.collection_id))
+    }
+
+    // Renames the references in `value`, pushing the (path, old, new) of
+    // every one renamed to `rewrites`
+    fn rewrite(&self, value: &mut FsValue, path: &str, rewrites: &mut Vec<Rewrite>) {
+        match value {
+            FsValue::Reference(reference) => {
+                if let Some(renamed) = self.apply(reference) {
+                    let old = std::mem::replace(reference, renamed.to_owned());
+                    rewrites.push((path.to_owned(), old, renamed));
+                }
+            }
+            FsValue::Array(elements) => {
+                for (index, element) in elements.iter_mut().enumerate() {
+                    self.rewrite(element, &format!("{}[{}]", path, index), rewrites);
+                }
+            }
+            FsValue::Map(map) => {
+                for (key, child) in map.iter_mut() {
+                    self.rewrite(child, &child_path(path, key), rewrites);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct RenamePlan {
+    // (old, new) references of the renamed documents
+    documents: Vec<(FsReference, FsReference)>,
+    // Documents holding renamed references, with their rewritten properties
+    properties: Vec<(FsReference, FsValue, Vec<Rewrite>)>,
+    // (old, new) references of renamed documents whose new reference is taken
+    collisions: Vec<(FsReference, FsReference)>,
+}
+
+impl RenamePlan {
+    fn rows(&self) -> Vec<(String, FsValue, String)> {
+        let document_rows = self.documents.iter().map(|(old, new)| {
+            (
+                "document".to_owned(),
+                FsValue::Reference(old.to_owned()),
+                new.to_string(),
+            )
+        });
+        let reference_rows = self.properties.iter().flat_map(|(reference, _, rewrites)| {
+            rewrites.iter().map(move |(path, old, new)| {
+                (
+                    "reference".to_owned(),
+                    FsValue::Reference(reference.to_owned()),
+                    format!("{}: {} -> {}", path, old, new),
+                )
+            })
+        });
+        let collision_rows = self.collisions.iter().map(|(old, new)| {
+            (
+                "collision".to_owned(),
+                FsValue::Reference(new.to_owned()),
+                format!("{} would be renamed to an existing document", old),
+            )
+        });
+        document_rows
+            .chain(reference_rows)
+            .chain(collision_rows)
+            .collect()
+    }
+}
+
+fn plan_rename(rename: &Rename) -> RenamePlan {
+    let mut plan = RenamePlan::default();
+    // Tombstones are renamed along with the documents
+    scan_documents(
+        "SELECT reference, properties FROM fs_documents ORDER BY reference",
+        vec![],
+        |reference, mut properties| {
+            let reference = reference
+                .as_reference()
+                .expect("reference must be a REFERENCE")
+                .to_owned();
+            if let Some(renamed) = rename.apply(&reference) {
+                plan.documents.push((reference.to_owned(), renamed));
+            }
+            let mut rewrites = Vec::new();
+            rename.rewrite(&mut properties, "", &mut rewrites);
+            if !rewrites.is_empty() {
+                plan.properties.push((reference, properties, rewrites));
+            }
+        },
+    );
+    let renamed = plan
+        .documents
+        .iter()
+        .map(|(_, new)| FsValue::Reference(new.to_owned()))
+        .collect::<Vec<_>>();
+    let taken = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT reference FROM fs_documents WHERE reference = ANY($1)",
+                None,
+                Some(vec![fsvalue_array_arg(renamed)]),
+            )?
+            .filter_map(|row| row.get::<FsValue>(1).transpose())
+            .collect::<std::result::Result<BTreeSet<_>, _>>()
+    })
+    .expect("Failed to read from fs_documents");
+    plan.collisions = plan
+        .documents
+        .iter()
+        .filter(|(_, new)| taken.contains(&FsValue::Reference(new.to_owned())))
+        .cloned()
+        .collect();
+    plan
+}
+
+// Rewrites the properties before renaming, while the documents can still be
+// found under their old references
+fn apply_rename(plan: &RenamePlan) {
+    let (references, properties): (Vec<FsValue>, Vec<FsValue>) = plan
+        .properties
+        .iter()
+        .map(|(reference, properties, _)| {
+            (
+                FsValue::Reference(reference.to_owned()),
+                properties.to_owned(),
+            )
+        })
+        .unzip();
+    let (old, new): (Vec<FsValue>, Vec<FsValue>) = plan
+        .documents
+        .iter()
+        .map(|(old, new)| {
+            (
+                FsValue::Reference(old.to_owned()),
+                FsValue::Reference(new.to_owned()),
+            )
+        })
+        .unzip();
+    Spi::connect(|mut client| {
+        client.update(
+            "UPDATE fs_documents AS d \
+             SET properties = r.properties, update_time = fs_request_time() \
+             FROM unnest($1, $2) AS r(reference, properties) WHERE d.reference = r.reference",
+            None,
+            Some(vec![
+                fsvalue_array_arg(references),
+                fsvalue_array_arg(properties),
+            ]),
+        )?;
+        client.update(
+            "UPDATE fs_documents AS d SET reference = r.new \
+             FROM unnest($1, $2) AS r(old, new) WHERE d.reference = r.old",
+            None,
+            Some(vec![fsvalue_array_arg(old), fsvalue_array_arg(new)]),
+        )?;
+        Ok::<(), pgrx::spi::Error>(())
+    })
+    .expect("Failed to write to fs_documents")
+}
+
+// Renames the collections matching `old_pattern` to `new_collection_id`:
+// documents in them and below them move, and references to them in the
+// properties of any document are rewritten. Returns the plan, which is only
+// carried out without `dry_run`. Nothing is written if a document would be
+// renamed to an existing one.
+#[pg_extern]
+fn fs_rename_collection(
+    old_pattern: &str,
+    new_collection_id: &str,
+    dry_run: default!(bool, true),
+) -> TableIterator<
+    'static,
+    (
+        name!(kind, String),
+        name!(reference, FsValue),
+        name!(detail, String),
+    ),
+> {
+    let rename = match Rename::new(old_pattern, new_collection_id) {
+        Ok(rename) => rename,
+        Err(error) => error.report(),
+    };
+    let plan = plan_rename(&rename);
+    if !dry_run {
+        if let Some((old, new)) = plan.collisions.first() {
+            report(
+                FsCode::AlreadyExists,
+                format!("Cannot rename {} to {} which already exists", old, new),
+            )
+        }
+        apply_rename(&plan);
+    }
+    TableIterator::new(plan.rows().into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_rename::*;
+    use crate::{fs_reference, fs_reference_text};
+
+    fn reference(path: &str) -> FsReference {
+        FsReference::from_str(path).unwrap()
+    }
+
+    fn renamed(rename: &Rename, path: &str) -> Option<String> {
+        rename
+            .apply(&reference(path))
+            .map(|reference| reference.to_string())
+    }
+
+    #[test]
+    fn test_rename_apply() {
+        let rename = Rename::new("/users/{uid}/posts", "articles").unwrap();
+        assert_eq!(
+            renamed(&rename, "/users/1/posts/2"),
+            Some("/users/1/articles/2".to_owned())
+        );
+        assert_eq!(
+            renamed(&rename, "/users/1/posts/2/likes/3"),
+            Some("/users/1/articles/2/likes/3".to_owned())
+        );
+        assert_eq!(
+            renamed(&rename, "/users/1/posts"),
+            Some("/users/1/articles".to_owned())
+        );
+        assert_eq!(renamed(&rename, "/users/1"), None);
+        assert_eq!(renamed(&rename, "/posts/1"), None);
+        assert_eq!(renamed(&rename, "/users/1/postsx/2"), None);
+
+        // Case matters, as in any reference comparison
+        let rename = Rename::new("/Posts", "posts").unwrap();
+        assert_eq!(renamed(&rename, "/Posts/1"), Some("/posts/1".to_owned()));
+        assert_eq!(renamed(&rename, "/posts/1"), None);
+    }
+
+    #[test]
+    fn test_rename_invalid() {
+        for (pattern, collection_id) in [
+            ("/users/{uid}", "customers"),
+            ("/users/{uid}/{collection}", "customers"),
+            ("/users/{rest=**}", "customers"),
+            ("/", "customers"),
+            ("/users", ""),
+            ("/users", "a/b"),
+            ("/users", "users"),
+            ("users", "customers"),
+        ] {
+            assert!(
+                Rename::new(pattern, collection_id).is_err(),
+                "{} {}",
+                pattern,
+                collection_id
+            );
+        }
+    }
+
+    #[test]
+    fn test_rename_rewrite() {
+        let rename = Rename::new("/users", "customers").unwrap();
+        let mut value = FsValue::from_plain_json(&serde_json::json!({
+            "owner": null,
+            "history": [null, {"by": null}],
+            "other": null
+        }));
+        value.set_field(&["owner".to_owned()], fs_reference("/users/1"));
+        value.set_field(
+            &["history".to_owned()],
+            FsValue::Array(vec![
+                fs_reference("/users/2/posts/1"),
+                FsValue::Map([("by".to_owned(), fs_reference("/users/3"))].into()),
+            ]),
+        );
+        value.set_field(&["other".to_owned()], fs_reference("/posts/1"));
+        let mut rewrites = Vec::new();
+        rename.rewrite(&mut value, "", &mut rewrites);
+        assert_eq!(
+            rewrites
+                .iter()
+                .map(|(path, old, new)| format!("{}: {} -> {}", path, old, new))
+                .collect::<Vec<_>>(),
+            vec![
+                "history[0]: /users/2/posts/1 -> /customers/2/posts/1",
+                "history[1].by: /users/3 -> /customers/3",
+                "owner: /users/1 -> /customers/1",
+            ]
+        );
+        assert_eq!(
+            value.get_field(&["owner".to_owned()]),
+            Some(&fs_reference("/customers/1"))
+        );
+        assert_eq!(
+            value.get_field(&["other".to_owned()]),
+            Some(&fs_reference("/posts/1"))
+        );
+    }
+
+    fn plan(
+        old_pattern: &str,
+        new_collection_id: &str,
+        dry_run: bool,
+    ) -> Vec<(String, String, String)> {
+        fs_rename_collection(old_pattern, new_collection_id, dry_run)
+            .map(|(kind, reference, detail)| (kind, fs_reference_text(reference), detail))
+            .collect()
+    }
+
+    fn exists(path: &str) -> bool {
+        Spi::get_one_with_args::<bool>(
+            "SELECT EXISTS (SELECT 1 FROM fs_documents WHERE reference = $1)",
+            vec![crate::fs_documents::fsvalue_arg(fs_reference(path))],
+        )
+        .expect("SPI failed")
+        .unwrap_or(false)
+    }
+
+    fn link(path: &str) -> Option<FsValue> {
+        Spi::get_one_with_args::<FsValue>(
+            "SELECT properties->'link' FROM fs_documents WHERE reference = $1",
+            vec![crate::fs_documents::fsvalue_arg(fs_reference(path))],
+        )
+        .expect("SPI failed")
+    }
+
+    fn expected_plan() -> Vec<(String, String, String)> {
+        [
+            ("document", "/users/1/posts/1", "/users/1/articles/1"),
+            ("document", "/users/1/posts/2", "/users/1/articles/2"),
+            (
+                "reference",
+                "/posts/1",
+                "link: /users/1/posts/1 -> /users/1/articles/1",
+            ),
+            (
+                "reference",
+                "/posts/2",
+                "link: /users/1/posts/2 -> /users/1/articles/2",
+            ),
+        ]
+        .iter()
+        .map(|(kind, reference, detail)| {
+            (kind.to_string(), reference.to_string(), detail.to_string())
+        })
+        .collect()
+    }
+
+    #[pg_test]
+    fn test_fs_rename_collection_dry_run() {
+        assert_eq!(
+            plan("/users/{uid}/posts", "articles", true),
+            expected_plan()
+        );
+        assert!(exists("/users/1/posts/1"));
+        assert!(!exists("/users/1/articles/1"));
+        assert_eq!(link("/posts/1"), Some(fs_reference("/users/1/posts/1")));
+    }
+
+    #[pg_test]
+    fn test_fs_rename_collection() {
+        assert_eq!(
+            plan("/users/{uid}/posts", "articles", false),
+            expected_plan()
+        );
+        assert!(!exists("/users/1/posts/1"));
+        assert!(exists("/users/1/articles/1"));
+        assert!(exists("/users/1/articles/2"));
+        assert_eq!(link("/posts/1"), Some(fs_reference("/users/1/articles/1")));
+        assert_eq!(link("/posts/2"), Some(fs_reference("/users/1/articles/2")));
+        // Top-level collections with the same ID are left alone
+        assert!(exists("/posts/1"));
+        assert_eq!(plan("/users/{uid}/posts", "articles", true), vec![]);
+    }
+
+    #[pg_test]
+    fn test_fs_rename_collection_collision() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/1/articles/2'), \
+                 fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        let mut expected = expected_plan();
+        expected.push((
+            "collision".to_owned(),
+            "/users/1/articles/2".to_owned(),
+            "/users/1/posts/2 would be renamed to an existing document".to_owned(),
+        ));
+        assert_eq!(plan("/users/{uid}/posts", "articles", true), expected);
+        assert!(exists("/users/1/posts/1"));
+        assert!(!exists("/users/1/articles/1"));
+        assert_eq!(link("/posts/1"), Some(fs_reference("/users/1/posts/1")));
+    }
+
+    #[pg_test(error = "Cannot rename /users/1/posts/2 to /users/1/articles/2 which already exists")]
+    fn test_fs_rename_collection_collision_fails() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/1/articles/2'), \
+                 fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        plan("/users/{uid}/posts", "articles", false);
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Expecting a pattern ending with a collection ID but found '/users/{uid}'"
+    )]
+    fn test_fs_rename_collection_document_pattern() {
+        plan("/users/{uid}", "customers", true);
+    }
+}
@@ -0,0 +1,384 @@
+// Logical replication of fs_documents
+//
+// Supported configurations:
+// - A publication including fs_documents, applied by a subscriber with
+//   pgfirestore installed at a release of the same ordering version, so
+//   that its indexes on fsvalue order rows like the publisher's.
+// - The subscription in text format (binary = false, the default), or in
+//   binary format with a subscriber reading the same version of fsvalue's
+//   binary format (see fs_binary.rs).
+// - pgfirestore.output_style = canonical for the replication user on the
+//   publisher. The walsender renders values with the type's output
+//   function, and readable output truncates long bytes.
+// - REPLICA IDENTITY DEFAULT, which uses the primary key on reference, or
+//   FULL. Updates and deletes need one of them to be published.
+//
+// Replicated values are applied by parsing the publisher's text output,
+// which the corpus below pins down. Its golden file only changes together
+// with the text format, and publisher and subscriber of releases with
+// different golden files must be upgraded together.
+use crate::fs_guc::{self, OutputStyle};
+use crate::fs_number::number_from_double;
+use crate::fs_ordering::ORDERING_VERSION;
+use crate::{FsNumber, FsReference, FsValue};
+use pgrx::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+// Lines of "name<TAB>canonical text", in corpus order
+const GOLDEN_TEXT: &str = include_str!("fs_replication_golden.txt");
+
+type Issue = (String, String);
+
+fn reference(path: &str) -> FsValue {
+    FsValue::Reference(FsReference::from_str(path).expect("corpus reference must be valid"))
+}
+
+fn integer(integer: i64) -> FsValue {
+    FsValue::Number(FsNumber::Number(integer.into()))
+}
+
+fn double(double: f64) -> FsValue {
+    FsValue::Number(number_from_double(double))
+}
+
+// A fixed value of every variant, with the edge cases of each text format
+fn corpus() -> Vec<(&'static str, FsValue)> {
+    vec![
+        ("null", FsValue::NULL),
+        ("boolean_true", FsValue::Boolean(true)),
+        ("boolean_false", FsValue::Boolean(false)),
+        ("integer_zero", integer(0)),
+        ("integer_negative", integer(-42)),
+        ("integer_max", integer(i64::MAX)),
+        ("integer_min", integer(i64::MIN)),
+        ("double_half", double(0.5)),
+        ("double_whole", double(1.0)),
+        ("double_third", double(1.0 / 3.0)),
+        ("double_negative_zero", double(-0.0)),
+        ("double_smallest", double(5e-324)),
+        ("double_largest", double(f64::MAX)),
+        ("double_exponent", double(1.5e-7)),
+        ("nan", FsValue::Number(FsNumber::NAN)),
+        ("infinity", FsValue::Number(FsNumber::PositiveInfinity)),
+        (
+            "negative_infinity",
+            FsValue::Number(FsNumber::NegativeInfinity),
+        ),
+        ("date", FsValue::Date(pgrx::Date::from(0))),
+        ("timestamp_epoch", FsValue::Timestamp(0)),
+        (
+            "timestamp_millis",
+            FsValue::Timestamp(1_706_702_400_500_000),
+        ),
+        (
+            "timestamp_micros",
+            FsValue::Timestamp(1_706_702_400_123_456),
+        ),
+        ("timestamp_min", FsValue::Timestamp(-62_135_596_800_000_000)),
+        ("timestamp_max", FsValue::Timestamp(253_402_300_799_999_999)),
+        ("string_empty", FsValue::String(String::new())),
+        (
+            "string_escapes",
+            FsValue::String("\"quoted\" \\ / \n\t\r \u{1} \u{7f}".to_owned()),
+        ),
+        (
+            "string_unicode",
+            FsValue::String("héllo 世界 🔥 \u{2028}".to_owned()),
+        ),
+        ("bytes_empty", FsValue::Bytes(vec![])),
+        ("bytes_all", FsValue::Bytes((0..=255).collect())),
+        ("reference_root", reference("/")),
+        ("reference_document", reference("/users/1")),
+        ("reference_nested", reference("/users/1/posts/héllo")),
+        (
+            "geopoint",
+            FsValue::GeoPoint(number_from_double(1.5), number_from_double(-2.25)),
+        ),
+        ("array_empty", FsValue::Array(vec![])),
+        (
+            "array_mixed",
+            FsValue::Array(vec![
+                FsValue::NULL,
+                integer(1),
+                double(2.5),
+                FsValue::String("a".to_owned()),
+                FsValue::Map(BTreeMap::from([("k".to_owned(), FsValue::Boolean(false))])),
+            ]),
+        ),
+        ("map_empty", FsValue::Map(BTreeMap::new())),
+        (
+            "map_nested",
+            FsValue::Map(BTreeMap::from([
+                ("b".to_owned(), integer(2)),
+                ("a".to_owned(), FsValue::Array(vec![reference("/users/2")])),
+                (
+                    "é".to_owned(),
+                    FsValue::Map(BTreeMap::from([("".to_owned(), FsValue::NULL)])),
+                ),
+                ("A".to_owned(), FsValue::Bytes(vec![0xff])),
+            ])),
+        ),
+    ]
+}
+
+// The value the type's input function reads a text as
+fn read_back(text: &str) -> Result<FsValue, String> {
+    FsValue::parse_text(text)
+        .map_err(|message| format!("{} cannot be read back: {}", text, message))
+}
+
+// Round-trip failures of the corpus, each of which stops documents holding
+// such a value from being applied, and texts that differ from the golden
+// ones
+fn corpus_issues(golden_text: &str) -> Vec<Issue> {
+    let golden: HashMap<&str, &str> = golden_text
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect();
+    let mut issues = Vec::new();
+    let mut round_trip_issue = |name: &str, value: &FsValue, error: String| {
+        issues.push((
+            "round_trip".to_owned(),
+            format!("{} {} {}", value.type_name(), name, error),
+        ))
+    };
+    let mut texts = Vec::new();
+    for (name, value) in corpus() {
+        // The canonical text, as the type's output function renders it
+        let text = value.to_json_value().to_string();
+        match read_back(&text) {
+            Ok(parsed) if parsed != value => {
+                round_trip_issue(name, &value, format!("reads back as {:?}", parsed))
+            }
+            Err(error) => round_trip_issue(name, &value, error),
+            _ => {}
+        }
+        texts.push((name, text));
+    }
+    for (name, text) in texts {
+        if Some(text.as_str()) != golden.get(name).copied() {
+            issues.push((
+                "text_drift".to_owned(),
+                format!(
+                    "{} is output as {} but the golden text is {}",
+                    name,
+                    text,
+                    golden.get(name).unwrap_or(&"missing")
+                ),
+            ));
+        }
+    }
+    issues
+}
+
+fn replica_identity_issues() -> Vec<Issue> {
+    let (identity, has_primary_key, has_identity_index) = Spi::connect(|client| {
+        let row = client
+            .select(
+                "SELECT c.relreplident::text, \
+                     EXISTS (SELECT 1 FROM pg_index i WHERE i.indrelid = c.oid AND i.indisprimary), \
+                     EXISTS (SELECT 1 FROM pg_index i \
+                         WHERE i.indrelid = c.oid AND i.indisreplident AND i.indisvalid) \
+                 FROM pg_class c WHERE c.oid = 'fs_documents'::regclass",
+                None,
+                None,
+            )?
+            .first();
+        Ok::<_, pgrx::spi::Error>((
+            row.get::<String>(1)?.expect("relreplident must not be null"),
+            row.get::<bool>(2)?.expect("has_primary_key must not be null"),
+            row.get::<bool>(3)?.expect("has_identity_index must not be null"),
+        ))
+    })
+    .expect("Failed to read the replica identity of fs_documents");
+    let detail = match identity.as_str() {
+        "d" if !has_primary_key => "fs_documents has REPLICA IDENTITY DEFAULT but no primary key",
+        "i" if !has_identity_index => "The replica identity index of fs_documents is missing",
+        "n" => "fs_documents has REPLICA IDENTITY NOTHING",
+        _ => return vec![],
+    };
+    vec![(
+        "replica_identity".to_owned(),
+        format!("{}, so updates and deletes cannot be published", detail),
+    )]
+}
+
+fn replication_issues(subscriber_ordering_version: Option<i32>) -> Vec<Issue> {
+    let mut issues = replica_identity_issues();
+    if fs_guc::OUTPUT_STYLE.get() != OutputStyle::Canonical {
+        issues.push((
+            "output_style".to_owned(),
+            "pgfirestore.output_style is not canonical, and readable output of bytes over 64 \
+             bytes cannot be applied"
+                .to_owned(),
+        ));
+    }
+    if let Some(version) = subscriber_ordering_version.filter(|v| *v != ORDERING_VERSION) {
+        issues.push((
+            "ordering_version".to_owned(),
+            format!(
+                "The subscriber orders fsvalue by version {} but the publisher by version {}",
+                version, ORDERING_VERSION
+            ),
+        ));
+    }
+    issues.extend(corpus_issues(GOLDEN_TEXT));
+    issues
+}
+
+// Problems with replicating fs_documents from this database, none when it is
+// ready. Run it as the replication user, and pass the subscriber's
+// fs_ordering_version() to compare it too.
+#[pg_extern]
+fn fs_replication_check(
+    subscriber_ordering_version: default!(Option<i32>, "NULL"),
+) -> TableIterator<'static, (name!(issue, String), name!(detail, String))> {
+    TableIterator::new(replication_issues(subscriber_ordering_version).into_iter())
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_replication::*;
+
+    #[test]
+    fn test_corpus_matches_golden_text() {
+        assert_eq!(corpus_issues(GOLDEN_TEXT), vec![]);
+    }
+
+    #[test]
+    fn test_golden_text_covers_corpus() {
+        let names: Vec<&str> = GOLDEN_TEXT
+            .lines()
+            .map(|line| {
+                line.split_once('\t')
+                    .expect("golden lines are tab separated")
+                    .0
+            })
+            .collect();
+        let expected: Vec<&str> = corpus().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_corpus_drift() {
+        let drifted = GOLDEN_TEXT.replace(
+            "double_whole\t{\"type\":\"NUMBER\",\"value\":1.0}",
+            "double_whole\t{\"type\":\"NUMBER\",\"value\":1}",
+        );
+        assert_ne!(drifted, GOLDEN_TEXT);
+        assert_eq!(
+            corpus_issues(&drifted),
+            vec![(
+                "text_drift".to_owned(),
+                "double_whole is output as {\"type\":\"NUMBER\",\"value\":1.0} but the golden \
+                 text is {\"type\":\"NUMBER\",\"value\":1}"
+                    .to_owned()
+            )]
+        );
+        let issues = corpus_issues("");
+        assert_eq!(issues.len(), corpus().len());
+        assert!(issues[0].1.ends_with("but the golden text is missing"));
+    }
+
+    fn check(query: &str) -> Vec<(String, String)> {
+        Spi::connect(|client| {
+            let mut issues = Vec::new();
+            for row in client.select(query, None, None)? {
+                issues.push((
+                    row.get::<String>(1)?.unwrap_or_default(),
+                    row.get::<String>(2)?.unwrap_or_default(),
+                ));
+            }
+            Ok::<_, pgrx::spi::Error>(issues)
+        })
+        .expect("SPI failed")
+    }
+
+    #[pg_test]
+    fn test_fs_replication_check() {
+        assert_eq!(check("SELECT * FROM fs_replication_check()"), vec![]);
+        assert_eq!(
+            check(&format!(
+                "SELECT * FROM fs_replication_check({})",
+                ORDERING_VERSION
+            )),
+            vec![]
+        );
+        assert_eq!(
+            check(&format!(
+                "SELECT * FROM fs_replication_check({})",
+                ORDERING_VERSION + 1
+            )),
+            vec![(
+                "ordering_version".to_owned(),
+                format!(
+                    "The subscriber orders fsvalue by version {} but the publisher by version {}",
+                    ORDERING_VERSION + 1,
+                    ORDERING_VERSION
+                )
+            )]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_replication_check_configuration() {
+        Spi::run(
+            "ALTER TABLE fs_documents REPLICA IDENTITY FULL; \
+             SET LOCAL pgfirestore.output_style = 'readable'",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            check("SELECT * FROM fs_replication_check()")
+                .into_iter()
+                .map(|(issue, _)| issue)
+                .collect::<Vec<_>>(),
+            vec!["output_style"]
+        );
+        Spi::run("ALTER TABLE fs_documents REPLICA IDENTITY NOTHING").expect("SPI failed");
+        assert_eq!(
+            check("SELECT * FROM fs_replication_check()")[0],
+            (
+                "replica_identity".to_owned(),
+                "fs_documents has REPLICA IDENTITY NOTHING, so updates and deletes cannot be \
+                 published"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[pg_test]
+    fn test_corpus_through_sql() {
+        // The input and output functions Postgres calls agree with the corpus
+        for (name, value) in corpus() {
+            if let Some((_, text)) = GOLDEN_TEXT
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .find(|(golden_name, _)| *golden_name == name)
+            {
+                assert_eq!(
+                    Spi::get_one_with_args::<String>(
+                        "SELECT $1::text",
+                        vec![(
+                            PgOid::from(FsValue::type_oid()),
+                            value.to_owned().into_datum()
+                        )],
+                    ),
+                    Ok(Some(text.to_owned())),
+                    "{}",
+                    name
+                );
+                assert_eq!(
+                    Spi::get_one_with_args::<FsValue>(
+                        "SELECT $1::fsvalue",
+                        vec![(PgBuiltInOids::TEXTOID.oid(), text.into_datum())],
+                    ),
+                    Ok(Some(value)),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+}
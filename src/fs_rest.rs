@@ -0,0 +1,1483 @@
+use crate::fs_bytes::bytes_value;
+use crate::fs_display::display_json;
+use crate::fs_documents::{
+    apply_update_mask, create_document, delete_document, fsvalue_arg, get_document,
+    parse_update_mask, set_document, text_arg,
+};
+use crate::fs_error::{report, FsCode};
+use crate::fs_geo::geo_point_value;
+use crate::fs_reference::{AUTO_ID_ALPHABET, AUTO_ID_LENGTH};
+use crate::fs_timestamp::{format_timestamp, parse_timestamp};
+use crate::{check_json_depth, FsError, FsNumber, FsReference, FsValue, FS_REFERENCE_ROOT};
+use base64::{engine::general_purpose, Engine as _};
+use pgrx::prelude::*;
+use pgrx::{JsonB, PgBuiltInOids};
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// Conversions between fsvalue and the JSON mapping of the Firestore REST API
+// (google.firestore.v1.Value and google.firestore.v1.Document).
+
+pub(crate) const DEFAULT_DATABASE: &str = "projects/pgfirestore/databases/(default)";
+
+// Auto IDs tried before giving up on a collection, should one collide
+pub(crate) const AUTO_ID_ATTEMPTS: usize = 5;
+
+// Resource names carry IDs as they are, without the escaping of the text form
+fn rest_reference_name(database: &str, reference: &FsReference) -> String {
+    format!("{}/documents/{}", database, reference.segments().join("/"))
+}
+
+// Parses a resource name such as
+// `projects/p/databases/(default)/documents/users/1` into the database name and
+// the (possibly root or collection) reference it points to.
+fn parse_rest_resource_name(name: &str) -> Result<(String, FsReference)> {
+    let invalid = || {
+        FsError::InvalidValue(format!(
+            "Expecting a resource name of the form projects/{{project}}/databases/{{database}}/documents/... but found '{}'",
+            name
+        ))
+    };
+    let segments: Vec<&str> = name.split('/').collect();
+    if segments.len() < 5
+        || segments[0] != "projects"
+        || segments[2] != "databases"
+        || segments[4] != "documents"
+        || segments[1].is_empty()
+        || segments[3].is_empty()
+    {
+        return Err(invalid());
+    }
+    let database = segments[..4].join("/");
+    if segments.len() == 5 {
+        return Ok((database, FS_REFERENCE_ROOT));
+    }
+    let path = &segments[5..];
+    if path.iter().any(|segment| segment.is_empty()) {
+        return Err(invalid());
+    }
+    Ok((database, FsReference::from_segments(path)?))
+}
+
+// The JSON encoding of a double, with non-finite values spelled as strings
+fn to_rest_double(number: &FsNumber) -> Value {
+    match number {
+        FsNumber::NAN => json!("NaN"),
+        FsNumber::PositiveInfinity => json!("Infinity"),
+        FsNumber::NegativeInfinity => json!("-Infinity"),
+        FsNumber::Number(number) => json!(number),
+    }
+}
+
+fn to_rest_number(number: &FsNumber) -> Value {
+    match number {
+        FsNumber::Number(number) if number.is_i64() || number.is_u64() => {
+            json!({ "integerValue": number.to_string() })
+        }
+        _ => json!({ "doubleValue": to_rest_double(number) }),
+    }
+}
+
+pub(crate) fn to_rest_value(value: &FsValue, database: &str) -> Result<Value> {
+    Ok(match value {
+        FsValue::NULL => json!({ "nullValue": null }),
+        FsValue::Boolean(boolean) => json!({ "booleanValue": boolean }),
+        FsValue::Number(number) => to_rest_number(number),
+        FsValue::String(string) => json!({ "stringValue": string }),
+        FsValue::Bytes(bytes) => json!({ "bytesValue": general_purpose::STANDARD.encode(bytes) }),
+        FsValue::Reference(reference) => {
+            json!({ "referenceValue": rest_reference_name(database, reference) })
+        }
+        FsValue::GeoPoint(latitude, longitude) => json!({
+            "geoPointValue": {
+                "latitude": to_rest_double(latitude),
+                "longitude": to_rest_double(longitude),
+            }
+        }),
+        FsValue::Array(array) => json!({
+            "arrayValue": {
+                "values": array
+                    .iter()
+                    .map(|element| to_rest_value(element, database))
+                    .collect::<Result<Vec<Value>>>()?,
+            }
+        }),
+        FsValue::Map(map) => json!({ "mapValue": { "fields": to_rest_fields(map, database)? } }),
+        FsValue::Timestamp(micros) => json!({ "timestampValue": format_timestamp(*micros) }),
+        FsValue::Date(_) => {
+            return Err(FsError::InvalidType(
+                "DATE values have no REST representation".to_string(),
+            ))
+        }
+    })
+}
+
+fn to_rest_fields(map: &BTreeMap<String, FsValue>, database: &str) -> Result<Map<String, Value>> {
+    map.iter()
+        .map(|(key, value)| Ok((key.to_owned(), to_rest_value(value, database)?)))
+        .collect()
+}
+
+fn from_rest_number(value: &Value) -> Result<FsNumber> {
+    match value {
+        Value::Number(number) => Ok(FsNumber::from(number.to_owned())),
+        Value::String(string) => FsNumber::from_str(string),
+        _ => Err(FsError::InvalidValue(format!(
+            "Failed to parse {} as a REST number",
+            display_json(value)
+        ))),
+    }
+}
+
+// Fields of a REST message other than `known` are rejected rather than dropped
+fn expect_known_fields(kind: &str, message: &Map<String, Value>, known: &[&str]) -> Result<()> {
+    match message
+        .keys()
+        .find(|field| !known.contains(&field.as_str()))
+    {
+        Some(field) => Err(FsError::InvalidValue(format!(
+            "Unknown field '{}' in REST {}",
+            field, kind
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn from_rest_value(value: &Value) -> Result<FsValue> {
+    let (kind, inner) = match value.as_object() {
+        Some(object) if object.len() == 1 => object.iter().next().unwrap(),
+        _ => {
+            return Err(FsError::InvalidValue(format!(
+                "Expecting a REST value with exactly one field but found {}",
+                display_json(value)
+            )))
+        }
+    };
+    let invalid = || {
+        FsError::InvalidValue(format!(
+            "Failed to parse {} as a REST {}",
+            display_json(inner),
+            kind
+        ))
+    };
+    match kind.as_str() {
+        "nullValue" => Ok(FsValue::NULL),
+        "booleanValue" => inner.as_bool().map(FsValue::Boolean).ok_or_else(invalid),
+        "integerValue" => {
+            let integer = match inner {
+                Value::String(string) => string.parse::<i64>().map_err(|_| invalid())?,
+                _ => inner.as_i64().ok_or_else(invalid)?,
+            };
+            Ok(FsValue::Number(FsNumber::Number(serde_json::Number::from(
+                integer,
+            ))))
+        }
+        "doubleValue" => Ok(FsValue::Number(from_rest_number(inner)?)),
+        "stringValue" => inner
+            .as_str()
+            .map(|string| FsValue::String(string.to_owned()))
+            .ok_or_else(invalid),
+        "bytesValue" => general_purpose::STANDARD
+            .decode(inner.as_str().ok_or_else(invalid)?)
+            .map_err(|_| invalid())
+            .and_then(bytes_value),
+        "referenceValue" => {
+            let (_, reference) = parse_rest_resource_name(inner.as_str().ok_or_else(invalid)?)?;
+            Ok(FsValue::Reference(reference))
+        }
+        "geoPointValue" => {
+            let point = inner.as_object().ok_or_else(invalid)?;
+            expect_known_fields(kind, point, &["latitude", "longitude"])?;
+            let coordinate = |name: &str| match point.get(name) {
+                Some(value) => from_rest_number(value),
+                None => Ok(FsNumber::Number(serde_json::Number::from(0))),
+            };
+            geo_point_value(
+                coordinate("latitude")?.as_double(),
+                coordinate("longitude")?.as_double(),
+            )
+        }
+        "arrayValue" => {
+            let message = inner.as_object().ok_or_else(invalid)?;
+            expect_known_fields(kind, message, &["values"])?;
+            let values = match message.get("values") {
+                Some(values) => values.as_array().ok_or_else(invalid)?.to_owned(),
+                None => Vec::new(),
+            };
+            let array = values
+                .iter()
+                .map(from_rest_value)
+                .collect::<Result<Vec<FsValue>>>()?;
+            FsValue::check_array_nesting(&array)?;
+            Ok(FsValue::Array(array))
+        }
+        "mapValue" => {
+            let message = inner.as_object().ok_or_else(invalid)?;
+            expect_known_fields(kind, message, &["fields"])?;
+            from_rest_fields(message.get("fields"))
+        }
+        "timestampValue" => {
+            parse_timestamp(inner.as_str().ok_or_else(invalid)?).map(FsValue::Timestamp)
+        }
+        _ => Err(FsError::InvalidValue(format!(
+            "Unknown REST value type '{}'",
+            kind
+        ))),
+    }
+}
+
+// Parses the `fields` of a REST Document or mapValue into a map fsvalue; a
+// missing `fields` is an empty map.
+fn from_rest_fields(fields: Option<&Value>) -> Result<FsValue> {
+    let fields = match fields {
+        Some(Value::Object(fields)) => fields.to_owned(),
+        Some(Value::Null) | None => Map::new(),
+        Some(other) => {
+            return Err(FsError::InvalidValue(format!(
+                "Expecting an object for REST fields but found {}",
+                display_json(other)
+            )))
+        }
+    };
+    let mut map = BTreeMap::new();
+    for (key, value) in fields.iter() {
+        map.insert(key.to_owned(), from_rest_value(value)?);
+    }
+    Ok(FsValue::Map(map))
+}
+
+fn to_rest_document(
+    database: &str,
+    reference: &FsReference,
+    properties: &FsValue,
+) -> Result<Value> {
+    let fields = match properties {
+        FsValue::Map(map) => to_rest_fields(map, database)?,
+        _ => {
+            return Err(FsError::InvalidValue(
+                "Expecting a map fsvalue for document properties".to_string(),
+            ))
+        }
+    };
+    Ok(json!({
+        "name": rest_reference_name(database, reference),
+        "fields": fields,
+    }))
+}
+
+#[pg_extern]
+fn fs_to_rest_document(
+    reference: FsValue,
+    properties: FsValue,
+    database: default!(&str, "'projects/pgfirestore/databases/(default)'"),
+) -> JsonB {
+    let fs_ref = reference.expect_reference();
+    match to_rest_document(database, fs_ref, &properties) {
+        Ok(document) => JsonB(document),
+        Err(error) => error.report(),
+    }
+}
+
+// A value in the google.firestore.v1.Value JSON mapping. References name
+// documents of `database`.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_to_api_json(
+    value: FsValue,
+    database: default!(&str, "'projects/pgfirestore/databases/(default)'"),
+) -> JsonB {
+    match to_rest_value(&value, database) {
+        Ok(json) => JsonB(json),
+        Err(error) => error.report(),
+    }
+}
+
+// The database of a referenceValue is dropped, so references to documents of
+// any database read as the same path
+#[pg_extern(immutable, parallel_safe)]
+fn fs_from_api_json(json: JsonB) -> FsValue {
+    match check_json_depth(&json.0).and_then(|_| from_rest_value(&json.0)) {
+        Ok(value) => value,
+        Err(error) => error.report(),
+    }
+}
+
+// An error in the shape of a google.rpc.Status as returned by the REST API
+struct RestError {
+    code: u16,
+    status: &'static str,
+    message: String,
+}
+
+impl RestError {
+    fn new(code: u16, status: &'static str, message: String) -> RestError {
+        RestError {
+            code,
+            status,
+            message,
+        }
+    }
+
+    fn invalid_argument(message: String) -> RestError {
+        RestError::new(400, "INVALID_ARGUMENT", message)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "error": {
+                "code": self.code,
+                "message": self.message,
+                "status": self.status,
+            }
+        })
+    }
+}
+
+impl From<FsError> for RestError {
+    fn from(error: FsError) -> RestError {
+        RestError::invalid_argument(error.to_string())
+    }
+}
+
+struct RestRequest {
+    database: String,
+    reference: FsReference,
+    query: Vec<(String, String)>,
+}
+
+fn percent_decode(input: &str) -> std::result::Result<String, RestError> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = input
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| {
+                        RestError::invalid_argument(format!(
+                            "Invalid percent-encoding in '{}'",
+                            input
+                        ))
+                    })?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded)
+        .map_err(|_| RestError::invalid_argument(format!("Invalid UTF-8 in '{}'", input)))
+}
+
+fn parse_rest_request(path: &str) -> std::result::Result<RestRequest, RestError> {
+    let (resource, query_string) = match path.split_once('?') {
+        Some((resource, query_string)) => (resource, Some(query_string)),
+        None => (path, None),
+    };
+    let resource = resource.trim_start_matches('/');
+    let resource = resource.strip_prefix("v1/").unwrap_or(resource);
+    let (database, reference) = parse_rest_resource_name(&percent_decode(resource)?)
+        .map_err(|error| RestError::new(404, "NOT_FOUND", error.to_string()))?;
+    let mut query = Vec::new();
+    for pair in query_string
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        query.push((percent_decode(key)?, percent_decode(value)?));
+    }
+    Ok(RestRequest {
+        database,
+        reference,
+        query,
+    })
+}
+
+// Field paths of the update mask, taken from `updateMask.fieldPaths` query
+// parameters or from an `updateMask` object in the body wrapper.
+fn update_mask_field_paths(request: &RestRequest, body: &Value) -> Option<Vec<String>> {
+    let mut field_paths: Vec<String> = request
+        .query
+        .iter()
+        .filter(|(key, _)| key == "updateMask.fieldPaths")
+        .map(|(_, value)| value.to_owned())
+        .collect();
+    if let Some(Value::Array(paths)) = body.pointer("/updateMask/fieldPaths") {
+        field_paths.extend(
+            paths
+                .iter()
+                .filter_map(|path| path.as_str().map(str::to_owned)),
+        );
+    }
+    if field_paths.is_empty() && body.get("updateMask").is_none() {
+        None
+    } else {
+        Some(field_paths)
+    }
+}
+
+// The Document of a request body, which is either the Document itself or a
+// wrapper of the form {"document": {...}, "updateMask": {...}}.
+fn request_document(body: &Value) -> std::result::Result<FsValue, RestError> {
+    check_json_depth(body)?;
+    let document = body.get("document").unwrap_or(body);
+    from_rest_fields(document.get("fields")).map_err(RestError::from)
+}
+
+// A Firestore style auto ID. thread_rng is a CSPRNG seeded from the OS, so
+// IDs are not predictable from earlier ones.
+pub(crate) fn generate_document_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..AUTO_ID_LENGTH)
+        .map(|_| AUTO_ID_ALPHABET[rng.gen_range(0..AUTO_ID_ALPHABET.len())] as char)
+        .collect()
+}
+
+fn not_found(request: &RestRequest) -> RestError {
+    RestError::new(
+        404,
+        "NOT_FOUND",
+        format!(
+            "Document \"{}\" not found",
+            rest_reference_name(&request.database, &request.reference)
+        ),
+    )
+}
+
+fn expect_document_path(request: &RestRequest, method: &str) -> std::result::Result<(), RestError> {
+    if request.reference.is_root() || !request.reference.has_complete_path() {
+        return Err(RestError::invalid_argument(format!(
+            "{} expects a document path but found \"{}\"",
+            method,
+            rest_reference_name(&request.database, &request.reference)
+        )));
+    }
+    Ok(())
+}
+
+fn handle_get(request: &RestRequest) -> std::result::Result<Value, RestError> {
+    expect_document_path(request, "GET")?;
+    let properties = get_document(&request.reference).ok_or_else(|| not_found(request))?;
+    to_rest_document(&request.database, &request.reference, &properties).map_err(RestError::from)
+}
+
+fn handle_patch(request: &RestRequest, body: &Value) -> std::result::Result<Value, RestError> {
+    expect_document_path(request, "PATCH")?;
+    let patch = request_document(body)?;
+    let properties = match update_mask_field_paths(request, body) {
+        Some(field_paths) => {
+            let update_mask = parse_update_mask(&field_paths)?;
+            let existing = get_document(&request.reference).unwrap_or(FsValue::NULL);
+            apply_update_mask(existing, &patch, &update_mask)
+        }
+        None => patch,
+    };
+    let document = to_rest_document(&request.database, &request.reference, &properties)?;
+    set_document(&request.reference, properties, true, false);
+    Ok(document)
+}
+
+fn handle_delete(request: &RestRequest) -> std::result::Result<Value, RestError> {
+    expect_document_path(request, "DELETE")?;
+    // Like Firestore, deleting a missing document is not an error
+    delete_document(&request.reference, false);
+    Ok(json!({}))
+}
+
+fn handle_post(request: &RestRequest, body: &Value) -> std::result::Result<Value, RestError> {
+    if request.reference.is_root() || request.reference.has_complete_path() {
+        return Err(RestError::invalid_argument(format!(
+            "POST expects a collection path but found \"{}\"",
+            rest_reference_name(&request.database, &request.reference)
+        )));
+    }
+    let properties = request_document(body)?;
+    let parent = request.reference.parent();
+    let collection_id = request.reference.collection_id();
+    let requested_id = request
+        .query
+        .iter()
+        .find(|(key, _)| key == "documentId")
+        .map(|(_, value)| value.to_owned())
+        .or_else(|| {
+            body.get("documentId")
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+        });
+    let attempts = if requested_id.is_some() {
+        1
+    } else {
+        AUTO_ID_ATTEMPTS
+    };
+    for _ in 0..attempts {
+        let document_id = requested_id.clone().unwrap_or_else(generate_document_id);
+        let reference = parent.child(collection_id, &document_id)?;
+        let document = to_rest_document(&request.database, &reference, &properties)?;
+        if create_document(&reference, properties.to_owned()) {
+            return Ok(document);
+        }
+    }
+    Err(RestError::new(
+        409,
+        "ALREADY_EXISTS",
+        format!(
+            "Document already exists in collection \"{}\"",
+            rest_reference_name(&request.database, &request.reference)
+        ),
+    ))
+}
+
+// A minimal emulation of the Firestore REST documents resource. Errors in the
+// request are returned as a google.rpc.Status style {"error": {...}} object.
+#[pg_extern]
+fn fs_rest_handle(method: &str, path: &str, body: Option<JsonB>) -> JsonB {
+    let body = body.map(|body| body.0).unwrap_or(Value::Null);
+    let result =
+        parse_rest_request(path).and_then(|request| match method.to_ascii_uppercase().as_str() {
+            "GET" => handle_get(&request),
+            "PATCH" => handle_patch(&request, &body),
+            "DELETE" => handle_delete(&request),
+            "POST" => handle_post(&request, &body),
+            _ => Err(RestError::new(
+                501,
+                "UNIMPLEMENTED",
+                format!("Method {} is not supported", method),
+            )),
+        });
+    JsonB(result.unwrap_or_else(|error| error.to_json()))
+}
+
+// A document of an import payload, in a form ready to be written
+struct ImportedDocument {
+    reference: FsReference,
+    properties: FsValue,
+    create_time: Option<String>,
+    update_time: Option<String>,
+}
+
+// The Documents of a ListDocuments page ({"documents": [...]}) or of a
+// runQuery response ([{"document": {...}}, ...]). runQuery elements without a
+// document, such as the readTime-only element of an empty result, are skipped.
+fn import_payload_documents(payload: &Value) -> Result<Vec<&Value>> {
+    check_json_depth(payload)?;
+    match payload {
+        Value::Object(page) => match page.get("documents") {
+            Some(Value::Array(documents)) => Ok(documents.iter().collect()),
+            None => Ok(Vec::new()),
+            Some(other) => Err(FsError::InvalidValue(format!(
+                "Expecting an array for 'documents' but found {}",
+                display_json(other)
+            ))),
+        },
+        Value::Array(results) => Ok(results
+            .iter()
+            .filter_map(|result| result.get("document"))
+            .collect()),
+        _ => Err(FsError::InvalidValue(format!(
+            "Expecting a ListDocuments or runQuery response but found {}",
+            display_json(payload)
+        ))),
+    }
+}
+
+// Converts a REST Document, remapping documents of another database to this
+// one when `remap_databases` is set and rejecting them otherwise.
+fn import_document(
+    document: &Value,
+    database: &str,
+    remap_databases: bool,
+) -> Result<ImportedDocument> {
+    let name = document
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a 'name' in document {}",
+                display_json(document)
+            ))
+        })?;
+    let (document_database, reference) = parse_rest_resource_name(name)?;
+    if document_database != database && !remap_databases {
+        return Err(FsError::InvalidValue(format!(
+            "Document {} belongs to database {} rather than {}",
+            name, document_database, database
+        )));
+    }
+    if reference.is_root() || !reference.has_complete_path() {
+        return Err(FsError::InvalidValue(format!(
+            "Expecting a document name but found '{}'",
+            name
+        )));
+    }
+    Ok(ImportedDocument {
+        reference,
+        properties: from_rest_fields(document.get("fields"))?,
+        create_time: document
+            .get("createTime")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+        update_time: document
+            .get("updateTime")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+    })
+}
+
+// Writes a document, returning what happened to it under `on_conflict`
+fn write_imported_document(document: ImportedDocument, on_conflict: &str) -> &'static str {
+    let query = match on_conflict {
+        "upsert" => {
+            "INSERT INTO fs_documents (reference, properties, update_time, create_time) \
+             VALUES ($1, $2, COALESCE($3::timestamptz, fs_request_time()), \
+                 COALESCE($4::timestamptz, fs_request_time())) \
+             ON CONFLICT (reference) DO UPDATE \
+             SET properties = EXCLUDED.properties, update_time = EXCLUDED.update_time, \
+                 create_time = COALESCE($4::timestamptz, CASE WHEN fs_documents.deleted_at IS NULL \
+                     THEN fs_documents.create_time ELSE fs_request_time() END), \
+                 deleted_at = NULL \
+             RETURNING xmax = 0"
+        }
+        _ => {
+            "INSERT INTO fs_documents (reference, properties, update_time, create_time) \
+             VALUES ($1, $2, COALESCE($3::timestamptz, fs_request_time()), \
+                 COALESCE($4::timestamptz, fs_request_time())) \
+             ON CONFLICT (reference) DO UPDATE \
+             SET properties = EXCLUDED.properties, update_time = EXCLUDED.update_time, \
+                 create_time = EXCLUDED.create_time, deleted_at = NULL \
+             WHERE fs_documents.deleted_at IS NOT NULL \
+             RETURNING true"
+        }
+    };
+    let inserted = Spi::connect(|mut client| {
+        let table = client.update(
+            query,
+            None,
+            Some(vec![
+                fsvalue_arg(FsValue::Reference(document.reference.to_owned())),
+                fsvalue_arg(document.properties),
+                (
+                    PgBuiltInOids::TEXTOID.oid(),
+                    document.update_time.into_datum(),
+                ),
+                (
+                    PgBuiltInOids::TEXTOID.oid(),
+                    document.create_time.into_datum(),
+                ),
+            ]),
+        )?;
+        match table.is_empty() {
+            true => Ok(None),
+            false => table.first().get_one::<bool>(),
+        }
+    })
+    .expect("Failed to write to fs_documents");
+    match (inserted, on_conflict) {
+        (Some(true), _) => "inserted",
+        (Some(false), _) => "updated",
+        (None, "skip") => "skipped",
+        (None, _) => report(
+            FsCode::AlreadyExists,
+            format!("Document {} already exists", document.reference),
+        ),
+    }
+}
+
+fn check_conflict_mode(on_conflict: &str) {
+    if !matches!(on_conflict, "upsert" | "skip" | "error") {
+        FsError::InvalidValue(format!(
+            "Unknown conflict mode '{}', expecting 'upsert', 'skip' or 'error'",
+            on_conflict
+        ))
+        .report()
+    }
+}
+
+// Imports the documents of a ListDocuments page or runQuery response of the
+// Firestore REST API. Every document is converted before any is written.
+#[pg_extern]
+fn fs_import_rest_documents(
+    payload: JsonB,
+    on_conflict: default!(&str, "'upsert'"),
+    database: default!(&str, "'projects/pgfirestore/databases/(default)'"),
+    remap_databases: default!(bool, true),
+) -> TableIterator<'static, (name!(reference, FsValue), name!(action, String))> {
+    check_conflict_mode(on_conflict);
+    let documents = import_payload_documents(&payload.0).and_then(|documents| {
+        documents
+            .into_iter()
+            .map(|document| import_document(document, database, remap_databases))
+            .collect::<Result<Vec<ImportedDocument>>>()
+    });
+    let documents = match documents {
+        Ok(documents) => documents,
+        Err(error) => error.report(),
+    };
+    TableIterator::new(write_imported_documents(documents, on_conflict).into_iter())
+}
+
+// Writes documents in order, checking for interrupts between them
+fn write_imported_documents(
+    documents: Vec<ImportedDocument>,
+    on_conflict: &str,
+) -> Vec<(FsValue, String)> {
+    documents
+        .into_iter()
+        .map(|document| {
+            check_for_interrupts!();
+            let reference = FsValue::Reference(document.reference.to_owned());
+            (
+                reference,
+                write_imported_document(document, on_conflict).to_owned(),
+            )
+        })
+        .collect()
+}
+
+// How far an import job got through its source: the lines it processed and
+// the byte offset at which the next one starts
+struct ImportOffset {
+    lines: usize,
+    bytes: usize,
+}
+
+// Claims import job `job_id` for `source`, returning its offset. The row
+// stays locked until the end of the transaction, so two batches of a job
+// never overlap.
+fn claim_import_job(job_id: &str, source: &str) -> ImportOffset {
+    let claimed = Spi::connect(|mut client| {
+        let table = client.update(
+            "INSERT INTO fs_import_jobs (job_id, source) VALUES ($1, $2) \
+             ON CONFLICT (job_id) DO UPDATE SET updated_at = clock_timestamp() \
+             RETURNING source, lines_processed, byte_offset",
+            Some(1),
+            Some(vec![text_arg(job_id), text_arg(source)]),
+        )?;
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>((
+            row.get::<String>(1)?,
+            row.get::<i64>(2)?,
+            row.get::<i64>(3)?,
+        ))
+    });
+    let (job_source, lines, bytes) = match claimed {
+        Ok((Some(job_source), Some(lines), Some(bytes))) => (job_source, lines, bytes),
+        Ok(row) => error!("Import job '{}' has an incomplete row: {:?}", job_id, row),
+        Err(error) => error!("Failed to claim import job '{}': {}", job_id, error),
+    };
+    if job_source != source {
+        report(
+            FsCode::FailedPrecondition,
+            format!(
+                "Import job '{}' imports source '{}' rather than '{}'",
+                job_id, job_source, source
+            ),
+        )
+    }
+    ImportOffset {
+        lines: lines as usize,
+        bytes: bytes as usize,
+    }
+}
+
+fn record_import_progress(
+    job_id: &str,
+    offset: &ImportOffset,
+    written: usize,
+    error: Option<String>,
+) {
+    let recorded = Spi::run_with_args(
+        "UPDATE fs_import_jobs \
+         SET lines_processed = $2, byte_offset = $3, \
+             documents_written = documents_written + $4, \
+             last_error = $5, updated_at = clock_timestamp() \
+         WHERE job_id = $1",
+        Some(vec![
+            text_arg(job_id),
+            (
+                PgBuiltInOids::INT8OID.oid(),
+                (offset.lines as i64).into_datum(),
+            ),
+            (
+                PgBuiltInOids::INT8OID.oid(),
+                (offset.bytes as i64).into_datum(),
+            ),
+            (PgBuiltInOids::INT8OID.oid(), (written as i64).into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), error.into_datum()),
+        ]),
+    );
+    if let Err(error) = recorded {
+        error!(
+            "Failed to record the progress of import job '{}': {}",
+            job_id, error
+        )
+    }
+}
+
+// The document on line `line` of an NDJSON payload, None for a blank line
+fn import_ndjson_line(
+    text: &str,
+    line: usize,
+    database: &str,
+    remap_databases: bool,
+) -> Result<Option<ImportedDocument>> {
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    let context = format!("Line {}", line);
+    let document: Value = serde_json::from_str(text).map_err(|error| {
+        FsError::InvalidValue(format!("{} is not a JSON document: {}", context, error))
+    })?;
+    check_json_depth(&document)
+        .and_then(|_| import_document(&document, database, remap_databases))
+        .map(Some)
+        .map_err(|error| error.with_context(&context))
+}
+
+// Imports the next `batch_size` lines of an NDJSON payload as job `job_id`,
+// each line a REST Document like those of fs_import_rest_documents, and
+// records how far the job got. Returns whether lines are left.
+// fs_import_ndjson_resume calls it once per transaction, so that every
+// batch commits its documents and progress together. An invalid line stops
+// the batch before it, recording the error, and leaves no lines to import
+// until the job is run again.
+#[pg_extern]
+fn fs_import_ndjson_batch(
+    job_id: &str,
+    source: &str,
+    payload: &str,
+    on_conflict: default!(&str, "'upsert'"),
+    database: default!(&str, "'projects/pgfirestore/databases/(default)'"),
+    remap_databases: default!(bool, true),
+    batch_size: default!(i32, 1000),
+) -> bool {
+    check_conflict_mode(on_conflict);
+    if batch_size < 1 {
+        FsError::InvalidValue(format!(
+            "Expecting a positive batch size but found {}",
+            batch_size
+        ))
+        .report()
+    }
+    let mut offset = claim_import_job(job_id, source);
+    // A job resumes at the start of a line of the same payload
+    let starts_line = offset.bytes == 0 || payload.as_bytes().get(offset.bytes - 1) == Some(&b'\n');
+    if offset.bytes > payload.len() || !starts_line {
+        report(
+            FsCode::FailedPrecondition,
+            format!(
+                "Import job '{}' stopped at byte {} of source '{}', which is not the start \
+                 of a line of this payload",
+                job_id, offset.bytes, source
+            ),
+        )
+    }
+    let mut documents = Vec::new();
+    let mut error = None;
+    for text in payload[offset.bytes..]
+        .split_inclusive('\n')
+        .take(batch_size as usize)
+    {
+        check_for_interrupts!();
+        match import_ndjson_line(text, offset.lines + 1, database, remap_databases) {
+            Ok(document) => documents.extend(document),
+            Err(invalid) => {
+                error = Some(invalid);
+                break;
+            }
+        }
+        offset.lines += 1;
+        offset.bytes += text.len();
+    }
+    let written = write_imported_documents(documents, on_conflict)
+        .iter()
+        .filter(|(_, action)| action != "skipped")
+        .count();
+    let stopped = error.is_some();
+    record_import_progress(
+        job_id,
+        &offset,
+        written,
+        error.map(|error| error.to_string()),
+    );
+    !stopped && offset.bytes < payload.len()
+}
+
+// fs_import_ndjson_resume commits after every batch, so it must be called
+// outside a transaction block. It fails once the batches are committed if
+// the job stopped at an invalid line, with the SQLSTATE of INVALID_ARGUMENT.
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_import_jobs (\n\
+            job_id text PRIMARY KEY,\n\
+            source text NOT NULL,\n\
+            lines_processed bigint NOT NULL DEFAULT 0,\n\
+            byte_offset bigint NOT NULL DEFAULT 0,\n\
+            documents_written bigint NOT NULL DEFAULT 0,\n\
+            last_error text,\n\
+            started_at timestamptz NOT NULL DEFAULT clock_timestamp(),\n\
+            updated_at timestamptz NOT NULL DEFAULT clock_timestamp()\n\
+        );\n\
+        CREATE FUNCTION fs_import_status(job_id text) RETURNS SETOF fs_import_jobs \n\
+        AS $$ SELECT * FROM fs_import_jobs WHERE fs_import_jobs.job_id = $1 $$ \n\
+        LANGUAGE sql STABLE; \n\
+        CREATE PROCEDURE fs_import_ndjson_resume( \n\
+            job_id text, \n\
+            source text, \n\
+            payload text, \n\
+            on_conflict text DEFAULT 'upsert', \n\
+            database text DEFAULT 'projects/pgfirestore/databases/(default)', \n\
+            remap_databases boolean DEFAULT true, \n\
+            batch_size integer DEFAULT 1000) \n\
+        LANGUAGE plpgsql AS $$ \n\
+        DECLARE \n\
+            more boolean := true; \n\
+            stopped_at bigint; \n\
+            failure text; \n\
+            state text; \n\
+        BEGIN \n\
+            WHILE more LOOP \n\
+                more := fs_import_ndjson_batch($1, $2, $3, $4, $5, $6, $7); \n\
+                COMMIT; \n\
+            END LOOP; \n\
+            SELECT s.lines_processed, s.last_error INTO stopped_at, failure \n\
+            FROM fs_import_status($1) s; \n\
+            IF failure IS NOT NULL THEN \n\
+                SELECT c.sqlstate INTO state FROM fs_error_codes() c WHERE c.code = 'INVALID_ARGUMENT'; \n\
+                RAISE EXCEPTION USING ERRCODE = state, DETAIL = 'fs_code=INVALID_ARGUMENT', \n\
+                    MESSAGE = format('Import job ''%s'' stopped at line %s: %s', $1, stopped_at + 1, failure); \n\
+            END IF; \n\
+        END $$; \n\
+    ",
+    name = "import_jobs",
+    requires = [fs_import_ndjson_batch, fs_error::fs_error_codes],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_documents::get_document;
+    use crate::fs_rest::*;
+    use crate::{fs_map_from_entries, fs_number_from_integer, fs_string};
+
+    fn integer(value: i64) -> FsValue {
+        FsValue::Number(FsNumber::Number(serde_json::Number::from(value)))
+    }
+
+    #[test]
+    fn test_parse_rest_resource_name() {
+        let (database, reference) =
+            parse_rest_resource_name("projects/p/databases/(default)/documents/users/1").unwrap();
+        assert_eq!(database, "projects/p/databases/(default)");
+        assert_eq!(reference, FsReference::from_str("/users/1").unwrap());
+        let (_, root) = parse_rest_resource_name("projects/p/databases/d/documents").unwrap();
+        assert!(root.is_root());
+        assert!(parse_rest_resource_name("projects/p/documents/users/1").is_err());
+        assert!(parse_rest_resource_name("projects/p/databases/d/documents//1").is_err());
+    }
+
+    #[test]
+    fn test_rest_reference_name_unescaped() {
+        let reference = FsReference::from_str("/users/50%25 off").unwrap();
+        assert_eq!(reference.to_string(), "/users/50%25 off");
+        let name = rest_reference_name("projects/p/databases/d", &reference);
+        assert_eq!(name, "projects/p/databases/d/documents/users/50% off");
+        assert_eq!(parse_rest_resource_name(&name).unwrap().1, reference);
+    }
+
+    #[test]
+    fn test_rest_value_round_trip() {
+        let value = FsValue::Map(BTreeMap::from([
+            ("null".to_owned(), FsValue::NULL),
+            ("int".to_owned(), integer(-3)),
+            (
+                "double".to_owned(),
+                FsValue::Number(FsNumber::Number(serde_json::Number::from_f64(1.5).unwrap())),
+            ),
+            ("nan".to_owned(), FsValue::Number(FsNumber::NAN)),
+            ("time".to_owned(), FsValue::Timestamp(1_500_000)),
+            ("bytes".to_owned(), FsValue::Bytes(vec![0, 1, 2])),
+            (
+                "ref".to_owned(),
+                FsValue::Reference(FsReference::from_str("/users/1").unwrap()),
+            ),
+            (
+                "list".to_owned(),
+                FsValue::Array(vec![
+                    FsValue::Boolean(true),
+                    FsValue::String("s".to_owned()),
+                ]),
+            ),
+        ]));
+        let rest = to_rest_value(&value, DEFAULT_DATABASE).unwrap();
+        assert_eq!(
+            rest.pointer("/mapValue/fields/int"),
+            Some(&json!({"integerValue": "-3"}))
+        );
+        assert_eq!(
+            rest.pointer("/mapValue/fields/ref"),
+            Some(
+                &json!({"referenceValue": "projects/pgfirestore/databases/(default)/documents/users/1"})
+            )
+        );
+        assert_eq!(
+            rest.pointer("/mapValue/fields/time"),
+            Some(&json!({"timestampValue": "1970-01-01T00:00:01.500Z"}))
+        );
+        assert_eq!(from_rest_value(&rest).unwrap(), value);
+    }
+
+    #[test]
+    fn test_from_rest_value_errors() {
+        assert!(from_rest_value(&json!({})).is_err());
+        assert!(from_rest_value(&json!({"integerValue": "1.5"})).is_err());
+        assert_eq!(
+            from_rest_value(&json!({"unknownValue": 1})).map_err(|e| e.to_string()),
+            Err("InvalidValue: Unknown REST value type 'unknownValue'".to_owned())
+        );
+        assert_eq!(
+            from_rest_value(&json!({"mapValue": {"fields": {}, "extra": 1}}))
+                .map_err(|e| e.to_string()),
+            Err("InvalidValue: Unknown field 'extra' in REST mapValue".to_owned())
+        );
+        assert!(from_rest_value(&json!({"arrayValue": {"value": []}})).is_err());
+        assert!(from_rest_value(&json!({"arrayValue": []})).is_err());
+        assert!(
+            from_rest_value(&json!({"geoPointValue": {"latitude": 1, "altitude": 2}})).is_err()
+        );
+        assert_eq!(
+            from_rest_value(&json!({"arrayValue": {}})).unwrap(),
+            FsValue::Array(vec![])
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_api_json_round_trip() {
+        let api_json = json!({"mapValue": {"fields": {
+            "int": {"integerValue": "9223372036854775807"},
+            "double": {"doubleValue": 1.5},
+            "inf": {"doubleValue": "-Infinity"},
+            "at": {"timestampValue": "2024-01-01T00:00:00.000001Z"},
+            "blob": {"bytesValue": "AP8="},
+            "owner": {"referenceValue": "projects/p/databases/(default)/documents/users/1"},
+            "where": {"geoPointValue": {"latitude": 1.5, "longitude": -2.5}},
+            "nested": {"arrayValue": {"values": [
+                {"nullValue": null},
+                {"mapValue": {"fields": {"ok": {"booleanValue": true}}}},
+                {"arrayValue": {}},
+                {"stringValue": "s"}
+            ]}}
+        }}});
+        let value = fs_from_api_json(JsonB(api_json.to_owned()));
+        assert_eq!(
+            value.as_map().and_then(|map| map.get("owner")),
+            Some(&crate::fs_reference("/users/1"))
+        );
+        let round_trip = fs_to_api_json(value.to_owned(), "projects/p/databases/(default)").0;
+        assert_eq!(
+            round_trip.pointer("/mapValue/fields/owner"),
+            api_json.pointer("/mapValue/fields/owner")
+        );
+        assert_eq!(
+            round_trip.pointer("/mapValue/fields/int"),
+            api_json.pointer("/mapValue/fields/int")
+        );
+        assert_eq!(fs_from_api_json(JsonB(round_trip)), value);
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT bool_and(fs_from_api_json(fs_to_api_json(properties)) = properties) FROM fs_documents"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Unknown REST value type 'type'")]
+    fn test_fs_from_api_json_tagged_json() {
+        fs_from_api_json(JsonB(json!({"type": "NULL"})));
+    }
+
+    #[pg_test(error = "InvalidType: DATE values have no REST representation")]
+    fn test_fs_to_api_json_date() {
+        Spi::get_one::<JsonB>("SELECT fs_to_api_json(fs_date('2024-01-01'))").expect("SPI failed");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%2Eb+c").ok(), Some("a.b c".to_owned()));
+        assert!(percent_decode("%zz").is_err());
+    }
+
+    const DOCUMENTS: &str = "/v1/projects/p/databases/(default)/documents";
+
+    fn handle(method: &str, path: &str, body: Option<Value>) -> Value {
+        fs_rest_handle(method, &format!("{}{}", DOCUMENTS, path), body.map(JsonB)).0
+    }
+
+    fn seeded(path: &str) -> Option<FsValue> {
+        get_document(&FsReference::from_str(path).unwrap())
+    }
+
+    #[pg_test]
+    fn test_rest_get() {
+        assert_eq!(
+            handle("GET", "/users/1", None),
+            json!({
+                "name": "projects/p/databases/(default)/documents/users/1",
+                "fields": {"bar": {"integerValue": "0"}, "foo": {"integerValue": "0"}},
+            })
+        );
+        assert_eq!(
+            handle("GET", "/users/404", None).pointer("/error/status"),
+            Some(&json!("NOT_FOUND"))
+        );
+        assert_eq!(
+            handle("GET", "/users", None).pointer("/error/code"),
+            Some(&json!(400))
+        );
+    }
+
+    #[pg_test]
+    fn test_rest_patch() {
+        let document = handle(
+            "PATCH",
+            "/users/9",
+            Some(json!({"fields": {"name": {"stringValue": "nine"}}})),
+        );
+        assert_eq!(
+            document.pointer("/fields/name"),
+            Some(&json!({"stringValue": "nine"}))
+        );
+        assert_eq!(
+            seeded("/users/9"),
+            Some(fs_map_from_entries(
+                vec!["name".to_owned()],
+                vec![fs_string("nine")]
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_rest_patch_update_mask() {
+        // /users/1 is seeded with {"foo": 0, "bar": 0}; "bar" is ignored as it
+        // is not in the mask and "missing" is masked but absent so deleted.
+        let body = json!({
+            "document": {"fields": {"foo": {"integerValue": "5"}, "bar": {"integerValue": "5"}}},
+            "updateMask": {"fieldPaths": ["foo", "missing"]},
+        });
+        handle("PATCH", "/users/1", Some(body));
+        assert_eq!(
+            seeded("/users/1"),
+            Some(fs_map_from_entries(
+                vec!["foo".to_owned(), "bar".to_owned()],
+                vec![fs_number_from_integer(5), fs_number_from_integer(0)]
+            ))
+        );
+
+        handle(
+            "PATCH",
+            "/users/1?updateMask.fieldPaths=bar",
+            Some(json!({"fields": {}})),
+        );
+        assert_eq!(
+            seeded("/users/1"),
+            Some(fs_map_from_entries(
+                vec!["foo".to_owned()],
+                vec![fs_number_from_integer(5)]
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_rest_delete() {
+        assert_eq!(handle("DELETE", "/users/2", None), json!({}));
+        assert_eq!(seeded("/users/2"), None);
+        assert_eq!(handle("DELETE", "/users/2", None), json!({}));
+    }
+
+    #[pg_test]
+    fn test_rest_post() {
+        let document = handle(
+            "POST",
+            "/users/1/posts",
+            Some(json!({"fields": {"title": {"stringValue": "hello"}}})),
+        );
+        let name = document["name"].as_str().unwrap();
+        let prefix = "projects/p/databases/(default)/documents/users/1/posts/";
+        assert!(name.starts_with(prefix));
+        assert_eq!(name.len(), prefix.len() + AUTO_ID_LENGTH);
+        assert_eq!(
+            seeded(&name["projects/p/databases/(default)/documents".len()..]),
+            Some(fs_map_from_entries(
+                vec!["title".to_owned()],
+                vec![fs_string("hello")]
+            ))
+        );
+
+        assert_eq!(
+            handle("POST", "/users?documentId=1", Some(json!({}))).pointer("/error/status"),
+            Some(&json!("ALREADY_EXISTS"))
+        );
+        assert_eq!(
+            handle("POST", "/users/1", Some(json!({}))).pointer("/error/code"),
+            Some(&json!(400))
+        );
+    }
+
+    #[pg_test]
+    fn test_rest_errors() {
+        assert_eq!(
+            handle("PUT", "/users/1", None).pointer("/error/status"),
+            Some(&json!("UNIMPLEMENTED"))
+        );
+        assert_eq!(
+            fs_rest_handle("GET", "/v1/projects/p/users/1", None)
+                .0
+                .pointer("/error/status"),
+            Some(&json!("NOT_FOUND"))
+        );
+        assert_eq!(
+            handle(
+                "PATCH",
+                "/users/1",
+                Some(json!({"fields": {"foo": {"integerValue": "x"}}}))
+            )
+            .pointer("/error/status"),
+            Some(&json!("INVALID_ARGUMENT"))
+        );
+    }
+
+    fn imported(payload: Value, on_conflict: &str) -> Vec<(String, String)> {
+        fs_import_rest_documents(JsonB(payload), on_conflict, DEFAULT_DATABASE, true)
+            .map(|(reference, action)| (reference.expect_reference().to_string(), action))
+            .collect()
+    }
+
+    fn list_documents_page() -> Value {
+        json!({
+            "documents": [
+                {
+                    "name": "projects/prod/databases/(default)/documents/imports/1",
+                    "fields": {"n": {"integerValue": "1"}},
+                    "createTime": "2023-01-01T00:00:00Z",
+                    "updateTime": "2023-01-02T03:04:05.123456Z"
+                },
+                {
+                    "name": "projects/prod/databases/(default)/documents/imports/1/notes/a",
+                    "fields": {"text": {"stringValue": "hi"}}
+                }
+            ],
+            "nextPageToken": "token"
+        })
+    }
+
+    #[pg_test]
+    fn test_fs_import_rest_documents() {
+        assert_eq!(
+            imported(list_documents_page(), "upsert"),
+            vec![
+                ("/imports/1".to_owned(), "inserted".to_owned()),
+                ("/imports/1/notes/a".to_owned(), "inserted".to_owned()),
+            ]
+        );
+        assert_eq!(
+            seeded("/imports/1/notes/a"),
+            Some(fs_map_from_entries(
+                vec!["text".to_owned()],
+                vec![fs_string("hi")]
+            ))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT create_time = '2023-01-01T00:00:00Z' \
+                     AND update_time = '2023-01-02T03:04:05.123456Z' \
+                 FROM fs_documents WHERE reference = fs_reference('/imports/1')"
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+
+        // Conflicts with the documents imported above
+        let mut page = list_documents_page();
+        page["documents"][0]["fields"] = json!({"n": {"integerValue": "2"}});
+        assert_eq!(
+            imported(page.to_owned(), "skip"),
+            vec![
+                ("/imports/1".to_owned(), "skipped".to_owned()),
+                ("/imports/1/notes/a".to_owned(), "skipped".to_owned()),
+            ]
+        );
+        assert_eq!(
+            seeded("/imports/1"),
+            Some(fs_map_from_entries(vec!["n".to_owned()], vec![integer(1)]))
+        );
+        assert_eq!(
+            imported(page, "upsert"),
+            vec![
+                ("/imports/1".to_owned(), "updated".to_owned()),
+                ("/imports/1/notes/a".to_owned(), "updated".to_owned()),
+            ]
+        );
+        assert_eq!(
+            seeded("/imports/1"),
+            Some(fs_map_from_entries(vec!["n".to_owned()], vec![integer(2)]))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_import_rest_documents_run_query() {
+        let payload = json!([
+            {
+                "document": {
+                    "name": "projects/pgfirestore/databases/(default)/documents/imports/2",
+                    "fields": {"ok": {"booleanValue": true}}
+                },
+                "readTime": "2023-01-01T00:00:00Z"
+            },
+            {"readTime": "2023-01-01T00:00:00Z"}
+        ]);
+        assert_eq!(
+            imported(payload, "error"),
+            vec![("/imports/2".to_owned(), "inserted".to_owned())]
+        );
+        assert_eq!(
+            imported(json!([{"readTime": "2023-01-01T00:00:00Z"}]), "error"),
+            vec![]
+        );
+    }
+
+    #[pg_test(error = "Document /imports/1 already exists")]
+    fn test_fs_import_rest_documents_conflict_error() {
+        imported(list_documents_page(), "error");
+        imported(list_documents_page(), "error");
+    }
+
+    fn job_status(job_id: &str) -> (i64, i64, Option<String>) {
+        Spi::connect(|client| {
+            let row = client
+                .select(
+                    "SELECT lines_processed, documents_written, last_error \
+                     FROM fs_import_status($1)",
+                    Some(1),
+                    Some(vec![text_arg(job_id)]),
+                )?
+                .first();
+            Ok::<_, pgrx::spi::Error>((
+                row.get::<i64>(1)?
+                    .expect("lines_processed must not be null"),
+                row.get::<i64>(2)?
+                    .expect("documents_written must not be null"),
+                row.get::<String>(3)?,
+            ))
+        })
+        .expect("SPI failed")
+    }
+
+    // An NDJSON payload of an empty document per name, a blank line standing
+    // for an empty name
+    fn ndjson(names: &[&str]) -> String {
+        names
+            .iter()
+            .map(|name| match name {
+                &"" => "\n".to_owned(),
+                name => format!(
+                    "{}\n",
+                    json!({
+                        "name": format!("projects/pgfirestore/databases/(default)/documents/{}", name),
+                        "fields": {}
+                    })
+                ),
+            })
+            .collect()
+    }
+
+    fn import_batch(job_id: &str, source: &str, payload: &str) -> bool {
+        fs_import_ndjson_batch(job_id, source, payload, "error", DEFAULT_DATABASE, true, 2)
+    }
+
+    fn count_jobs_documents() -> Option<i64> {
+        Spi::get_one::<i64>("SELECT count(*) FROM fs_collection(fs_reference('/'), 'jobs')")
+            .expect("SPI failed")
+    }
+
+    #[pg_test]
+    fn test_fs_import_ndjson_batch() {
+        let payload = ndjson(&["jobs/1", "", "jobs/2", "jobs/3", "jobs/4"]);
+        // Progress advances batch by batch, blank lines included
+        assert!(import_batch("load", "dump-1", &payload));
+        assert_eq!(job_status("load"), (2, 1, None));
+        assert!(import_batch("load", "dump-1", &payload));
+        assert_eq!(job_status("load"), (4, 3, None));
+        assert!(!import_batch("load", "dump-1", &payload));
+        assert_eq!(job_status("load"), (5, 4, None));
+        // A finished job has nothing left to import
+        assert!(!import_batch("load", "dump-1", &payload));
+        assert_eq!(job_status("load"), (5, 4, None));
+        assert_eq!(count_jobs_documents(), Some(4));
+    }
+
+    #[pg_test]
+    fn test_fs_import_ndjson_batch_resumes_after_invalid_line() {
+        let fixed = ndjson(&["jobs/5", "jobs/6", "jobs/7", "jobs/8"]);
+        let broken = fixed.replacen("jobs/7", "jobs", 1);
+        assert!(import_batch("resumed", "dump-2", &broken));
+        // The invalid line stops the batch after the lines before it
+        assert!(!import_batch("resumed", "dump-2", &broken));
+        assert_eq!(
+            job_status("resumed"),
+            (
+                2,
+                2,
+                Some(
+                    "InvalidValue: Line 3: Expecting a document name but found \
+                     'projects/pgfirestore/databases/(default)/documents/jobs'"
+                        .to_owned()
+                )
+            )
+        );
+        assert_eq!(count_jobs_documents(), Some(2));
+        // Resuming with the fixed payload skips what is already imported,
+        // which on_conflict => 'error' would otherwise reject
+        assert!(!import_batch("resumed", "dump-2", &fixed));
+        assert_eq!(job_status("resumed"), (4, 4, None));
+        assert_eq!(count_jobs_documents(), Some(4));
+    }
+
+    #[pg_test(error = "Import job 'load' imports source 'dump-1' rather than 'dump-2'")]
+    fn test_fs_import_ndjson_batch_other_source() {
+        import_batch("load", "dump-1", &ndjson(&["jobs/1"]));
+        import_batch("load", "dump-2", &ndjson(&["jobs/2"]));
+    }
+
+    #[pg_test(
+        error = "Import job 'load' stopped at byte 2 of source 'dump-1', which is not the start of a line of this payload"
+    )]
+    fn test_fs_import_ndjson_batch_other_payload() {
+        let payload = ndjson(&["", "", "", "jobs/1"]);
+        import_batch("load", "dump-1", &payload);
+        import_batch("load", "dump-1", "{}");
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Document projects/prod/databases/(default)/documents/imports/1 belongs to database projects/prod/databases/(default) rather than projects/pgfirestore/databases/(default)"
+    )]
+    fn test_fs_import_rest_documents_foreign_database() {
+        fs_import_rest_documents(
+            JsonB(list_documents_page()),
+            "upsert",
+            DEFAULT_DATABASE,
+            false,
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use crate::FsError;
+use crate::{parse_field_names, FsValue};
+use pgrx::prelude::*;
+use serde_json::Value;
+use std::ffi::{CStr, CString};
+
+// Helpers for row-level security policies on fs_documents, e.g. behind
+// PostgREST, which puts the claims of the request's JWT into the
+// request.jwt.claims setting. A missing setting, claim or field never makes a
+// policy predicate NULL.
+
+const CLAIMS_SETTING: &str = "request.jwt.claims";
+
+// The claims of the current request, None when the setting is missing or
+// empty like outside of a request
+fn request_claims() -> Option<Value> {
+    let name = CString::new(CLAIMS_SETTING).expect("setting name must not contain NUL");
+    let setting = unsafe { pg_sys::GetConfigOption(name.as_ptr(), true, false) };
+    if setting.is_null() {
+        return None;
+    }
+    let text = unsafe { CStr::from_ptr(setting) }.to_string_lossy();
+    if text.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str(&text) {
+        Ok(claims) => Some(claims),
+        Err(error) => {
+            FsError::InvalidValue(format!("{} is not valid JSON: {}", CLAIMS_SETTING, error))
+                .report()
+        }
+    }
+}
+
+// Strings as they are and other values as their JSON text, like the ->>
+// operator. A null claim is missing.
+fn claim_text(claims: &Value, name: &str) -> Option<String> {
+    match claims.get(name)? {
+        Value::Null => None,
+        Value::String(string) => Some(string.to_owned()),
+        other => Some(other.to_string()),
+    }
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn fs_request_claim(name: &str) -> Option<String> {
+    request_claims().and_then(|claims| claim_text(&claims, name))
+}
+
+// Whether the STRING at `owner_path` equals the claim. False, rather than
+// NULL, when the document, field or claim is missing.
+#[pg_extern(stable, parallel_safe)]
+fn fs_owner_matches(doc: Option<FsValue>, owner_path: &str, claim: &str) -> bool {
+    let owner = match doc
+        .as_ref()
+        .and_then(|doc| doc.get_field(&parse_field_names(owner_path)))
+    {
+        Some(FsValue::String(owner)) => owner,
+        _ => return false,
+    };
+    fs_request_claim(claim).is_some_and(|claim| &claim == owner)
+}
+
+// The path segment at a 1-based index like split_part, e.g. the user ID at 2
+// of /users/{uid}/posts/{postId}. NULL past the last segment.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_owner(reference: FsValue, segment_index: i32) -> Option<String> {
+    if segment_index < 1 {
+        FsError::InvalidValue(format!(
+            "Segment index must be positive but found {}",
+            segment_index
+        ))
+        .report()
+    }
+    reference
+        .as_reference()?
+        .segments()
+        .into_iter()
+        .nth(segment_index as usize - 1)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_reference;
+    use crate::fs_rls::*;
+    use serde_json::json;
+
+    fn set_claims(claims: &str) {
+        Spi::run_with_args(
+            "SELECT set_config('request.jwt.claims', $1, true)",
+            Some(vec![(PgBuiltInOids::TEXTOID.oid(), claims.into_datum())]),
+        )
+        .expect("SPI failed");
+    }
+
+    fn owned_by(owner: &str) -> FsValue {
+        FsValue::from_plain_json(&json!({ "owner": owner }))
+    }
+
+    #[test]
+    fn test_claim_text() {
+        let claims = json!({"sub": "1", "admin": true, "level": 3, "none": null, "roles": ["a"]});
+        assert_eq!(claim_text(&claims, "sub"), Some("1".to_owned()));
+        assert_eq!(claim_text(&claims, "admin"), Some("true".to_owned()));
+        assert_eq!(claim_text(&claims, "level"), Some("3".to_owned()));
+        assert_eq!(claim_text(&claims, "roles"), Some("[\"a\"]".to_owned()));
+        assert_eq!(claim_text(&claims, "none"), None);
+        assert_eq!(claim_text(&claims, "missing"), None);
+        assert_eq!(claim_text(&json!("sub"), "sub"), None);
+    }
+
+    #[pg_test]
+    fn test_fs_request_claim() {
+        assert_eq!(fs_request_claim("sub"), None);
+        set_claims("");
+        assert_eq!(fs_request_claim("sub"), None);
+        set_claims(r#"{"sub": "1", "role": "authenticated"}"#);
+        assert_eq!(fs_request_claim("sub"), Some("1".to_owned()));
+        assert_eq!(fs_request_claim("email"), None);
+    }
+
+    #[pg_test(
+        error = "InvalidValue: request.jwt.claims is not valid JSON: expected ident at line 1 column 2"
+    )]
+    fn test_fs_request_claim_invalid() {
+        set_claims("not json");
+        fs_request_claim("sub");
+    }
+
+    #[pg_test]
+    fn test_fs_owner_matches() {
+        assert!(!fs_owner_matches(Some(owned_by("1")), "owner", "sub"));
+        set_claims(r#"{"sub": "1"}"#);
+        assert!(fs_owner_matches(Some(owned_by("1")), "owner", "sub"));
+        assert!(!fs_owner_matches(Some(owned_by("2")), "owner", "sub"));
+        assert!(!fs_owner_matches(Some(owned_by("1")), "missing", "sub"));
+        assert!(!fs_owner_matches(Some(owned_by("1")), "owner", "email"));
+        assert!(!fs_owner_matches(None, "owner", "sub"));
+        // Only strings match, not the number 1
+        assert!(!fs_owner_matches(
+            Some(FsValue::from_plain_json(&json!({"owner": 1}))),
+            "owner",
+            "sub"
+        ));
+    }
+
+    #[pg_test]
+    fn test_fs_reference_owner() {
+        let reference = fs_reference("/users/1/posts/2");
+        assert_eq!(
+            fs_reference_owner(reference.to_owned(), 1),
+            Some("users".to_owned())
+        );
+        assert_eq!(
+            fs_reference_owner(reference.to_owned(), 2),
+            Some("1".to_owned())
+        );
+        assert_eq!(
+            fs_reference_owner(reference.to_owned(), 4),
+            Some("2".to_owned())
+        );
+        assert_eq!(fs_reference_owner(reference, 5), None);
+        assert_eq!(fs_reference_owner(fs_reference("/"), 1), None);
+        assert_eq!(fs_reference_owner(FsValue::NULL, 1), None);
+    }
+
+    #[pg_test(error = "InvalidValue: Segment index must be positive but found 0")]
+    fn test_fs_reference_owner_zero() {
+        fs_reference_owner(fs_reference("/users/1"), 0);
+    }
+
+    #[pg_test]
+    fn test_row_level_security_policy() {
+        Spi::run(
+            "CREATE ROLE fs_rls_user; \
+             GRANT SELECT ON fs_documents TO fs_rls_user; \
+             INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/notes/a'), fs_map_from_entries(ARRAY['owner'], ARRAY[fs_string('1')])), \
+                 (fs_reference('/notes/b'), fs_map_from_entries(ARRAY['owner'], ARRAY[fs_string('2')])); \
+             ALTER TABLE fs_documents ENABLE ROW LEVEL SECURITY; \
+             CREATE POLICY own_users ON fs_documents FOR SELECT \
+                 USING (fs_reference_owner(reference, 1) = 'users' \
+                     AND fs_reference_owner(reference, 2) = fs_request_claim('sub')); \
+             CREATE POLICY own_notes ON fs_documents FOR SELECT \
+                 USING (fs_owner_matches(properties, 'owner', 'sub'))",
+        )
+        .expect("SPI failed");
+        let visible = || {
+            Spi::get_one::<String>(
+                "SELECT string_agg(fs_reference_text(reference), ',' ORDER BY reference) \
+                 FROM fs_documents",
+            )
+            .expect("SPI failed")
+        };
+
+        Spi::run("SET LOCAL ROLE fs_rls_user").expect("SPI failed");
+        // Without claims no policy matches, and none of them fails
+        assert_eq!(visible(), None);
+        set_claims(r#"{"sub": "1"}"#);
+        assert_eq!(
+            visible(),
+            Some("/notes/a,/users/1,/users/1/posts/1,/users/1/posts/2".to_owned())
+        );
+        set_claims(r#"{"sub": "2"}"#);
+        assert_eq!(visible(), Some("/notes/b,/users/2".to_owned()));
+
+        // The table owner is not subject to the policies
+        Spi::run("RESET ROLE").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM fs_documents").expect("SPI failed"),
+            Some(11)
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::fs_documents::text_arg;
+use crate::fs_reference_pattern::ReferencePattern;
+use crate::FsValue;
+use pgrx::heap_tuple::PgHeapTupleError;
+use pgrx::prelude::*;
+use pgrx::WhoAllocated;
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+// Number of allowed patterns suggested when a reference is rejected
+const SUGGESTED_PATTERNS: usize = 3;
+
+fn parse_pattern(pattern: &str) -> ReferencePattern {
+    match ReferencePattern::from_str(pattern) {
+        Ok(pattern) => pattern,
+        Err(error) => error.report(),
+    }
+}
+
+pub(crate) fn allowed_patterns() -> Vec<ReferencePattern> {
+    Spi::connect(|client| {
+        let mut patterns = Vec::new();
+        for row in client.select(
+            "SELECT pattern FROM fs_allowed_collections ORDER BY pattern",
+            None,
+            None,
+        )? {
+            let pattern = row.get::<String>(1)?.expect("pattern must not be null");
+            patterns.push(parse_pattern(&pattern));
+        }
+        Ok::<Vec<ReferencePattern>, pgrx::spi::Error>(patterns)
+    })
+    .expect("Failed to read from fs_allowed_collections")
+}
+
+// The allowed patterns closest to `reference`: the longest matched prefix
+// first, then the closest depth.
+fn nearest_patterns(patterns: &[ReferencePattern], reference: &FsValue) -> Vec<String> {
+    let fs_ref = reference.expect_reference();
+    let depth = fs_ref
+        .to_string()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .count();
+    let mut ranked: Vec<(Reverse<usize>, usize, String)> = patterns
+        .iter()
+        .map(|pattern| {
+            (
+                Reverse(pattern.matched_prefix_len(fs_ref)),
+                pattern.segment_count().abs_diff(depth),
+                pattern.to_string(),
+            )
+        })
+        .collect();
+    ranked.sort();
+    ranked
+        .into_iter()
+        .take(SUGGESTED_PATTERNS)
+        .map(|(_, _, pattern)| pattern)
+        .collect()
+}
+
+pub(crate) fn is_allowed(patterns: &[ReferencePattern], reference: &FsValue) -> bool {
+    let fs_ref = reference.expect_reference();
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| pattern.extract(fs_ref).is_some())
+}
+
+// Returns whether the pattern was not allowed yet
+#[pg_extern]
+fn fs_allow_collection(pattern: &str) -> bool {
+    let pattern = parse_pattern(pattern);
+    Spi::connect(|mut client| {
+        client
+            .update(
+                "INSERT INTO fs_allowed_collections (pattern) VALUES ($1) \
+                 ON CONFLICT DO NOTHING RETURNING pattern",
+                None,
+                Some(vec![text_arg(&pattern.to_string())]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to write to fs_allowed_collections")
+}
+
+// Returns whether the pattern was allowed
+#[pg_extern]
+fn fs_disallow_collection(pattern: &str) -> bool {
+    let pattern = parse_pattern(pattern);
+    Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM fs_allowed_collections WHERE pattern = $1 RETURNING pattern",
+                None,
+                Some(vec![text_arg(&pattern.to_string())]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to delete from fs_allowed_collections")
+}
+
+#[pg_extern]
+fn fs_list_allowed_collections() -> SetOfIterator<'static, String> {
+    let patterns: Vec<String> = allowed_patterns()
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect();
+    SetOfIterator::new(patterns.into_iter())
+}
+
+// Everything is allowed while no pattern is
+#[pg_extern]
+fn fs_reference_allowed(reference: FsValue) -> bool {
+    is_allowed(&allowed_patterns(), &reference)
+}
+
+// Attaches or detaches the trigger rejecting inserts of disallowed references
+#[pg_extern]
+fn fs_enforce_schema(enable: bool) {
+    let statement = if enable {
+        "DROP TRIGGER IF EXISTS fs_documents_schema_guard ON fs_documents; \
+         CREATE TRIGGER fs_documents_schema_guard \
+         BEFORE INSERT ON fs_documents \
+         FOR EACH ROW EXECUTE PROCEDURE fs_documents_schema_guard()"
+    } else {
+        "DROP TRIGGER IF EXISTS fs_documents_schema_guard ON fs_documents"
+    };
+    Spi::run(statement).expect("Failed to change the schema trigger")
+}
+
+#[pg_trigger]
+fn fs_documents_schema_guard<'a>(
+    trigger: &'a pgrx::PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PgHeapTupleError> {
+    let new = trigger
+        .new()
+        .expect("expecting a NEW row in an INSERT trigger");
+    let reference = new
+        .get_by_name::<FsValue>("reference")
+        .expect("Failed to read reference from the NEW row")
+        .expect("reference must not be null");
+    let patterns = allowed_patterns();
+    if !is_allowed(&patterns, &reference) {
+        ereport!(
+            PgLogLevel::ERROR,
+            PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
+            format!(
+                "Reference {} is not allowed by the collection schema. Nearest allowed patterns: {}",
+                reference
+                    .expect_reference(),
+                nearest_patterns(&patterns, &reference).join(", ")
+            )
+        );
+    }
+    Ok(Some(new))
+}
+
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_allowed_collections (\n\
+            pattern text PRIMARY KEY\n\
+        );\n\
+    ",
+    name = "allowed_collections_table",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_reference;
+    use crate::fs_schema::*;
+
+    fn allow_deployment_patterns() {
+        for pattern in ["/users/{id}", "/users/{id}/posts/{id2}", "/config/{id}"] {
+            assert!(fs_allow_collection(pattern));
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_allow_collection() {
+        assert!(fs_reference_allowed(fs_reference("/payments/1")));
+
+        allow_deployment_patterns();
+        assert!(!fs_allow_collection("/config/{id}"));
+        assert_eq!(
+            fs_list_allowed_collections().collect::<Vec<String>>(),
+            vec!["/config/{id}", "/users/{id}", "/users/{id}/posts/{id2}"]
+        );
+        assert!(fs_reference_allowed(fs_reference("/users/1/posts/2")));
+        assert!(fs_reference_allowed(fs_reference("/config/1")));
+        assert!(!fs_reference_allowed(fs_reference("/payments/1")));
+
+        assert!(fs_disallow_collection("/config/{id}"));
+        assert!(!fs_disallow_collection("/config/{id}"));
+        assert!(!fs_reference_allowed(fs_reference("/config/1")));
+    }
+
+    #[pg_test]
+    fn test_fs_enforce_schema() {
+        // An empty catalog allows everything
+        fs_enforce_schema(true);
+        Spi::run("SELECT fs_set(fs_reference('/payments/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))")
+            .expect("SPI failed");
+
+        allow_deployment_patterns();
+        fs_enforce_schema(true);
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/9'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])); \
+             SELECT fs_set(fs_reference('/users/9/posts/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+
+        fs_enforce_schema(false);
+        Spi::run("SELECT fs_set(fs_reference('/payments/2'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))")
+            .expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "Reference /payments/1 is not allowed by the collection schema. Nearest allowed patterns: /config/{id}, /users/{id}, /users/{id}/posts/{id2}"
+    )]
+    fn test_fs_enforce_schema_rejects_insert() {
+        allow_deployment_patterns();
+        fs_enforce_schema(true);
+        Spi::run("SELECT fs_set(fs_reference('/payments/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))")
+            .expect("SPI failed");
+    }
+}
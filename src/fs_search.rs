@@ -0,0 +1,170 @@
+use crate::{parse_field_names, FsValue};
+use pgrx::prelude::*;
+
+// The texts fs_to_tsvector indexes, in path order: the string at each path,
+// or every string of an array there. Other values are skipped.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_search_texts(doc: FsValue, paths: Vec<String>) -> Vec<String> {
+    let mut texts = Vec::new();
+    for path in paths {
+        match doc.get_field(&parse_field_names(&path)) {
+            Some(FsValue::String(text)) => texts.push(text.to_owned()),
+            Some(FsValue::Array(elements)) => {
+                texts.extend(elements.iter().filter_map(|element| match element {
+                    FsValue::String(text) => Some(text.to_owned()),
+                    _ => None,
+                }))
+            }
+            _ => {}
+        }
+    }
+    texts
+}
+
+// Texts are parsed one by one and concatenated with ||, which offsets the
+// positions of each text past those of the texts before it. The function is
+// immutable so that it can be used in an expression index.
+extension_sql!(
+    "\n\
+        CREATE AGGREGATE fs_tsvector_concat(tsvector) ( \n\
+            SFUNC = tsvector_concat, STYPE = tsvector, INITCOND = '' \n\
+        ); \n\
+        CREATE FUNCTION fs_to_tsvector(config regconfig, doc fsvalue, paths text[]) \n\
+        RETURNS tsvector AS $$ \n\
+            SELECT fs_tsvector_concat(to_tsvector(config, field_text) ORDER BY field_index) \n\
+            FROM unnest(fs_search_texts(doc, paths)) WITH ORDINALITY AS t(field_text, field_index) \n\
+        $$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_search( \n\
+            parent fsvalue, collection_id text, query text, paths text[], \n\
+            config regconfig DEFAULT get_current_ts_config() \n\
+        ) \n\
+        RETURNS TABLE (reference fsvalue, properties fsvalue, rank real) AS $$ \n\
+            SELECT c.reference, c.properties, \n\
+                ts_rank(fs_to_tsvector(config, c.properties, paths), q) AS rank \n\
+            FROM fs_collection(parent, collection_id) AS c, \n\
+                websearch_to_tsquery(config, query) AS q \n\
+            WHERE fs_to_tsvector(config, c.properties, paths) @@ q \n\
+            ORDER BY rank DESC, c.reference \n\
+        $$ LANGUAGE SQL STABLE; \n\
+    ",
+    name = "text_search",
+    requires = [fs_search_texts, "collection_tvf"],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_search::*;
+    use crate::fs_test_util::explain;
+    use serde_json::json;
+
+    fn insert_articles() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/articles/1'), fs_map_from_entries(ARRAY['title', 'body'], \
+                     ARRAY[fs_string('Postgres extensions'), fs_string('Written in Rust')])), \
+                 (fs_reference('/articles/2'), fs_map_from_entries(ARRAY['title', 'body'], \
+                     ARRAY[fs_string('Rust'), fs_string('Rust everywhere, even more Rust')])), \
+                 (fs_reference('/articles/3'), fs_map_from_entries(ARRAY['title'], \
+                     ARRAY[fs_string('Learning Rust')])), \
+                 (fs_reference('/articles/4'), fs_map_from_entries(ARRAY['title'], \
+                     ARRAY[fs_string('Learning Rust')])), \
+                 (fs_reference('/articles/5'), fs_map_from_entries(ARRAY['title', 'body'], \
+                     ARRAY[fs_number_from_integer(7), fs_null()])), \
+                 (fs_reference('/articles/6'), fs_map_from_entries(ARRAY['summary'], \
+                     ARRAY[fs_string('Rust and Postgres')]))",
+        )
+        .expect("SPI failed");
+    }
+
+    // The references fs_search returns, in order
+    fn search(query: &str) -> Vec<String> {
+        Spi::get_one_with_args::<Vec<String>>(
+            "SELECT array_agg(fs_reference_text(s.reference) ORDER BY s.n) \
+             FROM fs_search(fs_reference('/'), 'articles', $1, ARRAY['title', 'body'], 'english') \
+                 WITH ORDINALITY AS s(reference, properties, rank, n)",
+            vec![(PgBuiltInOids::TEXTOID.oid(), query.into_datum())],
+        )
+        .expect("SPI failed")
+        .unwrap_or_default()
+    }
+
+    #[pg_test]
+    fn test_fs_search_texts() {
+        let doc = FsValue::from_plain_json(&json!({
+            "title": "A title",
+            "tags": ["one", 2, "three"],
+            "meta": {"summary": "Nested"},
+            "count": 3
+        }));
+        let paths = ["title", "tags", "meta.summary", "count", "missing"];
+        assert_eq!(
+            fs_search_texts(doc, paths.iter().map(|path| path.to_string()).collect()),
+            vec!["A title", "one", "three", "Nested"]
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_to_tsvector() {
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT fs_to_tsvector('english', \
+                     fs_map_from_entries(ARRAY['title', 'body'], ARRAY[fs_string('Quick foxes'), fs_string('jumped')]), \
+                     ARRAY['title', 'body'])::text"
+            ),
+            Ok(Some("'fox':2 'jump':3 'quick':1".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT fs_to_tsvector('english', fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]), \
+                     ARRAY['title'])::text"
+            ),
+            Ok(Some("".to_owned()))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_search() {
+        insert_articles();
+        // Each term is in a different field of /articles/1
+        assert_eq!(search("postgres rust"), vec!["/articles/1"]);
+        // Documents without the fields, or with other types there, do not match
+        assert_eq!(search("7"), Vec::<String>::new());
+        assert_eq!(search("postgres"), vec!["/articles/1"]);
+    }
+
+    #[pg_test]
+    fn test_fs_search_ranking() {
+        insert_articles();
+        // More occurrences rank first, and equal ranks follow the reference
+        let ranked = vec!["/articles/2", "/articles/1", "/articles/3", "/articles/4"];
+        assert_eq!(search("rust"), ranked);
+        assert_eq!(search("rust"), ranked);
+    }
+
+    #[pg_test]
+    fn test_fs_to_tsvector_index() {
+        insert_articles();
+        Spi::run(
+            "CREATE INDEX fs_documents_search ON fs_documents \
+                 USING gin (fs_to_tsvector('english', properties, ARRAY['title', 'body'])); \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        let plan = explain(
+            "SELECT reference FROM fs_documents \
+             WHERE fs_to_tsvector('english', properties, ARRAY['title', 'body']) \
+                 @@ websearch_to_tsquery('english', 'rust')",
+            true,
+        );
+        assert!(plan.contains("fs_documents_search"), "{}", plan);
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents \
+                 WHERE fs_to_tsvector('english', properties, ARRAY['title', 'body']) \
+                     @@ websearch_to_tsquery('english', 'rust')"
+            ),
+            Ok(Some(4))
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::fs_documents::{expect_parent_reference, text_arg};
+use crate::FsError;
+use crate::{FsReference, FsValue};
+use pgrx::prelude::*;
+use pgrx::PgBuiltInOids;
+use sha2::{Digest, Sha256};
+
+// Every (parent, collection) pair gets its own sequence. The name is derived
+// from a hash so that it stays a valid identifier within NAMEDATALEN.
+struct CollectionSequence {
+    name: String,
+    lock_key: i64,
+}
+
+impl CollectionSequence {
+    fn new(parent: &FsReference, collection_id: &str) -> CollectionSequence {
+        if collection_id.is_empty() || collection_id.contains('/') {
+            FsError::InvalidValue(format!("Invalid collection id '{}'", collection_id)).report()
+        }
+        let digest = Sha256::digest(format!("{}|{}", parent, collection_id).as_bytes());
+        let hex: String = digest[..12]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let mut lock_key = [0u8; 8];
+        lock_key.copy_from_slice(&digest[..8]);
+        CollectionSequence {
+            name: format!("fs_id_seq_{}", hex),
+            lock_key: i64::from_be_bytes(lock_key),
+        }
+    }
+
+    fn exists(&self) -> bool {
+        Spi::get_one_with_args::<bool>(
+            "SELECT to_regclass($1) IS NOT NULL",
+            vec![text_arg(&self.name)],
+        )
+        .expect("Failed to look up sequence")
+        .unwrap_or(false)
+    }
+
+    // CREATE SEQUENCE IF NOT EXISTS alone can still fail with a unique violation
+    // when two backends create the same sequence concurrently, so creation is
+    // serialized with a transaction level advisory lock. Returns whether the
+    // sequence was created by this call.
+    fn create_if_not_exists(&self) -> bool {
+        if self.exists() {
+            return false;
+        }
+        Spi::run_with_args(
+            "SELECT pg_advisory_xact_lock($1)",
+            Some(vec![(
+                PgBuiltInOids::INT8OID.oid(),
+                self.lock_key.into_datum(),
+            )]),
+        )
+        .expect("Failed to lock sequence creation");
+        if self.exists() {
+            return false;
+        }
+        Spi::run(&format!("CREATE SEQUENCE IF NOT EXISTS {}", self.name))
+            .expect("Failed to create sequence");
+        true
+    }
+
+    fn next_value(&self) -> i64 {
+        self.create_if_not_exists();
+        Spi::get_one_with_args::<i64>("SELECT nextval($1::regclass)", vec![text_arg(&self.name)])
+            .expect("Failed to get the next sequence value")
+            .expect("nextval must not return null")
+    }
+
+    fn restart(&self, restart_with: i64) {
+        self.create_if_not_exists();
+        Spi::run(&format!(
+            "ALTER SEQUENCE {} RESTART WITH {}",
+            self.name, restart_with
+        ))
+        .expect("Failed to restart sequence")
+    }
+}
+
+// Returns a new document reference under `parent`/`collection_id` with an
+// increasing numeric id, so that insertion order is recoverable from the
+// reference.
+#[pg_extern]
+fn fs_next_id(parent: FsValue, collection_id: &str) -> FsValue {
+    let fs_ref = expect_parent_reference(&parent);
+    let id = CollectionSequence::new(fs_ref, collection_id).next_value();
+    match fs_ref.child(collection_id, &id.to_string()) {
+        Ok(child) => FsValue::Reference(child),
+        Err(error) => error.report(),
+    }
+}
+
+#[pg_extern]
+fn fs_reset_collection_sequence(parent: FsValue, collection_id: &str, restart_with: i64) {
+    let fs_ref = expect_parent_reference(&parent);
+    CollectionSequence::new(fs_ref, collection_id).restart(restart_with)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_sequence::*;
+    use crate::{fs_database_root, fs_reference, fs_reference_text};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_sequence_name() {
+        let root = FsReference::from_str("/").unwrap();
+        let users = CollectionSequence::new(&root, "users");
+        assert!(users.name.starts_with("fs_id_seq_"));
+        assert_eq!(users.name.len(), "fs_id_seq_".len() + 24);
+        assert_eq!(users.name, CollectionSequence::new(&root, "users").name);
+        assert_ne!(users.name, CollectionSequence::new(&root, "posts").name);
+        let user = FsReference::from_str("/users/1").unwrap();
+        assert_ne!(users.name, CollectionSequence::new(&user, "users").name);
+    }
+
+    #[pg_test]
+    fn test_fs_next_id() {
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "events")),
+            "/events/1"
+        );
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "events")),
+            "/events/2"
+        );
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "logs")),
+            "/logs/1"
+        );
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_reference("/users/1"), "events")),
+            "/users/1/events/1"
+        );
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "events")),
+            "/events/3"
+        );
+    }
+
+    #[pg_test]
+    fn test_create_sequence_twice() {
+        let root = FsReference::from_str("/").unwrap();
+        let sequence = CollectionSequence::new(&root, "events");
+        assert!(sequence.create_if_not_exists());
+        assert!(!sequence.create_if_not_exists());
+        assert_eq!(sequence.next_value(), 1);
+    }
+
+    #[pg_test]
+    fn test_fs_reset_collection_sequence() {
+        fs_reset_collection_sequence(fs_database_root(), "events", 100);
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "events")),
+            "/events/100"
+        );
+        assert_eq!(
+            fs_reference_text(fs_next_id(fs_database_root(), "events")),
+            "/events/101"
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Expecting the database root or a document reference but found /users"
+    )]
+    fn test_fs_next_id_collection_parent() {
+        fs_next_id(fs_reference("/users"), "events");
+    }
+}
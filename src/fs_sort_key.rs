@@ -0,0 +1,446 @@
+use crate::fs_field_path::FieldPath;
+use crate::fs_number::number_to_bigdecimal;
+use crate::fs_reference::{FsReference, ResourceId};
+use crate::FsError;
+use crate::{FsNumber, FsValue};
+use pgrx::prelude::*;
+use std::str::FromStr;
+
+// Sort keys are byte strings whose memcmp order matches the fsvalue order.
+// Every encoding is prefix-free, so keys of several values concatenate
+// without separators and inverting the bytes of one reverses its order.
+
+// Type tags, in the order of the FsValue variants. A missing field sorts
+// before every value.
+const MISSING_TAG: u8 = 0x05;
+const NULL_TAG: u8 = 0x10;
+const BOOLEAN_TAG: u8 = 0x20;
+const NUMBER_TAG: u8 = 0x30;
+const DATE_TAG: u8 = 0x40;
+const TIMESTAMP_TAG: u8 = 0x48;
+const STRING_TAG: u8 = 0x50;
+const BYTES_TAG: u8 = 0x60;
+const REFERENCE_TAG: u8 = 0x70;
+const GEO_POINT_TAG: u8 = 0x80;
+const ARRAY_TAG: u8 = 0x90;
+const MAP_TAG: u8 = 0xa0;
+
+// Number classes after NUMBER_TAG
+const NAN: u8 = 0x00;
+const NEGATIVE_INFINITY: u8 = 0x01;
+const NEGATIVE: u8 = 0x02;
+const ZERO: u8 = 0x03;
+const POSITIVE: u8 = 0x04;
+const POSITIVE_INFINITY: u8 = 0x05;
+
+// Arrays, maps and reference paths are sequences of items, each introduced
+// by ITEM and closed by END so that a shorter prefix sorts first.
+const END: u8 = 0x01;
+const ITEM: u8 = 0x02;
+
+// The name of the field path that stands for the document reference
+pub(crate) const NAME_FIELD: &str = "__name__";
+
+fn encode_i64(value: i64, key: &mut Vec<u8>) {
+    key.extend_from_slice(&((value as u64) ^ (1 << 63)).to_be_bytes());
+}
+
+// 0x00 is escaped as 0x00 0xff and the bytes end with 0x00 0x01
+fn encode_bytes(bytes: &[u8], key: &mut Vec<u8>) {
+    for byte in bytes {
+        key.push(*byte);
+        if *byte == 0x00 {
+            key.push(0xff);
+        }
+    }
+    key.extend_from_slice(&[0x00, 0x01]);
+}
+
+fn invert(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        *byte = !*byte;
+    }
+}
+
+// A finite number is 0.d1d2...dk * 10^exponent with d1 != 0 and dk != 0. Its
+// magnitude is the exponent followed by the digits and a terminator, which
+// negative numbers invert.
+fn encode_number(number: &FsNumber, key: &mut Vec<u8>) {
+    let number = match number {
+        FsNumber::NAN => return key.push(NAN),
+        FsNumber::NegativeInfinity => return key.push(NEGATIVE_INFINITY),
+        FsNumber::PositiveInfinity => return key.push(POSITIVE_INFINITY),
+        FsNumber::Number(number) => number,
+    };
+    let (mantissa, scale) = number_to_bigdecimal(number)
+        .normalized()
+        .as_bigint_and_exponent();
+    let mantissa = mantissa.to_string();
+    let (negative, digits) = match mantissa.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, mantissa.as_str()),
+    };
+    if digits == "0" {
+        return key.push(ZERO);
+    }
+    key.push(if negative { NEGATIVE } else { POSITIVE });
+    let start = key.len();
+    encode_i64(digits.len() as i64 - scale, key);
+    key.extend(digits.bytes().map(|digit| digit - b'0' + 1));
+    key.push(0x00);
+    if negative {
+        invert(&mut key[start..]);
+    }
+}
+
+fn encode_reference(reference: &FsReference, key: &mut Vec<u8>) {
+    for element in reference.path.0.iter() {
+        key.push(ITEM);
+        encode_bytes(element.collection_id().as_bytes(), key);
+        match element.resource_id() {
+            None => key.push(0x01),
+            // IDs compare as text, a string ID before the numeric ID of the
+            // same text
+            Some(ResourceId::String(id)) => {
+                key.push(0x02);
+                encode_bytes(id.as_bytes(), key);
+                key.push(0x00);
+            }
+            Some(ResourceId::Number(id)) => {
+                key.push(0x02);
+                encode_bytes(id.to_string().as_bytes(), key);
+                key.push(0x01);
+            }
+        }
+    }
+    key.push(END);
+}
+
+pub(crate) fn encode_sort_key(value: &FsValue, key: &mut Vec<u8>) {
+    match value {
+        FsValue::NULL => key.push(NULL_TAG),
+        FsValue::Boolean(boolean) => key.extend_from_slice(&[BOOLEAN_TAG, *boolean as u8]),
+        FsValue::Number(number) => {
+            key.push(NUMBER_TAG);
+            encode_number(number, key);
+        }
+        FsValue::Date(date) => {
+            key.push(DATE_TAG);
+            encode_i64(date.to_pg_epoch_days() as i64, key);
+        }
+        FsValue::Timestamp(micros) => {
+            key.push(TIMESTAMP_TAG);
+            encode_i64(*micros, key);
+        }
+        FsValue::String(string) => {
+            key.push(STRING_TAG);
+            encode_bytes(string.as_bytes(), key);
+        }
+        FsValue::Bytes(bytes) => {
+            key.push(BYTES_TAG);
+            encode_bytes(bytes, key);
+        }
+        FsValue::Reference(reference) => {
+            key.push(REFERENCE_TAG);
+            encode_reference(reference, key);
+        }
+        FsValue::GeoPoint(latitude, longitude) => {
+            key.push(GEO_POINT_TAG);
+            encode_number(latitude, key);
+            encode_number(longitude, key);
+        }
+        FsValue::Array(array) => {
+            key.push(ARRAY_TAG);
+            for element in array.iter() {
+                key.push(ITEM);
+                encode_sort_key(element, key);
+            }
+            key.push(END);
+        }
+        FsValue::Map(map) => {
+            key.push(MAP_TAG);
+            for (name, value) in map.iter() {
+                key.push(ITEM);
+                encode_bytes(name.as_bytes(), key);
+                encode_sort_key(value, key);
+            }
+            key.push(END);
+        }
+    }
+}
+
+fn encode_field(value: Option<&FsValue>, descending: bool, key: &mut Vec<u8>) {
+    let start = key.len();
+    match value {
+        Some(value) => encode_sort_key(value, key),
+        None => key.push(MISSING_TAG),
+    }
+    if descending {
+        invert(&mut key[start..]);
+    }
+}
+
+// The key ordering documents by `paths`, then by reference in the direction
+// of the last path like Firestore's implicit `__name__` ordering.
+fn order_by_key(
+    reference: &FsValue,
+    properties: &FsValue,
+    paths: &[String],
+    descending: &[bool],
+) -> Vec<u8> {
+    if paths.len() != descending.len() {
+        FsError::InvalidValue(format!(
+            "Expecting one direction per path but found {} paths and {} directions",
+            paths.len(),
+            descending.len()
+        ))
+        .report()
+    }
+    let mut key = Vec::new();
+    for (path, descending) in paths.iter().zip(descending.iter()) {
+        if path == NAME_FIELD {
+            encode_field(Some(reference), *descending, &mut key);
+            continue;
+        }
+        let field_names = match FieldPath::from_str(path).and_then(FieldPath::into_field_names) {
+            Ok(field_names) => field_names,
+            Err(error) => error.report(),
+        };
+        encode_field(properties.get_field(&field_names), *descending, &mut key);
+    }
+    encode_field(
+        Some(reference),
+        descending.last().copied().unwrap_or(false),
+        &mut key,
+    );
+    key
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_sort_key(fs_value: FsValue) -> Vec<u8> {
+    let mut key = Vec::new();
+    encode_sort_key(&fs_value, &mut key);
+    key
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_order_by_key(
+    reference: FsValue,
+    properties: FsValue,
+    paths: Vec<String>,
+    descending: Vec<bool>,
+) -> Vec<u8> {
+    order_by_key(&reference, &properties, &paths, &descending)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_sort_key::*;
+    use crate::fs_test_util::explain;
+    use crate::{fs_map_from_entries, fs_number_from_integer, fs_reference};
+
+    #[test]
+    fn test_sort_key_matches_ordering() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        use std::cmp::Ordering;
+        use std::collections::BTreeMap;
+
+        const NUMBERS: [&str; 16] = [
+            "NaN",
+            "-Infinity",
+            "-1e300",
+            "-10",
+            "-1.5",
+            "-1",
+            "-0.000025",
+            "0",
+            "0.0",
+            "0.000025",
+            "1",
+            "1.0",
+            "1.5",
+            "10",
+            "9223372036854775807",
+            "Infinity",
+        ];
+        const STRINGS: [&str; 7] = ["", "\0", "a", "a\0", "a\0b", "ab", "b"];
+        const REFERENCES: [&str; 9] = [
+            "/",
+            "/users",
+            "/users/1",
+            "/users/%31",
+            "/users/10",
+            "/users/9",
+            "/users/2/posts/a",
+            "/users/a",
+            "/users/b",
+        ];
+
+        fn random_number(rng: &mut StdRng) -> FsNumber {
+            FsNumber::from_str(NUMBERS[rng.gen_range(0..NUMBERS.len())]).unwrap()
+        }
+
+        fn random_string(rng: &mut StdRng) -> String {
+            STRINGS[rng.gen_range(0..STRINGS.len())].to_owned()
+        }
+
+        fn random_value(rng: &mut StdRng, depth: u32) -> FsValue {
+            let kinds = if depth == 0 { 9 } else { 11 };
+            match rng.gen_range(0..kinds) {
+                0 => FsValue::NULL,
+                1 => FsValue::Boolean(rng.gen()),
+                2 => FsValue::Number(random_number(rng)),
+                3 => FsValue::Date(pgrx::Date::from(rng.gen_range(-2..3))),
+                4 => FsValue::Timestamp(rng.gen_range(-2..3)),
+                5 => FsValue::String(random_string(rng)),
+                6 => FsValue::Bytes(random_string(rng).into_bytes()),
+                7 => FsValue::Reference(
+                    FsReference::from_str(REFERENCES[rng.gen_range(0..REFERENCES.len())]).unwrap(),
+                ),
+                8 => FsValue::GeoPoint(random_number(rng), random_number(rng)),
+                9 => FsValue::Array(
+                    (0..rng.gen_range(0..3))
+                        .map(|_| random_value(rng, depth - 1))
+                        .collect(),
+                ),
+                _ => FsValue::Map(
+                    (0..rng.gen_range(0..3))
+                        .map(|_| (random_string(rng), random_value(rng, depth - 1)))
+                        .collect::<BTreeMap<String, FsValue>>(),
+                ),
+            }
+        }
+
+        fn sort_key(value: &FsValue) -> Vec<u8> {
+            let mut key = Vec::new();
+            encode_sort_key(value, &mut key);
+            key
+        }
+
+        let mut rng = StdRng::seed_from_u64(1937);
+        let values: Vec<FsValue> = (0..400).map(|_| random_value(&mut rng, 2)).collect();
+        for lhs in values.iter() {
+            for rhs in values.iter().take(100) {
+                assert_eq!(
+                    sort_key(lhs).cmp(&sort_key(rhs)),
+                    lhs.cmp(rhs),
+                    "{:?} vs {:?}",
+                    lhs,
+                    rhs
+                );
+            }
+        }
+
+        // Composite keys against sorting the decoded tuples, where a missing
+        // field is None and sorts first
+        let paths = vec!["a".to_owned(), "b.c".to_owned()];
+        let documents: Vec<(FsValue, FsValue)> = (0..300)
+            .map(|i| {
+                let mut properties = FsValue::Map(BTreeMap::new());
+                for path in [vec!["a".to_owned()], vec!["b".to_owned(), "c".to_owned()]] {
+                    if rng.gen_bool(0.8) {
+                        properties.set_field(&path, random_value(&mut rng, 1));
+                    }
+                }
+                let reference =
+                    FsReference::from_str(&format!("/docs/{}", i % 50)).expect("valid reference");
+                (FsValue::Reference(reference), properties)
+            })
+            .collect();
+        for descending in [[false, false], [false, true], [true, false], [true, true]] {
+            let tuple = |(reference, properties): &(FsValue, FsValue)| {
+                (
+                    properties.get_field(&["a".to_owned()]).cloned(),
+                    properties
+                        .get_field(&["b".to_owned(), "c".to_owned()])
+                        .cloned(),
+                    reference.to_owned(),
+                )
+            };
+            let compare = |lhs: &(FsValue, FsValue), rhs: &(FsValue, FsValue)| {
+                let (lhs, rhs) = (tuple(lhs), tuple(rhs));
+                let directed = |ordering: Ordering, descending: bool| {
+                    if descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                };
+                directed(lhs.0.cmp(&rhs.0), descending[0])
+                    .then(directed(lhs.1.cmp(&rhs.1), descending[1]))
+                    .then(directed(lhs.2.cmp(&rhs.2), descending[1]))
+            };
+            let key = |(reference, properties): &(FsValue, FsValue)| {
+                order_by_key(reference, properties, &paths, &descending)
+            };
+            for lhs in documents.iter() {
+                for rhs in documents.iter().take(50) {
+                    assert_eq!(
+                        key(lhs).cmp(&key(rhs)),
+                        compare(lhs, rhs),
+                        "{:?} vs {:?} with {:?}",
+                        lhs,
+                        rhs,
+                        descending
+                    );
+                }
+            }
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_order_by_key() {
+        let key = |reference: &str, foo: i32| {
+            fs_order_by_key(
+                fs_reference(reference),
+                fs_map_from_entries(vec!["foo".to_owned()], vec![fs_number_from_integer(foo)]),
+                vec!["foo".to_owned()],
+                vec![true],
+            )
+        };
+        assert!(key("/users/1", 2) < key("/users/2", 1));
+        // Ties on foo are broken by reference in the same direction
+        assert!(key("/users/2", 1) < key("/users/1", 1));
+        let reference_key = fs_sort_key(fs_reference("/users/1"));
+        assert_eq!(
+            fs_order_by_key(
+                fs_reference("/users/1"),
+                fs_map_from_entries(vec![], vec![]),
+                vec![NAME_FIELD.to_owned()],
+                vec![false],
+            ),
+            [reference_key.to_owned(), reference_key].concat()
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_order_by_key_index() {
+        Spi::run(
+            "CREATE INDEX fs_documents_order_by_foo ON fs_documents \
+             (fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true])); \
+             ANALYZE fs_documents; \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        let query = "SELECT fs_reference_text(reference) FROM fs_documents \
+                     ORDER BY fs_order_by_key(reference, properties, ARRAY['foo'], ARRAY[true]) \
+                     LIMIT 3";
+        let plan = explain(query, true);
+        assert!(
+            plan.contains("Index Scan using fs_documents_order_by_foo"),
+            "{}",
+            plan
+        );
+        let references = Spi::connect(|client| {
+            let mut references = Vec::new();
+            for row in client.select(query, None, None)? {
+                references.push(row.get::<String>(1)?.expect("reference must not be null"));
+            }
+            Ok::<Vec<String>, pgrx::spi::Error>(references)
+        })
+        .expect("SPI failed");
+        assert_eq!(references, vec!["/users/5", "/users/4", "/users/3"]);
+    }
+}
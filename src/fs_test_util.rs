@@ -0,0 +1,34 @@
+use crate::FsValue;
+use pgrx::prelude::*;
+use serde_json::Value;
+
+// Builders of values shared by the unit and pg tests of every module
+
+// A map of `entries`, the last value winning for a repeated key
+pub(crate) fn map(entries: Vec<(&str, FsValue)>) -> FsValue {
+    FsValue::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value))
+            .collect(),
+    )
+}
+
+// A value typed by the shape of plain JSON, like fs_from_plain_json
+pub(crate) fn plain(value: Value) -> FsValue {
+    FsValue::from_plain_json(&value)
+}
+
+// The EXPLAIN output of `query` as one string, with the cost estimates only
+// when `costs` is set
+pub(crate) fn explain(query: &str, costs: bool) -> String {
+    let options = if costs { "" } else { "(COSTS OFF) " };
+    Spi::connect(|client| {
+        let mut lines = Vec::new();
+        for row in client.select(&format!("EXPLAIN {}{}", options, query), None, None)? {
+            lines.push(row.get::<String>(1)?.unwrap_or_default());
+        }
+        Ok::<String, pgrx::spi::Error>(lines.join("\n"))
+    })
+    .expect("SPI failed")
+}
@@ -0,0 +1,835 @@
+use crate::fs_guc::fixed_request_time;
+use crate::{FsError, FsValue};
+use pgrx::prelude::*;
+use pgrx::Interval;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// Timestamps are microseconds since the Unix epoch in UTC, the precision
+// Firestore stores them with.
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+// Days from 1970-01-01 to 2000-01-01, the Postgres epoch
+const PG_EPOCH_DAYS: i64 = 10_957;
+
+// Firestore's range, 0001-01-01T00:00:00Z to 9999-12-31T23:59:59.999999Z
+const MIN_TIMESTAMP: i64 = -62_135_596_800 * MICROS_PER_SECOND;
+const MAX_TIMESTAMP: i64 = 253_402_300_800 * MICROS_PER_SECOND - 1;
+
+// Days since 1970-01-01 of a proleptic Gregorian date, after Howard Hinnant's
+// days_from_civil
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+pub(crate) fn check_range(micros: i64) -> Result<i64> {
+    if (MIN_TIMESTAMP..=MAX_TIMESTAMP).contains(&micros) {
+        Ok(micros)
+    } else {
+        Err(FsError::InvalidValue(
+            "Timestamp is out of Firestore's range of 0001-01-01T00:00:00Z to 9999-12-31T23:59:59.999999Z"
+                .to_owned(),
+        ))
+    }
+}
+
+// Reads `count` ASCII digits at `start`
+fn digits(text: &[u8], start: usize, count: usize) -> Option<i64> {
+    let digits = text.get(start..start + count)?;
+    digits.iter().try_fold(0i64, |value, byte| {
+        byte.is_ascii_digit()
+            .then(|| value * 10 + (byte - b'0') as i64)
+    })
+}
+
+// Parses an RFC 3339 timestamp such as 2024-01-31T12:00:00.5+01:00. Digits
+// past microseconds are truncated like Firestore does.
+pub(crate) fn parse_timestamp(text: &str) -> Result<i64> {
+    let invalid = || {
+        FsError::InvalidValue(format!(
+            "Failed to parse '{}' as an RFC 3339 timestamp",
+            text
+        ))
+    };
+    let bytes = text.as_bytes();
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if separators
+        .iter()
+        .any(|(at, separator)| bytes.get(*at) != Some(separator))
+        || !matches!(bytes.get(10), Some(b'T' | b't'))
+    {
+        return Err(invalid());
+    }
+    let field = |start, count| digits(bytes, start, count).ok_or_else(invalid);
+    let (year, month, day) = (field(0, 4)?, field(5, 2)?, field(8, 2)?);
+    let (hour, minute, second) = (field(11, 2)?, field(14, 2)?, field(17, 2)?);
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month as u32) as i64
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+    let mut at = 19;
+    let mut fraction = 0;
+    if bytes.get(at) == Some(&b'.') {
+        let count = bytes[at + 1..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_digit())
+            .count();
+        if count == 0 {
+            return Err(invalid());
+        }
+        fraction = field(at + 1, count.min(6))? * 10i64.pow(6 - count.min(6) as u32);
+        at += 1 + count;
+    }
+    let offset = match &bytes[at..] {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let (hours, minutes) = (field(at + 1, 2)?, field(at + 4, 2)?);
+            if hours > 23 || minutes > 59 {
+                return Err(invalid());
+            }
+            let offset = (hours * 60 + minutes) * 60 * MICROS_PER_SECOND;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return Err(invalid()),
+    };
+    let micros = days_from_civil(year, month as u32, day as u32) * MICROS_PER_DAY
+        + ((hour * 60 + minute) * 60 + second) * MICROS_PER_SECOND
+        + fraction
+        - offset;
+    check_range(micros)
+}
+
+// Formats like the Firestore REST API, with 0, 3 or 6 fractional digits
+pub(crate) fn format_timestamp(micros: i64) -> String {
+    let (year, month, day) = civil_from_days(micros.div_euclid(MICROS_PER_DAY));
+    let time = micros.rem_euclid(MICROS_PER_DAY);
+    let seconds = time / MICROS_PER_SECOND;
+    let fraction = match time % MICROS_PER_SECOND {
+        0 => String::new(),
+        fraction if fraction % 1_000 == 0 => format!(".{:03}", fraction / 1_000),
+        fraction => format!(".{:06}", fraction),
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60,
+        fraction
+    )
+}
+
+// Dates share the range of timestamps, 0001-01-01 to 9999-12-31
+pub(crate) fn unix_days(date: &Date) -> i64 {
+    date.to_pg_epoch_days() as i64 + PG_EPOCH_DAYS
+}
+
+pub(crate) fn date_from_unix_days(days: i64) -> Result<Date> {
+    if (MIN_TIMESTAMP.div_euclid(MICROS_PER_DAY)..=MAX_TIMESTAMP.div_euclid(MICROS_PER_DAY))
+        .contains(&days)
+    {
+        Ok(Date::from((days - PG_EPOCH_DAYS) as i32))
+    } else {
+        Err(FsError::InvalidValue(format!(
+            "Date {} days from 1970-01-01 is out of Firestore's range of 0001-01-01 to 9999-12-31",
+            days
+        )))
+    }
+}
+
+// Parses an ISO 8601 calendar date such as 2024-01-31
+pub(crate) fn parse_date(text: &str) -> Result<Date> {
+    let invalid =
+        || FsError::InvalidValue(format!("Failed to parse '{}' as an ISO 8601 date", text));
+    let bytes = text.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return Err(invalid());
+    }
+    let field = |start, count| digits(bytes, start, count).ok_or_else(invalid);
+    let (year, month, day) = (field(0, 4)?, field(5, 2)?, field(8, 2)?);
+    if year < 1
+        || !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month as u32) as i64
+    {
+        return Err(invalid());
+    }
+    date_from_unix_days(days_from_civil(year, month as u32, day as u32))
+}
+
+// ISO 8601, e.g. 2024-01-31
+pub(crate) fn format_date(date: &Date) -> String {
+    let (year, month, day) = civil_from_days(unix_days(date));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// The instant of a temporal value, where a Date stands for midnight UTC
+fn instant(value: &FsValue) -> Result<i64> {
+    match value {
+        FsValue::Timestamp(micros) => Ok(*micros),
+        FsValue::Date(date) => Ok(unix_days(date) * MICROS_PER_DAY),
+        _ => Err(FsError::InvalidType(format!(
+            "Expecting a TIMESTAMP or DATE value but found {}",
+            value.type_name()
+        ))),
+    }
+}
+
+// Shifts like Postgres shifts a timestamp in UTC: months first, clamping the
+// day to the end of the month, then days and then the time.
+fn add_interval(micros: i64, months: i32, days: i32, time: i64) -> Result<i64> {
+    let (year, month, day) = civil_from_days(micros.div_euclid(MICROS_PER_DAY));
+    let months = year * 12 + month as i64 - 1 + months as i64;
+    let (year, month) = (months.div_euclid(12), months.rem_euclid(12) as u32 + 1);
+    let day = day.min(days_in_month(year, month));
+    let shifted = (days_from_civil(year, month, day) + days as i64)
+        .checked_mul(MICROS_PER_DAY)
+        .and_then(|shifted| shifted.checked_add(micros.rem_euclid(MICROS_PER_DAY)))
+        .and_then(|shifted| shifted.checked_add(time))
+        .unwrap_or(i64::MAX);
+    check_range(shifted)
+}
+
+// `lhs - rhs` as whole days and the remaining time, both with its sign
+fn difference(lhs: i64, rhs: i64) -> (i64, i64) {
+    let micros = lhs - rhs;
+    (micros / MICROS_PER_DAY, micros % MICROS_PER_DAY)
+}
+
+const TRUNC_UNITS: [&str; 6] = ["minute", "hour", "day", "week", "month", "year"];
+
+// Truncates in UTC. Weeks start on Monday like ISO 8601 weeks.
+fn truncate(micros: i64, unit: &str) -> Result<i64> {
+    let days = micros.div_euclid(MICROS_PER_DAY);
+    let (year, month, _) = civil_from_days(days);
+    let truncated = match unit {
+        "minute" => micros - micros.rem_euclid(60 * MICROS_PER_SECOND),
+        "hour" => micros - micros.rem_euclid(3_600 * MICROS_PER_SECOND),
+        "day" => days * MICROS_PER_DAY,
+        // 1970-01-01 was a Thursday
+        "week" => (days - (days + 3).rem_euclid(7)) * MICROS_PER_DAY,
+        "month" => days_from_civil(year, month, 1) * MICROS_PER_DAY,
+        "year" => days_from_civil(year, 1, 1) * MICROS_PER_DAY,
+        _ => {
+            return Err(FsError::InvalidValue(format!(
+                "Unknown unit '{}', expecting one of {}",
+                unit,
+                TRUNC_UNITS.join(", ")
+            )))
+        }
+    };
+    check_range(truncated)
+}
+
+// Monday 2000-01-03, so that buckets of whole weeks start on Mondays
+const DEFAULT_BUCKET_ORIGIN: i64 = (PG_EPOCH_DAYS + 2) * MICROS_PER_DAY;
+
+// The start of the bucket of `width` microseconds holding `micros`, where
+// buckets are laid out from `origin` in both directions
+fn bucket(micros: i64, width: i64, origin: i64) -> Result<i64> {
+    let offset = (micros as i128 - origin as i128).div_euclid(width as i128) * width as i128;
+    check_range(i64::try_from(origin as i128 + offset).unwrap_or(i64::MIN))
+}
+
+pub(crate) fn timestamp_value(timestamp: TimestampWithTimeZone) -> Result<FsValue> {
+    let pg_micros: i64 = timestamp.into();
+    // Infinite timestamps are the extremes of i64
+    let micros = pg_micros
+        .checked_add(PG_EPOCH_DAYS * MICROS_PER_DAY)
+        .unwrap_or(i64::MAX);
+    check_range(micros).map(FsValue::Timestamp)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_timestamp(timestamp: TimestampWithTimeZone) -> FsValue {
+    match timestamp_value(timestamp) {
+        Ok(value) => value,
+        Err(error) => error.report(),
+    }
+}
+
+// Infinite dates are out of range
+#[pg_extern(immutable, parallel_safe)]
+fn fs_date(date: Date) -> FsValue {
+    match date_from_unix_days(unix_days(&date)) {
+        Ok(date) => FsValue::Date(date),
+        Err(error) => error.report(),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_timestamptz(fs_value: FsValue) -> Option<TimestampWithTimeZone> {
+    let micros = instant(&fs_value).ok()?;
+    TimestampWithTimeZone::try_from(micros - PG_EPOCH_DAYS * MICROS_PER_DAY).ok()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_timestamp_add(value: FsValue, interval_: Interval) -> FsValue {
+    match instant(&value).and_then(|micros| {
+        add_interval(
+            micros,
+            interval_.months(),
+            interval_.days(),
+            interval_.micros(),
+        )
+    }) {
+        Ok(micros) => FsValue::Timestamp(micros),
+        Err(error) => error.report(),
+    }
+}
+
+// `a - b`, positive when `a` is later
+#[pg_extern(immutable, parallel_safe)]
+fn fs_timestamp_diff(a: FsValue, b: FsValue) -> Interval {
+    let (days, time) = match instant(&a).and_then(|a| Ok(difference(a, instant(&b)?))) {
+        Ok(difference) => difference,
+        Err(error) => error.report(),
+    };
+    Interval::new(0, days as i32, time).unwrap_or_else(|_| {
+        FsError::LimitExceeded(format!(
+            "Difference of {} days is out of range for an interval",
+            days
+        ))
+        .report()
+    })
+}
+
+// Immutable since it works in UTC alone, so it can be used in expression
+// indexes and partition keys
+#[pg_extern(immutable, parallel_safe)]
+fn fs_time_trunc(value: FsValue, unit: &str) -> FsValue {
+    match instant(&value).and_then(|micros| truncate(micros, unit)) {
+        Ok(micros) => FsValue::Timestamp(micros),
+        Err(error) => error.report(),
+    }
+}
+
+// Like date_bin, buckets are a fixed number of days and time wide, so widths
+// with months are rejected
+#[pg_extern(immutable, parallel_safe)]
+fn fs_time_bucket(
+    value: Option<FsValue>,
+    width: Option<Interval>,
+    origin: default!(Option<FsValue>, "NULL"),
+) -> Option<FsValue> {
+    let (value, width) = (value?, width?);
+    if width.months() != 0 {
+        FsError::InvalidValue("Bucket width must not have months or years".to_string()).report()
+    }
+    let width_micros = width.days() as i64 * MICROS_PER_DAY + width.micros();
+    if width_micros <= 0 {
+        FsError::InvalidValue("Bucket width must be positive".to_string()).report()
+    }
+    let origin = match origin {
+        Some(origin) => instant(&origin),
+        None => Ok(DEFAULT_BUCKET_ORIGIN),
+    };
+    match origin.and_then(|origin| bucket(instant(&value)?, width_micros, origin)) {
+        Ok(micros) => Some(FsValue::Timestamp(micros)),
+        Err(error) => error.report(),
+    }
+}
+
+// The time of the current request: pgfirestore.fixed_request_time when set,
+// and the start of the transaction otherwise, like now()
+pub(crate) fn request_time() -> i64 {
+    fixed_request_time().unwrap_or_else(|| {
+        let pg_micros = unsafe { pg_sys::GetCurrentTransactionStartTimestamp() };
+        pg_micros + PG_EPOCH_DAYS * MICROS_PER_DAY
+    })
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn fs_request_time() -> TimestampWithTimeZone {
+    TimestampWithTimeZone::try_from(request_time() - PG_EPOCH_DAYS * MICROS_PER_DAY)
+        .expect("the request time is a valid timestamp")
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn fs_timestamp_now() -> FsValue {
+    FsValue::Timestamp(request_time())
+}
+
+// Writes through the fs_* functions set create_time and update_time to
+// fs_request_time() as well
+extension_sql!(
+    "\n\
+        ALTER TABLE fs_documents ALTER COLUMN create_time SET DEFAULT fs_request_time();\n\
+        ALTER TABLE fs_documents ALTER COLUMN update_time SET DEFAULT fs_request_time();\n\
+    ",
+    name = "update_time_default",
+    requires = ["main_table", fs_request_time],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_timestamp::*;
+
+    fn timestamp(text: &str) -> i64 {
+        parse_timestamp(text).unwrap()
+    }
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1), PG_EPOCH_DAYS);
+        assert_eq!(days_from_civil(1, 1, 1) * MICROS_PER_DAY, MIN_TIMESTAMP);
+        assert_eq!(
+            days_from_civil(10000, 1, 1) * MICROS_PER_DAY - 1,
+            MAX_TIMESTAMP
+        );
+        for days in [-719_162, -1, 0, 59, 11_016, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(timestamp("1970-01-01T00:00:00Z"), 0);
+        assert_eq!(timestamp("1970-01-01T00:00:00.5Z"), 500_000);
+        assert_eq!(timestamp("1970-01-01T00:00:00.123456789Z"), 123_456);
+        assert_eq!(timestamp("1970-01-01T01:00:00+01:00"), 0);
+        assert_eq!(timestamp("1969-12-31t23:30:00-00:30"), 0);
+        for text in [
+            "1970-01-01",
+            "1970-01-01T00:00:00",
+            "1970-13-01T00:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "1970-01-01T24:00:00Z",
+            "1970-01-01T00:00:00.Z",
+            "1970-01-01T00:00:00+0100",
+            "0000-12-31T23:59:59Z",
+        ] {
+            assert!(parse_timestamp(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_parse_date() {
+        for (text, unix_days) in [
+            ("1970-01-01", 0),
+            ("2000-01-01", PG_EPOCH_DAYS),
+            ("2024-02-29", 19_782),
+            ("0001-01-01", -719_162),
+            ("9999-12-31", 2_932_896),
+        ] {
+            let date = parse_date(text).unwrap();
+            assert_eq!(date.to_pg_epoch_days() as i64 + PG_EPOCH_DAYS, unix_days);
+            assert_eq!(format_date(&date), text);
+            assert_eq!(date_from_unix_days(unix_days).unwrap(), date);
+        }
+        for text in [
+            "2024-1-31",
+            "2024-01-31T00:00:00Z",
+            "2023-02-29",
+            "2024-13-01",
+            "2024-00-10",
+            "0000-12-31",
+            "+2024-01-31",
+        ] {
+            assert!(parse_date(text).is_err(), "{}", text);
+        }
+        assert!(date_from_unix_days(2_932_897).is_err());
+        assert!(date_from_unix_days(-719_163).is_err());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        for text in [
+            "1970-01-01T00:00:00Z",
+            "2024-02-29T12:34:56.500Z",
+            "0001-01-01T00:00:00Z",
+            "9999-12-31T23:59:59.999999Z",
+            "1969-12-31T23:59:59.000001Z",
+        ] {
+            assert_eq!(format_timestamp(timestamp(text)), text);
+        }
+    }
+
+    #[test]
+    fn test_add_interval() {
+        // A month from the end of January is the end of February
+        assert_eq!(
+            add_interval(timestamp("2024-01-31T10:00:00Z"), 1, 0, 0)
+                .map(format_timestamp)
+                .unwrap(),
+            "2024-02-29T10:00:00Z"
+        );
+        // A month across the March DST change elsewhere is still whole days in UTC
+        assert_eq!(
+            add_interval(
+                timestamp("2024-03-09T23:00:00Z"),
+                1,
+                1,
+                3_600 * MICROS_PER_SECOND
+            )
+            .map(format_timestamp)
+            .unwrap(),
+            "2024-04-11T00:00:00Z"
+        );
+        assert_eq!(
+            add_interval(timestamp("2024-01-01T00:00:00Z"), -13, 0, -1)
+                .map(format_timestamp)
+                .unwrap(),
+            "2022-11-30T23:59:59.999999Z"
+        );
+        assert_eq!(
+            add_interval(MIN_TIMESTAMP, 0, -1, 0).unwrap_err().to_string(),
+            "InvalidValue: Timestamp is out of Firestore's range of 0001-01-01T00:00:00Z to 9999-12-31T23:59:59.999999Z"
+        );
+        assert!(add_interval(MAX_TIMESTAMP, 0, 0, 1).is_err());
+        assert!(add_interval(MAX_TIMESTAMP, i32::MAX, i32::MAX, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let trunc =
+            |text: &str, unit: &str| format_timestamp(truncate(timestamp(text), unit).unwrap());
+        let at = "2024-03-31T23:59:59.999999Z";
+        assert_eq!(trunc(at, "minute"), "2024-03-31T23:59:00Z");
+        assert_eq!(trunc(at, "hour"), "2024-03-31T23:00:00Z");
+        assert_eq!(trunc(at, "day"), "2024-03-31T00:00:00Z");
+        assert_eq!(trunc(at, "month"), "2024-03-01T00:00:00Z");
+        assert_eq!(
+            trunc("2024-04-01T00:00:00Z", "month"),
+            "2024-04-01T00:00:00Z"
+        );
+        assert_eq!(
+            trunc("2024-02-29T12:00:00Z", "month"),
+            "2024-02-01T00:00:00Z"
+        );
+        assert_eq!(trunc(at, "year"), "2024-01-01T00:00:00Z");
+        // ISO weeks start on Monday: 2024-03-31 is a Sunday, 2024-03-25 a Monday
+        assert_eq!(trunc(at, "week"), "2024-03-25T00:00:00Z");
+        assert_eq!(
+            trunc("2024-03-25T00:00:00Z", "week"),
+            "2024-03-25T00:00:00Z"
+        );
+        assert_eq!(
+            trunc("1970-01-01T12:00:00Z", "week"),
+            "1969-12-29T00:00:00Z"
+        );
+        // Before the epoch too, and 0001-01-01 is a Monday
+        assert_eq!(
+            trunc("1969-12-31T23:59:59.5Z", "hour"),
+            "1969-12-31T23:00:00Z"
+        );
+        assert_eq!(
+            trunc("0001-01-07T00:00:00Z", "week"),
+            "0001-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            truncate(0, "decade").unwrap_err().to_string(),
+            "InvalidValue: Unknown unit 'decade', expecting one of minute, hour, day, week, month, year"
+        );
+    }
+
+    #[test]
+    fn test_bucket() {
+        let hour = 3_600 * MICROS_PER_SECOND;
+        let bucket_of = |text: &str, width: i64, origin: i64| {
+            format_timestamp(bucket(timestamp(text), width, origin).unwrap())
+        };
+        assert_eq!(
+            bucket_of(
+                "2024-01-01T10:59:59Z",
+                15 * 60 * MICROS_PER_SECOND,
+                DEFAULT_BUCKET_ORIGIN
+            ),
+            "2024-01-01T10:45:00Z"
+        );
+        // 2024-01-01 is a Monday
+        assert_eq!(
+            bucket_of(
+                "2024-01-07T23:00:00Z",
+                7 * MICROS_PER_DAY,
+                DEFAULT_BUCKET_ORIGIN
+            ),
+            "2024-01-01T00:00:00Z"
+        );
+        // Aligned to the origin, before and after it
+        let origin = timestamp("2024-01-01T00:30:00Z");
+        assert_eq!(
+            bucket_of("2024-01-01T02:29:59Z", 2 * hour, origin),
+            "2024-01-01T00:30:00Z"
+        );
+        assert_eq!(
+            bucket_of("2024-01-01T02:30:00Z", 2 * hour, origin),
+            "2024-01-01T02:30:00Z"
+        );
+        assert_eq!(
+            bucket_of("2024-01-01T00:29:59Z", 2 * hour, origin),
+            "2023-12-31T22:30:00Z"
+        );
+        assert!(bucket(MIN_TIMESTAMP, MICROS_PER_DAY, MIN_TIMESTAMP + 1).is_err());
+        assert_eq!(
+            bucket(MAX_TIMESTAMP, i64::MAX, MIN_TIMESTAMP).unwrap(),
+            MIN_TIMESTAMP
+        );
+    }
+
+    #[test]
+    fn test_difference() {
+        let (earlier, later) = (
+            timestamp("2024-01-01T00:00:00Z"),
+            timestamp("2024-01-02T02:00:00Z"),
+        );
+        let expected = (1, 2 * 3_600 * MICROS_PER_SECOND);
+        assert_eq!(difference(later, earlier), expected);
+        assert_eq!(difference(earlier, later), (-expected.0, -expected.1));
+        assert_eq!(
+            instant(&FsValue::Date(pgrx::Date::from(8_766))).unwrap(),
+            timestamp("2024-01-01T00:00:00Z")
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_timestamp_arithmetic() {
+        let shifted = Spi::get_one::<String>(
+            "SELECT fs_timestamp_add(fs_timestamp('2024-01-31 10:00:00+00'), '1 month 2 hours')::text",
+        );
+        assert_eq!(
+            shifted,
+            Ok(Some(
+                "{\"type\":\"TIMESTAMP\",\"value\":\"2024-02-29T12:00:00Z\"}".to_owned()
+            ))
+        );
+        // Dates are midnight UTC
+        let diff = fs_timestamp_diff(
+            FsValue::Timestamp(timestamp("2024-01-02T06:00:00Z")),
+            FsValue::Date(pgrx::Date::from(8_768)),
+        );
+        assert_eq!(
+            (diff.months(), diff.days(), diff.micros()),
+            (0, 0, -18 * 3_600 * MICROS_PER_SECOND)
+        );
+        assert_eq!(
+            fs_timestamp_add(FsValue::Date(pgrx::Date::from(8_768)), diff),
+            FsValue::Timestamp(timestamp("2024-01-02T06:00:00Z"))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_date_round_trip() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/events/1'), \
+                 fs_doc('day', fs_date('2024-01-31'), \
+                     'typed', '{\"type\": \"DATE\", \"value\": \"1999-12-31\"}'::fsvalue, \
+                     'days', '{\"type\": \"DATE\", \"value\": 19753}'::fsvalue))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<String>("SELECT fs_get(fs_reference('/events/1'))::text"),
+            Ok(Some(
+                "{\"type\":\"MAP\",\"value\":{\
+                 \"day\":{\"type\":\"DATE\",\"value\":\"2024-01-31\"},\
+                 \"days\":{\"type\":\"DATE\",\"value\":\"2024-01-31\"},\
+                 \"typed\":{\"type\":\"DATE\",\"value\":\"1999-12-31\"}}}"
+                    .to_owned()
+            ))
+        );
+        // The text output reads back as the same value
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT properties::text::fsvalue = properties FROM fs_documents \
+                 WHERE reference = fs_reference('/events/1')"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_date_ordering() {
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_date('1999-12-31') #< fs_date('2000-01-01') \
+                 AND fs_date('2023-12-31') #< fs_date('2024-01-01') \
+                 AND fs_date('2024-03-01') #> fs_date('2023-03-02') \
+                 AND fs_date('0001-01-01') #< fs_date('9999-12-31') \
+                 AND fs_date('2024-01-31') #= '{\"type\": \"DATE\", \"value\": \"2024-01-31\"}'::fsvalue"
+            ),
+            Ok(Some(true))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(day::text::jsonb->>'value', ' ' ORDER BY day) FROM unnest(ARRAY[\
+                 fs_date('2024-01-01'), fs_date('1999-12-31'), fs_date('2023-12-31'), \
+                 fs_date('2000-01-01')]) AS day"
+            ),
+            Ok(Some("1999-12-31 2000-01-01 2023-12-31 2024-01-01".to_owned()))
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Date 2932897 days from 1970-01-01 is out of Firestore's range of 0001-01-01 to 9999-12-31"
+    )]
+    fn test_fs_date_out_of_range() {
+        Spi::run("SELECT fs_date('10000-01-01')").expect("SPI failed");
+    }
+
+    fn timestamp_text(query: &str) -> Option<String> {
+        match Spi::get_one::<FsValue>(query).expect("SPI failed") {
+            Some(FsValue::Timestamp(micros)) => Some(format_timestamp(micros)),
+            Some(other) => panic!("Expecting a timestamp but found {:?}", other),
+            None => None,
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_time_trunc() {
+        assert_eq!(
+            timestamp_text("SELECT fs_time_trunc(fs_timestamp('2024-01-31 23:30:00-05'), 'month')"),
+            Some("2024-02-01T00:00:00Z".to_owned())
+        );
+        // Dates are midnight UTC
+        assert_eq!(
+            timestamp_text("SELECT fs_time_trunc(fs_value('2024-02-29'::date), 'week')"),
+            Some("2024-02-26T00:00:00Z".to_owned())
+        );
+        // Whatever the session time zone
+        Spi::run("SET LOCAL timezone = 'Asia/Tokyo'").expect("SPI failed");
+        assert_eq!(
+            timestamp_text("SELECT fs_time_trunc(fs_timestamp('2024-01-01 03:00:00+09'), 'day')"),
+            Some("2023-12-31T00:00:00Z".to_owned())
+        );
+        Spi::run(
+            "CREATE INDEX fs_documents_day ON fs_documents \
+                 ((fs_time_trunc(properties->'at', 'day')))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a TIMESTAMP or DATE value but found STRING")]
+    fn test_fs_time_trunc_not_a_timestamp() {
+        fs_time_trunc(FsValue::String("2024-01-01".to_owned()), "day");
+    }
+
+    #[pg_test]
+    fn test_fs_time_bucket() {
+        assert_eq!(
+            timestamp_text(
+                "SELECT fs_time_bucket(fs_timestamp('2024-01-03 10:00:00+00'), '1 week')"
+            ),
+            Some("2024-01-01T00:00:00Z".to_owned())
+        );
+        assert_eq!(
+            timestamp_text(
+                "SELECT fs_time_bucket(fs_timestamp('2024-01-03 10:00:00+00'), '6 hours', \
+                     fs_timestamp('2024-01-01 05:00:00+00'))"
+            ),
+            Some("2024-01-03T05:00:00Z".to_owned())
+        );
+        assert_eq!(
+            timestamp_text(
+                "SELECT fs_time_bucket(fs_value('2024-01-03'::date), '1 day 12 hours', \
+                     fs_value('2024-01-01'::date))"
+            ),
+            Some("2024-01-02T12:00:00Z".to_owned())
+        );
+        assert_eq!(timestamp_text("SELECT fs_time_bucket(NULL, '1 day')"), None);
+    }
+
+    #[pg_test(error = "InvalidValue: Bucket width must not have months or years")]
+    fn test_fs_time_bucket_months() {
+        Spi::run("SELECT fs_time_bucket(fs_timestamp(now()), '1 month')").expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidValue: Bucket width must be positive")]
+    fn test_fs_time_bucket_not_positive() {
+        Spi::run("SELECT fs_time_bucket(fs_timestamp(now()), '-1 hour')").expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Timestamp is out of Firestore's range of 0001-01-01T00:00:00Z to 9999-12-31T23:59:59.999999Z"
+    )]
+    fn test_fs_timestamp_add_out_of_range() {
+        Spi::run("SELECT fs_timestamp_add(fs_timestamp('0001-01-01 00:00:00+00'), '-1 second')")
+            .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fixed_request_time() {
+        Spi::run(
+            "SET pgfirestore.fixed_request_time = '2024-01-01T00:00:00Z'; \
+             SELECT fs_set(fs_reference('/clock/a'), fs_map_from_entries(ARRAY['at'], ARRAY[fs_timestamp_now()])); \
+             SELECT fs_set(fs_reference('/clock/b'), fs_map_from_entries(ARRAY['at'], ARRAY[fs_timestamp_now()]))",
+        )
+        .expect("SPI failed");
+        let pinned = FsValue::Timestamp(timestamp("2024-01-01T00:00:00Z"));
+        assert_eq!(fs_timestamp_now(), pinned);
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents \
+                 WHERE reference IN (fs_reference('/clock/a'), fs_reference('/clock/b')) \
+                 AND update_time = '2024-01-01T00:00:00Z' \
+                 AND properties->'at' = fs_timestamp_now()"
+            )
+            .expect("SPI failed"),
+            Some(2)
+        );
+
+        Spi::run("RESET pgfirestore.fixed_request_time").expect("SPI failed");
+        assert_ne!(fs_timestamp_now(), pinned);
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT fs_request_time() = now()").expect("SPI failed"),
+            Some(true)
+        );
+    }
+
+    #[pg_test(
+        error = "invalid value for parameter \"pgfirestore.fixed_request_time\": \"yesterday\""
+    )]
+    fn test_fixed_request_time_invalid() {
+        Spi::run("SET pgfirestore.fixed_request_time = 'yesterday'").expect("SPI failed");
+    }
+}
@@ -0,0 +1,517 @@
+use crate::fs_display::display_value;
+use crate::fs_documents::{expect_parent_reference, fsvalue_arg, text_arg};
+use crate::fs_error::{report, FsCode};
+use crate::fs_field_path::FieldPath;
+use crate::fs_query::sql_literal;
+use crate::fs_sort_key::encode_sort_key;
+use crate::fs_view::quote_identifier;
+use crate::{encode_hex, FsError, FsReference, FsValue};
+use pgrx::pg_sys::panic::CaughtError;
+use pgrx::prelude::*;
+use sha2::{Digest, Sha256};
+use std::ffi::CStr;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+const INDEX_PREFIX: &str = "fs_unique_";
+
+// A field whose values must differ between the live documents of a
+// collection, enforced by a partial unique index over their sort keys. NULL
+// and missing values are left out of the index, as are tombstones.
+struct UniqueConstraint {
+    name: String,
+    parent: FsReference,
+    collection_id: String,
+    field_path: String,
+}
+
+impl UniqueConstraint {
+    fn new(
+        parent: &FsReference,
+        collection_id: &str,
+        field_path: &str,
+    ) -> Result<UniqueConstraint> {
+        if collection_id.is_empty() || collection_id.contains('/') {
+            return Err(FsError::InvalidValue(format!(
+                "Invalid collection ID '{}'",
+                collection_id
+            )));
+        }
+        FieldPath::from_str(field_path)?.into_field_names()?;
+        Ok(UniqueConstraint {
+            name: constraint_name(parent, collection_id, field_path),
+            parent: parent.to_owned(),
+            collection_id: collection_id.to_owned(),
+            field_path: field_path.to_owned(),
+        })
+    }
+
+    fn field(&self) -> String {
+        format!(
+            "fs_get_field(properties, {})",
+            sql_literal(&self.field_path)
+        )
+    }
+
+    // Only immutable functions may appear in the predicate of an index
+    fn predicate(&self) -> String {
+        format!(
+            "fs_parent(reference) = {}::fsvalue AND fs_collection_id(reference) = {} \
+             AND deleted_at IS NULL AND fs_is_not_null({})",
+            sql_literal(&FsValue::Reference(self.parent.to_owned()).canonical_text()),
+            sql_literal(&self.collection_id),
+            self.field()
+        )
+    }
+
+    fn create_index_statement(&self) -> String {
+        format!(
+            "CREATE UNIQUE INDEX {} ON fs_documents (fs_sort_key({})) WHERE {}",
+            quote_identifier(&self.name),
+            self.field(),
+            self.predicate()
+        )
+    }
+
+    // Two documents already holding the same value, which would fail the
+    // creation of the index
+    fn find_duplicate(&self) -> Option<(FsValue, FsValue, FsValue)> {
+        Spi::connect(|client| {
+            let table = client.select(
+                &format!(
+                    "SELECT (array_agg(reference ORDER BY reference))[1], \
+                     (array_agg(reference ORDER BY reference))[2], \
+                     (array_agg({0}))[1] \
+                     FROM fs_documents WHERE {1} \
+                     GROUP BY fs_sort_key({0}) HAVING count(*) > 1 LIMIT 1",
+                    self.field(),
+                    self.predicate()
+                ),
+                None,
+                None,
+            )?;
+            if table.is_empty() {
+                return Ok(None);
+            }
+            let row = table.first();
+            Ok::<_, pgrx::spi::Error>(Some((
+                row.get::<FsValue>(1)?.expect("reference must not be null"),
+                row.get::<FsValue>(2)?.expect("reference must not be null"),
+                row.get::<FsValue>(3)?.unwrap_or(FsValue::NULL),
+            )))
+        })
+        .expect("Failed to read from fs_documents")
+    }
+}
+
+// Derived from the constraint so that a written document can be matched to
+// the constraints over its collection
+fn constraint_name(parent: &FsReference, collection_id: &str, field_path: &str) -> String {
+    let digest = Sha256::digest(format!("{}|{}|{}", parent, collection_id, field_path).as_bytes());
+    format!("{}{}", INDEX_PREFIX, encode_hex(&digest[..12]))
+}
+
+fn collection_path(parent: &FsReference, collection_id: &str) -> String {
+    if parent.is_root() {
+        format!("/{}", collection_id)
+    } else {
+        format!("{}/{}", parent, collection_id)
+    }
+}
+
+// The field paths of the constraints over the collections of `documents`,
+// by constraint name. They are read before writing, since the catalog cannot
+// be read once the write has failed.
+fn written_constraints(documents: &[(&FsReference, &FsValue)]) -> Vec<(String, String)> {
+    let constraints = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT constraint_name, field_path FROM fs_unique_field_constraints",
+                None,
+                None,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.expect("name must not be null"),
+                    row.get::<String>(2)?.expect("field path must not be null"),
+                ))
+            })
+            .collect::<std::result::Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .expect("Failed to read from fs_unique_field_constraints");
+    constraints
+        .into_iter()
+        .filter(|(name, field_path)| {
+            documents.iter().any(|(reference, _)| {
+                !reference.is_root()
+                    && constraint_name(&reference.parent(), reference.collection_id(), field_path)
+                        == *name
+            })
+        })
+        .collect()
+}
+
+// The constraint named by the error being handled. pgrx only keeps the
+// message and detail of a caught error, but the error itself stays current
+// until the handler returns.
+fn violated_constraint() -> Option<String> {
+    unsafe {
+        let error = pg_sys::CopyErrorData();
+        let name = (!(*error).constraint_name.is_null()).then(|| {
+            CStr::from_ptr((*error).constraint_name)
+                .to_string_lossy()
+                .into_owned()
+        });
+        pg_sys::FreeErrorData(error);
+        name.filter(|name| name.starts_with(INDEX_PREFIX))
+    }
+}
+
+// The readable message of a violation of the unique field constraint
+// `violated` by one of the written `documents`, or None when it is not
+// one of `constraints`
+fn violation_message(
+    violated: &str,
+    detail: Option<&str>,
+    constraints: &[(String, String)],
+    documents: &[(&FsReference, &FsValue)],
+) -> Option<String> {
+    let (_, field_path) = constraints.iter().find(|(name, _)| name == violated)?;
+    let field_names = FieldPath::from_str(field_path)
+        .and_then(FieldPath::into_field_names)
+        .ok()?;
+    // The written documents holding a value under the constraint, the later
+    // ones first
+    let candidates = documents
+        .iter()
+        .rev()
+        .filter(|(reference, _)| {
+            !reference.is_root()
+                && constraint_name(&reference.parent(), reference.collection_id(), field_path)
+                    == violated
+        })
+        .filter_map(|(reference, properties)| {
+            let value = properties.get_field(&field_names)?;
+            (*value != FsValue::NULL).then_some((reference, value))
+        })
+        .collect::<Vec<_>>();
+    // The detail shows the taken sort key unless the user cannot read the
+    // table. Of two written documents holding the same value, the later one
+    // is taken to be at fault.
+    let (reference, value) = candidates
+        .iter()
+        .find(|(_, value)| {
+            let mut sort_key = Vec::new();
+            encode_sort_key(value, &mut sort_key);
+            detail.is_some_and(|detail| detail.contains(&format!("\\x{}", encode_hex(&sort_key))))
+        })
+        .or_else(|| candidates.first())?;
+    let parent = reference.parent();
+    Some(format!(
+        "Field {} of {} must be unique in {} but {} is already taken",
+        field_path,
+        reference,
+        collection_path(&parent, reference.collection_id()),
+        display_value(value)
+    ))
+}
+
+// Runs `write` of `documents`, reporting a violation of a unique field
+// constraint as ALREADY_EXISTS with the field and value at fault. Other
+// errors are raised as they are.
+pub(crate) fn report_unique_violations<R>(
+    documents: &[(&FsReference, &FsValue)],
+    write: impl FnOnce() -> R,
+) -> R {
+    let constraints = written_constraints(documents);
+    if constraints.is_empty() {
+        return write();
+    }
+    PgTryBuilder::new(AssertUnwindSafe(write))
+        .catch_when(PgSqlErrorCode::ERRCODE_UNIQUE_VIOLATION, |error| {
+            if let CaughtError::PostgresError(ereport) = &error {
+                if let Some(message) = violated_constraint().and_then(|name| {
+                    violation_message(&name, ereport.detail(), &constraints, documents)
+                }) {
+                    report(FsCode::AlreadyExists, message)
+                }
+            }
+            error.rethrow()
+        })
+        .execute()
+}
+
+// Makes `field_path` unique among the live documents of a collection,
+// returning the name of the constraint. Creating a constraint that exists
+// returns its name.
+#[pg_extern]
+fn fs_create_unique_field_constraint(
+    parent: FsValue,
+    collection_id: &str,
+    field_path: &str,
+) -> String {
+    let parent_ref = expect_parent_reference(&parent);
+    let constraint = match UniqueConstraint::new(parent_ref, collection_id, field_path) {
+        Ok(constraint) => constraint,
+        Err(error) => error.report(),
+    };
+    let created = Spi::connect(|mut client| {
+        client
+            .update(
+                "INSERT INTO fs_unique_field_constraints \
+                 (constraint_name, parent, collection_id, field_path) VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT (constraint_name) DO NOTHING RETURNING constraint_name",
+                None,
+                Some(vec![
+                    text_arg(&constraint.name),
+                    fsvalue_arg(parent.to_owned()),
+                    text_arg(collection_id),
+                    text_arg(field_path),
+                ]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to write to fs_unique_field_constraints");
+    if created {
+        if let Some((first, second, value)) = constraint.find_duplicate() {
+            report(
+                FsCode::FailedPrecondition,
+                format!(
+                    "Cannot make field {} unique in {}: {} and {} both hold {}",
+                    field_path,
+                    collection_path(parent_ref, collection_id),
+                    display_value(&first),
+                    display_value(&second),
+                    display_value(&value)
+                ),
+            )
+        }
+        Spi::run(&constraint.create_index_statement()).expect("Failed to create unique index");
+    }
+    constraint.name
+}
+
+// Returns whether the constraint existed
+#[pg_extern]
+fn fs_drop_unique_field_constraint(constraint_name: &str) -> bool {
+    let dropped = Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM fs_unique_field_constraints WHERE constraint_name = $1 \
+                 RETURNING constraint_name",
+                None,
+                Some(vec![text_arg(constraint_name)]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to delete from fs_unique_field_constraints");
+    if dropped {
+        Spi::run(&format!(
+            "DROP INDEX IF EXISTS {}",
+            quote_identifier(constraint_name)
+        ))
+        .expect("Failed to drop unique index");
+    }
+    dropped
+}
+
+#[pg_extern]
+fn fs_list_unique_field_constraints() -> TableIterator<
+    'static,
+    (
+        name!(constraint_name, String),
+        name!(parent, FsValue),
+        name!(collection_id, String),
+        name!(field_path, String),
+    ),
+> {
+    let rows = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT constraint_name, parent, collection_id, field_path \
+                 FROM fs_unique_field_constraints ORDER BY constraint_name",
+                None,
+                None,
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<String>(1)?.expect("name must not be null"),
+                    row.get::<FsValue>(2)?.expect("parent must not be null"),
+                    row.get::<String>(3)?
+                        .expect("collection id must not be null"),
+                    row.get::<String>(4)?.expect("field path must not be null"),
+                ))
+            })
+            .collect::<std::result::Result<Vec<_>, pgrx::spi::Error>>()
+    })
+    .expect("Failed to read from fs_unique_field_constraints");
+    TableIterator::new(rows.into_iter())
+}
+
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_unique_field_constraints (\n\
+            constraint_name text PRIMARY KEY,\n\
+            parent fsvalue NOT NULL,\n\
+            collection_id text NOT NULL,\n\
+            field_path text NOT NULL\n\
+        );\n\
+    ",
+    name = "unique_field_constraints_table",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_unique::*;
+
+    fn set(path: &str, email: &str) {
+        Spi::run(&format!(
+            "SELECT fs_set(fs_reference('{}'), fs_map_from_entries(ARRAY['email'], ARRAY[{}]))",
+            path, email
+        ))
+        .expect("SPI failed");
+    }
+
+    fn unique_email() -> String {
+        fs_create_unique_field_constraint(
+            FsValue::Reference(crate::FS_REFERENCE_ROOT),
+            "users",
+            "email",
+        )
+    }
+
+    #[pg_test(
+        error = "Field email of /users/7 must be unique in /users but \"a@example.com\" is already taken"
+    )]
+    fn test_unique_field_violation() {
+        unique_email();
+        set("/users/6", "fs_string('a@example.com')");
+        set("/users/7", "fs_string('a@example.com')");
+    }
+
+    #[pg_test(
+        error = "Field email of /users/7 must be unique in /users but \"a@example.com\" is already taken"
+    )]
+    fn test_unique_field_bulk_violation() {
+        unique_email();
+        Spi::run(
+            "SELECT fs_bulk_set( \
+                 ARRAY[fs_reference('/users/6'), fs_reference('/users/7')], \
+                 ARRAY[fs_map_from_entries(ARRAY['email'], ARRAY[fs_string('a@example.com')]), \
+                       fs_map_from_entries(ARRAY['email'], ARRAY[fs_string('a@example.com')])])",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "Field email of /users/6 must be unique in /users but \"a@example.com\" is already taken"
+    )]
+    fn test_unique_field_bulk_violation_of_earlier_document() {
+        unique_email();
+        set("/users/5", "fs_string('a@example.com')");
+        Spi::run(
+            "SELECT fs_bulk_set( \
+                 ARRAY[fs_reference('/users/6'), fs_reference('/users/7')], \
+                 ARRAY[fs_map_from_entries(ARRAY['email'], ARRAY[fs_string('a@example.com')]), \
+                       fs_map_from_entries(ARRAY['email'], ARRAY[fs_string('b@example.com')])])",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_unique_field_allowed() {
+        let name = unique_email();
+        assert_eq!(unique_email(), name);
+        set("/users/6", "fs_string('a@example.com')");
+        // Other collections, including those with the same ID elsewhere
+        set("/posts/6", "fs_string('a@example.com')");
+        set("/users/1/users/6", "fs_string('a@example.com')");
+        // NULL and missing values
+        set("/users/7", "fs_null()");
+        set("/users/8", "fs_null()");
+        // The same value in another type
+        set("/users/9", "fs_bytes('a@example.com'::bytea)");
+        // Tombstones and documents moving away from a value
+        Spi::run("SET LOCAL pgfirestore.soft_delete = on").expect("SPI failed");
+        Spi::run("SELECT fs_delete(fs_reference('/users/6'))").expect("SPI failed");
+        set("/users/10", "fs_string('a@example.com')");
+        set("/users/10", "fs_string('b@example.com')");
+        set("/users/11", "fs_string('a@example.com')");
+        // Writing a document again with its own value
+        set("/users/11", "fs_string('a@example.com')");
+    }
+
+    #[pg_test(error = "Cannot make field foo unique in /users: /users/2 and /users/6 both hold 2")]
+    fn test_unique_field_existing_duplicates() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/6'), \
+                 fs_map_from_entries(ARRAY['foo'], ARRAY[fs_number_from_integer(2)]))",
+        )
+        .expect("SPI failed");
+        fs_create_unique_field_constraint(
+            FsValue::Reference(crate::FS_REFERENCE_ROOT),
+            "users",
+            "foo",
+        );
+    }
+
+    #[pg_test]
+    fn test_unique_field_constraints_list_and_drop() {
+        let name = unique_email();
+        let posts =
+            fs_create_unique_field_constraint(crate::fs_reference("/users/1"), "posts", "foo");
+        assert!(name.starts_with(INDEX_PREFIX) && posts.starts_with(INDEX_PREFIX));
+        let mut expected = vec![
+            (
+                name.to_owned(),
+                FsValue::Reference(crate::FS_REFERENCE_ROOT),
+                "users".to_owned(),
+                "email".to_owned(),
+            ),
+            (
+                posts.to_owned(),
+                crate::fs_reference("/users/1"),
+                "posts".to_owned(),
+                "foo".to_owned(),
+            ),
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            fs_list_unique_field_constraints().collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "SELECT EXISTS (SELECT 1 FROM pg_indexes WHERE indexname = $1)",
+                vec![text_arg(&name)],
+            ),
+            Ok(Some(true))
+        );
+
+        assert!(fs_drop_unique_field_constraint(&name));
+        assert!(!fs_drop_unique_field_constraint(&name));
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "SELECT EXISTS (SELECT 1 FROM pg_indexes WHERE indexname = $1)",
+                vec![text_arg(&name)],
+            ),
+            Ok(Some(false))
+        );
+        assert_eq!(fs_list_unique_field_constraints().count(), 1);
+        set("/users/6", "fs_string('a@example.com')");
+        set("/users/7", "fs_string('a@example.com')");
+    }
+
+    #[pg_test(error = "InvalidValue: Invalid collection ID 'a/b'")]
+    fn test_unique_field_invalid_collection() {
+        fs_create_unique_field_constraint(
+            FsValue::Reference(crate::FS_REFERENCE_ROOT),
+            "a/b",
+            "email",
+        );
+    }
+}
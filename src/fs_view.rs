@@ -0,0 +1,559 @@
+use crate::fs_display::display_json;
+use crate::fs_documents::{expect_parent_reference, fsvalue_arg, jsonb_arg, text_arg};
+use crate::fs_error::{report, FsCode};
+use crate::fs_field_path::FieldPath;
+use crate::fs_query::sql_literal;
+use crate::{FsError, FsValue};
+use pgrx::prelude::*;
+use pgrx::{JsonB, PgOid};
+use serde_json::Value;
+use std::str::FromStr;
+
+type Result<T> = std::result::Result<T, FsError>;
+
+// Columns every generated view and flat table starts with
+const RESERVED_COLUMNS: [&str; 2] = ["reference", "document_id"];
+
+const MATERIALIZE_BATCH_SIZE: i64 = 1000;
+
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+// A typed column extracting a field of the document properties
+struct Column {
+    name: String,
+    expression: String,
+    sql_type: &'static str,
+}
+
+// The SQL expression extracting `field_path` from `properties` as
+// `column_type`, and the name of that type. Values of another type come
+// through as NULL.
+fn column_expression(field_path: &str, column_type: &str) -> Result<(String, &'static str)> {
+    FieldPath::from_str(field_path)?.into_field_names()?;
+    let field = format!("fs_get_field(properties, {})", sql_literal(field_path));
+    let expression = match column_type.trim().to_lowercase().as_str() {
+        "fsvalue" => (field, "fsvalue"),
+        "text" => (format!("fs_as_text({})", field), "text"),
+        "bigint" | "int8" => (format!("fs_as_bigint({})", field), "bigint"),
+        "integer" | "int" | "int4" => (format!("fs_as_bigint({})::integer", field), "integer"),
+        "double precision" | "float8" => (format!("fs_as_double({})", field), "double precision"),
+        "real" | "float4" => (format!("fs_as_double({})::real", field), "real"),
+        "boolean" | "bool" => (format!("fs_as_boolean({})", field), "boolean"),
+        "text[]" => (format!("fs_as_text_array({})", field), "text[]"),
+        "bigint[]" | "int8[]" => (format!("fs_as_bigint_array({})", field), "bigint[]"),
+        "timestamptz" | "timestamp with time zone" => (
+            format!("fs_as_timestamptz({})", field),
+            "timestamp with time zone",
+        ),
+        _ => {
+            return Err(FsError::InvalidValue(format!(
+                "Unsupported column type '{}' for field '{}'",
+                column_type, field_path
+            )))
+        }
+    };
+    Ok(expression)
+}
+
+// The columns of a field spec, which maps field paths to column types
+fn spec_columns(fields: &Value) -> Result<Vec<Column>> {
+    let fields = fields.as_object().ok_or_else(|| {
+        FsError::InvalidValue(format!(
+            "Expecting a JSON object of field types but found {}",
+            display_json(fields)
+        ))
+    })?;
+    let mut columns = Vec::new();
+    for (field_path, column_type) in fields.iter() {
+        if RESERVED_COLUMNS.contains(&field_path.as_str()) {
+            return Err(FsError::InvalidValue(format!(
+                "Field '{}' clashes with a column every view includes",
+                field_path
+            )));
+        }
+        let column_type = column_type.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a type name for field '{}' but found {}",
+                field_path,
+                display_json(column_type)
+            ))
+        })?;
+        let (expression, sql_type) = column_expression(field_path, column_type)?;
+        columns.push(Column {
+            name: field_path.to_owned(),
+            expression,
+            sql_type,
+        });
+    }
+    Ok(columns)
+}
+
+fn view_definition(
+    view_name: &str,
+    parent: &FsValue,
+    collection_id: &str,
+    fields: &Value,
+) -> Result<String> {
+    let mut columns = vec![
+        "reference".to_owned(),
+        "fs_document_id(reference) AS document_id".to_owned(),
+    ];
+    columns.extend(spec_columns(fields)?.into_iter().map(|column| {
+        format!(
+            "{} AS {}",
+            column.expression,
+            quote_identifier(&column.name)
+        )
+    }));
+    Ok(format!(
+        "CREATE VIEW {} AS SELECT {} FROM fs_collection({}::fsvalue, {})",
+        quote_identifier(view_name),
+        columns.join(", "),
+        sql_literal(&parent.canonical_text()),
+        sql_literal(collection_id)
+    ))
+}
+
+fn build_view(view_name: &str, parent: &FsValue, collection_id: &str, fields: &Value) {
+    let definition = match view_definition(view_name, parent, collection_id, fields) {
+        Ok(definition) => definition,
+        Err(error) => error.report(),
+    };
+    Spi::run(&format!(
+        "DROP VIEW IF EXISTS {}; {}",
+        quote_identifier(view_name),
+        definition
+    ))
+    .expect("Failed to create view")
+}
+
+// Creates a view with one typed column per entry of `fields`, which maps
+// field paths to column types, e.g. {"name": "text", "age": "bigint"}.
+#[pg_extern]
+fn fs_create_view(view_name: &str, parent: FsValue, collection_id: &str, fields: JsonB) {
+    expect_parent_reference(&parent);
+    build_view(view_name, &parent, collection_id, &fields.0);
+    Spi::run_with_args(
+        "INSERT INTO fs_views (view_name, parent, collection_id, fields) VALUES ($1, $2, $3, $4)",
+        Some(vec![
+            text_arg(view_name),
+            fsvalue_arg(parent),
+            text_arg(collection_id),
+            jsonb_arg(fields.0),
+        ]),
+    )
+    .expect("Failed to write to fs_views")
+}
+
+// Regenerates a view, replacing its field spec when `fields` is given
+#[pg_extern]
+fn fs_refresh_view(view_name: &str, fields: default!(Option<JsonB>, "NULL")) {
+    let spec = Spi::connect(|client| {
+        let table = client.select(
+            "SELECT parent, collection_id, fields FROM fs_views WHERE view_name = $1",
+            Some(1),
+            Some(vec![text_arg(view_name)]),
+        )?;
+        if table.is_empty() {
+            return Ok(None);
+        }
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>(Some((
+            row.get::<FsValue>(1)?.expect("parent must not be null"),
+            row.get::<String>(2)?
+                .expect("collection id must not be null"),
+            row.get::<JsonB>(3)?.expect("fields must not be null").0,
+        )))
+    })
+    .expect("Failed to read from fs_views");
+    let (parent, collection_id, stored_fields) = match spec {
+        Some(spec) => spec,
+        None => report(
+            FsCode::NotFound,
+            format!("View {} was not created by fs_create_view", view_name),
+        ),
+    };
+    let fields = fields.map(|fields| fields.0).unwrap_or(stored_fields);
+    build_view(view_name, &parent, &collection_id, &fields);
+    Spi::run_with_args(
+        "UPDATE fs_views SET fields = $2 WHERE view_name = $1",
+        Some(vec![text_arg(view_name), jsonb_arg(fields)]),
+    )
+    .expect("Failed to write to fs_views")
+}
+
+// Returns whether the view existed
+#[pg_extern]
+fn fs_drop_view(view_name: &str) -> bool {
+    let dropped = Spi::connect(|mut client| {
+        client
+            .update(
+                "DELETE FROM fs_views WHERE view_name = $1 RETURNING view_name",
+                None,
+                Some(vec![text_arg(view_name)]),
+            )
+            .map(|table| !table.is_empty())
+    })
+    .expect("Failed to delete from fs_views");
+    if dropped {
+        Spi::run(&format!(
+            "DROP VIEW IF EXISTS {}",
+            quote_identifier(view_name)
+        ))
+        .expect("Failed to drop view");
+    }
+    dropped
+}
+
+// Inserts the next batch of documents after `after` in reference order into
+// a flat table, returning how many were inserted and the last one's reference
+fn materialize_batch(
+    insert: &str,
+    parent: &FsValue,
+    collection_id: &str,
+    after: Option<FsValue>,
+) -> (i64, Option<FsValue>) {
+    Spi::connect(|mut client| {
+        let table = client.update(
+            insert,
+            Some(1),
+            Some(vec![
+                fsvalue_arg(parent.to_owned()),
+                text_arg(collection_id),
+                (PgOid::from(FsValue::type_oid()), after.into_datum()),
+                (
+                    PgBuiltInOids::INT8OID.oid(),
+                    MATERIALIZE_BATCH_SIZE.into_datum(),
+                ),
+            ]),
+        )?;
+        let row = table.first();
+        Ok::<_, pgrx::spi::Error>((row.get::<i64>(1)?.unwrap_or(0), row.get::<FsValue>(2)?))
+    })
+    .expect("Failed to write to the flat table")
+}
+
+// Copies a collection into an ordinary table with one typed column per entry
+// of `fields`, in the format of fs_create_view, for scans that would be too
+// slow through a view. The table is dropped and created again unless
+// `refresh` is set, which keeps it and only replaces its rows.
+#[pg_extern]
+fn fs_materialize_flat(
+    parent: FsValue,
+    collection_id: &str,
+    table_name: &str,
+    fields: JsonB,
+    refresh: default!(bool, false),
+) -> i64 {
+    expect_parent_reference(&parent);
+    let columns = match spec_columns(&fields.0) {
+        Ok(columns) => columns,
+        Err(error) => error.report(),
+    };
+    let table = quote_identifier(table_name);
+    if refresh {
+        Spi::run(&format!("TRUNCATE {}", table)).expect("Failed to truncate the flat table");
+    } else {
+        let definitions = columns
+            .iter()
+            .map(|column| format!("{} {}", quote_identifier(&column.name), column.sql_type))
+            .collect::<Vec<_>>();
+        Spi::run(&format!(
+            "DROP TABLE IF EXISTS {0}; \
+             CREATE TABLE {0} (reference fsvalue PRIMARY KEY, document_id text{1}{2})",
+            table,
+            if definitions.is_empty() { "" } else { ", " },
+            definitions.join(", ")
+        ))
+        .expect("Failed to create the flat table");
+    }
+    let names = columns
+        .iter()
+        .map(|column| format!(", {}", quote_identifier(&column.name)))
+        .collect::<String>();
+    let expressions = columns
+        .iter()
+        .map(|column| format!(", {}", column.expression))
+        .collect::<String>();
+    let insert = format!(
+        "WITH batch AS ( \
+             SELECT reference, properties FROM fs_collection($1, $2) \
+             WHERE $3::fsvalue IS NULL OR reference > $3 ORDER BY reference LIMIT $4 \
+         ), inserted AS ( \
+             INSERT INTO {} (reference, document_id{}) \
+             SELECT reference, fs_document_id(reference){} FROM batch \
+         ) \
+         SELECT count(*), (array_agg(reference ORDER BY reference DESC))[1] FROM batch",
+        table, names, expressions
+    );
+    let mut inserted = 0;
+    let mut after = None;
+    loop {
+        // Honors statement_timeout and cancel requests on large collections
+        check_for_interrupts!();
+        let (count, last) = materialize_batch(&insert, &parent, collection_id, after);
+        inserted += count;
+        if count < MATERIALIZE_BATCH_SIZE {
+            return inserted;
+        }
+        after = last;
+    }
+}
+
+extension_sql!(
+    "\n\
+        CREATE TABLE fs_views (\n\
+            view_name text PRIMARY KEY,\n\
+            parent fsvalue NOT NULL,\n\
+            collection_id text NOT NULL,\n\
+            fields jsonb NOT NULL\n\
+        );\n\
+    ",
+    name = "views_table",
+    requires = [FsValue],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use crate::fs_view::*;
+    use serde_json::json;
+
+    fn view_rows(query: &str) -> Vec<(String, Option<i64>, Option<i32>)> {
+        Spi::connect(|client| {
+            let mut rows = Vec::new();
+            for row in client.select(query, None, None)? {
+                rows.push((
+                    row.get::<String>(1)?.expect("document id must not be null"),
+                    row.get::<i64>(2)?,
+                    row.get::<i32>(3)?,
+                ));
+            }
+            Ok::<_, pgrx::spi::Error>(rows)
+        })
+        .expect("SPI failed")
+    }
+
+    #[test]
+    fn test_view_definition() {
+        assert_eq!(
+            view_definition(
+                "user \"view\"",
+                &crate::fs_database_root(),
+                "users",
+                &json!({"name": "text", "tags": "text[]"})
+            )
+            .unwrap(),
+            "CREATE VIEW \"user \"\"view\"\"\" AS SELECT reference, \
+             fs_document_id(reference) AS document_id, \
+             fs_as_text(fs_get_field(properties, 'name')) AS \"name\", \
+             fs_as_text_array(fs_get_field(properties, 'tags')) AS \"tags\" \
+             FROM fs_collection('{\"type\":\"REFERENCE\",\"value\":\"/\"}'::fsvalue, 'users')"
+        );
+        assert!(view_definition("v", &crate::fs_database_root(), "users", &json!([])).is_err());
+        assert!(view_definition(
+            "v",
+            &crate::fs_database_root(),
+            "users",
+            &json!({"reference": "text"})
+        )
+        .is_err());
+    }
+
+    #[pg_test]
+    fn test_fs_create_view() {
+        fs_create_view(
+            "users_view",
+            crate::fs_database_root(),
+            "users",
+            JsonB(json!({"foo": "bigint", "bar": "integer"})),
+        );
+        assert_eq!(
+            view_rows("SELECT document_id, foo, bar FROM users_view ORDER BY document_id"),
+            vec![
+                ("1".to_owned(), Some(0), Some(0)),
+                ("2".to_owned(), Some(2), None),
+                ("3".to_owned(), Some(3), None),
+                ("4".to_owned(), Some(4), None),
+                ("5".to_owned(), Some(5), None),
+            ]
+        );
+
+        // The refreshed view swaps the columns
+        fs_refresh_view(
+            "users_view",
+            Some(JsonB(json!({"bar": "bigint", "foo": "integer"}))),
+        );
+        assert_eq!(
+            view_rows("SELECT document_id, bar, foo FROM users_view WHERE document_id = '1'"),
+            vec![("1".to_owned(), Some(0), Some(0))]
+        );
+        assert_eq!(
+            Spi::get_one::<JsonB>("SELECT fields FROM fs_views WHERE view_name = 'users_view'")
+                .expect("SPI failed")
+                .map(|fields| fields.0),
+            Some(json!({"bar": "bigint", "foo": "integer"}))
+        );
+
+        assert!(fs_drop_view("users_view"));
+        assert!(!fs_drop_view("users_view"));
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT to_regclass('users_view') IS NULL").expect("SPI failed"),
+            Some(true)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_create_view_timestamptz() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/events/1'), fs_map_from_entries(ARRAY['created'], \
+                 ARRAY[fs_timestamp('2024-01-31T12:00:00Z'::timestamptz)])); \
+             SELECT fs_set(fs_reference('/events/2'), fs_map_from_entries(ARRAY['created'], \
+                 ARRAY[fs_string('yesterday')]))",
+        )
+        .expect("SPI failed");
+        fs_create_view(
+            "events_view",
+            crate::fs_database_root(),
+            "events",
+            JsonB(json!({"created": "timestamptz"})),
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(document_id || '=' || coalesce( \
+                     to_char(created AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI'), 'null'), \
+                     ',' ORDER BY document_id) FROM events_view"
+            ),
+            Ok(Some("1=2024-01-31 12:00,2=null".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT format_type(atttypid, atttypmod) FROM pg_attribute \
+                 WHERE attrelid = 'events_view'::regclass AND attname = 'created'"
+            ),
+            Ok(Some("timestamp with time zone".to_owned()))
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Unsupported column type 'uuid' for field 'created'")]
+    fn test_fs_create_view_unsupported_type() {
+        fs_create_view(
+            "users_view",
+            crate::fs_database_root(),
+            "users",
+            JsonB(json!({"foo": "bigint", "created": "uuid"})),
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_materialize_flat() {
+        let fields = json!({"foo": "bigint", "bar": "integer"});
+        assert_eq!(
+            fs_materialize_flat(
+                crate::fs_database_root(),
+                "users",
+                "users_flat",
+                JsonB(fields.to_owned()),
+                false
+            ),
+            5
+        );
+        let query = "SELECT document_id, foo, bar FROM users_flat ORDER BY document_id";
+        assert_eq!(
+            view_rows(query),
+            vec![
+                ("1".to_owned(), Some(0), Some(0)),
+                ("2".to_owned(), Some(2), None),
+                ("3".to_owned(), Some(3), None),
+                ("4".to_owned(), Some(4), None),
+                ("5".to_owned(), Some(5), None),
+            ]
+        );
+
+        // A refresh reloads the rows but keeps the table and its indexes
+        Spi::run(
+            "CREATE INDEX users_flat_foo ON users_flat (foo); \
+             SELECT fs_set(fs_reference('/users/2'), \
+                 fs_map_from_entries(ARRAY['foo', 'bar'], ARRAY[fs_number_from_integer(20), fs_number_from_integer(21)])); \
+             SELECT fs_delete(fs_reference('/users/5'))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            fs_materialize_flat(
+                crate::fs_database_root(),
+                "users",
+                "users_flat",
+                JsonB(fields),
+                true
+            ),
+            4
+        );
+        assert_eq!(
+            view_rows(query),
+            vec![
+                ("1".to_owned(), Some(0), Some(0)),
+                ("2".to_owned(), Some(20), Some(21)),
+                ("3".to_owned(), Some(3), None),
+                ("4".to_owned(), Some(4), None),
+            ]
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT to_regclass('users_flat_foo') IS NOT NULL")
+                .expect("SPI failed"),
+            Some(true)
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_materialize_flat_batches() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             SELECT fs_reference('/items/' || i), \
+                 fs_map_from_entries(ARRAY['n'], ARRAY[fs_number_from_integer(i)]) \
+             FROM generate_series(1, 2500) i",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            fs_materialize_flat(
+                crate::fs_database_root(),
+                "items",
+                "items_flat",
+                JsonB(json!({"n": "bigint"})),
+                false
+            ),
+            2500
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT sum(n)::bigint FROM items_flat").expect("SPI failed"),
+            Some(2500 * 2501 / 2)
+        );
+    }
+
+    fn materialize_unsupported_type() -> i64 {
+        fs_materialize_flat(
+            crate::fs_database_root(),
+            "users",
+            "users_flat",
+            JsonB(json!({"foo": "bigint", "created": "uuid"})),
+            false,
+        )
+    }
+
+    #[pg_test(error = "InvalidValue: Unsupported column type 'uuid' for field 'created'")]
+    fn test_fs_materialize_flat_unsupported_type() {
+        materialize_unsupported_type();
+    }
+
+    #[pg_test]
+    fn test_fs_materialize_flat_checks_spec_first() {
+        let result = std::panic::catch_unwind(materialize_unsupported_type);
+        assert!(result.is_err(), "an unsupported type must fail");
+        // The spec is checked before the table is created
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT to_regclass('users_flat') IS NULL").expect("SPI failed"),
+            Some(true)
+        );
+    }
+}
@@ -3,47 +3,106 @@ use pgrx::prelude::*;
 use pgrx::{InOutFuncs, StringInfo};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::mem;
-use std::{collections::BTreeMap, str::FromStr};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
 
+mod fs_activity;
+mod fs_aggregate;
+mod fs_assert;
+mod fs_binary;
+mod fs_build;
+mod fs_bytes;
+mod fs_cast;
+mod fs_changes;
+mod fs_check;
+#[cfg(any(test, feature = "pg_test"))]
+mod fs_concurrent_test;
+mod fs_diff;
+mod fs_display;
+mod fs_documents;
 mod fs_error;
+mod fs_etag;
+mod fs_field_path;
+mod fs_freeze;
+mod fs_geo;
+mod fs_gin;
+mod fs_guc;
+mod fs_hash;
+mod fs_json_path;
+mod fs_lint;
 mod fs_number;
+mod fs_ordering;
+mod fs_parse;
+mod fs_partial_decode;
+mod fs_patch;
+mod fs_profiling;
+mod fs_query;
 mod fs_reference;
+mod fs_reference_cache;
+mod fs_reference_pattern;
+mod fs_rename;
+mod fs_replication;
+mod fs_rest;
+mod fs_rls;
+mod fs_schema;
+mod fs_search;
+mod fs_sequence;
+mod fs_sort_key;
+#[cfg(any(test, feature = "pg_test"))]
+mod fs_test_util;
+mod fs_timestamp;
+mod fs_unique;
+mod fs_view;
 
-use fs_error::FsError;
-use fs_number::FsNumber;
+use fs_display::{display_json, display_value};
+use fs_error::{report, FsCode, FsError};
+use fs_field_path::{FieldPath, PathSegment};
+use fs_guc::{InputMode, OutputStyle};
+use fs_number::{number_from_double, FsNumber};
+use fs_ordering::{contains_nan, query_cmp};
 use fs_reference::FsPath;
 use fs_reference::FsReference;
+use fs_reference::ResourceId;
 use fs_reference::FS_REFERENCE_ROOT;
+use fs_reference_pattern::ReferencePattern;
 
 type Result<T> = std::result::Result<T, FsError>;
 
 pgrx::pg_module_magic!();
 
-#[derive(
-    Serialize,
-    Deserialize,
-    Eq,
-    PartialEq,
-    PartialOrd,
-    Ord,
-    Debug,
-    Clone,
-    PostgresType,
-    PostgresEq,
-    PostgresOrd,
-)]
+#[allow(non_snake_case)]
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    fs_guc::init();
+    fs_activity::init();
+}
+
+// Ord follows Firestore's ordering, see fs_ordering.rs
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, PostgresType)]
 #[inoutfuncs]
 pub enum FsValue {
     NULL,
     Boolean(bool),
     Number(FsNumber),
-    // TODO(louiskuang): support date type
     Date(pgrx::Date),
+    // Microseconds since the Unix epoch, UTC
+    Timestamp(i64),
     String(String),
-    Bytes(Vec<u8>),
+    // Compared byte by byte as unsigned, stopping at the first difference,
+    // and values of different lengths are never equal
+    Bytes(
+        #[serde(
+            serialize_with = "fs_bytes::serialize_payload",
+            deserialize_with = "fs_bytes::deserialize_payload"
+        )]
+        Vec<u8>,
+    ),
     Reference(FsReference),
-    // TODO(louiskuang): support geo point type
     // f64 does not implement Eq because NaN != NaN
     GeoPoint(FsNumber, FsNumber),
     Array(Vec<FsValue>),
@@ -55,118 +114,210 @@ impl InOutFuncs for FsValue {
     where
         Self: Sized,
     {
-        let value = serde_json::from_str::<Value>(
-            input
-                .to_str()
-                .expect(&format!("Failed to parse cstring as a UTF-8 string")),
+        let input_str = input.to_str().unwrap_or_else(|error| {
+            report(
+                FsCode::InvalidArgument,
+                format!("Failed to parse cstring as a UTF-8 string: {}", error),
+            )
+        });
+        FsValue::parse_text(input_str)
+            .unwrap_or_else(|message| report(FsCode::InvalidArgument, message))
+    }
+
+    fn output(&self, buffer: &mut StringInfo) {
+        buffer.push_str(
+            self.to_styled_json_value(fs_guc::OUTPUT_STYLE.get())
+                .to_string()
+                .as_str(),
         )
-        .expect("Failed to parse cstring as a serde_json object");
-        match FsValue::from(value) {
-            Ok(value) => value,
-            Err(error) => panic!("{}", error),
+    }
+}
+
+// Readable output shows at most this many bytes of a BYTES value
+const READABLE_BYTES_LIMIT: usize = 64;
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    let invalid = || FsError::InvalidValue(format!("Failed to decode '{}' as a hex string", hex));
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+// Nesting allowed in JSON handed to the recursive parsers. serde_json stops
+// one level short of it when parsing text, at 128 open arrays or objects.
+const MAX_JSON_DEPTH: usize = 128;
+
+// Rejects JSON nested deeper than MAX_JSON_DEPTH. The walk keeps its own
+// stack so that an adversarial input cannot overflow the real one.
+pub(crate) fn check_json_depth(value: &Value) -> Result<()> {
+    let mut pending = vec![(value, 1)];
+    while let Some((value, depth)) = pending.pop() {
+        let children: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Array(array) => Box::new(array.iter()),
+            Value::Object(object) => Box::new(object.values()),
+            _ => continue,
+        };
+        if depth > MAX_JSON_DEPTH {
+            return Err(FsError::LimitExceeded(format!(
+                "JSON is nested more than {} levels deep",
+                MAX_JSON_DEPTH
+            )));
         }
+        pending.extend(children.map(|child| (child, depth + 1)));
     }
+    Ok(())
+}
 
-    fn output(&self, buffer: &mut StringInfo) {
-        buffer.push_str(self.to_json_value().to_string().as_str())
+// The "value" of a NUMBER, which names NaN and the infinities as strings
+fn number_json_value(number: &FsNumber) -> Value {
+    match number {
+        FsNumber::NAN => json!("NaN"),
+        FsNumber::PositiveInfinity => json!("Infinity"),
+        FsNumber::NegativeInfinity => json!("-Infinity"),
+        FsNumber::Number(number) => json!(number),
     }
 }
 
 impl FsValue {
     fn to_json_value(&self) -> Value {
+        self.to_styled_json_value(OutputStyle::Canonical)
+    }
+
+    fn to_styled_json_value(&self, style: OutputStyle) -> Value {
         match &self {
             FsValue::NULL => json!({
-                "type": "NULL",
+                "type": self.type_name(),
                 "value": null,
             }),
             FsValue::Boolean(boolean) => json!({
-                "type": "BOOLEAN",
+                "type": self.type_name(),
                 "value": boolean,
             }),
-            FsValue::Number(fs_number) => match fs_number {
-                FsNumber::NAN => json!({
-                    "type": "NUMBER",
-                    "value": "NaN",
-                }),
-                FsNumber::PositiveInfinity => json!({
-                    "type": "NUMBER",
-                    "value": "Infinity",
-                }),
-                FsNumber::NegativeInfinity => json!({
-                    "type": "NUMBER",
-                    "value": "-Infinity",
-                }),
-                FsNumber::Number(number) => json!({
-                    "type": "NUMBER",
-                    "value": number,
-                }),
-            },
+            FsValue::Number(fs_number) => json!({
+                "type": self.type_name(),
+                "value": number_json_value(fs_number),
+            }),
+            FsValue::Date(date) => json!({
+                "type": self.type_name(),
+                "value": fs_timestamp::format_date(date),
+            }),
+            FsValue::Timestamp(micros) => json!({
+                "type": self.type_name(),
+                "value": fs_timestamp::format_timestamp(*micros),
+            }),
             FsValue::String(fs_string) => json!({
-                "type": "STRING",
+                "type": self.type_name(),
                 "value": fs_string,
             }),
             FsValue::Reference(reference) => json!({
-                "type": "REFERENCE",
+                "type": self.type_name(),
                 "value": reference.to_string(),
             }),
-            FsValue::Bytes(fs_bytes) => json!({
-                "type": "BYTES",
-                "value": general_purpose::STANDARD.encode(fs_bytes),
-            }),
+            FsValue::Bytes(fs_bytes) => match style {
+                OutputStyle::Canonical => json!({
+                    "type": self.type_name(),
+                    "value": general_purpose::STANDARD.encode(fs_bytes),
+                }),
+                OutputStyle::Readable if fs_bytes.len() > READABLE_BYTES_LIMIT => json!({
+                    "type": self.type_name(),
+                    "encoding": "hex",
+                    "value": format!("0x{}…", encode_hex(&fs_bytes[..READABLE_BYTES_LIMIT])),
+                    "length": fs_bytes.len(),
+                }),
+                OutputStyle::Readable => json!({
+                    "type": self.type_name(),
+                    "encoding": "hex",
+                    "value": format!("0x{}", encode_hex(fs_bytes)),
+                }),
+            },
             FsValue::Array(fs_value_array) => {
                 let mut value_array = Vec::new();
                 for fs_array_element in fs_value_array.iter() {
-                    value_array.push(fs_array_element.to_json_value());
+                    value_array.push(fs_array_element.to_styled_json_value(style));
                 }
                 json!({
-                    "type": "ARRAY",
+                    "type": self.type_name(),
                     "value": value_array,
                 })
             }
             FsValue::Map(fs_value_map) => {
                 let mut value_map = BTreeMap::new();
                 for (key, value) in fs_value_map.iter() {
-                    value_map.insert(key, value.to_json_value());
+                    value_map.insert(key, value.to_styled_json_value(style));
                 }
                 json!({
-                    "type": "MAP",
+                    "type": self.type_name(),
                     "value": value_map,
                 })
             }
-            _ => panic!("Unsupported FsValue"),
+            FsValue::GeoPoint(latitude, longitude) => json!({
+                "type": self.type_name(),
+                "value": [number_json_value(latitude), number_json_value(longitude)],
+            }),
         }
     }
 
+    // The input function's parser before fs_parse, kept for differential tests
+    #[cfg(any(test, feature = "pg_test"))]
     fn from(json_value: Value) -> Result<FsValue> {
-        let json_value_as_object = json_value
-            .as_object()
-            .expect(&format!("Expecting a JSON object but got {}", json_value));
-        let fs_value_type = json_value_as_object
-            .get("type")
-            .ok_or(FsError::InvalidValue(format!(
+        check_json_depth(&json_value)?;
+        FsValue::from_typed_json(json_value)
+    }
+
+    fn from_typed_json(mut json_value: Value) -> Result<FsValue> {
+        // Messages are built lazily: formatting every nested value up front
+        // would serialize large documents over and over
+        let json_value_as_object = json_value.as_object().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting a JSON object but got {}",
+                display_json(&json_value)
+            ))
+        })?;
+        let fs_value_type = json_value_as_object.get("type").ok_or_else(|| {
+            FsError::InvalidValue(format!(
                 "Expecting field 'type' in object. Found: {}",
-                json_value.to_string()
-            )))?;
-        let fs_value_type_string = fs_value_type.as_str().expect(&format!(
-            "Expecting string value for field 'type' but found {}",
-            fs_value_type
-        ));
-        let fs_value = json_value_as_object
-            .get("value")
-            .ok_or(FsError::InvalidValue(format!(
+                display_json(&json_value)
+            ))
+        })?;
+        let fs_value_type_string = fs_value_type.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Expecting string value for field 'type' but found {}",
+                display_json(fs_value_type)
+            ))
+        })?;
+        let fs_value = json_value_as_object.get("value").ok_or_else(|| {
+            FsError::InvalidValue(format!(
                 "Expecting field 'value' in object. Found: {}",
-                json_value.to_string()
-            )))?;
+                display_json(&json_value)
+            ))
+        })?;
 
         match fs_value_type_string {
             "NULL" => FsValue::from_null_value(&fs_value),
             "BOOLEAN" => FsValue::from_boolean_value(&fs_value),
             "NUMBER" => FsValue::from_number_value(&fs_value),
+            "DATE" => FsValue::from_date_value(&fs_value),
+            "GEOPOINT" => FsValue::from_geo_point_value(&fs_value),
+            "TIMESTAMP" => FsValue::from_timestamp_value(&fs_value),
             "STRING" => FsValue::from_string_value(&fs_value),
             "REFERENCE" => FsValue::from_reference_value(&fs_value),
-            "BYTES" => FsValue::from_bytes_value(&fs_value),
-            "ARRAY" => FsValue::from_array_value(&fs_value),
-            "MAP" => FsValue::from_map_value(&fs_value),
+            "BYTES" => match json_value_as_object.get("encoding") {
+                Some(encoding) => FsValue::from_hex_bytes_value(&json_value, encoding, &fs_value),
+                None => FsValue::from_bytes_value(&fs_value),
+            },
+            // Arrays and maps move their elements out of `json_value` rather
+            // than copying them, which would copy a large document once for
+            // every level it is nested
+            "ARRAY" => FsValue::from_array_value(json_value["value"].take()),
+            "MAP" => FsValue::from_map_value(json_value["value"].take()),
             _ => Err(FsError::InvalidType(format!(
                 "Firestore does not support value of type '{}'",
                 fs_value_type_string
@@ -174,91 +325,395 @@ impl FsValue {
         }
     }
 
+    // The value the input function reads `text` as, or the message it fails
+    // with
+    pub(crate) fn parse_text(text: &str) -> std::result::Result<FsValue, String> {
+        match FsValue::from_bare_reference(text)? {
+            Some(reference) => Ok(reference),
+            None => fs_parse::parse_typed_json(text),
+        }
+    }
+
+    // Shorthand input: a bare path such as '/users/1' is read as a REFERENCE
+    // when pgfirestore.input_mode is 'auto'.
+    fn from_bare_reference(input: &str) -> std::result::Result<Option<FsValue>, String> {
+        let trimmed = input.trim();
+        if !trimmed.starts_with('/') {
+            return Ok(None);
+        }
+        match fs_guc::INPUT_MODE.get() {
+            InputMode::Auto => FsReference::from_str(trimmed)
+                .map(|reference| Some(FsValue::Reference(reference)))
+                .map_err(|error| error.to_string()),
+            InputMode::Strict => Err(format!(
+                "Bare reference input '{}' requires pgfirestore.input_mode = 'auto'",
+                trimmed
+            )),
+        }
+    }
+
     fn from_null_value(value: &Value) -> Result<FsValue> {
         if value.eq(&Value::Null) {
             Ok(FsValue::NULL)
         } else {
             Err(FsError::InvalidValue(format!(
                 "Failed to parse {} as a null fsvalue",
-                value
+                display_json(value)
             )))
         }
     }
 
     fn from_boolean_value(value: &Value) -> Result<FsValue> {
-        let boolean_value = value.as_bool().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as a boolean fsvalue",
-            value
-        )))?;
+        let boolean_value = value.as_bool().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a boolean fsvalue",
+                display_json(value)
+            ))
+        })?;
         Ok(FsValue::Boolean(boolean_value))
     }
 
+    // A JSON number, or a string as output for NaN and the infinities
     fn from_number_value(value: &Value) -> Result<FsValue> {
         match value {
             serde_json::Value::Number(number) => {
                 Ok(FsValue::Number(FsNumber::from(number.clone())))
             }
+            serde_json::Value::String(text) => FsNumber::from_str(text).map(FsValue::Number),
             _ => Err(FsError::InvalidValue(format!(
-                "Expecting a JSON number but found {}",
-                value
+                "Expecting a JSON number or a string but found {}",
+                display_json(value)
+            ))),
+        }
+    }
+
+    // An ISO 8601 date such as "2024-01-31", or a number of days since
+    // 1970-01-01
+    fn from_date_value(value: &Value) -> Result<FsValue> {
+        let date = match value {
+            Value::String(text) => fs_timestamp::parse_date(text),
+            Value::Number(number) => match number.as_i64() {
+                Some(days) => fs_timestamp::date_from_unix_days(days),
+                None => Err(FsError::InvalidValue(format!(
+                    "Expecting a whole number of days but found {}",
+                    number
+                ))),
+            },
+            _ => Err(FsError::InvalidValue(format!(
+                "Failed to parse {} as a date fsvalue",
+                display_json(value)
+            ))),
+        };
+        date.map(FsValue::Date)
+    }
+
+    // A [latitude, longitude] pair of numbers
+    fn from_geo_point_value(value: &Value) -> Result<FsValue> {
+        match value.as_array().map(Vec::as_slice) {
+            Some([Value::Number(latitude), Value::Number(longitude)]) => fs_geo::geo_point_value(
+                latitude.as_f64().unwrap_or(f64::NAN),
+                longitude.as_f64().unwrap_or(f64::NAN),
+            ),
+            _ => Err(FsError::InvalidValue(format!(
+                "Failed to parse {} as a [latitude, longitude] geo point fsvalue",
+                display_json(value)
             ))),
         }
     }
 
+    fn from_timestamp_value(value: &Value) -> Result<FsValue> {
+        let text = value.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a timestamp fsvalue",
+                display_json(value)
+            ))
+        })?;
+        fs_timestamp::parse_timestamp(text).map(FsValue::Timestamp)
+    }
+
     fn from_string_value(value: &Value) -> Result<FsValue> {
-        let string_value = value.as_str().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as a string",
-            value
-        )))?;
+        let string_value = value.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a string",
+                display_json(value)
+            ))
+        })?;
         Ok(FsValue::String(string_value.to_owned()))
     }
 
     fn from_reference_value(value: &Value) -> Result<FsValue> {
-        let string_value = value.as_str().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as a string",
-            value
-        )))?;
+        let string_value = value.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a string",
+                display_json(value)
+            ))
+        })?;
         FsReference::from_str(string_value).map(|reference| FsValue::Reference(reference))
     }
 
     fn from_bytes_value(value: &Value) -> Result<FsValue> {
-        let string_value = value.as_str().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as a string",
-            value
-        )))?;
+        let string_value = value.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a string",
+                display_json(value)
+            ))
+        })?;
         general_purpose::STANDARD
             .decode(string_value)
-            .map(|bytes| FsValue::Bytes(bytes))
             .map_err(|err| {
                 FsError::InvalidValue(format!(
                     "Failed to decode value as a base64 byte string: {}",
                     err
                 ))
             })
+            .and_then(fs_bytes::bytes_value)
     }
 
-    fn from_array_value(value: &Value) -> Result<FsValue> {
-        let array_value = value.as_array().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as an array fsvalue",
-            value
-        )))?;
-        let mut fs_array_value = Vec::new();
-        for array_element in array_value.iter() {
-            fs_array_value.push(FsValue::from(array_element.to_owned())?);
+    // Parses the BYTES rendering of the readable output style. Truncated
+    // values cannot be parsed back.
+    fn from_hex_bytes_value(
+        json_value: &Value,
+        encoding: &Value,
+        value: &Value,
+    ) -> Result<FsValue> {
+        if encoding.as_str() != Some("hex") {
+            return Err(FsError::InvalidValue(format!(
+                "Unsupported bytes encoding {}",
+                display_json(encoding)
+            )));
+        }
+        if json_value.get("length").is_some() {
+            return Err(FsError::InvalidValue(format!(
+                "Cannot parse truncated bytes {}",
+                display_json(json_value)
+            )));
         }
+        let string_value = value.as_str().ok_or_else(|| {
+            FsError::InvalidValue(format!(
+                "Failed to parse {} as a string",
+                display_json(value)
+            ))
+        })?;
+        let hex = string_value
+            .strip_prefix("0x")
+            .ok_or(FsError::InvalidValue(format!(
+                "Expecting a 0x-prefixed hex string but found {}",
+                display_json(value)
+            )))?;
+        decode_hex(hex).and_then(fs_bytes::bytes_value)
+    }
+
+    fn from_array_value(value: Value) -> Result<FsValue> {
+        let array_value = match value {
+            Value::Array(array_value) => array_value,
+            value => {
+                return Err(FsError::InvalidValue(format!(
+                    "Failed to parse {} as an array fsvalue",
+                    display_json(&value)
+                )))
+            }
+        };
+        let mut fs_array_value = Vec::with_capacity(array_value.len());
+        for array_element in array_value {
+            fs_array_value.push(FsValue::from_typed_json(array_element)?);
+        }
+        FsValue::check_array_nesting(&fs_array_value)?;
         Ok(FsValue::Array(fs_array_value))
     }
 
-    fn from_map_value(value: &Value) -> Result<FsValue> {
-        let map_value = value.as_object().ok_or(FsError::InvalidValue(format!(
-            "Failed to parse {} as a map fsvalue",
-            value
-        )))?;
-        let mut fs_map_value = BTreeMap::new();
-        for (key, value) in map_value.iter() {
-            fs_map_value.insert(key.to_owned(), FsValue::from(value.to_owned())?);
+    // Firestore does not allow an array to directly contain another array (an
+    // array inside a map inside an array is fine). Only enforced when
+    // pgfirestore.strict_limits is on.
+    fn check_array_nesting(elements: &[FsValue]) -> Result<()> {
+        if !fs_guc::STRICT_LIMITS.get() {
+            return Ok(());
+        }
+        match elements
+            .iter()
+            .position(|element| matches!(element, FsValue::Array(_)))
+        {
+            Some(index) => Err(FsError::InvalidValue(format!(
+                "Array element at index {} is an array; Firestore does not support directly nested arrays",
+                index
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // A BTreeMap cannot be sized up front, but one collected from the keys in
+    // order, as serde_json's map yields them, is built in bulk rather than
+    // one insertion at a time
+    fn from_map_value(value: Value) -> Result<FsValue> {
+        let map_value = match value {
+            Value::Object(map_value) => map_value,
+            value => {
+                return Err(FsError::InvalidValue(format!(
+                    "Failed to parse {} as a map fsvalue",
+                    display_json(&value)
+                )))
+            }
+        };
+        map_value
+            .into_iter()
+            .map(|(key, value)| Ok((key, FsValue::from_typed_json(value)?)))
+            .collect::<Result<BTreeMap<_, _>>>()
+            .map(FsValue::Map)
+    }
+
+    // Removes map entries holding FsValue::NULL, recursing into nested maps and
+    // into maps inside arrays. NULL array elements are kept so that indexes do
+    // not shift. With `prune_empty_maps`, map entries whose value is a map that
+    // is empty after stripping are removed as well.
+    fn strip_nulls(self, prune_empty_maps: bool) -> FsValue {
+        match self {
+            FsValue::Map(map) => FsValue::Map(
+                map.into_iter()
+                    .filter(|(_, value)| value.ne(&FsValue::NULL))
+                    .map(|(key, value)| (key, value.strip_nulls(prune_empty_maps)))
+                    .filter(|(_, value)| {
+                        !(prune_empty_maps && value.as_map().is_some_and(|map| map.is_empty()))
+                    })
+                    .collect(),
+            ),
+            FsValue::Array(array) => FsValue::Array(
+                array
+                    .into_iter()
+                    .map(|element| element.strip_nulls(prune_empty_maps))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    // The value at the nested map location named by `field_names`, if any
+    fn get_field(&self, field_names: &[String]) -> Option<&FsValue> {
+        field_names
+            .iter()
+            .try_fold(self, |value, name| value.as_map()?.get(name))
+    }
+
+    // Like get_field, except that a name of digits also indexes into an array,
+    // e.g. `tags.0`. Firestore field paths never do, so queries, indexes and
+    // constraints stay with get_field.
+    fn get_path(&self, field_names: &[String]) -> Option<&FsValue> {
+        field_names
+            .iter()
+            .try_fold(self, |value, name| match value {
+                FsValue::Array(array) => array.get(array_index(name)?),
+                _ => value.as_map()?.get(name),
+            })
+    }
+
+    // Sets the value at `field_names`, creating (or replacing non-map values
+    // with) intermediate maps along the way.
+    fn set_field(&mut self, field_names: &[String], value: FsValue) {
+        let (name, rest) = match field_names.split_first() {
+            Some(split) => split,
+            None => {
+                *self = value;
+                return;
+            }
+        };
+        if !matches!(self, FsValue::Map(_)) {
+            *self = FsValue::Map(BTreeMap::new());
+        }
+        if let FsValue::Map(map) = self {
+            map.entry(name.to_owned())
+                .or_insert_with(|| FsValue::Map(BTreeMap::new()))
+                .set_field(rest, value);
+        }
+    }
+
+    fn remove_field(&mut self, field_names: &[String]) -> Option<FsValue> {
+        let (last, parents) = field_names.split_last()?;
+        let mut value = self;
+        for name in parents {
+            value = match value {
+                FsValue::Map(map) => map.get_mut(name)?,
+                _ => return None,
+            };
+        }
+        match value {
+            FsValue::Map(map) => map.remove(last),
+            _ => None,
+        }
+    }
+
+    // Types plain JSON by its shape: objects become maps, arrays arrays, and
+    // scalars the matching null, boolean, number or string. Like fs_array,
+    // directly nested arrays are an error under pgfirestore.strict_limits.
+    fn from_plain_json(value: &Value) -> FsValue {
+        match value {
+            Value::Null => FsValue::NULL,
+            Value::Bool(boolean) => FsValue::Boolean(*boolean),
+            Value::Number(number) => FsValue::Number(FsNumber::from(number.to_owned())),
+            Value::String(string) => FsValue::String(string.to_owned()),
+            Value::Array(array) => {
+                checked_array(array.iter().map(FsValue::from_plain_json).collect())
+            }
+            Value::Object(object) => FsValue::Map(
+                object
+                    .iter()
+                    .map(|(key, value)| (key.to_owned(), FsValue::from_plain_json(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    // The values without their type tags. Types JSON has no notion of come
+    // out as strings (bytes in base64, references as paths, dates and
+    // timestamps in ISO 8601, NaN and infinities by name) and geo points as
+    // [latitude, longitude] arrays like in the text format, so
+    // from_plain_json does not restore them.
+    fn to_plain_json(&self) -> Value {
+        match self {
+            FsValue::NULL => Value::Null,
+            FsValue::Boolean(boolean) => json!(boolean),
+            FsValue::Number(FsNumber::NAN) => json!("NaN"),
+            FsValue::Number(FsNumber::PositiveInfinity) => json!("Infinity"),
+            FsValue::Number(FsNumber::NegativeInfinity) => json!("-Infinity"),
+            FsValue::Number(FsNumber::Number(number)) => json!(number),
+            FsValue::Date(date) => json!(fs_timestamp::format_date(date)),
+            FsValue::Timestamp(micros) => json!(fs_timestamp::format_timestamp(*micros)),
+            FsValue::String(string) => json!(string),
+            FsValue::Bytes(bytes) => json!(general_purpose::STANDARD.encode(bytes)),
+            FsValue::Reference(reference) => json!(reference.to_string()),
+            FsValue::GeoPoint(latitude, longitude) => json!([
+                FsValue::Number(latitude.to_owned()).to_plain_json(),
+                FsValue::Number(longitude.to_owned()).to_plain_json(),
+            ]),
+            FsValue::Array(array) => {
+                Value::Array(array.iter().map(FsValue::to_plain_json).collect())
+            }
+            FsValue::Map(map) => Value::Object(
+                map.iter()
+                    .map(|(key, value)| (key.to_owned(), value.to_plain_json()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // The JSON text format, which is deterministic since map keys are ordered
+    fn canonical_text(&self) -> String {
+        self.to_json_value().to_string()
+    }
+
+    // The tag of the value's type, which the `type` field of the JSON text
+    // format and fs_typeof both take from here
+    fn type_name(&self) -> &'static str {
+        match self {
+            FsValue::NULL => "NULL",
+            FsValue::Boolean(_) => "BOOLEAN",
+            FsValue::Number(_) => "NUMBER",
+            FsValue::Date(_) => "DATE",
+            FsValue::Timestamp(_) => "TIMESTAMP",
+            FsValue::String(_) => "STRING",
+            FsValue::Bytes(_) => "BYTES",
+            FsValue::Reference(_) => "REFERENCE",
+            FsValue::GeoPoint(_, _) => "GEOPOINT",
+            FsValue::Array(_) => "ARRAY",
+            FsValue::Map(_) => "MAP",
         }
-        Ok(FsValue::Map(fs_map_value))
     }
 
     fn as_reference(&self) -> Option<&FsReference> {
@@ -268,6 +723,16 @@ impl FsValue {
         }
     }
 
+    pub(crate) fn expect_reference(&self) -> &FsReference {
+        self.as_reference().unwrap_or_else(|| {
+            FsError::InvalidType(format!(
+                "Expecting a reference but found {}",
+                display_value(self)
+            ))
+            .report()
+        })
+    }
+
     fn as_array(&self) -> Option<&Vec<FsValue>> {
         match &self {
             FsValue::Array(value) => Some(value),
@@ -283,109 +748,358 @@ impl FsValue {
     }
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_null() -> FsValue {
     FsValue::NULL
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_nan() -> FsValue {
     FsValue::Number(FsNumber::NAN)
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_boolean(value: bool) -> FsValue {
     FsValue::Boolean(value)
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_number_from_integer(value: i32) -> FsValue {
     FsValue::Number(FsNumber::Number(serde_json::Number::from(value)))
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
+fn fs_number_from_bigint(value: i64) -> FsValue {
+    FsValue::Number(FsNumber::Number(serde_json::Number::from(value)))
+}
+
+// NaN and the infinities become the matching numbers, and -0.0 keeps its
+// sign, which its text shows, while it stays equal to 0.0
+#[pg_extern(immutable, parallel_safe)]
 fn fs_number_from_double(value: f64) -> FsValue {
-    FsValue::Number(FsNumber::Number(
-        serde_json::Number::from_f64(value)
-            .expect(&format!("Failed to parse {} as a json number", value)),
-    ))
+    FsValue::Number(number_from_double(value))
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_number_from_str(cstr: &core::ffi::CStr) -> FsValue {
     match cstr.to_str() {
         Ok(str) => match FsNumber::from_str(str) {
             Ok(number) => FsValue::Number(number),
-            Err(error) => panic!("{}", error),
+            Err(error) => error.report(),
         },
-        Err(error) => panic!("Failed to parse cstring as a UTF-8 string: {}", error),
+        Err(error) => FsError::InvalidValue(format!(
+            "Failed to parse cstring as a UTF-8 string: {}",
+            error
+        ))
+        .report(),
     }
 }
 
-#[pg_extern]
+// Whether a number is stored as an integer rather than a double. Integers and
+// doubles of the same value still compare equal in the ordering.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_number_is_integer(value: FsValue) -> bool {
+    expect_number(value).is_integer()
+}
+
+fn expect_number(value: FsValue) -> FsNumber {
+    match value {
+        FsValue::Number(number) => number,
+        other => FsError::InvalidType(format!(
+            "Expecting a number but found {}",
+            display_value(&other)
+        ))
+        .report(),
+    }
+}
+
+// Unlike fs_as_double, other types are an error. Integers beyond 2^53 round
+// to the nearest double.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_number_to_double(value: FsValue) -> f64 {
+    expect_number(value).as_double()
+}
+
+// Unlike fs_as_bigint, doubles, even of integral value, and other types are
+// an error, as are integers beyond the range of bigint
+#[pg_extern(immutable, parallel_safe)]
+fn fs_number_to_bigint(value: FsValue) -> i64 {
+    match expect_number(value) {
+        FsNumber::Number(number) if number.is_i64() => {
+            number.as_i64().expect("an i64 number converts to i64")
+        }
+        FsNumber::Number(number) if number.is_u64() => FsError::InvalidValue(format!(
+            "Integer {} is out of range for type bigint",
+            number
+        ))
+        .report(),
+        number => FsError::InvalidType(format!(
+            "Expecting an integer but found {}",
+            display_value(&FsValue::Number(number))
+        ))
+        .report(),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
 fn fs_string(string: &str) -> FsValue {
     FsValue::String(string.to_owned())
 }
 
-#[pg_extern]
+// Stable rather than immutable, as its size and depth limits are settings
+#[pg_extern(stable, parallel_safe)]
 fn fs_reference(string: &str) -> FsValue {
-    FsValue::Reference(
-        FsReference::from_str(string).expect("Failed to parse string as a reference"),
-    )
+    FsValue::Reference(FsReference::from_str(string).unwrap_or_else(|error| error.report()))
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_text(reference: FsValue) -> String {
+    let fs_ref = reference.expect_reference();
+    fs_ref.to_string()
+}
+
+fn extract_reference_pattern(
+    reference: &FsValue,
+    pattern: &str,
+) -> Option<BTreeMap<String, String>> {
+    let pattern = match ReferencePattern::from_str(pattern) {
+        Ok(pattern) => pattern,
+        Err(error) => error.report(),
+    };
+    let fs_ref = reference.expect_reference();
+    pattern.extract(fs_ref)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_matches(reference: FsValue, pattern: &str) -> bool {
+    extract_reference_pattern(&reference, pattern).is_some()
+}
+
+// Case-insensitive matching for queries. References themselves, and so the
+// fs_documents primary key, stay case-sensitive like Firestore IDs.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_equal_fold(lhs: FsValue, rhs: FsValue) -> bool {
+    lhs.expect_reference()
+        .eq_ignore_ascii_case(rhs.expect_reference())
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_extract(reference: FsValue, pattern: &str) -> Option<pgrx::JsonB> {
+    extract_reference_pattern(&reference, pattern).map(|bindings| pgrx::JsonB(json!(bindings)))
+}
+
+// Half-open bounds of the descendants of `ancestor`: R descends from it iff
+// lower < R AND R < upper, where a NULL upper (for the root) is unbounded.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_reference_range(
+    ancestor: FsValue,
+) -> TableIterator<'static, (name!(lower, FsValue), name!(upper, Option<FsValue>))> {
+    let fs_ref = ancestor.expect_reference();
+    let (lower, upper) = fs_ref.descendant_range();
+    TableIterator::new(vec![(FsValue::Reference(lower), upper.map(FsValue::Reference))].into_iter())
+}
+
+// Whether `descendant` is below `ancestor`, which is not its own ancestor
+#[pg_operator(immutable, parallel_safe)]
+#[opname(@>)]
+fn fs_is_ancestor(ancestor: FsValue, descendant: FsValue) -> bool {
+    ancestor
+        .expect_reference()
+        .is_ancestor_of(descendant.expect_reference())
+}
+
+// The smallest reference after `reference` and its descendants, NULL for the
+// root
+#[pg_extern(immutable, parallel_safe)]
+fn fs_prefix_successor(reference: FsValue) -> Option<FsValue> {
+    reference
+        .expect_reference()
+        .prefix_successor()
+        .map(FsValue::Reference)
+}
+
+// Stable rather than immutable, as its size limit depends on
+// pgfirestore.strict_limits
+#[pg_extern(stable, parallel_safe)]
 fn fs_bytes(bytes: Vec<u8>) -> FsValue {
-    FsValue::Bytes(bytes)
+    fs_bytes::bytes_value(bytes).unwrap_or_else(|error| error.report())
 }
 
-#[pg_extern]
+#[pg_extern(stable, parallel_safe)]
 fn fs_array(array: Vec<FsValue>) -> FsValue {
-    FsValue::Array(array)
+    checked_array(array)
 }
 
-#[pg_extern]
+// An array built in SQL, reporting directly nested arrays under
+// pgfirestore.strict_limits like the text input does
+fn checked_array(elements: Vec<FsValue>) -> FsValue {
+    if let Err(error) = FsValue::check_array_nesting(&elements) {
+        error.report()
+    }
+    FsValue::Array(elements)
+}
+
+#[pg_extern(immutable, parallel_safe)]
 fn fs_from_int8(value: i64) -> FsValue {
     let number = serde_json::Number::from(value);
     FsValue::Number(FsNumber::Number(number))
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_from_int4(value: i32) -> FsValue {
     let number = serde_json::Number::from(value);
     FsValue::Number(FsNumber::Number(number))
 }
 
-#[pg_extern]
-fn fs_array_contains(fs_array: FsValue, target: FsValue) -> bool {
-    let array = fs_array
-        .as_array()
-        .expect("Expecting an array fsvalue for array_contains operator.");
-    array.iter().any(|val| fs_ref_eq(&val, &target))
+fn expect_array_error(value: &FsValue) -> ! {
+    FsError::InvalidType(format!(
+        "Expecting an array but found {}",
+        display_value(value)
+    ))
+    .report()
 }
 
-#[pg_extern]
-fn fs_array_contains_any(fs_array: FsValue, targets: Vec<FsValue>) -> bool {
-    let array = fs_array
+fn expect_array(value: FsValue) -> Vec<FsValue> {
+    match value {
+        FsValue::Array(elements) => elements,
+        other => expect_array_error(&other),
+    }
+}
+
+// Chunks are directly nested arrays, which Firestore does not support, so
+// they are refused when pgfirestore.strict_limits is on. That makes the
+// function stable rather than immutable.
+#[pg_extern(stable, parallel_safe)]
+fn fs_array_chunk(fs_array: FsValue, size: i32) -> FsValue {
+    if size <= 0 {
+        FsError::InvalidValue(format!("Chunk size must be positive but found {}", size)).report()
+    }
+    let mut elements = expect_array(fs_array).into_iter().peekable();
+    let mut chunks = Vec::new();
+    while elements.peek().is_some() {
+        chunks.push(FsValue::Array(
+            elements.by_ref().take(size as usize).collect(),
+        ));
+    }
+    if let Err(error) = FsValue::check_array_nesting(&chunks) {
+        error.with_context("Cannot chunk an array").report()
+    }
+    FsValue::Array(chunks)
+}
+
+fn flatten_into(elements: Vec<FsValue>, depth: i32, flattened: &mut Vec<FsValue>) {
+    for element in elements {
+        match element {
+            FsValue::Array(inner) if depth > 0 => flatten_into(inner, depth - 1, flattened),
+            element => flattened.push(element),
+        }
+    }
+}
+
+// Replaces array elements by their own elements, `depth` levels down
+#[pg_extern(immutable, parallel_safe)]
+fn fs_array_flatten(fs_array: FsValue, depth: default!(i32, 1)) -> FsValue {
+    if depth < 0 {
+        FsError::InvalidValue(format!("Depth must not be negative but found {}", depth)).report()
+    }
+    let mut flattened = Vec::new();
+    flatten_into(expect_array(fs_array), depth, &mut flattened);
+    FsValue::Array(flattened)
+}
+
+// Array transforms judge membership like Firestore, by value: 1 and 1.0 are
+// the same element, and maps and arrays are compared element by element
+fn contains_value(elements: &[FsValue], value: &FsValue) -> bool {
+    elements.iter().any(|element| element.cmp(value).is_eq())
+}
+
+// The elements of an array being transformed. Like FieldValue.arrayUnion()
+// on a field that is not an array, anything else counts as an empty array.
+fn transform_base(base: FsValue) -> Vec<FsValue> {
+    match base {
+        FsValue::Array(elements) => elements,
+        _ => Vec::new(),
+    }
+}
+
+// Appends the additions missing from `base`, in order, like
+// FieldValue.arrayUnion(). Stable rather than immutable, as a nested array in
+// the result depends on pgfirestore.strict_limits.
+#[pg_extern(stable, parallel_safe)]
+fn fs_array_union(base: FsValue, additions: FsValue) -> FsValue {
+    let mut elements = transform_base(base);
+    for addition in expect_array(additions) {
+        if !contains_value(&elements, &addition) {
+            elements.push(addition);
+        }
+    }
+    checked_array(elements)
+}
+
+// Removes every occurrence of the removals from `base`, like
+// FieldValue.arrayRemove()
+#[pg_extern(immutable, parallel_safe)]
+fn fs_array_remove(base: FsValue, removals: FsValue) -> FsValue {
+    let removals = expect_array(removals);
+    let mut elements = transform_base(base);
+    elements.retain(|element| !contains_value(&removals, element));
+    FsValue::Array(elements)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_array_length(value: FsValue) -> i32 {
+    value
         .as_array()
-        .expect("Expecting an array fsvalue for array_contains operator.");
+        .unwrap_or_else(|| expect_array_error(&value))
+        .len() as i32
+}
 
-    for val in array.iter() {
-        for key in targets.iter() {
-            if fs_ref_eq(val, key) {
-                return true;
-            }
+// Stable rather than immutable, like fs_array_union
+#[pg_extern(stable, parallel_safe)]
+fn fs_array_concat(lhs: FsValue, rhs: FsValue) -> FsValue {
+    let mut elements = expect_array(lhs);
+    elements.extend(expect_array(rhs));
+    checked_array(elements)
+}
+
+// Why `fs_ref` cannot be the reference of a document, if it cannot
+pub(crate) fn document_key_error(fs_ref: &FsValue) -> Option<String> {
+    let reference = match fs_ref.as_reference() {
+        Some(reference) => reference,
+        None => {
+            return Some(format!(
+                "Document key must be a REFERENCE but found {}",
+                fs_ref.type_name()
+            ))
         }
+    };
+    if reference.is_root() {
+        return Some("Document key must not be the database root".to_owned());
+    }
+    if !reference.has_complete_path() {
+        return Some(format!(
+            "Document key {} is missing a document ID after collection '{}'",
+            reference,
+            reference.collection_id()
+        ));
     }
-    return false;
+    None
 }
 
 #[pg_extern]
 fn fs_is_valid_document_key(fs_ref: FsValue) -> bool {
-    fs_ref
-        .as_reference()
-        .map(|reference| !reference.is_root() && reference.has_complete_path())
-        .unwrap_or(false)
+    document_key_error(&fs_ref).is_none()
+}
+
+// Like fs_is_valid_document_key, but raises an error saying what is wrong
+// with an invalid key so that the CHECK constraint on fs_documents does not
+// fail with a bare constraint violation
+#[pg_extern]
+fn fs_validate_document_key(fs_ref: FsValue) -> bool {
+    if let Some(error) = document_key_error(&fs_ref) {
+        report(FsCode::InvalidArgument, error)
+    }
+    true
 }
 
 #[pg_extern]
@@ -396,35 +1110,80 @@ fn fs_is_valid_document_properties(properties: FsValue) -> bool {
     }
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_database_root() -> FsValue {
     FsValue::Reference(FS_REFERENCE_ROOT)
 }
 
-#[pg_extern]
-fn fs_parent(reference: FsValue) -> FsValue {
-    let fs_ref = reference
-        .as_reference()
-        .expect("expecting a reference type");
-    FsValue::Reference(fs_ref.parent())
+// fs_parent and fs_collection_id are defined in fs_reference_cache
+
+// The last path segment of a document reference, e.g. `1` for `/users/1`
+#[pg_extern(immutable, parallel_safe)]
+fn fs_document_id(reference: FsValue) -> String {
+    let fs_ref = reference.expect_reference();
+    match fs_ref.last_resource_id() {
+        Ok(resource_id) => resource_id.to_string(),
+        Err(error) => error.report(),
+    }
 }
 
-#[pg_extern]
-fn fs_collection_id(reference: FsValue) -> String {
-    let fs_ref = reference
+// The document `resource_id` in the collection `collection_id` of `parent`
+// Stable rather than immutable, as the reference limits are settings
+#[pg_extern(stable, parallel_safe)]
+fn fs_child(parent: FsValue, collection_id: &str, resource_id: &str) -> FsValue {
+    let fs_ref = parent.expect_reference();
+    match fs_ref.push_element(collection_id, Some(resource_id)) {
+        Ok(child) => FsValue::Reference(child),
+        Err(error) => error.report(),
+    }
+}
+
+// The collection `collection_id` of `parent`
+// Stable rather than immutable, as the reference limits are settings
+#[pg_extern(stable, parallel_safe)]
+fn fs_collection_ref(parent: FsValue, collection_id: &str) -> FsValue {
+    let fs_ref = parent.expect_reference();
+    match fs_ref.push_element(collection_id, None) {
+        Ok(collection) => FsValue::Reference(collection),
+        Err(error) => error.report(),
+    }
+}
+
+fn expect_resource_id(reference: &FsValue) -> &ResourceId {
+    reference
         .as_reference()
-        .expect("expecting a reference type");
-    fs_ref.collection_id().to_string()
+        .and_then(FsReference::resource_id)
+        .unwrap_or_else(|| {
+            FsError::InvalidType(format!(
+                "Expecting a document reference but found {}",
+                display_value(reference)
+            ))
+            .report()
+        })
 }
 
-#[pg_extern]
+// Whether the document ID has the shape of a Firestore auto ID
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_auto_id(reference: FsValue) -> bool {
+    expect_resource_id(&reference).is_auto_id()
+}
+
+// One of 'numeric', 'auto' or 'custom'
+#[pg_extern(immutable, parallel_safe)]
+fn fs_resource_id_kind(reference: FsValue) -> String {
+    expect_resource_id(&reference).kind().to_string()
+}
+
+#[pg_extern(immutable, parallel_safe)]
 fn fs_map_from_entries(keys: Vec<String>, values: Vec<FsValue>) -> FsValue {
-    assert!(
-        keys.len() == values.len(),
-        "Keys size ({}) does not match values size ({})",
-        keys.len(),
-        values.len()
-    );
+    if keys.len() != values.len() {
+        FsError::InvalidValue(format!(
+            "Keys size ({}) does not match values size ({})",
+            keys.len(),
+            values.len()
+        ))
+        .report()
+    }
     let mut map = BTreeMap::new();
     for (key, value) in keys.into_iter().zip(values.into_iter()) {
         map.insert(key, value);
@@ -432,97 +1191,823 @@ fn fs_map_from_entries(keys: Vec<String>, values: Vec<FsValue>) -> FsValue {
     FsValue::Map(map)
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(->)]
-fn fs_map_get(fs_map: FsValue, field_name: &str) -> Option<FsValue> {
-    fs_map
-        .as_map()
-        .and_then(|map| map.get(field_name).map(|value| value.to_owned()))
+// Like fs_map_from_entries, but a repeated key is an error rather than the
+// last value winning
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map(keys: Vec<String>, values: Vec<FsValue>) -> FsValue {
+    if keys.len() != values.len() {
+        FsError::InvalidValue(format!(
+            "fs_map expects as many keys as values but found {} keys and {} values",
+            keys.len(),
+            values.len()
+        ))
+        .report()
+    }
+    let mut map = BTreeMap::new();
+    for (key, value) in keys.into_iter().zip(values) {
+        if map.contains_key(&key) {
+            FsError::InvalidValue(format!("fs_map repeats the key '{}'", key)).report()
+        }
+        map.insert(key, value);
+    }
+    FsValue::Map(map)
 }
 
-fn is_same_type(lhs: &FsValue, rhs: &FsValue) -> bool {
-    mem::discriminant(lhs) == mem::discriminant(rhs)
+#[pg_extern(immutable, parallel_safe)]
+fn fs_empty_map() -> FsValue {
+    FsValue::Map(BTreeMap::new())
 }
 
-fn not_null_nor_nan(val: &FsValue) -> bool {
-    val.ne(&FsValue::NULL) && val.ne(&FsValue::Number(FsNumber::NAN))
+fn expect_map_arg(value: FsValue, function: &str) -> BTreeMap<String, FsValue> {
+    match value {
+        FsValue::Map(map) => map,
+        other => FsError::InvalidType(format!(
+            "{} expects a map but found {}",
+            function,
+            display_value(&other)
+        ))
+        .report(),
+    }
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(#<)]
-fn fs_lt(lhs: FsValue, rhs: FsValue) -> bool {
-    not_null_nor_nan(&rhs) && is_same_type(&lhs, &rhs) && lhs.lt(&rhs)
+// A copy of the map with `key` set to `value`, replacing any previous value
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_set(map: FsValue, key: String, value: FsValue) -> FsValue {
+    let mut map = expect_map_arg(map, "fs_map_set");
+    map.insert(key, value);
+    FsValue::Map(map)
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(#>)]
-fn fs_gt(lhs: FsValue, rhs: FsValue) -> bool {
-    not_null_nor_nan(&rhs) && is_same_type(&lhs, &rhs) && lhs.gt(&rhs)
+// A copy of the map without `key`, which need not be present
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_remove(map: FsValue, key: &str) -> FsValue {
+    let mut map = expect_map_arg(map, "fs_map_remove");
+    map.remove(key);
+    FsValue::Map(map)
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(#<=)]
-fn fs_le(lhs: FsValue, rhs: FsValue) -> bool {
-    not_null_nor_nan(&rhs) && is_same_type(&lhs, &rhs) && lhs.le(&rhs)
+// The keys in the order of the text format
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_keys(map: FsValue) -> Vec<String> {
+    expect_map_arg(map, "fs_map_keys").into_keys().collect()
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(#>=)]
-fn fs_ge(lhs: FsValue, rhs: FsValue) -> bool {
-    not_null_nor_nan(&rhs) && is_same_type(&lhs, &rhs) && lhs.ge(&rhs)
+// Mirrors jsonb_strip_nulls: only map entries are removed and non-map inputs
+// are returned unchanged.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_strip_nulls(value: FsValue, prune_empty_maps: default!(bool, false)) -> FsValue {
+    match value {
+        FsValue::Map(_) => value.strip_nulls(prune_empty_maps),
+        _ => value,
+    }
 }
 
-#[pg_operator(immutable, parallel_safe)]
-#[opname(+)]
-fn fs_add(lhs: FsValue, rhs: FsValue) -> FsValue {
+// The `->` operator and fs_get_field decode only the field they return, see
+// fs_partial_decode.rs
+fn fs_map_get(fs_map: FsValue, field_name: &str) -> Option<FsValue> {
+    fs_map
+        .as_map()
+        .and_then(|map| map.get(field_name).map(|value| value.to_owned()))
+}
+
+// A field holding a Firestore NULL is present, so only a missing field (or a
+// SQL NULL map) falls back to `default`.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_map_get_or(fs_map: Option<FsValue>, field_name: &str, default: FsValue) -> FsValue {
+    fs_map
+        .and_then(|fs_map| fs_map_get(fs_map, field_name))
+        .unwrap_or(default)
+}
+
+fn parse_field_names(field_path: &str) -> Vec<String> {
+    match FieldPath::from_str(field_path).and_then(FieldPath::into_field_names) {
+        Ok(field_names) => field_names,
+        Err(error) => error.report(),
+    }
+}
+
+// The index written by `name` in decimal without sign or leading zeros
+fn array_index(name: &str) -> Option<usize> {
+    let index = name.parse::<usize>().ok()?;
+    (index.to_string() == name).then_some(index)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(#>>)]
+fn fs_get_path(fs_value: FsValue, field_path: &str) -> Option<FsValue> {
+    fs_value
+        .get_path(&parse_field_names(field_path))
+        .map(|value| value.to_owned())
+}
+
+// Like fs_get_field with every key taken literally, so that keys from user
+// data need no escaping. Maps are taken apart on the way down rather than
+// cloned.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_pluck(fs_value: FsValue, keys: VariadicArray<&str>) -> Option<FsValue> {
+    let mut value = fs_value;
+    for key in keys.iter() {
+        value = match value {
+            FsValue::Map(mut map) => map.remove(key?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+// Like fs_map_get_or for a dotted field path
+#[pg_extern(immutable, parallel_safe)]
+fn fs_get_field_or(fs_value: Option<FsValue>, field_path: &str, default: FsValue) -> FsValue {
+    let field_names = parse_field_names(field_path);
+    fs_value
+        .and_then(|fs_value| {
+            fs_value
+                .get_field(&field_names)
+                .map(|value| value.to_owned())
+        })
+        .unwrap_or(default)
+}
+
+// The fs_as_* extractors convert a value to a plain SQL type, returning NULL
+// for values of any other type.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_text(fs_value: FsValue) -> Option<String> {
+    match fs_value {
+        FsValue::String(string) => Some(string),
+        FsValue::Reference(reference) => Some(reference.to_string()),
+        _ => None,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_bigint(fs_value: FsValue) -> Option<i64> {
+    match fs_value {
+        FsValue::Number(FsNumber::Number(number)) => number.as_i64(),
+        _ => None,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_double(fs_value: FsValue) -> Option<f64> {
+    match fs_value {
+        FsValue::Number(FsNumber::Number(number)) => number.as_f64(),
+        FsValue::Number(FsNumber::NAN) => Some(f64::NAN),
+        FsValue::Number(FsNumber::PositiveInfinity) => Some(f64::INFINITY),
+        FsValue::Number(FsNumber::NegativeInfinity) => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_boolean(fs_value: FsValue) -> Option<bool> {
+    match fs_value {
+        FsValue::Boolean(boolean) => Some(boolean),
+        _ => None,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_text_array(fs_value: FsValue) -> Option<Vec<Option<String>>> {
+    match fs_value {
+        FsValue::Array(array) => Some(array.into_iter().map(fs_as_text).collect()),
+        _ => None,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_as_bigint_array(fs_value: FsValue) -> Option<Vec<Option<i64>>> {
+    match fs_value {
+        FsValue::Array(array) => Some(array.into_iter().map(fs_as_bigint).collect()),
+        _ => None,
+    }
+}
+
+// Matches `text` against a SQL LIKE pattern: '%' matches any sequence of
+// characters, '_' matches a single character and '\' escapes the next one.
+fn like_match(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '%' {
+            backtrack = Some((p, t));
+            p += 1;
+            continue;
+        }
+        if p < pattern.len() {
+            let (is_match, width) = match pattern[p] {
+                '_' => (true, 1),
+                '\\' if p + 1 < pattern.len() => (pattern[p + 1] == text[t], 2),
+                c => (c == text[t], 1),
+            };
+            if is_match {
+                p += width;
+                t += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((percent, matched_until)) => {
+                p = percent + 1;
+                t = matched_until + 1;
+                backtrack = Some((percent, matched_until + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '%')
+}
+
+#[pg_extern]
+fn fs_keys_matching(fs_map: FsValue, pattern: &str) -> SetOfIterator<'static, String> {
+    let keys: Vec<String> = fs_map
+        .as_map()
+        .map(|map| {
+            map.keys()
+                .filter(|key| like_match(key, pattern))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    SetOfIterator::new(keys.into_iter())
+}
+
+// Expands `pattern` against nested maps, collecting the concrete path and the
+// value of every match. Wildcards only ever walk maps, never arrays.
+fn collect_fields_matching<'a>(
+    value: &'a FsValue,
+    pattern: &[PathSegment],
+    prefix: Vec<String>,
+    matches: &mut Vec<(Vec<String>, &'a FsValue)>,
+) {
+    let (segment, rest) = match pattern.split_first() {
+        Some(split) => split,
+        None => {
+            matches.push((prefix, value));
+            return;
+        }
+    };
+    let map = match value.as_map() {
+        Some(map) => map,
+        None => return,
+    };
+    match segment {
+        PathSegment::Field(name) => {
+            if let Some(child) = map.get(name) {
+                let mut path = prefix;
+                path.push(name.to_owned());
+                collect_fields_matching(child, rest, path, matches);
+            }
+        }
+        PathSegment::Wildcard => {
+            for (key, child) in map.iter() {
+                let mut path = prefix.clone();
+                path.push(key.to_owned());
+                collect_fields_matching(child, rest, path, matches);
+            }
+        }
+    }
+}
+
+// Collects the paths at which `lhs` and `rhs` differ, descending into maps
+// present on both sides. Any other pair of values is compared as a whole.
+fn collect_differences(
+    lhs: &FsValue,
+    rhs: &FsValue,
+    prefix: Vec<String>,
+    differences: &mut Vec<Vec<String>>,
+) {
+    match (lhs, rhs) {
+        (FsValue::Map(lhs_map), FsValue::Map(rhs_map)) => {
+            let keys: BTreeSet<&String> = lhs_map.keys().chain(rhs_map.keys()).collect();
+            for key in keys {
+                let mut path = prefix.clone();
+                path.push(key.to_owned());
+                match (lhs_map.get(key), rhs_map.get(key)) {
+                    (Some(lhs_child), Some(rhs_child)) => {
+                        collect_differences(lhs_child, rhs_child, path, differences)
+                    }
+                    _ => differences.push(path),
+                }
+            }
+        }
+        _ => {
+            if lhs != rhs {
+                differences.push(prefix)
+            }
+        }
+    }
+}
+
+#[pg_extern]
+fn fs_get_fields_matching(
+    fs_value: FsValue,
+    path_pattern: &str,
+) -> TableIterator<'static, (name!(path, String), name!(value, FsValue))> {
+    let pattern = match FieldPath::from_str(path_pattern) {
+        Ok(pattern) => pattern,
+        Err(error) => error.report(),
+    };
+    let mut matches = Vec::new();
+    collect_fields_matching(&fs_value, &pattern.0, Vec::new(), &mut matches);
+    // Sorted by path segments, i.e. map key order at every level
+    matches.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+    let rows: Vec<(String, FsValue)> = matches
+        .into_iter()
+        .map(|(path, value)| {
+            let field_path = FieldPath(path.into_iter().map(PathSegment::Field).collect());
+            (field_path.to_string(), value.to_owned())
+        })
+        .collect();
+    TableIterator::new(rows.into_iter())
+}
+
+#[pg_extern]
+fn fs_redact(
+    fs_value: FsValue,
+    paths: Vec<String>,
+    mode: default!(&str, "'mask'"),
+    replacement: default!(Option<FsValue>, "NULL"),
+) -> FsValue {
+    let replacement = replacement.unwrap_or(FsValue::String("[REDACTED]".to_string()));
+    let mut matched: Vec<(Vec<String>, FsValue)> = Vec::new();
+    for path in paths.iter() {
+        let pattern = match FieldPath::from_str(path) {
+            Ok(pattern) => pattern,
+            Err(error) => error.report(),
+        };
+        let mut matches = Vec::new();
+        collect_fields_matching(&fs_value, &pattern.0, Vec::new(), &mut matches);
+        matched.extend(
+            matches
+                .into_iter()
+                .map(|(field_names, value)| (field_names, value.to_owned())),
+        );
+    }
+    let mut redacted = fs_value;
+    for (field_names, value) in matched {
+        match mode {
+            "mask" => redacted.set_field(&field_names, replacement.to_owned()),
+            // Equal values hash to the same string so joins on them still work
+            "hash" => {
+                let digest = Sha256::digest(value.canonical_text().as_bytes());
+                let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+                redacted.set_field(&field_names, FsValue::String(hex))
+            }
+            "drop" => {
+                redacted.remove_field(&field_names);
+            }
+            _ => FsError::InvalidValue(format!(
+                "Unknown redaction mode '{}', expecting 'mask', 'drop' or 'hash'",
+                mode
+            ))
+            .report(),
+        }
+    }
+    redacted
+}
+
+fn is_same_type(lhs: &FsValue, rhs: &FsValue) -> bool {
+    mem::discriminant(lhs) == mem::discriminant(rhs)
+}
+
+// Whether a range filter on `lhs` against `rhs` matches with one of
+// `orderings`. NaN is unordered, see fs_ordering::query_cmp.
+fn range_matches(lhs: &FsValue, rhs: &FsValue, orderings: &[Ordering]) -> bool {
+    rhs.ne(&FsValue::NULL)
+        && is_same_type(lhs, rhs)
+        && query_cmp(lhs, rhs).is_some_and(|ordering| orderings.contains(&ordering))
+}
+
+fn fs_lt(lhs: FsValue, rhs: FsValue) -> bool {
+    range_matches(&lhs, &rhs, &[Ordering::Less])
+}
+
+fn fs_gt(lhs: FsValue, rhs: FsValue) -> bool {
+    range_matches(&lhs, &rhs, &[Ordering::Greater])
+}
+
+fn fs_le(lhs: FsValue, rhs: FsValue) -> bool {
+    range_matches(&lhs, &rhs, &[Ordering::Less, Ordering::Equal])
+}
+
+fn fs_ge(lhs: FsValue, rhs: FsValue) -> bool {
+    range_matches(&lhs, &rhs, &[Ordering::Greater, Ordering::Equal])
+}
+
+fn expect_numbers(lhs: FsValue, rhs: FsValue) -> (FsNumber, FsNumber) {
     match (lhs, rhs) {
-        (FsValue::Number(l), FsValue::Number(r)) => FsValue::Number(l + r),
-        _ => panic!("Expecting number type"),
+        (FsValue::Number(l), FsValue::Number(r)) => (l, r),
+        (lhs, rhs) => FsError::InvalidType(format!(
+            "Expecting numbers but found {} and {}",
+            display_value(&lhs),
+            display_value(&rhs)
+        ))
+        .report(),
+    }
+}
+
+// `+`, `-` and `*` follow Firestore's increment transform: a missing base
+// (SQL NULL) or one that is not a number counts as 0, while the operand must
+// be a number, and integers saturate at the bounds of a 64-bit integer. A
+// SQL NULL operand gives NULL.
+fn apply_transform(
+    base: Option<FsValue>,
+    operand: Option<FsValue>,
+    op: fn(FsNumber, FsNumber) -> FsNumber,
+) -> Option<FsValue> {
+    let operand = expect_number(operand?);
+    let base = match base {
+        Some(FsValue::Number(number)) => number,
+        _ => FsNumber::Number(0.into()),
+    };
+    Some(FsValue::Number(op(base, operand)))
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(+)]
+fn fs_add(lhs: Option<FsValue>, rhs: Option<FsValue>) -> Option<FsValue> {
+    apply_transform(lhs, rhs, |l, r| l + r)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(-)]
+fn fs_subtract(lhs: Option<FsValue>, rhs: Option<FsValue>) -> Option<FsValue> {
+    apply_transform(lhs, rhs, |l, r| l - r)
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(*)]
+fn fs_multiply(lhs: Option<FsValue>, rhs: Option<FsValue>) -> Option<FsValue> {
+    apply_transform(lhs, rhs, |l, r| l * r)
+}
+
+// The increment transform by name, the same as `value + by`
+#[pg_extern(immutable, parallel_safe)]
+fn fs_increment(value: Option<FsValue>, by: Option<FsValue>) -> Option<FsValue> {
+    fs_add(value, by)
+}
+
+// Integer division by zero fails like it does in Postgres, with SQLSTATE 22012
+fn division_result(result: Option<FsNumber>) -> FsValue {
+    match result {
+        Some(number) => FsValue::Number(number),
+        None => {
+            ereport!(
+                PgLogLevel::ERROR,
+                PgSqlErrorCode::ERRCODE_DIVISION_BY_ZERO,
+                "division by zero"
+            );
+            unreachable!("an ERROR report does not return")
+        }
     }
 }
 
 #[pg_operator(immutable, parallel_safe)]
-#[opname(#=)]
+#[opname(%)]
+fn fs_mod(lhs: FsValue, rhs: FsValue) -> FsValue {
+    let (l, r) = expect_numbers(lhs, rhs);
+    division_result(l.checked_rem(&r))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_idiv(lhs: FsValue, rhs: FsValue) -> FsValue {
+    let (l, r) = expect_numbers(lhs, rhs);
+    division_result(l.checked_div_trunc(&r))
+}
+
 fn fs_eq(lhs: FsValue, rhs: FsValue) -> bool {
     fs_ref_eq(&lhs, &rhs)
 }
 
+// Firestore equality, under which 1 and 1.0 are equal like under `=`.
+// Equal values hold NaN at the same places, and NaN equals nothing in a
+// Firestore filter, so no value holding one is equal to anything.
 fn fs_ref_eq(lhs: &FsValue, rhs: &FsValue) -> bool {
-    lhs.eq(rhs)
+    lhs.cmp(rhs).is_eq() && !contains_nan(lhs)
 }
 
-#[pg_extern]
+// The type tag of a value, e.g. to find or count values of each type in a
+// field of mixed types
+#[pg_extern(immutable, parallel_safe)]
+fn fs_typeof(value: FsValue) -> String {
+    value.type_name().to_owned()
+}
+
+// Type predicates for CHECK constraints and the predicates of partial indexes
+#[pg_extern(immutable, parallel_safe)]
 fn fs_is_null(val: FsValue) -> bool {
     val.eq(&FsValue::NULL)
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_number(value: FsValue) -> bool {
+    matches!(value, FsValue::Number(_))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_string(value: FsValue) -> bool {
+    matches!(value, FsValue::String(_))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_array(value: FsValue) -> bool {
+    matches!(value, FsValue::Array(_))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_map(value: FsValue) -> bool {
+    matches!(value, FsValue::Map(_))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_is_reference(value: FsValue) -> bool {
+    matches!(value, FsValue::Reference(_))
+}
+
+// The only filter that matches NaN, which the other operators never do
+#[pg_extern(immutable, parallel_safe)]
 fn fs_is_nan(val: FsValue) -> bool {
     val.eq(&FsValue::Number(FsNumber::NAN))
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_is_not_null(val: FsValue) -> bool {
     val.ne(&FsValue::NULL)
 }
 
-#[pg_extern]
+#[pg_extern(immutable, parallel_safe)]
 fn fs_is_not_nan(val: FsValue) -> bool {
     val.ne(&FsValue::Number(FsNumber::NAN)) && val.ne(&FsValue::NULL)
 }
 
 // For any `NULL` operands, this implement the `IS_NOT_NULL` semantics
 // https://cloud.google.com/firestore/docs/query-data/queries#not_equal_
-#[pg_operator(immutable, parallel_safe)]
-#[opname(#!=)]
 fn fs_neq(lhs: FsValue, rhs: FsValue) -> bool {
     match &rhs {
         FsValue::NULL => false,
         FsValue::Number(FsNumber::NAN) => fs_is_not_null(lhs),
-        _ => lhs.ne(&rhs),
+        _ => !fs_eq(lhs, rhs),
+    }
+}
+
+// Firestore's limit on the values of an IN or NOT_IN filter
+const MAX_IN_VALUES: usize = 30;
+
+fn expect_in_candidates(candidates: &[FsValue]) {
+    if candidates.len() > MAX_IN_VALUES {
+        FsError::LimitExceeded(format!(
+            "IN filter of {} values exceeds Firestore's limit of {} values",
+            candidates.len(),
+            MAX_IN_VALUES
+        ))
+        .report()
+    }
+}
+
+// IN matches a value `#=` to a candidate, so 1 matches 1.0 but never NaN,
+// and null only when null is a candidate
+fn in_candidates(value: &FsValue, candidates: &[FsValue]) -> bool {
+    expect_in_candidates(candidates);
+    candidates
+        .iter()
+        .any(|candidate| fs_ref_eq(value, candidate))
+}
+
+// NOT_IN, like `!=`, never matches null
+fn not_in_candidates(value: &FsValue, candidates: &[FsValue]) -> bool {
+    expect_in_candidates(candidates);
+    candidates
+        .iter()
+        .all(|candidate| fs_neq(value.to_owned(), candidate.to_owned()))
+        && !fs_is_null(value.to_owned())
+}
+
+// The IN and NOT_IN filters, false for a SQL NULL operand like the other
+// query operators. The candidates are an array value or a SQL array.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_in(value: Option<FsValue>, candidates: Option<FsValue>) -> bool {
+    compare_present(value, candidates, |value, candidates| {
+        in_candidates(&value, &expect_array(candidates))
+    })
+}
+
+#[pg_extern(immutable, parallel_safe, name = "fs_in")]
+fn fs_in_values(value: Option<FsValue>, candidates: Option<Vec<FsValue>>) -> bool {
+    match (value, candidates) {
+        (Some(value), Some(candidates)) => in_candidates(&value, &candidates),
+        _ => false,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_not_in(value: Option<FsValue>, candidates: Option<FsValue>) -> bool {
+    compare_present(value, candidates, |value, candidates| {
+        not_in_candidates(&value, &expect_array(candidates))
+    })
+}
+
+#[pg_extern(immutable, parallel_safe, name = "fs_not_in")]
+fn fs_not_in_values(value: Option<FsValue>, candidates: Option<Vec<FsValue>>) -> bool {
+    match (value, candidates) {
+        (Some(value), Some(candidates)) => not_in_candidates(&value, &candidates),
+        _ => false,
     }
 }
 
+// The Rust functions behind the query operators are not strict. A SQL NULL
+// operand stands for a missing field, which no Firestore filter matches, so
+// they return false rather than NULL, also under NOT. A Firestore NULL is a
+// value like any other.
+fn compare_present(
+    lhs: Option<FsValue>,
+    rhs: Option<FsValue>,
+    compare: fn(FsValue, FsValue) -> bool,
+) -> bool {
+    match (lhs, rhs) {
+        (Some(lhs), Some(rhs)) => compare(lhs, rhs),
+        _ => false,
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_query_lt(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_lt)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_query_gt(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_gt)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_query_le(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_le)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_query_ge(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_ge)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn fs_query_eq(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_eq)
+}
+
+#[pg_operator(immutable, parallel_safe, name = "fs_neq")]
+#[opname(#!=)]
+#[restrict(neqsel)]
+#[join(neqjoinsel)]
+fn fs_neq_operator(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    compare_present(lhs, rhs, fs_neq)
+}
+
+// `=` and `<>` are written out rather than derived with PostgresEq, which
+// declares no commutators. Without one, the planner fails on a join that
+// puts the indexed column on the right, like `p.reference = a.ancestor`.
+// Both follow the btree order, so 1 and 1.0 are equal, as they are in hashes;
+// fs_identical tells them apart.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(=)]
+#[commutator(=)]
+#[negator(<>)]
+#[restrict(eqsel)]
+#[join(eqjoinsel)]
+#[merges]
+#[hashes]
+fn fsvalue_eq(left: FsValue, right: FsValue) -> bool {
+    left.cmp(&right).is_eq()
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<>)]
+#[commutator(<>)]
+#[negator(=)]
+#[restrict(neqsel)]
+#[join(neqjoinsel)]
+fn fsvalue_ne(left: FsValue, right: FsValue) -> bool {
+    left.cmp(&right).is_ne()
+}
+
+// Whether two values are stored alike, which `=` does not tell: 1 and 1.0 are
+// equal but not identical. Like IS NOT DISTINCT FROM, a NULL is identical to
+// a NULL only, so writes and diffs that must see a 1 become 1.0 use it.
+#[pg_extern(immutable, parallel_safe)]
+fn fs_identical(lhs: Option<FsValue>, rhs: Option<FsValue>) -> bool {
+    lhs == rhs
+}
+
+// Like `=`, the ordering operators are written out rather than derived with
+// PostgresOrd, whose btree operator class compares with a function decoding
+// both values whole. fs_ordering.rs declares the operator class.
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<)]
+#[commutator(>)]
+#[negator(>=)]
+#[restrict(scalarltsel)]
+#[join(scalarltjoinsel)]
+fn fsvalue_lt(left: FsValue, right: FsValue) -> bool {
+    left < right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(>)]
+#[commutator(<)]
+#[negator(<=)]
+#[restrict(scalargtsel)]
+#[join(scalargtjoinsel)]
+fn fsvalue_gt(left: FsValue, right: FsValue) -> bool {
+    left > right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(<=)]
+#[commutator(>=)]
+#[negator(>)]
+#[restrict(scalarlesel)]
+#[join(scalarlejoinsel)]
+fn fsvalue_le(left: FsValue, right: FsValue) -> bool {
+    left <= right
+}
+
+#[pg_operator(immutable, parallel_safe)]
+#[opname(>=)]
+#[commutator(<=)]
+#[negator(<)]
+#[restrict(scalargesel)]
+#[join(scalargejoinsel)]
+fn fsvalue_ge(left: FsValue, right: FsValue) -> bool {
+    left >= right
+}
+
+// The type-clamped operators cannot be members of a btree operator family, as
+// `1 #< 'a'` and `'a' #< 1` are both false. Instead, their SQL functions are
+// inlined by the planner into a total-order comparison of the default btree
+// operator class, which any index on the operand can serve, and the exact
+// check. Whenever the exact check holds, the total-order one does too, so the
+// conjunction is the exact check, except that a SQL NULL operand makes it
+// false rather than NULL.
+//
+// `#=` is its own commutator, and `#<` and `#>`, like `#<=` and `#>=`, are
+// each other's, since NaN is unordered on either side: `NaN #< 1` and
+// `1 #> NaN` are both false. None of them have negators, since
+// `NOT (1 #< 'a')` differs from `1 #>= 'a'`.
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_lt(lhs fsvalue, rhs fsvalue) RETURNS boolean \n\
+        AS 'SELECT lhs < rhs AND fs_query_lt(lhs, rhs)' \n\
+        LANGUAGE sql IMMUTABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_gt(lhs fsvalue, rhs fsvalue) RETURNS boolean \n\
+        AS 'SELECT lhs > rhs AND fs_query_gt(lhs, rhs)' \n\
+        LANGUAGE sql IMMUTABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_le(lhs fsvalue, rhs fsvalue) RETURNS boolean \n\
+        AS 'SELECT lhs <= rhs AND fs_query_le(lhs, rhs)' \n\
+        LANGUAGE sql IMMUTABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_ge(lhs fsvalue, rhs fsvalue) RETURNS boolean \n\
+        AS 'SELECT lhs >= rhs AND fs_query_ge(lhs, rhs)' \n\
+        LANGUAGE sql IMMUTABLE PARALLEL SAFE; \n\
+        CREATE FUNCTION fs_eq(lhs fsvalue, rhs fsvalue) RETURNS boolean \n\
+        AS 'SELECT lhs = rhs AND fs_query_eq(lhs, rhs)' \n\
+        LANGUAGE sql IMMUTABLE PARALLEL SAFE; \n\
+        CREATE OPERATOR #< ( \n\
+            LEFTARG = fsvalue, RIGHTARG = fsvalue, FUNCTION = fs_lt, \n\
+            COMMUTATOR = #>, RESTRICT = scalarltsel, JOIN = scalarltjoinsel \n\
+        ); \n\
+        CREATE OPERATOR #> ( \n\
+            LEFTARG = fsvalue, RIGHTARG = fsvalue, FUNCTION = fs_gt, \n\
+            COMMUTATOR = #<, RESTRICT = scalargtsel, JOIN = scalargtjoinsel \n\
+        ); \n\
+        CREATE OPERATOR #<= ( \n\
+            LEFTARG = fsvalue, RIGHTARG = fsvalue, FUNCTION = fs_le, \n\
+            COMMUTATOR = #>=, RESTRICT = scalarlesel, JOIN = scalarlejoinsel \n\
+        ); \n\
+        CREATE OPERATOR #>= ( \n\
+            LEFTARG = fsvalue, RIGHTARG = fsvalue, FUNCTION = fs_ge, \n\
+            COMMUTATOR = #<=, RESTRICT = scalargesel, JOIN = scalargejoinsel \n\
+        ); \n\
+        CREATE OPERATOR #= ( \n\
+            LEFTARG = fsvalue, RIGHTARG = fsvalue, FUNCTION = fs_eq, \n\
+            COMMUTATOR = #=, RESTRICT = eqsel, JOIN = eqjoinsel \n\
+        ); \n\
+    ",
+    name = "query_operators",
+    requires = [
+        FsValue,
+        fsvalue_lt,
+        fsvalue_gt,
+        fsvalue_le,
+        fsvalue_ge,
+        fsvalue_eq,
+        fs_query_lt,
+        fs_query_gt,
+        fs_query_le,
+        fs_query_ge,
+        fs_query_eq,
+    ],
+);
+
 #[pg_extern]
 fn fs_value_examples() -> Vec<FsValue> {
     vec![
@@ -530,6 +2015,7 @@ fn fs_value_examples() -> Vec<FsValue> {
         FsValue::Boolean(true),
         FsValue::Number(FsNumber::from(serde_json::Number::from(7))),
         FsValue::Date(pgrx::Date::from(0)),
+        FsValue::Timestamp(0),
         FsValue::String(String::from("hello")),
         FsValue::Bytes(vec![0x00, 0x01]),
         FsValue::Reference(FsReference {
@@ -549,11 +2035,16 @@ extension_sql!(
         CREATE TABLE fs_documents (\n\
             reference fsvalue PRIMARY KEY, \n\
             properties fsvalue\n\
-            CONSTRAINT valid_document_key CHECK (fs_is_valid_document_key(reference))\n\
-            CONSTRAINT valid_document_properties CHECK (fs_is_valid_document_properties(properties))\n\
+            CONSTRAINT valid_document_key CHECK (fs_validate_document_key(reference))\n\
+            CONSTRAINT valid_document_properties CHECK (fs_is_valid_document_properties(properties)) \n\
+            CONSTRAINT valid_document_limits CHECK (fs_validate_document(properties)), \n\
+            create_time timestamptz NOT NULL DEFAULT now(), \n\
+            update_time timestamptz NOT NULL DEFAULT now(), \n\
+            deleted_at timestamptz\n\
         );\n\
     ",
     name = "main_table",
+    requires = [fs_check::fs_validate_document, "btree_opclass"],
 );
 
 extension_sql!(
@@ -574,28 +2065,93 @@ extension_sql!(
 
 extension_sql!(
     "\n\
-        CREATE FUNCTION fs_collection(parent fsvalue, collection_id text) \n\
-        RETURNS TABLE (reference fsvalue, properties fsvalue) AS $$ \n\
-            SELECT * FROM fs_documents \n\
+        CREATE FUNCTION fs_collection_v2( \n\
+            parent fsvalue, collection_id text, include_deleted boolean DEFAULT false \n\
+        ) \n\
+        RETURNS TABLE ( \n\
+            reference fsvalue, properties fsvalue, \n\
+            create_time timestamptz, update_time timestamptz \n\
+        ) AS $$ \n\
+        DECLARE \n\
+            documents bigint; \n\
+        BEGIN \n\
+            RETURN QUERY \n\
+            SELECT d.reference, d.properties, d.create_time, d.update_time FROM fs_documents AS d \n\
             WHERE \n\
-                fs_parent(reference) = parent AND \n\
-                fs_collection_id(reference) = collection_id \n\
+                fs_parent(d.reference) = parent AND \n\
+                fs_collection_id(d.reference) = collection_id AND \n\
+                (include_deleted OR d.deleted_at IS NULL); \n\
+            GET DIAGNOSTICS documents = ROW_COUNT; \n\
+            PERFORM fs_activity_count_reads(parent, collection_id, documents); \n\
+        END \n\
+        $$ LANGUAGE plpgsql; \n\
+        CREATE FUNCTION fs_collection( \n\
+            parent fsvalue, collection_id text, include_deleted boolean DEFAULT false \n\
+        ) \n\
+        RETURNS TABLE (reference fsvalue, properties fsvalue) AS $$ \n\
+            SELECT reference, properties \n\
+            FROM fs_collection_v2(parent, collection_id, include_deleted) \n\
         $$ LANGUAGE SQL; \n\
     ",
     name = "collection_tvf",
-    requires = ["main_table"],
+    requires = [
+        "main_table",
+        "reference_path_functions",
+        fs_activity::fs_activity_count_reads
+    ],
+);
+
+// Descendants are the references between the prefix and its successor, so
+// both branches are range scans of the fs_documents primary key. The second
+// one only runs for the root, which has no successor. Both functions are
+// STABLE so that the planner inlines them into the calling query.
+extension_sql!(
+    "\n\
+        CREATE FUNCTION fs_descendants_v2(prefix fsvalue, include_deleted boolean DEFAULT false) \n\
+        RETURNS TABLE ( \n\
+            reference fsvalue, properties fsvalue, \n\
+            create_time timestamptz, update_time timestamptz \n\
+        ) AS $$ \n\
+            SELECT d.reference, d.properties, d.create_time, d.update_time FROM fs_documents AS d \n\
+            WHERE d.reference > prefix AND d.reference < fs_prefix_successor(prefix) \n\
+                AND (include_deleted OR d.deleted_at IS NULL) \n\
+            UNION ALL \n\
+            SELECT d.reference, d.properties, d.create_time, d.update_time FROM fs_documents AS d \n\
+            WHERE fs_prefix_successor(prefix) IS NULL AND d.reference > prefix \n\
+                AND (include_deleted OR d.deleted_at IS NULL) \n\
+        $$ LANGUAGE SQL STABLE; \n\
+        CREATE FUNCTION fs_descendants(prefix fsvalue, include_deleted boolean DEFAULT false) \n\
+        RETURNS TABLE (reference fsvalue, properties fsvalue) AS $$ \n\
+            SELECT reference, properties \n\
+            FROM fs_descendants_v2(prefix, include_deleted) \n\
+        $$ LANGUAGE SQL STABLE; \n\
+    ",
+    name = "descendants_tvf",
+    requires = ["main_table", fs_prefix_successor]
 );
 
 extension_sql!(
     "\n\
-        CREATE FUNCTION fs_collection_group(collection_id text) \n\
+        CREATE FUNCTION fs_collection_group_v2( \n\
+            collection_id text, include_deleted boolean DEFAULT false \n\
+        ) \n\
+        RETURNS TABLE ( \n\
+            reference fsvalue, properties fsvalue, \n\
+            create_time timestamptz, update_time timestamptz \n\
+        ) AS $$ \n\
+            SELECT reference, properties, create_time, update_time FROM fs_documents \n\
+            WHERE \n\
+                fs_collection_id(reference) = collection_id AND \n\
+                (include_deleted OR deleted_at IS NULL) \n\
+        $$ LANGUAGE SQL; \n\
+        CREATE FUNCTION fs_collection_group(collection_id text, include_deleted boolean DEFAULT false) \n\
         RETURNS TABLE (reference fsvalue, properties fsvalue) AS $$ \n\
-            SELECT * FROM fs_documents \n\
-            WHERE fs_collection_id(reference) = collection_id \n\
+            SELECT reference, properties \n\
+            FROM fs_collection_group_v2(collection_id, include_deleted) \n\
         $$ LANGUAGE SQL; \n\
     ",
     name = "collection_group_tvf",
-    requires = ["main_table"],
+    requires = ["main_table", "reference_path_functions"],
 );
 
 extension_sql!(
@@ -611,6 +2167,7 @@ extension_sql!(
 #[cfg(any(test, feature = "pg_test"))]
 #[pg_schema]
 mod tests {
+    use crate::fs_test_util::{explain, map};
     use crate::*;
     use std::ffi::CString;
 
@@ -647,160 +2204,1986 @@ mod tests {
     }
 
     #[pg_test]
-    fn test_fs_string() {
+    fn test_fs_number_from_bigint() {
+        // 2^53 + 1 is the first integer a double cannot hold
+        assert_eq!(
+            Spi::get_one::<String>("SELECT fs_number_from_bigint(9007199254740993)::text"),
+            Ok(Some(
+                r#"{"type":"NUMBER","value":9007199254740993}"#.to_owned()
+            ))
+        );
         assert_eq!(
             Spi::get_one::<FsValue>(
-                r#"select '{"type": "STRING", "value": "hello world"}'::fsvalue"#
+                r#"SELECT '{"type": "NUMBER", "value": 9007199254740993}'::fsvalue"#
             ),
-            Ok(Some(fs_string("hello world")))
+            Ok(Some(fs_number_from_bigint(9_007_199_254_740_993)))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_number_from_bigint(9007199254740993) > fs_number_from_bigint(9007199254740992) \
+                 AND fs_number_from_bigint(9007199254740992) > fs_number_from_double(9007199254740991.0)"
+            ),
+            Ok(Some(true))
+        );
+        assert_eq!(
+            fs_number_from_bigint(i64::MIN).cmp(&fs_number_from_double(-9.3e18)),
+            std::cmp::Ordering::Greater
         );
     }
 
     #[pg_test]
-    fn test_fs_reference() {
+    fn test_fs_number_is_integer() {
+        assert!(fs_number_is_integer(fs_number_from_integer(1)));
+        assert!(fs_number_is_integer(fs_number_from_bigint(i64::MAX)));
+        assert!(!fs_number_is_integer(fs_number_from_double(1.0)));
+        assert!(!fs_number_is_integer(fs_nan()));
         assert_eq!(
-            Spi::get_one::<FsValue>(
-                r#"select '{"type": "REFERENCE", "value": "/users/1"}'::fsvalue"#
+            Spi::get_one::<bool>(
+                r#"SELECT fs_number_is_integer('{"type": "NUMBER", "value": 1}'::fsvalue)
+                   AND NOT fs_number_is_integer('{"type": "NUMBER", "value": 1.0}'::fsvalue)"#
             ),
-            Ok(Some(fs_reference("/users/1")))
+            Ok(Some(true))
+        );
+        // Told apart, yet the same in the ordering
+        assert_eq!(
+            fs_number_from_integer(1).cmp(&fs_number_from_double(1.0)),
+            std::cmp::Ordering::Equal
         );
     }
 
     #[pg_test]
-    fn test_fs_bytes() {
+    fn test_fs_identical() {
         assert_eq!(
-            Spi::get_one::<FsValue>(
-                r#"select concat('{"type": "BYTES", "value": "', encode('helloworld'::bytea, 'base64'), '"}')::fsvalue"#
+            Spi::get_one::<bool>(
+                "SELECT fs_number_from_integer(1) = fs_number_from_double(1.0) \
+                 AND NOT fs_identical(fs_number_from_integer(1), fs_number_from_double(1.0)) \
+                 AND fs_identical(fs_doc('a', 1), fs_doc('a', 1)) \
+                 AND fs_identical(NULL, NULL) \
+                 AND NOT fs_identical(fs_null(), NULL)"
             ),
-            Ok(Some(FsValue::Bytes(vec![
-                0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x77, 0x6f, 0x72, 0x6c, 0x64
-            ])))
+            Ok(Some(true))
         );
     }
 
+    #[pg_test(error = "InvalidType: Expecting a number but found \"1\"")]
+    fn test_fs_number_is_integer_of_string() {
+        fs_number_is_integer(fs_string("1"));
+    }
+
     #[pg_test]
-    fn test_fs_array() {
-        let array = Spi::get_one::<FsValue>(
-            r#"select '{
-                "type": "ARRAY",
-                "value": [
-                    {"type": "NUMBER", "value": 1},
-                    {"type": "NULL", "value": null},
-                    {"type": "BOOLEAN", "value": true}
-                ]
-            }'::fsvalue;"#,
+    fn test_fs_number_special_values_round_trip() {
+        for special in [
+            "fs_nan()",
+            "fs_number_from_str('Infinity')",
+            "fs_number_from_str('-Infinity')",
+        ] {
+            for value in [
+                special.to_owned(),
+                format!("fs_arr(fs_null(), {})", special),
+                format!("fs_doc('a', fs_doc('b', {}))", special),
+            ] {
+                // NaN is not #= to itself, so compare the values instead
+                assert_eq!(
+                    Spi::get_one::<FsValue>(&format!("SELECT ({})::text::fsvalue", value)),
+                    Spi::get_one::<FsValue>(&format!("SELECT {}", value)),
+                    "{}",
+                    value
+                );
+            }
+        }
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                r#"select '{"type": "NUMBER", "value": "-Infinity"}'::fsvalue"#
+            ),
+            Ok(Some(FsValue::Number(FsNumber::NegativeInfinity)))
         );
+    }
 
+    #[pg_test]
+    fn test_fs_number_from_double_special_values() {
+        for (double, expected) in [
+            ("'NaN'", FsNumber::NAN),
+            ("'Infinity'", FsNumber::PositiveInfinity),
+            ("'-Infinity'", FsNumber::NegativeInfinity),
+        ] {
+            assert_eq!(
+                Spi::get_one::<FsValue>(&format!(
+                    "SELECT fs_number_from_double({}::float8)",
+                    double
+                )),
+                Ok(Some(FsValue::Number(expected.to_owned())))
+            );
+            assert_eq!(
+                Spi::get_one::<bool>(&format!(
+                    "SELECT fs_number_to_double(fs_number_from_double({0}::float8)) = {0}::float8",
+                    double
+                )),
+                Ok(Some(true))
+            );
+        }
+        // -0.0 keeps its sign through the text format, yet equals 0.0
         assert_eq!(
-            array,
+            Spi::get_one::<String>("SELECT fs_number_from_double('-0'::float8)::text"),
+            Ok(Some(r#"{"type":"NUMBER","value":-0.0}"#.to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT fs_number_to_double(fs_number_from_double('-0'::float8)::text::fsvalue)::text"
+            ),
+            Ok(Some("-0".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_number_from_double('-0'::float8) = fs_number_from_double(0) \
+                 AND fs_number_from_double('-0'::float8) #= fs_number_from_double(0) \
+                 AND fs_hash(fs_number_from_double('-0'::float8)) = fs_hash(fs_number_from_double(0))"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_number_to_bigint() {
+        assert_eq!(
+            fs_number_to_bigint(fs_number_from_bigint(i64::MIN)),
+            i64::MIN
+        );
+        assert_eq!(fs_number_to_double(fs_number_from_integer(3)), 3.0);
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT fs_number_to_bigint(fs_number_from_integer(-7))"),
+            Ok(Some(-7))
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an integer but found 1.0")]
+    fn test_fs_number_to_bigint_of_double() {
+        fs_number_to_bigint(fs_number_from_double(1.0));
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an integer but found NaN")]
+    fn test_fs_number_to_bigint_of_nan() {
+        fs_number_to_bigint(fs_nan());
+    }
+
+    #[pg_test(error = "InvalidValue: Integer 18446744073709551615 is out of range for type bigint")]
+    fn test_fs_number_to_bigint_out_of_range() {
+        fs_number_to_bigint(FsValue::Number(FsNumber::Number(u64::MAX.into())));
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a number but found \"1\"")]
+    fn test_fs_number_to_double_of_string() {
+        fs_number_to_double(fs_string("1"));
+    }
+
+    #[test]
+    fn test_number_value_rejects_other_strings() {
+        for text in ["abc", "", "nan", "inf"] {
+            assert!(
+                matches!(
+                    FsValue::from_number_value(&json!(text)),
+                    Err(FsError::InvalidValue(_))
+                ),
+                "{}",
+                text
+            );
+        }
+        assert_eq!(
+            FsValue::from_number_value(&json!(true)).map_err(|error| error.to_string()),
+            Err("InvalidValue: Expecting a JSON number or a string but found true".to_owned())
+        );
+    }
+
+    #[pg_test]
+    fn test_arithmetic_operators() {
+        let number = |expression: &str| {
+            Spi::get_one::<FsValue>(&format!("SELECT {}", expression))
+                .expect("SPI failed")
+                .expect("a number operand gives a number")
+        };
+        assert_eq!(
+            number("fs_number_from_integer(7) - fs_number_from_integer(10)"),
+            fs_number_from_integer(-3)
+        );
+        assert_eq!(
+            number("fs_number_from_integer(-7) * fs_number_from_integer(6)"),
+            fs_number_from_integer(-42)
+        );
+        assert_eq!(
+            number("fs_number_from_integer(2) * fs_number_from_double(0.5)"),
+            fs_number_from_double(1.0)
+        );
+        // Integers saturate
+        assert_eq!(
+            number("fs_number_from_bigint(9223372036854775807) + fs_number_from_integer(1)"),
+            fs_number_from_bigint(i64::MAX)
+        );
+        assert_eq!(
+            number("fs_number_from_bigint(-9223372036854775808) - fs_number_from_integer(1)"),
+            fs_number_from_bigint(i64::MIN)
+        );
+        assert_eq!(
+            number("fs_number_from_bigint(9223372036854775807) * fs_number_from_integer(-2)"),
+            fs_number_from_bigint(i64::MIN)
+        );
+        // NaN and opposite infinities give NaN
+        for expression in [
+            "fs_nan() + fs_number_from_integer(1)",
+            "fs_number_from_integer(1) * fs_nan()",
+            "fs_number_from_double('-Infinity') + fs_number_from_double('Infinity')",
+            "fs_number_from_double('Infinity') - fs_number_from_double('Infinity')",
+            "fs_number_from_double('Infinity') * fs_number_from_integer(0)",
+        ] {
+            assert_eq!(number(expression), fs_nan(), "{}", expression);
+        }
+        assert_eq!(
+            number("fs_number_from_integer(1) - fs_number_from_double('Infinity')"),
+            fs_number_from_double(f64::NEG_INFINITY)
+        );
+        // A base that is missing or not a number counts as 0, like in the
+        // increment transform
+        assert_eq!(
+            number("fs_string('a') + fs_number_from_integer(1)"),
+            fs_number_from_integer(1)
+        );
+        assert_eq!(
+            number("NULL::fsvalue - fs_number_from_integer(1)"),
+            fs_number_from_integer(-1)
+        );
+        assert_eq!(
+            number("fs_null() * fs_number_from_integer(5)"),
+            fs_number_from_integer(0)
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>("SELECT fs_number_from_integer(1) + NULL::fsvalue"),
+            Ok(None)
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a number but found \"7\"")]
+    fn test_fs_subtract_not_a_number() {
+        fs_subtract(Some(fs_number_from_integer(2)), Some(fs_string("7")));
+    }
+
+    #[pg_test]
+    fn test_fs_increment() {
+        Spi::run(
+            "SELECT fs_set(fs_reference('/counters/1'), \
+                 fs_map_from_entries(ARRAY['count', 'label'], \
+                     ARRAY[fs_number_from_integer(41), fs_string('a')]))",
+        )
+        .expect("SPI failed");
+        Spi::run(
+            "UPDATE fs_documents SET properties = fs_map_set( \
+                 fs_map_set(fs_map_set(properties, 'count', \
+                     fs_increment(properties->'count', fs_number_from_integer(1))), \
+                 'label', fs_increment(properties->'label', fs_number_from_double(0.5))), \
+                 'missing', fs_increment(properties->'missing', fs_number_from_integer(2))) \
+             WHERE reference = fs_reference('/counters/1')",
+        )
+        .expect("SPI failed");
+        let field = |name: &str| {
+            Spi::get_one::<FsValue>(&format!(
+                "SELECT properties->'{}' FROM fs_documents \
+                 WHERE reference = fs_reference('/counters/1')",
+                name
+            ))
+            .expect("SPI failed")
+        };
+        assert_eq!(field("count"), Some(fs_number_from_integer(42)));
+        // A field that is not a number, or is missing, counts as 0
+        assert_eq!(field("label"), Some(fs_number_from_double(0.5)));
+        assert_eq!(field("missing"), Some(fs_number_from_integer(2)));
+        assert_eq!(
+            fs_increment(
+                Some(fs_number_from_bigint(i64::MAX)),
+                Some(fs_number_from_integer(1))
+            ),
+            Some(fs_number_from_bigint(i64::MAX))
+        );
+        assert_eq!(
+            fs_increment(Some(fs_nan()), Some(fs_number_from_integer(1))),
+            Some(fs_nan())
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a number but found \"1\"")]
+    fn test_fs_increment_by_not_a_number() {
+        fs_increment(Some(fs_number_from_integer(1)), Some(fs_string("1")));
+    }
+
+    #[pg_test]
+    fn test_fs_mod_and_idiv() {
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "select fs_number_from_integer(-7) % fs_number_from_integer(3)"
+            ),
+            Ok(Some(fs_number_from_integer(-1)))
+        );
+        assert_eq!(
+            fs_mod(fs_number_from_double(7.5), fs_number_from_integer(2)),
+            fs_number_from_double(1.5)
+        );
+        assert_eq!(
+            fs_mod(fs_number_from_double(1.5), fs_number_from_integer(0)),
+            fs_nan()
+        );
+        assert_eq!(
+            fs_idiv(fs_number_from_integer(-7), fs_number_from_integer(2)),
+            fs_number_from_integer(-3)
+        );
+    }
+
+    #[pg_test(error = "division by zero")]
+    fn test_fs_mod_integer_by_zero() {
+        fs_mod(fs_number_from_integer(1), fs_number_from_integer(0));
+    }
+
+    #[pg_test(error = "division by zero")]
+    fn test_fs_idiv_integer_by_zero() {
+        fs_idiv(fs_number_from_integer(1), fs_number_from_integer(0));
+    }
+
+    #[pg_test(error = "InvalidType: Expecting numbers but found \"7\" and 2")]
+    fn test_fs_mod_not_a_number() {
+        fs_mod(fs_string("7"), fs_number_from_integer(2));
+    }
+
+    #[pg_test]
+    fn test_fs_string() {
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                r#"select '{"type": "STRING", "value": "hello world"}'::fsvalue"#
+            ),
+            Ok(Some(fs_string("hello world")))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_reference() {
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                r#"select '{"type": "REFERENCE", "value": "/users/1"}'::fsvalue"#
+            ),
+            Ok(Some(fs_reference("/users/1")))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_reference_text() {
+        assert_eq!(fs_reference_text(fs_reference("/users/1")), "/users/1");
+        assert_eq!(
+            Spi::get_one::<String>("select fs_reference_text(fs_reference('/users/1/posts/2'))"),
+            Ok(Some("/users/1/posts/2".to_owned()))
+        );
+        assert!(std::panic::catch_unwind(|| fs_reference_text(fs_string("/users/1"))).is_err());
+    }
+
+    #[pg_test]
+    fn test_fs_reference_keeps_whole_ids() {
+        assert_eq!(
+            Spi::get_one::<String>("select fs_reference_text(fs_reference('/users/1?foo'))"),
+            Ok(Some("/users/1?foo".to_owned()))
+        );
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             VALUES (fs_reference('/users/a#b'), fs_empty_map())",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents WHERE reference = fs_reference('/users/a')"
+            ),
+            Ok(Some(0))
+        );
+    }
+
+    #[pg_test(error = "InvalidValue: Invalid reference '/users//1': Segment 2 is empty")]
+    fn test_fs_reference_empty_segment() {
+        Spi::get_one::<FsValue>("select fs_reference('/users//1')").expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Invalid reference '/users/..': Segment 2 '..' is a reserved ID"
+    )]
+    fn test_fs_reference_reserved_id() {
+        Spi::get_one::<FsValue>(r#"select '{"type": "REFERENCE", "value": "/users/.."}'::fsvalue"#)
+            .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_reference_builders() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES \
+                 (fs_reference('/users/a b'), fs_empty_map()), \
+                 (fs_reference('/users/a b/posts/1'), fs_empty_map()), \
+                 (fs_reference('/users/a b/posts/x%2Fy'), fs_empty_map()), \
+                 (fs_reference('/users/c/posts/1'), fs_empty_map())",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(fs_document_id(d.reference), ',' ORDER BY d.reference) \
+                 FROM (VALUES ('a b', '1'), ('a b', 'x/y'), ('c', '2')) AS ids(uid, pid) \
+                 JOIN fs_documents d \
+                     ON d.reference = fs_child(fs_child(fs_database_root(), 'users', uid), \
+                                               'posts', pid)"
+            ),
+            Ok(Some("1,x/y".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents \
+                 WHERE fs_is_ancestor( \
+                     fs_collection_ref(fs_child(fs_database_root(), 'users', 'a b'), 'posts'), \
+                     reference)"
+            ),
+            Ok(Some(2))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT fs_reference_text(fs_collection_ref(fs_reference('/users/1'), 'posts'))"
+            ),
+            Ok(Some("/users/1/posts".to_owned()))
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Cannot append to /users: Path ends in collection 'users' without a document ID"
+    )]
+    fn test_fs_child_of_collection() {
+        Spi::get_one::<FsValue>("SELECT fs_child(fs_reference('/users'), 'posts', '1')")
+            .expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidValue: Cannot append to /users/1: Segment 3 is empty")]
+    fn test_fs_collection_ref_empty_id() {
+        Spi::get_one::<FsValue>("SELECT fs_collection_ref(fs_reference('/users/1'), '')")
+            .expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "LimitExceeded: Cannot append to /c/d/c/d: Path is 3 levels deep, more than the limit of 2"
+    )]
+    fn test_fs_child_depth_limit() {
+        Spi::run("SET LOCAL pgfirestore.max_reference_depth = 2").expect("SPI failed");
+        Spi::get_one::<FsValue>(
+            "SELECT fs_child(fs_child(fs_child(fs_database_root(), 'c', 'd'), 'c', 'd'), 'c', 'd')",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidValue: The database root has no document ID")]
+    fn test_fs_document_id_of_root() {
+        Spi::get_one::<String>("SELECT fs_document_id(fs_database_root())").expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidValue: /users is a collection reference and has no document ID")]
+    fn test_fs_document_id_of_collection() {
+        Spi::get_one::<String>("SELECT fs_document_id(fs_reference('/users'))")
+            .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_reference_shorthand_input() {
+        Spi::run("SET LOCAL pgfirestore.input_mode = 'auto'").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<FsValue>("select '/users/1/posts/2'::fsvalue"),
+            Ok(Some(fs_reference("/users/1/posts/2")))
+        );
+        assert_eq!(
+            Spi::get_one::<String>("select fs_reference_text('/users/1'::fsvalue)"),
+            Ok(Some("/users/1".to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                r#"select '{"type": "REFERENCE", "value": "/users/1"}'::fsvalue"#
+            ),
+            Ok(Some(fs_reference("/users/1")))
+        );
+    }
+
+    #[pg_test(error = "Bare reference input '/users/1' requires pgfirestore.input_mode = 'auto'")]
+    fn test_fs_reference_shorthand_input_strict() {
+        Spi::run("SET LOCAL pgfirestore.input_mode = 'strict'").expect("SPI failed");
+        Spi::get_one::<FsValue>("select '/users/1'::fsvalue").expect("SPI failed");
+    }
+
+    #[test]
+    fn test_check_json_depth() {
+        let nested = |depth: usize| {
+            let mut value = json!({"type": "NULL", "value": null});
+            for _ in 0..depth {
+                value = json!({"type": "ARRAY", "value": [value]});
+            }
+            value
+        };
+        // Every ARRAY takes two levels: its object and its value
+        assert!(FsValue::from(nested(63)).is_ok());
+        assert_eq!(
+            FsValue::from(nested(64)).unwrap_err().to_string(),
+            "LimitExceeded: JSON is nested more than 128 levels deep"
+        );
+        assert!(check_json_depth(&json!([[[1]]])).is_ok());
+    }
+
+    // How arrays and maps were parsed before their elements were moved out of
+    // the JSON rather than copied. Everything else goes through the current
+    // parser.
+    fn from_typed_json_by_copy(json_value: Value) -> Result<FsValue> {
+        match (
+            json_value.get("type").and_then(Value::as_str),
+            json_value.get("value"),
+        ) {
+            (Some("ARRAY"), Some(Value::Array(array_value))) => {
+                let mut fs_array_value = Vec::new();
+                for array_element in array_value.iter() {
+                    fs_array_value.push(from_typed_json_by_copy(array_element.to_owned())?);
+                }
+                FsValue::check_array_nesting(&fs_array_value)?;
+                Ok(FsValue::Array(fs_array_value))
+            }
+            (Some("MAP"), Some(Value::Object(map_value))) => {
+                let mut fs_map_value = BTreeMap::new();
+                for (key, value) in map_value.iter() {
+                    fs_map_value.insert(key.to_owned(), from_typed_json_by_copy(value.to_owned())?);
+                }
+                Ok(FsValue::Map(fs_map_value))
+            }
+            _ => FsValue::from_typed_json(json_value),
+        }
+    }
+
+    // The value, or the message of the error or panic
+    fn parse_outcome(
+        parse: fn(Value) -> Result<FsValue>,
+        json_value: &Value,
+    ) -> std::result::Result<FsValue, String> {
+        let json_value = json_value.to_owned();
+        match std::panic::catch_unwind(move || parse(json_value)) {
+            Ok(result) => result.map_err(|error| error.to_string()),
+            Err(payload) => Err(payload
+                .downcast::<String>()
+                .map(|message| *message)
+                .unwrap_or_default()),
+        }
+    }
+
+    #[test]
+    fn test_from_typed_json_matches_copying_parser() {
+        let typed = |fs_type: &str, value: Value| json!({"type": fs_type, "value": value});
+        let typed_null = typed("NULL", Value::Null);
+        let elements = |count: usize, bad_index: Option<usize>, bad: &Value| {
+            (0..count)
+                .map(|index| match bad_index {
+                    Some(bad_index) if bad_index == index => bad.to_owned(),
+                    _ => typed("NUMBER", json!(index)),
+                })
+                .collect::<Vec<_>>()
+        };
+        let entries = |count: usize, bad_index: Option<usize>, bad: &Value| {
+            elements(count, bad_index, bad)
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| (format!("key{:05}", index), value))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let mut corpus = vec![
+            typed("ARRAY", json!([])),
+            typed("MAP", json!({})),
+            typed("ARRAY", json!({"a": null})),
+            typed("MAP", json!([null])),
+            typed("MAP", json!("x")),
+            json!({"type": "ARRAY"}),
+            json!({"value": []}),
+            typed("ARRAY", json!([[1]])),
+            typed("ARRAY", json!([typed_null, typed("ARRAY", json!([]))])),
+            typed(
+                "MAP",
+                json!({"a": typed("ARRAY", json!([typed("MAP", json!({"b": typed("BYTES", json!("AP8="))}))]))}),
+            ),
+        ];
+        // The first invalid element wins, wherever it is
+        let invalid = [
+            typed("STRING", json!(1)),
+            typed("UNKNOWN", json!(1)),
+            json!({"type": "NUMBER"}),
+            json!(true),
+            json!({"type": 7, "value": 1}),
+            typed("ARRAY", json!("x")),
+        ];
+        for bad in &invalid {
+            for bad_index in [None, Some(0), Some(500), Some(999)] {
+                corpus.push(typed("ARRAY", Value::Array(elements(1000, bad_index, bad))));
+                corpus.push(typed("MAP", Value::Object(entries(1000, bad_index, bad))));
+            }
+        }
+        corpus.push(typed(
+            "ARRAY",
+            json!([
+                typed("MAP", Value::Object(entries(10, Some(3), &invalid[0]))),
+                typed("ARRAY", Value::Array(elements(10, Some(3), &invalid[1])))
+            ]),
+        ));
+        for json_value in &corpus {
+            assert_eq!(
+                parse_outcome(FsValue::from_typed_json, json_value),
+                parse_outcome(from_typed_json_by_copy, json_value),
+                "{}",
+                json_value
+            );
+        }
+    }
+
+    #[pg_test(
+        error = "Failed to parse cstring as a serde_json object: recursion limit exceeded at line 1 column 128"
+    )]
+    fn test_fs_deeply_nested_input() {
+        Spi::get_one::<FsValue>(&format!(
+            "select '{}{}'::fsvalue",
+            "[".repeat(10_000),
+            "]".repeat(10_000)
+        ))
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_reference_limits_guc() {
+        let deep = format!("/{}", vec!["c/d"; 101].join("/"));
+        Spi::run("SET LOCAL pgfirestore.max_reference_depth = 200").expect("SPI failed");
+        assert!(fs_reference(&deep).as_reference().is_some());
+    }
+
+    #[pg_test(error = "LimitExceeded: Path is 101 levels deep, more than the limit of 100")]
+    fn test_fs_reference_depth_limit() {
+        fs_reference(&format!("/{}", vec!["c/d"; 101].join("/")));
+    }
+
+    #[pg_test]
+    fn test_fs_bytes() {
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                r#"select concat('{"type": "BYTES", "value": "', encode('helloworld'::bytea, 'base64'), '"}')::fsvalue"#
+            ),
+            Ok(Some(FsValue::Bytes(vec![
+                0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x77, 0x6f, 0x72, 0x6c, 0x64
+            ])))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_array() {
+        let array = Spi::get_one::<FsValue>(
+            r#"select '{
+                "type": "ARRAY",
+                "value": [
+                    {"type": "NUMBER", "value": 1},
+                    {"type": "NULL", "value": null},
+                    {"type": "BOOLEAN", "value": true}
+                ]
+            }'::fsvalue;"#,
+        );
+
+        assert_eq!(
+            array,
             Ok(Some(FsValue::Array(vec![
                 fs_number_from_integer(1),
-                fs_null(),
-                fs_boolean(true)
+                fs_null(),
+                fs_boolean(true)
+            ])))
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Array element at index 1 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_fs_array_nesting_strict() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        Spi::get_one::<FsValue>(
+            r#"select '{
+                "type": "ARRAY",
+                "value": [
+                    {"type": "NULL", "value": null},
+                    {"type": "ARRAY", "value": [{"type": "NULL", "value": null}]}
+                ]
+            }'::fsvalue;"#,
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_fs_array_nesting() {
+        let nested_array = r#"select '{
+                "type": "ARRAY",
+                "value": [{"type": "ARRAY", "value": [{"type": "NULL", "value": null}]}]
+            }'::fsvalue;"#;
+        let array_in_map_in_array = r#"select '{
+                "type": "ARRAY",
+                "value": [{
+                    "type": "MAP",
+                    "value": {"foo": {"type": "ARRAY", "value": [{"type": "NULL", "value": null}]}}
+                }]
+            }'::fsvalue;"#;
+
+        Spi::run("SET LOCAL pgfirestore.strict_limits = off").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<FsValue>(nested_array),
+            Ok(Some(FsValue::Array(vec![FsValue::Array(vec![fs_null()])])))
+        );
+        assert!(Spi::get_one::<FsValue>(array_in_map_in_array).is_ok());
+        assert_eq!(
+            fs_array(vec![fs_array(vec![fs_null()])]),
+            FsValue::Array(vec![FsValue::Array(vec![fs_null()])])
+        );
+
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert!(Spi::get_one::<FsValue>(array_in_map_in_array).is_ok());
+        let result = std::panic::catch_unwind(|| fs_array(vec![FsValue::Array(vec![fs_null()])]));
+        assert!(result.is_err());
+    }
+
+    #[pg_test]
+    fn test_fs_map() {
+        let map = Spi::get_one::<FsValue>(
+            r#"select '{
+                "type": "MAP",
+                "value": {
+                    "foo": {"type": "NUMBER", "value": 1},
+                    "bar": {"type": "NULL", "value": null},
+                    "baz": {"type": "BOOLEAN", "value": true}
+                }
+            }'::fsvalue;"#,
+        );
+
+        assert_eq!(
+            map,
+            Ok(Some(FsValue::Map(BTreeMap::from([
+                ("foo".to_owned(), fs_number_from_integer(1)),
+                ("bar".to_owned(), fs_null()),
+                ("baz".to_owned(), fs_boolean(true))
+            ]))))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_map_from_entries() {
+        let map = Spi::get_one::<FsValue>(
+            r#"select '{
+                "type": "MAP",
+                "value": {
+                    "foo": {"type": "NUMBER", "value": 1},
+                    "bar": {"type": "NULL", "value": null},
+                    "baz": {"type": "BOOLEAN", "value": true}
+                }
+            }'::fsvalue;"#,
+        );
+
+        assert_eq!(
+            map,
+            Ok(Some(fs_map_from_entries(
+                vec!["foo".to_owned(), "bar".to_owned(), "baz".to_owned()],
+                vec![fs_number_from_integer(1), fs_null(), fs_boolean(true)]
+            )))
+        );
+
+        let result =
+            std::panic::catch_unwind(|| fs_map_from_entries(vec!["foo".to_owned()], vec![]));
+        assert!(result.is_err());
+    }
+
+    #[pg_test]
+    fn test_fs_map_get() {
+        let map = FsValue::Map(BTreeMap::from([
+            ("foo".to_owned(), fs_number_from_integer(1)),
+            ("bar".to_owned(), fs_null()),
+            ("baz".to_owned(), fs_boolean(true)),
+            (
+                "qux".to_owned(),
+                FsValue::Map(BTreeMap::from([("foo".to_owned(), fs_null())])),
+            ),
+        ]));
+
+        assert_eq!(
+            fs_map_get(map.to_owned(), "foo"),
+            Some(fs_number_from_integer(1))
+        );
+        assert_eq!(
+            fs_map_get(fs_map_get(map.to_owned(), "qux").unwrap(), "foo"),
+            Some(fs_null())
+        );
+        assert_eq!(fs_map_get(map.to_owned(), "quxx"), None);
+    }
+
+    #[pg_test]
+    fn test_fs_map_mutators() {
+        let map = Spi::get_one::<FsValue>(
+            "SELECT fs_map_set( \
+                 fs_map(ARRAY['foo', 'bar', 'baz'], ARRAY[fs_number_from_integer(1), fs_null(), fs_boolean(true)]), \
+                 'qux', fs_map_set(fs_empty_map(), 'foo', fs_null()))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            map,
+            Some(FsValue::Map(BTreeMap::from([
+                ("foo".to_owned(), fs_number_from_integer(1)),
+                ("bar".to_owned(), fs_null()),
+                ("baz".to_owned(), fs_boolean(true)),
+                (
+                    "qux".to_owned(),
+                    FsValue::Map(BTreeMap::from([("foo".to_owned(), fs_null())])),
+                ),
+            ])))
+        );
+        let map = map.unwrap();
+        assert_eq!(
+            fs_map_keys(map.to_owned()),
+            vec!["bar", "baz", "foo", "qux"]
+        );
+        assert_eq!(
+            fs_map_set(map.to_owned(), "foo".to_owned(), fs_string("one"))
+                .as_map()
+                .unwrap()["foo"],
+            fs_string("one")
+        );
+        assert_eq!(
+            fs_map_keys(fs_map_remove(map.to_owned(), "qux")),
+            vec!["bar", "baz", "foo"]
+        );
+        assert_eq!(fs_map_remove(map.to_owned(), "missing"), map);
+        assert_eq!(fs_map_keys(fs_empty_map()), Vec::<String>::new());
+    }
+
+    #[pg_test(error = "InvalidValue: fs_map repeats the key 'a'")]
+    fn test_fs_map_repeated_key() {
+        fs_map(
+            vec!["a".to_owned(), "b".to_owned(), "a".to_owned()],
+            vec![fs_null(), fs_null(), fs_null()],
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: fs_map expects as many keys as values but found 2 keys and 1 values"
+    )]
+    fn test_fs_map_length_mismatch() {
+        fs_map(vec!["a".to_owned(), "b".to_owned()], vec![fs_null()]);
+    }
+
+    #[pg_test(error = "InvalidType: fs_map_set expects a map but found 1")]
+    fn test_fs_map_set_non_map() {
+        fs_map_set(fs_number_from_integer(1), "a".to_owned(), fs_null());
+    }
+
+    #[pg_test]
+    fn test_fs_get_or() {
+        let doc = fs_map_from_entries(
+            vec!["count".to_owned(), "gone".to_owned(), "stats".to_owned()],
+            vec![
+                fs_number_from_integer(3),
+                fs_null(),
+                fs_map_from_entries(vec!["views".to_owned()], vec![fs_number_from_integer(9)]),
+            ],
+        );
+        let zero = fs_number_from_integer(0);
+        assert_eq!(
+            fs_map_get_or(Some(doc.to_owned()), "count", zero.to_owned()),
+            fs_number_from_integer(3)
+        );
+        // A present NULL is returned rather than the default
+        assert_eq!(
+            fs_map_get_or(Some(doc.to_owned()), "gone", zero.to_owned()),
+            fs_null()
+        );
+        assert_eq!(
+            fs_map_get_or(Some(doc.to_owned()), "missing", zero.to_owned()),
+            zero
+        );
+        assert_eq!(
+            fs_get_field_or(Some(doc.to_owned()), "stats.views", zero.to_owned()),
+            fs_number_from_integer(9)
+        );
+        assert_eq!(
+            fs_get_field_or(Some(doc.to_owned()), "stats.likes.total", zero.to_owned()),
+            zero
+        );
+        assert_eq!(
+            fs_get_field_or(Some(doc.to_owned()), "count.total", zero.to_owned()),
+            zero
+        );
+        assert_eq!(
+            Spi::get_two::<FsValue, FsValue>(
+                "SELECT fs_map_get_or(NULL::fsvalue, 'count', fs_number_from_integer(0)), \
+                 fs_get_field_or(NULL::fsvalue, 'stats.views', fs_number_from_integer(0))"
+            ),
+            Ok((Some(zero.to_owned()), Some(zero)))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_keys_matching() {
+        let map = fs_map_from_entries(
+            vec![
+                "alice".to_owned(),
+                "albert".to_owned(),
+                "bob".to_owned(),
+                "a_b".to_owned(),
+            ],
+            vec![fs_null(), fs_null(), fs_null(), fs_null()],
+        );
+
+        assert_eq!(
+            fs_keys_matching(map.to_owned(), "al%").collect::<Vec<String>>(),
+            vec!["albert".to_owned(), "alice".to_owned()]
+        );
+        assert_eq!(
+            fs_keys_matching(map.to_owned(), "_o_").collect::<Vec<String>>(),
+            vec!["bob".to_owned()]
+        );
+        assert_eq!(
+            fs_keys_matching(map.to_owned(), "a\\_b").collect::<Vec<String>>(),
+            vec!["a_b".to_owned()]
+        );
+        assert_eq!(fs_keys_matching(map.to_owned(), "z%").count(), 0);
+        assert_eq!(fs_keys_matching(fs_string("alice"), "%").count(), 0);
+    }
+
+    #[pg_test]
+    fn test_fs_get_fields_matching() {
+        let scores = fs_map_from_entries(
+            vec!["bob".to_owned(), "alice".to_owned(), "carol".to_owned()],
+            vec![
+                fs_map_from_entries(vec!["total".to_owned()], vec![fs_number_from_integer(7)]),
+                fs_map_from_entries(
+                    vec!["total".to_owned(), "round".to_owned()],
+                    vec![fs_number_from_integer(10), fs_number_from_integer(1)],
+                ),
+                fs_string("n/a"),
+            ],
+        );
+        let dotted = fs_map_from_entries(
+            vec!["a.b".to_owned(), "c".to_owned()],
+            vec![fs_boolean(true), fs_boolean(false)],
+        );
+        let doc = fs_map_from_entries(
+            vec!["scores".to_owned(), "dotted".to_owned(), "list".to_owned()],
+            vec![
+                scores,
+                dotted,
+                FsValue::Array(vec![fs_map_from_entries(
+                    vec!["total".to_owned()],
+                    vec![fs_number_from_integer(1)],
+                )]),
+            ],
+        );
+
+        assert_eq!(
+            fs_get_fields_matching(doc.to_owned(), "scores.*.total").collect::<Vec<_>>(),
+            vec![
+                ("scores.alice.total".to_owned(), fs_number_from_integer(10)),
+                ("scores.bob.total".to_owned(), fs_number_from_integer(7)),
+            ]
+        );
+        assert_eq!(
+            fs_get_fields_matching(doc.to_owned(), "scores.alice.*").collect::<Vec<_>>(),
+            vec![
+                ("scores.alice.round".to_owned(), fs_number_from_integer(1)),
+                ("scores.alice.total".to_owned(), fs_number_from_integer(10)),
+            ]
+        );
+        assert_eq!(
+            fs_get_fields_matching(doc.to_owned(), "scores.*.missing").count(),
+            0
+        );
+        assert_eq!(fs_get_fields_matching(doc.to_owned(), "list.*").count(), 0);
+        assert_eq!(
+            fs_get_fields_matching(doc.to_owned(), "dotted.*").collect::<Vec<_>>(),
+            vec![
+                ("dotted.`a.b`".to_owned(), fs_boolean(true)),
+                ("dotted.c".to_owned(), fs_boolean(false)),
+            ]
+        );
+        assert_eq!(
+            fs_get_fields_matching(doc.to_owned(), "dotted.`a.b`").collect::<Vec<_>>(),
+            vec![("dotted.`a.b`".to_owned(), fs_boolean(true))]
+        );
+    }
+
+    fn redaction_doc() -> FsValue {
+        // {"name": "ann", "ssn": "123", "address": {"city": "x", "zip": "9"}}
+        fs_map_from_entries(
+            vec!["name".to_owned(), "ssn".to_owned(), "address".to_owned()],
+            vec![
+                fs_string("ann"),
+                fs_string("123"),
+                fs_map_from_entries(
+                    vec!["city".to_owned(), "zip".to_owned()],
+                    vec![fs_string("x"), fs_string("9")],
+                ),
+            ],
+        )
+    }
+
+    #[pg_test]
+    fn test_fs_redact_mask() {
+        let doc = redaction_doc();
+        let redacted = fs_redact(
+            doc.to_owned(),
+            vec!["address.zip".to_owned(), "missing.field".to_owned()],
+            "mask",
+            None,
+        );
+        assert_eq!(
+            fs_map_get(fs_map_get(redacted.to_owned(), "address").unwrap(), "zip"),
+            Some(fs_string("[REDACTED]"))
+        );
+        assert_eq!(fs_map_get(redacted.to_owned(), "missing"), None);
+        assert_eq!(
+            fs_map_get(redacted.to_owned(), "name"),
+            fs_map_get(doc.to_owned(), "name")
+        );
+        assert_eq!(
+            fs_map_get(fs_map_get(redacted.to_owned(), "address").unwrap(), "city"),
+            Some(fs_string("x"))
+        );
+
+        let replaced = fs_redact(doc, vec!["address.*".to_owned()], "mask", Some(fs_null()));
+        assert_eq!(
+            fs_map_get(replaced, "address"),
+            Some(fs_map_from_entries(
+                vec!["city".to_owned(), "zip".to_owned()],
+                vec![fs_null(), fs_null()]
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_redact_drop() {
+        let redacted = fs_redact(redaction_doc(), vec!["ssn".to_owned()], "drop", None);
+        assert_eq!(fs_map_get(redacted.to_owned(), "ssn"), None);
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "select fs_redact(fs_map_from_entries(ARRAY['ssn', 'name'], ARRAY[fs_string('1'), fs_string('ann')]), ARRAY['ssn'], 'drop')"
+            ),
+            Ok(Some(fs_map_from_entries(
+                vec!["name".to_owned()],
+                vec![fs_string("ann")]
+            )))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_redact_hash() {
+        let doc = redaction_doc();
+        let hashed = fs_redact(doc.to_owned(), vec!["ssn".to_owned()], "hash", None);
+        let other = fs_map_from_entries(vec!["ssn".to_owned()], vec![fs_string("123")]);
+        let other_hashed = fs_redact(other, vec!["ssn".to_owned()], "hash", None);
+        let hash = fs_map_get(hashed.to_owned(), "ssn").unwrap();
+        assert_eq!(Some(hash.to_owned()), fs_map_get(other_hashed, "ssn"));
+        match hash {
+            FsValue::String(hex) => assert_eq!(hex.len(), 64),
+            _ => panic!("Expecting a hex string"),
+        }
+        assert_ne!(
+            fs_map_get(hashed.to_owned(), "ssn"),
+            fs_map_get(
+                fs_redact(doc, vec!["name".to_owned()], "hash", None),
+                "name"
+            )
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Unknown redaction mode 'erase', expecting 'mask', 'drop' or 'hash'"
+    )]
+    fn test_fs_redact_unknown_mode() {
+        fs_redact(redaction_doc(), vec!["ssn".to_owned()], "erase", None);
+    }
+
+    #[pg_test]
+    fn test_fs_reference_range() {
+        let descendants = |ancestor: &str| {
+            Spi::connect(|client| {
+                let mut references = Vec::new();
+                for row in client.select(
+                    "SELECT fs_reference_text(d.reference) FROM fs_documents d, fs_reference_range(fs_reference($1)) r \
+                     WHERE d.reference > r.lower AND (r.upper IS NULL OR d.reference < r.upper) \
+                     ORDER BY d.reference",
+                    None,
+                    Some(vec![(PgBuiltInOids::TEXTOID.oid(), ancestor.into_datum())]),
+                )? {
+                    references.push(row.get::<String>(1)?.expect("reference must not be null"));
+                }
+                Ok::<Vec<String>, pgrx::spi::Error>(references)
+            })
+            .expect("SPI failed")
+        };
+        assert_eq!(
+            descendants("/users/1"),
+            vec!["/users/1/posts/1", "/users/1/posts/2"]
+        );
+        assert_eq!(descendants("/posts").len(), 2);
+        assert_eq!(descendants("/").len(), 9);
+    }
+
+    #[pg_test]
+    fn test_fs_descendants() {
+        let descendants = |prefix: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(fs_reference_text(reference), ',' ORDER BY reference) \
+                 FROM fs_descendants(fs_reference('{}'))",
+                prefix
+            ))
+            .expect("SPI failed")
+        };
+        assert_eq!(
+            descendants("/users/1"),
+            Some("/users/1/posts/1,/users/1/posts/2".to_owned())
+        );
+        assert_eq!(
+            descendants("/users/1/posts"),
+            Some("/users/1/posts/1,/users/1/posts/2".to_owned())
+        );
+        assert_eq!(descendants("/users/2"), None);
+        assert_eq!(descendants("/posts"), Some("/posts/1,/posts/2".to_owned()));
+        // Each matches the structural check of @>
+        for prefix in ["/", "/users", "/users/1", "/users/1/posts/1", "/posts"] {
+            assert_eq!(
+                Spi::get_one::<i64>(&format!(
+                    "SELECT count(*) FROM fs_descendants(fs_reference('{}'))",
+                    prefix
+                )),
+                Spi::get_one::<i64>(&format!(
+                    "SELECT count(*) FROM fs_documents WHERE fs_reference('{}') @> reference",
+                    prefix
+                )),
+                "{}",
+                prefix
+            );
+        }
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM fs_descendants(fs_database_root())"),
+            Ok(Some(9))
+        );
+    }
+
+    #[pg_test]
+    fn test_reference_ordering() {
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT string_agg(fs_reference_text(reference), ',' ORDER BY reference) \
+                 FROM (VALUES (fs_reference('/users/9')), (fs_reference('/users/10')), \
+                 (fs_reference('/users/1/posts/1')), (fs_reference('/users/1'))) \
+                 AS t(reference)"
+            ),
+            Ok(Some(
+                "/users/1,/users/1/posts/1,/users/10,/users/9".to_owned()
+            ))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT fs_reference('/users/10') < fs_reference('/users/9')"),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_is_ancestor() {
+        assert!(fs_is_ancestor(fs_reference("/"), fs_reference("/users/1")));
+        assert!(fs_is_ancestor(
+            fs_reference("/users/1"),
+            fs_reference("/users/1/posts")
+        ));
+        assert!(!fs_is_ancestor(
+            fs_reference("/users/1"),
+            fs_reference("/users/1")
+        ));
+        assert!(!fs_is_ancestor(
+            fs_reference("/users/1"),
+            fs_reference("/users/10")
+        ));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_reference('/users') @> fs_reference('/users/1/posts/2')"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_descendants_uses_primary_key() {
+        Spi::run("SET LOCAL enable_seqscan = off").expect("SPI failed");
+        for function in ["fs_descendants", "fs_descendants_v2"] {
+            let plan = explain(
+                &format!("SELECT * FROM {}(fs_reference('/users/1'))", function),
+                false,
+            );
+            assert!(plan.contains("Index Scan"), "{}", plan);
+            assert!(plan.contains("reference > "), "{}", plan);
+            assert!(plan.contains("reference < "), "{}", plan);
+            assert!(!plan.contains("Seq Scan"), "{}", plan);
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_resource_id_kind() {
+        let kinds = Spi::connect(|client| {
+            let mut kinds = Vec::new();
+            for row in client.select(
+                "SELECT DISTINCT fs_resource_id_kind(reference) FROM fs_collection(fs_database_root(), 'users')",
+                None,
+                None,
+            )? {
+                kinds.push(row.get::<String>(1)?.expect("kind must not be null"));
+            }
+            Ok::<Vec<String>, pgrx::spi::Error>(kinds)
+        })
+        .expect("SPI failed");
+        assert_eq!(kinds, vec!["numeric".to_owned()]);
+
+        let generated = format!("/users/{}", crate::fs_rest::generate_document_id());
+        assert!(fs_is_auto_id(fs_reference(&generated)));
+        assert_eq!(fs_resource_id_kind(fs_reference(&generated)), "auto");
+        assert!(!fs_is_auto_id(fs_reference("/users/jane.doe")));
+        assert_eq!(
+            fs_resource_id_kind(fs_reference("/users/jane.doe")),
+            "custom"
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting a document reference but found /users")]
+    fn test_fs_is_auto_id_collection_reference() {
+        fs_is_auto_id(fs_reference("/users"));
+    }
+
+    #[test]
+    fn test_document_key_error() {
+        assert_eq!(document_key_error(&fs_reference("/users/1")), None);
+        assert_eq!(
+            document_key_error(&fs_reference("/users")),
+            Some(
+                "Document key /users is missing a document ID after collection 'users'".to_owned()
+            )
+        );
+        assert_eq!(
+            document_key_error(&fs_database_root()),
+            Some("Document key must not be the database root".to_owned())
+        );
+        assert_eq!(
+            document_key_error(&fs_boolean(true)),
+            Some("Document key must be a REFERENCE but found BOOLEAN".to_owned())
+        );
+    }
+
+    #[pg_test(error = "Document key /users is missing a document ID after collection 'users'")]
+    fn test_insert_collection_reference() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             VALUES (fs_reference('/users'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(error = "Document key must not be the database root")]
+    fn test_insert_root_reference() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             VALUES (fs_database_root(), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(error = "Document key must be a REFERENCE but found STRING")]
+    fn test_insert_non_reference_key() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) \
+             VALUES (fs_string('/users/1'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_reference_case_sensitivity() {
+        // Both documents are stored side by side in the primary key
+        Spi::run(
+            "SELECT fs_set(fs_reference('/users/Bob'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[])); \
+             SELECT fs_set(fs_reference('/users/bob'), fs_map_from_entries(ARRAY[]::text[], ARRAY[]::fsvalue[]))",
+        )
+        .expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents WHERE fs_reference_equal_fold(reference, fs_reference('/USERS/BOB'))"
+            )
+            .expect("SPI failed"),
+            Some(2)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_reference('/users/Bob') = fs_reference('/users/bob') \
+                 OR NOT fs_reference('/users/Bob') < fs_reference('/users/bob')"
+            )
+            .expect("SPI failed"),
+            Some(false)
+        );
+        assert!(fs_reference_equal_fold(
+            fs_reference("/users/Bob"),
+            fs_reference("/users/bob")
+        ));
+        assert!(!fs_reference_equal_fold(
+            fs_reference("/users/Émile"),
+            fs_reference("/users/émile")
+        ));
+    }
+
+    #[pg_test]
+    fn test_fs_reference_matches() {
+        assert!(fs_reference_matches(
+            fs_reference("/users/1/posts/2"),
+            "/users/{uid}/posts/{postId}"
+        ));
+        assert!(fs_reference_matches(fs_reference("/users/1"), "/users/1"));
+        assert!(!fs_reference_matches(
+            fs_reference("/users/1"),
+            "/users/{uid}/posts/{postId}"
+        ));
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT fs_reference_matches(fs_reference('/users/1/posts/2'), '/users/{document=**}')"
+            ),
+            Ok(Some(true))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_reference_extract() {
+        assert_eq!(
+            fs_reference_extract(
+                fs_reference("/users/1/posts/2"),
+                "/users/{uid}/posts/{postId}"
+            )
+            .map(|bindings| bindings.0),
+            Some(json!({"uid": "1", "postId": "2"}))
+        );
+        assert_eq!(
+            fs_reference_extract(fs_reference("/users/1/posts/2"), "/users/{uid}/{rest=**}")
+                .map(|bindings| bindings.0),
+            Some(json!({"uid": "1", "rest": "posts/2"}))
+        );
+        assert!(fs_reference_extract(fs_reference("/users/1/posts/2"), "/users/{uid}").is_none());
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Invalid reference pattern '/users/{uid}x': wildcards must span a whole segment but found '{uid}x'"
+    )]
+    fn test_fs_reference_matches_invalid_pattern() {
+        fs_reference_matches(fs_reference("/users/1"), "/users/{uid}x");
+    }
+
+    #[pg_test]
+    fn test_output_style() {
+        let bytes_text = "SELECT fs_bytes('\\x00ff10'::bytea)::text";
+        let canonical = r#"{"type":"BYTES","value":"AP8Q"}"#;
+        let readable = r#"{"encoding":"hex","type":"BYTES","value":"0x00ff10"}"#;
+        assert_eq!(
+            Spi::get_one::<String>(bytes_text),
+            Ok(Some(canonical.to_owned()))
+        );
+
+        Spi::run("SET LOCAL pgfirestore.output_style = 'readable'").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<String>(bytes_text),
+            Ok(Some(readable.to_owned()))
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT fs_array(ARRAY[fs_bytes(''::bytea), fs_number_from_double(1.0)])::text"
+            ),
+            Ok(Some(
+                r#"{"type":"ARRAY","value":[{"encoding":"hex","type":"BYTES","value":"0x"},{"type":"NUMBER","value":1.0}]}"#
+                    .to_owned()
+            ))
+        );
+        let truncated =
+            Spi::get_one::<String>("SELECT fs_bytes(decode(repeat('ab', 100), 'hex'))::text")
+                .expect("SPI failed")
+                .expect("expecting a value");
+        assert!(truncated.contains(&format!("\"0x{}…\"", "ab".repeat(64))));
+        assert!(truncated.contains("\"length\":100"));
+        // Both renderings are accepted as input in any style
+        assert_eq!(
+            Spi::get_one::<FsValue>(&format!("SELECT '{}'::fsvalue", readable)),
+            Ok(Some(fs_bytes(vec![0x00, 0xff, 0x10])))
+        );
+        assert_eq!(
+            Spi::get_one::<FsValue>(&format!("SELECT '{}'::fsvalue", canonical)),
+            Ok(Some(fs_bytes(vec![0x00, 0xff, 0x10])))
+        );
+        // fs_canonical_text ignores the output style
+        assert_eq!(
+            fs_bytes(vec![0x00, 0xff, 0x10]).canonical_text(),
+            canonical.to_owned()
+        );
+
+        Spi::run("SET LOCAL pgfirestore.output_style = 'canonical'").expect("SPI failed");
+        assert_eq!(
+            Spi::get_one::<String>(bytes_text),
+            Ok(Some(canonical.to_owned()))
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Cannot parse truncated bytes {\"encoding\":\"hex\",\"length\":100,\"type\":\"BYTES\",\"value\":\"0xab…\"}"
+    )]
+    fn test_output_style_truncated_input() {
+        Spi::get_one::<FsValue>(
+            r#"SELECT '{"type":"BYTES","encoding":"hex","value":"0xab…","length":100}'::fsvalue"#,
+        )
+        .expect("SPI failed");
+    }
+
+    fn integers(range: std::ops::Range<i32>) -> Vec<FsValue> {
+        range.map(fs_number_from_integer).collect()
+    }
+
+    #[pg_test]
+    fn test_fs_array_chunk() {
+        assert_eq!(
+            fs_array_chunk(FsValue::Array(integers(0..7)), 3),
+            FsValue::Array(vec![
+                FsValue::Array(integers(0..3)),
+                FsValue::Array(integers(3..6)),
+                FsValue::Array(integers(6..7)),
+            ])
+        );
+        assert_eq!(
+            fs_array_chunk(FsValue::Array(integers(0..2)), 5),
+            FsValue::Array(vec![FsValue::Array(integers(0..2))])
+        );
+        assert_eq!(
+            fs_array_chunk(FsValue::Array(vec![]), 3),
+            FsValue::Array(vec![])
+        );
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        assert_eq!(
+            fs_array_chunk(FsValue::Array(vec![]), 3),
+            FsValue::Array(vec![])
+        );
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Cannot chunk an array: Array element at index 0 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_fs_array_chunk_strict_limits() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        fs_array_chunk(FsValue::Array(integers(0..7)), 3);
+    }
+
+    #[pg_test(error = "InvalidValue: Chunk size must be positive but found 0")]
+    fn test_fs_array_chunk_zero_size() {
+        fs_array_chunk(FsValue::Array(integers(0..7)), 0);
+    }
+
+    #[pg_test(error = "InvalidValue: Chunk size must be positive but found -1")]
+    fn test_fs_array_chunk_negative_size() {
+        fs_array_chunk(FsValue::Array(integers(0..7)), -1);
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an array but found \"a\"")]
+    fn test_fs_array_chunk_not_an_array() {
+        fs_array_chunk(fs_string("a"), 1);
+    }
+
+    #[pg_test]
+    fn test_fs_array_flatten() {
+        // [0, [1, [2, [3]]], 4]
+        let nested = FsValue::Array(vec![
+            fs_number_from_integer(0),
+            FsValue::Array(vec![
+                fs_number_from_integer(1),
+                FsValue::Array(vec![
+                    fs_number_from_integer(2),
+                    FsValue::Array(integers(3..4)),
+                ]),
+            ]),
+            fs_number_from_integer(4),
+        ]);
+        assert_eq!(
+            fs_array_flatten(nested.to_owned(), 1),
+            FsValue::Array(vec![
+                fs_number_from_integer(0),
+                fs_number_from_integer(1),
+                FsValue::Array(vec![
+                    fs_number_from_integer(2),
+                    FsValue::Array(integers(3..4)),
+                ]),
+                fs_number_from_integer(4),
+            ])
+        );
+        assert_eq!(
+            fs_array_flatten(nested.to_owned(), 2),
+            FsValue::Array(vec![
+                fs_number_from_integer(0),
+                fs_number_from_integer(1),
+                fs_number_from_integer(2),
+                FsValue::Array(integers(3..4)),
+                fs_number_from_integer(4),
+            ])
+        );
+        assert_eq!(
+            fs_array_flatten(nested.to_owned(), 10),
+            FsValue::Array(integers(0..5))
+        );
+        assert_eq!(fs_array_flatten(nested.to_owned(), 0), nested);
+        // Nothing to flatten, maps holding arrays included
+        let flat = FsValue::Array(vec![
+            fs_number_from_integer(0),
+            fs_map_from_entries(vec!["a".to_owned()], vec![FsValue::Array(integers(1..3))]),
+        ]);
+        assert_eq!(fs_array_flatten(flat.to_owned(), 1), flat);
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "SELECT fs_array_flatten(fs_array_chunk(fs_array(ARRAY[fs_string('a'), fs_string('b'), fs_string('c')]), 2))"
+            ),
+            Ok(Some(FsValue::Array(vec![
+                fs_string("a"),
+                fs_string("b"),
+                fs_string("c")
             ])))
         );
     }
 
-    #[pg_test]
-    fn test_fs_array_contains() {
-        let fs_array = FsValue::Array(vec![fs_number_from_integer(1), fs_null(), fs_boolean(true)]);
+    #[pg_test(error = "InvalidValue: Depth must not be negative but found -1")]
+    fn test_fs_array_flatten_negative_depth() {
+        fs_array_flatten(FsValue::Array(vec![]), -1);
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an array but found NULL")]
+    fn test_fs_array_flatten_not_an_array() {
+        fs_array_flatten(fs_null(), 1);
+    }
 
-        assert_eq!(fs_array_contains(fs_array.to_owned(), fs_null()), true);
+    fn point(x: i32, y: i32) -> FsValue {
+        fs_map_from_entries(
+            vec!["x".to_owned(), "y".to_owned()],
+            vec![fs_number_from_integer(x), fs_number_from_integer(y)],
+        )
+    }
+
+    #[pg_test]
+    fn test_fs_array_union() {
+        let base = FsValue::Array(vec![fs_number_from_integer(1), point(0, 0), fs_string("a")]);
         assert_eq!(
-            fs_array_contains(fs_array.to_owned(), fs_boolean(true)),
-            true
+            fs_array_union(
+                base.to_owned(),
+                FsValue::Array(vec![point(0, 0), point(0, 1), fs_string("b"), point(0, 1)])
+            ),
+            FsValue::Array(vec![
+                fs_number_from_integer(1),
+                point(0, 0),
+                fs_string("a"),
+                point(0, 1),
+                fs_string("b"),
+            ])
+        );
+        // Maps with the same entries are the same element, whatever order
+        // they were built in
+        let reordered = fs_map_from_entries(
+            vec!["y".to_owned(), "x".to_owned()],
+            vec![fs_number_from_integer(0), fs_number_from_integer(0)],
         );
         assert_eq!(
-            fs_array_contains(fs_array.to_owned(), fs_boolean(false)),
-            false
+            fs_array_union(base.to_owned(), FsValue::Array(vec![reordered])),
+            base
         );
+        // 1.0 is already there as 1
         assert_eq!(
-            fs_array_contains(fs_array.to_owned(), fs_number_from_integer(1)),
-            true
+            fs_array_union(
+                base.to_owned(),
+                FsValue::Array(vec![fs_number_from_double(1.0)])
+            ),
+            base
         );
+        // A base that is not an array counts as an empty one
         assert_eq!(
-            fs_array_contains(fs_array.to_owned(), fs_number_from_integer(2)),
-            false
+            fs_array_union(
+                fs_string("x"),
+                FsValue::Array(vec![point(1, 1), point(1, 1)])
+            ),
+            FsValue::Array(vec![point(1, 1)])
         );
         assert_eq!(
-            fs_array_contains_any(
-                fs_array.to_owned(),
-                vec![fs_number_from_integer(1), fs_number_from_integer(2)]
+            Spi::get_one::<i32>(
+                "SELECT fs_array_length(fs_array_union(properties, fs_array(ARRAY[ \
+                     fs_string('a'), fs_string('a')]))) \
+                 FROM fs_documents WHERE reference = fs_reference('/users/1')"
             ),
-            true
+            Ok(Some(1))
         );
     }
 
     #[pg_test]
-    fn test_fs_map() {
-        let map = Spi::get_one::<FsValue>(
-            r#"select '{
-                "type": "MAP",
-                "value": {
-                    "foo": {"type": "NUMBER", "value": 1},
-                    "bar": {"type": "NULL", "value": null},
-                    "baz": {"type": "BOOLEAN", "value": true}
-                }
-            }'::fsvalue;"#,
+    fn test_fs_array_remove() {
+        let base = FsValue::Array(vec![
+            point(0, 0),
+            fs_number_from_integer(1),
+            point(0, 1),
+            point(0, 0),
+            FsValue::Array(vec![point(0, 0)]),
+        ]);
+        assert_eq!(
+            fs_array_remove(
+                base.to_owned(),
+                FsValue::Array(vec![point(0, 0), fs_number_from_double(1.0)])
+            ),
+            FsValue::Array(vec![point(0, 1), FsValue::Array(vec![point(0, 0)])])
+        );
+        assert_eq!(
+            fs_array_remove(
+                base,
+                FsValue::Array(vec![FsValue::Array(vec![point(0, 0)])])
+            ),
+            FsValue::Array(vec![
+                point(0, 0),
+                fs_number_from_integer(1),
+                point(0, 1),
+                point(0, 0),
+            ])
+        );
+        assert_eq!(
+            fs_array_remove(fs_null(), FsValue::Array(vec![point(0, 0)])),
+            FsValue::Array(vec![])
         );
+    }
 
+    #[pg_test]
+    fn test_fs_array_length_and_concat() {
+        assert_eq!(fs_array_length(FsValue::Array(integers(0..3))), 3);
+        assert_eq!(fs_array_length(FsValue::Array(vec![])), 0);
         assert_eq!(
-            map,
-            Ok(Some(FsValue::Map(BTreeMap::from([
-                ("foo".to_owned(), fs_number_from_integer(1)),
-                ("bar".to_owned(), fs_null()),
-                ("baz".to_owned(), fs_boolean(true))
-            ]))))
+            fs_array_concat(
+                FsValue::Array(integers(0..2)),
+                FsValue::Array(integers(1..3))
+            ),
+            FsValue::Array(vec![
+                fs_number_from_integer(0),
+                fs_number_from_integer(1),
+                fs_number_from_integer(1),
+                fs_number_from_integer(2),
+            ])
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an array but found \"a\"")]
+    fn test_fs_array_union_non_array_additions() {
+        fs_array_union(FsValue::Array(vec![]), fs_string("a"));
+    }
+
+    #[pg_test(
+        error = "InvalidValue: Array element at index 1 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_fs_array_union_nesting_strict() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        fs_array_union(
+            FsValue::Array(vec![fs_null()]),
+            FsValue::Array(vec![FsValue::Array(vec![fs_null()])]),
         );
     }
 
+    #[pg_test(
+        error = "InvalidValue: Array element at index 0 is an array; Firestore does not support directly nested arrays"
+    )]
+    fn test_fs_array_concat_nesting_strict() {
+        Spi::run("SET LOCAL pgfirestore.strict_limits = on").expect("SPI failed");
+        fs_array_concat(
+            FsValue::Array(vec![]),
+            FsValue::Array(vec![FsValue::Array(vec![])]),
+        );
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an array but found 1")]
+    fn test_fs_array_length_not_an_array() {
+        fs_array_length(fs_number_from_integer(1));
+    }
+
     #[pg_test]
-    fn test_fs_map_from_entries() {
-        let map = Spi::get_one::<FsValue>(
-            r#"select '{
-                "type": "MAP",
-                "value": {
-                    "foo": {"type": "NUMBER", "value": 1},
-                    "bar": {"type": "NULL", "value": null},
-                    "baz": {"type": "BOOLEAN", "value": true}
-                }
-            }'::fsvalue;"#,
+    fn test_fs_pluck() {
+        let pluck = |keys: &str| {
+            Spi::get_one::<FsValue>(&format!(
+                "SELECT fs_pluck(fs_map_from_entries(ARRAY['a.b', 'a', '`c`'], ARRAY[ \
+                     fs_string('dotted'), \
+                     fs_map_from_entries(ARRAY['b'], ARRAY[fs_map_from_entries(ARRAY['c'], ARRAY[fs_null()])]), \
+                     fs_number_from_integer(1)]), {})",
+                keys
+            ))
+            .expect("SPI failed")
+        };
+        // fs_get_field would read 'a.b' as the path a, b
+        assert_eq!(pluck("'a.b'"), Some(fs_string("dotted")));
+        assert_eq!(pluck("'`c`'"), Some(fs_number_from_integer(1)));
+        assert_eq!(pluck("'a', 'b', 'c'"), Some(fs_null()));
+        assert_eq!(pluck("'a', 'missing', 'c'"), None);
+        assert_eq!(pluck("'a.b', 'c'"), None);
+        assert_eq!(pluck("'a', NULL"), None);
+        assert_eq!(
+            pluck("VARIADIC ARRAY[]::text[]")
+                .and_then(|doc| doc.get_field(&parse_field_names("`a.b`")).cloned()),
+            Some(fs_string("dotted"))
         );
+    }
+
+    #[test]
+    fn test_get_path() {
+        let doc = fs_map(
+            vec!["tags".to_owned(), "1".to_owned(), "weird.key".to_owned()],
+            vec![
+                fs_array(vec![
+                    fs_string("a"),
+                    fs_map(vec!["b".to_owned()], vec![fs_string("c")]),
+                ]),
+                fs_string("key"),
+                fs_map(vec!["inner".to_owned()], vec![fs_boolean(true)]),
+            ],
+        );
+        let get = |path: &str| doc.get_path(&parse_field_names(path)).cloned();
+        assert_eq!(get("tags.0"), Some(fs_string("a")));
+        assert_eq!(get("tags.1.b"), Some(fs_string("c")));
+        assert_eq!(get("`tags`.`1`.b"), Some(fs_string("c")));
+        assert_eq!(get("`weird.key`.inner"), Some(fs_boolean(true)));
+        // Digits are a key of a map
+        assert_eq!(get("1"), Some(fs_string("key")));
+        assert_eq!(get("tags.2"), None);
+        assert_eq!(get("tags.01"), None);
+        assert_eq!(get("tags.+1"), None);
+        assert_eq!(get("tags.-1"), None);
+        assert_eq!(get("tags.0.b"), None);
+        assert_eq!(doc.get_field(&parse_field_names("tags.0")), None);
+    }
 
+    #[pg_test]
+    fn test_fs_get_path_operator() {
+        Spi::run(
+            "INSERT INTO fs_documents (reference, properties) VALUES (fs_reference('/places/1'), \
+             fs_doc('address', fs_doc('city', fs_doc('zip', '75001', 'name', 'Paris')), \
+                    'tags', fs_arr('a', fs_doc('b', 1)), 'weird.key', fs_doc('inner', true)))",
+        )
+        .expect("SPI failed");
+        for (path, expected) in [
+            ("address.city.zip", Some(fs_string("75001"))),
+            ("tags.1.b", Some(fs_number_from_integer(1))),
+            ("`weird.key`.inner", Some(fs_boolean(true))),
+            ("address.country.zip", None),
+            ("address.city.zip.code", None),
+            ("tags.2", None),
+        ] {
+            assert_eq!(
+                Spi::get_one::<FsValue>(&format!(
+                    "SELECT properties #>> '{}' FROM fs_documents \
+                     WHERE reference = fs_reference('/places/1')",
+                    path
+                )),
+                Ok(expected),
+                "{}",
+                path
+            );
+        }
         assert_eq!(
-            map,
-            Ok(Some(fs_map_from_entries(
-                vec!["foo".to_owned(), "bar".to_owned(), "baz".to_owned()],
-                vec![fs_number_from_integer(1), fs_null(), fs_boolean(true)]
-            )))
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents WHERE properties #>> 'tags.0' #= fs_string('a')"
+            ),
+            Ok(Some(1))
         );
+    }
 
-        let result =
-            std::panic::catch_unwind(|| fs_map_from_entries(vec!["foo".to_owned()], vec![]));
-        assert!(result.is_err());
+    #[pg_test(error = "InvalidValue: Trailing '.' in field path 'address.'")]
+    fn test_fs_get_path_trailing_dot() {
+        Spi::get_one::<FsValue>("SELECT fs_doc('address', 1) #>> 'address.'").expect("SPI failed");
     }
 
     #[pg_test]
-    fn test_fs_map_get() {
-        let map = FsValue::Map(BTreeMap::from([
-            ("foo".to_owned(), fs_number_from_integer(1)),
-            ("bar".to_owned(), fs_null()),
-            ("baz".to_owned(), fs_boolean(true)),
+    fn test_fs_get_field_and_extractors() {
+        let doc = redaction_doc();
+        assert_eq!(
+            doc.get_field(&parse_field_names("address.zip")),
+            Some(&fs_string("9"))
+        );
+        assert_eq!(doc.get_field(&parse_field_names("address.missing")), None);
+        assert_eq!(fs_as_text(fs_string("ann")), Some("ann".to_owned()));
+        assert_eq!(
+            fs_as_text(fs_reference("/users/1")),
+            Some("/users/1".to_owned())
+        );
+        assert_eq!(fs_as_text(fs_number_from_integer(1)), None);
+        assert_eq!(fs_as_bigint(fs_number_from_integer(7)), Some(7));
+        assert_eq!(fs_as_bigint(fs_number_from_double(7.5)), None);
+        assert_eq!(fs_as_double(fs_number_from_double(7.5)), Some(7.5));
+        assert_eq!(fs_as_double(fs_number_from_integer(7)), Some(7.0));
+        assert_eq!(fs_as_boolean(fs_boolean(true)), Some(true));
+        assert_eq!(fs_as_boolean(fs_null()), None);
+        assert_eq!(
+            fs_as_text_array(fs_array(vec![fs_string("a"), fs_null()])),
+            Some(vec![Some("a".to_owned()), None])
+        );
+        assert_eq!(
+            fs_as_bigint_array(fs_array(vec![fs_number_from_integer(1)])),
+            Some(vec![Some(1)])
+        );
+        assert_eq!(fs_as_bigint_array(fs_string("a")), None);
+    }
+
+    #[pg_test]
+    fn test_map_ordering() {
+        use std::cmp::Ordering::{Equal, Greater, Less};
+
+        let one = || fs_number_from_integer(1);
+        let cases = vec![
+            // Same keys, the first differing value decides
             (
-                "qux".to_owned(),
-                FsValue::Map(BTreeMap::from([("foo".to_owned(), fs_null())])),
+                map(vec![("a", one()), ("b", one())]),
+                map(vec![("a", one()), ("b", fs_number_from_integer(2))]),
+                Less,
             ),
-        ]));
+            (
+                map(vec![("a", fs_null())]),
+                map(vec![("a", fs_boolean(false))]),
+                Less,
+            ),
+            // The first differing key decides, whatever the values
+            (
+                map(vec![("a", fs_number_from_integer(9))]),
+                map(vec![("b", fs_number_from_integer(0))]),
+                Less,
+            ),
+            (
+                map(vec![("a", one()), ("c", one())]),
+                map(vec![("a", one()), ("b", fs_string("z"))]),
+                Greater,
+            ),
+            // A strict prefix comes first
+            (
+                map(vec![("a", one())]),
+                map(vec![("a", one()), ("b", fs_null())]),
+                Less,
+            ),
+            // The empty map comes before any other map
+            (map(vec![]), map(vec![("", fs_null())]), Less),
+            (map(vec![]), map(vec![]), Equal),
+            // Keys compare by UTF-8 bytes: upper case before lower case and
+            // U+10000 after U+FFFF, unlike UTF-16 code units
+            (map(vec![("B", one())]), map(vec![("a", one())]), Less),
+            (map(vec![("a", one())]), map(vec![("A", one())]), Greater),
+            (
+                map(vec![("\u{ffff}", one())]),
+                map(vec![("\u{10000}", one())]),
+                Less,
+            ),
+            // Maps come after every other type
+            (fs_array(vec![]), map(vec![]), Less),
+        ];
+        for (lhs, rhs, expected) in cases {
+            assert_eq!(lhs.cmp(&rhs), expected, "{:?} vs {:?}", lhs, rhs);
+            assert_eq!(rhs.cmp(&lhs), expected.reverse(), "{:?} vs {:?}", rhs, lhs);
+            assert_eq!(lhs == rhs, expected == Equal);
+
+            // The SQL operators and btree opclass agree with Rust
+            let sql = Spi::get_one_with_args::<i32>(
+                "SELECT CASE WHEN $1 < $2 THEN -1 WHEN $1 = $2 THEN 0 WHEN $1 > $2 THEN 1 END",
+                vec![
+                    (
+                        PgOid::from(FsValue::type_oid()),
+                        lhs.to_owned().into_datum(),
+                    ),
+                    (
+                        PgOid::from(FsValue::type_oid()),
+                        rhs.to_owned().into_datum(),
+                    ),
+                ],
+            )
+            .expect("SPI failed");
+            assert_eq!(sql, Some(expected as i32), "{:?} vs {:?}", lhs, rhs);
+        }
+    }
+
+    #[pg_test]
+    fn test_fs_strip_nulls() {
+        let doc = fs_map_from_entries(
+            vec![
+                "a".to_owned(),
+                "b".to_owned(),
+                "c".to_owned(),
+                "d".to_owned(),
+            ],
+            vec![
+                fs_null(),
+                fs_number_from_integer(1),
+                fs_map_from_entries(
+                    vec!["x".to_owned(), "y".to_owned()],
+                    vec![fs_null(), fs_string("y")],
+                ),
+                FsValue::Array(vec![
+                    fs_null(),
+                    fs_map_from_entries(
+                        vec!["z".to_owned(), "w".to_owned()],
+                        vec![fs_null(), fs_boolean(true)],
+                    ),
+                ]),
+            ],
+        );
+        let stripped = fs_map_from_entries(
+            vec!["b".to_owned(), "c".to_owned(), "d".to_owned()],
+            vec![
+                fs_number_from_integer(1),
+                fs_map_from_entries(vec!["y".to_owned()], vec![fs_string("y")]),
+                FsValue::Array(vec![
+                    fs_null(),
+                    fs_map_from_entries(vec!["w".to_owned()], vec![fs_boolean(true)]),
+                ]),
+            ],
+        );
 
+        assert_eq!(fs_strip_nulls(doc.to_owned(), false), stripped);
+        assert_eq!(fs_strip_nulls(stripped.to_owned(), false), stripped);
         assert_eq!(
-            fs_map_get(map.to_owned(), "foo"),
-            Some(fs_number_from_integer(1))
+            fs_strip_nulls(FsValue::Array(vec![fs_null()]), false),
+            FsValue::Array(vec![fs_null()])
+        );
+        assert_eq!(fs_strip_nulls(fs_null(), true), fs_null());
+    }
+
+    #[pg_test]
+    fn test_fs_strip_nulls_prune_empty_maps() {
+        // {"keep": 1, "outer": {"inner": {"gone": null}}, "list": [{"gone": null}]}
+        let doc = fs_map_from_entries(
+            vec!["keep".to_owned(), "outer".to_owned(), "list".to_owned()],
+            vec![
+                fs_number_from_integer(1),
+                fs_map_from_entries(
+                    vec!["inner".to_owned()],
+                    vec![fs_map_from_entries(
+                        vec!["gone".to_owned()],
+                        vec![fs_null()],
+                    )],
+                ),
+                FsValue::Array(vec![fs_map_from_entries(
+                    vec!["gone".to_owned()],
+                    vec![fs_null()],
+                )]),
+            ],
         );
+
+        let pruned = fs_strip_nulls(doc.to_owned(), true);
         assert_eq!(
-            fs_map_get(fs_map_get(map.to_owned(), "qux").unwrap(), "foo"),
-            Some(fs_null())
+            pruned,
+            fs_map_from_entries(
+                vec!["keep".to_owned(), "list".to_owned()],
+                vec![
+                    fs_number_from_integer(1),
+                    FsValue::Array(vec![fs_map_from_entries(vec![], vec![])]),
+                ],
+            )
+        );
+        assert_eq!(fs_strip_nulls(pruned.to_owned(), true), pruned);
+        assert_eq!(
+            Spi::get_one::<FsValue>(
+                "select fs_strip_nulls(fs_map_from_entries(ARRAY['a'], ARRAY[fs_map_from_entries(ARRAY['b'], ARRAY[fs_null()])]), true)"
+            ),
+            Ok(Some(fs_map_from_entries(vec![], vec![])))
         );
-        assert_eq!(fs_map_get(map.to_owned(), "quxx"), None);
     }
 
     #[pg_test]
@@ -879,6 +4262,430 @@ mod tests {
         );
         assert_eq!(fs_neq(fs_number_from_integer(1), fs_string("foo")), true);
     }
+
+    #[pg_test]
+    fn test_fs_typeof() {
+        let values = [
+            FsValue::NULL,
+            fs_boolean(true),
+            fs_nan(),
+            fs_number_from_integer(1),
+            fs_string("a"),
+            fs_reference("/users/1"),
+            FsValue::Array(vec![]),
+            fs_empty_map(),
+        ];
+        for value in values {
+            // The tag and the `type` field of the text format stay in sync
+            assert_eq!(
+                Some(fs_typeof(value.to_owned()).as_str()),
+                value.to_json_value()["type"].as_str()
+            );
+            let tag = value.type_name();
+            assert_eq!(fs_is_null(value.to_owned()), tag == "NULL");
+            assert_eq!(fs_is_number(value.to_owned()), tag == "NUMBER");
+            assert_eq!(fs_is_string(value.to_owned()), tag == "STRING");
+            assert_eq!(fs_is_array(value.to_owned()), tag == "ARRAY");
+            assert_eq!(fs_is_map(value.to_owned()), tag == "MAP");
+            assert_eq!(fs_is_reference(value.to_owned()), tag == "REFERENCE");
+        }
+        assert_eq!(
+            Spi::get_one::<String>("SELECT fs_typeof(fs_geopoint(1, 2))"),
+            Ok(Some("GEOPOINT".to_owned()))
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_typeof_group_by() {
+        let counts = |expression: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(tag || '=' || count, ',' ORDER BY tag) FROM ( \
+                     SELECT fs_typeof({}) AS tag, count(*) FROM fs_documents GROUP BY 1) t",
+                expression
+            ))
+            .expect("SPI failed")
+        };
+        assert_eq!(counts("properties"), Some("MAP=9".to_owned()));
+        assert_eq!(
+            counts("COALESCE(properties->'foo', properties->'link')"),
+            Some("NUMBER=7,REFERENCE=2".to_owned())
+        );
+    }
+
+    #[pg_test]
+    fn test_fs_is_map_partial_index() {
+        Spi::run(
+            "CREATE TEMPORARY TABLE legacy (id integer, properties fsvalue); \
+             INSERT INTO legacy SELECT i, CASE WHEN i % 2 = 0 THEN fs_string('old') \
+                 ELSE fs_map_from_entries(ARRAY['id'], ARRAY[fs_number_from_integer(i)]) END \
+             FROM generate_series(1, 100) i; \
+             CREATE INDEX legacy_maps ON legacy (id) WHERE fs_is_map(properties); \
+             ANALYZE legacy; \
+             SET LOCAL enable_seqscan = off",
+        )
+        .expect("SPI failed");
+        let query = "SELECT count(*) FROM legacy WHERE fs_is_map(properties) AND id < 10";
+        let plan = explain(query, false);
+        assert!(plan.contains("legacy_maps"), "{}", plan);
+        assert_eq!(Spi::get_one::<i64>(query), Ok(Some(5)));
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM legacy WHERE fs_is_string(properties)"),
+            Ok(Some(50))
+        );
+    }
+
+    #[pg_test]
+    fn test_query_operators_nan() {
+        let query = |condition: &str| {
+            Spi::get_one::<bool>(&format!("SELECT {}", condition))
+                .expect("SPI failed")
+                .expect("the operators are never NULL here")
+        };
+        for operator in ["#=", "#<", "#<=", "#>", "#>="] {
+            for (lhs, rhs) in [
+                ("fs_nan()", "fs_nan()"),
+                ("fs_nan()", "fs_number_from_integer(1)"),
+                ("fs_number_from_integer(1)", "fs_nan()"),
+                ("fs_array(ARRAY[fs_nan()])", "fs_array(ARRAY[fs_nan()])"),
+                (
+                    "fs_map_from_entries(ARRAY['a'], ARRAY[fs_nan()])",
+                    "fs_map_from_entries(ARRAY['a'], ARRAY[fs_nan()])",
+                ),
+            ] {
+                let condition = format!("{} {} {}", lhs, operator, rhs);
+                assert!(!query(&condition), "{}", condition);
+            }
+        }
+        assert!(query("fs_nan() #!= fs_nan()"));
+        assert!(query("fs_nan() #!= fs_number_from_integer(1)"));
+        assert!(query(
+            "fs_array(ARRAY[fs_nan()]) #!= fs_array(ARRAY[fs_nan()])"
+        ));
+        assert!(!query("fs_null() #!= fs_nan()"));
+        assert!(query("fs_is_nan(fs_nan())"));
+        assert!(!query("fs_is_nan(fs_array(ARRAY[fs_nan()]))"));
+        assert!(query(
+            "fs_array(ARRAY[fs_number_from_integer(1), fs_nan()]) \
+             #< fs_array(ARRAY[fs_number_from_integer(2), fs_nan()])"
+        ));
+        assert!(!query(
+            "fs_array_contains(fs_array(ARRAY[fs_array(ARRAY[fs_nan()])]), fs_array(ARRAY[fs_nan()]))"
+        ));
+
+        // The total order keeps NaN a value equal to itself
+        assert!(query("fs_nan() = fs_nan()"));
+        assert!(query("fs_nan() < fs_number_from_integer(-1)"));
+        Spi::run(
+            "SELECT fs_set(fs_reference('/nan/1'), fs_map_from_entries(ARRAY['x'], ARRAY[fs_nan()])); \
+             SELECT fs_set(fs_reference('/nan/2'), fs_map_from_entries(ARRAY['x'], ARRAY[fs_number_from_integer(1)]))",
+        )
+        .expect("SPI failed");
+        let ids = |condition: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(fs_document_id(reference), ',' ORDER BY properties->'x') \
+                 FROM fs_collection(fs_database_root(), 'nan') WHERE {}",
+                condition
+            ))
+            .expect("SPI failed")
+        };
+        assert_eq!(ids("true"), Some("1,2".to_owned()));
+        assert_eq!(ids("properties->'x' #= fs_nan()"), None);
+        assert_eq!(ids("properties->'x' #!= fs_nan()"), Some("1,2".to_owned()));
+        assert_eq!(
+            ids("properties->'x' #>= fs_number_from_integer(0)"),
+            Some("2".to_owned())
+        );
+        assert_eq!(ids("fs_is_nan(properties->'x')"), Some("1".to_owned()));
+    }
+
+    #[pg_test]
+    fn test_fs_in() {
+        let query = |condition: &str| {
+            Spi::get_one::<bool>(&format!("SELECT {}", condition))
+                .expect("SPI failed")
+                .expect("fs_in and fs_not_in are never NULL")
+        };
+        let candidates = "fs_array(ARRAY[fs_number_from_integer(1), fs_string('a'), fs_null()])";
+        for (value, is_in, is_not_in) in [
+            ("fs_number_from_integer(1)", true, false),
+            ("fs_string('a')", true, false),
+            ("fs_null()", true, false),
+            ("fs_nan()", false, false),
+        ] {
+            assert_eq!(
+                query(&format!("fs_in({}, {})", value, candidates)),
+                is_in,
+                "{}",
+                value
+            );
+            assert_eq!(
+                query(&format!("fs_not_in({}, {})", value, candidates)),
+                is_not_in,
+                "{}",
+                value
+            );
+        }
+        // Without null among the candidates, NOT_IN matches every other value
+        // but null, NaN included
+        let candidates = "fs_array(ARRAY[fs_number_from_integer(1), fs_nan()])";
+        assert!(!query(&format!("fs_in(fs_nan(), {})", candidates)));
+        assert!(query(&format!("fs_not_in(fs_nan(), {})", candidates)));
+        assert!(query(&format!("fs_not_in(fs_string('a'), {})", candidates)));
+        assert!(!query(&format!("fs_not_in(fs_null(), {})", candidates)));
+        assert!(!query(&format!("fs_in(fs_null(), {})", candidates)));
+        // The candidates may be a SQL array, and a missing field never matches
+        assert!(query(
+            "fs_in(fs_string('a'), ARRAY[fs_string('b'), fs_string('a')])"
+        ));
+        assert!(!query("fs_not_in(fs_string('a'), ARRAY[fs_string('a')])"));
+        assert!(!query("fs_in(NULL, fs_array(ARRAY[fs_null()]))"));
+        assert!(!query("fs_not_in(NULL, ARRAY[fs_string('a')])"));
+        // Candidates compare by value, like `#=`
+        assert!(query(
+            "fs_in(fs_number_from_integer(1), fs_array(ARRAY[fs_number_from_double(1.0)]))"
+        ));
+        assert!(!query(
+            "fs_not_in(fs_number_from_integer(1), fs_array(ARRAY[fs_number_from_double(1.0)]))"
+        ));
+        assert!(query(
+            "fs_in(fs_number_from_integer(30), \
+             fs_array(ARRAY(SELECT fs_number_from_integer(i) FROM generate_series(1, 30) AS i)))"
+        ));
+    }
+
+    #[pg_test(
+        error = "LimitExceeded: IN filter of 31 values exceeds Firestore's limit of 30 values"
+    )]
+    fn test_fs_in_too_many_values() {
+        Spi::run(
+            "SELECT fs_in(fs_number_from_integer(1), \
+             fs_array(ARRAY(SELECT fs_number_from_integer(i) FROM generate_series(1, 31) AS i)))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(
+        error = "LimitExceeded: IN filter of 31 values exceeds Firestore's limit of 30 values"
+    )]
+    fn test_fs_not_in_too_many_values() {
+        Spi::run(
+            "SELECT fs_not_in(fs_number_from_integer(1), \
+             ARRAY(SELECT fs_number_from_integer(i) FROM generate_series(1, 31) AS i))",
+        )
+        .expect("SPI failed");
+    }
+
+    #[pg_test(error = "InvalidType: Expecting an array but found \"a\"")]
+    fn test_fs_in_expects_array() {
+        Spi::run("SELECT fs_in(fs_string('a'), fs_string('a'))").expect("SPI failed");
+    }
+
+    #[pg_test]
+    fn test_query_operators_sql_null() {
+        // (lhs, rhs, #=, #!=, #<) over a missing field, a Firestore NULL and 1
+        let rows = Spi::connect(|client| {
+            let mut rows = Vec::new();
+            for row in client.select(
+                "WITH operands(name, value) AS (VALUES \
+                     ('missing', NULL::fsvalue), ('null', fs_null()), ('one', fs_number_from_integer(1))) \
+                 SELECT l.name, r.name, l.value #= r.value, l.value #!= r.value, l.value #< r.value \
+                 FROM operands l CROSS JOIN operands r ORDER BY l.name, r.name",
+                None,
+                None,
+            )? {
+                rows.push((
+                    row.get::<String>(1)?.expect("name must not be null"),
+                    row.get::<String>(2)?.expect("name must not be null"),
+                    row.get::<bool>(3)?,
+                    row.get::<bool>(4)?,
+                    row.get::<bool>(5)?,
+                ));
+            }
+            Ok::<_, pgrx::spi::Error>(rows)
+        })
+        .expect("SPI failed");
+        let expected = [
+            ("missing", "missing", false, false, false),
+            ("missing", "null", false, false, false),
+            ("missing", "one", false, false, false),
+            ("null", "missing", false, false, false),
+            ("null", "null", true, false, false),
+            ("null", "one", false, true, false),
+            ("one", "missing", false, false, false),
+            ("one", "null", false, false, false),
+            ("one", "one", true, false, false),
+        ];
+        assert_eq!(
+            rows,
+            expected
+                .iter()
+                .map(|(lhs, rhs, eq, neq, lt)| (
+                    lhs.to_string(),
+                    rhs.to_string(),
+                    Some(*eq),
+                    Some(*neq),
+                    Some(*lt)
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[pg_test]
+    fn test_query_operators_left_join() {
+        let join = "FROM fs_collection(fs_database_root(), 'users') u \
+                    LEFT JOIN fs_collection(fs_reference('/users/1'), 'posts') p \
+                    ON p.properties->'foo' #= (u.properties->'foo')";
+        // Users without a matching post do not satisfy any filter on the post
+        assert_eq!(
+            Spi::get_one::<String>(&format!(
+                "SELECT string_agg(fs_document_id(u.reference), ',' ORDER BY 1) {} \
+                 WHERE p.properties->'foo' #!= fs_number_from_integer(1)",
+                join
+            ))
+            .expect("SPI failed"),
+            Some("2".to_owned())
+        );
+        // ... so they all satisfy its negation
+        assert_eq!(
+            Spi::get_one::<i64>(&format!(
+                "SELECT count(*) {} WHERE NOT (p.properties->'foo' #= fs_number_from_integer(2))",
+                join
+            ))
+            .expect("SPI failed"),
+            Some(4)
+        );
+    }
+
+    #[pg_test]
+    fn test_query_operators_use_indexes() {
+        Spi::run("SET LOCAL enable_seqscan = off").expect("SPI failed");
+        for filter in [
+            "reference #= fs_reference('/users/1')",
+            "fs_reference('/users/1') #= reference",
+            "reference #> fs_reference('/users/1')",
+            // Commuted, the index serves the operand on the right too
+            "fs_reference('/users/1') #< reference",
+            "fs_reference('/users/1') #>= reference",
+        ] {
+            let plan = explain(
+                &format!("SELECT properties FROM fs_documents WHERE {}", filter),
+                false,
+            );
+            assert!(plan.contains("Index Scan"), "{}", plan);
+        }
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM fs_documents WHERE reference #= fs_reference('/users/1')"
+            ),
+            Ok(Some(1))
+        );
+
+        // The index returns the values in the total order, of which the
+        // operators keep the ones of the operand's type
+        Spi::run(
+            "CREATE TEMP TABLE numbers AS \
+             SELECT n, fs_map_from_entries(ARRAY['n'], ARRAY[value]) AS properties \
+             FROM unnest(ARRAY[fs_number_from_integer(1), fs_number_from_double(1.0), \
+                 fs_number_from_integer(2), fs_nan(), fs_null(), fs_string('a'), \
+                 fs_boolean(true)]) WITH ORDINALITY AS v(value, n); \
+             CREATE INDEX ON numbers ((properties->'n')); \
+             ANALYZE numbers",
+        )
+        .expect("SPI failed");
+        let matches = |filter: &str, index: bool| {
+            Spi::run(&format!(
+                "SET LOCAL enable_seqscan = {}; SET LOCAL enable_indexscan = {}; \
+                 SET LOCAL enable_bitmapscan = {}",
+                !index, index, index
+            ))
+            .expect("SPI failed");
+            let query = format!("SELECT n FROM numbers WHERE properties->'n' {}", filter);
+            let plan = explain(&query, false);
+            assert_eq!(plan.contains("numbers_expr_idx"), index, "{}", plan);
+            Spi::get_one::<String>(&format!(
+                "SELECT coalesce(string_agg(n::text, ',' ORDER BY n), '') FROM ({}) AS m",
+                query
+            ))
+            .expect("SPI failed")
+            .expect("string_agg must not be null")
+        };
+        for (filter, expected) in [
+            ("#= fs_number_from_integer(1)", "1,2"),
+            ("#= fs_number_from_double(1.0)", "1,2"),
+            ("#< fs_number_from_integer(2)", "1,2"),
+            ("#<= fs_number_from_integer(1)", "1,2"),
+            ("#> fs_number_from_integer(1)", "3"),
+            ("#>= fs_number_from_double(1.0)", "1,2,3"),
+            ("#< fs_string('b')", "6"),
+            ("#> fs_boolean(false)", "7"),
+        ] {
+            assert_eq!(matches(filter, false), expected, "{}", filter);
+            assert_eq!(matches(filter, true), expected, "{}", filter);
+        }
+    }
+
+    #[pg_test]
+    fn test_collection_metadata_columns() {
+        Spi::run(
+            "SET pgfirestore.fixed_request_time = '2024-01-01T00:00:00Z'; \
+             SELECT fs_set(fs_reference('/users/1/posts/3'), fs_map_from_entries(ARRAY['foo'], ARRAY[fs_number_from_integer(3)])); \
+             SET pgfirestore.fixed_request_time = '2024-02-01T00:00:00Z'; \
+             SELECT fs_set(fs_reference('/users/1/posts/3'), fs_map_from_entries(ARRAY['foo'], ARRAY[fs_number_from_integer(4)]))",
+        )
+        .expect("SPI failed");
+        // An update moves update_time but leaves create_time alone
+        for source in [
+            "fs_collection_v2(fs_reference('/users/1'), 'posts')",
+            "fs_collection_group_v2('posts')",
+            "fs_descendants_v2(fs_reference('/users/1'))",
+        ] {
+            assert_eq!(
+                Spi::get_one::<bool>(&format!(
+                    "SELECT create_time = '2024-01-01T00:00:00Z' \
+                         AND update_time = '2024-02-01T00:00:00Z' \
+                         AND properties->'foo' #= fs_number_from_integer(4) \
+                     FROM {} WHERE reference = fs_reference('/users/1/posts/3')",
+                    source
+                ))
+                .expect("SPI failed"),
+                Some(true)
+            );
+            assert_eq!(
+                Spi::get_one::<i64>(&format!(
+                    "SELECT count(*) FROM {} WHERE create_time > update_time",
+                    source
+                ))
+                .expect("SPI failed"),
+                Some(0)
+            );
+        }
+        // The two column functions return the same documents
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT array_agg(reference ORDER BY reference)::text = \
+                     (SELECT array_agg(reference ORDER BY reference)::text \
+                      FROM fs_collection(fs_reference('/users/1'), 'posts')) \
+                 FROM fs_collection_v2(fs_reference('/users/1'), 'posts')"
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "SELECT array_agg(reference ORDER BY reference)::text = \
+                     (SELECT array_agg(reference ORDER BY reference)::text \
+                      FROM fs_descendants(fs_reference('/users/1'))) \
+                 FROM fs_descendants_v2(fs_reference('/users/1'))"
+            )
+            .expect("SPI failed"),
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM fs_collection_group('posts')")
+                .expect("SPI failed"),
+            Some(5)
+        );
+    }
 }
 
 /// This module is required by `cargo pgrx test` invocations.
@@ -891,6 +4698,6 @@ pub mod pg_test {
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
         // return any postgresql.conf settings that are required for your tests
-        vec![]
+        vec!["shared_preload_libraries = 'pgfirestore'"]
     }
 }